@@ -4,6 +4,10 @@ fn main() {
 
     #[cfg(target_os = "windows")]
     windows::build! {
+        Windows::Win32::Foundation::{CloseHandle, BOOL, HANDLE},
         Windows::Win32::System::Hypervisor::*,
+        Windows::Win32::System::JobObjects::*,
+        Windows::Win32::System::Memory::{VirtualAlloc, MEM_RESET, MEM_RESET_UNDO, PAGE_READWRITE},
+        Windows::Win32::System::Threading::*,
     }
 }
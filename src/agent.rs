@@ -0,0 +1,354 @@
+//! This module provides the [`AgentChannel`] struct, which implements the host side of a command
+//! channel to an agent running inside the guest, e.g. for test harnesses that need to run
+//! programs inside the guest or copy files in and out of the guest. The channel is built on guest
+//! physical memory shared with the guest and a doorbell that the guest rings to notify the host.
+//!
+//! # Protocol
+//!
+//! The channel occupies a region of guest physical memory that starts with the following header,
+//! where all fields are 32-bit little-endian integers:
+//!
+//! | Offset | Field      | Description                                                          |
+//! |--------|------------|----------------------------------------------------------------------|
+//! | `0x00` | `magic`    | [`AGENT_MAGIC`], written by the host.                                |
+//! | `0x04` | `version`  | [`AGENT_VERSION`], written by the host.                              |
+//! | `0x08` | `state`    | One of the `AGENT_STATE_*` values.                                   |
+//! | `0x0c` | `sequence` | The sequence number of the current request.                          |
+//! | `0x10` | `command`  | One of the `AGENT_COMMAND_*` values.                                 |
+//! | `0x14` | `status`   | The status of the response, e.g. the exit code of a program.         |
+//! | `0x18` | `length`   | The length of the payload of the current request or response.        |
+//! | `0x1c` | `capacity` | The size of the payload buffer, written by the host.                 |
+//!
+//! The payload buffer follows the header at offset [`AGENT_HEADER_SIZE`] and extends up to the
+//! end of the region.
+//!
+//! A request is handled as follows:
+//!  1. The host writes the payload, the `command`, the `length` and a new `sequence` number, and
+//!     then sets the `state` to [`AGENT_STATE_REQUEST`].
+//!  2. The guest agent polls the `state` until it observes [`AGENT_STATE_REQUEST`], and handles
+//!     the request.
+//!  3. The guest agent writes the payload of the response, the `status` and the `length`, and
+//!     then sets the `state` to [`AGENT_STATE_RESPONSE`].
+//!  4. The guest agent rings the doorbell by writing the `sequence` number to the I/O port or
+//!     MMIO address of the doorbell.
+//!  5. The host reads the response and sets the `state` back to [`AGENT_STATE_IDLE`].
+//!
+//! The payloads of the commands are:
+//!  * [`AGENT_COMMAND_EXEC`]: the program followed by its arguments, each terminated by a NUL
+//!    byte. The `status` of the response is the exit code of the program and the payload of the
+//!    response is the standard output of the program.
+//!  * [`AGENT_COMMAND_PUSH`]: the path of the file terminated by a NUL byte, followed by the
+//!    contents to write to the file. The response has no payload.
+//!  * [`AGENT_COMMAND_PULL`]: the path of the file. The payload of the response is the contents of
+//!    the file.
+//!  * [`AGENT_COMMAND_HEARTBEAT`]: no payload. The response has no payload.
+//!
+//! A non-zero `status` indicates failure for all commands other than [`AGENT_COMMAND_EXEC`].
+//!
+//! # Host
+//!
+//! The host sets up the channel through [`AgentChannel::attach`] and sends requests through
+//! [`AgentChannel::send`]. When [`crate::Vcpu::run`] returns an exit for which
+//! [`AgentChannel::is_doorbell`] returns `true`, the response can be collected through
+//! [`AgentChannel::poll`]. As the guest may also update the `state` without ringing the doorbell,
+//! [`AgentChannel::poll`] may also be called at any other time, e.g. to implement timeouts.
+
+use crate::error::Error;
+use crate::vcpu::ExitReason;
+use crate::vm::Vm;
+use std::sync::atomic::{fence, Ordering};
+
+/// The magic value identifying the channel, i.e. `HYAG` in ASCII.
+pub const AGENT_MAGIC:   u32 = 0x4741_5948;
+/// The version of the channel protocol.
+pub const AGENT_VERSION: u32 = 1;
+
+/// The size of the header preceding the payload buffer.
+pub const AGENT_HEADER_SIZE: usize = 0x40;
+
+/// There is no request.
+pub const AGENT_STATE_IDLE:     u32 = 0;
+/// The host has written a request that the guest has yet to respond to.
+pub const AGENT_STATE_REQUEST:  u32 = 1;
+/// The guest has written a response that the host has yet to read.
+pub const AGENT_STATE_RESPONSE: u32 = 2;
+
+/// Runs a program inside the guest.
+pub const AGENT_COMMAND_EXEC:      u32 = 1;
+/// Writes a file inside the guest.
+pub const AGENT_COMMAND_PUSH:      u32 = 2;
+/// Reads a file inside the guest.
+pub const AGENT_COMMAND_PULL:      u32 = 3;
+/// Checks whether the guest agent is alive.
+pub const AGENT_COMMAND_HEARTBEAT: u32 = 4;
+
+const OFFSET_MAGIC:    u64 = 0x00;
+const OFFSET_VERSION:  u64 = 0x04;
+const OFFSET_STATE:    u64 = 0x08;
+const OFFSET_SEQUENCE: u64 = 0x0c;
+const OFFSET_COMMAND:  u64 = 0x10;
+const OFFSET_STATUS:   u64 = 0x14;
+const OFFSET_LENGTH:   u64 = 0x18;
+const OFFSET_CAPACITY: u64 = 0x1c;
+
+/// The doorbell the guest rings after writing a response.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Doorbell {
+    /// The guest writes to the given I/O port.
+    IoPort(u16),
+    /// The guest writes to the given MMIO address, which must not be backed by guest memory.
+    Mmio(u64),
+}
+
+/// A request to the guest agent.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum AgentRequest {
+    /// Runs the program with the given arguments.
+    Exec { program: String, args: Vec<String> },
+    /// Writes the contents to the file at the given path.
+    Push { path: String, contents: Vec<u8> },
+    /// Reads the file at the given path.
+    Pull { path: String },
+    /// Checks whether the guest agent is alive.
+    Heartbeat,
+}
+
+impl AgentRequest {
+    /// Returns the command and the payload of the request.
+    fn encode(&self) -> Result<(u32, Vec<u8>), Error> {
+        let mut payload = vec![];
+
+        let command = match self {
+            Self::Exec { program, args } => {
+                for arg in std::iter::once(program).chain(args) {
+                    push_string(&mut payload, arg)?;
+                }
+
+                AGENT_COMMAND_EXEC
+            }
+            Self::Push { path, contents } => {
+                push_string(&mut payload, path)?;
+                payload.extend_from_slice(contents);
+
+                AGENT_COMMAND_PUSH
+            }
+            Self::Pull { path } => {
+                payload.extend_from_slice(path.as_bytes());
+
+                AGENT_COMMAND_PULL
+            }
+            Self::Heartbeat => AGENT_COMMAND_HEARTBEAT,
+        };
+
+        Ok((command, payload))
+    }
+}
+
+/// Appends the string terminated by a NUL byte to the payload.
+fn push_string(payload: &mut Vec<u8>, s: &str) -> Result<(), Error> {
+    // The strings are NUL-terminated, so they cannot contain NUL bytes themselves.
+    if s.as_bytes().contains(&0) {
+        return Err(Error::InvalidArgument);
+    }
+
+    payload.extend_from_slice(s.as_bytes());
+    payload.push(0);
+
+    Ok(())
+}
+
+/// A response from the guest agent.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct AgentResponse {
+    /// The sequence number of the request.
+    pub sequence: u32,
+    /// The status, i.e. the exit code for [`AgentRequest::Exec`] or non-zero on failure
+    /// otherwise.
+    pub status: u32,
+    /// The payload, i.e. the standard output for [`AgentRequest::Exec`] or the contents of the
+    /// file for [`AgentRequest::Pull`].
+    pub payload: Vec<u8>,
+}
+
+/// The host side of the command channel to the guest agent. See [`crate::agent`] for the
+/// protocol.
+#[derive(Debug)]
+pub struct AgentChannel {
+    /// The guest physical address of the channel.
+    guest_address: u64,
+    /// The size of the channel in bytes, including the header.
+    size: usize,
+    /// The doorbell rung by the guest.
+    doorbell: Doorbell,
+    /// The sequence number of the last request.
+    sequence: u32,
+    /// Whether the guest has yet to respond to the last request.
+    pending: bool,
+}
+
+impl AgentChannel {
+    /// Describes a channel in the guest physical memory of the given size at the given guest
+    /// address, which must already be allocated, see [`Vm::allocate_physical_memory`]. The size
+    /// includes the header of [`AGENT_HEADER_SIZE`] bytes.
+    pub fn new(guest_address: u64, size: usize, doorbell: Doorbell) -> Result<Self, Error> {
+        if size <= AGENT_HEADER_SIZE || size - AGENT_HEADER_SIZE > u32::MAX as usize {
+            return Err(Error::InvalidArgument);
+        }
+
+        Ok(Self {
+            guest_address,
+            size,
+            doorbell,
+            sequence: 0,
+            pending: false,
+        })
+    }
+
+    /// Returns the guest physical address of the channel.
+    pub fn guest_address(&self) -> u64 {
+        self.guest_address
+    }
+
+    /// Returns the doorbell rung by the guest.
+    pub fn doorbell(&self) -> Doorbell {
+        self.doorbell
+    }
+
+    /// Returns the size of the payload buffer, i.e. the maximum size of the payload of a request
+    /// or response.
+    pub fn capacity(&self) -> usize {
+        self.size - AGENT_HEADER_SIZE
+    }
+
+    /// Returns `true` if the guest has yet to respond to the last request.
+    pub fn is_pending(&self) -> bool {
+        self.pending
+    }
+
+    /// Initializes the header of the channel in guest memory, which the guest agent uses to
+    /// detect the channel. This discards any pending request.
+    pub fn attach(&mut self, vm: &mut Vm) -> Result<(), Error> {
+        self.write_u32(vm, OFFSET_STATE, AGENT_STATE_IDLE)?;
+        self.write_u32(vm, OFFSET_SEQUENCE, self.sequence)?;
+        self.write_u32(vm, OFFSET_COMMAND, 0)?;
+        self.write_u32(vm, OFFSET_STATUS, 0)?;
+        self.write_u32(vm, OFFSET_LENGTH, 0)?;
+        self.write_u32(vm, OFFSET_CAPACITY, self.capacity() as u32)?;
+        self.write_u32(vm, OFFSET_VERSION, AGENT_VERSION)?;
+
+        // Write the magic value last, such that the guest only observes a complete header.
+        fence(Ordering::SeqCst);
+        self.write_u32(vm, OFFSET_MAGIC, AGENT_MAGIC)?;
+
+        self.pending = false;
+
+        Ok(())
+    }
+
+    /// Sends the request to the guest agent and returns its sequence number. Only one request can
+    /// be pending at a time, so this fails with [`Error::InvalidArgument`] if the guest has yet to
+    /// respond to the last request, or if the payload does not fit the payload buffer.
+    pub fn send(&mut self, vm: &mut Vm, request: &AgentRequest) -> Result<u32, Error> {
+        if self.pending {
+            return Err(Error::InvalidArgument);
+        }
+
+        let (command, payload) = request.encode()?;
+
+        if payload.len() > self.capacity() {
+            return Err(Error::InvalidArgument);
+        }
+
+        let sequence = self.sequence.wrapping_add(1);
+
+        self.write_bytes(vm, AGENT_HEADER_SIZE as u64, &payload)?;
+        self.write_u32(vm, OFFSET_COMMAND, command)?;
+        self.write_u32(vm, OFFSET_STATUS, 0)?;
+        self.write_u32(vm, OFFSET_LENGTH, payload.len() as u32)?;
+        self.write_u32(vm, OFFSET_SEQUENCE, sequence)?;
+
+        // Publish the request only once it has been written completely.
+        fence(Ordering::SeqCst);
+        self.write_u32(vm, OFFSET_STATE, AGENT_STATE_REQUEST)?;
+
+        self.sequence = sequence;
+        self.pending = true;
+
+        Ok(sequence)
+    }
+
+    /// Returns `true` if the exit is the guest ringing the doorbell of the channel.
+    pub fn is_doorbell(&self, exit_reason: &ExitReason) -> bool {
+        match (self.doorbell, exit_reason) {
+            (Doorbell::IoPort(doorbell), ExitReason::IoOut { port, .. }) =>
+                doorbell == *port,
+            (Doorbell::Mmio(doorbell), ExitReason::MmioWrite { address, .. }) =>
+                doorbell == *address,
+            _ => false,
+        }
+    }
+
+    /// Collects the response to the pending request. Returns `None` if there is no pending
+    /// request, or if the guest has yet to respond to it. Fails with [`Error::AgentProtocol`] if
+    /// the guest responded with the wrong sequence number or with a payload that exceeds the
+    /// payload buffer.
+    pub fn poll(&mut self, vm: &mut Vm) -> Result<Option<AgentResponse>, Error> {
+        if !self.pending || self.read_u32(vm, OFFSET_STATE)? != AGENT_STATE_RESPONSE {
+            return Ok(None);
+        }
+
+        // Only read the response once the guest has published it.
+        fence(Ordering::SeqCst);
+
+        let sequence = self.read_u32(vm, OFFSET_SEQUENCE)?;
+        let status = self.read_u32(vm, OFFSET_STATUS)?;
+        let length = self.read_u32(vm, OFFSET_LENGTH)? as usize;
+
+        if sequence != self.sequence || length > self.capacity() {
+            return Err(Error::AgentProtocol);
+        }
+
+        let mut payload = vec![0u8; length];
+        self.read_bytes(vm, AGENT_HEADER_SIZE as u64, &mut payload)?;
+
+        self.write_u32(vm, OFFSET_STATE, AGENT_STATE_IDLE)?;
+        self.pending = false;
+
+        Ok(Some(AgentResponse {
+            sequence,
+            status,
+            payload,
+        }))
+    }
+
+    /// Helper function to read the bytes at the given offset into the channel.
+    fn read_bytes(&self, vm: &Vm, offset: u64, bytes: &mut [u8]) -> Result<(), Error> {
+        if vm.read_physical_memory(bytes, self.guest_address + offset)? != bytes.len() {
+            return Err(Error::InvalidGuestAddress);
+        }
+
+        Ok(())
+    }
+
+    /// Helper function to write the bytes to the given offset into the channel.
+    fn write_bytes(&self, vm: &mut Vm, offset: u64, bytes: &[u8]) -> Result<(), Error> {
+        if vm.write_physical_memory(self.guest_address + offset, bytes)? != bytes.len() {
+            return Err(Error::InvalidGuestAddress);
+        }
+
+        Ok(())
+    }
+
+    /// Helper function to read the header field at the given offset.
+    fn read_u32(&self, vm: &Vm, offset: u64) -> Result<u32, Error> {
+        let mut bytes = [0u8; 4];
+        self.read_bytes(vm, offset, &mut bytes)?;
+
+        Ok(u32::from_le_bytes(bytes))
+    }
+
+    /// Helper function to write the header field at the given offset.
+    fn write_u32(&self, vm: &mut Vm, offset: u64, value: u32) -> Result<(), Error> {
+        self.write_bytes(vm, offset, &value.to_le_bytes())
+    }
+}
@@ -0,0 +1,254 @@
+//! This module provides code specific to the AArch64 architecture.
+
+use crate::error::Error;
+use num_derive::FromPrimitive;
+use num_traits::FromPrimitive;
+
+/// Represents the general-purpose registers of the AArch64 architecture.
+#[derive(Clone, Copy, Debug, Eq, FromPrimitive, PartialEq)]
+pub enum Register {
+    /// The general-purpose register X0.
+    X0,
+    /// The general-purpose register X1.
+    X1,
+    /// The general-purpose register X2.
+    X2,
+    /// The general-purpose register X3.
+    X3,
+    /// The general-purpose register X4.
+    X4,
+    /// The general-purpose register X5.
+    X5,
+    /// The general-purpose register X6.
+    X6,
+    /// The general-purpose register X7.
+    X7,
+    /// The general-purpose register X8.
+    X8,
+    /// The general-purpose register X9.
+    X9,
+    /// The general-purpose register X10.
+    X10,
+    /// The general-purpose register X11.
+    X11,
+    /// The general-purpose register X12.
+    X12,
+    /// The general-purpose register X13.
+    X13,
+    /// The general-purpose register X14.
+    X14,
+    /// The general-purpose register X15.
+    X15,
+    /// The general-purpose register X16.
+    X16,
+    /// The general-purpose register X17.
+    X17,
+    /// The general-purpose register X18.
+    X18,
+    /// The general-purpose register X19.
+    X19,
+    /// The general-purpose register X20.
+    X20,
+    /// The general-purpose register X21.
+    X21,
+    /// The general-purpose register X22.
+    X22,
+    /// The general-purpose register X23.
+    X23,
+    /// The general-purpose register X24.
+    X24,
+    /// The general-purpose register X25.
+    X25,
+    /// The general-purpose register X26.
+    X26,
+    /// The general-purpose register X27.
+    X27,
+    /// The general-purpose register X28.
+    X28,
+    /// The general-purpose register X29, also known as the frame pointer.
+    X29,
+    /// The general-purpose register X30, also known as the link register.
+    X30,
+    /// The stack pointer register.
+    Sp,
+    /// The program counter register.
+    Pc,
+    /// The processor state register.
+    Pstate,
+}
+
+/// Extends the virtual CPU with functions to access the architecture-specific registers.
+pub trait CpuRegs {
+    /// Gets the general-purpose registers specified by the array of [`Register`]s.
+    fn get_registers(
+        &self,
+        registers: &[Register],
+    ) -> Result<Vec<u64>, Error>;
+
+    /// Sets the general-purpose registers specified by the array of [`Register`]s to the
+    /// corresponding values.
+    fn set_registers(
+        &mut self,
+        registers: &[Register],
+        values: &[u64],
+    ) -> Result<(), Error>;
+}
+
+/// The exception classes of the exception syndrome that the hypervisor reports when a virtual CPU
+/// exits, i.e. bits 31:26 of `ESR_EL2`.
+#[derive(Copy, Clone, Debug, Eq, FromPrimitive, PartialEq)]
+#[repr(u32)]
+pub enum ExceptionClass {
+    /// An exception for an unknown reason, e.g. an undefined instruction.
+    Unknown                 = 0x00,
+    /// A trapped `wfi` or `wfe` instruction.
+    Wfx                     = 0x01,
+    /// An `hvc` instruction executed in AArch64 state.
+    Hvc64                   = 0x16,
+    /// An `smc` instruction executed in AArch64 state.
+    Smc64                   = 0x17,
+    /// A trapped `msr` or `mrs` instruction, or a trapped system instruction.
+    SysReg                  = 0x18,
+    /// An instruction abort from a lower exception level, e.g. executing unmapped memory.
+    InstructionAbortLowerEl = 0x20,
+    /// A misaligned program counter.
+    PcAlignment             = 0x22,
+    /// A data abort from a lower exception level, e.g. accessing unmapped memory.
+    DataAbortLowerEl        = 0x24,
+    /// A misaligned stack pointer.
+    SpAlignment             = 0x26,
+    /// An SError interrupt.
+    SError                  = 0x2f,
+    /// A hardware breakpoint from a lower exception level.
+    BreakpointLowerEl       = 0x30,
+    /// A software step from a lower exception level.
+    SoftwareStepLowerEl     = 0x32,
+    /// A watchpoint from a lower exception level.
+    WatchpointLowerEl       = 0x34,
+    /// A `brk` instruction executed in AArch64 state.
+    Brk64                   = 0x3c,
+}
+
+impl ExceptionClass {
+    /// Extracts the exception class from the given exception syndrome. Returns `None` for the
+    /// exception classes that are not listed.
+    pub fn from_syndrome(syndrome: u64) -> Option<Self> {
+        Self::from_u64((syndrome >> 26) & 0x3f)
+    }
+}
+
+/// Returns whether the given exception syndrome of [`ExceptionClass::Wfx`] is for the `wfi`
+/// instruction, as opposed to the `wfe` instruction.
+pub fn is_wfi(syndrome: u64) -> bool {
+    syndrome & 0x1 == 0
+}
+
+/// Returns the immediate of the `hvc` instruction for the given exception syndrome of
+/// [`ExceptionClass::Hvc64`], which is usually zero, as the hypercall number is passed in the
+/// registers.
+pub fn hvc_immediate(syndrome: u64) -> u16 {
+    syndrome as u16
+}
+
+/// The function IDs of the Power State Coordination Interface (PSCI), which the guest calls
+/// through `hvc #0` or `smc #0` with the function ID in `w0`, where the functions that take
+/// addresses use the SMC64 calling convention.
+pub const PSCI_VERSION:           u32 = 0x8400_0000;
+pub const PSCI_CPU_SUSPEND:       u32 = 0xc400_0001;
+pub const PSCI_CPU_OFF:           u32 = 0x8400_0002;
+pub const PSCI_CPU_ON:            u32 = 0xc400_0003;
+pub const PSCI_AFFINITY_INFO:     u32 = 0xc400_0004;
+pub const PSCI_MIGRATE_INFO_TYPE: u32 = 0x8400_0006;
+pub const PSCI_SYSTEM_OFF:        u32 = 0x8400_0008;
+pub const PSCI_SYSTEM_RESET:      u32 = 0x8400_0009;
+pub const PSCI_FEATURES:          u32 = 0x8400_000a;
+
+/// The return codes of the PSCI functions, which are returned in `x0`.
+pub const PSCI_SUCCESS:          i64 = 0;
+pub const PSCI_NOT_SUPPORTED:    i64 = -1;
+pub const PSCI_INVALID_PARAMS:   i64 = -2;
+pub const PSCI_DENIED:           i64 = -3;
+pub const PSCI_ALREADY_ON:       i64 = -4;
+pub const PSCI_ON_PENDING:       i64 = -5;
+pub const PSCI_INTERNAL_FAILURE: i64 = -6;
+
+/// Returns whether the given function ID is a PSCI function, i.e. a standard secure service call
+/// with a function number in the range reserved for PSCI, using either the SMC32 or the SMC64
+/// calling convention.
+pub fn is_psci_function(function: u64) -> bool {
+    let function = match u32::try_from(function) {
+        Ok(function) => function,
+        _ => return false,
+    };
+
+    matches!(function & !0x4000_0000, 0x8400_0000..=0x8400_001f)
+}
+
+/// The access that caused a data abort, as decoded from the instruction syndrome of
+/// [`ExceptionClass::DataAbortLowerEl`]. This is how the MMIO accesses of the guest are reported.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct DataAbort {
+    /// Whether the access is a write rather than a read.
+    pub is_write: bool,
+    /// The size of the access in bytes.
+    pub size: usize,
+    /// The register that is transferred, or `None` for the zero register.
+    pub register: Option<Register>,
+    /// Whether a read is sign-extended into the register.
+    pub sign_extend: bool,
+    /// Whether the register is accessed as a 64-bit register rather than a 32-bit register.
+    pub is_64bit: bool,
+}
+
+impl DataAbort {
+    /// Decodes the access from the given exception syndrome. Returns `None` if the exception
+    /// syndrome is not a data abort or does not describe the access, e.g. for load and store pair
+    /// instructions, in which case the instruction has to be decoded instead.
+    pub fn from_syndrome(syndrome: u64) -> Option<Self> {
+        if ExceptionClass::from_syndrome(syndrome) != Some(ExceptionClass::DataAbortLowerEl) {
+            return None;
+        }
+
+        // The ISV bit indicates whether bits 23:14 hold a valid instruction syndrome.
+        if syndrome & (1 << 24) == 0 {
+            return None;
+        }
+
+        // Register 31 is the zero register rather than the stack pointer.
+        let register = match (syndrome >> 16) & 0x1f {
+            31 => None,
+            number => Register::from_u64(number),
+        };
+
+        Some(Self {
+            is_write: syndrome & (1 << 6) != 0,
+            size: 1 << ((syndrome >> 22) & 0x3),
+            register,
+            sign_extend: syndrome & (1 << 21) != 0,
+            is_64bit: syndrome & (1 << 15) != 0,
+        })
+    }
+}
+
+/// The size of the GICv3 distributor region in bytes.
+pub const GIC_DISTRIBUTOR_SIZE: u64 = 0x1_0000;
+/// The size of the GICv3 redistributor region of a single virtual CPU in bytes, i.e. the
+/// `RD_base` and `SGI_base` frames.
+pub const GIC_REDISTRIBUTOR_SIZE: u64 = 0x2_0000;
+/// The first interrupt ID of the shared peripheral interrupts (SPIs). The interrupt IDs below are
+/// the private interrupts (SGIs and PPIs) of each virtual CPU.
+pub const GIC_SPI_BASE: u32 = 32;
+
+/// The configuration of the in-kernel GICv3 interrupt controller, see
+/// [`crate::VmBuilder::with_gic`].
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct GicConfig {
+    /// The guest physical address of the distributor, which spans [`GIC_DISTRIBUTOR_SIZE`] bytes.
+    pub distributor_base: u64,
+    /// The guest physical address of the redistributors, which span [`GIC_REDISTRIBUTOR_SIZE`]
+    /// bytes for every virtual CPU.
+    pub redistributor_base: u64,
+    /// The number of shared peripheral interrupts (SPIs), starting at [`GIC_SPI_BASE`]. On Linux,
+    /// this must be a multiple of 32.
+    pub spi_count: u32,
+}
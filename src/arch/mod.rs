@@ -1,4 +1,8 @@
 //! This module provides architecture-specific code.
 
-#[cfg(target_arch = "x86_64")]
+#[cfg(target_arch = "aarch64")]
+pub mod aarch64;
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+pub mod x86;
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
 pub mod x86_64;
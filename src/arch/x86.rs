@@ -0,0 +1,109 @@
+//! This module provides code specific to the 32-bit x86 architecture, i.e. guests that never
+//! enable long mode. The hypervisor APIs expose the full 64-bit register file regardless of the
+//! mode the guest runs in, so this module builds on top of [`crate::arch::x86_64::CpuRegs`] and
+//! provides 32-bit views of the general-purpose registers. The remaining register types, e.g.
+//! [`ControlRegister`] and [`SegmentRegister`], are shared with the x86-64 architecture.
+//!
+//! This module is also available on 32-bit x86 hosts, where only the KVM backend is supported.
+
+use crate::arch::x86_64;
+use crate::error::Error;
+
+pub use crate::arch::x86_64::{
+    ControlRegister, DescriptorTable, DescriptorTableRegister, Segment, SegmentRegister,
+};
+
+/// Represents the general-purpose registers of the 32-bit x86 architecture.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Register {
+    /// The accumulator register.
+    Eax,
+    /// The counter register.
+    Ecx,
+    /// The data register.
+    Edx,
+    /// The base register.
+    Ebx,
+    /// The stack pointer register.
+    Esp,
+    /// The base pointer register.
+    Ebp,
+    /// The source index register.
+    Esi,
+    /// The destination index register.
+    Edi,
+    /// The instruction pointer register.
+    Eip,
+    /// The status register.
+    Eflags,
+}
+
+impl From<Register> for x86_64::Register {
+    fn from(register: Register) -> Self {
+        match register {
+            Register::Eax    => x86_64::Register::Rax,
+            Register::Ecx    => x86_64::Register::Rcx,
+            Register::Edx    => x86_64::Register::Rdx,
+            Register::Ebx    => x86_64::Register::Rbx,
+            Register::Esp    => x86_64::Register::Rsp,
+            Register::Ebp    => x86_64::Register::Rbp,
+            Register::Esi    => x86_64::Register::Rsi,
+            Register::Edi    => x86_64::Register::Rdi,
+            Register::Eip    => x86_64::Register::Rip,
+            Register::Eflags => x86_64::Register::Rflags,
+        }
+    }
+}
+
+/// Extends the virtual CPU with functions to access the 32-bit general-purpose registers.
+pub trait CpuRegs32 {
+    /// Gets the general-purpose registers specified by the array of [`Register`]s. The upper 32
+    /// bits of the underlying 64-bit registers are discarded.
+    fn get_registers32(
+        &self,
+        registers: &[Register],
+    ) -> Result<Vec<u32>, Error>;
+
+    /// Sets the general-purpose registers specified by the array of [`Register`]s to the
+    /// corresponding values. The upper 32 bits of the underlying 64-bit registers are cleared.
+    fn set_registers32(
+        &mut self,
+        registers: &[Register],
+        values: &[u32],
+    ) -> Result<(), Error>;
+}
+
+impl<T: x86_64::CpuRegs> CpuRegs32 for T {
+    fn get_registers32(
+        &self,
+        registers: &[Register],
+    ) -> Result<Vec<u32>, Error> {
+        let registers: Vec<x86_64::Register> = registers
+            .iter()
+            .map(|register| (*register).into())
+            .collect();
+
+        Ok(self.get_registers(&registers)?
+            .into_iter()
+            .map(|value| value as u32)
+            .collect())
+    }
+
+    fn set_registers32(
+        &mut self,
+        registers: &[Register],
+        values: &[u32],
+    ) -> Result<(), Error> {
+        let registers: Vec<x86_64::Register> = registers
+            .iter()
+            .map(|register| (*register).into())
+            .collect();
+
+        let values: Vec<u64> = values
+            .iter()
+            .map(|value| *value as u64)
+            .collect();
+
+        self.set_registers(&registers, &values)
+    }
+}
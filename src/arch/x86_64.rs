@@ -2,7 +2,9 @@
 
 use bitflags::bitflags;
 use crate::error::Error;
+use crate::vm::Vm;
 use num_derive::FromPrimitive;
+use std::ops::Range;
 
 /// Represents the general-purpose registers of the x86-64 architecture.
 #[derive(Clone, Copy, Debug, Eq, PartialEq)]
@@ -45,6 +47,11 @@ pub enum Register {
     Rflags,
 }
 
+/// Trap Flag.
+pub const RFLAGS_TF: u64 = 1 << 8;
+/// Interrupt Enable Flag.
+pub const RFLAGS_IF: u64 = 1 << 9;
+
 /// Protected Mode Enable.
 pub const CR0_PE: u64 = 1 << 0;
 /// Monitor Co-Processor.
@@ -93,6 +100,8 @@ pub const CR4_OSXMMEXCPT: u64 = 1 << 10;
 /// User Mode Instruction Prevention (disables `sgdt`, sidt`, `sldt`, `smsw` and `str` are disabled
 /// in user mode).
 pub const CR4_UMIP:       u64 = 1 << 11;
+/// Enable 5-level paging, i.e. 57-bit linear addresses.
+pub const CR4_LA57:       u64 = 1 << 12;
 /// Virtual Machine eXtension Enable.
 pub const CR4_VMXE:       u64 = 1 << 13;
 /// Safer Mode eXtension Enable.
@@ -132,6 +141,48 @@ pub enum ControlRegister {
     Cr8,
 }
 
+bitflags! {
+    /// The `CrExits` select the accesses of the guest to the control registers that exit with
+    /// [`crate::ExitReason::CrAccess`], see [`crate::VmBuilder::with_cr_exits`]. The reads of CR0
+    /// and CR4 cannot be intercepted, as the guest reads them from the read shadows instead.
+    pub struct CrExits: u32 {
+        /// Exit upon writes to CR0, including the `clts` and `lmsw` instructions.
+        const CR0_WRITE = 1 << 0;
+        /// Exit upon reads from CR3.
+        const CR3_READ  = 1 << 1;
+        /// Exit upon writes to CR3.
+        const CR3_WRITE = 1 << 2;
+        /// Exit upon writes to CR4.
+        const CR4_WRITE = 1 << 3;
+        /// Exit upon reads from CR8.
+        const CR8_READ  = 1 << 4;
+        /// Exit upon writes to CR8.
+        const CR8_WRITE = 1 << 5;
+    }
+}
+
+impl Default for CrExits {
+    fn default() -> Self {
+        Self::empty()
+    }
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum DebugRegister {
+    /// Debug register DR0. This contains the linear address of hardware breakpoint 0.
+    Dr0,
+    /// Debug register DR1. This contains the linear address of hardware breakpoint 1.
+    Dr1,
+    /// Debug register DR2. This contains the linear address of hardware breakpoint 2.
+    Dr2,
+    /// Debug register DR3. This contains the linear address of hardware breakpoint 3.
+    Dr3,
+    /// Debug register DR6. This contains the status of the last debug exception.
+    Dr6,
+    /// Debug register DR7. This enables and configures the hardware breakpoints.
+    Dr7,
+}
+
 /// Represents a segment descriptor on the x86-64 architecture.
 #[derive(Clone, Debug, Default)]
 pub struct Segment {
@@ -160,6 +211,164 @@ pub struct Segment {
     pub granularity: bool,
 }
 
+impl Segment {
+    /// The type of an execute/read code segment that has been accessed.
+    pub const TYPE_CODE: u8 = 0xb;
+    /// The type of a read/write data segment that has been accessed.
+    pub const TYPE_DATA: u8 = 0x3;
+
+    /// Returns a 16-bit real mode code segment for the given selector, where the base is the
+    /// selector multiplied by 16.
+    pub fn real_mode_code(selector: u16) -> Self {
+        Self {
+            base: (selector as u64) << 4,
+            limit: 0xffff,
+            selector,
+            segment_type: Self::TYPE_CODE,
+            non_system_segment: true,
+            present: true,
+            ..Default::default()
+        }
+    }
+
+    /// Returns a 16-bit real mode data segment for the given selector, where the base is the
+    /// selector multiplied by 16.
+    pub fn real_mode_data(selector: u16) -> Self {
+        Self {
+            segment_type: Self::TYPE_DATA,
+            ..Self::real_mode_code(selector)
+        }
+    }
+
+    /// Returns a flat 32-bit protected mode code segment for the given selector, i.e. a segment
+    /// that spans the 4 GiB address space starting at zero.
+    pub fn protected_flat_code(selector: u16) -> Self {
+        Self {
+            base: 0,
+            limit: 0xffff_ffff,
+            selector,
+            segment_type: Self::TYPE_CODE,
+            non_system_segment: true,
+            present: true,
+            default: true,
+            granularity: true,
+            ..Default::default()
+        }
+    }
+
+    /// Returns a flat 32-bit protected mode data segment for the given selector, i.e. a segment
+    /// that spans the 4 GiB address space starting at zero.
+    pub fn protected_flat_data(selector: u16) -> Self {
+        Self {
+            segment_type: Self::TYPE_DATA,
+            ..Self::protected_flat_code(selector)
+        }
+    }
+
+    /// Returns a 64-bit long mode code segment for the given selector. The base and the limit
+    /// are ignored in 64-bit mode, but are set up as a flat segment.
+    pub fn long_mode_code(selector: u16) -> Self {
+        // The default operand size must be cleared for 64-bit code segments.
+        Self {
+            long: true,
+            default: false,
+            ..Self::protected_flat_code(selector)
+        }
+    }
+
+    /// Returns a long mode data segment for the given selector, which is the same as
+    /// [`Segment::protected_flat_data`], as the processor ignores most of the attributes of data
+    /// segments in 64-bit mode.
+    pub fn long_mode_data(selector: u16) -> Self {
+        Self::protected_flat_data(selector)
+    }
+
+    /// Encodes the segment as an 8-byte segment descriptor to store in the GDT or the LDT. The
+    /// selector is not part of the descriptor, and the base is truncated to 32 bits.
+    pub fn to_descriptor(&self) -> u64 {
+        let limit = if self.granularity {
+            self.limit >> 12
+        } else {
+            self.limit
+        } as u64;
+
+        let access = (self.segment_type as u64 & 0xf) |
+            (self.non_system_segment as u64) << 4 |
+            (self.dpl as u64 & 0x3) << 5 |
+            (self.present as u64) << 7;
+
+        let flags = (self.available as u64) |
+            (self.long as u64) << 1 |
+            (self.default as u64) << 2 |
+            (self.granularity as u64) << 3;
+
+        (limit & 0xffff) |
+            (self.base & 0xff_ffff) << 16 |
+            access << 40 |
+            ((limit >> 16) & 0xf) << 48 |
+            flags << 52 |
+            ((self.base >> 24) & 0xff) << 56
+    }
+}
+
+/// The selector of the code segment set up by [`crate::Vcpu::enter_protected_mode`] and
+/// [`crate::Vcpu::enter_long_mode`].
+pub const GDT_CODE_SELECTOR: u16 = 0x08;
+/// The selector of the data segment set up by [`crate::Vcpu::enter_protected_mode`] and
+/// [`crate::Vcpu::enter_long_mode`].
+pub const GDT_DATA_SELECTOR: u16 = 0x10;
+/// The size of the GDT set up by [`crate::Vcpu::enter_protected_mode`] and
+/// [`crate::Vcpu::enter_long_mode`], which holds the null descriptor followed by the code and the
+/// data segment descriptors.
+pub const GDT_SIZE: u64 = 3 * 8;
+
+/// Describes where [`crate::Vcpu::enter_protected_mode`] places the GDT in guest physical memory,
+/// and where the guest starts executing.
+#[derive(Clone, Copy, Debug)]
+pub struct ProtectedModeLayout {
+    /// The guest physical address of the GDT, which must be backed by [`GDT_SIZE`] bytes of guest
+    /// memory.
+    pub gdt: u64,
+    /// The address at which the guest starts executing.
+    pub entry: u64,
+    /// The initial stack pointer of the guest.
+    pub stack: u64,
+}
+
+/// Describes where [`crate::Vcpu::enter_long_mode`] places the page tables and the GDT in guest
+/// physical memory, and where the guest starts executing.
+#[derive(Clone, Copy, Debug)]
+pub struct LongModeLayout {
+    /// The guest physical address of the page tables, which must be aligned to 4 kiB and backed
+    /// by [`LongModeLayout::page_tables_size`] bytes of guest memory.
+    pub page_tables: u64,
+    /// The number of bytes of guest physical memory that is identity mapped starting at address
+    /// zero, which is rounded up to 1 GiB and mapped using 2 MiB pages. This is limited to 512
+    /// GiB.
+    pub identity_map_size: u64,
+    /// The guest physical address of the GDT, which must be backed by [`GDT_SIZE`] bytes of guest
+    /// memory.
+    pub gdt: u64,
+    /// The address at which the guest starts executing.
+    pub entry: u64,
+    /// The initial stack pointer of the guest.
+    pub stack: u64,
+}
+
+impl LongModeLayout {
+    /// Returns the number of page directories needed to identity map the memory, i.e. one for
+    /// every GiB.
+    pub fn page_directory_count(&self) -> u64 {
+        (self.identity_map_size + (1 << 30) - 1) >> 30
+    }
+
+    /// Returns the size of the page tables in bytes, i.e. the PML4, the PDPT and the page
+    /// directories.
+    pub fn page_tables_size(&self) -> u64 {
+        (2 + self.page_directory_count()) * 0x1000
+    }
+}
+
 /// Represents the segment registers of the x86-64 architecture.
 #[derive(Clone, Copy, Debug, Eq, PartialEq)]
 pub enum SegmentRegister {
@@ -199,6 +408,269 @@ pub struct DescriptorTable {
     pub limit: u16,
 }
 
+/// The type of an available 64-bit TSS, or an available 32-bit TSS in protected mode.
+pub const SEGMENT_TYPE_TSS: u8 = 0x9;
+
+/// The `GdtBuilder` assembles a global descriptor table, which starts with the null descriptor.
+/// The GDT is written to guest memory through [`GdtBuilder::write`], which returns the
+/// [`DescriptorTable`] to load through [`CpuRegs::set_descriptor_tables`].
+#[derive(Clone, Debug)]
+pub struct GdtBuilder {
+    entries: Vec<u64>,
+}
+
+impl GdtBuilder {
+    /// Creates a GDT that only holds the null descriptor.
+    pub fn new() -> Self {
+        Self {
+            entries: vec![0],
+        }
+    }
+
+    /// Returns the selector of the next descriptor with the given privilege level.
+    fn next_selector(&self, dpl: u8) -> u16 {
+        (self.entries.len() as u16) << 3 | (dpl as u16 & 0x3)
+    }
+
+    /// Appends the descriptor of the given segment. Returns the segment with the selector of the
+    /// descriptor filled in, which can be loaded through [`CpuRegs::set_segment_registers`].
+    pub fn add_segment(&mut self, segment: &Segment) -> Segment {
+        let selector = self.next_selector(segment.dpl);
+
+        self.entries.push(segment.to_descriptor());
+
+        Segment {
+            selector,
+            ..segment.clone()
+        }
+    }
+
+    /// Appends the 16-byte descriptor of a 64-bit TSS at the given base address with the given
+    /// limit, which is usually 0x67 without an I/O permission bitmap. Returns the segment to load
+    /// into the task register through [`CpuRegs::set_segment_registers`] with
+    /// [`SegmentRegister::Tr`]. In 32-bit protected mode, use [`GdtBuilder::add_segment`] with
+    /// [`SEGMENT_TYPE_TSS`] instead.
+    pub fn add_tss(&mut self, base: u64, limit: u32) -> Segment {
+        let segment = Segment {
+            base,
+            limit,
+            selector: self.next_selector(0),
+            segment_type: SEGMENT_TYPE_TSS,
+            present: true,
+            ..Default::default()
+        };
+
+        // The upper half of the descriptor holds the upper 32 bits of the base address.
+        self.entries.push(segment.to_descriptor());
+        self.entries.push(base >> 32);
+
+        segment
+    }
+
+    /// Returns the GDT as bytes.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        self.entries.iter().flat_map(|entry| entry.to_le_bytes()).collect()
+    }
+
+    /// Writes the GDT to the given guest physical address and returns the [`DescriptorTable`]
+    /// describing it. Returns [`Error::UnmappedGuestAddress`] if the GDT is not backed by guest
+    /// memory.
+    pub fn write(&self, vm: &mut Vm, guest_address: u64) -> Result<DescriptorTable, Error> {
+        let bytes = self.to_bytes();
+
+        vm.write_all_physical_memory(guest_address, &bytes)?;
+
+        Ok(DescriptorTable {
+            base: guest_address,
+            limit: (bytes.len() - 1) as u16,
+        })
+    }
+}
+
+impl Default for GdtBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Describes an interrupt or trap gate of the interrupt descriptor table.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct Gate {
+    /// The address of the handler.
+    pub offset: u64,
+    /// The selector of the code segment of the handler.
+    pub selector: u16,
+    /// Whether this is a trap gate, which leaves interrupts enabled, rather than an interrupt
+    /// gate.
+    pub trap: bool,
+    /// The privilege level required to invoke the gate through the `int` instruction.
+    pub dpl: u8,
+    /// The index of the stack in the interrupt stack table to switch to, or zero to not switch
+    /// stacks. This is only used in long mode.
+    pub ist: u8,
+}
+
+/// The `IdtBuilder` assembles an interrupt descriptor table of 256 gates, where the gates that
+/// are not set are not present. The IDT is written to guest memory through
+/// [`IdtBuilder::write`], which returns the [`DescriptorTable`] to load through
+/// [`CpuRegs::set_descriptor_tables`].
+#[derive(Clone, Debug)]
+pub struct IdtBuilder {
+    /// Whether to use the 16-byte gates of long mode rather than the 8-byte gates of protected
+    /// mode.
+    long_mode: bool,
+    gates: Vec<Option<Gate>>,
+}
+
+impl IdtBuilder {
+    /// Creates an IDT with the 16-byte gates of 64-bit long mode.
+    pub fn long_mode() -> Self {
+        Self {
+            long_mode: true,
+            gates: vec![None; 256],
+        }
+    }
+
+    /// Creates an IDT with the 8-byte gates of 32-bit protected mode.
+    pub fn protected_mode() -> Self {
+        Self {
+            long_mode: false,
+            ..Self::long_mode()
+        }
+    }
+
+    /// Sets the gate for the given vector.
+    pub fn set_gate(&mut self, vector: u8, gate: Gate) {
+        self.gates[vector as usize] = Some(gate);
+    }
+
+    /// Returns the IDT as bytes.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = vec![];
+
+        for gate in &self.gates {
+            let (low, high) = match gate {
+                Some(gate) => {
+                    // The gate types are the same in both modes, where they are 32-bit gates in
+                    // protected mode and 64-bit gates in long mode.
+                    let gate_type = if gate.trap { 0xf } else { 0xe };
+                    let ist = if self.long_mode { gate.ist as u64 & 0x7 } else { 0 };
+
+                    let low = (gate.offset & 0xffff) |
+                        (gate.selector as u64) << 16 |
+                        ist << 32 |
+                        gate_type << 40 |
+                        (gate.dpl as u64 & 0x3) << 45 |
+                        1 << 47 |
+                        ((gate.offset >> 16) & 0xffff) << 48;
+
+                    (low, gate.offset >> 32)
+                }
+                _ => (0, 0),
+            };
+
+            bytes.extend_from_slice(&low.to_le_bytes());
+
+            if self.long_mode {
+                bytes.extend_from_slice(&high.to_le_bytes());
+            }
+        }
+
+        bytes
+    }
+
+    /// Writes the IDT to the given guest physical address and returns the [`DescriptorTable`]
+    /// describing it. Returns [`Error::UnmappedGuestAddress`] if the IDT is not backed by guest
+    /// memory.
+    pub fn write(&self, vm: &mut Vm, guest_address: u64) -> Result<DescriptorTable, Error> {
+        let bytes = self.to_bytes();
+
+        vm.write_all_physical_memory(guest_address, &bytes)?;
+
+        Ok(DescriptorTable {
+            base: guest_address,
+            limit: (bytes.len() - 1) as u16,
+        })
+    }
+}
+
+/// The time stamp counter.
+pub const MSR_IA32_TSC:            u32 = 0x0000_0010;
+
+/// The base address and state of the local APIC.
+pub const MSR_IA32_APIC_BASE:      u32 = 0x0000_001b;
+
+/// Indicates that the virtual CPU is the bootstrap processor.
+pub const APIC_BASE_BSP:     u64 = 1 << 8;
+/// Enables x2APIC mode.
+pub const APIC_BASE_X2APIC:  u64 = 1 << 10;
+/// Enables the local APIC.
+pub const APIC_BASE_ENABLE:  u64 = 1 << 11;
+/// The mask of the base address of the local APIC.
+pub const APIC_BASE_ADDRESS: u64 = 0x000f_ffff_ffff_f000;
+/// The default base address of the local APIC.
+pub const APIC_DEFAULT_BASE: u64 = 0xfee0_0000;
+/// The size of the MMIO range of the local APIC.
+pub const APIC_MMIO_SIZE:    u64 = 0x1000;
+
+/// Describes the value of the `IA32_APIC_BASE` MSR.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct ApicBase {
+    /// The guest physical address of the MMIO range of the local APIC.
+    pub address: u64,
+    /// Whether the local APIC is enabled.
+    pub enabled: bool,
+    /// Whether the local APIC is in x2APIC mode, in which case it is accessed through MSRs
+    /// rather than MMIO.
+    pub x2apic: bool,
+    /// Whether the virtual CPU is the bootstrap processor.
+    pub bsp: bool,
+}
+
+impl ApicBase {
+    /// Decodes the value of the `IA32_APIC_BASE` MSR.
+    pub fn from_msr(value: u64) -> Self {
+        Self {
+            address: value & APIC_BASE_ADDRESS,
+            enabled: value & APIC_BASE_ENABLE != 0,
+            x2apic: value & APIC_BASE_X2APIC != 0,
+            bsp: value & APIC_BASE_BSP != 0,
+        }
+    }
+
+    /// Encodes the value of the `IA32_APIC_BASE` MSR.
+    pub fn to_msr(&self) -> u64 {
+        let mut value = self.address & APIC_BASE_ADDRESS;
+
+        if self.enabled {
+            value |= APIC_BASE_ENABLE;
+        }
+
+        if self.x2apic {
+            value |= APIC_BASE_X2APIC;
+        }
+
+        if self.bsp {
+            value |= APIC_BASE_BSP;
+        }
+
+        value
+    }
+
+    /// Returns the guest physical address range the local APIC should be emulated at, or `None`
+    /// if the local APIC is disabled or in x2APIC mode.
+    pub fn mmio_range(&self) -> Option<Range<u64>> {
+        if !self.enabled || self.x2apic {
+            return None;
+        }
+
+        Some(self.address..self.address + APIC_MMIO_SIZE)
+    }
+}
+
+/// The base address of the SMRAM state save area.
+pub const MSR_IA32_SMBASE:         u32 = 0x0000_009e;
+
 /// The code segment to load when issuing the `sysenter` instruction.
 pub const MSR_IA32_SYSENTER_CS:    u32 = 0x0000_0174;
 /// The stack pointer to load when issuing the `sysenter` instruction.
@@ -218,6 +690,110 @@ pub const EFER_LMA: u64 = 1 << 10;
 /// Enables the non-executable bit.
 pub const EFER_NXE: u64 = 1 << 11;
 
+/// Indicates support for 5-level paging in `ecx` of CPUID leaf 7, subleaf 0.
+pub const CPUID_7_0_ECX_LA57: u32 = 1 << 16;
+
+/// The result of a `cpuid` instruction, which is used to complete an
+/// [`crate::ExitReason::Cpuid`] exit.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct CpuidResult {
+    /// The value returned in `eax`.
+    pub eax: u32,
+    /// The value returned in `ebx`.
+    pub ebx: u32,
+    /// The value returned in `ecx`.
+    pub ecx: u32,
+    /// The value returned in `edx`.
+    pub edx: u32,
+}
+
+/// An entry of the CPUID table of a virtual CPU, see [`crate::Vcpu::set_cpuid`].
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct CpuidEntry {
+    /// The leaf, i.e. the value of `eax`.
+    pub leaf: u32,
+    /// The subleaf, i.e. the value of `ecx`, which is ignored unless `indexed` is set.
+    pub subleaf: u32,
+    /// Whether the result depends on the subleaf.
+    pub indexed: bool,
+    /// The result of the `cpuid` instruction.
+    pub result: CpuidResult,
+}
+
+/// The CPUID leaves whose results depend on the subleaf.
+const CPUID_INDEXED_LEAVES: [u32; 10] = [0x4, 0x7, 0xb, 0xd, 0xf, 0x10, 0x12, 0x14, 0x17, 0x1f];
+
+/// Looks up the result for the given leaf and subleaf in the CPUID table. Returns `None` if the
+/// table does not have an entry for them.
+pub(crate) fn lookup_cpuid(entries: &[CpuidEntry], leaf: u32, subleaf: u32) -> Option<CpuidResult> {
+    entries
+        .iter()
+        .find(|entry| entry.leaf == leaf && (!entry.indexed || entry.subleaf == subleaf))
+        .map(|entry| entry.result)
+}
+
+/// Enumerates the CPUID of the host, for the platforms that serve the CPUID of the guest from a
+/// table maintained by this crate rather than by the hypervisor.
+pub(crate) fn host_cpuid() -> Vec<CpuidEntry> {
+    #[cfg(target_arch = "x86")]
+    use std::arch::x86::__cpuid_count;
+    #[cfg(target_arch = "x86_64")]
+    use std::arch::x86_64::__cpuid_count;
+
+    let cpuid = |leaf, subleaf| {
+        let result = unsafe { __cpuid_count(leaf, subleaf) };
+
+        CpuidResult {
+            eax: result.eax,
+            ebx: result.ebx,
+            ecx: result.ecx,
+            edx: result.edx,
+        }
+    };
+
+    let mut entries = vec![];
+
+    for base in [0, 0x8000_0000] {
+        let max = cpuid(base, 0).eax;
+
+        for leaf in base..=max {
+            if !CPUID_INDEXED_LEAVES.contains(&leaf) {
+                entries.push(CpuidEntry {
+                    leaf,
+                    subleaf: 0,
+                    indexed: false,
+                    result: cpuid(leaf, 0),
+                });
+
+                continue;
+            }
+
+            for subleaf in 0..64 {
+                let result = cpuid(leaf, subleaf);
+
+                // The topology leaves report the number of logical processors in `ebx`, which is
+                // zero for the subleaves past the last level, while the other registers may not
+                // be.
+                let valid = match leaf {
+                    0xb | 0x1f => result.ebx != 0,
+                    _ => result.eax | result.ebx | result.ecx | result.edx != 0,
+                };
+
+                if subleaf == 0 || valid {
+                    entries.push(CpuidEntry {
+                        leaf,
+                        subleaf,
+                        indexed: true,
+                        result,
+                    });
+                }
+            }
+        }
+    }
+
+    entries
+}
+
 /// The user segment base \[48:63\], the kernel segment base \[32:47\] and the syscall EIP
 /// \[0:31\].
 pub const MSR_IA32_STAR:           u32 = 0xc000_0081;
@@ -230,6 +806,176 @@ pub const MSR_IA32_CSTAR:          u32 = 0xc000_0083;
 pub const MSR_IA32_SYSCALL_MASK:   u32 = 0xc000_0084;
 /// The GS segment to swap when issuing the `swapgs` instruction.
 pub const MSR_IA32_KERNEL_GS_BASE: u32 = 0xc000_0102;
+/// The auxiliary value returned in `ecx` when issuing the `rdtscp` instruction.
+pub const MSR_IA32_TSC_AUX:        u32 = 0xc000_0103;
+
+/// Selects how the accesses to the MSRs selected by an MSR filter are handled, see
+/// [`crate::Vm::set_msr_filter`].
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum MsrPolicy {
+    /// The accesses to the selected MSRs exit to user space, while the accesses to any other MSR
+    /// are handled by the hypervisor.
+    Exit,
+    /// The accesses to the selected MSRs are handled by the hypervisor, while the accesses to any
+    /// other MSR exit to user space.
+    Native,
+}
+
+/// Selects the MSRs whose accesses exit to user space.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub(crate) struct MsrFilter {
+    /// The ranges of MSRs selected by the filter.
+    pub ranges: Vec<Range<u32>>,
+    /// How the accesses to the selected MSRs are handled.
+    pub policy: MsrPolicy,
+}
+
+impl MsrFilter {
+    /// Returns `true` if the accesses to the given MSR exit to user space.
+    pub fn exits(&self, msr: u32) -> bool {
+        let selected = self.ranges.iter().any(|range| range.contains(&msr));
+
+        selected == (self.policy == MsrPolicy::Exit)
+    }
+}
+
+/// Describes the System Management Mode (SMM) state of a virtual CPU.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct SmmState {
+    /// Whether the virtual CPU is currently in SMM.
+    pub active: bool,
+    /// Whether an SMI is pending, i.e. the virtual CPU enters SMM upon the next entry.
+    pub pending: bool,
+    /// Whether the virtual CPU entered SMM while handling an NMI.
+    pub inside_nmi: bool,
+    /// Whether an INIT was latched while in SMM, which is delivered upon leaving SMM.
+    pub latched_init: bool,
+}
+
+/// Describes the state of the x87 FPU and the SSE registers of the x86 architecture.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct FpuState {
+    /// The x87 FPU control word.
+    pub fcw: u16,
+    /// The x87 FPU status word.
+    pub fsw: u16,
+    /// The abridged x87 FPU tag word, where bit `n` is set if the physical register `n` is valid,
+    /// as used by `fxsave`.
+    pub ftw: u8,
+    /// The opcode of the last x87 FPU instruction.
+    pub last_opcode: u16,
+    /// The instruction pointer of the last x87 FPU instruction.
+    pub last_ip: u64,
+    /// The data pointer of the last x87 FPU instruction.
+    pub last_dp: u64,
+    /// The x87 FPU registers ST0 to ST7, i.e. the MMX registers, where the 80-bit values are
+    /// stored in the lower 10 bytes.
+    pub st: [[u8; 16]; 8],
+    /// The SSE control and status register.
+    pub mxcsr: u32,
+    /// The SSE registers XMM0 to XMM15.
+    pub xmm: [[u8; 16]; 16],
+}
+
+impl FpuState {
+    /// The size of the legacy region of the `fxsave` area that holds the state.
+    pub(crate) const FXSAVE_SIZE: usize = 416;
+
+    /// Decodes the state from the legacy region of the `fxsave` area in 64-bit format.
+    pub(crate) fn from_fxsave(bytes: &[u8]) -> Self {
+        let u16_at = |offset: usize| u16::from_le_bytes([bytes[offset], bytes[offset + 1]]);
+        let mut u64_bytes = [0u8; 8];
+
+        let mut state = Self {
+            fcw: u16_at(0),
+            fsw: u16_at(2),
+            ftw: bytes[4],
+            last_opcode: u16_at(6),
+            ..Default::default()
+        };
+
+        u64_bytes.copy_from_slice(&bytes[8..16]);
+        state.last_ip = u64::from_le_bytes(u64_bytes);
+        u64_bytes.copy_from_slice(&bytes[16..24]);
+        state.last_dp = u64::from_le_bytes(u64_bytes);
+        state.mxcsr = u32::from_le_bytes([bytes[24], bytes[25], bytes[26], bytes[27]]);
+
+        for (index, register) in state.st.iter_mut().enumerate() {
+            register.copy_from_slice(&bytes[32 + index * 16..48 + index * 16]);
+        }
+
+        for (index, register) in state.xmm.iter_mut().enumerate() {
+            register.copy_from_slice(&bytes[160 + index * 16..176 + index * 16]);
+        }
+
+        state
+    }
+
+    /// Encodes the state into the legacy region of the `fxsave` area in 64-bit format, leaving
+    /// the reserved bytes untouched.
+    pub(crate) fn to_fxsave(&self, bytes: &mut [u8]) {
+        bytes[0..2].copy_from_slice(&self.fcw.to_le_bytes());
+        bytes[2..4].copy_from_slice(&self.fsw.to_le_bytes());
+        bytes[4] = self.ftw;
+        bytes[6..8].copy_from_slice(&self.last_opcode.to_le_bytes());
+        bytes[8..16].copy_from_slice(&self.last_ip.to_le_bytes());
+        bytes[16..24].copy_from_slice(&self.last_dp.to_le_bytes());
+        bytes[24..28].copy_from_slice(&self.mxcsr.to_le_bytes());
+
+        for (index, register) in self.st.iter().enumerate() {
+            bytes[32 + index * 16..48 + index * 16].copy_from_slice(register);
+        }
+
+        for (index, register) in self.xmm.iter().enumerate() {
+            bytes[160 + index * 16..176 + index * 16].copy_from_slice(register);
+        }
+    }
+}
+
+/// Describes an exception that is injected into the virtual CPU upon the next entry.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct PendingException {
+    /// The vector of the exception.
+    pub vector: u8,
+    /// The error code of the exception, if the exception pushes one.
+    pub error_code: Option<u32>,
+}
+
+/// Describes the events of a virtual CPU that are pending delivery or that affect the delivery of
+/// interrupts, which are part of the state of the virtual CPU.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct PendingEvents {
+    /// The exception that is injected upon the next entry, if any.
+    pub exception: Option<PendingException>,
+    /// The vector of the external interrupt that is injected upon the next entry, if any.
+    pub interrupt: Option<u8>,
+    /// The vectors of the external interrupts that were injected through
+    /// [`crate::Vcpu::inject_interrupt`] and that are queued until the guest is able to accept
+    /// them, in the order in which they are delivered. This is empty where the local APIC of the
+    /// hypervisor queues them instead.
+    pub queued_interrupts: Vec<u8>,
+    /// Whether an NMI is pending.
+    pub nmi_pending: bool,
+    /// Whether NMIs are blocked, i.e. the virtual CPU is handling an NMI.
+    pub nmi_masked: bool,
+    /// Whether interrupts are inhibited for one instruction, i.e. after `sti` or `mov ss`.
+    pub interrupt_shadow: bool,
+}
+
+/// The Hyper-V synthetic interrupt controller control MSR.
+pub const HV_X64_MSR_SCONTROL:     u32 = 0x4000_0080;
+/// The Hyper-V synthetic interrupt controller version MSR.
+pub const HV_X64_MSR_SVERSION:     u32 = 0x4000_0081;
+/// The guest physical address of the Hyper-V synthetic interrupt event flags page.
+pub const HV_X64_MSR_SIEFP:        u32 = 0x4000_0082;
+/// The guest physical address of the Hyper-V synthetic interrupt message page.
+pub const HV_X64_MSR_SIMP:         u32 = 0x4000_0083;
+/// The Hyper-V end-of-message MSR.
+pub const HV_X64_MSR_EOM:          u32 = 0x4000_0084;
+/// The first of the sixteen Hyper-V synthetic interrupt source MSRs.
+pub const HV_X64_MSR_SINT0:        u32 = 0x4000_0090;
+/// The last of the sixteen Hyper-V synthetic interrupt source MSRs.
+pub const HV_X64_MSR_SINT15:       u32 = 0x4000_009f;
 
 /// Extends the virtual CPU with functions to access the architecture-specific registers.
 pub trait CpuRegs {
@@ -302,6 +1048,26 @@ pub trait CpuRegs {
         registers: &[DescriptorTableRegister],
         values: &[DescriptorTable],
     ) -> Result<(), Error>;
+
+    /// Gets the debug registers specified by the array of [`DebugRegister`]s.
+    fn get_debug_registers(
+        &self,
+        registers: &[DebugRegister],
+    ) -> Result<Vec<u64>, Error>;
+
+    /// Sets the debug registers specified by the array of [`DebugRegister`]s to the
+    /// corresponding values.
+    fn set_debug_registers(
+        &mut self,
+        registers: &[DebugRegister],
+        values: &[u64],
+    ) -> Result<(), Error>;
+
+    /// Gets the state of the x87 FPU and the SSE registers.
+    fn get_fpu(&self) -> Result<FpuState, Error>;
+
+    /// Sets the state of the x87 FPU and the SSE registers.
+    fn set_fpu(&mut self, state: &FpuState) -> Result<(), Error>;
 }
 
 bitflags! {
@@ -364,6 +1130,10 @@ pub enum Vmcs {
     GuestLdtr             = 0x0000_080c,
     /// The task register of the guest.
     GuestTr               = 0x0000_080e,
+    /// The offset added to the TSC of the host when the guest reads the TSC.
+    TscOffset             = 0x0000_2010,
+    /// The address of the virtual-APIC page.
+    VirtualApicAddress    = 0x0000_2012,
     /// The guest physical address that caused an EPT violation.
     GuestPhysicalAddress  = 0x0000_2400,
     /// The EFER MSR of the guest.
@@ -372,14 +1142,33 @@ pub enum Vmcs {
     PinBased              = 0x0000_4000,
     /// CPU-based controls.
     CpuBased              = 0x0000_4002,
+    /// The exceptions that cause a VM exit.
+    ExceptionBitmap       = 0x0000_4004,
     /// VM exit controls.
     VmExitControls        = 0x0000_400c,
     /// VM entry controls.
     VmEntryControls       = 0x0000_4012,
+    /// The interruption information of the event that is injected upon VM entry.
+    EntryInterruptionInfo = 0x0000_4016,
+    /// The error code of the exception that is injected upon VM entry.
+    EntryErrorCode        = 0x0000_4018,
+    /// The TPR threshold below which writes to the TPR cause a VM exit.
+    TprThreshold          = 0x0000_401c,
     /// Secondary CPU-based controls.
     CpuBased2             = 0x0000_401e,
     /// The reason for the VM exit.
     ExitReason            = 0x0000_4402,
+    /// The interruption information of the exception or interrupt that caused the VM exit.
+    ExitInterruptionInfo  = 0x0000_4404,
+    /// The error code of the exception that caused the VM exit.
+    ExitInterruptionError = 0x0000_4406,
+    /// The interruption information of the event that was being delivered when the VM exit
+    /// occurred.
+    IdtVectoringInfo      = 0x0000_4408,
+    /// The error code of the exception that was being delivered when the VM exit occurred.
+    IdtVectoringError     = 0x0000_440a,
+    /// The length of the instruction that caused the VM exit.
+    ExitInstructionLength = 0x0000_440c,
     /// The ES limit of the guest.
     GuestEsLimit          = 0x0000_4800,
     /// The code segment limit of the guest.
@@ -416,10 +1205,14 @@ pub enum Vmcs {
     GuestLdtrAccessRights = 0x0000_4820,
     /// The TR access rights of the guest.
     GuestTrAccessRights   = 0x0000_4822,
+    /// The interruptibility state of the guest.
+    GuestInterruptibility = 0x0000_4824,
     Cr0Mask               = 0x0000_6000,
     Cr4Mask               = 0x0000_6002,
     Cr0Shadow             = 0x0000_6004,
     Cr4Shadow             = 0x0000_6006,
+    /// The exit qualification of the VM exit.
+    ExitQualification     = 0x0000_6400,
     GuestLinearAddress    = 0x0000_640a,
     /// The CR0 register of the guest.
     GuestCr0              = 0x0000_6800,
@@ -514,3 +1307,67 @@ pub enum VmxReason {
     Xsaves            = 63,
     Xrstors           = 64,
 }
+
+/// The exit codes of AMD SVM, i.e. the `EXITCODE` field of the VMCB, which correspond to the exit
+/// reasons of [`VmxReason`] on Intel VMX. The exit codes 0x40 to 0x5f are the exceptions, where
+/// the vector is the exit code minus [`SvmExitCode::Exception`].
+#[cfg(target_arch = "x86_64")]
+#[derive(Copy, Clone, Debug, Eq, FromPrimitive, PartialEq)]
+#[repr(u64)]
+pub enum SvmExitCode {
+    Exception         = 0x040,
+    Intr              = 0x060,
+    Nmi               = 0x061,
+    Smi               = 0x062,
+    Init              = 0x063,
+    Vintr             = 0x064,
+    Cr0SelWrite       = 0x065,
+    Rdtsc             = 0x06e,
+    Rdpmc             = 0x06f,
+    Cpuid             = 0x072,
+    Rsm               = 0x073,
+    Iret              = 0x074,
+    Swint             = 0x075,
+    Invd              = 0x076,
+    Pause             = 0x077,
+    Hlt               = 0x078,
+    Invlpg            = 0x079,
+    Invlpga           = 0x07a,
+    IoIo              = 0x07b,
+    Msr               = 0x07c,
+    TaskSwitch        = 0x07d,
+    FerrFreeze        = 0x07e,
+    /// A triple fault, after which the state in the VMCB is undefined.
+    Shutdown          = 0x07f,
+    Vmrun             = 0x080,
+    Vmmcall           = 0x081,
+    Vmload            = 0x082,
+    Vmsave            = 0x083,
+    Stgi              = 0x084,
+    Clgi              = 0x085,
+    Skinit            = 0x086,
+    Rdtscp            = 0x087,
+    Icebp             = 0x088,
+    Wbinvd            = 0x089,
+    Monitor           = 0x08a,
+    Mwait             = 0x08b,
+    Xsetbv            = 0x08d,
+    /// A nested page fault, which corresponds to [`VmxReason::EptViolation`].
+    Npf               = 0x400,
+    AvicIncompleteIpi = 0x401,
+    AvicNoAccel       = 0x402,
+    /// The VMRUN instruction failed, which corresponds to [`VmxReason::VmEntryGuest`].
+    Invalid           = u64::MAX,
+}
+
+#[cfg(target_arch = "x86_64")]
+impl SvmExitCode {
+    /// Decodes the given exit code, where all the exception exit codes map to
+    /// [`SvmExitCode::Exception`].
+    pub fn from_exit_code(code: u64) -> Option<Self> {
+        match code {
+            0x40..=0x5f => Some(Self::Exception),
+            _ => num_traits::FromPrimitive::from_u64(code),
+        }
+    }
+}
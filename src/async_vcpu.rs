@@ -0,0 +1,285 @@
+//! This module provides [`AsyncVcpu`], which runs a virtual CPU on a dedicated thread and exposes
+//! it through futures, such that many virtual CPUs and the device I/O can be driven from a single
+//! async runtime, e.g. tokio. The futures do not depend on any particular runtime.
+//!
+//! As some platforms, e.g. the Hypervisor Framework on Mac OS X, require a virtual CPU to be
+//! created and run on the same thread, the virtual CPU is created from a [`VcpuFactory`] on the
+//! dedicated thread. All accesses to the virtual CPU are then executed on that thread through
+//! [`AsyncVcpu::call`] and [`AsyncVcpu::run_async`].
+
+use crate::error::Error;
+use crate::vcpu::{ExitReason, Vcpu, VcpuFactory, VcpuHandle};
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Sender};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, Waker};
+use std::thread::JoinHandle;
+
+/// A request that is executed on the thread running the virtual CPU.
+type Request = Box<dyn FnOnce(&mut Vcpu) + Send>;
+
+/// The state shared between a [`VcpuFuture`] and the thread running the virtual CPU.
+struct Shared<T> {
+    /// The result of the request, until the future takes it.
+    result: Option<Result<T, Error>>,
+    /// Whether the request completed.
+    completed: bool,
+    /// Whether the thread running the virtual CPU started executing the request.
+    running: bool,
+    /// Whether the future was dropped before the request completed.
+    canceled: bool,
+    /// The waker of the task that last polled the future.
+    waker: Option<Waker>,
+}
+
+/// Completes a [`VcpuFuture`] from the thread running the virtual CPU. If the request is dropped
+/// without being executed, e.g. because the thread terminated, the future resolves with
+/// [`Error::VcpuThreadTerminated`] instead.
+struct Completion<T> {
+    shared: Arc<Mutex<Shared<T>>>,
+}
+
+impl<T> Completion<T> {
+    /// Marks the request as running. Returns `false` if the future was dropped while the request
+    /// was queued, in which case the request is skipped.
+    fn start(&self) -> bool {
+        let mut shared = self.shared.lock().unwrap();
+
+        if shared.canceled {
+            shared.completed = true;
+            return false;
+        }
+
+        shared.running = true;
+
+        true
+    }
+
+    /// Stores the result and wakes the task polling the future. Returns whether the future was
+    /// dropped in the meantime.
+    fn complete(&self, result: Result<T, Error>) -> bool {
+        let mut shared = self.shared.lock().unwrap();
+
+        shared.result = Some(result);
+        shared.completed = true;
+
+        let canceled = shared.canceled;
+        let waker = shared.waker.take();
+
+        drop(shared);
+
+        if let Some(waker) = waker {
+            waker.wake();
+        }
+
+        canceled
+    }
+}
+
+impl<T> Drop for Completion<T> {
+    fn drop(&mut self) {
+        let completed = self.shared.lock().unwrap().completed;
+
+        if !completed {
+            self.complete(Err(Error::VcpuThreadTerminated));
+        }
+    }
+}
+
+/// A future that resolves with the result of a request executed on the thread running the virtual
+/// CPU, see [`AsyncVcpu::call`] and [`AsyncVcpu::run_async`].
+pub struct VcpuFuture<T> {
+    shared: Arc<Mutex<Shared<T>>>,
+    /// The handle to kick the virtual CPU if the future is dropped while running it.
+    kick: Option<VcpuHandle>,
+}
+
+impl<T> Future for VcpuFuture<T> {
+    type Output = Result<T, Error>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let mut shared = self.shared.lock().unwrap();
+
+        match shared.result.take() {
+            Some(result) => Poll::Ready(result),
+            _ => {
+                shared.waker = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+}
+
+impl<T> Drop for VcpuFuture<T> {
+    fn drop(&mut self) {
+        let mut shared = self.shared.lock().unwrap();
+
+        if shared.completed {
+            return;
+        }
+
+        shared.canceled = true;
+
+        // A queued request is skipped once the thread gets to it. Otherwise, force the virtual CPU
+        // to exit, such that the thread becomes available promptly. Hold the lock while kicking,
+        // such that the thread discards the kick if it completes the request without observing
+        // it.
+        if !shared.running {
+            return;
+        }
+
+        if let Some(handle) = &self.kick {
+            let _ = handle.kick();
+        }
+    }
+}
+
+/// The `AsyncVcpu` struct runs a virtual CPU on a dedicated thread and exposes it through futures.
+pub struct AsyncVcpu {
+    /// The channel to send the requests to the thread running the virtual CPU.
+    sender: Option<Sender<Request>>,
+    /// The handle to kick the virtual CPU.
+    handle: VcpuHandle,
+    /// Whether the `AsyncVcpu` was dropped, in which case the thread skips the pending requests.
+    closed: Arc<AtomicBool>,
+    /// The thread running the virtual CPU.
+    thread: Option<JoinHandle<()>>,
+}
+
+impl AsyncVcpu {
+    /// Spawns a dedicated thread and creates the virtual CPU described by the given
+    /// [`VcpuFactory`] on that thread.
+    pub fn new(factory: VcpuFactory) -> Result<Self, Error> {
+        let (sender, receiver) = mpsc::channel::<Request>();
+        let (created, result) = mpsc::channel();
+        let closed = Arc::new(AtomicBool::new(false));
+        let thread_closed = closed.clone();
+
+        let thread = std::thread::Builder::new()
+            .name(format!("hy-rs-vcpu-{}", factory.spec().id))
+            .spawn(move || {
+                let mut vcpu = match factory.create() {
+                    Ok(vcpu) => vcpu,
+                    Err(e) => {
+                        let _ = created.send(Err(e));
+                        return;
+                    }
+                };
+
+                let _ = created.send(Ok(vcpu.handle()));
+
+                // Execute the requests until the `AsyncVcpu` is dropped. The pending requests are
+                // dropped instead, which resolves their futures with an error.
+                for request in receiver {
+                    if thread_closed.load(Ordering::SeqCst) {
+                        continue;
+                    }
+
+                    request(&mut vcpu);
+                }
+            })?;
+
+        let handle = result.recv().map_err(|_| Error::VcpuThreadTerminated)??;
+
+        Ok(Self {
+            sender: Some(sender),
+            handle,
+            closed,
+            thread: Some(thread),
+        })
+    }
+
+    /// Returns a handle that can be shared with other threads to force the virtual CPU to exit,
+    /// see [`VcpuHandle`].
+    pub fn handle(&self) -> VcpuHandle {
+        self.handle.clone()
+    }
+
+    /// Executes the given function on the thread running the virtual CPU, e.g. to access the
+    /// registers of the virtual CPU. The requests are executed in the order in which they are
+    /// made, such that this waits for any pending call to [`AsyncVcpu::run_async`] to complete.
+    pub fn call<F, T>(&self, f: F) -> VcpuFuture<T>
+    where
+        F: FnOnce(&mut Vcpu) -> Result<T, Error> + Send + 'static,
+        T: Send + 'static,
+    {
+        self.submit(None, f)
+    }
+
+    /// Runs the virtual CPU on its thread until the next exit point, see [`Vcpu::run`]. As the
+    /// [`ExitReason`] borrows the virtual CPU, it is passed to the given handler on the thread
+    /// running the virtual CPU, e.g. to fill in the data of [`ExitReason::IoIn`]. The returned
+    /// future resolves with the result of the handler.
+    ///
+    /// Dropping the future before it resolves cancels the run by kicking the virtual CPU, see
+    /// [`VcpuHandle::kick`]. The handler is still called for the exit that ends the run, which is
+    /// usually [`ExitReason::Canceled`]. If the run has not started yet, e.g. because of pending
+    /// calls, it is skipped instead.
+    pub fn run_async<F, T>(&self, handler: F) -> VcpuFuture<T>
+    where
+        F: FnOnce(ExitReason) -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        self.submit(Some(self.handle.clone()), move |vcpu| vcpu.run().map(handler))
+    }
+
+    /// Helper function to send the given function to the thread running the virtual CPU. The
+    /// virtual CPU is kicked through the given handle if the future is dropped before the function
+    /// completes.
+    fn submit<F, T>(&self, kick: Option<VcpuHandle>, f: F) -> VcpuFuture<T>
+    where
+        F: FnOnce(&mut Vcpu) -> Result<T, Error> + Send + 'static,
+        T: Send + 'static,
+    {
+        let shared = Arc::new(Mutex::new(Shared {
+            result: None,
+            completed: false,
+            running: false,
+            canceled: false,
+            waker: None,
+        }));
+
+        let completion = Completion {
+            shared: shared.clone(),
+        };
+
+        let request: Request = Box::new(move |vcpu| {
+            if !completion.start() {
+                return;
+            }
+
+            // Discard the kick of a canceled run if the virtual CPU exited for another reason
+            // before observing it, such that it does not cancel the next run.
+            if completion.complete(f(vcpu)) {
+                vcpu.handle().inner.clear_kick();
+            }
+        });
+
+        // If the thread terminated, the request is dropped and the future resolves with an error.
+        if let Some(sender) = &self.sender {
+            let _ = sender.send(request);
+        }
+
+        VcpuFuture {
+            shared,
+            kick,
+        }
+    }
+}
+
+impl Drop for AsyncVcpu {
+    fn drop(&mut self) {
+        // Skip the pending requests, stop the thread and force the virtual CPU to exit if it is
+        // running. The kick is observed by the current request, or by the next call to
+        // `Vcpu::run` if the thread is about to run the virtual CPU.
+        self.closed.store(true, Ordering::SeqCst);
+        self.sender = None;
+        let _ = self.handle.kick();
+
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
@@ -0,0 +1,142 @@
+//! This module provides [`Balloon`], the host side of a memory balloon device such as
+//! virtio-balloon. The guest releases memory to the balloon by reporting the frame numbers of the
+//! pages that it no longer uses, after which the host pages backing them are reclaimed through
+//! [`Vm::reclaim`]. The guest takes the memory back by deflating the balloon, after which the
+//! host pages are repopulated through [`Vm::reinflate`].
+//!
+//! The guest reports the memory in pages of [`BALLOON_PAGE_SIZE`] bytes, which are coalesced into
+//! ranges. As the host pages may be larger, only the host pages that are fully covered by the
+//! balloon are reclaimed.
+//!
+//! This is not supported on FreeBSD.
+
+use crate::error::Error;
+use crate::vm::Vm;
+use mmap_rs::MmapOptions;
+use rangemap::RangeMap;
+use std::ops::Range;
+
+/// The size of the pages that the guest reports, which matches the page frame numbers used by
+/// virtio-balloon.
+pub const BALLOON_PAGE_SIZE: u64 = 4096;
+
+/// Tracks the guest physical memory that the guest released to a memory balloon device, see the
+/// [module-level documentation](self).
+pub struct Balloon<'a> {
+    vm: Vm<'a>,
+    /// The guest physical memory in the balloon.
+    ranges: RangeMap<u64, ()>,
+}
+
+impl<'a> Balloon<'a> {
+    /// Creates an empty balloon for the given VM.
+    pub fn new(vm: Vm<'a>) -> Self {
+        Self {
+            vm,
+            ranges: RangeMap::new(),
+        }
+    }
+
+    /// Inflates the balloon by the pages with the given frame numbers, as reported by the guest
+    /// on the inflate queue, and reclaims the host pages that are fully covered by the balloon.
+    pub fn inflate<I>(&mut self, frames: I) -> Result<(), Error>
+    where
+        I: IntoIterator<Item = u64>,
+    {
+        for range in coalesce(frames) {
+            self.ranges.insert(range.clone(), ());
+
+            // Reclaim the whole coalesced range, as the pages inflated earlier may complete a host
+            // page.
+            let range = match self.ranges.get_key_value(&range.start) {
+                Some((range, _)) => range.clone(),
+                _ => continue,
+            };
+
+            let page_size = host_page_size();
+            let start = (range.start + page_size - 1) & !(page_size - 1);
+            let end = range.end & !(page_size - 1);
+
+            if start < end {
+                self.for_each_region(start..end, |vm, range| vm.reclaim(range))?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Deflates the balloon by the pages with the given frame numbers, as reported by the guest
+    /// on the deflate queue, and repopulates the host pages backing them.
+    pub fn deflate<I>(&mut self, frames: I) -> Result<(), Error>
+    where
+        I: IntoIterator<Item = u64>,
+    {
+        for range in coalesce(frames) {
+            self.ranges.remove(range.clone());
+
+            let page_size = host_page_size();
+            let start = range.start & !(page_size - 1);
+            let end = (range.end + page_size - 1) & !(page_size - 1);
+
+            self.for_each_region(start..end, |vm, range| vm.reinflate(range))?;
+        }
+
+        Ok(())
+    }
+
+    /// Returns the size of the balloon in bytes.
+    pub fn size(&self) -> u64 {
+        self.ranges
+            .iter()
+            .map(|(range, _)| range.end - range.start)
+            .sum()
+    }
+
+    /// Returns whether the page at the given guest address is in the balloon.
+    pub fn contains(&self, guest_address: u64) -> bool {
+        self.ranges.get(&guest_address).is_some()
+    }
+
+    /// Helper function to call the given function for the parts of the given range that overlap
+    /// with the regions of guest physical memory, as [`Vm::reclaim`] and [`Vm::reinflate`] only
+    /// operate on a single region.
+    fn for_each_region<F>(&self, range: Range<u64>, mut f: F) -> Result<(), Error>
+    where
+        F: FnMut(&Vm<'a>, Range<u64>) -> Result<(), Error>,
+    {
+        for (region, _, _) in self.vm.regions()? {
+            let start = range.start.max(region.start);
+            let end = range.end.min(region.end);
+
+            if start < end {
+                f(&self.vm, start..end)?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Helper function to return the host page size.
+fn host_page_size() -> u64 {
+    MmapOptions::page_size().1 as u64
+}
+
+/// Helper function to coalesce the given frame numbers into ranges of guest physical addresses.
+fn coalesce<I>(frames: I) -> Vec<Range<u64>>
+where
+    I: IntoIterator<Item = u64>,
+{
+    let mut ranges: Vec<Range<u64>> = vec![];
+
+    for frame in frames {
+        let address = frame * BALLOON_PAGE_SIZE;
+
+        match ranges.last_mut() {
+            Some(range) if range.end == address => range.end += BALLOON_PAGE_SIZE,
+            _ => ranges.push(address..address + BALLOON_PAGE_SIZE),
+        }
+    }
+
+    ranges
+}
@@ -0,0 +1,199 @@
+//! This module provides the buses that route the port I/O and MMIO accesses of the guest to the
+//! devices emulated by the VMM, i.e. [`PioBus`] and [`MmioBus`]. A device implements the
+//! [`Device`] trait and is registered for a range of I/O ports or guest physical addresses, after
+//! which it receives the accesses to that range with the offset into the range.
+//!
+//! Every [`crate::Vm`] has a bus of each kind, where the devices are registered through
+//! [`crate::Vm::register_pio_device`] and [`crate::Vm::register_mmio_device`], and
+//! [`crate::Vm::dispatch_exit`] routes the [`crate::ExitReason::IoIn`],
+//! [`crate::ExitReason::IoOut`], [`crate::ExitReason::MmioRead`] and
+//! [`crate::ExitReason::MmioWrite`] exits to the registered devices. The buses are shared with
+//! all the clones of the VM, such that the threads running the virtual CPUs can dispatch their
+//! exits concurrently, where every device is locked for the duration of the access.
+
+use crate::error::Error;
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+
+/// A device that is registered on a [`PioBus`] or [`MmioBus`].
+pub trait Device: Send {
+    /// Handles a read of the size of the given data at the given offset into the range the device
+    /// is registered for, by filling the given data.
+    fn read(&mut self, offset: u64, data: &mut [u8]) -> Result<(), Error>;
+
+    /// Handles a write of the given data at the given offset into the range the device is
+    /// registered for.
+    fn write(&mut self, offset: u64, data: &[u8]) -> Result<(), Error>;
+}
+
+/// A device that can be registered on a [`PioBus`] or [`MmioBus`], which may be shared with the
+/// VMM, e.g. to feed input to the device.
+pub type SharedDevice = Arc<Mutex<dyn Device>>;
+
+/// The ranges of a bus and the devices they are routed to.
+#[derive(Clone, Default)]
+struct Bus {
+    /// The size of the range and the device by the start of the range.
+    ranges: BTreeMap<u64, (u64, SharedDevice)>,
+}
+
+impl Bus {
+    /// Registers the device for the given range. Returns [`Error::InvalidArgument`] if the range
+    /// is empty or overlaps with the range of another device.
+    fn insert(&mut self, base: u64, size: u64, device: SharedDevice) -> Result<(), Error> {
+        let end = match base.checked_add(size) {
+            Some(end) if size > 0 => end,
+            _ => return Err(Error::InvalidArgument),
+        };
+
+        // Only the last range that starts before the end of the new range can overlap with it.
+        if let Some((start, (size, _))) = self.ranges.range(..end).next_back() {
+            if start + size > base {
+                return Err(Error::InvalidArgument);
+            }
+        }
+
+        self.ranges.insert(base, (size, device));
+
+        Ok(())
+    }
+
+    /// Removes the device registered for the range starting at the given base, if any.
+    fn remove(&mut self, base: u64) -> Option<SharedDevice> {
+        self.ranges
+            .remove(&base)
+            .map(|(_, device)| device)
+    }
+
+    /// Looks up the device for the given address, and returns it along with the offset of the
+    /// address into its range.
+    fn get(&self, address: u64) -> Option<(SharedDevice, u64)> {
+        let (start, (size, device)) = self.ranges.range(..=address).next_back()?;
+        let offset = address - start;
+
+        match offset < *size {
+            true => Some((device.clone(), offset)),
+            _ => None,
+        }
+    }
+
+}
+
+/// Helper function to route a read to the given device, if any. Returns whether there is a
+/// device.
+fn read_device(entry: Option<(SharedDevice, u64)>, data: &mut [u8]) -> Result<bool, Error> {
+    let (device, offset) = match entry {
+        Some(entry) => entry,
+        _ => return Ok(false),
+    };
+
+    device.lock().unwrap().read(offset, data)?;
+
+    Ok(true)
+}
+
+/// Helper function to route a write to the given device, if any. Returns whether there is a
+/// device.
+fn write_device(entry: Option<(SharedDevice, u64)>, data: &[u8]) -> Result<bool, Error> {
+    let (device, offset) = match entry {
+        Some(entry) => entry,
+        _ => return Ok(false),
+    };
+
+    device.lock().unwrap().write(offset, data)?;
+
+    Ok(true)
+}
+
+/// The bus that routes the port I/O of the guest to the registered devices, see the
+/// [module-level documentation](self).
+#[derive(Clone, Default)]
+pub struct PioBus {
+    bus: Bus,
+}
+
+impl PioBus {
+    /// Creates an empty bus.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Registers the device for the given number of I/O ports starting at the given port. Returns
+    /// [`Error::InvalidArgument`] if the range is empty, exceeds the I/O port space, or overlaps
+    /// with the range of another device.
+    pub fn insert(&mut self, port: u16, count: u16, device: SharedDevice) -> Result<(), Error> {
+        if port as u32 + count as u32 > 0x10000 {
+            return Err(Error::InvalidArgument);
+        }
+
+        self.bus.insert(port as u64, count as u64, device)
+    }
+
+    /// Removes the device registered for the range starting at the given port, and returns it.
+    pub fn remove(&mut self, port: u16) -> Option<SharedDevice> {
+        self.bus.remove(port as u64)
+    }
+
+    /// Returns the device registered for the given port, along with the offset of the port into
+    /// the range of the device.
+    pub fn get(&self, port: u16) -> Option<(SharedDevice, u64)> {
+        self.bus.get(port as u64)
+    }
+
+    /// Routes an `in` instruction on the given port to the registered device. Returns whether
+    /// there is a device for the port.
+    pub fn read(&self, port: u16, data: &mut [u8]) -> Result<bool, Error> {
+        read_device(self.get(port), data)
+    }
+
+    /// Routes an `out` instruction on the given port to the registered device. Returns whether
+    /// there is a device for the port.
+    pub fn write(&self, port: u16, data: &[u8]) -> Result<bool, Error> {
+        write_device(self.get(port), data)
+    }
+}
+
+/// The bus that routes the MMIO accesses of the guest to the registered devices, see the
+/// [module-level documentation](self).
+#[derive(Clone, Default)]
+pub struct MmioBus {
+    bus: Bus,
+}
+
+impl MmioBus {
+    /// Creates an empty bus.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Registers the device for the given number of bytes starting at the given guest physical
+    /// address. Returns [`Error::InvalidArgument`] if the range is empty, wraps around the address
+    /// space, or overlaps with the range of another device.
+    pub fn insert(&mut self, address: u64, size: u64, device: SharedDevice) -> Result<(), Error> {
+        self.bus.insert(address, size, device)
+    }
+
+    /// Removes the device registered for the range starting at the given guest physical address,
+    /// and returns it.
+    pub fn remove(&mut self, address: u64) -> Option<SharedDevice> {
+        self.bus.remove(address)
+    }
+
+    /// Returns the device registered for the given guest physical address, along with the offset
+    /// of the address into the range of the device.
+    pub fn get(&self, address: u64) -> Option<(SharedDevice, u64)> {
+        self.bus.get(address)
+    }
+
+    /// Routes an MMIO read at the given guest physical address to the registered device. Returns
+    /// whether there is a device for the address.
+    pub fn read(&self, address: u64, data: &mut [u8]) -> Result<bool, Error> {
+        read_device(self.get(address), data)
+    }
+
+    /// Routes an MMIO write at the given guest physical address to the registered device. Returns
+    /// whether there is a device for the address.
+    pub fn write(&self, address: u64, data: &[u8]) -> Result<bool, Error> {
+        write_device(self.get(address), data)
+    }
+}
@@ -0,0 +1,223 @@
+//! This module provides the traits to access guest memory as typed objects through
+//! [`crate::Vm::read_obj`] and [`crate::Vm::write_obj`], rather than slicing bytes by hand, e.g.
+//! to build boot parameter blocks or to parse the structures set up by the guest.
+//!
+//! [`FromBytes`] marks the types that can be created from any sequence of bytes, while
+//! [`AsBytes`] marks the types that can be viewed as a sequence of bytes. Both are implemented for
+//! the primitive integer types and arrays thereof, and can be implemented for `#[repr(C)]` or
+//! `#[repr(transparent)]` structs whose fields implement them.
+//!
+//! The objects are copied from and to guest memory, so the guest address does not have to be
+//! aligned to the alignment of the type. The integers are stored in the byte order of the host,
+//! so use [`Le`] and [`Be`] for the fields whose byte order is fixed by the guest, e.g. [`Le`]
+//! for the structures of the x86 boot protocol.
+//!
+//! [`Atomic`] marks the integer types that can be accessed atomically through
+//! [`crate::Vm::load_atomic`], [`crate::Vm::store_atomic`] and
+//! [`crate::Vm::compare_exchange_atomic`], e.g. to access the lock-free data structures shared
+//! with the guest, such as virtio rings and spinlocks.
+
+use std::fmt;
+use std::sync::atomic::{
+    AtomicI16, AtomicI32, AtomicI64, AtomicI8, AtomicIsize, AtomicU16, AtomicU32, AtomicU64,
+    AtomicU8, AtomicUsize, Ordering,
+};
+
+/// Types that can be created from any sequence of bytes of the size of the type.
+///
+/// # Safety
+///
+/// Any bit pattern must be a valid value of the type, so the type must not contain any
+/// references, pointers, `bool`, `char` or enums.
+pub unsafe trait FromBytes: Copy {}
+
+/// Types that can be viewed as a sequence of bytes of the size of the type.
+///
+/// # Safety
+///
+/// The type must not contain any padding, as the padding bytes are uninitialized.
+pub unsafe trait AsBytes: Copy {}
+
+/// Helper function to view the given object as bytes.
+pub(crate) fn as_bytes<T: AsBytes>(value: &T) -> &[u8] {
+    unsafe {
+        std::slice::from_raw_parts(value as *const T as *const u8, std::mem::size_of::<T>())
+    }
+}
+
+/// Helper function to create an object from the given bytes, which must be of the size of the
+/// type.
+pub(crate) fn from_bytes<T: FromBytes>(bytes: &[u8]) -> T {
+    assert_eq!(bytes.len(), std::mem::size_of::<T>());
+
+    unsafe {
+        std::ptr::read_unaligned(bytes.as_ptr() as *const T)
+    }
+}
+
+/// The integer types that can be converted from and to a fixed byte order, see [`Le`] and
+/// [`Be`].
+pub trait Endian: FromBytes + AsBytes {
+    /// Converts the integer from the byte order of the host to little endian.
+    fn to_le(self) -> Self;
+    /// Converts the integer from little endian to the byte order of the host.
+    fn from_le(value: Self) -> Self;
+    /// Converts the integer from the byte order of the host to big endian.
+    fn to_be(self) -> Self;
+    /// Converts the integer from big endian to the byte order of the host.
+    fn from_be(value: Self) -> Self;
+}
+
+macro_rules! impl_integer {
+    ($($ty:ty),*) => {
+        $(
+            unsafe impl FromBytes for $ty {}
+            unsafe impl AsBytes for $ty {}
+
+            impl Endian for $ty {
+                fn to_le(self) -> Self {
+                    <$ty>::to_le(self)
+                }
+
+                fn from_le(value: Self) -> Self {
+                    <$ty>::from_le(value)
+                }
+
+                fn to_be(self) -> Self {
+                    <$ty>::to_be(self)
+                }
+
+                fn from_be(value: Self) -> Self {
+                    <$ty>::from_be(value)
+                }
+            }
+        )*
+    };
+}
+
+impl_integer!(u8, u16, u32, u64, u128, usize, i8, i16, i32, i64, i128, isize);
+
+unsafe impl<T: FromBytes, const N: usize> FromBytes for [T; N] {}
+unsafe impl<T: AsBytes, const N: usize> AsBytes for [T; N] {}
+
+/// The integer types that can be accessed atomically in guest memory.
+///
+/// # Safety
+///
+/// [`Atomic::Atomic`] must be the atomic type of the integer, such that it has the same size as
+/// the integer and an alignment that does not exceed its size.
+pub unsafe trait Atomic: FromBytes + AsBytes {
+    /// The atomic type of the integer, e.g. [`AtomicU32`] for [`u32`].
+    type Atomic;
+
+    /// Loads the value from the atomic with the given ordering.
+    fn load(atomic: &Self::Atomic, order: Ordering) -> Self;
+    /// Stores the value into the atomic with the given ordering.
+    fn store(atomic: &Self::Atomic, value: Self, order: Ordering);
+    /// Stores `new` into the atomic if its value is equal to `current`. Returns `Ok(current)` on
+    /// success, or `Err` with the value of the atomic otherwise.
+    fn compare_exchange(
+        atomic: &Self::Atomic,
+        current: Self,
+        new: Self,
+        success: Ordering,
+        failure: Ordering,
+    ) -> Result<Self, Self>;
+}
+
+macro_rules! impl_atomic {
+    ($($ty:ty => $atomic:ty),*) => {
+        $(
+            unsafe impl Atomic for $ty {
+                type Atomic = $atomic;
+
+                fn load(atomic: &Self::Atomic, order: Ordering) -> Self {
+                    atomic.load(order)
+                }
+
+                fn store(atomic: &Self::Atomic, value: Self, order: Ordering) {
+                    atomic.store(value, order)
+                }
+
+                fn compare_exchange(
+                    atomic: &Self::Atomic,
+                    current: Self,
+                    new: Self,
+                    success: Ordering,
+                    failure: Ordering,
+                ) -> Result<Self, Self> {
+                    atomic.compare_exchange(current, new, success, failure)
+                }
+            }
+        )*
+    };
+}
+
+impl_atomic!(
+    u8 => AtomicU8, u16 => AtomicU16, u32 => AtomicU32, u64 => AtomicU64, usize => AtomicUsize,
+    i8 => AtomicI8, i16 => AtomicI16, i32 => AtomicI32, i64 => AtomicI64, isize => AtomicIsize
+);
+
+/// An integer that is stored in little endian, regardless of the byte order of the host.
+#[derive(Clone, Copy, Default, Eq, Hash, PartialEq)]
+#[repr(transparent)]
+pub struct Le<T: Endian>(T);
+
+impl<T: Endian> Le<T> {
+    /// Creates the little-endian representation of the given integer.
+    pub fn new(value: T) -> Self {
+        Self(value.to_le())
+    }
+
+    /// Returns the integer in the byte order of the host.
+    pub fn get(self) -> T {
+        T::from_le(self.0)
+    }
+}
+
+impl<T: Endian> From<T> for Le<T> {
+    fn from(value: T) -> Self {
+        Self::new(value)
+    }
+}
+
+impl<T: Endian + fmt::Debug> fmt::Debug for Le<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.get().fmt(f)
+    }
+}
+
+unsafe impl<T: Endian> FromBytes for Le<T> {}
+unsafe impl<T: Endian> AsBytes for Le<T> {}
+
+/// An integer that is stored in big endian, regardless of the byte order of the host.
+#[derive(Clone, Copy, Default, Eq, Hash, PartialEq)]
+#[repr(transparent)]
+pub struct Be<T: Endian>(T);
+
+impl<T: Endian> Be<T> {
+    /// Creates the big-endian representation of the given integer.
+    pub fn new(value: T) -> Self {
+        Self(value.to_be())
+    }
+
+    /// Returns the integer in the byte order of the host.
+    pub fn get(self) -> T {
+        T::from_be(self.0)
+    }
+}
+
+impl<T: Endian> From<T> for Be<T> {
+    fn from(value: T) -> Self {
+        Self::new(value)
+    }
+}
+
+impl<T: Endian + fmt::Debug> fmt::Debug for Be<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.get().fmt(f)
+    }
+}
+
+unsafe impl<T: Endian> FromBytes for Be<T> {}
+unsafe impl<T: Endian> AsBytes for Be<T> {}
@@ -0,0 +1,65 @@
+//! This module provides the types to run confidential guests through AMD Secure Encrypted
+//! Virtualization (SEV), i.e. guests whose memory is encrypted with a key that is only known to
+//! the AMD Secure Processor. With SEV-ES, the register state of the virtual CPUs is encrypted as
+//! well.
+//!
+//! SEV is enabled through [`crate::VmBuilder::with_sev`] with the [`SevPolicy`] of the guest.
+//! Building the VM initializes SEV, or SEV-ES if the policy contains [`SevPolicy::ES`], and
+//! starts the launch of the guest. The guest physical memory allocated afterwards is pinned and
+//! registered with the SEV API. The launch then proceeds as follows:
+//!
+//!  1. The initial contents of the guest memory, e.g. the firmware, are written through
+//!     [`crate::Vm::write_physical_memory`] and then encrypted in place and added to the launch
+//!     measurement through [`crate::Vm::sev_launch_update_data`].
+//!  2. With SEV-ES, the initial register state of the virtual CPUs is set through
+//!     [`crate::Vcpu::set_state`] and then encrypted through
+//!     [`crate::Vm::sev_launch_update_vmsa`], after which the host can no longer access it.
+//!  3. The measurement of the launch is retrieved through [`crate::Vm::sev_launch_measure`],
+//!     such that the guest owner can verify it before providing any secrets.
+//!  4. The launch is completed through [`crate::Vm::sev_launch_finish`], after which the virtual
+//!     CPUs can run.
+//!
+//! The host can still access the guest memory, but the pages that the guest maps as encrypted
+//! read back as ciphertext. Guests communicate with the host through the pages they map as
+//! shared instead.
+//!
+//! This is currently only supported on Linux through KVM, which requires access to `/dev/sev`.
+
+use bitflags::bitflags;
+
+/// The path to the device of the AMD Secure Processor.
+pub const SEV_DEVICE_PATH: &str = "/dev/sev";
+
+/// The size of the launch measurement in bytes.
+pub const LAUNCH_MEASUREMENT_SIZE: usize = 32;
+
+/// The size of the nonce of the launch measurement in bytes.
+pub const LAUNCH_NONCE_SIZE: usize = 16;
+
+bitflags! {
+    /// The policy of an SEV guest, which the SEV firmware enforces for the lifetime of the guest.
+    pub struct SevPolicy: u32 {
+        /// Debugging of the guest is disallowed.
+        const NO_DEBUG       = 1 << 0;
+        /// Sharing the key with other guests is disallowed.
+        const NO_KEY_SHARING = 1 << 1;
+        /// SEV-ES is required, i.e. the register state of the virtual CPUs is encrypted.
+        const ES             = 1 << 2;
+        /// Sending the guest to another platform is disallowed.
+        const NO_SEND        = 1 << 3;
+        /// The guest must not be sent to a platform outside of the domain.
+        const DOMAIN         = 1 << 4;
+        /// The guest must not be sent to a platform that does not support SEV.
+        const SEV            = 1 << 5;
+    }
+}
+
+/// The measurement of the launch of an SEV guest, as retrieved through
+/// [`crate::Vm::sev_launch_measure`].
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct LaunchMeasurement {
+    /// The HMAC over the measured guest memory and VMSAs, keyed with the transport integrity key.
+    pub measurement: [u8; LAUNCH_MEASUREMENT_SIZE],
+    /// The nonce used to compute the measurement.
+    pub nonce: [u8; LAUNCH_NONCE_SIZE],
+}
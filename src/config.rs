@@ -0,0 +1,451 @@
+//! This module provides the [`VmConfig`] struct, which describes a VM declaratively rather than
+//! through a chain of [`crate::VmBuilder`] calls. A [`VmConfig`] is applied through
+//! [`crate::Hypervisor::create_vm`], which builds the VM and allocates the memory regions.
+//!
+//! With the `serde` feature enabled, the configuration types implement `Serialize` and
+//! `Deserialize`, such that a machine can be described in any format supported by serde, e.g.
+//! TOML or JSON.
+//!
+//! The virtual CPUs are not created by [`crate::Hypervisor::create_vm`], as some platforms
+//! require them to be created on the thread that runs them. Use [`crate::Vm::vcpu_factory`] to
+//! create them instead, after which [`VmConfig::configure_vcpu`] applies the topology and the
+//! CPUID tweaks to them on x86.
+//!
+//! The devices described by [`VmConfig::devices`] are registered on the buses of the VM, see
+//! [`crate::bus`], such that the VMM only has to pass the exits to [`crate::Vm::dispatch_exit`].
+//! Their interrupts are raised through [`crate::Vm::set_irq_line`], which requires the in-kernel
+//! interrupt controllers. Devices with a host side that the VMM drives itself, e.g. sockets
+//! through [`crate::Vsock`], have to be attached by hand.
+
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+use crate::arch::x86_64::{CpuidEntry, CpuidResult};
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+use crate::devices::serial::{Uart16550, UART_PORT_COUNT};
+use crate::error::Error;
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+use crate::hypervisor::Hypervisor;
+use crate::thread::ThreadPriority;
+use crate::tsc::TscMode;
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+use crate::vcpu::Vcpu;
+use crate::virtio::{Block, GuestMemory, MmioTransport, VIRTIO_MMIO_SIZE};
+use crate::vm::{ProtectionFlags, Vm, VmBuilder};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+
+/// Describes the topology of the virtual CPUs.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Deserialize, serde::Serialize))]
+pub struct CpuTopology {
+    /// The number of sockets.
+    pub sockets: usize,
+    /// The number of cores per socket.
+    pub cores_per_socket: usize,
+    /// The number of threads per core.
+    pub threads_per_core: usize,
+}
+
+impl CpuTopology {
+    /// Returns the total number of virtual CPUs described by the topology, or `None` if the
+    /// number overflows.
+    pub fn vcpu_count(&self) -> Option<usize> {
+        self.sockets
+            .checked_mul(self.cores_per_socket)?
+            .checked_mul(self.threads_per_core)
+    }
+
+    /// Returns the number of bits of the APIC ID that identify the thread within the core and
+    /// the core within the socket, as every level is padded to a power of two.
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    fn apic_id_bits(&self) -> (u32, u32) {
+        let bits = |count: usize| usize::BITS - count.saturating_sub(1).leading_zeros();
+
+        (bits(self.threads_per_core), bits(self.cores_per_socket))
+    }
+
+    /// Returns the APIC ID of the virtual CPU with the given vCPU ID, where the vCPU IDs are
+    /// assigned to the threads of the first core of the first socket first. The APIC ID matches
+    /// the vCPU ID if the number of cores per socket and threads per core are powers of two.
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    pub fn apic_id(&self, vcpu_id: usize) -> u32 {
+        let (thread_bits, core_bits) = self.apic_id_bits();
+        let thread = vcpu_id % self.threads_per_core;
+        let core = vcpu_id / self.threads_per_core % self.cores_per_socket;
+        let socket = vcpu_id / self.threads_per_core / self.cores_per_socket;
+
+        ((socket << (core_bits + thread_bits)) | (core << thread_bits) | thread) as u32
+    }
+}
+
+/// Describes a change to the CPUID table of the virtual CPUs, which clears and then sets the
+/// given bits in the result of the given leaf. The leaf is added to the table if the table does
+/// not have it yet.
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Deserialize, serde::Serialize))]
+#[cfg_attr(feature = "serde", serde(default))]
+pub struct CpuidTweak {
+    /// The leaf, i.e. the value of `eax`.
+    pub leaf: u32,
+    /// The subleaf, i.e. the value of `ecx`, or `None` to change every subleaf of the leaf.
+    pub subleaf: Option<u32>,
+    /// The bits to clear in `eax`, `ebx`, `ecx` and `edx`.
+    pub clear: [u32; 4],
+    /// The bits to set in `eax`, `ebx`, `ecx` and `edx`.
+    pub set: [u32; 4],
+}
+
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+impl CpuidTweak {
+    /// Applies the tweak to the given CPUID table.
+    fn apply(&self, entries: &mut Vec<CpuidEntry>) {
+        let mut found = false;
+
+        for entry in entries.iter_mut() {
+            let subleaf = match self.subleaf {
+                Some(subleaf) => !entry.indexed || entry.subleaf == subleaf,
+                _ => true,
+            };
+
+            if entry.leaf != self.leaf || !subleaf {
+                continue;
+            }
+
+            let result = &mut entry.result;
+
+            result.eax = (result.eax & !self.clear[0]) | self.set[0];
+            result.ebx = (result.ebx & !self.clear[1]) | self.set[1];
+            result.ecx = (result.ecx & !self.clear[2]) | self.set[2];
+            result.edx = (result.edx & !self.clear[3]) | self.set[3];
+
+            found = true;
+        }
+
+        if !found {
+            entries.push(CpuidEntry {
+                leaf: self.leaf,
+                subleaf: self.subleaf.unwrap_or(0),
+                indexed: self.subleaf.is_some(),
+                result: CpuidResult {
+                    eax: self.set[0],
+                    ebx: self.set[1],
+                    ecx: self.set[2],
+                    edx: self.set[3],
+                },
+            });
+        }
+    }
+}
+
+/// Describes a device to attach to the VM, see [`VmConfig::devices`].
+#[derive(Clone, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Deserialize, serde::Serialize))]
+pub enum DeviceConfig {
+    /// A 16550A UART at the given base I/O port with the given interrupt line, which writes the
+    /// transmitted bytes to the standard output of the host, see [`Uart16550`].
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    Serial {
+        /// The base I/O port, e.g. [`crate::devices::serial::COM1_PORT`].
+        port: u16,
+        /// The GSI of the interrupt line, e.g. [`crate::devices::serial::COM1_IRQ`].
+        irq: u32,
+    },
+    /// A virtio-blk device backed by the file at the given path, whose MMIO transport is mapped
+    /// at the given guest physical address, see [`Block`].
+    VirtioBlock {
+        /// The guest physical address of the MMIO transport.
+        address: u64,
+        /// The GSI of the interrupt line.
+        irq: u32,
+        /// The path to the disk image or block device.
+        path: PathBuf,
+        /// Whether the guest is not allowed to write to the disk.
+        read_only: bool,
+    },
+}
+
+impl DeviceConfig {
+    /// Creates the device and registers it on the buses of the given [`Vm`].
+    fn attach(&self, vm: &Vm) -> Result<(), Error> {
+        let inner = vm.inner.clone();
+
+        match self {
+            #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+            Self::Serial { port, irq } => {
+                let irq = *irq;
+                let uart = Uart16550::new(*port, std::io::stdout(), move |level| {
+                    inner.read().unwrap().set_irq_line(irq, level)
+                });
+
+                vm.register_pio_device(*port, UART_PORT_COUNT, Arc::new(Mutex::new(uart)))
+            }
+            Self::VirtioBlock { address, irq, path, read_only } => {
+                let irq = *irq;
+                let block = Block::open(path, *read_only)?;
+                let transport = MmioTransport::new(block, GuestMemory::new(vm), move |level| {
+                    inner.read().unwrap().set_irq_line(irq, level)
+                });
+
+                vm.register_mmio_device(*address, VIRTIO_MMIO_SIZE, Arc::new(Mutex::new(transport)))
+            }
+        }
+    }
+}
+
+/// Describes a region of guest physical memory to allocate.
+#[derive(Clone, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Deserialize, serde::Serialize))]
+pub struct MemoryRegionConfig {
+    /// The guest physical address of the region.
+    pub guest_address: u64,
+    /// The size of the region in bytes, which must be aligned to the page size.
+    pub size: usize,
+    /// The protection of the region.
+    pub protection: ProtectionFlags,
+}
+
+/// Describes a VM declaratively.
+#[derive(Clone, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Deserialize, serde::Serialize))]
+#[cfg_attr(feature = "serde", serde(default))]
+pub struct VmConfig {
+    /// The name of the VM.
+    pub name: String,
+    /// The maximum number of virtual CPUs.
+    pub vcpu_count: usize,
+    /// The topology of the virtual CPUs, which must describe exactly `vcpu_count` virtual CPUs
+    /// if specified. On x86, the topology is presented to the guest through the CPUID, see
+    /// [`VmConfig::configure_vcpu`]. On other architectures, it is only validated.
+    pub topology: Option<CpuTopology>,
+    /// The scheduling priority of the threads running the virtual CPUs, see
+    /// [`VmBuilder::with_vcpu_thread_priority`].
+    pub vcpu_thread_priority: ThreadPriority,
+    /// How the virtual CPUs observe the time stamp counter, see [`VmBuilder::with_tsc_mode`].
+    pub tsc_mode: TscMode,
+    /// Whether the guest memory is protected from the host, see
+    /// [`VmBuilder::with_protected_guest`].
+    pub protected_guest: bool,
+    /// Whether to enable the synthetic interrupt controller, see
+    /// [`VmBuilder::with_synthetic_interrupts`].
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    pub synthetic_interrupts: bool,
+    /// Whether to expose or hide 5-level paging, see [`VmBuilder::with_la57`]. The CPUID is left
+    /// untouched if not specified.
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    pub la57: Option<bool>,
+    /// Whether to emulate the interrupt controllers in the kernel, see
+    /// [`VmBuilder::with_in_kernel_irqchip`]. This is required by the interrupts of the
+    /// [`VmConfig::devices`].
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    pub in_kernel_irqchip: bool,
+    /// The changes to the CPUID table of the virtual CPUs, which are applied in order on top of
+    /// the CPUID supported by the hypervisor, see [`VmConfig::configure_vcpu`].
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    pub cpuid: Vec<CpuidTweak>,
+    /// The regions of guest physical memory to allocate.
+    pub memory: Vec<MemoryRegionConfig>,
+    /// The devices to attach to the VM.
+    pub devices: Vec<DeviceConfig>,
+}
+
+impl Default for VmConfig {
+    fn default() -> Self {
+        Self {
+            name: String::new(),
+            vcpu_count: 1,
+            topology: None,
+            vcpu_thread_priority: ThreadPriority::default(),
+            tsc_mode: TscMode::default(),
+            protected_guest: false,
+            #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+            synthetic_interrupts: false,
+            #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+            la57: None,
+            #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+            in_kernel_irqchip: false,
+            #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+            cpuid: vec![],
+            memory: vec![],
+            devices: vec![],
+        }
+    }
+}
+
+impl VmConfig {
+    /// Applies the configuration to the given [`VmBuilder`].
+    pub fn apply(&self, builder: VmBuilder) -> Result<VmBuilder, Error> {
+        if let Some(topology) = self.topology {
+            if topology.vcpu_count() != Some(self.vcpu_count) {
+                return Err(Error::InvalidArgument);
+            }
+        }
+
+        let mut builder = builder
+            .with_vcpu_count(self.vcpu_count)?
+            .with_vcpu_thread_priority(self.vcpu_thread_priority)?;
+
+        // Only apply the optional features when requested, as not all platforms support them.
+        if self.tsc_mode != TscMode::Native {
+            builder = builder.with_tsc_mode(self.tsc_mode)?;
+        }
+
+        if self.protected_guest {
+            builder = builder.with_protected_guest(true)?;
+        }
+
+        #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+        if self.synthetic_interrupts {
+            builder = builder.with_synthetic_interrupts(true)?;
+        }
+
+        #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+        if let Some(enabled) = self.la57 {
+            builder = builder.with_la57(enabled)?;
+        }
+
+        #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+        if self.in_kernel_irqchip {
+            builder = builder.with_in_kernel_irqchip(true)?;
+        }
+
+        Ok(builder)
+    }
+
+    /// Allocates the memory regions in the given [`Vm`].
+    pub fn allocate_memory(&self, vm: &mut Vm) -> Result<(), Error> {
+        for region in &self.memory {
+            vm.allocate_physical_memory(region.guest_address, region.size, region.protection)?;
+        }
+
+        Ok(())
+    }
+
+    /// Creates the devices and registers them on the buses of the given [`Vm`].
+    pub fn attach_devices(&self, vm: &Vm) -> Result<(), Error> {
+        for device in &self.devices {
+            device.attach(vm)?;
+        }
+
+        Ok(())
+    }
+
+    /// Returns the CPUID table of the virtual CPU with the given vCPU ID, which is the given
+    /// CPUID table with the topology and the CPUID tweaks applied. The topology sets the APIC ID
+    /// and the number of logical processors in leaf 0x1, the number of cores in leaf 0x4, the
+    /// levels of leaves 0xb and 0x1f, and the number of threads in leaf 0x8000_0008. Returns
+    /// [`Error::InvalidArgument`] if the vCPU ID is out of range.
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    pub fn cpuid(&self, vcpu_id: usize, supported: &[CpuidEntry]) -> Result<Vec<CpuidEntry>, Error> {
+        if vcpu_id >= self.vcpu_count {
+            return Err(Error::InvalidArgument);
+        }
+
+        let mut entries = supported.to_vec();
+
+        if let Some(topology) = self.topology {
+            set_topology(&mut entries, &topology, vcpu_id);
+        }
+
+        for tweak in &self.cpuid {
+            tweak.apply(&mut entries);
+        }
+
+        Ok(entries)
+    }
+
+    /// Sets the CPUID table of the given virtual CPU with the given vCPU ID to the table returned
+    /// by [`VmConfig::cpuid`] for the CPUID supported by the hypervisor. This does nothing if
+    /// neither a topology nor CPUID tweaks have been configured. See [`Vcpu::set_cpuid`] for the
+    /// platforms that support this.
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    pub fn configure_vcpu(
+        &self,
+        hypervisor: &Hypervisor,
+        vcpu_id: usize,
+        vcpu: &mut Vcpu,
+    ) -> Result<(), Error> {
+        if self.topology.is_none() && self.cpuid.is_empty() {
+            return Ok(());
+        }
+
+        let entries = self.cpuid(vcpu_id, &hypervisor.get_supported_cpuid()?)?;
+
+        vcpu.set_cpuid(&entries)
+    }
+}
+
+/// Helper function to present the given topology to the virtual CPU with the given vCPU ID
+/// through the given CPUID table.
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+fn set_topology(entries: &mut Vec<CpuidEntry>, topology: &CpuTopology, vcpu_id: usize) {
+    let (thread_bits, core_bits) = topology.apic_id_bits();
+    let apic_id = topology.apic_id(vcpu_id);
+    let threads_per_socket = (topology.cores_per_socket * topology.threads_per_core) as u32;
+    let max_ids = 1u32 << (thread_bits + core_bits);
+
+    for entry in entries.iter_mut() {
+        let result = &mut entry.result;
+
+        match entry.leaf {
+            0x1 => {
+                // The initial APIC ID and the number of addressable IDs per package.
+                result.ebx = (result.ebx & 0xffff) | (max_ids.min(0xff) << 16) | (apic_id << 24);
+
+                // Hyper-threading, i.e. multiple logical processors per package.
+                if max_ids > 1 {
+                    result.edx |= 1 << 28;
+                } else {
+                    result.edx &= !(1 << 28);
+                }
+            }
+            0x4 => {
+                // The number of addressable core IDs per package minus one.
+                result.eax = (result.eax & 0x03ff_ffff) | (((1 << core_bits) - 1) << 26);
+            }
+            0x8000_0008 => {
+                // The size of the APIC ID and the number of threads per package minus one.
+                result.ecx = (result.ecx & !0xf0ff)
+                    | ((thread_bits + core_bits) << 12)
+                    | (threads_per_socket.saturating_sub(1) & 0xff);
+            }
+            _ => (),
+        }
+    }
+
+    // Leaf 0x1f is the extended version of leaf 0xb, so describe the same levels if the host
+    // supports it.
+    let has_leaf_1f = entries.iter().any(|entry| entry.leaf == 0x1f);
+    let leaves: &[u32] = if has_leaf_1f { &[0xb, 0x1f] } else { &[0xb] };
+
+    entries.retain(|entry| !leaves.contains(&entry.leaf));
+
+    for &leaf in leaves {
+        // The SMT level, the core level and the invalid level that terminates the list.
+        let levels = [
+            (thread_bits, topology.threads_per_core as u32, 1),
+            (thread_bits + core_bits, threads_per_socket, 2),
+            (0, 0, 0),
+        ];
+
+        for (subleaf, &(shift, count, kind)) in levels.iter().enumerate() {
+            let subleaf = subleaf as u32;
+
+            entries.push(CpuidEntry {
+                leaf,
+                subleaf,
+                indexed: true,
+                result: CpuidResult {
+                    eax: shift,
+                    ebx: count,
+                    ecx: (kind << 8) | subleaf,
+                    edx: apic_id,
+                },
+            });
+        }
+    }
+
+    // Make sure the guest looks at leaf 0xb.
+    if let Some(entry) = entries.iter_mut().find(|entry| entry.leaf == 0) {
+        entry.result.eax = entry.result.eax.max(0xb);
+    }
+}
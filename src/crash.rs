@@ -0,0 +1,46 @@
+//! This module provides the [`CrashReport`] struct, which describes the state of a virtual CPU
+//! that exited with [`crate::ExitReason::UnhandledException`], e.g. upon a triple fault. The
+//! crash report is available through [`crate::Vcpu::last_crash_report`] until the virtual CPU is
+//! run again.
+//!
+//! Some implementations reset the virtual CPU state upon an unhandled exception (e.g. KVM when
+//! using AMD SVM), in which case the crash report describes the reset state rather than the
+//! state at the time of the crash.
+
+use crate::arch::x86_64::{
+    ControlRegister, DescriptorTable, DescriptorTableRegister, Register, Segment, SegmentRegister,
+};
+
+/// The number of bytes of guest memory captured around the instruction pointer and from the
+/// stack pointer.
+pub const CRASH_WINDOW_SIZE: usize = 64;
+
+/// The maximum number of frames captured in the backtrace.
+pub const CRASH_BACKTRACE_FRAMES: usize = 32;
+
+/// Describes the state of a virtual CPU after an unhandled exception.
+#[derive(Clone, Debug)]
+pub struct CrashReport {
+    /// The general-purpose registers.
+    pub registers: Vec<(Register, u64)>,
+    /// The control registers.
+    pub control_registers: Vec<(ControlRegister, u64)>,
+    /// The value of the EFER MSR.
+    pub efer: u64,
+    /// The segment registers.
+    pub segments: Vec<(SegmentRegister, Segment)>,
+    /// The descriptor table registers.
+    pub descriptor_tables: Vec<(DescriptorTableRegister, DescriptorTable)>,
+    /// The linear address that caused the last page fault, i.e. the value of CR2.
+    pub fault_address: u64,
+    /// The guest virtual address of the first byte of [`CrashReport::code`].
+    pub code_address: u64,
+    /// The guest memory around the instruction pointer, which is empty if the memory could not be
+    /// read.
+    pub code: Vec<u8>,
+    /// The guest memory starting at the stack pointer, which is empty if the memory could not be
+    /// read.
+    pub stack: Vec<u8>,
+    /// The return addresses found by walking the stack, see [`crate::Vcpu::walk_stack`].
+    pub backtrace: Vec<u64>,
+}
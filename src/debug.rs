@@ -0,0 +1,345 @@
+//! This module provides the [`GuestDebug`] struct which is used to configure the debugging
+//! features of a virtual CPU through [`crate::Vcpu::set_guest_debug`], i.e. single-stepping,
+//! software breakpoints and hardware breakpoints. When one of these features triggers, the
+//! virtual CPU exits with [`crate::ExitReason::Debug`].
+//!
+//! Software breakpoints are set by writing the breakpoint instruction (e.g. `int3` on x86) to
+//! guest memory. Once enabled, the breakpoint exception is reported to the host rather than
+//! delivered to the guest.
+//!
+//! Not all platforms support the full set of debugging features:
+//!  * Linux uses `KVM_SET_GUEST_DEBUG`.
+//!  * Microsoft Windows uses the debug registers and exception exits. Single-stepping sets the
+//!    trap flag, which is visible to the guest.
+//!  * Mac OS X uses the monitor trap flag for single-stepping, and the debug registers and the
+//!    exception bitmap for breakpoints.
+//!  * FreeBSD does not support guest debugging.
+//!
+//! On the x86 architecture, the [`BreakpointManager`] takes care of the software breakpoints:
+//! it saves the original bytes, writes the `int3` instructions, maps the breakpoint exceptions
+//! back to the breakpoints and steps over the breakpoint the virtual CPU stopped at when resuming
+//! it.
+
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+use crate::error::Error;
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+use crate::unwind::PagingState;
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+use crate::vcpu::{ExitReason, Vcpu};
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+use std::collections::BTreeMap;
+
+/// The maximum number of hardware breakpoints.
+pub const MAX_HW_BREAKPOINTS: usize = 4;
+
+/// The kind of access that triggers a hardware breakpoint.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum BreakpointKind {
+    /// Triggers when executing the instruction at the address.
+    Execute,
+    /// Triggers when writing to the address.
+    Write,
+    /// Triggers when reading from or writing to the address.
+    ReadWrite,
+}
+
+/// Describes a hardware breakpoint.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct HwBreakpoint {
+    /// The guest virtual address of the breakpoint.
+    pub address: u64,
+    /// The kind of access that triggers the breakpoint.
+    pub kind: BreakpointKind,
+    /// The size of the watched area in bytes, which must be 1, 2, 4 or 8. This must be 1 for
+    /// [`BreakpointKind::Execute`].
+    pub size: usize,
+}
+
+/// The debugging features of a virtual CPU.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct GuestDebug {
+    /// Exit after every instruction.
+    pub single_step: bool,
+    /// Exit on software breakpoints rather than delivering them to the guest.
+    pub sw_breakpoints: bool,
+    /// The hardware breakpoints, of which there can be at most [`MAX_HW_BREAKPOINTS`].
+    pub hw_breakpoints: Vec<HwBreakpoint>,
+}
+
+impl GuestDebug {
+    /// Returns `true` if any of the debugging features is enabled.
+    pub fn is_enabled(&self) -> bool {
+        self.single_step || self.sw_breakpoints || !self.hw_breakpoints.is_empty()
+    }
+}
+
+/// Describes why the virtual CPU exited with [`crate::ExitReason::Debug`].
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum DebugExitKind {
+    /// The virtual CPU executed a single instruction.
+    SingleStep,
+    /// The virtual CPU hit a software breakpoint.
+    SoftwareBreakpoint,
+    /// The virtual CPU hit the hardware breakpoint with the given index.
+    HardwareBreakpoint(usize),
+    /// The virtual CPU raised a debug exception for some other reason.
+    Unknown,
+}
+
+/// Describes a debug exit.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct DebugExit {
+    /// The instruction pointer of the virtual CPU at the time of the exit.
+    pub pc: u64,
+    /// The reason for the debug exit.
+    pub kind: DebugExitKind,
+    /// The hardware breakpoint that triggered, i.e. the address and the kind of access, for
+    /// [`DebugExitKind::HardwareBreakpoint`].
+    pub breakpoint: Option<HwBreakpoint>,
+}
+
+/// The single-step status bit in DR6 of the x86 architecture.
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+pub(crate) const DR6_BS: u64 = 1 << 14;
+
+/// Encodes the hardware breakpoints into the value of DR7 of the x86 architecture.
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+pub(crate) fn encode_dr7(breakpoints: &[HwBreakpoint]) -> Result<u64, Error> {
+    if breakpoints.len() > MAX_HW_BREAKPOINTS {
+        return Err(Error::InvalidArgument);
+    }
+
+    let mut dr7 = 0;
+
+    for (index, breakpoint) in breakpoints.iter().enumerate() {
+        let rw = match breakpoint.kind {
+            BreakpointKind::Execute   => 0b00,
+            BreakpointKind::Write     => 0b01,
+            BreakpointKind::ReadWrite => 0b11,
+        };
+
+        let len = match (breakpoint.kind, breakpoint.size) {
+            (BreakpointKind::Execute, 1) => 0b00,
+            (BreakpointKind::Execute, _) => return Err(Error::InvalidArgument),
+            (_, 1) => 0b00,
+            (_, 2) => 0b01,
+            (_, 4) => 0b11,
+            (_, 8) => 0b10,
+            _ => return Err(Error::InvalidArgument),
+        };
+
+        // Set the global enable bit and the condition and length of the breakpoint.
+        dr7 |= 1 << (index * 2 + 1);
+        dr7 |= (rw | (len << 2)) << (16 + index * 4);
+    }
+
+    Ok(dr7)
+}
+
+/// Decodes the reason for a debug exception from the value of DR6 of the x86 architecture.
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+pub(crate) fn decode_dr6(dr6: u64) -> DebugExitKind {
+    if dr6 & DR6_BS != 0 {
+        return DebugExitKind::SingleStep;
+    }
+
+    match (0..MAX_HW_BREAKPOINTS).find(|index| dr6 & (1 << index) != 0) {
+        Some(index) => DebugExitKind::HardwareBreakpoint(index),
+        _ => DebugExitKind::Unknown,
+    }
+}
+
+/// The `int3` instruction of the x86 architecture.
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+const INT3: u8 = 0xcc;
+
+/// A software breakpoint managed by the [`BreakpointManager`].
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+#[derive(Clone, Copy, Debug)]
+struct SwBreakpoint {
+    /// The guest physical address the `int3` instruction was written to.
+    phys_addr: u64,
+    /// The original byte that was overwritten by the `int3` instruction.
+    original: u8,
+}
+
+/// The `BreakpointManager` manages the software breakpoints of a virtual CPU. The breakpoints are
+/// set by overwriting the first byte of the instruction with `int3`, and the virtual CPU is run
+/// through [`BreakpointManager::run`], which reports the breakpoints as
+/// [`crate::ExitReason::Debug`] with [`DebugExitKind::SoftwareBreakpoint`].
+///
+/// When resuming a virtual CPU that stopped at one of the breakpoints, the original byte is
+/// restored and the virtual CPU is single-stepped over the instruction, after which the `int3`
+/// instruction is written back. This is transparent to the caller, unless single-stepping was
+/// enabled through [`Vcpu::set_guest_debug`].
+///
+/// The breakpoints are set at guest virtual addresses, which are translated using the paging
+/// state of the virtual CPU at the time the breakpoint is inserted. As the `int3` instructions
+/// are written to guest memory, the breakpoints are visible to the other virtual CPUs as well.
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+#[derive(Debug, Default)]
+pub struct BreakpointManager {
+    /// The breakpoints by guest virtual address.
+    breakpoints: BTreeMap<u64, SwBreakpoint>,
+    /// The breakpoint the virtual CPU stopped at, which has to be stepped over upon the next run.
+    stopped_at: Option<u64>,
+    /// The breakpoint the virtual CPU is stepping over.
+    stepping_over: Option<u64>,
+}
+
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+impl BreakpointManager {
+    /// Creates a `BreakpointManager` without any breakpoints.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns `true` if there is a breakpoint at the given guest virtual address.
+    pub fn contains(&self, address: u64) -> bool {
+        self.breakpoints.contains_key(&address)
+    }
+
+    /// Returns the guest virtual addresses of the breakpoints.
+    pub fn breakpoints(&self) -> impl Iterator<Item = u64> + '_ {
+        self.breakpoints.keys().copied()
+    }
+
+    /// Inserts a breakpoint at the given guest virtual address. This enables
+    /// [`GuestDebug::sw_breakpoints`] on the virtual CPU, which remains enabled when the
+    /// breakpoints are removed.
+    pub fn insert(&mut self, vcpu: &mut Vcpu, address: u64) -> Result<(), Error> {
+        if self.contains(address) {
+            return Ok(());
+        }
+
+        if !vcpu.guest_debug().sw_breakpoints {
+            let debug = GuestDebug {
+                sw_breakpoints: true,
+                ..vcpu.guest_debug().clone()
+            };
+
+            vcpu.set_guest_debug(&debug)?;
+        }
+
+        let phys_addr = PagingState::new(&vcpu.inner)?
+            .translate(&vcpu.vm.read().unwrap(), address)?;
+
+        let mut original = [0u8; 1];
+
+        if vcpu.vm.read().unwrap().read_physical_memory(&mut original, phys_addr)? == 0 {
+            return Err(Error::InvalidGuestAddress);
+        }
+
+        write_byte(vcpu, phys_addr, INT3)?;
+
+        self.breakpoints.insert(address, SwBreakpoint {
+            phys_addr,
+            original: original[0],
+        });
+
+        Ok(())
+    }
+
+    /// Removes the breakpoint at the given guest virtual address by restoring the original byte.
+    /// Returns [`Error::InvalidArgument`] if there is no breakpoint at the address.
+    pub fn remove(&mut self, vcpu: &mut Vcpu, address: u64) -> Result<(), Error> {
+        let breakpoint = self.breakpoints.remove(&address)
+            .ok_or(Error::InvalidArgument)?;
+
+        write_byte(vcpu, breakpoint.phys_addr, breakpoint.original)?;
+
+        // There is nothing left to step over.
+        if self.stopped_at == Some(address) {
+            self.stopped_at = None;
+        }
+
+        Ok(())
+    }
+
+    /// Runs the virtual CPU like [`Vcpu::run`], while stepping over the breakpoint the virtual
+    /// CPU stopped at, if any.
+    pub fn run<'a>(&mut self, vcpu: &'a mut Vcpu) -> Result<ExitReason<'a>, Error> {
+        loop {
+            if let Some(address) = self.stopped_at.take() {
+                self.begin_step_over(vcpu, address)?;
+            }
+
+            // The borrow checker rejects using the virtual CPU in the next iteration, as the exit
+            // reason may be returned. As the exit reason is not used anymore at that point, this
+            // is sound.
+            let exit_reason = unsafe { &mut *(vcpu as *mut Vcpu) }.run()?;
+
+            let exit = match exit_reason {
+                ExitReason::Debug(exit) => exit,
+                exit_reason => return Ok(exit_reason),
+            };
+
+            match exit.kind {
+                DebugExitKind::SingleStep if self.stepping_over.is_some() => {
+                    self.end_step_over(vcpu)?;
+
+                    // Only report the single step if single-stepping was requested.
+                    if !vcpu.guest_debug().single_step {
+                        continue;
+                    }
+                }
+                DebugExitKind::SoftwareBreakpoint if self.contains(exit.pc) => {
+                    self.stopped_at = Some(exit.pc);
+                }
+                _ => (),
+            }
+
+            return Ok(ExitReason::Debug(exit));
+        }
+    }
+
+    /// Helper function to restore the original byte of the breakpoint and to single-step the
+    /// virtual CPU over the instruction.
+    fn begin_step_over(&mut self, vcpu: &mut Vcpu, address: u64) -> Result<(), Error> {
+        let breakpoint = match self.breakpoints.get(&address) {
+            Some(breakpoint) => *breakpoint,
+            _ => return Ok(()),
+        };
+
+        write_byte(vcpu, breakpoint.phys_addr, breakpoint.original)?;
+
+        // Enable single-stepping without changing the configuration reported by the virtual CPU.
+        let debug = GuestDebug {
+            single_step: true,
+            ..vcpu.guest_debug().clone()
+        };
+
+        vcpu.inner.set_guest_debug(&debug)?;
+        self.stepping_over = Some(address);
+
+        Ok(())
+    }
+
+    /// Helper function to write the `int3` instruction back after stepping over the breakpoint
+    /// and to restore the debugging features of the virtual CPU.
+    fn end_step_over(&mut self, vcpu: &mut Vcpu) -> Result<(), Error> {
+        let address = match self.stepping_over.take() {
+            Some(address) => address,
+            _ => return Ok(()),
+        };
+
+        // The breakpoint may have been removed in the meantime.
+        if let Some(breakpoint) = self.breakpoints.get(&address) {
+            write_byte(vcpu, breakpoint.phys_addr, INT3)?;
+        }
+
+        let debug = vcpu.guest_debug().clone();
+
+        vcpu.inner.set_guest_debug(&debug)
+    }
+}
+
+/// Helper function to write a byte to the guest physical memory of the VM of the virtual CPU.
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+fn write_byte(vcpu: &Vcpu, phys_addr: u64, value: u8) -> Result<(), Error> {
+    if vcpu.vm.write().unwrap().write_physical_memory(phys_addr, &[value])? == 0 {
+        return Err(Error::InvalidGuestAddress);
+    }
+
+    Ok(())
+}
@@ -0,0 +1,1084 @@
+//! This module provides an emulation of the local APIC of x86 in user space, see [`LocalApic`],
+//! for the platforms and configurations where the hypervisor does not virtualize the local APIC,
+//! i.e. Mac OS X, Microsoft Windows without the local APIC emulation and Linux without the
+//! in-kernel irqchip. It is selected per VM through [`crate::VmBuilder::with_software_apic`], in
+//! which case [`crate::Vcpu::run`] handles the accesses to the MMIO page of the local APIC, and
+//! delivers the interrupts and the IPIs between the virtual CPUs, without reporting them.
+//!
+//! The local APIC is only accessible through MMIO, i.e. the x2APIC mode is not supported. The
+//! timer runs at 1 GHz, i.e. one tick of the bus clock per nanosecond, and supports the one-shot
+//! and periodic modes, but not the TSC-deadline mode. The virtual CPUs other than the bootstrap
+//! processor, i.e. vCPU 0, wait for an INIT and startup IPI from the bootstrap processor before
+//! they run, like on bare metal.
+
+use crate::arch::x86_64::APIC_BASE_ADDRESS;
+use crate::error::Error;
+use crate::vcpu::VcpuHandle;
+use std::collections::HashMap;
+use std::sync::{Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+/// The default guest physical address of the MMIO page of the local APIC.
+pub const LOCAL_APIC_ADDRESS: u64 = 0xfee0_0000;
+/// The size of the MMIO page of the local APIC.
+pub const LOCAL_APIC_SIZE: u64 = 0x1000;
+/// The frequency of the bus clock that drives the timer in Hz.
+pub const LOCAL_APIC_TIMER_FREQUENCY: u64 = 1_000_000_000;
+
+/// The version of the local APIC, i.e. an integrated APIC with six LVT entries.
+const LOCAL_APIC_VERSION: u32 = 0x14 | (5 << 16);
+
+/// The offset of the local APIC ID register.
+const APIC_ID: u64 = 0x020;
+/// The offset of the local APIC version register.
+const APIC_VERSION: u64 = 0x030;
+/// The offset of the task priority register (TPR).
+const APIC_TPR: u64 = 0x080;
+/// The offset of the arbitration priority register (APR).
+const APIC_APR: u64 = 0x090;
+/// The offset of the processor priority register (PPR).
+const APIC_PPR: u64 = 0x0a0;
+/// The offset of the EOI register.
+const APIC_EOI: u64 = 0x0b0;
+/// The offset of the logical destination register (LDR).
+const APIC_LDR: u64 = 0x0d0;
+/// The offset of the destination format register (DFR).
+const APIC_DFR: u64 = 0x0e0;
+/// The offset of the spurious interrupt vector register (SVR).
+const APIC_SVR: u64 = 0x0f0;
+/// The offset of the first in-service register (ISR).
+const APIC_ISR: u64 = 0x100;
+/// The offset of the first trigger mode register (TMR).
+const APIC_TMR: u64 = 0x180;
+/// The offset of the first interrupt request register (IRR).
+const APIC_IRR: u64 = 0x200;
+/// The offset of the error status register (ESR).
+const APIC_ESR: u64 = 0x280;
+/// The offset of the low half of the interrupt command register (ICR).
+const APIC_ICR_LOW: u64 = 0x300;
+/// The offset of the high half of the interrupt command register (ICR).
+const APIC_ICR_HIGH: u64 = 0x310;
+/// The offset of the first LVT entry, i.e. the timer.
+const APIC_LVT: u64 = 0x320;
+/// The offset of the initial count register of the timer.
+const APIC_TIMER_INITIAL: u64 = 0x380;
+/// The offset of the current count register of the timer.
+const APIC_TIMER_CURRENT: u64 = 0x390;
+/// The offset of the divide configuration register of the timer.
+const APIC_TIMER_DIVIDE: u64 = 0x3e0;
+
+/// The number of LVT entries, i.e. timer, thermal, performance counter, LINT0, LINT1 and error.
+const LVT_COUNT: usize = 6;
+/// The index of the LVT entry of the timer.
+const LVT_TIMER: usize = 0;
+/// The mask bit of an LVT entry.
+const LVT_MASKED: u32 = 1 << 16;
+/// The timer mode bits of the LVT entry of the timer.
+const LVT_TIMER_MODE: u32 = 0x3 << 17;
+/// The periodic timer mode.
+const LVT_TIMER_PERIODIC: u32 = 1 << 17;
+/// The writable bits of the LVT entries, in the order of the LVT.
+const LVT_WRITABLE: [u32; LVT_COUNT] = [
+    0x000710ff, 0x000107ff, 0x000107ff, 0x0001a7ff, 0x0001a7ff, 0x000100ff,
+];
+
+/// The bit of the SVR that enables the local APIC.
+const SVR_ENABLED: u32 = 1 << 8;
+
+/// The delivery mode of the ICR.
+const ICR_DELIVERY_MODE: u32 = 0x7 << 8;
+/// The destination mode of the ICR, which is set for logical destinations.
+const ICR_LOGICAL: u32 = 1 << 11;
+/// The level of the ICR, which is clear for the INIT level de-assert IPI.
+const ICR_ASSERT: u32 = 1 << 14;
+/// The destination shorthand of the ICR.
+const ICR_SHORTHAND: u32 = 0x3 << 18;
+/// The bits of the low half of the ICR that the guest can write.
+const ICR_WRITABLE: u32 = 0x000cdfff;
+
+/// The destination that refers to all local APICs in the physical destination mode.
+const BROADCAST: u8 = 0xff;
+
+/// The kind of an IPI sent through the interrupt command register.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum IpiKind {
+    /// An interrupt with the given vector.
+    Fixed(u8),
+    /// An interrupt with the given vector for the lowest priority local APIC in the destination.
+    LowestPriority(u8),
+    /// A non-maskable interrupt.
+    Nmi,
+    /// An INIT, which resets the virtual CPU and waits for a startup IPI.
+    Init,
+    /// A startup IPI, which starts the virtual CPU in real mode at the given vector times 4 KiB.
+    Startup(u8),
+}
+
+/// The destination of an IPI sent through the interrupt command register.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum IpiDestination {
+    /// The local APIC with the given local APIC ID, or all local APICs for ID 0xff.
+    Physical(u8),
+    /// The local APICs that match the given logical destination.
+    Logical(u8),
+    /// The local APIC that sent the IPI.
+    SelfOnly,
+    /// All local APICs.
+    All,
+    /// All local APICs but the one that sent the IPI.
+    AllExcludingSelf,
+}
+
+/// An IPI sent through the interrupt command register, as returned by [`LocalApic::write`].
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Ipi {
+    /// The kind of IPI.
+    pub kind: IpiKind,
+    /// The destination of the IPI.
+    pub destination: IpiDestination,
+}
+
+/// The state of the virtual CPU with respect to the INIT and startup IPIs.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum Startup {
+    /// The virtual CPU runs.
+    Running,
+    /// The virtual CPU has been reset by an INIT that has yet to be applied.
+    Init,
+    /// The virtual CPU waits for a startup IPI.
+    WaitForSipi,
+    /// The virtual CPU received a startup IPI with the given vector that has yet to be applied.
+    Sipi(u8),
+}
+
+/// An emulation of the local APIC of a single virtual CPU.
+#[derive(Clone, Debug)]
+pub struct LocalApic {
+    /// The guest physical address of the MMIO page.
+    base: u64,
+    /// The local APIC ID.
+    id: u8,
+    /// The task priority register.
+    tpr: u32,
+    /// The logical destination register.
+    ldr: u32,
+    /// The destination format register.
+    dfr: u32,
+    /// The spurious interrupt vector register.
+    svr: u32,
+    /// The in-service register.
+    isr: [u32; 8],
+    /// The trigger mode register.
+    tmr: [u32; 8],
+    /// The interrupt request register.
+    irr: [u32; 8],
+    /// The error status register.
+    esr: u32,
+    /// The interrupt command register.
+    icr: u64,
+    /// The local vector table.
+    lvt: [u32; LVT_COUNT],
+    /// The initial count of the timer.
+    initial_count: u32,
+    /// The divide configuration of the timer.
+    divide: u32,
+    /// The time at which the timer started counting, or `None` if it is not counting.
+    timer_start: Option<Instant>,
+    /// The number of times the timer expired since it started counting.
+    timer_expired: u64,
+    /// The state of the virtual CPU with respect to the INIT and startup IPIs.
+    startup: Startup,
+    /// Whether an NMI is pending.
+    nmi: bool,
+}
+
+impl LocalApic {
+    /// Creates the local APIC with the given local APIC ID in its reset state. Unless the virtual
+    /// CPU is the bootstrap processor, the virtual CPU waits for a startup IPI.
+    pub fn new(id: u8, bsp: bool) -> Self {
+        Self {
+            base: LOCAL_APIC_ADDRESS,
+            id,
+            tpr: 0,
+            ldr: 0,
+            dfr: 0xffff_ffff,
+            svr: 0xff,
+            isr: [0; 8],
+            tmr: [0; 8],
+            irr: [0; 8],
+            esr: 0,
+            icr: 0,
+            lvt: [LVT_MASKED; LVT_COUNT],
+            initial_count: 0,
+            divide: 0,
+            timer_start: None,
+            timer_expired: 0,
+            startup: match bsp {
+                true => Startup::Running,
+                _ => Startup::WaitForSipi,
+            },
+            nmi: false,
+        }
+    }
+
+    /// Returns the local APIC ID.
+    pub fn id(&self) -> u8 {
+        self.id
+    }
+
+    /// Moves the MMIO page to the given guest physical address, e.g. after the guest wrote the
+    /// `IA32_APIC_BASE` MSR.
+    pub fn set_base(&mut self, address: u64) {
+        self.base = address & APIC_BASE_ADDRESS;
+    }
+
+    /// Returns whether the given guest physical address belongs to the MMIO page.
+    pub fn contains_address(&self, address: u64) -> bool {
+        (self.base..self.base + LOCAL_APIC_SIZE).contains(&address)
+    }
+
+    /// Returns whether the local APIC has been enabled through the SVR.
+    fn enabled(&self) -> bool {
+        self.svr & SVR_ENABLED != 0
+    }
+
+    /// Returns the highest vector that is set in the given register, if any.
+    fn highest_vector(register: &[u32; 8]) -> Option<u8> {
+        (0..8).rev().find_map(|i| match register[i] {
+            0 => None,
+            bits => Some((i as u32 * 32 + 31 - bits.leading_zeros()) as u8),
+        })
+    }
+
+    /// Sets or clears the given vector in the given register.
+    fn set_vector(register: &mut [u32; 8], vector: u8, set: bool) {
+        let (index, bit) = ((vector / 32) as usize, 1 << (vector % 32));
+
+        match set {
+            true => register[index] |= bit,
+            _ => register[index] &= !bit,
+        }
+    }
+
+    /// Returns the processor priority, i.e. the higher of the task priority and the priority
+    /// class of the highest interrupt in service.
+    fn ppr(&self) -> u32 {
+        let isrv = Self::highest_vector(&self.isr).unwrap_or(0) as u32;
+
+        match self.tpr >> 4 >= isrv >> 4 {
+            true => self.tpr & 0xff,
+            _ => isrv & 0xf0,
+        }
+    }
+
+    /// Returns the divisor of the bus clock of the timer.
+    fn timer_divisor(&self) -> u64 {
+        let value = (self.divide & 0x3) | ((self.divide & 0x8) >> 1);
+
+        1 << ((value + 1) & 0x7)
+    }
+
+    /// Returns whether the timer is in the periodic mode.
+    fn timer_periodic(&self) -> bool {
+        self.lvt[LVT_TIMER] & LVT_TIMER_MODE == LVT_TIMER_PERIODIC
+    }
+
+    /// Returns the number of ticks of the timer since it started counting.
+    fn timer_ticks(&self, now: Instant) -> Option<u64> {
+        let start = self.timer_start?;
+        let elapsed = now.saturating_duration_since(start).as_nanos();
+        let ticks = elapsed * LOCAL_APIC_TIMER_FREQUENCY as u128 / 1_000_000_000;
+
+        Some((ticks / self.timer_divisor() as u128) as u64)
+    }
+
+    /// Returns the current count of the timer.
+    fn timer_current(&self, now: Instant) -> u32 {
+        let initial = self.initial_count as u64;
+
+        match self.timer_ticks(now) {
+            Some(ticks) if self.timer_periodic() => (initial - ticks % initial) as u32,
+            Some(ticks) => initial.saturating_sub(ticks) as u32,
+            _ => 0,
+        }
+    }
+
+    /// Requests the interrupt of the timer if it expired since it was last polled.
+    fn poll_timer(&mut self, now: Instant) {
+        let ticks = match self.timer_ticks(now) {
+            Some(ticks) => ticks,
+            _ => return,
+        };
+
+        let expired = match self.timer_periodic() {
+            true => ticks / self.initial_count as u64,
+            _ => (ticks >= self.initial_count as u64) as u64,
+        };
+
+        if expired <= self.timer_expired {
+            return;
+        }
+
+        self.timer_expired = expired;
+
+        // The expirations that the guest missed are merged, like on bare metal.
+        let lvt = self.lvt[LVT_TIMER];
+
+        if lvt & LVT_MASKED == 0 && self.enabled() {
+            self.accept(lvt as u8);
+        }
+    }
+
+    /// Returns the time at which the timer expires next, or `None` if the timer is not counting
+    /// or has already expired in the one-shot mode.
+    pub fn timer_deadline(&self) -> Option<Instant> {
+        let start = self.timer_start?;
+
+        if !self.timer_periodic() && self.timer_expired > 0 {
+            return None;
+        }
+
+        let ticks = (self.timer_expired + 1) * self.initial_count as u64 * self.timer_divisor();
+        let nanos = ticks as u128 * 1_000_000_000 / LOCAL_APIC_TIMER_FREQUENCY as u128;
+
+        Some(start + Duration::from_nanos(nanos as u64))
+    }
+
+    /// Requests an edge-triggered interrupt with the given vector. Interrupts are ignored while
+    /// the local APIC is disabled through the SVR.
+    pub fn accept(&mut self, vector: u8) {
+        if !self.enabled() {
+            return;
+        }
+
+        Self::set_vector(&mut self.irr, vector, true);
+        Self::set_vector(&mut self.tmr, vector, false);
+    }
+
+    /// Returns the highest priority interrupt that is requested and has a higher priority than
+    /// the processor priority, if any.
+    pub fn pending_interrupt(&self) -> Option<u8> {
+        let vector = Self::highest_vector(&self.irr)?;
+
+        match vector as u32 >> 4 > self.ppr() >> 4 {
+            true => Some(vector),
+            _ => None,
+        }
+    }
+
+    /// Acknowledges the highest priority pending interrupt by moving it into service, and returns
+    /// its vector.
+    pub fn acknowledge(&mut self) -> Option<u8> {
+        let vector = self.pending_interrupt()?;
+
+        Self::set_vector(&mut self.irr, vector, false);
+        Self::set_vector(&mut self.isr, vector, true);
+
+        Some(vector)
+    }
+
+    /// Returns whether the given destination of an IPI or an interrupt of the I/O APIC matches
+    /// this local APIC, where logical destinations use the flat or the cluster model as selected
+    /// through the DFR.
+    pub fn matches(&self, destination: u8, logical: bool) -> bool {
+        if !logical {
+            return destination == BROADCAST || destination == self.id;
+        }
+
+        let ldr = (self.ldr >> 24) as u8;
+
+        match self.dfr >> 28 {
+            // The flat model.
+            0xf => ldr & destination != 0,
+            // The cluster model.
+            _ => ldr >> 4 == destination >> 4 && ldr & destination & 0xf != 0,
+        }
+    }
+
+    /// Reads the register at the given offset into the MMIO page. Unsupported registers read as
+    /// zero.
+    pub fn read(&mut self, offset: u64) -> u32 {
+        let now = Instant::now();
+
+        self.poll_timer(now);
+
+        let index = ((offset >> 4) & 0x7) as usize;
+
+        match offset & !0xf {
+            APIC_ID => (self.id as u32) << 24,
+            APIC_VERSION => LOCAL_APIC_VERSION,
+            APIC_TPR => self.tpr,
+            APIC_APR => 0,
+            APIC_PPR => self.ppr(),
+            APIC_LDR => self.ldr,
+            APIC_DFR => self.dfr,
+            APIC_SVR => self.svr,
+            offset if (APIC_ISR..APIC_TMR).contains(&offset) => self.isr[index],
+            offset if (APIC_TMR..APIC_IRR).contains(&offset) => self.tmr[index],
+            offset if (APIC_IRR..APIC_ESR).contains(&offset) => self.irr[index],
+            APIC_ESR => self.esr,
+            APIC_ICR_LOW => self.icr as u32,
+            APIC_ICR_HIGH => (self.icr >> 32) as u32,
+            offset if (APIC_LVT..APIC_TIMER_INITIAL).contains(&offset) => {
+                self.lvt[((offset - APIC_LVT) >> 4) as usize]
+            }
+            APIC_TIMER_INITIAL => self.initial_count,
+            APIC_TIMER_CURRENT => self.timer_current(now),
+            APIC_TIMER_DIVIDE => self.divide,
+            _ => 0,
+        }
+    }
+
+    /// Writes the given value to the register at the given offset into the MMIO page. Returns
+    /// the IPI to deliver if the guest wrote the low half of the ICR. Writes to read-only and
+    /// unsupported registers are ignored.
+    pub fn write(&mut self, offset: u64, value: u32) -> Option<Ipi> {
+        let now = Instant::now();
+
+        self.poll_timer(now);
+
+        match offset & !0xf {
+            APIC_TPR => self.tpr = value & 0xff,
+            APIC_EOI => {
+                if let Some(vector) = Self::highest_vector(&self.isr) {
+                    Self::set_vector(&mut self.isr, vector, false);
+                }
+            }
+            APIC_LDR => self.ldr = value & 0xff00_0000,
+            APIC_DFR => self.dfr = value | 0x0fff_ffff,
+            APIC_SVR => {
+                self.svr = value & 0x1ff;
+
+                // Disabling the local APIC masks all the LVT entries.
+                if !self.enabled() {
+                    for lvt in self.lvt.iter_mut() {
+                        *lvt |= LVT_MASKED;
+                    }
+                }
+            }
+            APIC_ESR => self.esr = 0,
+            APIC_ICR_LOW => {
+                self.icr = (self.icr & !0xffff_ffff) | (value & ICR_WRITABLE) as u64;
+
+                return self.ipi();
+            }
+            APIC_ICR_HIGH => self.icr = (self.icr & 0xffff_ffff) | ((value as u64) << 32),
+            offset if (APIC_LVT..APIC_TIMER_INITIAL).contains(&offset) => {
+                let index = ((offset - APIC_LVT) >> 4) as usize;
+
+                self.lvt[index] = match self.enabled() {
+                    true => value & LVT_WRITABLE[index],
+                    _ => (value & LVT_WRITABLE[index]) | LVT_MASKED,
+                };
+            }
+            APIC_TIMER_INITIAL => {
+                self.initial_count = value;
+                self.timer_expired = 0;
+                self.timer_start = match value {
+                    0 => None,
+                    _ => Some(now),
+                };
+            }
+            APIC_TIMER_DIVIDE => self.divide = value & 0xb,
+            _ => (),
+        }
+
+        None
+    }
+
+    /// Decodes the IPI described by the ICR.
+    fn ipi(&self) -> Option<Ipi> {
+        let icr = self.icr as u32;
+        let vector = icr as u8;
+
+        let kind = match (icr & ICR_DELIVERY_MODE) >> 8 {
+            0 => IpiKind::Fixed(vector),
+            1 => IpiKind::LowestPriority(vector),
+            4 => IpiKind::Nmi,
+            // The INIT level de-assert IPI has no effect.
+            5 if icr & ICR_ASSERT == 0 => return None,
+            5 => IpiKind::Init,
+            6 => IpiKind::Startup(vector),
+            // SMIs are not supported.
+            _ => return None,
+        };
+
+        let destination = (self.icr >> 56) as u8;
+
+        let destination = match (icr & ICR_SHORTHAND) >> 18 {
+            0 if icr & ICR_LOGICAL != 0 => IpiDestination::Logical(destination),
+            0 => IpiDestination::Physical(destination),
+            1 => IpiDestination::SelfOnly,
+            2 => IpiDestination::All,
+            _ => IpiDestination::AllExcludingSelf,
+        };
+
+        Some(Ipi { kind, destination })
+    }
+
+    /// Delivers the given IPI to this local APIC.
+    fn deliver(&mut self, kind: IpiKind) {
+        match kind {
+            IpiKind::Fixed(vector) | IpiKind::LowestPriority(vector) => self.accept(vector),
+            IpiKind::Nmi => self.nmi = true,
+            IpiKind::Init => {
+                *self = Self {
+                    base: self.base,
+                    startup: Startup::Init,
+                    ..Self::new(self.id, false)
+                };
+            }
+            IpiKind::Startup(vector) => {
+                if self.startup == Startup::WaitForSipi {
+                    self.startup = Startup::Sipi(vector);
+                }
+            }
+        }
+    }
+}
+
+/// Describes what the virtual CPU should do before entering the guest, as returned by
+/// [`ApicBus::prepare`].
+#[derive(Clone, Copy, Debug, Default)]
+pub(crate) struct ApicEntry {
+    /// Whether to reset the virtual CPU, as requested by an INIT.
+    pub reset: bool,
+    /// The vector of the startup IPI to start the virtual CPU with, if any.
+    pub startup: Option<u8>,
+    /// Whether the virtual CPU waits for a startup IPI, in which case it should not run.
+    pub waiting: bool,
+    /// Whether to inject an NMI.
+    pub nmi: bool,
+    /// The vector of the interrupt to inject, if any.
+    pub vector: Option<u8>,
+    /// The time at which the timer expires next, if any.
+    pub deadline: Option<Instant>,
+}
+
+/// A local APIC and the handle to kick its virtual CPU.
+struct ApicSlot {
+    apic: LocalApic,
+    handle: VcpuHandle,
+    /// Whether the virtual CPU was kicked to deliver an IPI.
+    kicked: bool,
+}
+
+/// The local APICs of the virtual CPUs of a VM, which delivers the IPIs between them. The local
+/// APIC IDs are the vCPU IDs.
+#[derive(Default)]
+pub(crate) struct ApicBus {
+    slots: Mutex<HashMap<usize, ApicSlot>>,
+    /// Signalled whenever an interrupt is requested for any of the local APICs.
+    condvar: Condvar,
+}
+
+impl ApicBus {
+    /// Creates the local APIC of the virtual CPU with the given vCPU ID.
+    pub fn register(&self, id: usize, handle: VcpuHandle) {
+        self.slots.lock().unwrap().insert(id, ApicSlot {
+            apic: LocalApic::new(id as u8, id == 0),
+            handle,
+            kicked: false,
+        });
+    }
+
+    /// Runs the given function on the local APIC of the virtual CPU with the given vCPU ID.
+    pub fn with<T>(&self, id: usize, f: impl FnOnce(&mut LocalApic) -> T) -> Option<T> {
+        self.slots
+            .lock()
+            .unwrap()
+            .get_mut(&id)
+            .map(|slot| f(&mut slot.apic))
+    }
+
+    /// Requests an interrupt with the given vector for the virtual CPU with the given vCPU ID.
+    pub fn accept(&self, id: usize, vector: u8) {
+        self.with(id, |apic| apic.accept(vector));
+        self.condvar.notify_all();
+    }
+
+    /// Handles an MMIO read of the virtual CPU with the given vCPU ID. Returns whether the
+    /// address belongs to the MMIO page of its local APIC.
+    pub fn mmio_read(&self, id: usize, address: u64, data: &mut [u8]) -> bool {
+        let value = self.with(id, |apic| match apic.contains_address(address) {
+            true => Some(apic.read(address - apic.base)),
+            _ => None,
+        });
+
+        let value = match value.flatten() {
+            Some(value) => value,
+            _ => return false,
+        };
+
+        for (byte, value) in data.iter_mut().zip(value.to_le_bytes().iter()) {
+            *byte = *value;
+        }
+
+        true
+    }
+
+    /// Handles an MMIO write of the virtual CPU with the given vCPU ID, and delivers the IPI sent
+    /// by the write, if any. Returns whether the address belongs to the MMIO page of its local
+    /// APIC.
+    pub fn mmio_write(&self, id: usize, address: u64, data: &[u8]) -> Result<bool, Error> {
+        let mut bytes = [0u8; 4];
+
+        for (byte, value) in bytes.iter_mut().zip(data) {
+            *byte = *value;
+        }
+
+        let value = u32::from_le_bytes(bytes);
+
+        let ipi = self.with(id, |apic| match apic.contains_address(address) {
+            true => Some(apic.write(address - apic.base, value)),
+            _ => None,
+        });
+
+        match ipi.flatten() {
+            Some(Some(ipi)) => self.send(id, ipi)?,
+            Some(None) => (),
+            None => return Ok(false),
+        }
+
+        Ok(true)
+    }
+
+    /// Delivers the given IPI sent by the virtual CPU with the given vCPU ID, and kicks the other
+    /// virtual CPUs it was delivered to, such that they deliver it promptly.
+    fn send(&self, source: usize, ipi: Ipi) -> Result<(), Error> {
+        let mut slots = self.slots.lock().unwrap();
+        let mut ids: Vec<usize> = slots.keys().copied().collect();
+
+        ids.sort_unstable();
+
+        let mut targets: Vec<usize> = ids
+            .into_iter()
+            .filter(|id| {
+                let apic = &slots[id].apic;
+
+                match ipi.destination {
+                    IpiDestination::Physical(destination) => apic.matches(destination, false),
+                    IpiDestination::Logical(destination) => apic.matches(destination, true),
+                    IpiDestination::SelfOnly => *id == source,
+                    IpiDestination::All => true,
+                    IpiDestination::AllExcludingSelf => *id != source,
+                }
+            })
+            .collect();
+
+        if let IpiKind::LowestPriority(_) = ipi.kind {
+            targets.truncate(1);
+        }
+
+        let mut handles = vec![];
+
+        for id in targets {
+            let slot = slots.get_mut(&id).unwrap();
+
+            slot.apic.deliver(ipi.kind);
+
+            if id != source {
+                slot.kicked = true;
+                handles.push(slot.handle.clone());
+            }
+        }
+
+        drop(slots);
+        self.condvar.notify_all();
+
+        for handle in handles {
+            handle.kick()?;
+        }
+
+        Ok(())
+    }
+
+    /// Moves the MMIO page of the local APIC of the virtual CPU with the given vCPU ID.
+    pub fn set_base(&self, id: usize, address: u64) {
+        self.with(id, |apic| apic.set_base(address));
+    }
+
+    /// Returns whether the virtual CPU with the given vCPU ID was kicked to deliver an IPI since
+    /// this was last called.
+    pub fn take_kicked(&self, id: usize) -> bool {
+        self.slots
+            .lock()
+            .unwrap()
+            .get_mut(&id)
+            .map(|slot| std::mem::take(&mut slot.kicked))
+            .unwrap_or(false)
+    }
+
+    /// Returns whether the virtual CPU with the given vCPU ID has an interrupt, an NMI or an INIT
+    /// or startup IPI to handle.
+    pub fn has_interrupt(&self, id: usize) -> bool {
+        self.with(id, |apic| {
+            apic.poll_timer(Instant::now());
+
+            apic.pending_interrupt().is_some() ||
+                apic.nmi ||
+                matches!(apic.startup, Startup::Init | Startup::Sipi(_))
+        })
+        .unwrap_or(false)
+    }
+
+    /// Takes the events to handle before the virtual CPU with the given vCPU ID enters the
+    /// guest, where the interrupt to inject is moved into service.
+    pub fn prepare(&self, id: usize) -> ApicEntry {
+        self.with(id, |apic| {
+            apic.poll_timer(Instant::now());
+
+            let mut entry = ApicEntry::default();
+
+            if apic.startup == Startup::Init {
+                apic.startup = Startup::WaitForSipi;
+                entry.reset = true;
+            }
+
+            if let Startup::Sipi(vector) = apic.startup {
+                apic.startup = Startup::Running;
+                entry.startup = Some(vector);
+            }
+
+            if apic.startup != Startup::Running {
+                entry.waiting = true;
+                return entry;
+            }
+
+            entry.nmi = std::mem::take(&mut apic.nmi);
+            entry.vector = apic.acknowledge();
+            entry.deadline = apic.timer_deadline();
+            entry
+        })
+        .unwrap_or_default()
+    }
+
+    /// Waits until the virtual CPU with the given vCPU ID has an interrupt, an NMI or an INIT or
+    /// startup IPI to handle, or until the given timeout passes. Returns whether there is
+    /// anything to handle.
+    pub fn wait(&self, id: usize, timeout: Duration) -> bool {
+        let end = Instant::now() + timeout;
+
+        loop {
+            if self.has_interrupt(id) {
+                return true;
+            }
+
+            let now = Instant::now();
+
+            if now >= end {
+                return false;
+            }
+
+            // Wake up in time for the timer to expire.
+            let deadline = self.with(id, |apic| apic.timer_deadline()).flatten();
+            let wake = deadline.map(|deadline| deadline.min(end)).unwrap_or(end);
+            let slots = self.slots.lock().unwrap();
+
+            let _ = self.condvar.wait_timeout(slots, wake.saturating_duration_since(now));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Helper function to create the local APIC of the bootstrap processor with the local APIC
+    /// enabled through the SVR.
+    fn enabled_apic() -> LocalApic {
+        let mut apic = LocalApic::new(0, true);
+
+        apic.write(APIC_SVR, SVR_ENABLED | 0xff);
+        apic
+    }
+
+    /// Helper function to send an IPI through the ICR.
+    fn send(apic: &mut LocalApic, low: u32, destination: u8) -> Option<Ipi> {
+        apic.write(APIC_ICR_HIGH, (destination as u32) << 24);
+        apic.write(APIC_ICR_LOW, low)
+    }
+
+    #[test]
+    fn registers() {
+        let mut apic = LocalApic::new(3, true);
+
+        assert_eq!(apic.read(APIC_ID), 3 << 24);
+        assert_eq!(apic.read(APIC_VERSION), LOCAL_APIC_VERSION);
+        assert_eq!(apic.read(APIC_DFR), 0xffff_ffff);
+        assert_eq!(apic.read(APIC_SVR), 0xff);
+
+        // Read-only registers ignore writes.
+        apic.write(APIC_ID, 0x0500_0000);
+        apic.write(APIC_VERSION, 0);
+        assert_eq!(apic.read(APIC_ID), 3 << 24);
+        assert_eq!(apic.read(APIC_VERSION), LOCAL_APIC_VERSION);
+
+        // Only the writable bits are stored.
+        apic.write(APIC_TPR, 0xffff_ff20);
+        apic.write(APIC_LDR, 0xffff_ffff);
+        apic.write(APIC_DFR, 0x0000_0000);
+        apic.write(APIC_SVR, 0xffff_ffff);
+        apic.write(APIC_TIMER_DIVIDE, 0xffff_ffff);
+        assert_eq!(apic.read(APIC_TPR), 0x20);
+        assert_eq!(apic.read(APIC_LDR), 0xff00_0000);
+        assert_eq!(apic.read(APIC_DFR), 0x0fff_ffff);
+        assert_eq!(apic.read(APIC_SVR), 0x1ff);
+        assert_eq!(apic.read(APIC_TIMER_DIVIDE), 0xb);
+
+        // The low bits of the offset are ignored.
+        assert_eq!(apic.read(APIC_TPR + 4), 0x20);
+
+        // Writing the ESR clears it.
+        apic.esr = 0x40;
+        apic.write(APIC_ESR, 0xffff_ffff);
+        assert_eq!(apic.read(APIC_ESR), 0);
+
+        // Unsupported registers read as zero and ignore writes.
+        for &offset in &[0x000, 0x3f0, 0xff0, 0x1000, u64::MAX] {
+            assert_eq!(apic.write(offset, 0xffff_ffff), None);
+            assert_eq!(apic.read(offset), 0);
+        }
+    }
+
+    #[test]
+    fn base_address() {
+        let mut apic = LocalApic::new(0, true);
+
+        assert!(apic.contains_address(LOCAL_APIC_ADDRESS));
+        assert!(apic.contains_address(LOCAL_APIC_ADDRESS + LOCAL_APIC_SIZE - 1));
+        assert!(!apic.contains_address(LOCAL_APIC_ADDRESS + LOCAL_APIC_SIZE));
+
+        // The flags of the IA32_APIC_BASE MSR are ignored.
+        apic.set_base(0xfed0_0000 | 0x900);
+        assert!(apic.contains_address(0xfed0_0000));
+        assert!(!apic.contains_address(LOCAL_APIC_ADDRESS));
+    }
+
+    #[test]
+    fn disabled() {
+        let mut apic = LocalApic::new(0, true);
+
+        // Interrupts are ignored while the local APIC is disabled.
+        apic.accept(0x40);
+        assert_eq!(apic.pending_interrupt(), None);
+
+        // LVT entries stay masked while the local APIC is disabled.
+        apic.write(APIC_LVT, 0x40);
+        assert_eq!(apic.read(APIC_LVT), 0x40 | LVT_MASKED);
+
+        apic.write(APIC_SVR, SVR_ENABLED | 0xff);
+        apic.write(APIC_LVT, 0xffff_ffff);
+        assert_eq!(apic.read(APIC_LVT), LVT_WRITABLE[LVT_TIMER]);
+
+        apic.accept(0x40);
+        assert_eq!(apic.pending_interrupt(), Some(0x40));
+
+        // Disabling the local APIC masks all LVT entries.
+        apic.write(APIC_SVR, 0xff);
+
+        for index in 0..LVT_COUNT {
+            assert_ne!(apic.read(APIC_LVT + index as u64 * 0x10) & LVT_MASKED, 0);
+        }
+    }
+
+    #[test]
+    fn priorities() {
+        let mut apic = enabled_apic();
+
+        apic.accept(0x31);
+        apic.accept(0x52);
+        assert_eq!(apic.read(APIC_IRR + 0x10), 1 << (0x31 - 0x20));
+        assert_eq!(apic.read(APIC_IRR + 0x20), 1 << (0x52 - 0x40));
+
+        assert_eq!(apic.acknowledge(), Some(0x52));
+        assert_eq!(apic.read(APIC_ISR + 0x20), 1 << (0x52 - 0x40));
+        assert_eq!(apic.read(APIC_PPR), 0x50);
+
+        // Interrupts of a lower or the same priority class wait for the EOI.
+        apic.accept(0x5f);
+        assert_eq!(apic.pending_interrupt(), None);
+
+        apic.accept(0x61);
+        assert_eq!(apic.acknowledge(), Some(0x61));
+
+        // The EOI completes the highest priority interrupt in service.
+        apic.write(APIC_EOI, 0);
+        assert_eq!(apic.read(APIC_PPR), 0x50);
+        apic.write(APIC_EOI, 0);
+        assert_eq!(apic.read(APIC_PPR), 0);
+        assert_eq!(apic.acknowledge(), Some(0x5f));
+        apic.write(APIC_EOI, 0);
+        assert_eq!(apic.acknowledge(), Some(0x31));
+        apic.write(APIC_EOI, 0);
+        assert_eq!(apic.acknowledge(), None);
+
+        // An EOI without any interrupt in service has no effect.
+        apic.write(APIC_EOI, 0);
+
+        // The task priority masks the interrupts of the same or a lower priority class.
+        apic.write(APIC_TPR, 0x70);
+        apic.accept(0x7f);
+        assert_eq!(apic.read(APIC_PPR), 0x70);
+        assert_eq!(apic.pending_interrupt(), None);
+
+        apic.write(APIC_TPR, 0x60);
+        assert_eq!(apic.pending_interrupt(), Some(0x7f));
+    }
+
+    #[test]
+    fn destinations() {
+        let mut apic = LocalApic::new(2, false);
+
+        assert!(apic.matches(2, false));
+        assert!(apic.matches(BROADCAST, false));
+        assert!(!apic.matches(1, false));
+
+        // The flat model.
+        apic.write(APIC_LDR, 0x0400_0000);
+        assert!(apic.matches(0x04, true));
+        assert!(apic.matches(0x0c, true));
+        assert!(!apic.matches(0x03, true));
+
+        // The cluster model.
+        apic.write(APIC_DFR, 0x0fff_ffff);
+        apic.write(APIC_LDR, 0x2100_0000);
+        assert!(apic.matches(0x21, true));
+        assert!(apic.matches(0x23, true));
+        assert!(!apic.matches(0x22, true));
+        assert!(!apic.matches(0x11, true));
+    }
+
+    #[test]
+    fn ipis() {
+        let mut apic = enabled_apic();
+
+        let ipi = |kind, destination| Some(Ipi { kind, destination });
+
+        assert_eq!(
+            send(&mut apic, 0x40, 2),
+            ipi(IpiKind::Fixed(0x40), IpiDestination::Physical(2)),
+        );
+        assert_eq!(
+            send(&mut apic, 0x141 | ICR_LOGICAL, 0x0c),
+            ipi(IpiKind::LowestPriority(0x41), IpiDestination::Logical(0x0c)),
+        );
+        assert_eq!(
+            send(&mut apic, 0x400 | (1 << 18), 0),
+            ipi(IpiKind::Nmi, IpiDestination::SelfOnly),
+        );
+        assert_eq!(
+            send(&mut apic, 0x500 | ICR_ASSERT | (3 << 18), 0),
+            ipi(IpiKind::Init, IpiDestination::AllExcludingSelf),
+        );
+        assert_eq!(
+            send(&mut apic, 0x610 | (2 << 18), 0),
+            ipi(IpiKind::Startup(0x10), IpiDestination::All),
+        );
+
+        // The INIT level de-assert IPI, SMIs and the reserved delivery modes are ignored.
+        assert_eq!(send(&mut apic, 0x500, 0), None);
+
+        for &mode in &[2, 3, 7] {
+            assert_eq!(send(&mut apic, mode << 8, 0), None);
+        }
+
+        // The read-only and reserved bits of the ICR are not stored.
+        send(&mut apic, 0xffff_ffff, 0xff);
+        assert_eq!(apic.read(APIC_ICR_LOW), ICR_WRITABLE);
+        assert_eq!(apic.read(APIC_ICR_HIGH), 0xff00_0000);
+    }
+
+    #[test]
+    fn init_and_startup() {
+        let mut apic = LocalApic::new(1, false);
+
+        assert_eq!(apic.startup, Startup::WaitForSipi);
+
+        apic.deliver(IpiKind::Startup(0x10));
+        assert_eq!(apic.startup, Startup::Sipi(0x10));
+
+        // Further startup IPIs are ignored.
+        apic.deliver(IpiKind::Startup(0x20));
+        assert_eq!(apic.startup, Startup::Sipi(0x10));
+
+        apic.startup = Startup::Running;
+        apic.deliver(IpiKind::Startup(0x20));
+        assert_eq!(apic.startup, Startup::Running);
+
+        // An INIT resets the local APIC, apart from its ID and its base address.
+        apic.set_base(0xfed0_0000);
+        apic.write(APIC_SVR, SVR_ENABLED | 0xff);
+        apic.write(APIC_TPR, 0x20);
+        apic.accept(0x40);
+        apic.deliver(IpiKind::Nmi);
+        apic.deliver(IpiKind::Init);
+
+        assert_eq!(apic.startup, Startup::Init);
+        assert_eq!(apic.id(), 1);
+        assert!(apic.contains_address(0xfed0_0000));
+        assert_eq!(apic.read(APIC_SVR), 0xff);
+        assert_eq!(apic.read(APIC_TPR), 0);
+        assert_eq!(apic.pending_interrupt(), None);
+        assert!(!apic.nmi);
+    }
+
+    #[test]
+    fn one_shot_timer() {
+        let mut apic = enabled_apic();
+
+        apic.write(APIC_TIMER_DIVIDE, 0xb);
+        apic.write(APIC_LVT, 0x40);
+        apic.write(APIC_TIMER_INITIAL, 100);
+
+        let start = apic.timer_start.unwrap();
+
+        assert_eq!(apic.timer_current(start + Duration::from_nanos(30)), 70);
+        assert_eq!(apic.timer_deadline(), Some(start + Duration::from_nanos(100)));
+
+        apic.poll_timer(start + Duration::from_nanos(99));
+        assert_eq!(apic.pending_interrupt(), None);
+
+        apic.poll_timer(start + Duration::from_nanos(100));
+        assert_eq!(apic.acknowledge(), Some(0x40));
+        assert_eq!(apic.timer_current(start + Duration::from_nanos(150)), 0);
+        assert_eq!(apic.timer_deadline(), None);
+
+        // The timer only expires once.
+        apic.poll_timer(start + Duration::from_nanos(1000));
+        assert_eq!(apic.pending_interrupt(), None);
+
+        // Writing an initial count of zero stops the timer.
+        apic.write(APIC_TIMER_INITIAL, 0);
+        assert_eq!(apic.read(APIC_TIMER_CURRENT), 0);
+        assert_eq!(apic.timer_deadline(), None);
+    }
+
+    #[test]
+    fn periodic_timer() {
+        let mut apic = enabled_apic();
+
+        // Divide the bus clock by two.
+        apic.write(APIC_TIMER_DIVIDE, 0x0);
+        apic.write(APIC_LVT, 0x40 | LVT_TIMER_PERIODIC);
+        apic.write(APIC_TIMER_INITIAL, 100);
+
+        let start = apic.timer_start.unwrap();
+
+        assert_eq!(apic.timer_deadline(), Some(start + Duration::from_nanos(200)));
+        assert_eq!(apic.timer_current(start + Duration::from_nanos(500)), 50);
+
+        // The expirations that were missed are merged.
+        apic.poll_timer(start + Duration::from_nanos(500));
+        assert_eq!(apic.acknowledge(), Some(0x40));
+        assert_eq!(apic.timer_deadline(), Some(start + Duration::from_nanos(600)));
+
+        apic.poll_timer(start + Duration::from_nanos(599));
+        assert_eq!(apic.pending_interrupt(), None);
+
+        // The interrupt is not requested while the LVT entry is masked.
+        apic.write(APIC_LVT, 0x40 | LVT_TIMER_PERIODIC | LVT_MASKED);
+        apic.poll_timer(start + Duration::from_nanos(600));
+        assert_eq!(apic.pending_interrupt(), None);
+    }
+}
@@ -0,0 +1,1039 @@
+//! This module provides emulations of the interrupt controllers of x86 in user space, i.e. the
+//! pair of cascaded 8259A PICs, see [`Pic`], and the I/O APIC, see [`IoApic`]. This allows
+//! interrupt-driven guests to run on the platforms without an in-kernel irqchip, i.e. Mac OS X,
+//! and Microsoft Windows without the local APIC emulation, see
+//! [`crate::VmBuilder::with_in_kernel_irqchip`].
+//!
+//! [`InterruptController`] combines both and routes the interrupt lines of the devices in the same
+//! way as [`crate::Vm::set_irq_line`], where the GSIs below 16 are the ISA interrupts that are
+//! routed to both the PIC and the I/O APIC. The interrupts are queued per virtual CPU until the
+//! VMM calls [`InterruptController::deliver`] on the thread running the virtual CPU, e.g. before
+//! resuming the virtual CPU, which injects them through [`Vcpu::inject_interrupt`] and
+//! [`Vcpu::inject_nmi`]. The VMM should kick the virtual CPUs for which
+//! [`InterruptController::has_pending`] holds after raising an interrupt line, such that the
+//! interrupts are delivered promptly.
+//!
+//! The output of the PIC is connected to LINT0 of the virtual CPU with local APIC ID 0, i.e. in
+//! virtual wire mode. As the local APICs are not emulated, the logical destinations of the I/O
+//! APIC are interpreted in the flat model with local APIC ID `n` being logical ID `1 << n`, and
+//! the lowest priority delivery mode delivers to the first virtual CPU in the destination.
+//! Level-triggered interrupts of the I/O APIC are only delivered again once the guest signals the
+//! end of the interrupt, either through the EOI register of the I/O APIC or through
+//! [`InterruptController::end_of_interrupt`] if the VMM observes the EOI of the local APIC.
+
+use crate::error::Error;
+use crate::vcpu::Vcpu;
+use std::collections::VecDeque;
+
+/// The I/O port of the command register of the master PIC.
+pub const PIC_MASTER_COMMAND: u16 = 0x20;
+/// The I/O port of the data register of the master PIC.
+pub const PIC_MASTER_DATA: u16 = 0x21;
+/// The I/O port of the command register of the slave PIC.
+pub const PIC_SLAVE_COMMAND: u16 = 0xa0;
+/// The I/O port of the data register of the slave PIC.
+pub const PIC_SLAVE_DATA: u16 = 0xa1;
+/// The I/O port of the edge/level control register (ELCR) of the master PIC.
+pub const PIC_MASTER_ELCR: u16 = 0x4d0;
+/// The I/O port of the edge/level control register (ELCR) of the slave PIC.
+pub const PIC_SLAVE_ELCR: u16 = 0x4d1;
+
+/// The default guest physical address of the I/O APIC.
+pub const IO_APIC_ADDRESS: u64 = 0xfec0_0000;
+/// The size of the MMIO range of the I/O APIC.
+pub const IO_APIC_SIZE: u64 = 0x1000;
+/// The number of pins of the I/O APIC.
+pub const IO_APIC_PINS: usize = 24;
+
+/// The IRQ of the master PIC that the slave PIC is cascaded to.
+const PIC_CASCADE_IRQ: u8 = 2;
+/// The IRQs of the master PIC that can be level-triggered, i.e. all but IRQs 0, 1 and 2.
+const PIC_MASTER_ELCR_MASK: u8 = 0xf8;
+/// The IRQs of the slave PIC that can be level-triggered, i.e. all but IRQs 8 and 13.
+const PIC_SLAVE_ELCR_MASK: u8 = 0xde;
+
+/// The offset of the register select register of the I/O APIC.
+const IO_APIC_IOREGSEL: u64 = 0x00;
+/// The offset of the data window of the I/O APIC.
+const IO_APIC_IOWIN: u64 = 0x10;
+/// The offset of the EOI register of the I/O APIC.
+const IO_APIC_EOI: u64 = 0x40;
+/// The version of the I/O APIC, i.e. one that has the EOI register.
+const IO_APIC_VERSION: u32 = 0x20;
+
+/// The index of the identification register of the I/O APIC.
+const IO_APIC_REG_ID: u8 = 0x00;
+/// The index of the version register of the I/O APIC.
+const IO_APIC_REG_VERSION: u8 = 0x01;
+/// The index of the arbitration register of the I/O APIC.
+const IO_APIC_REG_ARBITRATION: u8 = 0x02;
+/// The index of the first redirection table register of the I/O APIC.
+const IO_APIC_REG_REDIRECTION: u8 = 0x10;
+
+/// The delivery mode of a redirection entry.
+const REDIRECTION_DELIVERY_MODE: u64 = 0x7 << 8;
+/// The destination mode of a redirection entry, which is set for logical destinations.
+const REDIRECTION_LOGICAL: u64 = 1 << 11;
+/// The delivery status of a redirection entry.
+const REDIRECTION_DELIVERY_STATUS: u64 = 1 << 12;
+/// The remote IRR of a redirection entry, which is set while a level-triggered interrupt is
+/// being serviced.
+const REDIRECTION_REMOTE_IRR: u64 = 1 << 14;
+/// The trigger mode of a redirection entry, which is set for level-triggered interrupts.
+const REDIRECTION_LEVEL: u64 = 1 << 15;
+/// The mask of a redirection entry.
+const REDIRECTION_MASKED: u64 = 1 << 16;
+/// The bits of a redirection entry that the guest cannot write.
+const REDIRECTION_READ_ONLY: u64 = REDIRECTION_DELIVERY_STATUS | REDIRECTION_REMOTE_IRR;
+
+/// The fixed delivery mode.
+const DELIVERY_FIXED: u64 = 0;
+/// The lowest priority delivery mode.
+const DELIVERY_LOWEST_PRIORITY: u64 = 1;
+/// The NMI delivery mode.
+const DELIVERY_NMI: u64 = 4;
+/// The ExtINT delivery mode, where the vector is supplied by the PIC.
+const DELIVERY_EXTINT: u64 = 7;
+
+/// The destination that refers to all local APICs in the physical destination mode.
+const BROADCAST: u8 = 0xff;
+
+/// An interrupt that is pending for a virtual CPU.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Interrupt {
+    /// An external interrupt with the given vector.
+    Fixed(u8),
+    /// A non-maskable interrupt.
+    Nmi,
+    /// An external interrupt of which the vector is supplied by the PIC upon delivery.
+    ExtInt,
+}
+
+/// An interrupt that has been signalled by the I/O APIC, as returned by [`IoApic::set_irq`].
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct IoApicInterrupt {
+    /// The interrupt to deliver.
+    pub interrupt: Interrupt,
+    /// The destination, i.e. a local APIC ID, or a set of logical IDs if `logical` is set.
+    pub destination: u8,
+    /// Whether the destination is a set of logical IDs.
+    pub logical: bool,
+    /// Whether the interrupt is delivered to the lowest priority virtual CPU in the destination.
+    pub lowest_priority: bool,
+}
+
+/// The state of a single 8259A PIC.
+#[derive(Clone, Debug, Default)]
+struct PicChip {
+    /// The interrupt request register.
+    irr: u8,
+    /// The in-service register.
+    isr: u8,
+    /// The interrupt mask register.
+    imr: u8,
+    /// The IRQs that are level-triggered.
+    elcr: u8,
+    /// The IRQs of which the line is raised, to detect the rising edges.
+    lines: u8,
+    /// The vector of IRQ 0, as set through ICW2.
+    vector_base: u8,
+    /// The IRQ with the lowest priority minus one, i.e. the rotation of the priorities.
+    priority_add: u8,
+    /// The initialization command word that is expected next, or zero when initialized.
+    init_state: u8,
+    /// Whether the initialization sequence includes ICW4.
+    icw4_needed: bool,
+    /// Whether the PIC runs without a cascaded PIC, i.e. ICW3 is skipped.
+    single: bool,
+    /// Whether the PIC ends the interrupts automatically upon acknowledging them.
+    auto_eoi: bool,
+    /// Whether the priorities rotate in the automatic EOI mode.
+    rotate_on_auto_eoi: bool,
+    /// Whether reading the command register returns the ISR rather than the IRR.
+    read_isr: bool,
+    /// Whether the next read of the command register is a poll command.
+    poll: bool,
+}
+
+impl PicChip {
+    /// Creates a PIC of which all IRQs are masked until the guest initializes it.
+    fn new() -> Self {
+        Self {
+            imr: 0xff,
+            ..Default::default()
+        }
+    }
+
+    /// Returns the priority of the highest priority IRQ in the given mask, where zero is the
+    /// highest priority.
+    fn priority(&self, mask: u8) -> Option<u8> {
+        (0..8).find(|priority| mask & (1 << ((priority + self.priority_add) & 7)) != 0)
+    }
+
+    /// Returns the highest priority IRQ that is requested, unmasked and has a higher priority than
+    /// the IRQs in service.
+    fn pending_irq(&self) -> Option<u8> {
+        if self.init_state != 0 {
+            return None;
+        }
+
+        let priority = self.priority(self.irr & !self.imr)?;
+
+        match self.priority(self.isr) {
+            Some(in_service) if in_service <= priority => None,
+            _ => Some((priority + self.priority_add) & 7),
+        }
+    }
+
+    /// Raises or lowers the line of the given IRQ.
+    fn set_irq(&mut self, irq: u8, level: bool) {
+        let mask = 1 << irq;
+
+        if self.elcr & mask != 0 {
+            // The level-triggered IRQs are requested as long as the line is raised.
+            match level {
+                true => self.irr |= mask,
+                _ => self.irr &= !mask,
+            }
+        } else if level && self.lines & mask == 0 {
+            self.irr |= mask;
+        }
+
+        match level {
+            true => self.lines |= mask,
+            _ => self.lines &= !mask,
+        }
+    }
+
+    /// Acknowledges the given IRQ, i.e. the interrupt acknowledge cycle.
+    fn acknowledge(&mut self, irq: u8) {
+        let mask = 1 << irq;
+
+        match self.auto_eoi {
+            true if self.rotate_on_auto_eoi => self.priority_add = (irq + 1) & 7,
+            true => (),
+            _ => self.isr |= mask,
+        }
+
+        // The level-triggered IRQs remain requested as long as the line is raised.
+        if self.elcr & mask == 0 {
+            self.irr &= !mask;
+        }
+    }
+
+    /// Reads the command register.
+    fn read_command(&mut self) -> u8 {
+        if self.poll {
+            self.poll = false;
+
+            return match self.pending_irq() {
+                Some(irq) => {
+                    self.acknowledge(irq);
+                    0x80 | irq
+                }
+                _ => 0,
+            };
+        }
+
+        match self.read_isr {
+            true => self.isr,
+            _ => self.irr,
+        }
+    }
+
+    /// Writes the command register, i.e. ICW1, OCW2 or OCW3.
+    fn write_command(&mut self, value: u8) {
+        if value & 0x10 != 0 {
+            // ICW1 resets the PIC and starts the initialization sequence.
+            *self = Self {
+                elcr: self.elcr,
+                vector_base: self.vector_base,
+                init_state: 1,
+                icw4_needed: value & 0x01 != 0,
+                single: value & 0x02 != 0,
+                ..Default::default()
+            };
+        } else if value & 0x08 != 0 {
+            // OCW3 selects the register to read or issues a poll command.
+            if value & 0x04 != 0 {
+                self.poll = true;
+            }
+
+            if value & 0x02 != 0 {
+                self.read_isr = value & 0x01 != 0;
+            }
+        } else {
+            // OCW2 ends interrupts and rotates the priorities.
+            let irq = value & 0x7;
+
+            match value >> 5 {
+                0 => self.rotate_on_auto_eoi = false,
+                4 => self.rotate_on_auto_eoi = true,
+                command @ 1 | command @ 5 => {
+                    if let Some(priority) = self.priority(self.isr) {
+                        let irq = (priority + self.priority_add) & 7;
+
+                        self.isr &= !(1 << irq);
+
+                        if command == 5 {
+                            self.priority_add = (irq + 1) & 7;
+                        }
+                    }
+                }
+                3 => self.isr &= !(1 << irq),
+                6 => self.priority_add = (irq + 1) & 7,
+                7 => {
+                    self.isr &= !(1 << irq);
+                    self.priority_add = (irq + 1) & 7;
+                }
+                _ => (),
+            }
+        }
+    }
+
+    /// Reads the data register, i.e. the IMR.
+    fn read_data(&mut self) -> u8 {
+        self.imr
+    }
+
+    /// Writes the data register, i.e. ICW2, ICW3 and ICW4 during the initialization sequence, or
+    /// OCW1 otherwise.
+    fn write_data(&mut self, value: u8) {
+        self.init_state = match self.init_state {
+            0 => {
+                self.imr = value;
+                0
+            }
+            1 => {
+                self.vector_base = value & 0xf8;
+
+                match (self.single, self.icw4_needed) {
+                    (false, _) => 2,
+                    (true, true) => 3,
+                    (true, false) => 0,
+                }
+            }
+            2 => match self.icw4_needed {
+                true => 3,
+                _ => 0,
+            },
+            _ => {
+                self.auto_eoi = value & 0x02 != 0;
+                0
+            }
+        };
+    }
+}
+
+/// An emulation of the pair of cascaded 8259A PICs of the PC/AT, where the slave PIC is cascaded
+/// to IRQ 2 of the master PIC. All IRQs are masked until the guest initializes the PICs.
+#[derive(Clone, Debug)]
+pub struct Pic {
+    /// The master PIC, i.e. IRQs 0 to 7.
+    master: PicChip,
+    /// The slave PIC, i.e. IRQs 8 to 15.
+    slave: PicChip,
+}
+
+impl Default for Pic {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Pic {
+    /// Creates the pair of PICs.
+    pub fn new() -> Self {
+        Self {
+            master: PicChip::new(),
+            slave: PicChip::new(),
+        }
+    }
+
+    /// Returns whether the given I/O port belongs to the PICs, i.e. ports 0x20, 0x21, 0xa0,
+    /// 0xa1, 0x4d0 and 0x4d1.
+    pub fn contains_port(port: u16) -> bool {
+        matches!(
+            port,
+            PIC_MASTER_COMMAND | PIC_MASTER_DATA | PIC_SLAVE_COMMAND | PIC_SLAVE_DATA |
+            PIC_MASTER_ELCR | PIC_SLAVE_ELCR
+        )
+    }
+
+    /// Propagates the output of the slave PIC to the cascade IRQ of the master PIC.
+    fn update_cascade(&mut self) {
+        let level = self.slave.pending_irq().is_some();
+
+        self.master.set_irq(PIC_CASCADE_IRQ, level);
+    }
+
+    /// Raises or lowers the line of the given IRQ, i.e. 0 to 15. Returns
+    /// [`Error::InvalidArgument`] if the IRQ does not exist or is IRQ 2, which the slave PIC is
+    /// cascaded to.
+    pub fn set_irq(&mut self, irq: u8, level: bool) -> Result<(), Error> {
+        match irq {
+            PIC_CASCADE_IRQ => return Err(Error::InvalidArgument),
+            0..=7 => self.master.set_irq(irq, level),
+            8..=15 => self.slave.set_irq(irq - 8, level),
+            _ => return Err(Error::InvalidArgument),
+        }
+
+        self.update_cascade();
+
+        Ok(())
+    }
+
+    /// Returns whether the PICs signal an interrupt to the virtual CPU.
+    pub fn has_interrupt(&self) -> bool {
+        self.master.pending_irq().is_some()
+    }
+
+    /// Acknowledges the highest priority interrupt and returns its vector, or `None` if the PICs
+    /// do not signal an interrupt. If the slave PIC no longer signals an interrupt, the spurious
+    /// IRQ 15 is returned, like on bare metal.
+    pub fn acknowledge(&mut self) -> Option<u8> {
+        let irq = self.master.pending_irq()?;
+
+        self.master.acknowledge(irq);
+
+        let vector = match irq {
+            PIC_CASCADE_IRQ => match self.slave.pending_irq() {
+                Some(irq) => {
+                    self.slave.acknowledge(irq);
+                    self.slave.vector_base + irq
+                }
+                _ => self.slave.vector_base + 7,
+            },
+            _ => self.master.vector_base + irq,
+        };
+
+        self.update_cascade();
+
+        Some(vector)
+    }
+
+    /// Handles an `in` instruction on the given port by filling the given data, where accesses
+    /// wider than a byte read the same byte repeatedly. Ports that do not belong to the PICs read
+    /// as all ones.
+    pub fn io_in(&mut self, port: u16, data: &mut [u8]) {
+        let value = match port {
+            PIC_MASTER_COMMAND => self.master.read_command(),
+            PIC_MASTER_DATA => self.master.read_data(),
+            PIC_SLAVE_COMMAND => self.slave.read_command(),
+            PIC_SLAVE_DATA => self.slave.read_data(),
+            PIC_MASTER_ELCR => self.master.elcr,
+            PIC_SLAVE_ELCR => self.slave.elcr,
+            _ => 0xff,
+        };
+
+        self.update_cascade();
+
+        for byte in data {
+            *byte = value;
+        }
+    }
+
+    /// Handles an `out` instruction on the given port with the given data, where only the first
+    /// byte is used. Writes to ports that do not belong to the PICs are ignored.
+    pub fn io_out(&mut self, port: u16, data: &[u8]) {
+        let value = match data.first() {
+            Some(&value) => value,
+            _ => return,
+        };
+
+        match port {
+            PIC_MASTER_COMMAND => self.master.write_command(value),
+            PIC_MASTER_DATA => self.master.write_data(value),
+            PIC_SLAVE_COMMAND => self.slave.write_command(value),
+            PIC_SLAVE_DATA => self.slave.write_data(value),
+            PIC_MASTER_ELCR => self.master.elcr = value & PIC_MASTER_ELCR_MASK,
+            PIC_SLAVE_ELCR => self.slave.elcr = value & PIC_SLAVE_ELCR_MASK,
+            _ => (),
+        }
+
+        self.update_cascade();
+    }
+}
+
+/// An emulation of an I/O APIC with 24 pins, where all pins are masked until the guest programs
+/// the redirection table.
+#[derive(Clone, Debug)]
+pub struct IoApic {
+    /// The guest physical address of the MMIO range.
+    address: u64,
+    /// The I/O APIC ID.
+    id: u8,
+    /// The selected register.
+    register: u8,
+    /// The redirection table.
+    redirection: [u64; IO_APIC_PINS],
+    /// The pins of which the line is raised.
+    lines: u32,
+    /// The edge-triggered pins that have a pending interrupt, e.g. because they were masked.
+    pending: u32,
+}
+
+impl IoApic {
+    /// Creates the I/O APIC with the given I/O APIC ID at the given guest physical address,
+    /// which is usually [`IO_APIC_ADDRESS`].
+    pub fn new(address: u64, id: u8) -> Self {
+        Self {
+            address,
+            id,
+            register: 0,
+            redirection: [REDIRECTION_MASKED; IO_APIC_PINS],
+            lines: 0,
+            pending: 0,
+        }
+    }
+
+    /// Returns whether the given guest physical address belongs to the MMIO range of the I/O
+    /// APIC.
+    pub fn contains_address(&self, address: u64) -> bool {
+        (self.address..self.address + IO_APIC_SIZE).contains(&address)
+    }
+
+    /// Raises or lowers the line of the given pin. Returns the interrupt signalled by the pin, if
+    /// any, or [`Error::InvalidArgument`] if the pin does not exist.
+    pub fn set_irq(&mut self, pin: usize, level: bool) -> Result<Option<IoApicInterrupt>, Error> {
+        if pin >= IO_APIC_PINS {
+            return Err(Error::InvalidArgument);
+        }
+
+        let mask = 1 << pin;
+        let rising = level && self.lines & mask == 0;
+
+        match level {
+            true => self.lines |= mask,
+            _ => self.lines &= !mask,
+        }
+
+        if self.redirection[pin] & REDIRECTION_LEVEL == 0 && rising {
+            self.pending |= mask;
+        }
+
+        Ok(self.service(pin))
+    }
+
+    /// Delivers the interrupt of the given pin if it is unmasked and pending, i.e. the line of a
+    /// level-triggered pin is raised and the previous interrupt has ended, or an edge-triggered
+    /// pin has seen a rising edge.
+    fn service(&mut self, pin: usize) -> Option<IoApicInterrupt> {
+        let mask = 1 << pin;
+        let entry = self.redirection[pin];
+
+        if entry & REDIRECTION_MASKED != 0 {
+            return None;
+        }
+
+        if entry & REDIRECTION_LEVEL != 0 {
+            if self.lines & mask == 0 || entry & REDIRECTION_REMOTE_IRR != 0 {
+                return None;
+            }
+
+            self.redirection[pin] |= REDIRECTION_REMOTE_IRR;
+        } else {
+            if self.pending & mask == 0 {
+                return None;
+            }
+
+            self.pending &= !mask;
+        }
+
+        let delivery_mode = (entry & REDIRECTION_DELIVERY_MODE) >> 8;
+
+        let interrupt = match delivery_mode {
+            DELIVERY_FIXED | DELIVERY_LOWEST_PRIORITY => Interrupt::Fixed(entry as u8),
+            DELIVERY_NMI => Interrupt::Nmi,
+            DELIVERY_EXTINT => Interrupt::ExtInt,
+            // SMIs and INITs are not supported.
+            _ => return None,
+        };
+
+        Some(IoApicInterrupt {
+            interrupt,
+            destination: (entry >> 56) as u8,
+            logical: entry & REDIRECTION_LOGICAL != 0,
+            lowest_priority: delivery_mode == DELIVERY_LOWEST_PRIORITY,
+        })
+    }
+
+    /// Delivers the pending interrupts of all pins.
+    fn service_all(&mut self) -> Vec<IoApicInterrupt> {
+        (0..IO_APIC_PINS).filter_map(|pin| self.service(pin)).collect()
+    }
+
+    /// Ends the level-triggered interrupts with the given vector, which delivers them again if
+    /// the line of their pin is still raised. Returns the interrupts signalled as a result.
+    pub fn end_of_interrupt(&mut self, vector: u8) -> Vec<IoApicInterrupt> {
+        for entry in self.redirection.iter_mut() {
+            if *entry & REDIRECTION_LEVEL != 0 && *entry as u8 == vector {
+                *entry &= !REDIRECTION_REMOTE_IRR;
+            }
+        }
+
+        self.service_all()
+    }
+
+    /// Reads the selected register.
+    fn read_register(&self) -> u32 {
+        match self.register {
+            IO_APIC_REG_ID | IO_APIC_REG_ARBITRATION => (self.id as u32) << 24,
+            IO_APIC_REG_VERSION => IO_APIC_VERSION | ((IO_APIC_PINS as u32 - 1) << 16),
+            register if register >= IO_APIC_REG_REDIRECTION => {
+                let index = (register - IO_APIC_REG_REDIRECTION) as usize;
+
+                match self.redirection.get(index / 2) {
+                    Some(&entry) if index % 2 == 0 => entry as u32,
+                    Some(&entry) => (entry >> 32) as u32,
+                    _ => 0,
+                }
+            }
+            _ => 0,
+        }
+    }
+
+    /// Writes the selected register. Returns the interrupts signalled as a result, e.g. by
+    /// unmasking a pin with a pending interrupt.
+    fn write_register(&mut self, value: u32) -> Vec<IoApicInterrupt> {
+        match self.register {
+            IO_APIC_REG_ID => self.id = ((value >> 24) & 0xf) as u8,
+            register if register >= IO_APIC_REG_REDIRECTION => {
+                let index = (register - IO_APIC_REG_REDIRECTION) as usize;
+
+                if let Some(entry) = self.redirection.get_mut(index / 2) {
+                    let (shift, mask) = match index % 2 {
+                        0 => (0, 0xffff_ffff & !REDIRECTION_READ_ONLY),
+                        _ => (32, 0xffff_ffff << 32),
+                    };
+
+                    *entry = (*entry & !mask) | (((value as u64) << shift) & mask);
+
+                    return self.service_all();
+                }
+            }
+            _ => (),
+        }
+
+        vec![]
+    }
+
+    /// Handles an MMIO read at the given guest physical address by filling the given data.
+    /// Unsupported registers read as zero.
+    pub fn mmio_read(&mut self, address: u64, data: &mut [u8]) {
+        let value = match address.wrapping_sub(self.address) {
+            IO_APIC_IOREGSEL => self.register as u32,
+            IO_APIC_IOWIN => self.read_register(),
+            _ => 0,
+        };
+
+        for (byte, value) in data.iter_mut().zip(value.to_le_bytes().iter()) {
+            *byte = *value;
+        }
+    }
+
+    /// Handles an MMIO write at the given guest physical address with the given data, which is
+    /// zero-extended to 32 bits. Returns the interrupts signalled as a result.
+    pub fn mmio_write(&mut self, address: u64, data: &[u8]) -> Vec<IoApicInterrupt> {
+        let mut bytes = [0u8; 4];
+
+        for (byte, value) in bytes.iter_mut().zip(data) {
+            *byte = *value;
+        }
+
+        let value = u32::from_le_bytes(bytes);
+
+        match address.wrapping_sub(self.address) {
+            IO_APIC_IOREGSEL => {
+                self.register = value as u8;
+                vec![]
+            }
+            IO_APIC_IOWIN => self.write_register(value),
+            IO_APIC_EOI => self.end_of_interrupt(value as u8),
+            _ => vec![],
+        }
+    }
+}
+
+/// Combines the PICs and the I/O APIC, and queues the interrupts they signal for the virtual CPUs
+/// until they are delivered. See the [module-level documentation](self) for how to drive it.
+#[derive(Clone, Debug)]
+pub struct InterruptController {
+    /// The pair of cascaded PICs.
+    pic: Pic,
+    /// The I/O APIC.
+    io_apic: IoApic,
+    /// The interrupts pending for every virtual CPU, indexed by local APIC ID.
+    pending: Vec<VecDeque<Interrupt>>,
+}
+
+impl InterruptController {
+    /// Creates the interrupt controllers for the given number of virtual CPUs, which have the
+    /// local APIC IDs `0..vcpu_count`. The I/O APIC is placed at [`IO_APIC_ADDRESS`] with the
+    /// I/O APIC ID `vcpu_count`, which matches the tables generated by
+    /// [`crate::loader::mptable::write_mptable`].
+    pub fn new(vcpu_count: usize) -> Self {
+        Self {
+            pic: Pic::new(),
+            io_apic: IoApic::new(IO_APIC_ADDRESS, vcpu_count as u8),
+            pending: vec![VecDeque::new(); vcpu_count],
+        }
+    }
+
+    /// Returns the PICs.
+    pub fn pic(&mut self) -> &mut Pic {
+        &mut self.pic
+    }
+
+    /// Returns the I/O APIC.
+    pub fn io_apic(&mut self) -> &mut IoApic {
+        &mut self.io_apic
+    }
+
+    /// Queues the given interrupts signalled by the I/O APIC for the virtual CPUs in their
+    /// destination.
+    fn route(&mut self, interrupts: Vec<IoApicInterrupt>) {
+        for interrupt in interrupts {
+            let targets: Vec<usize> = (0..self.pending.len())
+                .filter(|&id| match (interrupt.logical, interrupt.destination) {
+                    (false, BROADCAST) => true,
+                    (false, destination) => id == destination as usize,
+                    (true, destination) => id < 8 && destination & (1 << id) != 0,
+                })
+                .collect();
+
+            let targets = match interrupt.lowest_priority {
+                true => &targets[..targets.len().min(1)],
+                _ => &targets[..],
+            };
+
+            for &id in targets {
+                self.pending[id].push_back(interrupt.interrupt);
+            }
+        }
+    }
+
+    /// Raises or lowers the interrupt line with the given global system interrupt (GSI) number,
+    /// where the GSIs below 16 are routed to both the PICs and the I/O APIC, except for GSI 2,
+    /// which is only routed to the I/O APIC as the slave PIC is cascaded to IRQ 2. Returns
+    /// [`Error::InvalidArgument`] if the I/O APIC does not have a pin for the GSI.
+    pub fn set_irq_line(&mut self, gsi: u32, level: bool) -> Result<(), Error> {
+        if gsi < 16 && gsi != PIC_CASCADE_IRQ as u32 {
+            self.pic.set_irq(gsi as u8, level)?;
+        }
+
+        let interrupt = self.io_apic.set_irq(gsi as usize, level)?;
+
+        self.route(interrupt.into_iter().collect());
+
+        Ok(())
+    }
+
+    /// Ends the level-triggered interrupts of the I/O APIC with the given vector, e.g. when the
+    /// VMM observes a write to the EOI register of the local APIC.
+    pub fn end_of_interrupt(&mut self, vector: u8) {
+        let interrupts = self.io_apic.end_of_interrupt(vector);
+
+        self.route(interrupts);
+    }
+
+    /// Handles an MMIO read of the I/O APIC, see [`IoApic::mmio_read`].
+    pub fn mmio_read(&mut self, address: u64, data: &mut [u8]) {
+        self.io_apic.mmio_read(address, data);
+    }
+
+    /// Handles an MMIO write of the I/O APIC, see [`IoApic::mmio_write`].
+    pub fn mmio_write(&mut self, address: u64, data: &[u8]) {
+        let interrupts = self.io_apic.mmio_write(address, data);
+
+        self.route(interrupts);
+    }
+
+    /// Returns whether there are interrupts pending for the virtual CPU with the given local
+    /// APIC ID, i.e. whether [`InterruptController::deliver`] would inject any interrupt.
+    pub fn has_pending(&self, id: usize) -> bool {
+        let pending = self.pending.get(id).map(|pending| !pending.is_empty());
+
+        pending.unwrap_or(false) || (id == 0 && self.pic.has_interrupt())
+    }
+
+    /// Injects the interrupts pending for the virtual CPU with the given local APIC ID into the
+    /// given virtual CPU, where the virtual CPU with local APIC ID 0 also receives the highest
+    /// priority interrupt of the PICs. The ExtINT interrupts and the interrupts of the PICs are
+    /// acknowledged upon injection.
+    pub fn deliver(&mut self, id: usize, vcpu: &mut Vcpu) -> Result<(), Error> {
+        if let Some(pending) = self.pending.get_mut(id) {
+            while let Some(interrupt) = pending.pop_front() {
+                match interrupt {
+                    Interrupt::Fixed(vector) => vcpu.inject_interrupt(vector)?,
+                    Interrupt::Nmi => vcpu.inject_nmi()?,
+                    Interrupt::ExtInt => {
+                        if let Some(vector) = self.pic.acknowledge() {
+                            vcpu.inject_interrupt(vector)?;
+                        }
+                    }
+                }
+            }
+        }
+
+        if id != 0 {
+            return Ok(());
+        }
+
+        if let Some(vector) = self.pic.acknowledge() {
+            vcpu.inject_interrupt(vector)?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Helper function to initialize the PICs like Linux does, i.e. with the vectors starting at
+    /// 0x20 and 0x28, and with all IRQs unmasked.
+    fn init_pic() -> Pic {
+        let mut pic = Pic::new();
+
+        for &(port, value) in &[
+            (PIC_MASTER_COMMAND, 0x11),
+            (PIC_MASTER_DATA, 0x20),
+            (PIC_MASTER_DATA, 1 << PIC_CASCADE_IRQ),
+            (PIC_MASTER_DATA, 0x01),
+            (PIC_SLAVE_COMMAND, 0x11),
+            (PIC_SLAVE_DATA, 0x28),
+            (PIC_SLAVE_DATA, PIC_CASCADE_IRQ),
+            (PIC_SLAVE_DATA, 0x01),
+            (PIC_MASTER_DATA, 0x00),
+            (PIC_SLAVE_DATA, 0x00),
+        ] {
+            pic.io_out(port, &[value]);
+        }
+
+        pic
+    }
+
+    /// Helper function to write the given redirection entry of the I/O APIC.
+    fn write_redirection(io_apic: &mut IoApic, pin: usize, entry: u64) -> Vec<IoApicInterrupt> {
+        let register = IO_APIC_REG_REDIRECTION as u32 + pin as u32 * 2;
+        let mut write = |register: u32, value: u32| {
+            io_apic.mmio_write(IO_APIC_ADDRESS + IO_APIC_IOREGSEL, &register.to_le_bytes());
+            io_apic.mmio_write(IO_APIC_ADDRESS + IO_APIC_IOWIN, &value.to_le_bytes())
+        };
+
+        let mut interrupts = write(register + 1, (entry >> 32) as u32);
+        interrupts.extend(write(register, entry as u32));
+
+        interrupts
+    }
+
+    /// Helper function to read the given register of the I/O APIC.
+    fn read_register(io_apic: &mut IoApic, register: u8) -> u32 {
+        let mut data = [0u8; 4];
+
+        io_apic.mmio_write(IO_APIC_ADDRESS + IO_APIC_IOREGSEL, &[register]);
+        io_apic.mmio_read(IO_APIC_ADDRESS + IO_APIC_IOWIN, &mut data);
+
+        u32::from_le_bytes(data)
+    }
+
+    fn fixed(vector: u8, destination: u8) -> IoApicInterrupt {
+        IoApicInterrupt {
+            interrupt: Interrupt::Fixed(vector),
+            destination,
+            logical: false,
+            lowest_priority: false,
+        }
+    }
+
+    #[test]
+    fn pic_masked_until_initialized() {
+        let mut pic = Pic::new();
+        pic.set_irq(1, true).unwrap();
+
+        assert!(!pic.has_interrupt());
+        assert_eq!(pic.acknowledge(), None);
+    }
+
+    #[test]
+    fn pic_priorities() {
+        let mut pic = init_pic();
+        pic.set_irq(4, true).unwrap();
+        pic.set_irq(1, true).unwrap();
+
+        assert_eq!(pic.acknowledge(), Some(0x21));
+
+        // IRQ 4 has a lower priority than IRQ 1, which is still in service.
+        assert!(!pic.has_interrupt());
+
+        // A non-specific EOI ends IRQ 1.
+        pic.io_out(PIC_MASTER_COMMAND, &[0x20]);
+
+        assert_eq!(pic.acknowledge(), Some(0x24));
+        assert_eq!(pic.acknowledge(), None);
+    }
+
+    #[test]
+    fn pic_cascade() {
+        let mut pic = init_pic();
+        pic.set_irq(12, true).unwrap();
+
+        assert!(pic.has_interrupt());
+        assert_eq!(pic.acknowledge(), Some(0x2c));
+        assert!(!pic.has_interrupt());
+
+        // The slave PIC no longer signals an interrupt once the IRQ is masked, so the master PIC
+        // reports the spurious IRQ 15.
+        let mut pic = init_pic();
+        pic.set_irq(13, true).unwrap();
+        pic.io_out(PIC_SLAVE_DATA, &[0xff]);
+
+        assert_eq!(pic.acknowledge(), Some(0x2f));
+    }
+
+    #[test]
+    fn pic_edge_and_level() {
+        let mut pic = init_pic();
+
+        // An edge-triggered IRQ is requested once per rising edge.
+        pic.set_irq(3, true).unwrap();
+        pic.set_irq(3, true).unwrap();
+
+        assert_eq!(pic.acknowledge(), Some(0x23));
+        pic.io_out(PIC_MASTER_COMMAND, &[0x20]);
+        assert_eq!(pic.acknowledge(), None);
+
+        // A level-triggered IRQ is requested for as long as the line is raised.
+        pic.io_out(PIC_MASTER_ELCR, &[0xff]);
+        pic.set_irq(3, true).unwrap();
+
+        assert_eq!(pic.acknowledge(), Some(0x23));
+        pic.io_out(PIC_MASTER_COMMAND, &[0x20]);
+        assert_eq!(pic.acknowledge(), Some(0x23));
+        pic.io_out(PIC_MASTER_COMMAND, &[0x20]);
+        pic.set_irq(3, false).unwrap();
+        assert_eq!(pic.acknowledge(), None);
+    }
+
+    #[test]
+    fn pic_registers() {
+        let mut pic = init_pic();
+        let mut data = [0u8; 2];
+
+        // IRQs 0, 1 and 2 cannot be level-triggered.
+        pic.io_out(PIC_MASTER_ELCR, &[0xff]);
+        pic.io_in(PIC_MASTER_ELCR, &mut data);
+        assert_eq!(data, [PIC_MASTER_ELCR_MASK; 2]);
+
+        pic.io_out(PIC_MASTER_DATA, &[0x5a]);
+        pic.io_in(PIC_MASTER_DATA, &mut data[..1]);
+        assert_eq!(data[0], 0x5a);
+
+        // The poll command acknowledges the highest priority IRQ.
+        pic.io_out(PIC_MASTER_DATA, &[0x00]);
+        pic.set_irq(5, true).unwrap();
+        pic.io_out(PIC_MASTER_COMMAND, &[0x0c]);
+        pic.io_in(PIC_MASTER_COMMAND, &mut data[..1]);
+        assert_eq!(data[0], 0x85);
+
+        // Empty writes and unknown ports are ignored.
+        pic.io_out(PIC_MASTER_DATA, &[]);
+        pic.io_in(0x22, &mut data[..1]);
+        assert_eq!(data[0], 0xff);
+    }
+
+    #[test]
+    fn pic_invalid_irq() {
+        let mut pic = init_pic();
+
+        assert!(matches!(pic.set_irq(PIC_CASCADE_IRQ, true), Err(Error::InvalidArgument)));
+        assert!(matches!(pic.set_irq(16, true), Err(Error::InvalidArgument)));
+    }
+
+    #[test]
+    fn io_apic_edge() {
+        let mut io_apic = IoApic::new(IO_APIC_ADDRESS, 4);
+
+        // The pins are masked until the guest programs them, but the edge is remembered.
+        assert_eq!(io_apic.set_irq(1, true).unwrap(), None);
+        assert_eq!(write_redirection(&mut io_apic, 1, (2 << 56) | 0x31), [fixed(0x31, 2)]);
+
+        io_apic.set_irq(1, false).unwrap();
+
+        assert_eq!(io_apic.set_irq(1, true).unwrap(), Some(fixed(0x31, 2)));
+        assert_eq!(io_apic.set_irq(1, true).unwrap(), None);
+    }
+
+    #[test]
+    fn io_apic_level() {
+        let mut io_apic = IoApic::new(IO_APIC_ADDRESS, 4);
+        write_redirection(&mut io_apic, 9, REDIRECTION_LEVEL | 0x39);
+
+        assert_eq!(io_apic.set_irq(9, true).unwrap(), Some(fixed(0x39, 0)));
+
+        // The interrupt is only delivered again once the guest ends it.
+        assert_eq!(io_apic.set_irq(9, true).unwrap(), None);
+        assert_ne!(read_register(&mut io_apic, 0x10 + 18) & REDIRECTION_REMOTE_IRR as u32, 0);
+        assert_eq!(io_apic.mmio_write(IO_APIC_ADDRESS + IO_APIC_EOI, &[0x39]), [fixed(0x39, 0)]);
+
+        io_apic.set_irq(9, false).unwrap();
+
+        assert!(io_apic.end_of_interrupt(0x39).is_empty());
+    }
+
+    #[test]
+    fn io_apic_registers() {
+        let mut io_apic = IoApic::new(IO_APIC_ADDRESS, 4);
+
+        assert_eq!(read_register(&mut io_apic, IO_APIC_REG_ID), 4 << 24);
+        assert_eq!(read_register(&mut io_apic, IO_APIC_REG_VERSION), 0x17_0020);
+
+        // The delivery status and the remote IRR cannot be written.
+        write_redirection(&mut io_apic, 0, REDIRECTION_MASKED | REDIRECTION_READ_ONLY | 0x30);
+
+        assert_eq!(read_register(&mut io_apic, 0x10) as u64, REDIRECTION_MASKED | 0x30);
+
+        // The registers beyond the redirection table read as zero and ignore writes.
+        let last = IO_APIC_REG_REDIRECTION + IO_APIC_PINS as u8 * 2;
+
+        assert_eq!(read_register(&mut io_apic, last), 0);
+        assert!(write_redirection(&mut io_apic, IO_APIC_PINS, 0x30).is_empty());
+        assert!(!io_apic.contains_address(IO_APIC_ADDRESS + IO_APIC_SIZE));
+    }
+
+    #[test]
+    fn io_apic_invalid_pin() {
+        let mut io_apic = IoApic::new(IO_APIC_ADDRESS, 4);
+
+        assert!(matches!(io_apic.set_irq(IO_APIC_PINS, true), Err(Error::InvalidArgument)));
+    }
+
+    #[test]
+    fn routing() {
+        let mut controller = InterruptController::new(4);
+
+        // Route GSI 10 to the logical destination of virtual CPUs 1 and 3, and GSI 11 to the
+        // lowest priority virtual CPU of the same destination.
+        let destination = 0b1010 << 56;
+        let io_apic = controller.io_apic();
+        write_redirection(io_apic, 10, destination | REDIRECTION_LOGICAL | 0x3a);
+        write_redirection(io_apic, 11, destination | REDIRECTION_LOGICAL | (1 << 8) | 0x3b);
+        write_redirection(io_apic, 12, (0xff << 56) | (4 << 8));
+
+        controller.set_irq_line(10, true).unwrap();
+        controller.set_irq_line(11, true).unwrap();
+        controller.set_irq_line(12, true).unwrap();
+
+        assert_eq!(controller.pending[0], [Interrupt::Nmi]);
+        assert_eq!(controller.pending[1], [
+            Interrupt::Fixed(0x3a),
+            Interrupt::Fixed(0x3b),
+            Interrupt::Nmi,
+        ]);
+        assert_eq!(controller.pending[3], [Interrupt::Fixed(0x3a), Interrupt::Nmi]);
+        assert!(!controller.has_pending(4));
+        assert!(matches!(controller.set_irq_line(24, true), Err(Error::InvalidArgument)));
+    }
+}
@@ -0,0 +1,15 @@
+//! This module provides emulations of legacy devices in user space, for the platforms where the
+//! hypervisor does not emulate them in the kernel. The VMM drives the devices from the exits of
+//! its virtual CPUs, i.e. [`crate::ExitReason::IoIn`], [`crate::ExitReason::IoOut`],
+//! [`crate::ExitReason::MmioRead`] and [`crate::ExitReason::MmioWrite`].
+//!
+//! On x86, [`interrupts`] provides the 8259A PIC and the I/O APIC, which deliver the interrupts
+//! through [`crate::Vcpu::inject_interrupt`], and [`apic`] provides the local APIC, which is
+//! driven by [`crate::Vcpu::run`] itself. On all architectures, [`serial`] provides the 16550A
+//! UART for a serial console.
+
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+pub mod apic;
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+pub mod interrupts;
+pub mod serial;
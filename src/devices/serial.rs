@@ -0,0 +1,712 @@
+//! This module provides an emulation of the 16550A UART in user space, see [`Uart16550`], such that
+//! guests get a serial console without the VMM having to emulate one. The transmitted bytes are
+//! written to any [`Write`] endpoint of the host, e.g. [`std::io::stdout`] or a file, while the
+//! received bytes are fed from the host through [`Uart16550::receive`] or
+//! [`Uart16550::receive_from`] for any [`Read`] endpoint, e.g. from a thread reading the terminal.
+//!
+//! On x86, the VMM passes the [`crate::ExitReason::IoIn`] and [`crate::ExitReason::IoOut`] exits
+//! on the ports for which [`Uart16550::contains_port`] holds to [`Uart16550::io_in`] and
+//! [`Uart16550::io_out`]. On other architectures, the registers can be mapped as MMIO through
+//! [`Uart16550::read`] and [`Uart16550::write`] instead. Alternatively, the UART implements
+//! [`Device`], such that it can be registered on a bus, e.g. through
+//! [`crate::Vm::register_pio_device`] for [`UART_PORT_COUNT`] ports at [`COM1_PORT`].
+//!
+//! The UART raises and lowers its interrupt line through the callback given upon creation, e.g.
+//! by calling [`crate::Vm::set_irq_line`] or
+//! [`crate::devices::interrupts::InterruptController::set_irq_line`] with [`COM1_IRQ`].
+//!
+//! The UART transmits every byte immediately, such that the transmitter is always empty, and the
+//! baud rate and the line control settings are only reflected in the registers. The modem control
+//! lines are always asserted, except in the loopback mode, where the transmitted bytes are
+//! received by the UART itself. Like on the PC, the interrupt line is only raised while OUT2 is
+//! set in the modem control register.
+
+use crate::bus::Device;
+use crate::error::Error;
+use std::collections::VecDeque;
+use std::io::{Read, Write};
+
+/// The base I/O port of COM1.
+pub const COM1_PORT: u16 = 0x3f8;
+/// The ISA IRQ of COM1.
+pub const COM1_IRQ: u32 = 4;
+/// The base I/O port of COM2.
+pub const COM2_PORT: u16 = 0x2f8;
+/// The ISA IRQ of COM2.
+pub const COM2_IRQ: u32 = 3;
+/// The number of I/O ports of the UART.
+pub const UART_PORT_COUNT: u16 = 8;
+
+/// The size of the receive FIFO.
+const FIFO_SIZE: usize = 16;
+
+/// The receive buffer register (read) and the transmit holding register (write), or the low byte
+/// of the divisor latch if DLAB is set.
+const UART_DATA: u8 = 0;
+/// The interrupt enable register, or the high byte of the divisor latch if DLAB is set.
+const UART_IER: u8 = 1;
+/// The interrupt identification register (read) and the FIFO control register (write).
+const UART_IIR: u8 = 2;
+/// The line control register.
+const UART_LCR: u8 = 3;
+/// The modem control register.
+const UART_MCR: u8 = 4;
+/// The line status register.
+const UART_LSR: u8 = 5;
+/// The modem status register.
+const UART_MSR: u8 = 6;
+/// The scratch register.
+const UART_SCR: u8 = 7;
+
+/// The interrupt enable bit for received data.
+const IER_RX_DATA: u8 = 1 << 0;
+/// The interrupt enable bit for the empty transmit holding register.
+const IER_THR_EMPTY: u8 = 1 << 1;
+/// The interrupt enable bit for the line status.
+const IER_LINE_STATUS: u8 = 1 << 2;
+/// The interrupt enable bit for the modem status.
+const IER_MODEM_STATUS: u8 = 1 << 3;
+
+/// The interrupt identification when no interrupt is pending.
+const IIR_NONE: u8 = 0x01;
+/// The interrupt identification of the modem status interrupt.
+const IIR_MODEM_STATUS: u8 = 0x00;
+/// The interrupt identification of the empty transmit holding register.
+const IIR_THR_EMPTY: u8 = 0x02;
+/// The interrupt identification of the received data.
+const IIR_RX_DATA: u8 = 0x04;
+/// The interrupt identification of the line status interrupt.
+const IIR_LINE_STATUS: u8 = 0x06;
+/// The bits of the interrupt identification that report the FIFOs as enabled.
+const IIR_FIFO_ENABLED: u8 = 0xc0;
+
+/// The bit of the FIFO control register that enables the FIFOs.
+const FCR_ENABLE: u8 = 1 << 0;
+/// The bit of the FIFO control register that clears the receive FIFO.
+const FCR_CLEAR_RX: u8 = 1 << 1;
+
+/// The divisor latch access bit of the line control register.
+const LCR_DLAB: u8 = 1 << 7;
+
+/// The data terminal ready bit of the modem control register.
+const MCR_DTR: u8 = 1 << 0;
+/// The request to send bit of the modem control register.
+const MCR_RTS: u8 = 1 << 1;
+/// The OUT1 bit of the modem control register.
+const MCR_OUT1: u8 = 1 << 2;
+/// The OUT2 bit of the modem control register, which gates the interrupt line on the PC.
+const MCR_OUT2: u8 = 1 << 3;
+/// The loopback bit of the modem control register.
+const MCR_LOOPBACK: u8 = 1 << 4;
+
+/// The data ready bit of the line status register.
+const LSR_DATA_READY: u8 = 1 << 0;
+/// The overrun error bit of the line status register.
+const LSR_OVERRUN: u8 = 1 << 1;
+/// The empty transmit holding register bit of the line status register.
+const LSR_THR_EMPTY: u8 = 1 << 5;
+/// The empty transmitter bit of the line status register.
+const LSR_TX_EMPTY: u8 = 1 << 6;
+
+/// The trailing edge ring indicator bit of the modem status register.
+const MSR_TERI: u8 = 1 << 2;
+/// The clear to send bit of the modem status register.
+const MSR_CTS: u8 = 1 << 4;
+/// The data set ready bit of the modem status register.
+const MSR_DSR: u8 = 1 << 5;
+/// The ring indicator bit of the modem status register.
+const MSR_RI: u8 = 1 << 6;
+/// The data carrier detect bit of the modem status register.
+const MSR_DCD: u8 = 1 << 7;
+/// The delta bits of the modem status register.
+const MSR_DELTA: u8 = 0x0f;
+
+/// The callback that raises or lowers the interrupt line of the UART.
+type IrqCallback = Box<dyn FnMut(bool) -> Result<(), Error> + Send>;
+
+/// An emulation of the 16550A UART, see the [module-level documentation](self).
+pub struct Uart16550 {
+    /// The base I/O port.
+    base: u16,
+    /// The endpoint the transmitted bytes are written to.
+    output: Box<dyn Write + Send>,
+    /// The callback that drives the interrupt line.
+    irq: IrqCallback,
+    /// The current level of the interrupt line.
+    irq_level: bool,
+    /// The receive FIFO, which holds a single byte unless the FIFOs are enabled.
+    rx: VecDeque<u8>,
+    /// The divisor latch.
+    divisor: u16,
+    /// The interrupt enable register.
+    ier: u8,
+    /// The FIFO control register.
+    fcr: u8,
+    /// The line control register.
+    lcr: u8,
+    /// The modem control register.
+    mcr: u8,
+    /// The line status register, of which only the error bits are stored.
+    lsr: u8,
+    /// The modem status register, of which only the delta bits are stored.
+    msr: u8,
+    /// The scratch register.
+    scr: u8,
+    /// Whether the interrupt of the empty transmit holding register is pending.
+    thr_empty_pending: bool,
+}
+
+impl Uart16550 {
+    /// Creates the UART at the given base I/O port, which writes the transmitted bytes to the
+    /// given endpoint and calls the given callback to raise or lower its interrupt line.
+    pub fn new<W, F>(base: u16, output: W, irq: F) -> Self
+    where
+        W: Write + Send + 'static,
+        F: FnMut(bool) -> Result<(), Error> + Send + 'static,
+    {
+        Self {
+            base,
+            output: Box::new(output),
+            irq: Box::new(irq),
+            irq_level: false,
+            rx: VecDeque::with_capacity(FIFO_SIZE),
+            // 115200 baud.
+            divisor: 1,
+            ier: 0,
+            fcr: 0,
+            lcr: 0,
+            mcr: 0,
+            lsr: 0,
+            msr: 0,
+            scr: 0,
+            thr_empty_pending: false,
+        }
+    }
+
+    /// Creates the UART at the I/O ports of COM1, see [`Uart16550::new`].
+    pub fn com1<W, F>(output: W, irq: F) -> Self
+    where
+        W: Write + Send + 'static,
+        F: FnMut(bool) -> Result<(), Error> + Send + 'static,
+    {
+        Self::new(COM1_PORT, output, irq)
+    }
+
+    /// Returns whether the given I/O port belongs to the UART.
+    pub fn contains_port(&self, port: u16) -> bool {
+        (self.base..self.base + UART_PORT_COUNT).contains(&port)
+    }
+
+    /// Returns the number of bytes the receive FIFO can hold.
+    fn capacity(&self) -> usize {
+        match self.fcr & FCR_ENABLE {
+            0 => 1,
+            _ => FIFO_SIZE,
+        }
+    }
+
+    /// Returns the number of bytes that can be received before the receive FIFO is full.
+    pub fn receive_capacity(&self) -> usize {
+        self.capacity().saturating_sub(self.rx.len())
+    }
+
+    /// Returns the value of the modem status register.
+    fn modem_status(&self) -> u8 {
+        if self.mcr & MCR_LOOPBACK == 0 {
+            return MSR_DCD | MSR_DSR | MSR_CTS | self.msr;
+        }
+
+        let mut msr = self.msr;
+
+        if self.mcr & MCR_RTS != 0 {
+            msr |= MSR_CTS;
+        }
+
+        if self.mcr & MCR_DTR != 0 {
+            msr |= MSR_DSR;
+        }
+
+        if self.mcr & MCR_OUT1 != 0 {
+            msr |= MSR_RI;
+        }
+
+        if self.mcr & MCR_OUT2 != 0 {
+            msr |= MSR_DCD;
+        }
+
+        msr
+    }
+
+    /// Returns the identification of the highest priority pending interrupt.
+    fn interrupt_id(&self) -> u8 {
+        if self.ier & IER_LINE_STATUS != 0 && self.lsr & LSR_OVERRUN != 0 {
+            IIR_LINE_STATUS
+        } else if self.ier & IER_RX_DATA != 0 && !self.rx.is_empty() {
+            IIR_RX_DATA
+        } else if self.ier & IER_THR_EMPTY != 0 && self.thr_empty_pending {
+            IIR_THR_EMPTY
+        } else if self.ier & IER_MODEM_STATUS != 0 && self.msr & MSR_DELTA != 0 {
+            IIR_MODEM_STATUS
+        } else {
+            IIR_NONE
+        }
+    }
+
+    /// Drives the interrupt line if its level changed.
+    fn update_irq(&mut self) -> Result<(), Error> {
+        let level = self.mcr & MCR_OUT2 != 0 && self.interrupt_id() != IIR_NONE;
+
+        if level != self.irq_level {
+            self.irq_level = level;
+            (self.irq)(level)?;
+        }
+
+        Ok(())
+    }
+
+    /// Queues the given byte in the receive FIFO, or flags an overrun if the FIFO is full.
+    fn push_rx(&mut self, byte: u8) {
+        match self.rx.len() < self.capacity() {
+            true => self.rx.push_back(byte),
+            _ => self.lsr |= LSR_OVERRUN,
+        }
+    }
+
+    /// Passes the given bytes received from the host to the guest, and returns the number of
+    /// bytes that fit in the receive FIFO, such that the remainder can be passed once the guest
+    /// has read the FIFO. The bytes are discarded in the loopback mode, like on bare metal.
+    pub fn receive(&mut self, data: &[u8]) -> Result<usize, Error> {
+        if self.mcr & MCR_LOOPBACK != 0 {
+            return Ok(data.len());
+        }
+
+        let count = data.len().min(self.receive_capacity());
+
+        self.rx.extend(&data[..count]);
+        self.update_irq()?;
+
+        Ok(count)
+    }
+
+    /// Reads as many bytes from the given endpoint as fit in the receive FIFO and passes them to
+    /// the guest, see [`Uart16550::receive`]. Returns the number of bytes read, which is zero if
+    /// the receive FIFO is full or the endpoint reached its end. This blocks as long as reading
+    /// from the endpoint blocks.
+    pub fn receive_from<R: Read>(&mut self, reader: &mut R) -> Result<usize, Error> {
+        let mut buffer = [0u8; FIFO_SIZE];
+        let count = self.receive_capacity();
+
+        if count == 0 {
+            return Ok(0);
+        }
+
+        let count = reader.read(&mut buffer[..count])?;
+
+        self.receive(&buffer[..count])
+    }
+
+    /// Reads the register at the given offset, i.e. 0 to 7. Offsets that do not belong to the
+    /// UART read as all ones.
+    pub fn read(&mut self, offset: u8) -> Result<u8, Error> {
+        let dlab = self.lcr & LCR_DLAB != 0;
+
+        let value = match offset {
+            UART_DATA if dlab => self.divisor as u8,
+            UART_DATA => self.rx.pop_front().unwrap_or(0),
+            UART_IER if dlab => (self.divisor >> 8) as u8,
+            UART_IER => self.ier,
+            UART_IIR => {
+                let id = self.interrupt_id();
+
+                // Reading the identification acknowledges the empty transmit holding register.
+                if id == IIR_THR_EMPTY {
+                    self.thr_empty_pending = false;
+                }
+
+                match self.fcr & FCR_ENABLE {
+                    0 => id,
+                    _ => id | IIR_FIFO_ENABLED,
+                }
+            }
+            UART_LCR => self.lcr,
+            UART_MCR => self.mcr,
+            UART_LSR => {
+                let mut lsr = self.lsr | LSR_THR_EMPTY | LSR_TX_EMPTY;
+
+                if !self.rx.is_empty() {
+                    lsr |= LSR_DATA_READY;
+                }
+
+                // Reading the line status clears the error bits.
+                self.lsr = 0;
+
+                lsr
+            }
+            UART_MSR => {
+                let msr = self.modem_status();
+
+                // Reading the modem status clears the delta bits.
+                self.msr = 0;
+
+                msr
+            }
+            UART_SCR => self.scr,
+            _ => 0xff,
+        };
+
+        self.update_irq()?;
+
+        Ok(value)
+    }
+
+    /// Writes the given value to the register at the given offset, i.e. 0 to 7. Writes to offsets
+    /// that do not belong to the UART are ignored. Returns any error of writing to the endpoint.
+    pub fn write(&mut self, offset: u8, value: u8) -> Result<(), Error> {
+        let dlab = self.lcr & LCR_DLAB != 0;
+
+        match offset {
+            UART_DATA if dlab => self.divisor = (self.divisor & 0xff00) | value as u16,
+            UART_DATA => {
+                if self.mcr & MCR_LOOPBACK != 0 {
+                    self.push_rx(value);
+                } else {
+                    self.output.write_all(&[value])?;
+                    self.output.flush()?;
+                }
+
+                self.thr_empty_pending = true;
+            }
+            UART_IER if dlab => self.divisor = (self.divisor & 0x00ff) | ((value as u16) << 8),
+            UART_IER => {
+                // Enabling the interrupt raises it right away, as the register is always empty.
+                if value & IER_THR_EMPTY != 0 && self.ier & IER_THR_EMPTY == 0 {
+                    self.thr_empty_pending = true;
+                }
+
+                self.ier = value & 0x0f;
+            }
+            UART_IIR => {
+                // Changing the FIFO enable bit clears the FIFOs.
+                if (value ^ self.fcr) & FCR_ENABLE != 0 || value & FCR_CLEAR_RX != 0 {
+                    self.rx.clear();
+                }
+
+                self.fcr = value & !0x06;
+            }
+            UART_LCR => self.lcr = value,
+            UART_MCR => {
+                let old = self.modem_status();
+
+                self.mcr = value & 0x1f;
+
+                // In the loopback mode, the outputs are reflected in the modem status, which
+                // records the changes in the delta bits.
+                let new = self.modem_status();
+
+                self.msr |= ((old ^ new) & (MSR_CTS | MSR_DSR | MSR_DCD)) >> 4;
+
+                if old & MSR_RI != 0 && new & MSR_RI == 0 {
+                    self.msr |= MSR_TERI;
+                }
+            }
+            UART_SCR => self.scr = value,
+            _ => (),
+        }
+
+        self.update_irq()
+    }
+
+    /// Handles an `in` instruction on the given port by filling the given data, where accesses
+    /// wider than a byte read the same byte repeatedly.
+    pub fn io_in(&mut self, port: u16, data: &mut [u8]) -> Result<(), Error> {
+        let offset = port.wrapping_sub(self.base).min(UART_PORT_COUNT) as u8;
+        let value = self.read(offset)?;
+
+        for byte in data {
+            *byte = value;
+        }
+
+        Ok(())
+    }
+
+    /// Handles an `out` instruction on the given port with the given data, where only the first
+    /// byte is used.
+    pub fn io_out(&mut self, port: u16, data: &[u8]) -> Result<(), Error> {
+        let offset = port.wrapping_sub(self.base).min(UART_PORT_COUNT) as u8;
+
+        match data.first() {
+            Some(&value) => self.write(offset, value),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl Device for Uart16550 {
+    fn read(&mut self, offset: u64, data: &mut [u8]) -> Result<(), Error> {
+        let value = Uart16550::read(self, offset.min(UART_PORT_COUNT as u64) as u8)?;
+
+        for byte in data {
+            *byte = value;
+        }
+
+        Ok(())
+    }
+
+    fn write(&mut self, offset: u64, data: &[u8]) -> Result<(), Error> {
+        let offset = offset.min(UART_PORT_COUNT as u64) as u8;
+
+        match data.first() {
+            Some(&value) => Uart16550::write(self, offset, value),
+            _ => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+
+    /// The endpoint that records the transmitted bytes.
+    #[derive(Clone, Default)]
+    struct Output(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Output {
+        fn write(&mut self, bytes: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().write(bytes)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    /// Helper function to create COM1 along with the transmitted bytes and the level of its
+    /// interrupt line.
+    fn uart() -> (Uart16550, Output, Arc<Mutex<bool>>) {
+        let output = Output::default();
+        let level = Arc::new(Mutex::new(false));
+        let irq = level.clone();
+
+        let uart = Uart16550::com1(output.clone(), move |level| {
+            *irq.lock().unwrap() = level;
+            Ok(())
+        });
+
+        (uart, output, level)
+    }
+
+    #[test]
+    fn transmit() {
+        let (mut uart, output, _) = uart();
+
+        for &byte in b"hello" {
+            uart.io_out(COM1_PORT, &[byte]).unwrap();
+        }
+
+        assert_eq!(&*output.0.lock().unwrap(), b"hello");
+        assert_eq!(uart.read(UART_LSR).unwrap(), LSR_THR_EMPTY | LSR_TX_EMPTY);
+    }
+
+    #[test]
+    fn receive() {
+        let (mut uart, _, _) = uart();
+
+        // Without the FIFOs, only a single byte fits.
+        assert_eq!(uart.receive(b"ab").unwrap(), 1);
+        assert_eq!(uart.receive_capacity(), 0);
+        assert_ne!(uart.read(UART_LSR).unwrap() & LSR_DATA_READY, 0);
+        assert_eq!(uart.read(UART_DATA).unwrap(), b'a');
+        assert_eq!(uart.read(UART_LSR).unwrap() & LSR_DATA_READY, 0);
+
+        // Reading an empty receive buffer returns zero.
+        assert_eq!(uart.read(UART_DATA).unwrap(), 0);
+
+        // With the FIFOs, up to 16 bytes fit.
+        uart.write(UART_IIR, FCR_ENABLE).unwrap();
+        assert_eq!(uart.read(UART_IIR).unwrap(), IIR_NONE | IIR_FIFO_ENABLED);
+
+        let data: Vec<u8> = (0..32).collect();
+        let mut reader = &data[..];
+
+        assert_eq!(uart.receive_from(&mut reader).unwrap(), FIFO_SIZE);
+        assert_eq!(uart.receive_from(&mut reader).unwrap(), 0);
+        assert_eq!(reader.len(), 32 - FIFO_SIZE);
+
+        for byte in 0..FIFO_SIZE as u8 {
+            assert_eq!(uart.read(UART_DATA).unwrap(), byte);
+        }
+
+        // Clearing the receive FIFO discards the received bytes.
+        uart.receive(b"abc").unwrap();
+        uart.write(UART_IIR, FCR_ENABLE | FCR_CLEAR_RX).unwrap();
+        assert_eq!(uart.receive_capacity(), FIFO_SIZE);
+
+        // Disabling the FIFOs also clears them.
+        uart.receive(b"abc").unwrap();
+        uart.write(UART_IIR, 0).unwrap();
+        assert_eq!(uart.receive_capacity(), 1);
+    }
+
+    #[test]
+    fn divisor_latch() {
+        let (mut uart, output, _) = uart();
+
+        uart.write(UART_IER, IER_RX_DATA).unwrap();
+        uart.write(UART_LCR, LCR_DLAB | 0x03).unwrap();
+        uart.write(UART_DATA, 0x0c).unwrap();
+        uart.write(UART_IER, 0x01).unwrap();
+        assert_eq!(uart.read(UART_DATA).unwrap(), 0x0c);
+        assert_eq!(uart.read(UART_IER).unwrap(), 0x01);
+        assert_eq!(uart.divisor, 0x010c);
+
+        // The divisor latch shadows the data and the interrupt enable registers.
+        uart.write(UART_LCR, 0x03).unwrap();
+        assert_eq!(uart.read(UART_IER).unwrap(), IER_RX_DATA);
+        assert!(output.0.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn interrupts() {
+        let (mut uart, _, level) = uart();
+
+        // The interrupt line is only raised while OUT2 is set.
+        uart.write(UART_IER, IER_RX_DATA).unwrap();
+        uart.receive(b"a").unwrap();
+        assert_eq!(uart.read(UART_IIR).unwrap(), IIR_RX_DATA);
+        assert!(!*level.lock().unwrap());
+
+        uart.write(UART_MCR, MCR_OUT2).unwrap();
+        assert!(*level.lock().unwrap());
+
+        uart.read(UART_DATA).unwrap();
+        assert_eq!(uart.read(UART_IIR).unwrap(), IIR_NONE);
+        assert!(!*level.lock().unwrap());
+
+        // Enabling the interrupt of the empty transmit holding register raises it right away,
+        // and reading the identification acknowledges it.
+        uart.write(UART_IER, IER_RX_DATA | IER_THR_EMPTY).unwrap();
+        assert!(*level.lock().unwrap());
+        assert_eq!(uart.read(UART_IIR).unwrap(), IIR_THR_EMPTY);
+        assert!(!*level.lock().unwrap());
+        assert_eq!(uart.read(UART_IIR).unwrap(), IIR_NONE);
+
+        uart.write(UART_DATA, b'a').unwrap();
+        assert!(*level.lock().unwrap());
+
+        // Received data has a higher priority than the empty transmit holding register.
+        uart.receive(b"b").unwrap();
+        assert_eq!(uart.read(UART_IIR).unwrap(), IIR_RX_DATA);
+        uart.read(UART_DATA).unwrap();
+        assert_eq!(uart.read(UART_IIR).unwrap(), IIR_THR_EMPTY);
+
+        // Disabling the interrupts lowers the interrupt line.
+        uart.write(UART_DATA, b'a').unwrap();
+        uart.write(UART_IER, 0).unwrap();
+        assert!(!*level.lock().unwrap());
+    }
+
+    #[test]
+    fn overrun() {
+        let (mut uart, _, _) = uart();
+
+        uart.write(UART_IER, IER_LINE_STATUS | IER_RX_DATA).unwrap();
+        uart.write(UART_MCR, MCR_LOOPBACK).unwrap();
+        uart.write(UART_DATA, b'a').unwrap();
+        uart.write(UART_DATA, b'b').unwrap();
+
+        // The line status interrupt has the highest priority.
+        assert_eq!(uart.read(UART_IIR).unwrap(), IIR_LINE_STATUS);
+
+        // Reading the line status clears the error bits.
+        let lsr = uart.read(UART_LSR).unwrap();
+        assert_eq!(lsr & (LSR_OVERRUN | LSR_DATA_READY), LSR_OVERRUN | LSR_DATA_READY);
+        assert_eq!(uart.read(UART_LSR).unwrap() & LSR_OVERRUN, 0);
+        assert_eq!(uart.read(UART_IIR).unwrap(), IIR_RX_DATA);
+        assert_eq!(uart.read(UART_DATA).unwrap(), b'a');
+    }
+
+    #[test]
+    fn loopback() {
+        let (mut uart, output, _) = uart();
+
+        assert_eq!(uart.read(UART_MSR).unwrap(), MSR_DCD | MSR_DSR | MSR_CTS);
+
+        // The transmitted bytes are received by the UART itself, while the bytes from the host
+        // are discarded.
+        uart.write(UART_MCR, MCR_LOOPBACK).unwrap();
+        uart.write(UART_DATA, b'a').unwrap();
+        assert_eq!(uart.receive(b"bc").unwrap(), 2);
+        assert_eq!(uart.read(UART_DATA).unwrap(), b'a');
+        assert_eq!(uart.read(UART_DATA).unwrap(), 0);
+        assert!(output.0.lock().unwrap().is_empty());
+
+        // The outputs are reflected in the modem status, along with the delta bits.
+        uart.read(UART_MSR).unwrap();
+        uart.write(UART_MCR, MCR_LOOPBACK | MCR_DTR | MCR_RTS | MCR_OUT1 | MCR_OUT2).unwrap();
+        assert_eq!(uart.read(UART_MSR).unwrap(), 0xf0 | 0x0b);
+        assert_eq!(uart.read(UART_MSR).unwrap(), 0xf0);
+
+        // Dropping the ring indicator sets the trailing edge bit.
+        uart.write(UART_MCR, MCR_LOOPBACK | MCR_DTR | MCR_RTS | MCR_OUT2).unwrap();
+        assert_eq!(uart.read(UART_MSR).unwrap(), 0xb0 | MSR_TERI);
+
+        // The modem status interrupt has the lowest priority.
+        uart.write(UART_IER, IER_MODEM_STATUS).unwrap();
+        uart.write(UART_MCR, MCR_LOOPBACK | MCR_OUT2).unwrap();
+        assert_eq!(uart.read(UART_IIR).unwrap(), IIR_MODEM_STATUS);
+        uart.read(UART_MSR).unwrap();
+        assert_eq!(uart.read(UART_IIR).unwrap(), IIR_NONE);
+    }
+
+    #[test]
+    fn registers() {
+        let (mut uart, _, _) = uart();
+
+        uart.write(UART_SCR, 0x5a).unwrap();
+        uart.write(UART_LCR, 0x1b).unwrap();
+        uart.write(UART_IER, 0xff).unwrap();
+        uart.write(UART_MCR, 0xff).unwrap();
+        assert_eq!(uart.read(UART_SCR).unwrap(), 0x5a);
+        assert_eq!(uart.read(UART_LCR).unwrap(), 0x1b);
+        assert_eq!(uart.read(UART_IER).unwrap(), 0x0f);
+        assert_eq!(uart.read(UART_MCR).unwrap(), 0x1f);
+    }
+
+    #[test]
+    fn invalid_offsets() {
+        let (mut uart, output, _) = uart();
+        let mut data = [0u8; 2];
+
+        assert!(uart.contains_port(COM1_PORT + UART_PORT_COUNT - 1));
+        assert!(!uart.contains_port(COM1_PORT + UART_PORT_COUNT));
+        assert!(!uart.contains_port(COM1_PORT - 1));
+
+        // Offsets and ports that do not belong to the UART read as all ones and ignore writes.
+        for &offset in &[UART_PORT_COUNT as u8, 0xff] {
+            uart.write(offset, b'a').unwrap();
+            assert_eq!(uart.read(offset).unwrap(), 0xff);
+        }
+
+        for &port in &[COM1_PORT - 1, COM1_PORT + UART_PORT_COUNT, 0, u16::MAX] {
+            uart.io_out(port, b"a").unwrap();
+            uart.io_in(port, &mut data).unwrap();
+            assert_eq!(data, [0xff; 2]);
+        }
+
+        for &offset in &[UART_PORT_COUNT as u64, u64::MAX] {
+            Device::write(&mut uart, offset, b"a").unwrap();
+            Device::read(&mut uart, offset, &mut data).unwrap();
+            assert_eq!(data, [0xff; 2]);
+        }
+
+        // Empty writes are ignored, while wide reads repeat the byte.
+        uart.io_out(COM1_PORT, &[]).unwrap();
+        Device::write(&mut uart, 0, &[]).unwrap();
+        uart.write(UART_SCR, 0x5a).unwrap();
+        Device::read(&mut uart, UART_SCR as u64, &mut data).unwrap();
+        assert_eq!(data, [0x5a; 2]);
+
+        assert!(output.0.lock().unwrap().is_empty());
+    }
+}
@@ -0,0 +1,386 @@
+//! This module provides a minimal parser for little-endian ELF files, which is shared by the
+//! symbol maps and the guest loader, see [`crate::symbols`] and [`crate::loader`].
+
+use crate::error::Error;
+use std::convert::{TryFrom, TryInto};
+
+/// The ELF class of 32-bit files.
+const ELFCLASS32: u8 = 1;
+/// The ELF class of 64-bit files.
+const ELFCLASS64: u8 = 2;
+/// The ELF data encoding of little-endian files.
+const ELFDATA2LSB: u8 = 1;
+/// The ELF machine type of 32-bit x86.
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+const EM_386: u16 = 3;
+/// The ELF machine type of x86-64.
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+const EM_X86_64: u16 = 62;
+/// The ELF machine type of AArch64.
+#[cfg(target_arch = "aarch64")]
+const EM_AARCH64: u16 = 183;
+
+/// A section header of an ELF file.
+pub(crate) struct ElfSection {
+    pub(crate) kind: u32,
+    pub(crate) offset: u64,
+    pub(crate) size: u64,
+    pub(crate) link: u32,
+    pub(crate) entry_size: u64,
+}
+
+/// A symbol of an ELF file.
+pub(crate) struct ElfSymbol {
+    pub(crate) name: u32,
+    pub(crate) info: u8,
+    pub(crate) value: u64,
+    pub(crate) size: u64,
+}
+
+/// A program header of an ELF file.
+pub(crate) struct ElfSegment {
+    pub(crate) kind: u32,
+    pub(crate) flags: u32,
+    pub(crate) offset: u64,
+    pub(crate) virtual_address: u64,
+    pub(crate) physical_address: u64,
+    pub(crate) file_size: u64,
+    pub(crate) memory_size: u64,
+}
+
+/// A minimal parser for the program headers, section headers and symbol tables of
+/// little-endian ELF files.
+pub(crate) struct Elf<'a> {
+    bytes: &'a [u8],
+    pub(crate) is_64: bool,
+    pub(crate) entry: u64,
+    pub(crate) segment_offset: u64,
+    pub(crate) segment_entry_size: u64,
+    pub(crate) segment_count: usize,
+    pub(crate) section_offset: u64,
+    pub(crate) section_entry_size: u64,
+    pub(crate) section_count: usize,
+}
+
+impl<'a> Elf<'a> {
+    /// Parses the file header of the given ELF file. Returns [`Error::InvalidArgument`] if the
+    /// file is not a little-endian ELF file for the architecture of the host.
+    pub(crate) fn parse(bytes: &'a [u8]) -> Result<Self, Error> {
+        if bytes.len() < 0x34 || &bytes[0..4] != b"\x7fELF" || bytes[5] != ELFDATA2LSB {
+            return Err(Error::InvalidArgument);
+        }
+
+        let is_64 = match bytes[4] {
+            ELFCLASS32 => false,
+            ELFCLASS64 => true,
+            _ => return Err(Error::InvalidArgument),
+        };
+
+        let mut elf = Self {
+            bytes,
+            is_64,
+            entry: 0,
+            segment_offset: 0,
+            segment_entry_size: 0,
+            segment_count: 0,
+            section_offset: 0,
+            section_entry_size: 0,
+            section_count: 0,
+        };
+
+        if !is_supported_machine(is_64, elf.read_u16(0x12)?) {
+            return Err(Error::InvalidArgument);
+        }
+
+        if elf.is_64 {
+            elf.entry = elf.read_u64(0x18)?;
+            elf.segment_offset = elf.read_u64(0x20)?;
+            elf.segment_entry_size = elf.read_u16(0x36)? as u64;
+            elf.segment_count = elf.read_u16(0x38)? as usize;
+            elf.section_offset = elf.read_u64(0x28)?;
+            elf.section_entry_size = elf.read_u16(0x3a)? as u64;
+            elf.section_count = elf.read_u16(0x3c)? as usize;
+        } else {
+            elf.entry = elf.read_u32(0x18)? as u64;
+            elf.segment_offset = elf.read_u32(0x1c)? as u64;
+            elf.segment_entry_size = elf.read_u16(0x2a)? as u64;
+            elf.segment_count = elf.read_u16(0x2c)? as usize;
+            elf.section_offset = elf.read_u32(0x20)? as u64;
+            elf.section_entry_size = elf.read_u16(0x2e)? as u64;
+            elf.section_count = elf.read_u16(0x30)? as usize;
+        }
+
+        Ok(elf)
+    }
+
+    fn read<const N: usize>(&self, offset: u64) -> Result<[u8; N], Error> {
+        let offset = usize::try_from(offset).map_err(|_| Error::InvalidArgument)?;
+        let end = offset.checked_add(N).ok_or(Error::InvalidArgument)?;
+
+        self.bytes
+            .get(offset..end)
+            .and_then(|bytes| bytes.try_into().ok())
+            .ok_or(Error::InvalidArgument)
+    }
+
+    fn read_u16(&self, offset: u64) -> Result<u16, Error> {
+        Ok(u16::from_le_bytes(self.read(offset)?))
+    }
+
+    fn read_u32(&self, offset: u64) -> Result<u32, Error> {
+        Ok(u32::from_le_bytes(self.read(offset)?))
+    }
+
+    fn read_u64(&self, offset: u64) -> Result<u64, Error> {
+        Ok(u64::from_le_bytes(self.read(offset)?))
+    }
+
+    /// Helper function to compute the file offset of the entry with the given index in the table
+    /// at the given file offset with the given entry size, plus the given offset into the entry.
+    fn entry_offset(
+        table_offset: u64,
+        entry_size: u64,
+        index: usize,
+        offset: u64,
+    ) -> Result<u64, Error> {
+        (index as u64)
+            .checked_mul(entry_size)
+            .and_then(|entry| entry.checked_add(table_offset))
+            .and_then(|entry| entry.checked_add(offset))
+            .ok_or(Error::InvalidArgument)
+    }
+
+    pub(crate) fn segment(&self, index: usize) -> Result<ElfSegment, Error> {
+        let field = |offset| {
+            Self::entry_offset(self.segment_offset, self.segment_entry_size, index, offset)
+        };
+
+        if self.is_64 {
+            Ok(ElfSegment {
+                kind: self.read_u32(field(0x00)?)?,
+                flags: self.read_u32(field(0x04)?)?,
+                offset: self.read_u64(field(0x08)?)?,
+                virtual_address: self.read_u64(field(0x10)?)?,
+                physical_address: self.read_u64(field(0x18)?)?,
+                file_size: self.read_u64(field(0x20)?)?,
+                memory_size: self.read_u64(field(0x28)?)?,
+            })
+        } else {
+            Ok(ElfSegment {
+                kind: self.read_u32(field(0x00)?)?,
+                offset: self.read_u32(field(0x04)?)? as u64,
+                virtual_address: self.read_u32(field(0x08)?)? as u64,
+                physical_address: self.read_u32(field(0x0c)?)? as u64,
+                file_size: self.read_u32(field(0x10)?)? as u64,
+                memory_size: self.read_u32(field(0x14)?)? as u64,
+                flags: self.read_u32(field(0x18)?)?,
+            })
+        }
+    }
+
+    /// Returns the contents of the given segment in the file.
+    pub(crate) fn segment_bytes(&self, segment: &ElfSegment) -> Result<&'a [u8], Error> {
+        let start = usize::try_from(segment.offset).map_err(|_| Error::InvalidArgument)?;
+        let size = usize::try_from(segment.file_size).map_err(|_| Error::InvalidArgument)?;
+        let end = start.checked_add(size).ok_or(Error::InvalidArgument)?;
+
+        self.bytes.get(start..end).ok_or(Error::InvalidArgument)
+    }
+
+    pub(crate) fn section(&self, index: usize) -> Result<ElfSection, Error> {
+        let field = |offset| {
+            Self::entry_offset(self.section_offset, self.section_entry_size, index, offset)
+        };
+
+        if self.is_64 {
+            Ok(ElfSection {
+                kind: self.read_u32(field(0x04)?)?,
+                offset: self.read_u64(field(0x18)?)?,
+                size: self.read_u64(field(0x20)?)?,
+                link: self.read_u32(field(0x28)?)?,
+                entry_size: self.read_u64(field(0x38)?)?,
+            })
+        } else {
+            Ok(ElfSection {
+                kind: self.read_u32(field(0x04)?)?,
+                offset: self.read_u32(field(0x10)?)? as u64,
+                size: self.read_u32(field(0x14)?)? as u64,
+                link: self.read_u32(field(0x18)?)?,
+                entry_size: self.read_u32(field(0x24)?)? as u64,
+            })
+        }
+    }
+
+    pub(crate) fn symbol(&self, base: u64) -> Result<ElfSymbol, Error> {
+        let field = |offset| base.checked_add(offset).ok_or(Error::InvalidArgument);
+
+        if self.is_64 {
+            Ok(ElfSymbol {
+                name: self.read_u32(base)?,
+                info: self.read::<1>(field(0x04)?)?[0],
+                value: self.read_u64(field(0x08)?)?,
+                size: self.read_u64(field(0x10)?)?,
+            })
+        } else {
+            Ok(ElfSymbol {
+                name: self.read_u32(base)?,
+                value: self.read_u32(field(0x04)?)? as u64,
+                size: self.read_u32(field(0x08)?)? as u64,
+                info: self.read::<1>(field(0x0c)?)?[0],
+            })
+        }
+    }
+
+    pub(crate) fn string(&self, offset: u64) -> Result<&'a str, Error> {
+        let offset = usize::try_from(offset).map_err(|_| Error::InvalidArgument)?;
+        let bytes = self.bytes.get(offset..).ok_or(Error::InvalidArgument)?;
+        let end = bytes.iter().position(|&b| b == 0).ok_or(Error::InvalidArgument)?;
+
+        std::str::from_utf8(&bytes[..end]).map_err(|_| Error::InvalidArgument)
+    }
+}
+
+/// Helper function to check whether the given ELF class and machine type match the architecture
+/// of the host.
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+fn is_supported_machine(is_64: bool, machine: u16) -> bool {
+    matches!((is_64, machine), (false, EM_386) | (true, EM_X86_64))
+}
+
+/// Helper function to check whether the given ELF class and machine type match the architecture
+/// of the host.
+#[cfg(target_arch = "aarch64")]
+fn is_supported_machine(is_64: bool, machine: u16) -> bool {
+    is_64 && machine == EM_AARCH64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The ELF machine type of the host.
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    const MACHINE: u16 = EM_X86_64;
+    /// The ELF machine type of the host.
+    #[cfg(target_arch = "aarch64")]
+    const MACHINE: u16 = EM_AARCH64;
+
+    /// Helper function to build a 64-bit ELF file with a single `PT_LOAD` segment that holds the
+    /// given bytes.
+    fn build(data: &[u8]) -> Vec<u8> {
+        let mut bytes = vec![0u8; 0x40 + 0x38];
+
+        bytes[0..4].copy_from_slice(b"\x7fELF");
+        bytes[4] = ELFCLASS64;
+        bytes[5] = ELFDATA2LSB;
+        bytes[6] = 1;
+        bytes[0x12..0x14].copy_from_slice(&MACHINE.to_le_bytes());
+        bytes[0x18..0x20].copy_from_slice(&0x10_0000u64.to_le_bytes());
+        bytes[0x20..0x28].copy_from_slice(&0x40u64.to_le_bytes());
+        bytes[0x36..0x38].copy_from_slice(&0x38u16.to_le_bytes());
+        bytes[0x38..0x3a].copy_from_slice(&1u16.to_le_bytes());
+
+        let offset = bytes.len() as u64;
+        let segment = &mut bytes[0x40..];
+        segment[0x00..0x04].copy_from_slice(&1u32.to_le_bytes());
+        segment[0x08..0x10].copy_from_slice(&offset.to_le_bytes());
+        segment[0x18..0x20].copy_from_slice(&0x10_0000u64.to_le_bytes());
+        segment[0x20..0x28].copy_from_slice(&(data.len() as u64).to_le_bytes());
+        segment[0x28..0x30].copy_from_slice(&(data.len() as u64).to_le_bytes());
+
+        bytes.extend_from_slice(data);
+        bytes
+    }
+
+    #[test]
+    fn parse_segment() {
+        let bytes = build(b"payload");
+        let elf = Elf::parse(&bytes).unwrap();
+
+        assert!(elf.is_64);
+        assert_eq!(elf.entry, 0x10_0000);
+        assert_eq!(elf.segment_count, 1);
+
+        let segment = elf.segment(0).unwrap();
+
+        assert_eq!(segment.kind, 1);
+        assert_eq!(segment.physical_address, 0x10_0000);
+        assert_eq!(elf.segment_bytes(&segment).unwrap(), b"payload");
+        assert!(matches!(elf.segment(1), Err(Error::InvalidArgument)));
+    }
+
+    #[test]
+    fn invalid_header() {
+        let bytes = build(b"");
+
+        let mut truncated = bytes.clone();
+        truncated.truncate(0x33);
+
+        let mut magic = bytes.clone();
+        magic[0] = 0;
+
+        let mut class = bytes.clone();
+        class[4] = 3;
+
+        let mut big_endian = bytes.clone();
+        big_endian[5] = 2;
+
+        let mut machine = bytes.clone();
+        machine[0x12..0x14].copy_from_slice(&0xffffu16.to_le_bytes());
+
+        // A 32-bit file with the machine type of a 64-bit architecture.
+        let mut mismatch = bytes;
+        mismatch[4] = ELFCLASS32;
+
+        for bytes in [truncated, magic, class, big_endian, machine, mismatch] {
+            assert!(matches!(Elf::parse(&bytes), Err(Error::InvalidArgument)));
+        }
+    }
+
+    #[test]
+    fn overflowing_tables() {
+        let mut bytes = build(b"");
+
+        // The program header table starts right before the end of the address space.
+        bytes[0x20..0x28].copy_from_slice(&(u64::MAX - 1).to_le_bytes());
+        bytes[0x38..0x3a].copy_from_slice(&0xffffu16.to_le_bytes());
+
+        let elf = Elf::parse(&bytes).unwrap();
+
+        assert!(matches!(elf.segment(0), Err(Error::InvalidArgument)));
+        assert!(matches!(elf.segment(0xfffe), Err(Error::InvalidArgument)));
+
+        // The section header table has huge entries.
+        bytes[0x28..0x30].copy_from_slice(&0x40u64.to_le_bytes());
+        bytes[0x3a..0x3c].copy_from_slice(&0xffffu16.to_le_bytes());
+        bytes[0x3c..0x3e].copy_from_slice(&0xffffu16.to_le_bytes());
+
+        let elf = Elf::parse(&bytes).unwrap();
+
+        assert!(matches!(elf.section(0xfffe), Err(Error::InvalidArgument)));
+        assert!(matches!(elf.symbol(u64::MAX - 2), Err(Error::InvalidArgument)));
+        assert!(matches!(elf.string(u64::MAX), Err(Error::InvalidArgument)));
+    }
+
+    #[test]
+    fn overflowing_segment() {
+        let mut bytes = build(b"payload");
+
+        // The contents of the segment wrap around the end of the address space.
+        bytes[0x48..0x50].copy_from_slice(&u64::MAX.to_le_bytes());
+
+        let elf = Elf::parse(&bytes).unwrap();
+        let segment = elf.segment(0).unwrap();
+
+        assert!(matches!(elf.segment_bytes(&segment), Err(Error::InvalidArgument)));
+    }
+
+    #[test]
+    fn unterminated_string() {
+        let bytes = build(b"name");
+        let elf = Elf::parse(&bytes).unwrap();
+        let offset = (bytes.len() - 4) as u64;
+
+        assert!(matches!(elf.string(offset), Err(Error::InvalidArgument)));
+    }
+}
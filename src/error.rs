@@ -1,4 +1,5 @@
 //! This module provides an `Error` type for the crate using the [`thiserror`] crate.
+use std::ops::Range;
 use thiserror::Error;
 
 /// The `Error` type.
@@ -19,12 +20,54 @@ pub enum Error {
     /// The guest address is invalid.
     #[error("invalid guest address")]
     InvalidGuestAddress,
+    /// The guest physical memory at the given guest address is not mapped.
+    #[error("guest address {0:#x} is not mapped")]
+    UnmappedGuestAddress(u64),
+    /// The guest address is not aligned to the size of the access.
+    #[error("misaligned guest address")]
+    MisalignedGuestAddress,
+    /// The virtual CPU was used on a thread other than the one that created it, which is not
+    /// supported by the platform.
+    #[error("virtual CPU used on a thread other than the one that created it")]
+    WrongThread,
+    /// The guest memory of a protected guest is not accessible to the host.
+    #[error("guest memory is not accessible to the host")]
+    ProtectedGuestMemory,
+    /// The requested region of guest physical memory overlaps with an existing region.
+    #[error("region {requested:#x?} overlaps with existing region {existing:#x?}")]
+    OverlappingRegion {
+        /// The guest physical address range of the existing region.
+        existing: Range<u64>,
+        /// The guest physical address range of the requested region.
+        requested: Range<u64>,
+    },
+    /// An argument is invalid.
+    #[error("invalid argument")]
+    InvalidArgument,
+    /// The guest agent violated the protocol of the command channel.
+    #[error("guest agent protocol violation")]
+    AgentProtocol,
+    /// The thread running the virtual CPU terminated, e.g. because it panicked.
+    #[error("virtual CPU thread terminated")]
+    VcpuThreadTerminated,
+    /// The file is not a valid snapshot, or the snapshot was taken with another version of the
+    /// format or on another architecture.
+    #[error("invalid snapshot")]
+    InvalidSnapshot,
+    /// The migration stream is invalid, or it was sent with another version of the protocol or
+    /// from another architecture.
+    #[error("invalid migration stream")]
+    InvalidMigrationStream,
     /// Wraps ['std::io::Error'].
     #[error(transparent)]
     Io(#[from] std::io::Error),
     /// Wraps ['mmap_rs::Error'].
     #[error(transparent)]
     Mmap(#[from] mmap_rs::error::Error),
+    /// Wraps an error that originates from any calls to the [`vm_memory`] crate.
+    #[cfg(all(feature = "vm-memory", unix))]
+    #[error(transparent)]
+    VmMemory(#[from] vm_memory::mmap::Error),
     /// Wraps an error that originates from any calls to the ['sysctl'] crate.
     #[cfg(target_os = "freebsd")]
     #[error(transparent)]
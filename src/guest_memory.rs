@@ -0,0 +1,102 @@
+//! This module provides [`VmMemory`], which exposes the guest physical memory of a [`Vm`]
+//! through the [`vm_memory`] crate, such that the device crates of rust-vmm, e.g. `virtio-queue`
+//! and `linux-loader`, can be used with hy-rs VMs. This module is only available with the
+//! `vm-memory` feature.
+//!
+//! [`VmMemory`] implements [`GuestAddressSpace`] with a [`GuestMemoryMmap`] that refers to the
+//! host mappings of the regions of the VM. As the regions remain owned by the VM, the memory map
+//! of the VM must not change while the [`GuestMemoryMmap`] is in use. Call [`VmMemory::refresh`]
+//! after mapping new regions to make them visible.
+//!
+//! This is only supported on Linux and Mac OS X, as the other platforms do not expose the host
+//! mappings of the guest memory.
+
+use crate::error::Error;
+use crate::vm::{ProtectionFlags, Vm};
+use std::sync::Arc;
+use vm_memory::mmap::{Error as MmapError, GuestRegionMmap, MmapRegion};
+use vm_memory::{GuestAddress, GuestAddressSpace, GuestMemoryMmap};
+
+/// The guest physical memory of a [`Vm`] as a [`GuestAddressSpace`], see the
+/// [module-level documentation](self).
+#[derive(Clone)]
+pub struct VmMemory<'a> {
+    vm: Vm<'a>,
+    /// The snapshot of the memory map of the VM.
+    memory: Arc<GuestMemoryMmap>,
+}
+
+impl<'a> VmMemory<'a> {
+    /// Creates the guest address space of the given VM from its current regions.
+    ///
+    /// # Safety
+    ///
+    /// The regions must not be unmapped, resized or moved for as long as the returned
+    /// [`VmMemory`] or any [`GuestMemoryMmap`] obtained from it is alive, as these refer to the
+    /// host mappings of the regions.
+    pub unsafe fn new(vm: Vm<'a>) -> Result<Self, Error> {
+        let memory = Arc::new(snapshot(&vm)?);
+
+        Ok(Self {
+            vm,
+            memory,
+        })
+    }
+
+    /// Updates the guest address space to the current regions of the VM. The
+    /// [`GuestMemoryMmap`] obtained before keeps referring to the previous regions.
+    pub fn refresh(&mut self) -> Result<(), Error> {
+        self.memory = Arc::new(snapshot(&self.vm)?);
+
+        Ok(())
+    }
+
+    /// Returns the VM.
+    pub fn vm(&self) -> &Vm<'a> {
+        &self.vm
+    }
+}
+
+impl<'a> GuestAddressSpace for VmMemory<'a> {
+    type M = GuestMemoryMmap;
+    type T = Arc<GuestMemoryMmap>;
+
+    fn memory(&self) -> Self::T {
+        self.memory.clone()
+    }
+}
+
+/// Helper function to build a [`GuestMemoryMmap`] that refers to the host mappings of the
+/// regions of the given VM.
+fn snapshot(vm: &Vm) -> Result<GuestMemoryMmap, Error> {
+    let inner = vm.inner.read().unwrap();
+    let mut regions = vec![];
+
+    for (range, protection, _) in inner.regions()? {
+        let size = (range.end - range.start) as usize;
+        let host_address = inner.host_address(range.start, size)?;
+
+        let mut prot = 0;
+
+        if protection.contains(ProtectionFlags::READ) {
+            prot |= libc::PROT_READ;
+        }
+
+        if protection.contains(ProtectionFlags::WRITE) {
+            prot |= libc::PROT_WRITE;
+        }
+
+        let region = unsafe {
+            MmapRegion::build_raw(
+                host_address,
+                size,
+                prot,
+                libc::MAP_SHARED | libc::MAP_NORESERVE,
+            )
+        }.map_err(MmapError::MmapRegion)?;
+
+        regions.push(GuestRegionMmap::new(region, GuestAddress(range.start))?);
+    }
+
+    Ok(GuestMemoryMmap::from_regions(regions)?)
+}
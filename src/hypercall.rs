@@ -0,0 +1,207 @@
+//! This module provides the types to define paravirtual host services as hypercall handlers. A
+//! handler is registered for a hypercall number through [`crate::Vm::register_hypercall`], after
+//! which [`crate::Vcpu::run`] calls the handler whenever a virtual CPU of the VM makes a hypercall
+//! with that number, writes the value returned by the handler back to the result register and
+//! resumes the virtual CPU. Hypercalls without a registered handler exit with
+//! [`crate::ExitReason::Hypercall`] instead.
+//!
+//! The hypercalls follow the calling convention of KVM:
+//!  * On the x86 architecture, the guest executes `vmcall` or `vmmcall` with the hypercall number
+//!    in `rax` and up to four arguments in `rbx`, `rcx`, `rdx` and `rsi`. The result is returned
+//!    in `rax`.
+//!  * On the AArch64 architecture, the guest executes `hvc #0` with the hypercall number in `x0`
+//!    and up to four arguments in `x1` to `x4`. The result is returned in `x0`. The PSCI function
+//!    IDs are reserved and exit with `ExitReason::Psci` instead.
+//!
+//! Handlers receive a [`HypercallContext`] to access the registers of the virtual CPU and the
+//! guest memory, e.g. to read buffers that the guest passed by address.
+//!
+//! This is currently only supported on Mac OS X, and on Microsoft Windows on the x86-64
+//! architecture, see [`crate::Capability::Hypercalls`]. KVM handles the hypercalls of the guest
+//! itself, while FreeBSD does not report them.
+
+#[cfg(target_arch = "aarch64")]
+use crate::arch::aarch64::{CpuRegs, Register};
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+use crate::arch::x86_64::{CpuRegs, Register};
+use crate::error::Error;
+use crate::platform;
+use crate::vm::{read_across_regions, write_across_regions};
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+
+/// The registers holding the hypercall number and the arguments, where the first register also
+/// holds the result.
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+const HYPERCALL_REGISTERS: [Register; 5] = [
+    Register::Rax,
+    Register::Rbx,
+    Register::Rcx,
+    Register::Rdx,
+    Register::Rsi,
+];
+
+/// The registers holding the hypercall number and the arguments, where the first register also
+/// holds the result.
+#[cfg(target_arch = "aarch64")]
+const HYPERCALL_REGISTERS: [Register; 5] = [
+    Register::X0,
+    Register::X1,
+    Register::X2,
+    Register::X3,
+    Register::X4,
+];
+
+/// Describes a hypercall made by the guest.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Hypercall {
+    /// The hypercall number.
+    pub nr: u64,
+    /// The arguments to the hypercall.
+    pub args: [u64; 4],
+}
+
+/// A handler for a hypercall, which returns the value to write back to the result register.
+pub type HypercallHandler = dyn Fn(&mut HypercallContext) -> Result<u64, Error> + Send + Sync;
+
+/// The context passed to a [`HypercallHandler`], which provides access to the registers of the
+/// virtual CPU that made the hypercall and to the guest memory.
+pub struct HypercallContext<'a> {
+    /// The hypercall.
+    hypercall: Hypercall,
+    /// The virtual CPU that made the hypercall.
+    vcpu: &'a mut dyn CpuRegs,
+    /// The VM the virtual CPU belongs to.
+    vm: &'a RwLock<platform::Vm>,
+}
+
+impl<'a> HypercallContext<'a> {
+    /// Returns the hypercall.
+    pub fn hypercall(&self) -> &Hypercall {
+        &self.hypercall
+    }
+
+    /// Returns the hypercall number.
+    pub fn nr(&self) -> u64 {
+        self.hypercall.nr
+    }
+
+    /// Returns the arguments to the hypercall.
+    pub fn args(&self) -> [u64; 4] {
+        self.hypercall.args
+    }
+
+    /// Returns the registers of the virtual CPU that made the hypercall. Any changes to the
+    /// registers other than the result register are visible to the guest upon resuming.
+    pub fn registers(&mut self) -> &mut dyn CpuRegs {
+        self.vcpu
+    }
+
+    /// Reads the bytes at the given guest physical address. Unlike
+    /// [`crate::Vm::read_physical_memory`], this fails with [`Error::UnmappedGuestAddress`] if
+    /// not all the bytes could be read.
+    pub fn read_physical_memory(&self, bytes: &mut [u8], guest_address: u64) -> Result<(), Error> {
+        let size = read_across_regions(&self.vm.read().unwrap(), bytes, guest_address)?;
+
+        if size != bytes.len() {
+            return Err(Error::UnmappedGuestAddress(guest_address + size as u64));
+        }
+
+        Ok(())
+    }
+
+    /// Writes the bytes to the given guest physical address. Unlike
+    /// [`crate::Vm::write_physical_memory`], this fails with [`Error::UnmappedGuestAddress`] if
+    /// not all the bytes could be written.
+    pub fn write_physical_memory(&self, guest_address: u64, bytes: &[u8]) -> Result<(), Error> {
+        let size = write_across_regions(&mut self.vm.write().unwrap(), guest_address, bytes)?;
+
+        if size != bytes.len() {
+            return Err(Error::UnmappedGuestAddress(guest_address + size as u64));
+        }
+
+        Ok(())
+    }
+
+    /// Reads the buffer of the given size at the given guest physical address, e.g. a buffer
+    /// passed by the guest as one of the arguments.
+    pub fn read_buffer(&self, guest_address: u64, size: usize) -> Result<Vec<u8>, Error> {
+        let mut bytes = vec![0u8; size];
+
+        self.read_physical_memory(&mut bytes, guest_address)?;
+
+        Ok(bytes)
+    }
+
+    /// Reads the bytes at the given guest virtual address, which is translated using the page
+    /// tables of the guest as configured in the virtual CPU.
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    pub fn read_virtual_memory(&self, bytes: &mut [u8], address: u64) -> Result<(), Error> {
+        let paging = crate::unwind::PagingState::new(&*self.vcpu)?;
+
+        paging.read_virtual_memory(&self.vm.read().unwrap(), bytes, address)
+    }
+}
+
+/// The hypercall handlers registered in a VM.
+#[derive(Default)]
+pub(crate) struct HypercallTable {
+    /// A mapping of the hypercall numbers to the handlers.
+    handlers: HashMap<u64, Arc<HypercallHandler>>,
+}
+
+impl HypercallTable {
+    /// Registers the handler for the given hypercall number, replacing any previous handler.
+    pub fn insert(&mut self, nr: u64, handler: Arc<HypercallHandler>) {
+        self.handlers.insert(nr, handler);
+    }
+
+    /// Removes the handler for the given hypercall number.
+    pub fn remove(&mut self, nr: u64) {
+        self.handlers.remove(&nr);
+    }
+}
+
+/// Dispatches the hypercalls made by a virtual CPU to the handlers registered in the VM. This is
+/// passed to the platform-specific implementation of [`crate::Vcpu::run`].
+pub(crate) struct Hypercalls<'a> {
+    /// The hypercall handlers registered in the VM.
+    pub table: &'a RwLock<HypercallTable>,
+    /// The VM the virtual CPU belongs to, which the platform-specific implementations may also use
+    /// to access the guest memory, e.g. to fetch instructions.
+    pub vm: &'a RwLock<platform::Vm>,
+}
+
+impl<'a> Hypercalls<'a> {
+    /// Reads the hypercall from the registers of the given virtual CPU and calls the registered
+    /// handler, if any. The caller must have moved the instruction pointer past the hypercall
+    /// instruction. Returns `None` if the hypercall has been handled, or the hypercall if there is
+    /// no handler for it.
+    pub fn dispatch(&self, vcpu: &mut dyn CpuRegs) -> Result<Option<Hypercall>, Error> {
+        let values = vcpu.get_registers(&HYPERCALL_REGISTERS)?;
+
+        let hypercall = Hypercall {
+            nr: values[0],
+            args: [values[1], values[2], values[3], values[4]],
+        };
+
+        // Release the lock before calling the handler, such that the handler can register
+        // handlers itself.
+        let handler = match self.table.read().unwrap().handlers.get(&hypercall.nr) {
+            Some(handler) => handler.clone(),
+            _ => return Ok(Some(hypercall)),
+        };
+
+        let mut context = HypercallContext {
+            hypercall,
+            vcpu,
+            vm: self.vm,
+        };
+
+        let result = handler(&mut context)?;
+
+        context.vcpu.set_registers(&HYPERCALL_REGISTERS[..1], &[result])?;
+
+        Ok(None)
+    }
+}
@@ -2,9 +2,27 @@
 //! API, as some platforms require some state to use the underlying API. For instance, KVM requires
 //! an open file descriptor to `/dev/kvm`.
 
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+use crate::arch::x86_64::CpuidEntry;
+use crate::config::VmConfig;
 use crate::error::Error;
 use crate::platform;
-use crate::vm::VmBuilder;
+use crate::snapshot::{self, RestoredVm};
+use crate::thread::ThreadPriority;
+use crate::vm::{Vm, VmBuilder};
+use std::fs::File;
+use std::io::BufReader;
+use std::path::Path;
+
+/// The optional capabilities of the underlying hypervisor API.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Capability {
+    /// Protected guests, i.e. guests whose memory is not accessible to the host, see
+    /// [`VmBuilder::with_protected_guest`].
+    ProtectedGuest,
+    /// Hypercalls of the guest, see [`crate::Vm::register_hypercall`].
+    Hypercalls,
+}
 
 /// The `Hypervisor` struct serving as an entry point to the API.
 pub struct Hypervisor {
@@ -21,12 +39,79 @@ impl Hypervisor {
         })
     }
 
+    /// Returns `true` if the underlying hypervisor API supports the given capability.
+    pub fn has_capability(&self, capability: Capability) -> bool {
+        self.inner.has_capability(capability)
+    }
+
     /// Returns a [`VmBuilder`] that uses the builder pattern to create a new VM. This allows the
     /// configuration of certain properties for the VM on platforms where these become immutable
     /// the moment you build the VM.
     pub fn build_vm(&self) -> Result<VmBuilder, Error> {
         Ok(VmBuilder {
             inner: self.inner.build_vm()?,
+            vcpu_thread_priority: ThreadPriority::Normal,
+            vcpu_resource_group: None,
+            #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+            software_apic: false,
         })
     }
+
+    /// Builds the VM described by the given [`VmConfig`], allocates its memory regions and
+    /// attaches its devices. This is equivalent to applying the configuration to
+    /// [`Hypervisor::build_vm`] by hand. The virtual CPUs still have to be configured through
+    /// [`VmConfig::configure_vcpu`] once they have been created.
+    pub fn create_vm<'a>(&self, config: &'a VmConfig) -> Result<Vm<'a>, Error> {
+        let mut vm = config
+            .apply(self.build_vm()?)?
+            .build(&config.name)?;
+
+        config.allocate_memory(&mut vm)?;
+        config.attach_devices(&vm)?;
+
+        Ok(vm)
+    }
+
+    /// Restores the snapshot at the given path, which was saved through [`Vm::save`], into a new
+    /// VM with the given name. The VM is built with the default configuration apart from the
+    /// number of virtual CPUs. Use [`crate::snapshot::restore`] to build the VM from a configured
+    /// [`VmBuilder`] or to read the snapshot from another transport instead. The virtual CPUs are
+    /// not created, as some platforms require them to be created on the thread that runs them, so
+    /// their states have to be restored through [`crate::Vcpu::set_state`] once they have been
+    /// created.
+    pub fn restore<'a, P: AsRef<Path>>(
+        &self,
+        path: P,
+        name: &'a str,
+    ) -> Result<RestoredVm<'a>, Error> {
+        let mut file = BufReader::new(File::open(path)?);
+
+        snapshot::restore(self.build_vm()?, &mut file, name)
+    }
+
+    /// Restores the encrypted snapshot at the given path, which was saved through
+    /// [`Vm::save_encrypted`], like [`Hypervisor::restore`], but decrypts the snapshot with the
+    /// given key and verifies its integrity. Returns [`Error::InvalidSnapshot`] if the key is
+    /// wrong or if the snapshot has been tampered with.
+    #[cfg(feature = "encryption")]
+    pub fn restore_encrypted<'a, P: AsRef<Path>>(
+        &self,
+        path: P,
+        key: &[u8; 32],
+        name: &'a str,
+    ) -> Result<RestoredVm<'a>, Error> {
+        let mut file = BufReader::new(File::open(path)?);
+
+        snapshot::restore_encrypted(self.build_vm()?, &mut file, key, name)
+    }
+
+    /// Returns the CPUID that the hypervisor supports for the guest, which serves as a starting
+    /// point for the table passed to [`crate::Vcpu::set_cpuid`]. On Linux, this is the CPUID
+    /// supported by KVM, while on Mac OS X and Microsoft Windows this is the CPUID of the host.
+    ///
+    /// This is not supported on FreeBSD, as bhyve serves the CPUID of the guest in the kernel.
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    pub fn get_supported_cpuid(&self) -> Result<Vec<CpuidEntry>, Error> {
+        self.inner.get_supported_cpuid()
+    }
 }
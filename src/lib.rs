@@ -5,15 +5,66 @@
 //! This crate supports the following platforms:
 //!  * Microsoft Windows through the [WinHV
 //!  API](https://docs.microsoft.com/en-us/virtualization/api/hypervisor-platform/hypervisor-platform) or Hyper-V.
-//!  * Linux through the [KVM API](https://github.com/rust-vmm/kvm-ioctls).
+//!  Both x86-64 and AArch64 hosts are supported.
+//!  * Linux through the [KVM API](https://github.com/rust-vmm/kvm-ioctls). Both 64-bit and 32-bit
+//!  x86 hosts are supported.
 //!  * Mac OS X through [Apple's Hypervisor
 //!  Framework](https://developer.apple.com/documentation/hypervisor/).
+//!
+//! The following optional features are available:
+//!  * `async`: a runtime-agnostic async API that runs the virtual CPUs on dedicated threads, see
+//!  [`async_vcpu`].
+//!  * `encryption`: authenticated encryption of snapshots, see [`snapshot`] and [`sealed`].
+//!  * `serde`: serialization of the configuration types, see [`config`].
+//!  * `vm-memory`: interoperability with the device crates of rust-vmm, see [`guest_memory`].
+//!  * `xen`: support for Xen HVM guests on Linux, see [`xen`].
 
+pub mod agent;
 pub mod arch;
+pub mod balloon;
+pub mod bus;
+pub mod bytes;
+#[cfg(feature = "async")]
+pub mod async_vcpu;
+pub mod config;
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+pub mod confidential;
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+pub mod crash;
+pub mod debug;
+pub mod devices;
 pub mod error;
+#[cfg(all(feature = "vm-memory", unix))]
+pub mod guest_memory;
+pub mod hypercall;
 pub mod hypervisor;
+pub mod loader;
+pub mod migration;
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+pub mod pit;
+pub mod runner;
+pub mod scan;
+#[cfg(feature = "encryption")]
+pub mod sealed;
+pub mod snapshot;
+pub mod state;
+pub mod symbols;
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+pub mod synic;
+pub mod thread;
+pub mod tsc;
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+pub mod unwind;
 pub mod vm;
 pub mod vcpu;
+pub mod virtio;
+pub mod volatile;
+pub mod vsock;
+#[cfg(feature = "xen")]
+pub mod xen;
+mod elf;
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+mod mmio;
 mod os_impl;
 
 #[cfg(target_os = "freebsd")]
@@ -26,7 +77,44 @@ pub(crate) use os_impl::macos as platform;
 pub(crate) use os_impl::windows as platform;
 
 pub use page_walker::address_space::PageTableMapper;
+pub use agent::{AgentChannel, AgentRequest, AgentResponse, Doorbell};
+pub use balloon::Balloon;
+pub use bus::{Device, MmioBus, PioBus, SharedDevice};
+pub use bytes::{AsBytes, Atomic, Be, FromBytes, Le};
+#[cfg(feature = "async")]
+pub use async_vcpu::{AsyncVcpu, VcpuFuture};
+pub use config::VmConfig;
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+pub use confidential::{LaunchMeasurement, SevPolicy};
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+pub use crash::CrashReport;
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+pub use debug::BreakpointManager;
+pub use debug::{GuestDebug, HwBreakpoint};
 pub use error::Error;
-pub use hypervisor::Hypervisor;
-pub use vm::{ProtectionFlags, Vm, VmBuilder};
-pub use vcpu::{ExitReason, Vcpu};
+#[cfg(all(feature = "vm-memory", unix))]
+pub use guest_memory::VmMemory;
+pub use hypercall::{Hypercall, HypercallContext};
+pub use hypervisor::{Capability, Hypervisor};
+pub use loader::{load_elf, load_flat, load_pe, EntryInfo, LoadedSegment};
+pub use migration::{MigrationOptions, MigrationState};
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+pub use pit::{Pit, PitReinjection};
+pub use runner::{ExitAction, ExitHandler, VcpuRunner};
+pub use scan::Pattern;
+pub use snapshot::RestoredVm;
+pub use state::VcpuState;
+pub use symbols::SymbolMap;
+pub use thread::{ResourceGroup, ResourceLimits, ThreadPriority, ThreadPriorityReport};
+pub use tsc::TscMode;
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+pub use unwind::UnwindHint;
+pub use vm::{
+    AllocationOptions, DirtyBitmap, FileSharing, HugePageSize, MemoryBacking, MemoryBackingKind,
+    ProtectionFlags, SharedRegion, Vm, VmBuilder,
+};
+pub use vcpu::{ExitPolicy, ExitReason, Vcpu, VcpuFactory, VcpuHandle, VcpuSpec};
+pub use volatile::VolatileSlice;
+pub use vsock::{Vsock, VsockListener, VsockStream};
+#[cfg(feature = "xen")]
+pub use xen::{XenConfig, XenHypercall};
@@ -0,0 +1,585 @@
+//! This module provides a builder for the flattened device tree (FDT) of AArch64 guests, see
+//! [`FdtBuilder`]. Unlike x86 guests, AArch64 guests cannot discover the hardware of the VM by
+//! themselves, so Linux refuses to boot without a device tree describing the memory, the virtual
+//! CPUs, the GICv3 interrupt controller and the architected timer.
+//!
+//! The device tree is loaded at the start of the last 2 MiB of the highest memory region of the
+//! VM, which satisfies the alignment and placement requirements of the arm64 boot protocol
+//! described in `Documentation/arch/arm64/booting.rst` of the Linux kernel. Its guest physical
+//! address has to be passed to the kernel in register `x0`.
+
+use crate::arch::aarch64::{GicConfig, GIC_DISTRIBUTOR_SIZE, GIC_REDISTRIBUTOR_SIZE, GIC_SPI_BASE};
+use crate::error::Error;
+use crate::vm::{ProtectionFlags, Vm};
+use std::collections::HashMap;
+use std::ffi::CString;
+use std::ops::Range;
+
+/// The magic number of the device tree header.
+const FDT_MAGIC: u32 = 0xd00d_feed;
+/// The version of the device tree format.
+const FDT_VERSION: u32 = 17;
+/// The oldest version of the device tree format that this version is compatible with.
+const FDT_LAST_COMPATIBLE_VERSION: u32 = 16;
+/// The size of the device tree header.
+const FDT_HEADER_SIZE: usize = 40;
+/// The maximum size of the device tree, which also determines its alignment.
+const FDT_MAX_SIZE: u64 = 2 << 20;
+
+/// The token that starts a node in the structure block.
+const FDT_BEGIN_NODE: u32 = 1;
+/// The token that ends a node in the structure block.
+const FDT_END_NODE: u32 = 2;
+/// The token that starts a property in the structure block.
+const FDT_PROP: u32 = 3;
+/// The token that ends the structure block.
+const FDT_END: u32 = 9;
+
+/// The phandle of the interrupt controller.
+const GIC_PHANDLE: u32 = 1;
+/// The interrupt type of a shared peripheral interrupt in the `interrupts` property.
+const GIC_SPI: u32 = 0;
+/// The interrupt type of a private peripheral interrupt in the `interrupts` property.
+const GIC_PPI: u32 = 1;
+/// The flag of a level-triggered, active-high interrupt in the `interrupts` property.
+const IRQ_TYPE_LEVEL_HIGH: u32 = 4;
+/// The PPI of the GIC maintenance interrupt.
+const GIC_MAINTENANCE_PPI: u32 = 9;
+/// The PPIs of the secure physical, non-secure physical, virtual and hypervisor timers.
+const TIMER_PPIS: [u32; 4] = [13, 14, 11, 10];
+/// The number of virtual CPUs per cluster, i.e. the number of values of affinity level 0 of the
+/// MPIDR assigned by KVM.
+const CPUS_PER_CLUSTER: usize = 16;
+/// The size of the MMIO region of the UART.
+const UART_SIZE: u64 = 0x1000;
+/// The frequency of the clock of the UART.
+const UART_CLOCK_FREQUENCY: u32 = 1_843_200;
+
+/// A builder for the flattened device tree of an AArch64 guest, see the [module-level
+/// documentation](self).
+#[derive(Clone, Debug)]
+pub struct FdtBuilder {
+    vcpu_count: usize,
+    gic: GicConfig,
+    memory: Vec<Range<u64>>,
+    uart: Option<(u64, u32)>,
+    cmdline: Option<String>,
+    initrd: Option<Range<u64>>,
+}
+
+impl FdtBuilder {
+    /// Creates a builder for a device tree describing the given number of virtual CPUs and the
+    /// given in-kernel GIC, see [`crate::VmBuilder::with_gic`]. The virtual CPUs are described
+    /// with the MPIDR assigned by KVM and are brought up through PSCI using the `hvc` conduit.
+    pub fn new(vcpu_count: usize, gic: GicConfig) -> Self {
+        Self {
+            vcpu_count,
+            gic,
+            memory: vec![],
+            uart: None,
+            cmdline: None,
+            initrd: None,
+        }
+    }
+
+    /// Adds the given guest physical address range as a memory node. If no memory is added,
+    /// the device tree describes the writable regions of the VM, see [`crate::Vm::regions`].
+    pub fn with_memory(mut self, range: Range<u64>) -> Self {
+        self.memory.push(range);
+        self
+    }
+
+    /// Adds an NS16550A-compatible UART with its registers at the given guest physical address
+    /// and the given interrupt ID, which must be an SPI. The UART is used as the console.
+    pub fn with_uart(mut self, address: u64, intid: u32) -> Self {
+        self.uart = Some((address, intid));
+        self
+    }
+
+    /// Sets the kernel command line.
+    pub fn with_cmdline(mut self, cmdline: &str) -> Self {
+        self.cmdline = Some(cmdline.to_string());
+        self
+    }
+
+    /// Sets the guest physical address range of the initial RAM disk.
+    pub fn with_initrd(mut self, range: Range<u64>) -> Self {
+        self.initrd = Some(range);
+        self
+    }
+
+    /// Builds the device tree and writes it into the guest physical memory of the given VM.
+    /// Returns the guest physical address of the device tree.
+    ///
+    /// Returns [`Error::InvalidArgument`] if there are no virtual CPUs, if the UART interrupt is
+    /// not an SPI, if the command line contains a null byte, or if there is no memory region
+    /// that can hold the device tree.
+    pub fn load(&self, vm: &mut Vm) -> Result<u64, Error> {
+        let memory: Vec<Range<u64>> = if self.memory.is_empty() {
+            vm.regions()?
+                .filter(|(_, protection, _)| protection.contains(ProtectionFlags::WRITE))
+                .map(|(range, _, _)| range)
+                .collect()
+        } else {
+            self.memory.clone()
+        };
+
+        let bytes = self.build(&memory)?;
+
+        // Place the device tree at the start of the last 2 MiB of the highest memory region.
+        let address = memory
+            .iter()
+            .filter_map(|range| {
+                let start = range.end.checked_sub(FDT_MAX_SIZE)? & !(FDT_MAX_SIZE - 1);
+
+                if start >= range.start {
+                    Some(start)
+                } else {
+                    None
+                }
+            })
+            .max()
+            .ok_or(Error::InvalidArgument)?;
+
+        vm.write_all_physical_memory(address, &bytes)?;
+
+        Ok(address)
+    }
+
+    /// Helper function to serialize the device tree describing the given memory.
+    fn build(&self, memory: &[Range<u64>]) -> Result<Vec<u8>, Error> {
+        if self.vcpu_count == 0 {
+            return Err(Error::InvalidArgument);
+        }
+
+        let mut fdt = FdtWriter::default();
+
+        fdt.begin_node("");
+        fdt.property_string("compatible", "linux,dummy-virt")?;
+        fdt.property_u32("#address-cells", 2);
+        fdt.property_u32("#size-cells", 2);
+        fdt.property_u32("interrupt-parent", GIC_PHANDLE);
+
+        // Describe the virtual CPUs.
+        fdt.begin_node("cpus");
+        fdt.property_u32("#address-cells", 1);
+        fdt.property_u32("#size-cells", 0);
+
+        for index in 0..self.vcpu_count {
+            let mpidr = ((index / CPUS_PER_CLUSTER) << 8) | (index % CPUS_PER_CLUSTER);
+
+            fdt.begin_node(&format!("cpu@{:x}", mpidr));
+            fdt.property_string("device_type", "cpu")?;
+            fdt.property_string("compatible", "arm,arm-v8")?;
+            fdt.property_string("enable-method", "psci")?;
+            fdt.property_u32("reg", mpidr as u32);
+            fdt.end_node();
+        }
+
+        fdt.end_node();
+
+        fdt.begin_node("psci");
+        fdt.property_strings("compatible", &["arm,psci-0.2", "arm,psci"])?;
+        fdt.property_string("method", "hvc")?;
+        fdt.end_node();
+
+        // Describe the memory.
+        for range in memory {
+            fdt.begin_node(&format!("memory@{:x}", range.start));
+            fdt.property_string("device_type", "memory")?;
+            fdt.property_u64s("reg", &[range.start, range.end - range.start]);
+            fdt.end_node();
+        }
+
+        // Describe the interrupt controller.
+        fdt.begin_node(&format!("intc@{:x}", self.gic.distributor_base));
+        fdt.property_string("compatible", "arm,gic-v3")?;
+        fdt.property_null("interrupt-controller");
+        fdt.property_u32("#interrupt-cells", 3);
+        fdt.property_u64s("reg", &[
+            self.gic.distributor_base,
+            GIC_DISTRIBUTOR_SIZE,
+            self.gic.redistributor_base,
+            GIC_REDISTRIBUTOR_SIZE * self.vcpu_count as u64,
+        ]);
+        fdt.property_u32s("interrupts", &[GIC_PPI, GIC_MAINTENANCE_PPI, IRQ_TYPE_LEVEL_HIGH]);
+        fdt.property_u32("phandle", GIC_PHANDLE);
+        fdt.end_node();
+
+        // Describe the architected timer.
+        let interrupts: Vec<u32> = TIMER_PPIS
+            .iter()
+            .flat_map(|&ppi| vec![GIC_PPI, ppi, IRQ_TYPE_LEVEL_HIGH])
+            .collect();
+
+        fdt.begin_node("timer");
+        fdt.property_string("compatible", "arm,armv8-timer")?;
+        fdt.property_u32s("interrupts", &interrupts);
+        fdt.property_null("always-on");
+        fdt.end_node();
+
+        // Describe the UART.
+        let uart_path = match self.uart {
+            Some((address, intid)) => {
+                if intid < GIC_SPI_BASE {
+                    return Err(Error::InvalidArgument);
+                }
+
+                let name = format!("uart@{:x}", address);
+
+                fdt.begin_node(&name);
+                fdt.property_string("compatible", "ns16550a")?;
+                fdt.property_u64s("reg", &[address, UART_SIZE]);
+                fdt.property_u32("clock-frequency", UART_CLOCK_FREQUENCY);
+                fdt.property_u32s("interrupts", &[
+                    GIC_SPI,
+                    intid - GIC_SPI_BASE,
+                    IRQ_TYPE_LEVEL_HIGH,
+                ]);
+                fdt.end_node();
+
+                Some(format!("/{}", name))
+            }
+            _ => None,
+        };
+
+        // Describe the boot configuration.
+        fdt.begin_node("chosen");
+
+        if let Some(cmdline) = &self.cmdline {
+            fdt.property_string("bootargs", cmdline)?;
+        }
+
+        if let Some(path) = &uart_path {
+            fdt.property_string("stdout-path", path)?;
+        }
+
+        if let Some(initrd) = &self.initrd {
+            fdt.property_u64s("linux,initrd-start", &[initrd.start]);
+            fdt.property_u64s("linux,initrd-end", &[initrd.end]);
+        }
+
+        fdt.end_node();
+        fdt.end_node();
+
+        let bytes = fdt.finish();
+
+        if bytes.len() as u64 > FDT_MAX_SIZE {
+            return Err(Error::InvalidArgument);
+        }
+
+        Ok(bytes)
+    }
+}
+
+/// Helper struct to serialize the structure block and the strings block of a device tree.
+#[derive(Default)]
+struct FdtWriter {
+    structure: Vec<u8>,
+    strings: Vec<u8>,
+    string_offsets: HashMap<String, u32>,
+}
+
+impl FdtWriter {
+    fn push_u32(&mut self, value: u32) {
+        self.structure.extend_from_slice(&value.to_be_bytes());
+    }
+
+    /// Pads the structure block with zeroes up to a multiple of four bytes.
+    fn align(&mut self) {
+        while self.structure.len() % 4 != 0 {
+            self.structure.push(0);
+        }
+    }
+
+    fn begin_node(&mut self, name: &str) {
+        self.push_u32(FDT_BEGIN_NODE);
+        self.structure.extend_from_slice(name.as_bytes());
+        self.structure.push(0);
+        self.align();
+    }
+
+    fn end_node(&mut self) {
+        self.push_u32(FDT_END_NODE);
+    }
+
+    fn property(&mut self, name: &str, value: &[u8]) {
+        let strings = &mut self.strings;
+        let offset = *self.string_offsets.entry(name.to_string()).or_insert_with(|| {
+            let offset = strings.len() as u32;
+
+            strings.extend_from_slice(name.as_bytes());
+            strings.push(0);
+
+            offset
+        });
+
+        self.push_u32(FDT_PROP);
+        self.push_u32(value.len() as u32);
+        self.push_u32(offset);
+        self.structure.extend_from_slice(value);
+        self.align();
+    }
+
+    fn property_null(&mut self, name: &str) {
+        self.property(name, &[]);
+    }
+
+    fn property_u32(&mut self, name: &str, value: u32) {
+        self.property_u32s(name, &[value]);
+    }
+
+    fn property_u32s(&mut self, name: &str, values: &[u32]) {
+        let bytes: Vec<u8> = values.iter().flat_map(|value| value.to_be_bytes()).collect();
+
+        self.property(name, &bytes);
+    }
+
+    fn property_u64s(&mut self, name: &str, values: &[u64]) {
+        let bytes: Vec<u8> = values.iter().flat_map(|value| value.to_be_bytes()).collect();
+
+        self.property(name, &bytes);
+    }
+
+    fn property_string(&mut self, name: &str, value: &str) -> Result<(), Error> {
+        self.property_strings(name, &[value])
+    }
+
+    /// Adds a property holding the given list of strings. Returns [`Error::InvalidArgument`] if
+    /// any of the strings contains a null byte.
+    fn property_strings(&mut self, name: &str, values: &[&str]) -> Result<(), Error> {
+        let mut bytes = vec![];
+
+        for value in values {
+            let value = CString::new(*value).map_err(|_| Error::InvalidArgument)?;
+
+            bytes.extend_from_slice(value.as_bytes_with_nul());
+        }
+
+        self.property(name, &bytes);
+
+        Ok(())
+    }
+
+    /// Returns the device tree consisting of the header, an empty memory reservation block, the
+    /// structure block and the strings block.
+    fn finish(mut self) -> Vec<u8> {
+        self.push_u32(FDT_END);
+
+        // The memory reservation block consists of the terminating entry only.
+        let reservation_offset = FDT_HEADER_SIZE;
+        let structure_offset = reservation_offset + 16;
+        let strings_offset = structure_offset + self.structure.len();
+        let total_size = strings_offset + self.strings.len();
+
+        let header = [
+            FDT_MAGIC,
+            total_size as u32,
+            structure_offset as u32,
+            strings_offset as u32,
+            reservation_offset as u32,
+            FDT_VERSION,
+            FDT_LAST_COMPATIBLE_VERSION,
+            0,
+            self.strings.len() as u32,
+            self.structure.len() as u32,
+        ];
+
+        let mut bytes: Vec<u8> = header.iter().flat_map(|value| value.to_be_bytes()).collect();
+
+        bytes.extend_from_slice(&[0; 16]);
+        bytes.extend_from_slice(&self.structure);
+        bytes.extend_from_slice(&self.strings);
+
+        bytes
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::convert::TryInto;
+
+    /// A node of a parsed device tree with its properties and its child nodes.
+    #[derive(Debug, Default)]
+    struct Node {
+        name: String,
+        properties: HashMap<String, Vec<u8>>,
+        children: Vec<Node>,
+    }
+
+    impl Node {
+        fn child(&self, name: &str) -> &Node {
+            self.children
+                .iter()
+                .find(|node| node.name == name)
+                .unwrap_or_else(|| panic!("missing node {}", name))
+        }
+
+        fn property(&self, name: &str) -> &[u8] {
+            &self.properties[name]
+        }
+    }
+
+    fn gic() -> GicConfig {
+        GicConfig {
+            distributor_base: 0x800_0000,
+            redistributor_base: 0x80a_0000,
+            spi_count: 64,
+        }
+    }
+
+    fn read_u32(bytes: &[u8], offset: usize) -> u32 {
+        u32::from_be_bytes(bytes[offset..offset + 4].try_into().unwrap())
+    }
+
+    fn read_string(bytes: &[u8], offset: usize) -> String {
+        let end = offset + bytes[offset..].iter().position(|&byte| byte == 0).unwrap();
+
+        String::from_utf8(bytes[offset..end].to_vec()).unwrap()
+    }
+
+    /// Helper function to validate the header of the given device tree and to parse its
+    /// structure block.
+    fn parse(bytes: &[u8]) -> Node {
+        assert_eq!(read_u32(bytes, 0), FDT_MAGIC);
+        assert_eq!(read_u32(bytes, 4) as usize, bytes.len());
+        assert_eq!(read_u32(bytes, 20), FDT_VERSION);
+        assert_eq!(read_u32(bytes, 24), FDT_LAST_COMPATIBLE_VERSION);
+
+        let structure_offset = read_u32(bytes, 8) as usize;
+        let strings_offset = read_u32(bytes, 12) as usize;
+        let reservation_offset = read_u32(bytes, 16) as usize;
+        let strings_size = read_u32(bytes, 32) as usize;
+        let structure_size = read_u32(bytes, 36) as usize;
+
+        assert_eq!(bytes[reservation_offset..reservation_offset + 16], [0; 16]);
+        assert_eq!(structure_offset % 4, 0);
+        assert_eq!(structure_offset + structure_size, strings_offset);
+        assert_eq!(strings_offset + strings_size, bytes.len());
+
+        let strings = &bytes[strings_offset..];
+        let mut stack: Vec<Node> = vec![];
+        let mut root = None;
+        let mut offset = structure_offset;
+
+        loop {
+            let token = read_u32(bytes, offset);
+            offset += 4;
+
+            match token {
+                FDT_BEGIN_NODE => {
+                    let name = read_string(bytes, offset);
+                    offset = (offset + name.len() + 1 + 3) & !3;
+
+                    stack.push(Node {
+                        name,
+                        ..Default::default()
+                    });
+                }
+                FDT_END_NODE => {
+                    let node = stack.pop().unwrap();
+
+                    match stack.last_mut() {
+                        Some(parent) => parent.children.push(node),
+                        _ => root = Some(node),
+                    }
+                }
+                FDT_PROP => {
+                    let len = read_u32(bytes, offset) as usize;
+                    let name = read_string(strings, read_u32(bytes, offset + 4) as usize);
+                    let value = bytes[offset + 8..offset + 8 + len].to_vec();
+                    offset = (offset + 8 + len + 3) & !3;
+
+                    stack.last_mut().unwrap().properties.insert(name, value);
+                }
+                FDT_END => break,
+                _ => panic!("invalid token {:#x}", token),
+            }
+        }
+
+        assert!(stack.is_empty());
+        assert_eq!(offset, strings_offset);
+
+        root.unwrap()
+    }
+
+    #[test]
+    fn device_tree() {
+        let memory = 0x4000_0000..0x8000_0000;
+        let bytes = FdtBuilder::new(17, gic())
+            .with_uart(0x900_0000, GIC_SPI_BASE + 1)
+            .with_cmdline("console=ttyS0")
+            .with_initrd(0x4800_0000..0x4900_0000)
+            .build(std::slice::from_ref(&memory))
+            .unwrap();
+
+        let root = parse(&bytes);
+
+        assert_eq!(root.name, "");
+        assert_eq!(root.property("compatible"), b"linux,dummy-virt\0");
+
+        // The virtual CPUs are numbered by their MPIDR.
+        let cpus = root.child("cpus");
+
+        assert_eq!(cpus.children.len(), 17);
+        assert_eq!(cpus.child("cpu@f").property("reg"), 15u32.to_be_bytes());
+        assert_eq!(cpus.child("cpu@100").property("reg"), 0x100u32.to_be_bytes());
+
+        let memory = root.child("memory@40000000");
+        let reg: Vec<u8> = [0x4000_0000u64, 0x4000_0000]
+            .iter()
+            .flat_map(|value| value.to_be_bytes())
+            .collect();
+
+        assert_eq!(memory.property("reg"), reg);
+
+        let uart = root.child("uart@9000000");
+
+        assert_eq!(uart.property("interrupts")[4..8], 1u32.to_be_bytes());
+
+        let chosen = root.child("chosen");
+
+        assert_eq!(chosen.property("bootargs"), b"console=ttyS0\0");
+        assert_eq!(chosen.property("stdout-path"), b"/uart@9000000\0");
+        assert_eq!(chosen.property("linux,initrd-end"), 0x4900_0000u64.to_be_bytes());
+
+        let intc = root.child("intc@8000000");
+
+        assert!(intc.property("interrupt-controller").is_empty());
+        assert_eq!(intc.property("phandle"), GIC_PHANDLE.to_be_bytes());
+    }
+
+    #[test]
+    fn shared_strings() {
+        let memory = 0..0x1000;
+        let bytes = FdtBuilder::new(2, gic()).build(std::slice::from_ref(&memory)).unwrap();
+        let strings_offset = read_u32(&bytes, 12) as usize;
+        let strings = &bytes[strings_offset..];
+
+        // Every property name is stored once.
+        let count = strings
+            .split(|&byte| byte == 0)
+            .filter(|name| *name == b"compatible")
+            .count();
+
+        assert_eq!(count, 1);
+    }
+
+    #[test]
+    fn invalid_arguments() {
+        let results = [
+            FdtBuilder::new(0, gic()).build(&[]),
+            FdtBuilder::new(1, gic())
+                .with_uart(0x900_0000, GIC_SPI_BASE - 1)
+                .build(&[]),
+            FdtBuilder::new(1, gic())
+                .with_cmdline("console=ttyS0\0")
+                .build(&[]),
+        ];
+
+        for result in results {
+            assert!(matches!(result, Err(Error::InvalidArgument)));
+        }
+    }
+}
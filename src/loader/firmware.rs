@@ -0,0 +1,80 @@
+//! This module provides a helper to map firmware images, e.g. OVMF or SeaBIOS, into the guest
+//! physical memory on x86, see [`map_rom`].
+//!
+//! The firmware image is mapped such that it ends at 4 GiB, where the virtual CPUs start
+//! executing at the architectural reset vector 16 bytes below the end of the image. In addition,
+//! the last 128 KiB of the image are mapped just below 1 MiB, where legacy BIOS code expects the
+//! firmware to be. As the firmware is mapped read-only, this alias is a copy of the image rather
+//! than a mapping of the same memory.
+
+use crate::error::Error;
+use crate::vcpu::Vcpu;
+use crate::vm::{ProtectionFlags, Vm};
+use std::ops::Range;
+use super::LoadedSegment;
+
+/// The guest physical address at which the firmware image ends.
+const ROM_END: u64 = 1 << 32;
+/// The maximum size of the firmware image, i.e. the size of the flash region below 4 GiB.
+const ROM_MAX_SIZE: u64 = 16 << 20;
+/// The guest physical address at which the alias of the firmware image ends.
+const ALIAS_END: u64 = 1 << 20;
+/// The maximum size of the alias of the firmware image below 1 MiB.
+const ALIAS_MAX_SIZE: u64 = 128 << 10;
+
+/// Describes a firmware image that has been mapped into guest physical memory, as returned by
+/// [`map_rom`].
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct FirmwareInfo {
+    /// The guest physical address range of the firmware image below 4 GiB.
+    pub rom: Range<u64>,
+    /// The guest physical address range of the alias of the firmware image below 1 MiB.
+    pub alias: Range<u64>,
+}
+
+/// Maps the given firmware image into the guest physical memory of the given VM as read-only and
+/// executable memory, such that the image ends at 4 GiB and the last 128 KiB of the image are
+/// aliased below 1 MiB, and resets the given virtual CPUs to the architectural reset vector, see
+/// [`Vcpu::reset`]. The guest physical memory is not handed to the page allocator of the VM.
+///
+/// Returns [`Error::InvalidArgument`] if the image is empty or larger than 16 MiB, or
+/// [`Error::OverlappingRegion`] if the image or its alias overlaps with guest physical memory that
+/// has already been mapped, e.g. if the guest RAM covers the legacy BIOS area below 1 MiB.
+pub fn map_rom(vm: &mut Vm, bytes: &[u8], vcpus: &mut [Vcpu]) -> Result<FirmwareInfo, Error> {
+    let size = bytes.len() as u64;
+
+    if size == 0 || size > ROM_MAX_SIZE {
+        return Err(Error::InvalidArgument);
+    }
+
+    let alias_size = size.min(ALIAS_MAX_SIZE);
+    let protection = ProtectionFlags::READ | ProtectionFlags::EXECUTE;
+
+    let rom = LoadedSegment {
+        physical_address: ROM_END - size,
+        virtual_address: ROM_END - size,
+        size,
+        protection,
+    };
+
+    let alias = LoadedSegment {
+        physical_address: ALIAS_END - alias_size,
+        virtual_address: ALIAS_END - alias_size,
+        size: alias_size,
+        protection,
+    };
+
+    super::load_segments(vm, vec![
+        (rom, bytes),
+        (alias, &bytes[(size - alias_size) as usize..]),
+    ])?;
+
+    for vcpu in vcpus {
+        vcpu.reset()?;
+    }
+
+    Ok(FirmwareInfo {
+        rom: ROM_END - size..ROM_END,
+        alias: ALIAS_END - alias_size..ALIAS_END,
+    })
+}
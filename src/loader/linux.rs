@@ -0,0 +1,326 @@
+//! This module provides a loader for Linux kernels in the bzImage format on x86, see
+//! [`load_bzimage`]. The kernel is booted through the 64-bit boot protocol described in
+//! `Documentation/arch/x86/boot.rst` of the Linux kernel, which skips the real-mode setup code of
+//! the kernel and enters the kernel in 64-bit long mode instead.
+//!
+//! The loader places the boot data at fixed guest physical addresses in the first MiB and the
+//! kernel at 1 MiB, so the VM must have guest physical memory mapped there. The e820 memory map
+//! passed to the kernel is derived from the regions of the VM, see [`crate::Vm::regions`].
+
+use crate::arch::x86_64::{
+    CpuRegs, DescriptorTableRegister, GdtBuilder, LongModeLayout, Register, Segment,
+    SegmentRegister,
+};
+use crate::bytes::{self, AsBytes, FromBytes, Le};
+use crate::error::Error;
+use crate::vcpu::Vcpu;
+use crate::vm::{ProtectionFlags, Vm};
+use std::ffi::CString;
+use std::ops::Range;
+
+/// The guest physical address of the GDT.
+const GDT_ADDRESS: u64 = 0x500;
+/// The guest physical address of the boot parameters, also known as the zero page.
+const BOOT_PARAMS_ADDRESS: u64 = 0x7000;
+/// The initial stack pointer, which is unused by the kernel until it sets up its own stack.
+const STACK_ADDRESS: u64 = 0x8ff0;
+/// The guest physical address of the page tables.
+const PAGE_TABLES_ADDRESS: u64 = 0x9000;
+/// The guest physical address of the kernel command line.
+const CMDLINE_ADDRESS: u64 = 0x20000;
+/// The guest physical address of the protected-mode kernel.
+const KERNEL_ADDRESS: u64 = 0x100000;
+/// The number of bytes identity mapped for the kernel, which must cover the kernel, the boot
+/// parameters and the command line.
+const IDENTITY_MAP_SIZE: u64 = 1 << 32;
+
+/// The size of the boot parameters.
+const BOOT_PARAMS_SIZE: usize = 0x1000;
+/// The offset of the setup header into the kernel image and the boot parameters.
+const SETUP_HEADER_OFFSET: usize = 0x1f1;
+/// The offset of the number of entries of the e820 memory map into the boot parameters.
+const E820_ENTRIES_OFFSET: u64 = 0x1e8;
+/// The offset of the e820 memory map into the boot parameters.
+const E820_TABLE_OFFSET: u64 = 0x2d0;
+/// The maximum number of entries of the e820 memory map in the boot parameters.
+const E820_MAX_ENTRIES: usize = 128;
+/// The e820 type of usable memory.
+const E820_RAM: u32 = 1;
+/// The e820 type of reserved memory.
+const E820_RESERVED: u32 = 2;
+
+/// The magic number at the end of the boot sector.
+const BOOT_FLAG: u16 = 0xaa55;
+/// The magic number of the setup header, i.e. `HdrS`.
+const HEADER_MAGIC: u32 = 0x5372_6448;
+/// The oldest version of the boot protocol that supports the 64-bit entry point.
+const MIN_PROTOCOL_VERSION: u16 = 0x020c;
+/// The flag of `xloadflags` indicating that the kernel has the 64-bit entry point.
+const XLF_KERNEL_64: u16 = 1 << 0;
+/// The offset of the 64-bit entry point into the protected-mode kernel.
+const ENTRY_64_OFFSET: u64 = 0x200;
+/// The type of loader that is not registered with the Linux kernel.
+const LOADER_TYPE_UNDEFINED: u8 = 0xff;
+
+/// The setup header of the kernel image, which is part of the boot parameters.
+#[allow(dead_code)]
+#[derive(Clone, Copy)]
+#[repr(C, packed)]
+struct SetupHeader {
+    setup_sects: u8,
+    root_flags: Le<u16>,
+    syssize: Le<u32>,
+    ram_size: Le<u16>,
+    vid_mode: Le<u16>,
+    root_dev: Le<u16>,
+    boot_flag: Le<u16>,
+    jump: Le<u16>,
+    header: Le<u32>,
+    version: Le<u16>,
+    realmode_swtch: Le<u32>,
+    start_sys_seg: Le<u16>,
+    kernel_version: Le<u16>,
+    type_of_loader: u8,
+    loadflags: u8,
+    setup_move_size: Le<u16>,
+    code32_start: Le<u32>,
+    ramdisk_image: Le<u32>,
+    ramdisk_size: Le<u32>,
+    bootsect_kludge: Le<u32>,
+    heap_end_ptr: Le<u16>,
+    ext_loader_ver: u8,
+    ext_loader_type: u8,
+    cmd_line_ptr: Le<u32>,
+    initrd_addr_max: Le<u32>,
+    kernel_alignment: Le<u32>,
+    relocatable_kernel: u8,
+    min_alignment: u8,
+    xloadflags: Le<u16>,
+    cmdline_size: Le<u32>,
+    hardware_subarch: Le<u32>,
+    hardware_subarch_data: Le<u64>,
+    payload_offset: Le<u32>,
+    payload_length: Le<u32>,
+    setup_data: Le<u64>,
+    pref_address: Le<u64>,
+    init_size: Le<u32>,
+    handover_offset: Le<u32>,
+    kernel_info_offset: Le<u32>,
+}
+
+unsafe impl FromBytes for SetupHeader {}
+unsafe impl AsBytes for SetupHeader {}
+
+/// An entry of the e820 memory map.
+#[allow(dead_code)]
+#[derive(Clone, Copy)]
+#[repr(C, packed)]
+struct E820Entry {
+    address: Le<u64>,
+    size: Le<u64>,
+    kind: Le<u32>,
+}
+
+unsafe impl FromBytes for E820Entry {}
+unsafe impl AsBytes for E820Entry {}
+
+/// Describes a Linux kernel that has been loaded into guest physical memory, as returned by
+/// [`load_bzimage`].
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct BootInfo {
+    /// The guest physical address of the 64-bit entry point of the kernel.
+    pub entry: u64,
+    /// The guest physical address of the boot parameters.
+    pub boot_params: u64,
+    /// The guest physical address range of the initial RAM disk, if any.
+    pub initrd: Option<Range<u64>>,
+}
+
+impl BootInfo {
+    /// Sets up the given virtual CPU to enter the kernel through the 64-bit boot protocol, i.e.
+    /// in long mode with the first 4 GiB identity mapped, with flat segments at the selectors
+    /// `__BOOT_CS` and `__BOOT_DS`, and with `rsi` pointing to the boot parameters. This should
+    /// only be called for the bootstrap processor, as the kernel starts the other virtual CPUs
+    /// itself.
+    pub fn setup_vcpu(&self, vm: &mut Vm, vcpu: &mut Vcpu) -> Result<(), Error> {
+        let layout = LongModeLayout {
+            page_tables: PAGE_TABLES_ADDRESS,
+            identity_map_size: IDENTITY_MAP_SIZE,
+            gdt: GDT_ADDRESS,
+            entry: self.entry,
+            stack: STACK_ADDRESS,
+        };
+
+        vcpu.enter_long_mode(vm, &layout)?;
+
+        // The boot protocol expects the code segment at selector 0x10 and the data segment at
+        // selector 0x18, so skip the first descriptor after the null descriptor.
+        let mut builder = GdtBuilder::new();
+
+        builder.add_segment(&Segment::default());
+        let code = builder.add_segment(&Segment::long_mode_code(0));
+        let data = builder.add_segment(&Segment::long_mode_data(0));
+
+        let table = builder.write(vm, GDT_ADDRESS)?;
+
+        vcpu.set_descriptor_tables(&[DescriptorTableRegister::Gdt], &[table])?;
+        vcpu.set_segment_registers(
+            &[
+                SegmentRegister::Cs,
+                SegmentRegister::Ss,
+                SegmentRegister::Ds,
+                SegmentRegister::Es,
+                SegmentRegister::Fs,
+                SegmentRegister::Gs,
+            ],
+            &[code, data.clone(), data.clone(), data.clone(), data.clone(), data],
+        )?;
+
+        vcpu.set_registers(&[Register::Rsi], &[self.boot_params])
+    }
+}
+
+/// Loads the given Linux kernel in the bzImage format into the guest physical memory of the
+/// given VM, along with the given initial RAM disk and kernel command line. This writes the
+/// protected-mode kernel, the initial RAM disk, the command line and the boot parameters with the
+/// e820 memory map to guest physical memory. Use [`BootInfo::setup_vcpu`] to set up the
+/// bootstrap processor to enter the kernel afterwards.
+///
+/// The initial RAM disk is placed at the end of the highest region of writable guest physical
+/// memory below the limit set by the kernel, which must not overlap with the kernel. Returns
+/// [`Error::InvalidArgument`] if the kernel is not a bzImage supporting the 64-bit boot
+/// protocol, if the command line is too long, or if there is no room for the initial RAM disk.
+/// Returns [`Error::UnmappedGuestAddress`] if the guest physical memory at the fixed addresses
+/// used by the loader is not mapped.
+pub fn load_bzimage(
+    vm: &mut Vm,
+    kernel: &[u8],
+    initrd: Option<&[u8]>,
+    cmdline: &str,
+) -> Result<BootInfo, Error> {
+    // The setup header ends at the offset stored in the second byte of the jump instruction.
+    let header_end = match kernel.get(0x201) {
+        Some(&jump) => 0x202 + jump as usize,
+        _ => return Err(Error::InvalidArgument),
+    };
+
+    let header_size = std::mem::size_of::<SetupHeader>();
+    let size = (header_end - SETUP_HEADER_OFFSET).min(header_size);
+    let mut header_bytes = vec![0u8; header_size];
+
+    header_bytes[..size].copy_from_slice(
+        kernel
+            .get(SETUP_HEADER_OFFSET..SETUP_HEADER_OFFSET + size)
+            .ok_or(Error::InvalidArgument)?,
+    );
+
+    let mut header: SetupHeader = bytes::from_bytes(&header_bytes);
+
+    if header.boot_flag.get() != BOOT_FLAG ||
+        header.header.get() != HEADER_MAGIC ||
+        header.version.get() < MIN_PROTOCOL_VERSION ||
+        header.xloadflags.get() & XLF_KERNEL_64 == 0 {
+        return Err(Error::InvalidArgument);
+    }
+
+    // A value of zero means four sectors for compatibility.
+    let setup_sects = match header.setup_sects {
+        0 => 4,
+        sectors => sectors as usize,
+    };
+
+    let protected_kernel = kernel
+        .get((setup_sects + 1) * 512..)
+        .ok_or(Error::InvalidArgument)?;
+
+    // The kernel needs the memory up to its initialization size to decompress itself.
+    let kernel_end = KERNEL_ADDRESS +
+        (protected_kernel.len() as u64).max(header.init_size.get() as u64);
+
+    // Set up the command line.
+    if cmdline.len() > header.cmdline_size.get() as usize {
+        return Err(Error::InvalidArgument);
+    }
+
+    let cmdline = CString::new(cmdline).map_err(|_| Error::InvalidArgument)?;
+
+    // Derive the e820 memory map from the regions of the VM.
+    let regions: Vec<_> = vm.regions()?.collect();
+
+    if regions.len() > E820_MAX_ENTRIES {
+        return Err(Error::InvalidArgument);
+    }
+
+    let entries: Vec<E820Entry> = regions
+        .iter()
+        .map(|(range, protection, _)| {
+            let kind = if protection.contains(ProtectionFlags::WRITE) {
+                E820_RAM
+            } else {
+                E820_RESERVED
+            };
+
+            E820Entry {
+                address: Le::new(range.start),
+                size: Le::new(range.end - range.start),
+                kind: Le::new(kind),
+            }
+        })
+        .collect();
+
+    // Place the initial RAM disk as high as possible in writable guest physical memory.
+    let initrd_range = match initrd {
+        Some(initrd) => {
+            let size = initrd.len() as u64;
+            let limit = header.initrd_addr_max.get() as u64 + 1;
+
+            let start = regions
+                .iter()
+                .rev()
+                .filter(|(_, protection, _)| protection.contains(ProtectionFlags::WRITE))
+                .find_map(|(range, _, _)| {
+                    let end = range.end.min(limit);
+                    let start = end.checked_sub(size)? & !0xfff;
+
+                    if start >= range.start && start >= kernel_end {
+                        Some(start)
+                    } else {
+                        None
+                    }
+                })
+                .ok_or(Error::InvalidArgument)?;
+
+            vm.write_all_physical_memory(start, initrd)?;
+
+            header.ramdisk_image = Le::new(start as u32);
+            header.ramdisk_size = Le::new(size as u32);
+
+            Some(start..start + size)
+        }
+        _ => None,
+    };
+
+    vm.write_all_physical_memory(KERNEL_ADDRESS, protected_kernel)?;
+    vm.write_all_physical_memory(CMDLINE_ADDRESS, cmdline.as_bytes_with_nul())?;
+
+    header.type_of_loader = LOADER_TYPE_UNDEFINED;
+    header.code32_start = Le::new(KERNEL_ADDRESS as u32);
+    header.cmd_line_ptr = Le::new(CMDLINE_ADDRESS as u32);
+
+    // Set up the boot parameters.
+    vm.write_all_physical_memory(BOOT_PARAMS_ADDRESS, &[0u8; BOOT_PARAMS_SIZE])?;
+    vm.write_obj(BOOT_PARAMS_ADDRESS + SETUP_HEADER_OFFSET as u64, &header)?;
+    vm.write_obj(BOOT_PARAMS_ADDRESS + E820_ENTRIES_OFFSET, &(entries.len() as u8))?;
+
+    for (index, entry) in entries.iter().enumerate() {
+        let offset = E820_TABLE_OFFSET + (index * std::mem::size_of::<E820Entry>()) as u64;
+
+        vm.write_obj(BOOT_PARAMS_ADDRESS + offset, entry)?;
+    }
+
+    Ok(BootInfo {
+        entry: KERNEL_ADDRESS + ENTRY_64_OFFSET,
+        boot_params: BOOT_PARAMS_ADDRESS,
+        initrd: initrd_range,
+    })
+}
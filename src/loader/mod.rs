@@ -0,0 +1,289 @@
+//! This module provides a loader for ELF images, e.g. kernels or bare-metal test payloads, see
+//! [`load_elf`]. Linux kernels in the bzImage format can be loaded through [`linux`] and ELF
+//! kernels supporting the PVH boot protocol through [`pvh`] on x86. PE images can be loaded
+//! through [`load_pe`] and flat binaries, e.g. unikernels, through [`load_flat`].
+//! Firmware images, e.g. OVMF or SeaBIOS, can be mapped through [`firmware`] on x86. For guests
+//! booted without firmware, [`mptable`] and [`smbios`] generate the tables describing the system.
+//! On AArch64, [`fdt`] generates the device tree describing the system instead.
+//!
+//! Every `PT_LOAD` segment of an ELF image is placed at its physical address in guest physical
+//! memory, where the loader allocates the guest physical memory for the segments and protects it
+//! according to the flags of the segments. The virtual addresses of the segments are returned as
+//! is, so the caller is responsible for setting up the page tables that map them, unless the
+//! image runs with paging disabled or identity mapped.
+
+#[cfg(target_arch = "aarch64")]
+pub mod fdt;
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+pub mod firmware;
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+pub mod linux;
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+pub mod mptable;
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+pub mod pvh;
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+pub mod smbios;
+
+mod pe;
+
+use crate::elf::Elf;
+use crate::error::Error;
+use crate::vm::{ProtectionFlags, Vm};
+use mmap_rs::MmapOptions;
+use pe::Pe;
+use std::ops::Range;
+
+/// The ELF program header type of a loadable segment.
+const PT_LOAD: u32 = 1;
+/// The ELF segment flag that marks the segment as executable.
+const PF_X: u32 = 1 << 0;
+/// The ELF segment flag that marks the segment as writable.
+const PF_W: u32 = 1 << 1;
+/// The ELF segment flag that marks the segment as readable.
+const PF_R: u32 = 1 << 2;
+/// The PE section characteristic that marks the section as executable.
+const IMAGE_SCN_MEM_EXECUTE: u32 = 1 << 29;
+/// The PE section characteristic that marks the section as readable.
+const IMAGE_SCN_MEM_READ: u32 = 1 << 30;
+/// The PE section characteristic that marks the section as writable.
+const IMAGE_SCN_MEM_WRITE: u32 = 1 << 31;
+
+/// A segment of an image that has been loaded into guest physical memory.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct LoadedSegment {
+    /// The guest physical address of the segment.
+    pub physical_address: u64,
+    /// The guest virtual address of the segment.
+    pub virtual_address: u64,
+    /// The size of the segment in guest memory, including the zero-initialized part.
+    pub size: u64,
+    /// The protection of the segment.
+    pub protection: ProtectionFlags,
+}
+
+/// Describes an image that has been loaded into guest physical memory, as returned by
+/// [`load_elf`], [`load_pe`] and [`load_flat`].
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct EntryInfo {
+    /// The guest virtual address of the entry point.
+    pub entry: u64,
+    /// The loaded segments in the order of their guest physical addresses.
+    pub segments: Vec<LoadedSegment>,
+}
+
+/// Loads the given little-endian ELF image into the guest physical memory of the given VM, where
+/// every `PT_LOAD` segment is placed at its physical address.
+///
+/// The guest physical memory for the segments is allocated in page-aligned regions, where the
+/// segments sharing a page end up in the same region with the union of their protection flags.
+/// The regions are allocated like [`Vm::allocate_physical_memory`], but their pages are reserved
+/// in the page allocator of the VM, such that the pages of the image are never allocated for e.g.
+/// page tables. Returns [`Error::InvalidArgument`] if the image is not a valid ELF image for the
+/// architecture of the host or has no loadable segments, or [`Error::OverlappingRegion`] if a
+/// segment overlaps with guest physical memory that has already been mapped.
+pub fn load_elf(vm: &mut Vm, bytes: &[u8]) -> Result<EntryInfo, Error> {
+    let elf = Elf::parse(bytes)?;
+    let mut segments = vec![];
+
+    for index in 0..elf.segment_count {
+        let segment = elf.segment(index)?;
+
+        if segment.kind != PT_LOAD || segment.memory_size == 0 {
+            continue;
+        }
+
+        if segment.file_size > segment.memory_size {
+            return Err(Error::InvalidArgument);
+        }
+
+        let mut protection = ProtectionFlags::empty();
+
+        if segment.flags & PF_R != 0 {
+            protection |= ProtectionFlags::READ;
+        }
+
+        if segment.flags & PF_W != 0 {
+            protection |= ProtectionFlags::WRITE;
+        }
+
+        if segment.flags & PF_X != 0 {
+            protection |= ProtectionFlags::EXECUTE;
+        }
+
+        let data = elf.segment_bytes(&segment)?;
+
+        segments.push((LoadedSegment {
+            physical_address: segment.physical_address,
+            virtual_address: segment.virtual_address,
+            size: segment.memory_size,
+            protection,
+        }, data));
+    }
+
+    Ok(EntryInfo {
+        entry: elf.entry,
+        segments: load_segments(vm, segments)?,
+    })
+}
+
+/// Loads the given PE image into the guest physical memory of the given VM, where the image is
+/// placed at the given guest physical address as it would be laid out in memory, i.e. with every
+/// section at its relative virtual address. The sections are protected according to their
+/// characteristics, and the headers are mapped read-only. The virtual addresses of the sections
+/// and the entry point are relative to the preferred image base, as the image is not relocated.
+///
+/// The guest physical memory is allocated like [`load_elf`]. Returns [`Error::InvalidArgument`]
+/// if the image is not a valid PE32 or PE32+ image, or [`Error::OverlappingRegion`] if the image
+/// overlaps with guest physical memory that has already been mapped.
+pub fn load_pe(vm: &mut Vm, bytes: &[u8], guest_address: u64) -> Result<EntryInfo, Error> {
+    let pe = Pe::parse(bytes)?;
+    let headers = pe.bytes(0, pe.header_size)?;
+
+    let mut segments = vec![(LoadedSegment {
+        physical_address: guest_address,
+        virtual_address: pe.image_base,
+        size: pe.header_size,
+        protection: ProtectionFlags::READ,
+    }, headers)];
+
+    for index in 0..pe.section_count {
+        let section = pe.section(index)?;
+
+        // Sections that only describe data in the file, e.g. debug information, have no size in
+        // memory.
+        let size = match section.virtual_size {
+            0 => section.raw_size,
+            size => size,
+        } as u64;
+
+        if size == 0 {
+            continue;
+        }
+
+        let mut protection = ProtectionFlags::empty();
+
+        if section.characteristics & IMAGE_SCN_MEM_READ != 0 {
+            protection |= ProtectionFlags::READ;
+        }
+
+        if section.characteristics & IMAGE_SCN_MEM_WRITE != 0 {
+            protection |= ProtectionFlags::WRITE;
+        }
+
+        if section.characteristics & IMAGE_SCN_MEM_EXECUTE != 0 {
+            protection |= ProtectionFlags::EXECUTE;
+        }
+
+        // The raw data is padded to the file alignment, so it may be larger than the section.
+        let raw_size = (section.raw_size as u64).min(size);
+        let data = pe.bytes(section.raw_offset as u64, raw_size)?;
+
+        segments.push((LoadedSegment {
+            physical_address: guest_address
+                .checked_add(section.virtual_address as u64)
+                .ok_or(Error::InvalidArgument)?,
+            virtual_address: pe.image_base.wrapping_add(section.virtual_address as u64),
+            size,
+            protection,
+        }, data));
+    }
+
+    Ok(EntryInfo {
+        entry: pe.image_base.wrapping_add(pe.entry as u64),
+        segments: load_segments(vm, segments)?,
+    })
+}
+
+/// Loads the given flat binary into the guest physical memory of the given VM at the given guest
+/// physical address with the given protection, such that the guest starts executing at the
+/// start of the binary, e.g. for firmware or unikernels. The guest physical memory is allocated
+/// like [`load_elf`], where the binary is padded with zeroes up to a multiple of the page size.
+/// Returns [`Error::InvalidArgument`] if the binary is empty, or [`Error::OverlappingRegion`] if
+/// the binary overlaps with guest physical memory that has already been mapped.
+pub fn load_flat(
+    vm: &mut Vm,
+    bytes: &[u8],
+    guest_address: u64,
+    protection: ProtectionFlags,
+) -> Result<EntryInfo, Error> {
+    let segment = LoadedSegment {
+        physical_address: guest_address,
+        virtual_address: guest_address,
+        size: bytes.len() as u64,
+        protection,
+    };
+
+    Ok(EntryInfo {
+        entry: guest_address,
+        segments: load_segments(vm, vec![(segment, bytes)])?,
+    })
+}
+
+/// Helper function to allocate the guest physical memory for the given segments, and to write
+/// the given contents of the segments to it. The pages of the segments are reserved in the page
+/// allocator of the VM, such that they are not handed out, e.g. for page tables. Returns the
+/// segments in the order of their guest physical addresses.
+fn load_segments(
+    vm: &mut Vm,
+    mut segments: Vec<(LoadedSegment, &[u8])>,
+) -> Result<Vec<LoadedSegment>, Error> {
+    let page_size = MmapOptions::page_size().1 as u64;
+
+    if segments.is_empty() || segments.iter().any(|(segment, _)| segment.size == 0) {
+        return Err(Error::InvalidArgument);
+    }
+
+    // The end of every segment must be representable after rounding it up to a page.
+    for (segment, _) in &segments {
+        match segment.physical_address.checked_add(segment.size) {
+            Some(end) if end <= u64::MAX - page_size => (),
+            _ => return Err(Error::InvalidArgument),
+        }
+    }
+
+    segments.sort_by_key(|(segment, _)| segment.physical_address);
+
+    // Merge the page ranges of the segments that share a page.
+    let mut regions: Vec<(Range<u64>, ProtectionFlags)> = vec![];
+
+    for (segment, _) in &segments {
+        let start = segment.physical_address & !(page_size - 1);
+        let end = (segment.physical_address + segment.size + page_size - 1) & !(page_size - 1);
+
+        match regions.last_mut() {
+            Some((range, protection)) if start < range.end => {
+                range.end = range.end.max(end);
+                *protection |= segment.protection;
+            }
+            _ => regions.push((start..end, segment.protection)),
+        }
+    }
+
+    for (range, protection) in regions {
+        vm.allocate_physical_memory(range.start, (range.end - range.start) as usize, protection)?;
+
+        vm.page_allocator
+            .write()
+            .unwrap()
+            .reserve_range(range);
+    }
+
+    // The guest physical memory is zero-initialized, so only the contents of the segments in the
+    // file have to be written.
+    for (segment, data) in &segments {
+        vm.write_all_physical_memory(segment.physical_address, data)?;
+    }
+
+    Ok(segments.into_iter().map(|(segment, _)| segment).collect())
+}
+
+/// Helper function to compute the checksum byte that makes the given bytes of a firmware table
+/// sum up to zero.
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+fn checksum(bytes: &[u8]) -> u8 {
+    bytes
+        .iter()
+        .fold(0u8, |sum, byte| sum.wrapping_add(*byte))
+        .wrapping_neg()
+}
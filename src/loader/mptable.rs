@@ -0,0 +1,335 @@
+//! This module provides a generator for the tables of the Intel MultiProcessor Specification on
+//! x86, see [`write_mptable`]. Older guests that do not parse the ACPI tables use the MP tables to
+//! discover the application processors, the I/O APIC and the routing of the ISA interrupts.
+//!
+//! The MP floating pointer structure is placed in the last KiB of the 640 KiB of base memory,
+//! which is one of the locations the guest scans for it, followed by the MP configuration table.
+//! The VM must have guest physical memory mapped there.
+
+use crate::bytes::{self, AsBytes, Le};
+use crate::error::Error;
+use crate::vm::Vm;
+use std::ops::Range;
+use super::checksum;
+
+/// The guest physical address of the MP floating pointer structure.
+const MPTABLE_ADDRESS: u64 = 0x9fc00;
+/// The guest physical address of the local APIC.
+const LOCAL_APIC_ADDRESS: u32 = 0xfee0_0000;
+/// The guest physical address of the I/O APIC.
+const IO_APIC_ADDRESS: u32 = 0xfec0_0000;
+/// The version of the MP specification, i.e. 1.4.
+const MP_REVISION: u8 = 4;
+/// The version of the local APIC.
+const LOCAL_APIC_VERSION: u8 = 0x14;
+/// The version of the I/O APIC.
+const IO_APIC_VERSION: u8 = 0x11;
+/// The number of ISA interrupts routed through the I/O APIC.
+const ISA_IRQ_COUNT: u8 = 16;
+/// The CPU signature of the processors, i.e. family 6.
+const CPU_SIGNATURE: u32 = 0x600;
+/// The feature flags of the processors, i.e. an on-chip FPU and APIC.
+const CPU_FEATURES: u32 = (1 << 0) | (1 << 9);
+
+/// The entry type of a processor.
+const MP_PROCESSOR: u8 = 0;
+/// The entry type of a bus.
+const MP_BUS: u8 = 1;
+/// The entry type of an I/O APIC.
+const MP_IO_APIC: u8 = 2;
+/// The entry type of an interrupt routed to an I/O APIC.
+const MP_IO_INTERRUPT: u8 = 3;
+/// The entry type of an interrupt routed to the local APICs.
+const MP_LOCAL_INTERRUPT: u8 = 4;
+
+/// The flag of a processor or I/O APIC entry that marks it as usable.
+const MP_ENABLED: u8 = 1 << 0;
+/// The flag of a processor entry that marks it as the bootstrap processor.
+const MP_BOOTSTRAP: u8 = 1 << 1;
+/// The vectored interrupt type.
+const MP_INT: u8 = 0;
+/// The non-maskable interrupt type.
+const MP_NMI: u8 = 1;
+/// The interrupt type of an interrupt routed through an external 8259A-compatible controller.
+const MP_EXTINT: u8 = 3;
+/// The local APIC ID that refers to all local APICs.
+const MP_ALL_APICS: u8 = 0xff;
+
+/// The MP floating pointer structure.
+#[allow(dead_code)]
+#[derive(Clone, Copy)]
+#[repr(C, packed)]
+struct FloatingPointer {
+    signature: [u8; 4],
+    config_table: Le<u32>,
+    length: u8,
+    revision: u8,
+    checksum: u8,
+    features: [u8; 5],
+}
+
+unsafe impl AsBytes for FloatingPointer {}
+
+/// The header of the MP configuration table.
+#[allow(dead_code)]
+#[derive(Clone, Copy)]
+#[repr(C, packed)]
+struct ConfigHeader {
+    signature: [u8; 4],
+    length: Le<u16>,
+    revision: u8,
+    checksum: u8,
+    oem_id: [u8; 8],
+    product_id: [u8; 12],
+    oem_table: Le<u32>,
+    oem_table_size: Le<u16>,
+    entry_count: Le<u16>,
+    local_apic: Le<u32>,
+    extended_length: Le<u16>,
+    extended_checksum: u8,
+    reserved: u8,
+}
+
+unsafe impl AsBytes for ConfigHeader {}
+
+/// A processor entry of the MP configuration table.
+#[allow(dead_code)]
+#[derive(Clone, Copy)]
+#[repr(C, packed)]
+struct ProcessorEntry {
+    kind: u8,
+    apic_id: u8,
+    apic_version: u8,
+    flags: u8,
+    signature: Le<u32>,
+    features: Le<u32>,
+    reserved: [u8; 8],
+}
+
+unsafe impl AsBytes for ProcessorEntry {}
+
+/// A bus entry of the MP configuration table.
+#[allow(dead_code)]
+#[derive(Clone, Copy)]
+#[repr(C, packed)]
+struct BusEntry {
+    kind: u8,
+    bus_id: u8,
+    bus_type: [u8; 6],
+}
+
+unsafe impl AsBytes for BusEntry {}
+
+/// An I/O APIC entry of the MP configuration table.
+#[allow(dead_code)]
+#[derive(Clone, Copy)]
+#[repr(C, packed)]
+struct IoApicEntry {
+    kind: u8,
+    apic_id: u8,
+    apic_version: u8,
+    flags: u8,
+    address: Le<u32>,
+}
+
+unsafe impl AsBytes for IoApicEntry {}
+
+/// An I/O interrupt or local interrupt entry of the MP configuration table.
+#[allow(dead_code)]
+#[derive(Clone, Copy)]
+#[repr(C, packed)]
+struct InterruptEntry {
+    kind: u8,
+    interrupt_type: u8,
+    flags: Le<u16>,
+    bus_id: u8,
+    bus_irq: u8,
+    apic_id: u8,
+    apic_pin: u8,
+}
+
+unsafe impl AsBytes for InterruptEntry {}
+
+/// Writes the MP floating pointer structure and the MP configuration table for the given number
+/// of virtual CPUs into the guest physical memory of the given VM, where the virtual CPUs have
+/// the local APIC IDs `0..vcpu_count` and virtual CPU 0 is the bootstrap processor. The ISA
+/// interrupts are routed to the pins of the same number of the I/O APIC. Returns the guest
+/// physical address range of the tables.
+///
+/// Returns [`Error::InvalidArgument`] if the number of virtual CPUs is zero or does not leave a
+/// local APIC ID for the I/O APIC, or [`Error::UnmappedGuestAddress`] if the tables do not fit in
+/// the guest physical memory of the VM.
+pub fn write_mptable(vm: &mut Vm, vcpu_count: usize) -> Result<Range<u64>, Error> {
+    let (pointer, config_table) = build(vcpu_count)?;
+    let config_address = MPTABLE_ADDRESS + std::mem::size_of::<FloatingPointer>() as u64;
+
+    vm.write_obj(MPTABLE_ADDRESS, &pointer)?;
+    vm.write_all_physical_memory(config_address, &config_table)?;
+
+    Ok(MPTABLE_ADDRESS..config_address + config_table.len() as u64)
+}
+
+/// Helper function to build the MP floating pointer structure and the MP configuration table for
+/// the given number of virtual CPUs.
+fn build(vcpu_count: usize) -> Result<(FloatingPointer, Vec<u8>), Error> {
+    if vcpu_count == 0 || vcpu_count >= MP_ALL_APICS as usize {
+        return Err(Error::InvalidArgument);
+    }
+
+    let io_apic_id = vcpu_count as u8;
+    let mut entries = vec![];
+    let mut entry_count = 0u16;
+
+    for apic_id in 0..io_apic_id {
+        let flags = match apic_id {
+            0 => MP_ENABLED | MP_BOOTSTRAP,
+            _ => MP_ENABLED,
+        };
+
+        entries.extend_from_slice(bytes::as_bytes(&ProcessorEntry {
+            kind: MP_PROCESSOR,
+            apic_id,
+            apic_version: LOCAL_APIC_VERSION,
+            flags,
+            signature: Le::new(CPU_SIGNATURE),
+            features: Le::new(CPU_FEATURES),
+            reserved: [0; 8],
+        }));
+        entry_count += 1;
+    }
+
+    entries.extend_from_slice(bytes::as_bytes(&BusEntry {
+        kind: MP_BUS,
+        bus_id: 0,
+        bus_type: *b"ISA   ",
+    }));
+    entries.extend_from_slice(bytes::as_bytes(&IoApicEntry {
+        kind: MP_IO_APIC,
+        apic_id: io_apic_id,
+        apic_version: IO_APIC_VERSION,
+        flags: MP_ENABLED,
+        address: Le::new(IO_APIC_ADDRESS),
+    }));
+    entry_count += 2;
+
+    for irq in 0..ISA_IRQ_COUNT {
+        entries.extend_from_slice(bytes::as_bytes(&InterruptEntry {
+            kind: MP_IO_INTERRUPT,
+            interrupt_type: MP_INT,
+            flags: Le::new(0),
+            bus_id: 0,
+            bus_irq: irq,
+            apic_id: io_apic_id,
+            apic_pin: irq,
+        }));
+        entry_count += 1;
+    }
+
+    // Route the external interrupt controller to LINT0 and NMIs to LINT1 of all local APICs.
+    for (pin, &interrupt_type) in [MP_EXTINT, MP_NMI].iter().enumerate() {
+        entries.extend_from_slice(bytes::as_bytes(&InterruptEntry {
+            kind: MP_LOCAL_INTERRUPT,
+            interrupt_type,
+            flags: Le::new(0),
+            bus_id: 0,
+            bus_irq: 0,
+            apic_id: MP_ALL_APICS,
+            apic_pin: pin as u8,
+        }));
+        entry_count += 1;
+    }
+
+    let config_address = MPTABLE_ADDRESS + std::mem::size_of::<FloatingPointer>() as u64;
+    let config_length = std::mem::size_of::<ConfigHeader>() + entries.len();
+
+    let header = ConfigHeader {
+        signature: *b"PCMP",
+        length: Le::new(config_length as u16),
+        revision: MP_REVISION,
+        checksum: 0,
+        oem_id: *b"HY-RS   ",
+        product_id: *b"VIRTUAL     ",
+        oem_table: Le::new(0),
+        oem_table_size: Le::new(0),
+        entry_count: Le::new(entry_count),
+        local_apic: Le::new(LOCAL_APIC_ADDRESS),
+        extended_length: Le::new(0),
+        extended_checksum: 0,
+        reserved: 0,
+    };
+
+    let mut config_table = bytes::as_bytes(&header).to_vec();
+
+    // The checksum covers the header and the entries, and is stored at offset 7 of the header.
+    config_table.extend_from_slice(&entries);
+    config_table[7] = checksum(&config_table);
+
+    let mut pointer = FloatingPointer {
+        signature: *b"_MP_",
+        config_table: Le::new(config_address as u32),
+        length: (std::mem::size_of::<FloatingPointer>() / 16) as u8,
+        revision: MP_REVISION,
+        checksum: 0,
+        features: [0; 5],
+    };
+
+    pointer.checksum = checksum(bytes::as_bytes(&pointer));
+
+    Ok((pointer, config_table))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Helper function to sum up the given bytes, which is zero for a valid checksum.
+    fn sum(bytes: &[u8]) -> u8 {
+        bytes.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte))
+    }
+
+    #[test]
+    fn tables() {
+        let (pointer, config_table) = build(4).unwrap();
+        let pointer = bytes::as_bytes(&pointer);
+
+        assert_eq!(&pointer[0..4], b"_MP_");
+        assert_eq!(pointer[8] as usize * 16, pointer.len());
+        assert_eq!(sum(pointer), 0);
+        assert_eq!(pointer[4..8], ((MPTABLE_ADDRESS + 16) as u32).to_le_bytes());
+
+        assert_eq!(&config_table[0..4], b"PCMP");
+        assert_eq!(config_table[4..6], (config_table.len() as u16).to_le_bytes());
+        assert_eq!(sum(&config_table), 0);
+
+        // Walk the entries, where processor entries are 20 bytes and all others 8 bytes.
+        let entry_count = u16::from_le_bytes([config_table[34], config_table[35]]);
+        let mut offset = std::mem::size_of::<ConfigHeader>();
+        let mut processors = vec![];
+
+        for _ in 0..entry_count {
+            match config_table[offset] {
+                MP_PROCESSOR => {
+                    processors.push((config_table[offset + 1], config_table[offset + 3]));
+                    offset += 20;
+                }
+                MP_BUS..=MP_LOCAL_INTERRUPT => offset += 8,
+                kind => panic!("invalid entry type {}", kind),
+            }
+        }
+
+        assert_eq!(offset, config_table.len());
+        assert_eq!(processors, [
+            (0, MP_ENABLED | MP_BOOTSTRAP),
+            (1, MP_ENABLED),
+            (2, MP_ENABLED),
+            (3, MP_ENABLED),
+        ]);
+    }
+
+    #[test]
+    fn invalid_vcpu_count() {
+        assert!(matches!(build(0), Err(Error::InvalidArgument)));
+        assert!(matches!(build(MP_ALL_APICS as usize), Err(Error::InvalidArgument)));
+        assert!(build(MP_ALL_APICS as usize - 1).is_ok());
+    }
+}
@@ -0,0 +1,110 @@
+//! This module provides a minimal parser for the headers and section tables of PE32 and PE32+
+//! images, which is used by [`super::load_pe`].
+
+use crate::error::Error;
+use std::convert::TryInto;
+
+/// The magic of the optional header of a PE32 image.
+const PE32_MAGIC: u16 = 0x10b;
+/// The magic of the optional header of a PE32+ image.
+const PE32_PLUS_MAGIC: u16 = 0x20b;
+
+/// A section header of a PE image.
+pub(crate) struct PeSection {
+    pub(crate) virtual_size: u32,
+    pub(crate) virtual_address: u32,
+    pub(crate) raw_size: u32,
+    pub(crate) raw_offset: u32,
+    pub(crate) characteristics: u32,
+}
+
+/// A minimal parser for the headers and section tables of PE images.
+pub(crate) struct Pe<'a> {
+    bytes: &'a [u8],
+    pub(crate) image_base: u64,
+    pub(crate) entry: u32,
+    pub(crate) header_size: u64,
+    pub(crate) section_offset: u64,
+    pub(crate) section_count: usize,
+}
+
+impl<'a> Pe<'a> {
+    pub(crate) fn parse(bytes: &'a [u8]) -> Result<Self, Error> {
+        if bytes.len() < 0x40 || &bytes[0..2] != b"MZ" {
+            return Err(Error::InvalidArgument);
+        }
+
+        let mut pe = Self {
+            bytes,
+            image_base: 0,
+            entry: 0,
+            header_size: 0,
+            section_offset: 0,
+            section_count: 0,
+        };
+
+        let base = pe.read_u32(0x3c)? as u64;
+
+        if pe.read::<4>(base)? != *b"PE\0\0" {
+            return Err(Error::InvalidArgument);
+        }
+
+        let optional_header = base + 0x18;
+
+        pe.section_count = pe.read_u16(base + 0x06)? as usize;
+        pe.section_offset = optional_header + pe.read_u16(base + 0x14)? as u64;
+        pe.entry = pe.read_u32(optional_header + 0x10)?;
+
+        pe.image_base = match pe.read_u16(optional_header)? {
+            PE32_MAGIC => pe.read_u32(optional_header + 0x1c)? as u64,
+            PE32_PLUS_MAGIC => pe.read_u64(optional_header + 0x18)?,
+            _ => return Err(Error::InvalidArgument),
+        };
+
+        // The headers are padded to the file alignment, so they may extend past a tiny image.
+        pe.header_size = (pe.read_u32(optional_header + 0x3c)? as u64).min(bytes.len() as u64);
+
+        Ok(pe)
+    }
+
+    fn read<const N: usize>(&self, offset: u64) -> Result<[u8; N], Error> {
+        let offset = offset as usize;
+
+        self.bytes
+            .get(offset..offset + N)
+            .and_then(|bytes| bytes.try_into().ok())
+            .ok_or(Error::InvalidArgument)
+    }
+
+    fn read_u16(&self, offset: u64) -> Result<u16, Error> {
+        Ok(u16::from_le_bytes(self.read(offset)?))
+    }
+
+    fn read_u32(&self, offset: u64) -> Result<u32, Error> {
+        Ok(u32::from_le_bytes(self.read(offset)?))
+    }
+
+    fn read_u64(&self, offset: u64) -> Result<u64, Error> {
+        Ok(u64::from_le_bytes(self.read(offset)?))
+    }
+
+    pub(crate) fn section(&self, index: usize) -> Result<PeSection, Error> {
+        let base = self.section_offset + index as u64 * 0x28;
+
+        Ok(PeSection {
+            virtual_size: self.read_u32(base + 0x08)?,
+            virtual_address: self.read_u32(base + 0x0c)?,
+            raw_size: self.read_u32(base + 0x10)?,
+            raw_offset: self.read_u32(base + 0x14)?,
+            characteristics: self.read_u32(base + 0x24)?,
+        })
+    }
+
+    /// Returns the given number of bytes at the given offset in the file.
+    pub(crate) fn bytes(&self, offset: u64, size: u64) -> Result<&'a [u8], Error> {
+        let start = offset as usize;
+        let end = start.checked_add(size as usize).ok_or(Error::InvalidArgument)?;
+
+        self.bytes.get(start..end).ok_or(Error::InvalidArgument)
+    }
+}
@@ -0,0 +1,331 @@
+//! This module provides a loader for ELF kernels that support the PVH boot protocol on x86, see
+//! [`load_pvh`]. This includes Linux kernels built with `CONFIG_PVH`, i.e. their `vmlinux` image,
+//! as well as FreeBSD and various unikernels. The kernel advertises the 32-bit entry point
+//! through the `XEN_ELFNOTE_PHYS32_ENTRY` ELF note, where it is entered in 32-bit protected mode
+//! without paging and with a pointer to the `hvm_start_info` structure describing the boot
+//! modules and the memory map. Unlike the bzImage format, this does not require any firmware or
+//! decompression stage, which makes it the fastest way to boot a kernel.
+//!
+//! The loader places the boot data at fixed guest physical addresses in the first MiB and the
+//! kernel at the physical addresses of its segments, so the VM must have guest physical memory
+//! mapped there. The memory map passed to the kernel is derived from the regions of the VM, see
+//! [`crate::Vm::regions`].
+
+use crate::arch::x86_64::{
+    CpuRegs, DescriptorTableRegister, GdtBuilder, ProtectedModeLayout, Register, Segment,
+    SegmentRegister,
+};
+use crate::bytes::{AsBytes, Le};
+use crate::elf::Elf;
+use crate::error::Error;
+use crate::vcpu::Vcpu;
+use crate::vm::{ProtectionFlags, Vm};
+use std::convert::TryInto;
+use std::ffi::CString;
+use std::ops::Range;
+
+/// The guest physical address of the GDT.
+const GDT_ADDRESS: u64 = 0x500;
+/// The guest physical address of the start info.
+const START_INFO_ADDRESS: u64 = 0x6000;
+/// The guest physical address of the module list.
+const MODLIST_ADDRESS: u64 = 0x6040;
+/// The guest physical address of the memory map.
+const MEMMAP_ADDRESS: u64 = 0x7000;
+/// The initial stack pointer, which is unused by the kernel until it sets up its own stack.
+const STACK_ADDRESS: u64 = 0x8ff0;
+/// The guest physical address of the kernel command line.
+const CMDLINE_ADDRESS: u64 = 0x20000;
+
+/// The maximum number of entries of the memory map.
+const MEMMAP_MAX_ENTRIES: usize = 128;
+/// The memory map type of usable memory.
+const MEMMAP_TYPE_RAM: u32 = 1;
+/// The memory map type of reserved memory.
+const MEMMAP_TYPE_RESERVED: u32 = 2;
+
+/// The magic number of the start info, i.e. `xEn3` with the top bit of each byte set.
+const START_INFO_MAGIC: u32 = 0x336e_c578;
+/// The version of the start info that includes the memory map.
+const START_INFO_VERSION: u32 = 1;
+
+/// The ELF program header type of a loadable segment.
+const PT_LOAD: u32 = 1;
+/// The ELF program header type of a segment holding notes.
+const PT_NOTE: u32 = 4;
+/// The type of the Xen ELF note holding the 32-bit entry point of the PVH boot protocol.
+const XEN_ELFNOTE_PHYS32_ENTRY: u32 = 18;
+/// The name of the Xen ELF notes.
+const XEN_ELFNOTE_NAME: &[u8] = b"Xen\0";
+/// The type of a busy 32-bit TSS, which the task register must refer to on VM entry.
+const SEGMENT_TYPE_BUSY_TSS: u8 = 0xb;
+/// The limit of a TSS without an I/O permission bitmap.
+const TSS_LIMIT: u32 = 0x67;
+
+/// The start info describing the boot modules and the memory map, i.e. `hvm_start_info`.
+#[allow(dead_code)]
+#[derive(Clone, Copy)]
+#[repr(C)]
+struct StartInfo {
+    magic: Le<u32>,
+    version: Le<u32>,
+    flags: Le<u32>,
+    module_count: Le<u32>,
+    modlist_address: Le<u64>,
+    cmdline_address: Le<u64>,
+    rsdp_address: Le<u64>,
+    memmap_address: Le<u64>,
+    memmap_entries: Le<u32>,
+    reserved: Le<u32>,
+}
+
+unsafe impl AsBytes for StartInfo {}
+
+/// An entry of the module list, i.e. `hvm_modlist_entry`.
+#[allow(dead_code)]
+#[derive(Clone, Copy)]
+#[repr(C)]
+struct ModlistEntry {
+    address: Le<u64>,
+    size: Le<u64>,
+    cmdline_address: Le<u64>,
+    reserved: Le<u64>,
+}
+
+unsafe impl AsBytes for ModlistEntry {}
+
+/// An entry of the memory map, i.e. `hvm_memmap_table_entry`.
+#[allow(dead_code)]
+#[derive(Clone, Copy)]
+#[repr(C)]
+struct MemmapEntry {
+    address: Le<u64>,
+    size: Le<u64>,
+    kind: Le<u32>,
+    reserved: Le<u32>,
+}
+
+unsafe impl AsBytes for MemmapEntry {}
+
+/// Describes a kernel that has been loaded into guest physical memory, as returned by
+/// [`load_pvh`].
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct PvhBootInfo {
+    /// The guest physical address of the 32-bit entry point of the kernel.
+    pub entry: u64,
+    /// The guest physical address of the start info.
+    pub start_info: u64,
+    /// The guest physical address range of the initial RAM disk, if any.
+    pub initrd: Option<Range<u64>>,
+}
+
+impl PvhBootInfo {
+    /// Sets up the given virtual CPU to enter the kernel through the PVH boot protocol, i.e. in
+    /// 32-bit protected mode without paging, with flat code and data segments, with the task
+    /// register referring to a 32-bit TSS and with `ebx` pointing to the start info. This should
+    /// only be called for the bootstrap processor, as the kernel starts the other virtual CPUs
+    /// itself.
+    pub fn setup_vcpu(&self, vm: &mut Vm, vcpu: &mut Vcpu) -> Result<(), Error> {
+        let layout = ProtectedModeLayout {
+            gdt: GDT_ADDRESS,
+            entry: self.entry,
+            stack: STACK_ADDRESS,
+        };
+
+        vcpu.enter_protected_mode(vm, &layout)?;
+
+        // The boot protocol expects the task register to refer to a 32-bit TSS, so extend the
+        // GDT with a TSS descriptor.
+        let mut builder = GdtBuilder::new();
+
+        builder.add_segment(&Segment::protected_flat_code(0));
+        builder.add_segment(&Segment::protected_flat_data(0));
+        let tss = builder.add_segment(&Segment {
+            limit: TSS_LIMIT,
+            segment_type: SEGMENT_TYPE_BUSY_TSS,
+            present: true,
+            ..Default::default()
+        });
+
+        let table = builder.write(vm, GDT_ADDRESS)?;
+
+        vcpu.set_descriptor_tables(&[DescriptorTableRegister::Gdt], &[table])?;
+        vcpu.set_segment_registers(&[SegmentRegister::Tr], &[tss])?;
+
+        vcpu.set_registers(&[Register::Rbx], &[self.start_info])
+    }
+}
+
+/// Loads the given ELF kernel supporting the PVH boot protocol into the guest physical memory of
+/// the given VM, along with the given initial RAM disk and kernel command line. This writes the
+/// loadable segments of the kernel, the initial RAM disk, the command line, the start info and
+/// the memory map to guest physical memory. Use [`PvhBootInfo::setup_vcpu`] to set up the
+/// bootstrap processor to enter the kernel afterwards.
+///
+/// The initial RAM disk is placed at the end of the highest region of writable guest physical
+/// memory below 4 GiB, which must not overlap with the kernel. Returns
+/// [`Error::InvalidArgument`] if the kernel is not an ELF image with the
+/// `XEN_ELFNOTE_PHYS32_ENTRY` note, if the command line contains a null byte, or if there is no
+/// room for the initial RAM disk. Returns [`Error::UnmappedGuestAddress`] if the guest physical
+/// memory at the addresses used by the loader is not mapped.
+pub fn load_pvh(
+    vm: &mut Vm,
+    kernel: &[u8],
+    initrd: Option<&[u8]>,
+    cmdline: &str,
+) -> Result<PvhBootInfo, Error> {
+    let elf = Elf::parse(kernel)?;
+    let entry = phys32_entry(&elf)?;
+    let cmdline = CString::new(cmdline).map_err(|_| Error::InvalidArgument)?;
+
+    // Derive the memory map from the regions of the VM.
+    let regions: Vec<_> = vm.regions()?.collect();
+
+    if regions.len() > MEMMAP_MAX_ENTRIES {
+        return Err(Error::InvalidArgument);
+    }
+
+    // Load the kernel, where the part of the segments that is not in the file is zeroed.
+    let mut kernel_end = 0;
+
+    for index in 0..elf.segment_count {
+        let segment = elf.segment(index)?;
+
+        if segment.kind != PT_LOAD || segment.memory_size == 0 {
+            continue;
+        }
+
+        if segment.file_size > segment.memory_size {
+            return Err(Error::InvalidArgument);
+        }
+
+        let data = elf.segment_bytes(&segment)?;
+        let zeroes = vec![0u8; (segment.memory_size - segment.file_size) as usize];
+
+        vm.write_all_physical_memory(segment.physical_address, data)?;
+        vm.write_all_physical_memory(segment.physical_address + segment.file_size, &zeroes)?;
+
+        kernel_end = kernel_end.max(segment.physical_address + segment.memory_size);
+    }
+
+    // Place the initial RAM disk as high as possible in writable guest physical memory.
+    let initrd_range = match initrd {
+        Some(initrd) => {
+            let size = initrd.len() as u64;
+
+            let start = regions
+                .iter()
+                .rev()
+                .filter(|(_, protection, _)| protection.contains(ProtectionFlags::WRITE))
+                .find_map(|(range, _, _)| {
+                    let end = range.end.min(1 << 32);
+                    let start = end.checked_sub(size)? & !0xfff;
+
+                    if start >= range.start && start >= kernel_end {
+                        Some(start)
+                    } else {
+                        None
+                    }
+                })
+                .ok_or(Error::InvalidArgument)?;
+
+            vm.write_all_physical_memory(start, initrd)?;
+
+            Some(start..start + size)
+        }
+        _ => None,
+    };
+
+    vm.write_all_physical_memory(CMDLINE_ADDRESS, cmdline.as_bytes_with_nul())?;
+
+    for (index, (range, protection, _)) in regions.iter().enumerate() {
+        let kind = if protection.contains(ProtectionFlags::WRITE) {
+            MEMMAP_TYPE_RAM
+        } else {
+            MEMMAP_TYPE_RESERVED
+        };
+
+        let entry = MemmapEntry {
+            address: Le::new(range.start),
+            size: Le::new(range.end - range.start),
+            kind: Le::new(kind),
+            reserved: Le::new(0),
+        };
+
+        vm.write_obj(MEMMAP_ADDRESS + (index * std::mem::size_of::<MemmapEntry>()) as u64, &entry)?;
+    }
+
+    let module_count = match &initrd_range {
+        Some(range) => {
+            vm.write_obj(MODLIST_ADDRESS, &ModlistEntry {
+                address: Le::new(range.start),
+                size: Le::new(range.end - range.start),
+                cmdline_address: Le::new(0),
+                reserved: Le::new(0),
+            })?;
+
+            1
+        }
+        _ => 0,
+    };
+
+    vm.write_obj(START_INFO_ADDRESS, &StartInfo {
+        magic: Le::new(START_INFO_MAGIC),
+        version: Le::new(START_INFO_VERSION),
+        flags: Le::new(0),
+        module_count: Le::new(module_count),
+        modlist_address: Le::new(if module_count > 0 { MODLIST_ADDRESS } else { 0 }),
+        cmdline_address: Le::new(CMDLINE_ADDRESS),
+        rsdp_address: Le::new(0),
+        memmap_address: Le::new(MEMMAP_ADDRESS),
+        memmap_entries: Le::new(regions.len() as u32),
+        reserved: Le::new(0),
+    })?;
+
+    Ok(PvhBootInfo {
+        entry,
+        start_info: START_INFO_ADDRESS,
+        initrd: initrd_range,
+    })
+}
+
+/// Helper function to look up the 32-bit entry point in the `XEN_ELFNOTE_PHYS32_ENTRY` note of
+/// the given ELF image. Returns [`Error::InvalidArgument`] if the note is missing.
+fn phys32_entry(elf: &Elf) -> Result<u64, Error> {
+    for index in 0..elf.segment_count {
+        let segment = elf.segment(index)?;
+
+        if segment.kind != PT_NOTE {
+            continue;
+        }
+
+        let mut notes = elf.segment_bytes(&segment)?;
+
+        // Every note consists of the sizes of the name and the descriptor and the type, followed
+        // by the name and the descriptor, which are both padded to four bytes.
+        while notes.len() >= 12 {
+            let name_size = u32::from_le_bytes(notes[0..4].try_into().unwrap()) as usize;
+            let desc_size = u32::from_le_bytes(notes[4..8].try_into().unwrap()) as usize;
+            let kind = u32::from_le_bytes(notes[8..12].try_into().unwrap());
+
+            let desc_start = 12 + ((name_size + 3) & !3);
+            let desc_end = desc_start + desc_size;
+
+            let name = notes.get(12..12 + name_size).ok_or(Error::InvalidArgument)?;
+            let desc = notes.get(desc_start..desc_end).ok_or(Error::InvalidArgument)?;
+
+            if name == XEN_ELFNOTE_NAME && kind == XEN_ELFNOTE_PHYS32_ENTRY {
+                // The entry point is stored as a 32-bit or a 64-bit value.
+                return match desc.len() {
+                    4 => Ok(u32::from_le_bytes(desc.try_into().unwrap()) as u64),
+                    8 => Ok(u64::from_le_bytes(desc.try_into().unwrap())),
+                    _ => Err(Error::InvalidArgument),
+                };
+            }
+
+            notes = notes.get((desc_end + 3) & !3..).unwrap_or(&[]);
+        }
+    }
+
+    Err(Error::InvalidArgument)
+}
@@ -0,0 +1,515 @@
+//! This module provides a generator for a minimal SMBIOS table on x86, see [`write_smbios`]. The
+//! guest uses the SMBIOS table to identify the system, and older guests also use it to determine
+//! the number of processors and the amount of memory installed.
+//!
+//! The SMBIOS 2.8 entry point is placed at the start of the legacy BIOS area at 0xf0000, where
+//! the guest scans for it, followed by the structure table. The VM must have guest physical
+//! memory mapped there.
+
+use crate::bytes::{self, AsBytes, Le};
+use crate::error::Error;
+use crate::vm::{ProtectionFlags, Vm};
+use std::ops::Range;
+use super::checksum;
+
+/// The guest physical address of the SMBIOS entry point.
+const SMBIOS_ADDRESS: u64 = 0xf_0000;
+/// The guest physical address of the structure table.
+const STRUCTURE_TABLE_ADDRESS: u64 = 0xf_0020;
+/// The guest physical address at which the legacy BIOS area ends.
+const BIOS_AREA_END: u64 = 0x10_0000;
+/// The major version of the SMBIOS specification.
+const SMBIOS_MAJOR: u8 = 2;
+/// The minor version of the SMBIOS specification.
+const SMBIOS_MINOR: u8 = 8;
+/// The vendor, manufacturer and product name reported by the structures.
+const VENDOR: &str = "hy-rs";
+
+/// The structure type of the BIOS information.
+const SMBIOS_BIOS_INFORMATION: u8 = 0;
+/// The structure type of the system information.
+const SMBIOS_SYSTEM_INFORMATION: u8 = 1;
+/// The structure type of the processor information.
+const SMBIOS_PROCESSOR_INFORMATION: u8 = 4;
+/// The structure type of the physical memory array.
+const SMBIOS_MEMORY_ARRAY: u8 = 16;
+/// The structure type of the memory device.
+const SMBIOS_MEMORY_DEVICE: u8 = 17;
+/// The structure type of the memory array mapped address.
+const SMBIOS_MEMORY_ARRAY_MAPPED_ADDRESS: u8 = 19;
+/// The structure type that marks the end of the structure table.
+const SMBIOS_END_OF_TABLE: u8 = 127;
+
+/// The handle that indicates that no error information structure is provided.
+const NO_ERROR_INFORMATION: u16 = 0xfffe;
+/// The handle that indicates that no cache information structure is provided.
+const NO_CACHE_INFORMATION: u16 = 0xffff;
+
+/// The SMBIOS 2.x entry point structure.
+#[allow(dead_code)]
+#[derive(Clone, Copy)]
+#[repr(C, packed)]
+struct EntryPoint {
+    anchor: [u8; 4],
+    checksum: u8,
+    length: u8,
+    major: u8,
+    minor: u8,
+    max_structure_size: Le<u16>,
+    revision: u8,
+    formatted_area: [u8; 5],
+    intermediate_anchor: [u8; 5],
+    intermediate_checksum: u8,
+    table_length: Le<u16>,
+    table_address: Le<u32>,
+    structure_count: Le<u16>,
+    bcd_revision: u8,
+}
+
+unsafe impl AsBytes for EntryPoint {}
+
+/// The BIOS information (type 0) without the structure header.
+#[allow(dead_code)]
+#[derive(Clone, Copy)]
+#[repr(C, packed)]
+struct BiosInformation {
+    vendor: u8,
+    version: u8,
+    start_segment: Le<u16>,
+    release_date: u8,
+    rom_size: u8,
+    characteristics: Le<u64>,
+    characteristics_extension: [u8; 2],
+    major: u8,
+    minor: u8,
+    controller_major: u8,
+    controller_minor: u8,
+}
+
+unsafe impl AsBytes for BiosInformation {}
+
+/// The system information (type 1) without the structure header.
+#[allow(dead_code)]
+#[derive(Clone, Copy)]
+#[repr(C, packed)]
+struct SystemInformation {
+    manufacturer: u8,
+    product: u8,
+    version: u8,
+    serial: u8,
+    uuid: [u8; 16],
+    wake_up_type: u8,
+    sku: u8,
+    family: u8,
+}
+
+unsafe impl AsBytes for SystemInformation {}
+
+/// The processor information (type 4) without the structure header.
+#[allow(dead_code)]
+#[derive(Clone, Copy)]
+#[repr(C, packed)]
+struct ProcessorInformation {
+    socket: u8,
+    processor_type: u8,
+    family: u8,
+    manufacturer: u8,
+    id: Le<u64>,
+    version: u8,
+    voltage: u8,
+    external_clock: Le<u16>,
+    max_speed: Le<u16>,
+    current_speed: Le<u16>,
+    status: u8,
+    upgrade: u8,
+    l1_cache: Le<u16>,
+    l2_cache: Le<u16>,
+    l3_cache: Le<u16>,
+    serial: u8,
+    asset_tag: u8,
+    part_number: u8,
+    core_count: u8,
+    cores_enabled: u8,
+    thread_count: u8,
+    characteristics: Le<u16>,
+    family2: Le<u16>,
+}
+
+unsafe impl AsBytes for ProcessorInformation {}
+
+/// The physical memory array (type 16) without the structure header.
+#[allow(dead_code)]
+#[derive(Clone, Copy)]
+#[repr(C, packed)]
+struct MemoryArray {
+    location: u8,
+    usage: u8,
+    error_correction: u8,
+    max_capacity: Le<u32>,
+    error_information: Le<u16>,
+    device_count: Le<u16>,
+    extended_max_capacity: Le<u64>,
+}
+
+unsafe impl AsBytes for MemoryArray {}
+
+/// The memory device (type 17) without the structure header.
+#[allow(dead_code)]
+#[derive(Clone, Copy)]
+#[repr(C, packed)]
+struct MemoryDevice {
+    array: Le<u16>,
+    error_information: Le<u16>,
+    total_width: Le<u16>,
+    data_width: Le<u16>,
+    size: Le<u16>,
+    form_factor: u8,
+    device_set: u8,
+    device_locator: u8,
+    bank_locator: u8,
+    memory_type: u8,
+    type_detail: Le<u16>,
+    speed: Le<u16>,
+    manufacturer: u8,
+    serial: u8,
+    asset_tag: u8,
+    part_number: u8,
+    attributes: u8,
+    extended_size: Le<u32>,
+    configured_speed: Le<u16>,
+    min_voltage: Le<u16>,
+    max_voltage: Le<u16>,
+    configured_voltage: Le<u16>,
+}
+
+unsafe impl AsBytes for MemoryDevice {}
+
+/// The memory array mapped address (type 19) without the structure header.
+#[allow(dead_code)]
+#[derive(Clone, Copy)]
+#[repr(C, packed)]
+struct MemoryArrayMappedAddress {
+    start: Le<u32>,
+    end: Le<u32>,
+    array: Le<u16>,
+    partition_width: u8,
+    extended_start: Le<u64>,
+    extended_end: Le<u64>,
+}
+
+unsafe impl AsBytes for MemoryArrayMappedAddress {}
+
+/// Helper struct to build the structure table.
+#[derive(Default)]
+struct StructureTable {
+    bytes: Vec<u8>,
+    count: u16,
+    max_size: usize,
+}
+
+impl StructureTable {
+    /// Appends the structure of the given type with the given formatted section and strings,
+    /// where the strings are referred to by their index starting at 1. Returns the handle of the
+    /// structure.
+    fn push<T: AsBytes>(&mut self, kind: u8, structure: &T, strings: &[&str]) -> u16 {
+        let handle = self.count;
+        let start = self.bytes.len();
+
+        self.bytes.push(kind);
+        self.bytes.push((4 + std::mem::size_of::<T>()) as u8);
+        self.bytes.extend_from_slice(&handle.to_le_bytes());
+        self.bytes.extend_from_slice(bytes::as_bytes(structure));
+
+        for string in strings {
+            self.bytes.extend_from_slice(string.as_bytes());
+            self.bytes.push(0);
+        }
+
+        // The strings are terminated by an additional null byte, and the string set by two null
+        // bytes if it is empty.
+        if strings.is_empty() {
+            self.bytes.push(0);
+        }
+
+        self.bytes.push(0);
+        self.count += 1;
+        self.max_size = self.max_size.max(self.bytes.len() - start);
+
+        handle
+    }
+}
+
+/// Writes an SMBIOS entry point and a minimal structure table describing a system with the given
+/// number of virtual CPUs into the guest physical memory of the given VM, where the memory size
+/// is derived from the writable regions of the VM, see [`crate::Vm::regions`]. Returns the guest
+/// physical address range of the entry point and the structure table.
+///
+/// Returns [`Error::InvalidArgument`] if the number of virtual CPUs is zero or the structure
+/// table does not fit in the legacy BIOS area, or [`Error::UnmappedGuestAddress`] if the legacy
+/// BIOS area is not mapped.
+pub fn write_smbios(vm: &mut Vm, vcpu_count: usize) -> Result<Range<u64>, Error> {
+    let memory: Vec<Range<u64>> = vm
+        .regions()?
+        .filter(|(_, protection, _)| protection.contains(ProtectionFlags::WRITE))
+        .map(|(range, _, _)| range)
+        .collect();
+
+    let (entry_point, table) = build(vcpu_count, &memory)?;
+
+    vm.write_obj(SMBIOS_ADDRESS, &entry_point)?;
+    vm.write_all_physical_memory(STRUCTURE_TABLE_ADDRESS, &table)?;
+
+    Ok(SMBIOS_ADDRESS..STRUCTURE_TABLE_ADDRESS + table.len() as u64)
+}
+
+/// Helper function to build the SMBIOS entry point and the structure table describing the given
+/// number of virtual CPUs and the given memory.
+fn build(vcpu_count: usize, memory: &[Range<u64>]) -> Result<(EntryPoint, Vec<u8>), Error> {
+    if vcpu_count == 0 {
+        return Err(Error::InvalidArgument);
+    }
+
+    let memory_size: u64 = memory.iter().map(|range| range.end - range.start).sum();
+
+    let mut table = StructureTable::default();
+
+    table.push(SMBIOS_BIOS_INFORMATION, &BiosInformation {
+        vendor: 1,
+        version: 2,
+        start_segment: Le::new(0),
+        release_date: 0,
+        rom_size: 0,
+        // BIOS characteristics are not supported.
+        characteristics: Le::new(1 << 3),
+        // The system is a virtual machine.
+        characteristics_extension: [0, 1 << 4],
+        major: 0,
+        minor: 0,
+        controller_major: 0xff,
+        controller_minor: 0xff,
+    }, &[VENDOR, env!("CARGO_PKG_VERSION")]);
+
+    table.push(SMBIOS_SYSTEM_INFORMATION, &SystemInformation {
+        manufacturer: 1,
+        product: 2,
+        version: 0,
+        serial: 0,
+        uuid: [0; 16],
+        // The system was powered on through the power switch.
+        wake_up_type: 6,
+        sku: 0,
+        family: 0,
+    }, &[VENDOR, "Virtual Machine"]);
+
+    for index in 0..vcpu_count {
+        let socket = format!("CPU {}", index);
+
+        table.push(SMBIOS_PROCESSOR_INFORMATION, &ProcessorInformation {
+            socket: 1,
+            // A central processor of an unspecified family.
+            processor_type: 3,
+            family: 1,
+            manufacturer: 2,
+            id: Le::new(0),
+            version: 0,
+            voltage: 0,
+            external_clock: Le::new(0),
+            max_speed: Le::new(0),
+            current_speed: Le::new(0),
+            // The socket is populated and the processor is enabled.
+            status: 0x41,
+            upgrade: 1,
+            l1_cache: Le::new(NO_CACHE_INFORMATION),
+            l2_cache: Le::new(NO_CACHE_INFORMATION),
+            l3_cache: Le::new(NO_CACHE_INFORMATION),
+            serial: 0,
+            asset_tag: 0,
+            part_number: 0,
+            core_count: 1,
+            cores_enabled: 1,
+            thread_count: 1,
+            characteristics: Le::new(1 << 1),
+            family2: Le::new(1),
+        }, &[socket.as_str(), VENDOR]);
+    }
+
+    // The capacities are in KiB, unless they do not fit.
+    let memory_kib = memory_size >> 10;
+
+    let array = table.push(SMBIOS_MEMORY_ARRAY, &MemoryArray {
+        // The system memory is located on the system board and does not use error correction.
+        location: 3,
+        usage: 3,
+        error_correction: 3,
+        max_capacity: Le::new(memory_kib.min(0x8000_0000) as u32),
+        error_information: Le::new(NO_ERROR_INFORMATION),
+        device_count: Le::new(1),
+        extended_max_capacity: Le::new(if memory_kib >= 0x8000_0000 { memory_size } else { 0 }),
+    }, &[]);
+
+    // The size of the memory device is in MiB, unless it does not fit.
+    let memory_mib = memory_size >> 20;
+
+    table.push(SMBIOS_MEMORY_DEVICE, &MemoryDevice {
+        array: Le::new(array),
+        error_information: Le::new(NO_ERROR_INFORMATION),
+        total_width: Le::new(64),
+        data_width: Le::new(64),
+        size: Le::new(memory_mib.min(0x7fff) as u16),
+        // A DIMM of unspecified RAM.
+        form_factor: 9,
+        device_set: 0,
+        device_locator: 1,
+        bank_locator: 0,
+        memory_type: 7,
+        type_detail: Le::new(1 << 1),
+        speed: Le::new(0),
+        manufacturer: 2,
+        serial: 0,
+        asset_tag: 0,
+        part_number: 0,
+        attributes: 0,
+        extended_size: Le::new(if memory_mib >= 0x7fff { memory_mib as u32 } else { 0 }),
+        configured_speed: Le::new(0),
+        min_voltage: Le::new(0),
+        max_voltage: Le::new(0),
+        configured_voltage: Le::new(0),
+    }, &["DIMM 0", VENDOR]);
+
+    for range in memory {
+        // The addresses are in KiB and inclusive, unless they do not fit.
+        let start = range.start >> 10;
+        let end = (range.end >> 10).saturating_sub(1);
+        let extended = end >= 0xffff_ffff;
+
+        table.push(SMBIOS_MEMORY_ARRAY_MAPPED_ADDRESS, &MemoryArrayMappedAddress {
+            start: Le::new(if extended { 0xffff_ffff } else { start as u32 }),
+            end: Le::new(if extended { 0xffff_ffff } else { end as u32 }),
+            array: Le::new(array),
+            partition_width: 1,
+            extended_start: Le::new(if extended { range.start } else { 0 }),
+            extended_end: Le::new(if extended { range.end - 1 } else { 0 }),
+        }, &[]);
+    }
+
+    table.push(SMBIOS_END_OF_TABLE, &[0u8; 0], &[]);
+
+    if STRUCTURE_TABLE_ADDRESS + table.bytes.len() as u64 > BIOS_AREA_END {
+        return Err(Error::InvalidArgument);
+    }
+
+    let mut entry_point = EntryPoint {
+        anchor: *b"_SM_",
+        checksum: 0,
+        length: std::mem::size_of::<EntryPoint>() as u8,
+        major: SMBIOS_MAJOR,
+        minor: SMBIOS_MINOR,
+        max_structure_size: Le::new(table.max_size as u16),
+        revision: 0,
+        formatted_area: [0; 5],
+        intermediate_anchor: *b"_DMI_",
+        intermediate_checksum: 0,
+        table_length: Le::new(table.bytes.len() as u16),
+        table_address: Le::new(STRUCTURE_TABLE_ADDRESS as u32),
+        structure_count: Le::new(table.count),
+        bcd_revision: (SMBIOS_MAJOR << 4) | SMBIOS_MINOR,
+    };
+
+    // The intermediate checksum covers the intermediate entry point starting at the `_DMI_`
+    // anchor, whereas the checksum covers the whole entry point.
+    entry_point.intermediate_checksum = checksum(&bytes::as_bytes(&entry_point)[0x10..]);
+    entry_point.checksum = checksum(bytes::as_bytes(&entry_point));
+
+    Ok((entry_point, table.bytes))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Helper function to sum up the given bytes, which is zero for a valid checksum.
+    fn sum(bytes: &[u8]) -> u8 {
+        bytes.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte))
+    }
+
+    /// Helper function to walk the given structure table, returning the type and the size of
+    /// every structure including its strings.
+    fn structures(table: &[u8]) -> Vec<(u8, usize)> {
+        let mut structures = vec![];
+        let mut offset = 0;
+
+        while offset < table.len() {
+            let kind = table[offset];
+            let length = table[offset + 1] as usize;
+
+            // The string set ends with two null bytes.
+            let end = table[offset + length..]
+                .windows(2)
+                .position(|window| window == [0, 0])
+                .unwrap();
+
+            structures.push((kind, length + end + 2));
+            offset += length + end + 2;
+        }
+
+        structures
+    }
+
+    #[test]
+    fn tables() {
+        let (entry_point, table) = build(2, &[0..0xa_0000, 0x10_0000..0x8000_0000]).unwrap();
+        let entry_point = bytes::as_bytes(&entry_point);
+
+        assert_eq!(&entry_point[0..4], b"_SM_");
+        assert_eq!(entry_point[5] as usize, entry_point.len());
+        assert_eq!(sum(entry_point), 0);
+        assert_eq!(&entry_point[0x10..0x15], b"_DMI_");
+        assert_eq!(sum(&entry_point[0x10..]), 0);
+        assert_eq!(entry_point[0x16..0x18], (table.len() as u16).to_le_bytes());
+
+        let structures = structures(&table);
+        let kinds: Vec<u8> = structures.iter().map(|&(kind, _)| kind).collect();
+        let max_size = structures.iter().map(|&(_, size)| size).max().unwrap();
+
+        assert_eq!(kinds, [
+            SMBIOS_BIOS_INFORMATION,
+            SMBIOS_SYSTEM_INFORMATION,
+            SMBIOS_PROCESSOR_INFORMATION,
+            SMBIOS_PROCESSOR_INFORMATION,
+            SMBIOS_MEMORY_ARRAY,
+            SMBIOS_MEMORY_DEVICE,
+            SMBIOS_MEMORY_ARRAY_MAPPED_ADDRESS,
+            SMBIOS_MEMORY_ARRAY_MAPPED_ADDRESS,
+            SMBIOS_END_OF_TABLE,
+        ]);
+        assert_eq!(entry_point[0x1c..0x1e], (structures.len() as u16).to_le_bytes());
+        assert_eq!(entry_point[0x08..0x0a], (max_size as u16).to_le_bytes());
+    }
+
+    #[test]
+    fn extended_memory() {
+        let memory = 0..0x1000_0000_0000;
+        let (_, table) = build(1, std::slice::from_ref(&memory)).unwrap();
+
+        // The mapped address uses the extended addresses, as the addresses in KiB do not fit.
+        let offset: usize = structures(&table)
+            .iter()
+            .take_while(|&&(kind, _)| kind != SMBIOS_MEMORY_ARRAY_MAPPED_ADDRESS)
+            .map(|&(_, size)| size)
+            .sum();
+        let mapped = &table[offset..];
+
+        assert_eq!(mapped[0], SMBIOS_MEMORY_ARRAY_MAPPED_ADDRESS);
+        assert_eq!(mapped[4..8], [0xff; 4]);
+        assert_eq!(mapped[8..12], [0xff; 4]);
+        assert_eq!(mapped[23..31], (memory.end - 1).to_le_bytes());
+    }
+
+    #[test]
+    fn invalid_vcpu_count() {
+        assert!(matches!(build(0, &[]), Err(Error::InvalidArgument)));
+
+        // The processor structures no longer fit in the legacy BIOS area.
+        assert!(matches!(build(4096, &[]), Err(Error::InvalidArgument)));
+    }
+}
@@ -0,0 +1,389 @@
+//! This module provides live migration of a VM over a transport supplied by the VMM, e.g. a TCP
+//! stream, through iterative pre-copy. The sender first copies the whole guest physical memory
+//! while the guest keeps running, and then keeps copying the pages that the guest wrote to in the
+//! meantime, as reported by [`crate::Vm::dirty_bitmap`], until few enough pages remain. The VMM
+//! then pauses the virtual CPUs, after which the remaining dirty pages, the states of the virtual
+//! CPUs and the extra state of the VMM are sent. The receiver reconstructs the VM from the
+//! stream, see [`receive`].
+//!
+//! As the migration relies on dirty tracking, it is only supported on Linux and Microsoft
+//! Windows.
+//!
+//! # Protocol
+//!
+//! The stream starts with the following header, where all fields are little-endian integers:
+//!
+//! | Offset | Field     | Description                                                           |
+//! |--------|-----------|-----------------------------------------------------------------------|
+//! | `0x00` | `magic`   | [`MIGRATION_MAGIC`].                                                  |
+//! | `0x08` | `version` | [`MIGRATION_VERSION`] as a 32-bit integer.                            |
+//! | `0x0c` | `arch`    | The architecture as a 32-bit integer, see [`SNAPSHOT_ARCH`].          |
+//!
+//! The header is followed by a sequence of messages, which are framed like the sections of a
+//! snapshot, i.e. a 32-bit `tag` followed by the 64-bit `length` of the payload. The messages
+//! that are not recognized are skipped. The sequence ends with a message tagged
+//! [`MESSAGE_END`]. The payloads of the messages are:
+//!  * [`MESSAGE_REGION`]: the 64-bit guest physical address and size of a region of guest
+//!    physical memory and the 32-bit [`crate::ProtectionFlags`]. The regions precede the other
+//!    messages.
+//!  * [`MESSAGE_PAGES`]: the 64-bit guest physical address of a range of guest physical memory
+//!    followed by its contents. A range may be sent several times, in which case the last copy
+//!    wins.
+//!  * [`MESSAGE_VCPU`]: the [`VcpuState`] of a virtual CPU, encoded as in a snapshot. The
+//!    messages are sent in the order of the vCPU IDs.
+//!  * [`MESSAGE_EXTRA`]: the extra state of the VMM, which is opaque to this crate.
+
+use crate::error::Error;
+use crate::snapshot::{
+    decode_vcpu_state, encode_vcpu_state, read_payload, read_u32, read_u64, write_section,
+    write_section_header, RestoredVm, SNAPSHOT_ARCH,
+};
+use crate::state::VcpuState;
+use crate::vm::{ProtectionFlags, Vm, VmBuilder};
+use rangemap::RangeMap;
+use std::convert::TryFrom;
+use std::io::{Read, Write};
+use std::ops::Range;
+
+/// The magic that identifies a migration stream.
+pub const MIGRATION_MAGIC: [u8; 8] = *b"HYRSMIGR";
+/// The version of the migration protocol.
+pub const MIGRATION_VERSION: u32 = 1;
+
+/// The message that ends the stream.
+pub const MESSAGE_END: u32 = 0;
+/// The message that announces a region of guest physical memory.
+pub const MESSAGE_REGION: u32 = 1;
+/// The message that holds the contents of a range of guest physical memory.
+pub const MESSAGE_PAGES: u32 = 2;
+/// The message that holds the state of a virtual CPU.
+pub const MESSAGE_VCPU: u32 = 3;
+/// The message that holds the extra state of the VMM.
+pub const MESSAGE_EXTRA: u32 = 4;
+
+/// The size of the chunks in which the guest physical memory is copied.
+const CHUNK_SIZE: usize = 1 << 20;
+
+/// The options that control when the sender stops iterating and pauses the VM.
+#[derive(Clone, Debug)]
+pub struct MigrationOptions {
+    /// The maximum number of rounds in which the dirty pages are copied while the guest keeps
+    /// running, after which the VM is paused regardless of the amount of dirty memory. This
+    /// bounds the migration for guests that dirty memory faster than it can be sent.
+    pub max_rounds: usize,
+    /// The amount of dirty guest physical memory in bytes at or below which the VM is paused,
+    /// which bounds the amount of memory that is copied while the guest does not run.
+    pub dirty_threshold: u64,
+}
+
+impl Default for MigrationOptions {
+    fn default() -> Self {
+        Self {
+            max_rounds: 30,
+            dirty_threshold: 8 << 20,
+        }
+    }
+}
+
+/// The state captured by the VMM once the virtual CPUs have been paused, see [`send`].
+#[derive(Clone, Debug, Default)]
+pub struct MigrationState {
+    /// The states of the virtual CPUs in the order of the vCPU IDs, as captured through
+    /// [`crate::Vcpu::get_state`].
+    pub vcpus: Vec<VcpuState>,
+    /// The extra state of the VMM, e.g. the state of the emulated devices.
+    pub extra: Vec<u8>,
+}
+
+/// The statistics of a migration, as returned by [`send`].
+#[derive(Clone, Copy, Debug, Default)]
+pub struct MigrationStats {
+    /// The number of rounds in which the dirty pages were copied while the guest kept running.
+    pub rounds: usize,
+    /// The total number of bytes of guest physical memory sent.
+    pub bytes: u64,
+    /// The number of bytes of guest physical memory sent while the VM was paused.
+    pub stopped_bytes: u64,
+}
+
+/// Migrates the given VM over the given transport through iterative pre-copy, see the
+/// [module-level documentation](self). Once the amount of dirty memory drops to the threshold
+/// of the given [`MigrationOptions`], or once the maximum number of rounds has been reached,
+/// `stop` is called to pause the virtual CPUs, e.g. through [`crate::VcpuRunner::pause`], and to
+/// capture their states and the extra state of the VMM. The guest must not run after `stop`
+/// returns, and the layout of the guest physical memory must not change during the migration.
+///
+/// Dirty tracking is disabled once the migration has finished, whether it succeeded or not.
+///
+/// This is only supported on Linux and Microsoft Windows.
+pub fn send<W, F>(
+    vm: &Vm,
+    transport: &mut W,
+    options: &MigrationOptions,
+    stop: F,
+) -> Result<MigrationStats, Error>
+where
+    W: Write,
+    F: FnOnce() -> Result<MigrationState, Error>,
+{
+    // Enable dirty tracking before copying the guest physical memory, such that the pages that
+    // are written to while copying are sent again.
+    vm.set_dirty_tracking(true)?;
+
+    let result = precopy(vm, transport, options, stop);
+    let disabled = vm.set_dirty_tracking(false);
+
+    let stats = result?;
+    disabled?;
+
+    Ok(stats)
+}
+
+/// Helper function to send the VM once dirty tracking has been enabled.
+fn precopy<W, F>(
+    vm: &Vm,
+    transport: &mut W,
+    options: &MigrationOptions,
+    stop: F,
+) -> Result<MigrationStats, Error>
+where
+    W: Write,
+    F: FnOnce() -> Result<MigrationState, Error>,
+{
+    transport.write_all(&MIGRATION_MAGIC)?;
+    transport.write_all(&MIGRATION_VERSION.to_le_bytes())?;
+    transport.write_all(&SNAPSHOT_ARCH.to_le_bytes())?;
+
+    let regions: Vec<_> = vm
+        .regions()?
+        .map(|(range, protection, _)| (range, protection))
+        .collect();
+
+    for (range, protection) in &regions {
+        write_section_header(transport, MESSAGE_REGION, 20)?;
+        transport.write_all(&range.start.to_le_bytes())?;
+        transport.write_all(&(range.end - range.start).to_le_bytes())?;
+        transport.write_all(&protection.bits().to_le_bytes())?;
+    }
+
+    let mut stats = MigrationStats::default();
+    let mut chunk = vec![0u8; CHUNK_SIZE];
+
+    // Copy the whole guest physical memory first.
+    for (range, _) in &regions {
+        stats.bytes += send_range(vm, transport, range.clone(), &mut chunk)?;
+    }
+
+    let mut dirty = RangeMap::new();
+    collect_dirty(vm, &regions, &mut dirty)?;
+
+    while stats.rounds < options.max_rounds && dirty_size(&dirty) > options.dirty_threshold {
+        let pending = std::mem::replace(&mut dirty, RangeMap::new());
+
+        for (range, _) in pending.iter() {
+            stats.bytes += send_range(vm, transport, range.clone(), &mut chunk)?;
+        }
+
+        stats.rounds += 1;
+        collect_dirty(vm, &regions, &mut dirty)?;
+    }
+
+    let state = stop()?;
+
+    // Merge the pages dirtied until the virtual CPUs were paused with the pending ones.
+    collect_dirty(vm, &regions, &mut dirty)?;
+
+    for (range, _) in dirty.iter() {
+        let size = send_range(vm, transport, range.clone(), &mut chunk)?;
+
+        stats.bytes += size;
+        stats.stopped_bytes += size;
+    }
+
+    for vcpu in &state.vcpus {
+        write_section(transport, MESSAGE_VCPU, &encode_vcpu_state(vcpu)?)?;
+    }
+
+    write_section(transport, MESSAGE_EXTRA, &state.extra)?;
+    write_section_header(transport, MESSAGE_END, 0)?;
+    transport.flush()?;
+
+    Ok(stats)
+}
+
+/// Helper function to add the dirty pages of the given regions to the given set of ranges, where
+/// adjacent pages are coalesced into a single range.
+fn collect_dirty(
+    vm: &Vm,
+    regions: &[(Range<u64>, ProtectionFlags)],
+    dirty: &mut RangeMap<u64, ()>,
+) -> Result<(), Error> {
+    for (range, _) in regions {
+        let bitmap = vm.dirty_bitmap(range.start)?;
+
+        for address in bitmap.dirty_pages() {
+            dirty.insert(address..(address + bitmap.page_size).min(range.end), ());
+        }
+    }
+
+    Ok(())
+}
+
+/// Helper function to compute the number of bytes in the given set of ranges.
+fn dirty_size(dirty: &RangeMap<u64, ()>) -> u64 {
+    dirty
+        .iter()
+        .map(|(range, _)| range.end - range.start)
+        .sum()
+}
+
+/// Helper function to send the given range of guest physical memory in chunks. Returns the number
+/// of bytes sent.
+fn send_range<W: Write>(
+    vm: &Vm,
+    transport: &mut W,
+    range: Range<u64>,
+    chunk: &mut [u8],
+) -> Result<u64, Error> {
+    let mut address = range.start;
+
+    while address < range.end {
+        let length = chunk.len().min((range.end - address) as usize);
+
+        if vm.read_physical_memory(&mut chunk[..length], address)? != length {
+            return Err(Error::InvalidGuestAddress);
+        }
+
+        write_section_header(transport, MESSAGE_PAGES, 8 + length as u64)?;
+        transport.write_all(&address.to_le_bytes())?;
+        transport.write_all(&chunk[..length])?;
+        address += length as u64;
+    }
+
+    Ok(range.end - range.start)
+}
+
+/// Helper function to compute the range of guest physical memory of the given size at the given
+/// guest physical address. Returns `None` if the range wraps around the address space.
+fn guest_range(guest_address: u64, size: u64) -> Option<Range<u64>> {
+    Some(guest_address..guest_address.checked_add(size)?)
+}
+
+/// Receives a VM migrated through [`send`] from the given transport. The VM is built from the
+/// given [`VmBuilder`], which should be configured the same way as the builder of the original
+/// VM, including the number of virtual CPUs. Returns [`Error::InvalidMigrationStream`] if the
+/// stream does not follow the protocol, e.g. if a range wraps around the address space or if a
+/// payload is larger than [`crate::snapshot::MAX_PAYLOAD_SIZE`], or if it was sent with another
+/// version of the protocol or from another architecture.
+///
+/// The virtual CPUs are not created, as some platforms require them to be created on the thread
+/// that runs them, so their states have to be restored through [`crate::Vcpu::set_state`] once
+/// they have been created.
+pub fn receive<'a, R: Read>(
+    builder: VmBuilder,
+    transport: &mut R,
+    name: &'a str,
+) -> Result<RestoredVm<'a>, Error> {
+    let mut magic = [0u8; 8];
+    transport.read_exact(&mut magic)?;
+
+    if magic != MIGRATION_MAGIC || read_u32(transport)? != MIGRATION_VERSION {
+        return Err(Error::InvalidMigrationStream);
+    }
+
+    if read_u32(transport)? != SNAPSHOT_ARCH {
+        return Err(Error::InvalidMigrationStream);
+    }
+
+    let mut vm = builder.build(name)?;
+    let mut vcpus = vec![];
+    let mut extra = vec![];
+    let mut chunk = vec![0u8; CHUNK_SIZE];
+
+    loop {
+        let tag = read_u32(transport)?;
+        let length = read_u64(transport)?;
+
+        match tag {
+            MESSAGE_END => break,
+            MESSAGE_REGION => {
+                if length != 20 {
+                    return Err(Error::InvalidMigrationStream);
+                }
+
+                let guest_address = read_u64(transport)?;
+                let size = read_u64(transport)?;
+                let protection = ProtectionFlags::from_bits(read_u32(transport)?)
+                    .ok_or(Error::InvalidMigrationStream)?;
+
+                guest_range(guest_address, size).ok_or(Error::InvalidMigrationStream)?;
+                let size = usize::try_from(size).map_err(|_| Error::InvalidMigrationStream)?;
+
+                vm.allocate_physical_memory(guest_address, size, protection)?;
+            }
+            MESSAGE_PAGES => {
+                // The payload is copied in chunks, so its length only has to fit the address
+                // space, rather than the memory of the host.
+                let size = length.checked_sub(8).ok_or(Error::InvalidMigrationStream)?;
+                let guest_address = read_u64(transport)?;
+                let end = guest_range(guest_address, size)
+                    .ok_or(Error::InvalidMigrationStream)?
+                    .end;
+                let mut address = guest_address;
+
+                while address < end {
+                    let length = CHUNK_SIZE.min((end - address) as usize);
+
+                    transport.read_exact(&mut chunk[..length])?;
+                    vm.write_all_physical_memory(address, &chunk[..length])?;
+                    address += length as u64;
+                }
+            }
+            MESSAGE_VCPU => {
+                let bytes = read_payload(transport, length)?
+                    .ok_or(Error::InvalidMigrationStream)?;
+                let state = decode_vcpu_state(&bytes)
+                    .map_err(|_| Error::InvalidMigrationStream)?;
+
+                vcpus.push(state);
+            }
+            MESSAGE_EXTRA => {
+                extra = read_payload(transport, length)?
+                    .ok_or(Error::InvalidMigrationStream)?;
+            }
+            _ => {
+                std::io::copy(&mut (&mut *transport).take(length), &mut std::io::sink())?;
+            }
+        }
+    }
+
+    Ok(RestoredVm {
+        vm,
+        vcpus,
+        extra,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn guest_range_overflow() {
+        assert_eq!(guest_range(0x1000, 0x2000), Some(0x1000..0x3000));
+        assert_eq!(guest_range(u64::MAX - 0xfff, 0xfff), Some(u64::MAX - 0xfff..u64::MAX));
+        assert_eq!(guest_range(u64::MAX - 0xfff, 0x1000), None);
+        assert_eq!(guest_range(u64::MAX, u64::MAX), None);
+    }
+
+    #[test]
+    fn dirty_size_coalesces() {
+        let mut dirty = RangeMap::new();
+        dirty.insert(0x1000..0x2000, ());
+        dirty.insert(0x2000..0x3000, ());
+        dirty.insert(0x1000..0x2000, ());
+        dirty.insert(0x8000..0x9000, ());
+
+        assert_eq!(dirty_size(&dirty), 0x3000);
+    }
+}
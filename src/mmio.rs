@@ -0,0 +1,321 @@
+//! This module provides a decoder for the instructions that access MMIO regions on the x86
+//! architecture. Unlike KVM, the Hypervisor Framework and the Windows Hypervisor Platform only
+//! report the guest physical address when the guest accesses memory that is not mapped, such that
+//! the instruction has to be decoded to determine the size of the access, the data and the
+//! register to complete a read in.
+//!
+//! Only the `mov` and `movzx` instructions are decoded, as these are what compilers emit for
+//! volatile accesses to device registers. Any other instruction is reported as an invalid memory
+//! access instead.
+
+use crate::arch::x86_64::{CpuRegs, Register};
+use crate::error::Error;
+
+/// The general-purpose registers in the order in which they are encoded in the instructions.
+pub(crate) const REGISTERS: [Register; 16] = [
+    Register::Rax,
+    Register::Rcx,
+    Register::Rdx,
+    Register::Rbx,
+    Register::Rsp,
+    Register::Rbp,
+    Register::Rsi,
+    Register::Rdi,
+    Register::R8,
+    Register::R9,
+    Register::R10,
+    Register::R11,
+    Register::R12,
+    Register::R13,
+    Register::R14,
+    Register::R15,
+];
+
+/// The mode the virtual CPU executes code in, which determines the default operand and address
+/// sizes.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub(crate) enum CodeMode {
+    /// 16-bit code, i.e. real mode or a 16-bit code segment.
+    Bits16,
+    /// 32-bit code, i.e. a 32-bit code segment in protected mode or compatibility mode.
+    Bits32,
+    /// 64-bit code.
+    Bits64,
+}
+
+impl CodeMode {
+    /// Determines the mode from the L and D/B bits of the access rights of the code segment.
+    pub fn from_cs_access_rights(access_rights: u64) -> Self {
+        if access_rights & (1 << 13) != 0 {
+            Self::Bits64
+        } else if access_rights & (1 << 14) != 0 {
+            Self::Bits32
+        } else {
+            Self::Bits16
+        }
+    }
+}
+
+/// A part of a general-purpose register that is the operand of an instruction.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub(crate) struct RegisterOperand {
+    /// The register.
+    pub register: Register,
+    /// The size of the operand in bytes.
+    pub size: usize,
+    /// The offset of the operand in bits, which is 8 for `ah`, `ch`, `dh` and `bh`.
+    pub shift: u32,
+}
+
+impl RegisterOperand {
+    /// Reads the operand from the given virtual CPU.
+    pub fn read(&self, vcpu: &dyn CpuRegs) -> Result<u64, Error> {
+        let value = vcpu.get_registers(&[self.register])?[0];
+
+        Ok((value >> self.shift) & mask(self.size))
+    }
+
+    /// Writes the value to the operand of the given virtual CPU. Like on bare metal, a 32-bit
+    /// operand zero-extends into the full register, while the smaller operands preserve the other
+    /// bits.
+    pub fn write(&self, vcpu: &mut dyn CpuRegs, value: u64) -> Result<(), Error> {
+        let value = match self.size {
+            4 | 8 => value & mask(self.size),
+            _ => {
+                let old = vcpu.get_registers(&[self.register])?[0];
+                let mask = mask(self.size) << self.shift;
+
+                (old & !mask) | ((value << self.shift) & mask)
+            }
+        };
+
+        vcpu.set_registers(&[self.register], &[value])
+    }
+}
+
+/// The operation of an instruction that accesses memory.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub(crate) enum MmioOperation {
+    /// The instruction reads memory into the register, where the value is zero-extended if the
+    /// register is larger than the access.
+    Read(RegisterOperand),
+    /// The instruction writes the register to memory.
+    WriteRegister(RegisterOperand),
+    /// The instruction writes the immediate value to memory.
+    WriteImmediate(u64),
+}
+
+/// A decoded instruction that accesses memory.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub(crate) struct MmioInstruction {
+    /// The length of the instruction in bytes.
+    pub length: u64,
+    /// The size of the memory access in bytes.
+    pub size: usize,
+    /// The operation.
+    pub operation: MmioOperation,
+}
+
+impl MmioInstruction {
+    /// Returns whether the instruction writes memory.
+    pub fn is_write(&self) -> bool {
+        !matches!(self.operation, MmioOperation::Read(_))
+    }
+
+    /// Returns the data the instruction writes to memory, which is read from the given virtual CPU
+    /// for register operands.
+    pub fn write_data(&self, vcpu: &dyn CpuRegs) -> Result<u64, Error> {
+        let value = match self.operation {
+            MmioOperation::WriteRegister(operand) => operand.read(vcpu)?,
+            MmioOperation::WriteImmediate(value) => value,
+            MmioOperation::Read(_) => return Err(Error::InvalidArgument),
+        };
+
+        Ok(value & mask(self.size))
+    }
+}
+
+/// Helper function to get the mask for a value of the given size in bytes.
+fn mask(size: usize) -> u64 {
+    match size {
+        8 => !0,
+        size => (1 << (size * 8)) - 1,
+    }
+}
+
+/// Helper function to get the operand for the given register number, where `rex` indicates
+/// whether an REX prefix is present, as that selects `spl`, `bpl`, `sil` and `dil` rather than
+/// `ah`, `ch`, `dh` and `bh` for the 8-bit operands.
+fn register_operand(number: usize, size: usize, rex: bool) -> RegisterOperand {
+    if size == 1 && !rex && (4..8).contains(&number) {
+        return RegisterOperand {
+            register: REGISTERS[number - 4],
+            size,
+            shift: 8,
+        };
+    }
+
+    RegisterOperand {
+        register: REGISTERS[number],
+        size,
+        shift: 0,
+    }
+}
+
+/// Helper function to determine the length of the ModR/M byte and the bytes that follow it, i.e.
+/// the SIB byte and the displacement. Returns `None` if the operand is a register rather than
+/// memory.
+fn modrm_length(bytes: &[u8], address_size: usize) -> Option<usize> {
+    let modrm = *bytes.first()?;
+    let mode = modrm >> 6;
+    let rm = modrm & 0x7;
+
+    if mode == 0b11 {
+        return None;
+    }
+
+    // The 16-bit addressing forms do not have a SIB byte.
+    if address_size == 2 {
+        return Some(1 + match (mode, rm) {
+            (0b00, 0b110) => 2,
+            (0b00, _) => 0,
+            (0b01, _) => 1,
+            _ => 2,
+        });
+    }
+
+    let mut length = 1;
+    let mut base = rm;
+
+    if rm == 0b100 {
+        base = *bytes.get(1)? & 0x7;
+        length += 1;
+    }
+
+    length += match (mode, base) {
+        // Either a 32-bit displacement without a base or relative to the instruction pointer.
+        (0b00, 0b101) => 4,
+        (0b00, _) => 0,
+        (0b01, _) => 1,
+        _ => 4,
+    };
+
+    Some(length)
+}
+
+/// Decodes the instruction in the given bytes. Returns `None` if the instruction is not supported.
+pub(crate) fn decode(bytes: &[u8], mode: CodeMode) -> Option<MmioInstruction> {
+    let (mut operand_size, mut address_size) = match mode {
+        CodeMode::Bits16 => (2, 2),
+        CodeMode::Bits32 => (4, 4),
+        CodeMode::Bits64 => (4, 8),
+    };
+
+    let mut offset = 0;
+
+    // Parse the legacy prefixes.
+    loop {
+        match *bytes.get(offset)? {
+            0x66 => operand_size = match mode {
+                CodeMode::Bits16 => 4,
+                _ => 2,
+            },
+            0x67 => address_size = match mode {
+                CodeMode::Bits16 => 4,
+                CodeMode::Bits32 => 2,
+                CodeMode::Bits64 => 4,
+            },
+            // The segment overrides and the `lock` prefix do not affect the access.
+            0x26 | 0x2e | 0x36 | 0x3e | 0x64 | 0x65 | 0xf0 => (),
+            _ => break,
+        }
+
+        offset += 1;
+    }
+
+    // Parse the REX prefix.
+    let mut rex = 0;
+
+    if mode == CodeMode::Bits64 {
+        if let 0x40..=0x4f = *bytes.get(offset)? {
+            rex = bytes[offset];
+            offset += 1;
+        }
+    }
+
+    if rex & 0x8 != 0 {
+        operand_size = 8;
+    }
+
+    let opcode = *bytes.get(offset)?;
+    offset += 1;
+
+    let (size, register_size, write, immediate) = match opcode {
+        // mov r/m8, r8
+        0x88 => (1, 1, true, false),
+        // mov r/m, r
+        0x89 => (operand_size, operand_size, true, false),
+        // mov r8, r/m8
+        0x8a => (1, 1, false, false),
+        // mov r, r/m
+        0x8b => (operand_size, operand_size, false, false),
+        // mov r/m8, imm8
+        0xc6 => (1, 1, true, true),
+        // mov r/m, imm
+        0xc7 => (operand_size, operand_size, true, true),
+        0x0f => {
+            let opcode = *bytes.get(offset)?;
+            offset += 1;
+
+            match opcode {
+                // movzx r, r/m8
+                0xb6 => (1, operand_size, false, false),
+                // movzx r, r/m16
+                0xb7 => (2, operand_size, false, false),
+                _ => return None,
+            }
+        }
+        _ => return None,
+    };
+
+    let modrm = *bytes.get(offset)?;
+    let reg = (((rex as usize >> 2) & 0x1) << 3) | ((modrm as usize >> 3) & 0x7);
+
+    offset += modrm_length(&bytes[offset..], address_size)?;
+
+    let operation = if immediate {
+        // The `mov` instruction with an immediate only uses /0.
+        if reg & 0x7 != 0 {
+            return None;
+        }
+
+        // The immediate is at most 32 bits and is sign-extended for 64-bit operands.
+        let immediate_size = size.min(4);
+        let bytes = bytes.get(offset..offset + immediate_size)?;
+        offset += immediate_size;
+
+        let mut value = [0u8; 8];
+        value[..immediate_size].copy_from_slice(bytes);
+        let mut value = u64::from_le_bytes(value);
+
+        if size == 8 {
+            value = value as u32 as i32 as i64 as u64;
+        }
+
+        MmioOperation::WriteImmediate(value)
+    } else {
+        let operand = register_operand(reg, register_size, rex != 0);
+
+        if write {
+            MmioOperation::WriteRegister(operand)
+        } else {
+            MmioOperation::Read(operand)
+        }
+    };
+
+    Some(MmioInstruction {
+        length: offset as u64,
+        size,
+        operation,
+    })
+}
@@ -19,6 +19,18 @@ const VM_GET_REGISTER:           u8 = 21;
 const VM_SET_SEGMENT_DESCRIPTOR: u8 = 22;
 const VM_GET_SEGMENT_DESCRIPTOR: u8 = 23;
 
+const VM_GET_INTINFO:      u8 = 28;
+const VM_SET_INTINFO:      u8 = 29;
+const VM_INJECT_EXCEPTION: u8 = 30;
+const VM_LAPIC_IRQ:        u8 = 31;
+const VM_INJECT_NMI:       u8 = 32;
+
+const VM_IOAPIC_ASSERT_IRQ:   u8 = 33;
+const VM_IOAPIC_DEASSERT_IRQ: u8 = 34;
+
+const VM_ISA_ASSERT_IRQ:   u8 = 80;
+const VM_ISA_DEASSERT_IRQ: u8 = 81;
+
 #[repr(C)]
 #[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum vm_reg_name {
@@ -55,6 +67,18 @@ pub enum vm_reg_name {
     VM_REG_GUEST_IDTR,
     VM_REG_GUEST_GDTR,
     VM_REG_GUEST_EFER,
+    VM_REG_GUEST_CR2,
+    VM_REG_GUEST_PDPTE0,
+    VM_REG_GUEST_PDPTE1,
+    VM_REG_GUEST_PDPTE2,
+    VM_REG_GUEST_PDPTE3,
+    VM_REG_GUEST_INTR_SHADOW,
+    VM_REG_GUEST_DR0,
+    VM_REG_GUEST_DR1,
+    VM_REG_GUEST_DR2,
+    VM_REG_GUEST_DR3,
+    VM_REG_GUEST_DR6,
+    VM_REG_GUEST_ENTRY_INST_LENGTH,
     VM_REG_LAST,
 }
 
@@ -72,6 +96,20 @@ pub enum vm_exitcode {
     VM_EXITCODE_PAGING,
     VM_EXITCODE_INST_EMUL,
     VM_EXITCODE_SPINUP_AP,
+    VM_EXITCODE_DEPRECATED1,
+    VM_EXITCODE_RENDEZVOUS,
+    VM_EXITCODE_IOAPIC_EOI,
+    VM_EXITCODE_SUSPENDED,
+    VM_EXITCODE_INOUT_STR,
+    VM_EXITCODE_TASK_SWITCH,
+    VM_EXITCODE_MONITOR,
+    VM_EXITCODE_MWAIT,
+    VM_EXITCODE_SVM,
+    VM_EXITCODE_REQIDLE,
+    VM_EXITCODE_DEBUG,
+    VM_EXITCODE_VMINSN,
+    VM_EXITCODE_BPT,
+    VM_EXITCODE_IPI,
     VM_EXITCODE_MAX,
 }
 
@@ -85,11 +123,49 @@ pub enum vm_cap_type {
     VM_CAP_MAX,
 }
 
+/// The exit information of `VM_EXITCODE_PAGING`, which is reported for both EPT violations on
+/// Intel and nested page faults on AMD.
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct vm_exit_paging {
+    pub gpa: u64,
+    pub fault_type: i32,
+}
+
+/// The exit information of `VM_EXITCODE_VMX`, i.e. the exits on Intel that bhyve does not handle.
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct vm_exit_vmx {
+    pub status: i32,
+    pub exit_reason: u32,
+    pub exit_qualification: u64,
+    pub inst_type: i32,
+    pub inst_error: i32,
+}
+
+/// The exit information of `VM_EXITCODE_SVM`, i.e. the exits on AMD that bhyve does not handle.
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct vm_exit_svm {
+    pub exitcode: u64,
+    pub exitinfo1: u64,
+    pub exitinfo2: u64,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub union vm_exit_info {
+    pub paging: vm_exit_paging,
+    pub vmx: vm_exit_vmx,
+    pub svm: vm_exit_svm,
+}
+
 #[repr(C)]
 pub struct vm_exit {
     pub exitcode: vm_exitcode,
     pub inst_length: i32,
     pub rip: u64,
+    pub u: vm_exit_info,
 }
 
 #[repr(C)]
@@ -135,6 +211,44 @@ pub struct vm_seg_desc {
     pub desc: seg_desc,
 }
 
+#[repr(C)]
+pub struct vm_intinfo {
+    pub cpuid: i32,
+    pub info1: u64,
+    pub info2: u64,
+}
+
+#[repr(C)]
+pub struct vm_exception {
+    pub cpuid: i32,
+    pub vector: i32,
+    pub error_code: u32,
+    pub error_code_valid: i32,
+    pub restart_instruction: i32,
+}
+
+#[repr(C)]
+pub struct vm_lapic_irq {
+    pub cpuid: i32,
+    pub vector: i32,
+}
+
+#[repr(C)]
+pub struct vm_nmi {
+    pub cpuid: i32,
+}
+
+#[repr(C)]
+pub struct vm_ioapic_irq {
+    pub irq: i32,
+}
+
+#[repr(C)]
+pub struct vm_isa_irq {
+    pub atpic_irq: i32,
+    pub ioapic_irq: i32,
+}
+
 pub fn vm_create(name: &str) -> Result<(), Error> {
     let ctl = sysctl::Ctl::new("hw.vmm.create")?;
 
@@ -162,3 +276,14 @@ ioctl_write_ptr!(vm_set_register, VM_MAGIC, VM_SET_REGISTER, vm_register);
 ioctl_readwrite!(vm_get_register, VM_MAGIC, VM_GET_REGISTER, vm_register);
 ioctl_write_ptr!(vm_set_segment_descriptor, VM_MAGIC, VM_SET_SEGMENT_DESCRIPTOR, vm_seg_desc);
 ioctl_readwrite!(vm_get_segment_descriptor, VM_MAGIC, VM_GET_SEGMENT_DESCRIPTOR, vm_seg_desc);
+
+ioctl_readwrite!(vm_get_intinfo, VM_MAGIC, VM_GET_INTINFO, vm_intinfo);
+ioctl_write_ptr!(vm_set_intinfo, VM_MAGIC, VM_SET_INTINFO, vm_intinfo);
+ioctl_write_ptr!(vm_inject_exception, VM_MAGIC, VM_INJECT_EXCEPTION, vm_exception);
+ioctl_write_ptr!(vm_lapic_irq, VM_MAGIC, VM_LAPIC_IRQ, vm_lapic_irq);
+ioctl_write_ptr!(vm_inject_nmi, VM_MAGIC, VM_INJECT_NMI, vm_nmi);
+
+ioctl_write_ptr!(vm_ioapic_assert_irq, VM_MAGIC, VM_IOAPIC_ASSERT_IRQ, vm_ioapic_irq);
+ioctl_write_ptr!(vm_ioapic_deassert_irq, VM_MAGIC, VM_IOAPIC_DEASSERT_IRQ, vm_ioapic_irq);
+ioctl_write_ptr!(vm_isa_assert_irq, VM_MAGIC, VM_ISA_ASSERT_IRQ, vm_isa_irq);
+ioctl_write_ptr!(vm_isa_deassert_irq, VM_MAGIC, VM_ISA_DEASSERT_IRQ, vm_isa_irq);
@@ -1,4 +1,7 @@
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+use crate::arch::x86_64::CpuidEntry;
 use crate::error::Error;
+use crate::hypervisor::Capability;
 use super::vm::VmBuilder;
 
 pub struct Hypervisor;
@@ -8,7 +11,23 @@ impl Hypervisor {
         Ok(Self)
     }
 
+    pub fn has_capability(&self, capability: Capability) -> bool {
+        match capability {
+            Capability::ProtectedGuest => false,
+            Capability::Hypercalls => false,
+        }
+    }
+
     pub fn build_vm(&self) -> Result<VmBuilder, Error> {
-        Ok(VmBuilder)
+        Ok(VmBuilder {
+            hlt_exiting: true,
+            msr_exits: false,
+        })
+    }
+
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    pub fn get_supported_cpuid(&self) -> Result<Vec<CpuidEntry>, Error> {
+        // bhyve serves the CPUID of the guest in the kernel.
+        Err(Error::NotImplemented)
     }
 }
@@ -1,8 +1,9 @@
 pub mod bindings;
 pub mod hypervisor;
+pub mod thread;
 pub mod vcpu;
 pub mod vm;
 
 pub use hypervisor::Hypervisor;
 pub use vm::{Vm, VmBuilder};
-pub use vcpu::Vcpu;
+pub use vcpu::{Vcpu, VcpuHandle};
@@ -0,0 +1,74 @@
+use crate::error::Error;
+use crate::thread::{ResourceLimits, ThreadPriority, ThreadPriorityReport};
+
+fn set_fifo(priority: u8) -> Result<libc::c_int, std::io::Error> {
+    let min = unsafe { libc::sched_get_priority_min(libc::SCHED_FIFO) };
+    let max = unsafe { libc::sched_get_priority_max(libc::SCHED_FIFO) };
+    let priority = (priority as libc::c_int).max(min).min(max);
+
+    let param = libc::sched_param {
+        sched_priority: priority,
+    };
+
+    let result = unsafe {
+        libc::pthread_setschedparam(libc::pthread_self(), libc::SCHED_FIFO, &param)
+    };
+
+    if result != 0 {
+        return Err(std::io::Error::from_raw_os_error(result));
+    }
+
+    Ok(priority)
+}
+
+fn is_permission_error(error: &std::io::Error) -> bool {
+    matches!(error.raw_os_error(), Some(libc::EPERM) | Some(libc::EACCES))
+}
+
+pub fn set_current_thread_priority(
+    priority: ThreadPriority,
+) -> Result<ThreadPriorityReport, Error> {
+    let mut diagnostics = vec![];
+
+    if let ThreadPriority::RealTime { priority: value } = priority {
+        match set_fifo(value) {
+            Ok(applied) => return Ok(ThreadPriorityReport {
+                requested: priority,
+                applied: ThreadPriority::RealTime { priority: applied as u8 },
+                diagnostics,
+            }),
+            Err(e) if is_permission_error(&e) => diagnostics.push(format!(
+                "SCHED_FIFO was denied ({}), root privileges are required; keeping the normal \
+                priority",
+                e,
+            )),
+            Err(e) => return Err(e.into()),
+        }
+    } else {
+        // On FreeBSD, the nice value is a per-process attribute and the priority of a time-sharing
+        // thread is recomputed by the scheduler, so there is no elevated priority that only
+        // applies to the calling thread.
+        diagnostics.push(
+            "FreeBSD does not support an elevated priority for a single thread; keeping the \
+            normal priority".to_string(),
+        );
+    }
+
+    Ok(ThreadPriorityReport {
+        requested: priority,
+        applied: ThreadPriority::Normal,
+        diagnostics,
+    })
+}
+
+pub struct ResourceGroup;
+
+impl ResourceGroup {
+    pub fn new(_name: &str, _limits: &ResourceLimits) -> Result<Self, Error> {
+        Err(Error::NotImplemented)
+    }
+
+    pub fn add_current_thread(&self) -> Result<(), Error> {
+        Err(Error::NotImplemented)
+    }
+}
@@ -1,5 +1,8 @@
+use crate::arch::x86_64::{SvmExitCode, VmxReason};
+use crate::debug::GuestDebug;
 use crate::error::Error;
-use crate::vcpu::ExitReason;
+use crate::hypercall::Hypercalls;
+use crate::vcpu::{ExitPolicy, ExitReason};
 use std::fs::File;
 use std::os::unix::io::AsRawFd;
 use super::bindings::*;
@@ -8,6 +11,25 @@ pub struct Vcpu {
     pub(crate) cpuid: i32,
     pub(crate) file: File,
     pub(crate) rip: u64,
+    /// Whether to report the MSR accesses as [`ExitReason::MsrRead`] and
+    /// [`ExitReason::MsrWrite`].
+    pub(crate) msr_exits: bool,
+    /// Whether the last exit was an `rdmsr` instruction that has not been completed yet.
+    pub(crate) pending_msr_read: bool,
+    /// Whether [`Vcpu::run`] returns without entering the guest.
+    pub(crate) immediate_exit: bool,
+}
+
+#[derive(Clone)]
+pub struct VcpuHandle;
+
+impl VcpuHandle {
+    pub fn clear_kick(&self) {
+    }
+
+    pub fn kick(&self) -> Result<(), Error> {
+        Err(Error::NotImplemented)
+    }
 }
 
 impl Vcpu {
@@ -81,29 +103,380 @@ impl Vcpu {
         Ok(())
     }
 
+    pub fn handle(&self) -> VcpuHandle {
+        VcpuHandle
+    }
+
+    pub fn set_immediate_exit(&mut self, immediate_exit: bool) {
+        self.immediate_exit = immediate_exit;
+    }
+
+    pub fn virtual_tsc(&self) -> Option<u64> {
+        None
+    }
+
+    pub fn set_virtual_tsc(&mut self, _value: u64) -> Result<(), Error> {
+        Err(Error::NotImplemented)
+    }
 
-    pub fn run(&self) -> Result<ExitReason, Error> {
+    pub fn run(
+        &mut self,
+        policy: ExitPolicy,
+        _hypercalls: &Hypercalls,
+    ) -> Result<ExitReason, Error> {
         let mut args: vm_run = unsafe { std::mem::zeroed() };
 
         args.cpuid = self.cpuid;
         args.rip   = self.rip;
 
-        unsafe {
-            vm_run(self.file.as_raw_fd(), &mut args)
-        }?;
+        self.pending_msr_read = false;
 
-        let exit_reason = match args.vm_exit.exitcode {
-            vm_exitcode::VM_EXITCODE_HLT => ExitReason::Halted,
-            _ => ExitReason::Unknown,
+        if self.immediate_exit {
+            return Ok(ExitReason::Canceled);
+        }
+
+        let exit_reason = loop {
+            unsafe {
+                vm_run(self.file.as_raw_fd(), &mut args)
+            }?;
+
+            break match args.vm_exit.exitcode {
+                vm_exitcode::VM_EXITCODE_HLT => ExitReason::Halted,
+                vm_exitcode::VM_EXITCODE_RDMSR if self.msr_exits => {
+                    let msr = self.vm_get_register(vm_reg_name::VM_REG_GUEST_RCX)? as u32;
+
+                    // The guest reads zero unless the read is completed.
+                    self.vm_set_register(vm_reg_name::VM_REG_GUEST_RAX, 0)?;
+                    self.vm_set_register(vm_reg_name::VM_REG_GUEST_RDX, 0)?;
+                    self.rip = args.vm_exit.rip + args.vm_exit.inst_length as u64;
+                    self.pending_msr_read = true;
+
+                    ExitReason::MsrRead { msr }
+                }
+                vm_exitcode::VM_EXITCODE_WRMSR if self.msr_exits => {
+                    let msr = self.vm_get_register(vm_reg_name::VM_REG_GUEST_RCX)? as u32;
+                    let eax = self.vm_get_register(vm_reg_name::VM_REG_GUEST_RAX)?;
+                    let edx = self.vm_get_register(vm_reg_name::VM_REG_GUEST_RDX)?;
+                    self.rip = args.vm_exit.rip + args.vm_exit.inst_length as u64;
+
+                    ExitReason::MsrWrite {
+                        msr,
+                        value: (edx << 32) | (eax & 0xffff_ffff),
+                    }
+                }
+                // bhyve reports both EPT violations and nested page faults that it cannot
+                // resolve as paging exits.
+                vm_exitcode::VM_EXITCODE_PAGING => ExitReason::InvalidMemoryAccess {
+                    gpa: unsafe { args.vm_exit.u.paging.gpa },
+                    gva: 0,
+                },
+                _ if is_triple_fault(&args.vm_exit) => ExitReason::UnhandledException,
+                // bhyve returns to user space for these exits without having to emulate
+                // anything, so resume at the instruction pointer reported by the exit.
+                vm_exitcode::VM_EXITCODE_BOGUS |
+                vm_exitcode::VM_EXITCODE_RENDEZVOUS |
+                vm_exitcode::VM_EXITCODE_REQIDLE
+                    if policy.contains(ExitPolicy::RESUME_HOUSEKEEPING) => {
+                    args.rip = args.vm_exit.rip;
+                    continue;
+                }
+                _ => ExitReason::Unknown,
+            };
         };
 
         Ok(exit_reason)
     }
+
+    pub fn set_guest_debug(&mut self, _debug: &GuestDebug) -> Result<(), Error> {
+        Err(Error::NotImplemented)
+    }
+
+    pub fn complete_io(&mut self, _data: &[u8]) -> Result<(), Error> {
+        Err(Error::NotImplemented)
+    }
+
+    pub fn complete_mmio_read(&mut self, _data: &[u8]) -> Result<(), Error> {
+        Err(Error::NotImplemented)
+    }
+}
+
+/// Helper function to check if the exit is a triple fault, which bhyve reports as a raw exit of
+/// Intel VMX or AMD SVM. On AMD, the state of the virtual CPU is undefined afterwards, as the
+/// shutdown intercept does not preserve the VMCB.
+fn is_triple_fault(exit: &vm_exit) -> bool {
+    match exit.exitcode {
+        vm_exitcode::VM_EXITCODE_VMX => {
+            let reason = unsafe { exit.u.vmx.exit_reason } & 0xffff;
+
+            reason == VmxReason::TripleFault as u32
+        }
+        vm_exitcode::VM_EXITCODE_SVM => {
+            let code = unsafe { exit.u.svm.exitcode };
+
+            SvmExitCode::from_exit_code(code) == Some(SvmExitCode::Shutdown)
+        }
+        _ => false,
+    }
+}
+
+#[cfg(feature = "xen")]
+impl Vcpu {
+    pub fn set_xen_vcpu_info(&mut self, _gpa: u64) -> Result<(), Error> {
+        Err(Error::NotImplemented)
+    }
+
+    pub fn complete_xen_hypercall(&mut self, _result: u64) -> Result<(), Error> {
+        Err(Error::NotImplemented)
+    }
 }
 
 #[cfg(target_arch = "x86_64")]
 use crate::arch::x86_64::*;
 
+#[cfg(target_arch = "x86_64")]
+impl Vcpu {
+    pub fn inject_smi(&mut self) -> Result<(), Error> {
+        Err(Error::NotImplemented)
+    }
+
+    pub fn translate_gva(&self, _gva: u64) -> Result<u64, Error> {
+        Err(Error::NotImplemented)
+    }
+
+    pub fn get_smm_state(&self) -> Result<SmmState, Error> {
+        Err(Error::NotImplemented)
+    }
+
+    pub fn set_smm_state(&mut self, _state: &SmmState) -> Result<(), Error> {
+        Err(Error::NotImplemented)
+    }
+
+    pub fn get_pending_events(&self) -> Result<PendingEvents, Error> {
+        let mut args = vm_intinfo {
+            cpuid: self.cpuid,
+            info1: 0,
+            info2: 0,
+        };
+
+        unsafe {
+            vm_get_intinfo(self.file.as_raw_fd(), &mut args)
+        }?;
+
+        let shadow = self.vm_get_register(vm_reg_name::VM_REG_GUEST_INTR_SHADOW)?;
+
+        // bhyve does not expose whether NMIs are blocked.
+        let mut events = PendingEvents {
+            interrupt_shadow: shadow != 0,
+            ..Default::default()
+        };
+
+        // The event whose delivery was interrupted by the last exit and the pending exception,
+        // where the error code is stored in the upper 32 bits.
+        for info in [args.info1, args.info2] {
+            // Check if the interruption information is valid.
+            if info & (1 << 31) == 0 {
+                continue;
+            }
+
+            let vector = (info & 0xff) as u8;
+
+            match (info >> 8) & 0x7 {
+                0 => events.interrupt = Some(vector),
+                2 => events.nmi_pending = true,
+                3 => {
+                    events.exception = Some(PendingException {
+                        vector,
+                        error_code: match info & (1 << 11) {
+                            0 => None,
+                            _ => Some((info >> 32) as u32),
+                        },
+                    });
+                }
+                _ => (),
+            }
+        }
+
+        Ok(events)
+    }
+
+    pub fn set_pending_events(&mut self, events: &PendingEvents) -> Result<(), Error> {
+        // bhyve does not support blocking NMIs, and queues the interrupts in its local APIC.
+        if events.nmi_masked || !events.queued_interrupts.is_empty() {
+            return Err(Error::InvalidArgument);
+        }
+
+        // Only a single event can be injected upon VM entry.
+        let info = match (events.exception, events.interrupt, events.nmi_pending) {
+            (None, None, false) => 0,
+            (Some(exception), None, false) => {
+                let mut info = (1 << 31) | (3 << 8) | exception.vector as u64;
+
+                if let Some(error_code) = exception.error_code {
+                    info |= (1 << 11) | ((error_code as u64) << 32);
+                }
+
+                info
+            }
+            (None, Some(vector), false) => (1 << 31) | vector as u64,
+            (None, None, true) => (1 << 31) | (2 << 8) | 2,
+            _ => return Err(Error::InvalidArgument),
+        };
+
+        let args = vm_intinfo {
+            cpuid: self.cpuid,
+            info1: info,
+            info2: 0,
+        };
+
+        unsafe {
+            vm_set_intinfo(self.file.as_raw_fd(), &args)
+        }?;
+
+        // bhyve only supports clearing the interrupt shadow.
+        self.vm_set_register(
+            vm_reg_name::VM_REG_GUEST_INTR_SHADOW,
+            events.interrupt_shadow as u64,
+        )
+    }
+
+    pub fn get_xsave(&self) -> Result<Vec<u8>, Error> {
+        Err(Error::NotImplemented)
+    }
+
+    pub fn set_xsave(&mut self, _bytes: &[u8]) -> Result<(), Error> {
+        Err(Error::NotImplemented)
+    }
+
+    pub fn get_xcr0(&self) -> Result<u64, Error> {
+        Err(Error::NotImplemented)
+    }
+
+    pub fn set_xcr0(&mut self, _value: u64) -> Result<(), Error> {
+        Err(Error::NotImplemented)
+    }
+
+    pub fn get_tsc(&self) -> Result<u64, Error> {
+        Err(Error::NotImplemented)
+    }
+
+    pub fn set_tsc(&mut self, _value: u64) -> Result<(), Error> {
+        Err(Error::NotImplemented)
+    }
+
+    pub fn get_tsc_offset(&self) -> Result<u64, Error> {
+        Err(Error::NotImplemented)
+    }
+
+    pub fn set_tsc_offset(&mut self, _offset: u64) -> Result<(), Error> {
+        Err(Error::NotImplemented)
+    }
+
+    pub fn get_tsc_khz(&self) -> Result<u32, Error> {
+        Err(Error::NotImplemented)
+    }
+
+    pub fn set_tsc_khz(&mut self, _khz: u32) -> Result<(), Error> {
+        Err(Error::NotImplemented)
+    }
+
+    pub fn set_tpr_threshold(&mut self, _threshold: u8) -> Result<(), Error> {
+        Err(Error::NotImplemented)
+    }
+
+    pub fn set_virtual_apic_page(&mut self, _gpa: u64) -> Result<(), Error> {
+        Err(Error::NotImplemented)
+    }
+
+    pub fn request_interrupt_window(&mut self) -> Result<(), Error> {
+        Err(Error::NotImplemented)
+    }
+
+    pub fn inject_interrupt(&mut self, vector: u8) -> Result<(), Error> {
+        let args = vm_lapic_irq {
+            cpuid: self.cpuid,
+            vector: vector as i32,
+        };
+
+        // The local APIC emulated by bhyve delivers the interrupt once the guest is able to accept
+        // it.
+        unsafe {
+            vm_lapic_irq(self.file.as_raw_fd(), &args)
+        }?;
+
+        Ok(())
+    }
+
+    pub fn inject_exception(&mut self, vector: u8, error_code: Option<u32>) -> Result<(), Error> {
+        let args = vm_exception {
+            cpuid: self.cpuid,
+            vector: vector as i32,
+            error_code: error_code.unwrap_or(0),
+            error_code_valid: error_code.is_some() as i32,
+            // Deliver the exception as a fault, i.e. before the current instruction.
+            restart_instruction: 1,
+        };
+
+        unsafe {
+            vm_inject_exception(self.file.as_raw_fd(), &args)
+        }?;
+
+        Ok(())
+    }
+
+    pub fn inject_nmi(&mut self) -> Result<(), Error> {
+        let args = vm_nmi {
+            cpuid: self.cpuid,
+        };
+
+        unsafe {
+            vm_inject_nmi(self.file.as_raw_fd(), &args)
+        }?;
+
+        Ok(())
+    }
+
+    pub fn complete_cpuid(&mut self, _result: &CpuidResult) -> Result<(), Error> {
+        Err(Error::NotImplemented)
+    }
+
+    pub fn set_cpuid(&mut self, _entries: &[CpuidEntry]) -> Result<(), Error> {
+        // bhyve serves the CPUID of the guest in the kernel.
+        Err(Error::NotImplemented)
+    }
+
+    pub fn set_exception_exits(&mut self, vectors: u32) -> Result<(), Error> {
+        if vectors != 0 {
+            return Err(Error::NotImplemented);
+        }
+
+        Ok(())
+    }
+
+    pub fn complete_msr_read(&mut self, value: u64) -> Result<(), Error> {
+        if !std::mem::take(&mut self.pending_msr_read) {
+            return Err(Error::InvalidArgument);
+        }
+
+        self.vm_set_register(vm_reg_name::VM_REG_GUEST_RAX, value & 0xffff_ffff)?;
+        self.vm_set_register(vm_reg_name::VM_REG_GUEST_RDX, value >> 32)?;
+
+        Ok(())
+    }
+}
+
+/// Helper function to map the [`DebugRegister`] to the register of bhyve.
+#[cfg(target_arch = "x86_64")]
+fn debug_register(register: DebugRegister) -> vm_reg_name {
+    match register {
+        DebugRegister::Dr0 => vm_reg_name::VM_REG_GUEST_DR0,
+        DebugRegister::Dr1 => vm_reg_name::VM_REG_GUEST_DR1,
+        DebugRegister::Dr2 => vm_reg_name::VM_REG_GUEST_DR2,
+        DebugRegister::Dr3 => vm_reg_name::VM_REG_GUEST_DR3,
+        DebugRegister::Dr6 => vm_reg_name::VM_REG_GUEST_DR6,
+        DebugRegister::Dr7 => vm_reg_name::VM_REG_GUEST_DR7,
+    }
+}
+
 #[cfg(target_arch = "x86_64")]
 impl CpuRegs for Vcpu {
     fn get_registers(
@@ -190,7 +563,7 @@ impl CpuRegs for Vcpu {
             let regnum = match *register {
                 ControlRegister::Cr0 => Some(vm_reg_name::VM_REG_GUEST_CR0),
                 ControlRegister::Cr1 => None,
-                ControlRegister::Cr2 => None,
+                ControlRegister::Cr2 => Some(vm_reg_name::VM_REG_GUEST_CR2),
                 ControlRegister::Cr3 => Some(vm_reg_name::VM_REG_GUEST_CR3),
                 ControlRegister::Cr4 => Some(vm_reg_name::VM_REG_GUEST_CR4),
                 ControlRegister::Cr8 => None,
@@ -217,7 +590,7 @@ impl CpuRegs for Vcpu {
             let regnum = match *register {
                 ControlRegister::Cr0 => vm_reg_name::VM_REG_GUEST_CR0,
                 ControlRegister::Cr1 => continue,
-                ControlRegister::Cr2 => continue,
+                ControlRegister::Cr2 => vm_reg_name::VM_REG_GUEST_CR2,
                 ControlRegister::Cr3 => vm_reg_name::VM_REG_GUEST_CR3,
                 ControlRegister::Cr4 => vm_reg_name::VM_REG_GUEST_CR4,
                 ControlRegister::Cr8 => continue,
@@ -389,4 +762,37 @@ impl CpuRegs for Vcpu {
 
         Ok(())
     }
+
+    fn get_debug_registers(
+        &self,
+        registers: &[DebugRegister],
+    ) -> Result<Vec<u64>, Error> {
+        let mut values = vec![];
+
+        for register in registers {
+            values.push(self.vm_get_register(debug_register(*register))?);
+        }
+
+        Ok(values)
+    }
+
+    fn set_debug_registers(
+        &mut self,
+        registers: &[DebugRegister],
+        values: &[u64],
+    ) -> Result<(), Error> {
+        for (register, value) in registers.iter().zip(values.iter()) {
+            self.vm_set_register(debug_register(*register), *value)?;
+        }
+
+        Ok(())
+    }
+
+    fn get_fpu(&self) -> Result<FpuState, Error> {
+        Err(Error::NotImplemented)
+    }
+
+    fn set_fpu(&mut self, _state: &FpuState) -> Result<(), Error> {
+        Err(Error::NotImplemented)
+    }
 }
@@ -1,21 +1,118 @@
+use crate::arch::x86_64::{CrExits, MsrPolicy};
+use crate::confidential::{LaunchMeasurement, SevPolicy};
 use crate::error::Error;
+use crate::pit::PitReinjection;
+use crate::synic::SyntheticMessage;
+use crate::tsc::TscMode;
 use crate::mmap::MmapMut;
-use crate::vm::ProtectionFlags;
+use crate::vm::{DirtyBitmap, MemoryBackingKind, MissingPageHandler, ProtectionFlags};
+#[cfg(feature = "xen")]
+use crate::xen::XenConfig;
 use mmap_rs::MmapOptions;
 use std::fs::{File, OpenOptions};
+use std::ops::Range;
 use std::os::unix::io::AsRawFd;
 use std::path::PathBuf;
 
 use super::bindings::*;
 use super::vcpu::Vcpu;
 
-pub struct VmBuilder;
+pub fn create_shared_memory(_size: usize) -> Result<File, Error> {
+    Err(Error::NotImplemented)
+}
+
+pub struct VmBuilder {
+    hlt_exiting: bool,
+    msr_exits: bool,
+}
 
 impl VmBuilder {
     pub fn with_vcpu_count(self, _count: usize) -> Result<Self, Error> {
         Ok(self)
     }
 
+    pub fn with_tsc_mode(self, mode: TscMode) -> Result<Self, Error> {
+        // bhyve does not support intercepting `rdtsc`.
+        match mode {
+            TscMode::Native => Ok(self),
+            _ => Err(Error::NotImplemented),
+        }
+    }
+
+    pub fn with_protected_guest(self, enabled: bool) -> Result<Self, Error> {
+        if enabled {
+            return Err(Error::NotImplemented);
+        }
+
+        Ok(self)
+    }
+
+    pub fn with_synthetic_interrupts(self, enabled: bool) -> Result<Self, Error> {
+        // bhyve does not provide a SynIC.
+        if enabled {
+            return Err(Error::NotImplemented);
+        }
+
+        Ok(self)
+    }
+
+    pub fn with_la57(self, _enabled: bool) -> Result<Self, Error> {
+        Err(Error::NotImplemented)
+    }
+
+    pub fn with_hlt_exiting(self, enabled: bool) -> Result<Self, Error> {
+        Ok(Self {
+            hlt_exiting: enabled,
+            ..self
+        })
+    }
+
+    pub fn with_cpuid_exits(self, enabled: bool) -> Result<Self, Error> {
+        // bhyve handles `cpuid` in the kernel.
+        if enabled {
+            return Err(Error::NotImplemented);
+        }
+
+        Ok(self)
+    }
+
+    pub fn with_msr_exits(self, enabled: bool) -> Result<Self, Error> {
+        // bhyve always exits on the MSRs it does not handle itself, so this only selects whether
+        // the exits are reported.
+        Ok(Self {
+            msr_exits: enabled,
+            ..self
+        })
+    }
+
+    pub fn with_in_kernel_irqchip(self, _enabled: bool) -> Result<Self, Error> {
+        // bhyve always emulates the PIC, the I/O APIC and the local APICs in the kernel.
+        Ok(self)
+    }
+
+    pub fn with_in_kernel_pit(self, _reinjection: PitReinjection) -> Result<Self, Error> {
+        // bhyve always emulates the PIT in the kernel, without reinjecting the missed ticks.
+        Ok(self)
+    }
+
+    pub fn with_sev(self, _policy: SevPolicy) -> Result<Self, Error> {
+        Err(Error::NotImplemented)
+    }
+
+    pub fn with_cr_exits(self, exits: CrExits) -> Result<Self, Error> {
+        // bhyve handles the control register accesses in the kernel.
+        if !exits.is_empty() {
+            return Err(Error::NotImplemented);
+        }
+
+        Ok(self)
+    }
+
+    #[cfg(feature = "xen")]
+    pub fn with_xen(self, _config: XenConfig) -> Result<Self, Error> {
+        Err(Error::NotImplemented)
+    }
+
     pub fn build(self, name: &str) -> Result<Vm, Error> {
         vm_create(name)?;
 
@@ -32,6 +129,8 @@ impl VmBuilder {
         Ok(Vm {
             name: name.to_string(),
             file,
+            hlt_exiting: self.hlt_exiting,
+            msr_exits: self.msr_exits,
         })
     }
 }
@@ -39,14 +138,31 @@ impl VmBuilder {
 pub struct Vm {
     name: String,
     file: File,
+    hlt_exiting: bool,
+    msr_exits: bool,
 }
 
 impl Vm {
     pub fn create_vcpu(&mut self, id: usize) -> Result<Vcpu, Error> {
+        // bhyve only exits on `hlt` if the capability is set.
+        let args = vm_capability {
+            cpuid: id as i32,
+            captype: vm_cap_type::VM_CAP_HALT_EXIT,
+            capval: self.hlt_exiting as i32,
+            allcpus: 0,
+        };
+
+        unsafe {
+            vm_set_capability(self.file.as_raw_fd(), &args)
+        }?;
+
         Ok(Vcpu {
             cpuid: id as i32,
             file: self.file.try_clone()?,
             rip: 0,
+            msr_exits: self.msr_exits,
+            pending_msr_read: false,
+            immediate_exit: false,
         })
     }
 
@@ -77,14 +193,32 @@ impl Vm {
         })
     }
 
-    pub unsafe fn map_physical_memory(
+    pub fn map_physical_memory(
         &mut self,
-        guest_address: u64,
-        bytes: *mut std::ffi::c_void,
-        size: usize,
-        protection: ProtectionFlags,
+        _guest_address: u64,
+        _mapping: mmap_rs::MmapMut,
+        _protection: ProtectionFlags,
+        _backing: MemoryBackingKind,
     ) -> Result<(), Error> {
-        Ok(())
+        Err(Error::NotImplemented)
+    }
+
+    pub fn regions(
+        &self,
+    ) -> Result<Vec<(Range<u64>, ProtectionFlags, MemoryBackingKind)>, Error> {
+        Err(Error::NotImplemented)
+    }
+
+    pub fn protection(&self, _guest_address: u64) -> Option<ProtectionFlags> {
+        None
+    }
+
+    pub fn set_dirty_tracking(&mut self, _enabled: bool) -> Result<(), Error> {
+        Err(Error::NotImplemented)
+    }
+
+    pub fn dirty_bitmap(&self, _guest_address: u64) -> Result<DirtyBitmap, Error> {
+        Err(Error::NotImplemented)
     }
 
     pub fn unmap_physical_memory(
@@ -94,6 +228,30 @@ impl Vm {
         Ok(())
     }
 
+    pub fn take_physical_memory(
+        &mut self,
+        _guest_address: u64,
+    ) -> Result<(MmapMut, ProtectionFlags, MemoryBackingKind), Error> {
+        Err(Error::NotImplemented)
+    }
+
+    pub fn remap_physical_memory(
+        &mut self,
+        _guest_address: u64,
+        _new_guest_address: u64,
+    ) -> Result<(), Error> {
+        Err(Error::NotImplemented)
+    }
+
+    pub fn resize_physical_memory(
+        &mut self,
+        _guest_address: u64,
+        _size: usize,
+        _backing_file: Option<(File, u64)>,
+    ) -> Result<(), Error> {
+        Err(Error::NotImplemented)
+    }
+
     pub fn protect_physical_memory(
         &mut self,
         guest_address: u64,
@@ -101,6 +259,162 @@ impl Vm {
     ) -> Result<(), Error> {
         Ok(())
     }
+
+    pub fn protect_physical_memory_range(
+        &mut self,
+        _range: Range<u64>,
+        _protection: ProtectionFlags,
+    ) -> Result<(), Error> {
+        Err(Error::NotImplemented)
+    }
+
+    pub fn host_address(
+        &self,
+        _guest_address: u64,
+        _size: usize,
+    ) -> Result<*mut u8, Error> {
+        Err(Error::NotImplemented)
+    }
+
+    pub fn missing_page_handler(&mut self, _handler: MissingPageHandler) -> Result<(), Error> {
+        Err(Error::NotImplemented)
+    }
+
+    pub fn reclaim(&self, _range: Range<u64>) -> Result<(), Error> {
+        Err(Error::NotImplemented)
+    }
+
+    pub fn reinflate(&self, _range: Range<u64>) -> Result<(), Error> {
+        Err(Error::NotImplemented)
+    }
+
+    pub fn resident_size(&self) -> Result<usize, Error> {
+        Err(Error::NotImplemented)
+    }
+
+    pub fn trim_resident_size(&self, _limit: usize) -> Result<usize, Error> {
+        Err(Error::NotImplemented)
+    }
+
+    pub fn allocate_smram(
+        &mut self,
+        _guest_address: u64,
+        _size: usize,
+    ) -> Result<(), Error> {
+        Err(Error::NotImplemented)
+    }
+
+    pub fn read_smram(
+        &self,
+        _bytes: &mut [u8],
+        _guest_address: u64,
+    ) -> Result<usize, Error> {
+        Err(Error::NotImplemented)
+    }
+
+    pub fn write_smram(
+        &mut self,
+        _guest_address: u64,
+        _bytes: &[u8],
+    ) -> Result<usize, Error> {
+        Err(Error::NotImplemented)
+    }
+
+    pub fn post_synthetic_message(
+        &self,
+        _vcpu_id: usize,
+        _sint: u8,
+        _message: &SyntheticMessage,
+    ) -> Result<(), Error> {
+        Err(Error::NotImplemented)
+    }
+
+    pub fn signal_synthetic_event(
+        &self,
+        _vcpu_id: usize,
+        _sint: u8,
+        _flag: u16,
+    ) -> Result<bool, Error> {
+        Err(Error::NotImplemented)
+    }
+
+    pub fn sev_launch_update_data(
+        &self,
+        _guest_address: u64,
+        _size: usize,
+    ) -> Result<(), Error> {
+        Err(Error::NotImplemented)
+    }
+
+    pub fn sev_launch_update_vmsa(&self) -> Result<(), Error> {
+        Err(Error::NotImplemented)
+    }
+
+    pub fn sev_launch_measure(&self) -> Result<LaunchMeasurement, Error> {
+        Err(Error::NotImplemented)
+    }
+
+    pub fn sev_launch_finish(&self) -> Result<(), Error> {
+        Err(Error::NotImplemented)
+    }
+
+    pub fn set_msr_filter(
+        &mut self,
+        _ranges: &[Range<u32>],
+        _policy: MsrPolicy,
+    ) -> Result<(), Error> {
+        // bhyve handles the MSRs in the kernel.
+        Err(Error::NotImplemented)
+    }
+
+    pub fn set_irq_line(&self, gsi: u32, level: bool) -> Result<(), Error> {
+        let fd = self.file.as_raw_fd();
+
+        // The ISA interrupts are routed to both the PIC and the I/O APIC.
+        if gsi < 16 {
+            let args = vm_isa_irq {
+                atpic_irq: gsi as i32,
+                ioapic_irq: gsi as i32,
+            };
+
+            if level {
+                unsafe { vm_isa_assert_irq(fd, &args) }?;
+            } else {
+                unsafe { vm_isa_deassert_irq(fd, &args) }?;
+            }
+        } else {
+            let args = vm_ioapic_irq {
+                irq: gsi as i32,
+            };
+
+            if level {
+                unsafe { vm_ioapic_assert_irq(fd, &args) }?;
+            } else {
+                unsafe { vm_ioapic_deassert_irq(fd, &args) }?;
+            }
+        }
+
+        Ok(())
+    }
+
+    #[cfg(feature = "xen")]
+    pub fn set_xen_shared_info(&mut self, _gfn: u64) -> Result<(), Error> {
+        Err(Error::NotImplemented)
+    }
+
+    #[cfg(feature = "xen")]
+    pub fn set_xen_upcall_vector(&mut self, _vector: u8) -> Result<(), Error> {
+        Err(Error::NotImplemented)
+    }
+
+    #[cfg(feature = "xen")]
+    pub fn send_xen_event(
+        &self,
+        _port: u32,
+        _vcpu_id: usize,
+    ) -> Result<(), Error> {
+        Err(Error::NotImplemented)
+    }
 }
 
 impl Drop for Vm {
@@ -0,0 +1,534 @@
+//! Bindings for the KVM ioctls that are not (yet) wrapped by the [`kvm_ioctls`] crate.
+
+#![allow(dead_code)]
+
+use std::os::unix::io::RawFd;
+
+pub const KVMIO: u32 = 0xae;
+
+/// Encodes an ioctl number without any arguments, i.e. `_IO(type, nr)`.
+pub const fn io(ty: u32, nr: u32) -> u32 {
+    (ty << 8) | nr
+}
+
+/// Encodes an ioctl number that passes an argument of the given size to the kernel, i.e.
+/// `_IOW(type, nr, size)`.
+pub const fn iow(ty: u32, nr: u32, size: usize) -> u32 {
+    (1 << 30) | ((size as u32) << 16) | (ty << 8) | nr
+}
+
+/// Encodes an ioctl number that passes an argument of the given size from the kernel, i.e.
+/// `_IOR(type, nr, size)`.
+pub const fn ior(ty: u32, nr: u32, size: usize) -> u32 {
+    (2 << 30) | ((size as u32) << 16) | (ty << 8) | nr
+}
+
+/// Encodes an ioctl number that passes an argument of the given size to and from the kernel, i.e.
+/// `_IOWR(type, nr, size)`.
+pub const fn iowr(ty: u32, nr: u32, size: usize) -> u32 {
+    (3 << 30) | ((size as u32) << 16) | (ty << 8) | nr
+}
+
+/// Checks whether the given capability is supported. Returns a capability-specific value.
+pub const KVM_CHECK_EXTENSION: u32 = io(KVMIO, 0x03);
+
+/// Sets up whether the in-kernel PIT reinjects the ticks that the guest missed.
+pub const KVM_REINJECT_CONTROL: u32 = io(KVMIO, 0x71);
+
+/// Injects an SMI into the virtual CPU.
+pub const KVM_SMI: u32 = io(KVMIO, 0xb7);
+
+/// Queues an external interrupt for the virtual CPU when the interrupt controller is emulated in
+/// user space.
+pub const KVM_INTERRUPT: u32 = iow(KVMIO, 0x86, std::mem::size_of::<u32>());
+
+/// Injects an NMI into the virtual CPU.
+pub const KVM_NMI: u32 = io(KVMIO, 0x9a);
+
+/// Sets the guest physical address of the virtual-APIC page used for TPR access reporting.
+pub const KVM_SET_VAPIC_ADDR: u32 = iow(KVMIO, 0x93, std::mem::size_of::<u64>());
+
+/// Configures the debugging features of the virtual CPU.
+pub const KVM_SET_GUEST_DEBUG: u32 =
+    iow(KVMIO, 0x9b, std::mem::size_of::<kvm_bindings::kvm_guest_debug>());
+
+/// The offsets of the fields of `kvm_run.debug.arch` on x86, relative to the start of `kvm_run`.
+pub const KVM_RUN_DEBUG_EXCEPTION: usize = KVM_RUN_EXIT_OFFSET;
+pub const KVM_RUN_DEBUG_PC:        usize = KVM_RUN_EXIT_OFFSET + 8;
+pub const KVM_RUN_DEBUG_DR6:       usize = KVM_RUN_EXIT_OFFSET + 16;
+
+/// Sets the TSC frequency of the virtual CPU in kHz.
+pub const KVM_SET_TSC_KHZ: u32 = io(KVMIO, 0xa2);
+
+/// Gets the TSC frequency of the virtual CPU in kHz.
+pub const KVM_GET_TSC_KHZ: u32 = io(KVMIO, 0xa3);
+
+/// Sets and gets the attributes of a device, which includes the virtual CPU itself.
+pub const KVM_SET_DEVICE_ATTR: u32 =
+    iow(KVMIO, 0xe1, std::mem::size_of::<kvm_bindings::kvm_device_attr>());
+pub const KVM_GET_DEVICE_ATTR: u32 =
+    iow(KVMIO, 0xe2, std::mem::size_of::<kvm_bindings::kvm_device_attr>());
+
+/// The attribute group and the attribute of the TSC offset of the virtual CPU.
+pub const KVM_VCPU_TSC_CTRL:   u32 = 0;
+pub const KVM_VCPU_TSC_OFFSET: u64 = 0;
+
+/// The bit in the memory slot number that selects the SMM address space.
+pub const KVM_SMM_ADDRESS_SPACE: u32 = 1 << 16;
+
+/// Issues an ioctl without any arguments.
+pub unsafe fn ioctl(fd: RawFd, request: u32) -> Result<(), std::io::Error> {
+    let result = libc::ioctl(fd, request as _);
+
+    if result < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+
+    Ok(())
+}
+
+/// Issues an ioctl with the given integer argument and returns the result.
+pub unsafe fn ioctl_with_val(
+    fd: RawFd,
+    request: u32,
+    arg: libc::c_ulong,
+) -> Result<i32, std::io::Error> {
+    let result = libc::ioctl(fd, request as _, arg);
+
+    if result < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+
+    Ok(result)
+}
+
+/// Issues an ioctl that passes a reference to the given argument to the kernel.
+pub unsafe fn ioctl_with_ref<T>(
+    fd: RawFd,
+    request: u32,
+    arg: &T,
+) -> Result<(), std::io::Error> {
+    let result = libc::ioctl(fd, request as _, arg as *const T);
+
+    if result < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+
+    Ok(())
+}
+
+pub unsafe fn ioctl_with_mut_ref<T>(
+    fd: RawFd,
+    request: u32,
+    arg: &mut T,
+) -> Result<(), std::io::Error> {
+    let result = libc::ioctl(fd, request as _, arg as *mut T);
+
+    if result < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+
+    Ok(())
+}
+
+/// The offset of the exit reason specific data in the `kvm_run` structure.
+pub const KVM_RUN_EXIT_OFFSET: usize = 32;
+
+/// The offset of `kvm_run.request_interrupt_window`, which requests an exit as soon as the guest
+/// can accept interrupts.
+pub const KVM_RUN_REQUEST_INTERRUPT_WINDOW: usize = 0;
+
+/// The offset of `kvm_run.immediate_exit`, which makes `KVM_RUN` fail with `EINTR` rather than
+/// entering the guest when set.
+pub const KVM_RUN_IMMEDIATE_EXIT: usize = 1;
+
+/// The offset of `kvm_run.ready_for_interrupt_injection`, which indicates whether an interrupt
+/// can be injected through `KVM_INTERRUPT` upon the next entry.
+pub const KVM_RUN_READY_FOR_INTERRUPT_INJECTION: usize = 12;
+
+/// The offset of `kvm_run.apic_base`, which KVM updates with the value of the `IA32_APIC_BASE`
+/// MSR upon every exit.
+pub const KVM_RUN_APIC_BASE: usize = 24;
+
+/// The offset of `kvm_run.io.data_offset`, which is the offset of the data of the port I/O exit
+/// relative to the start of `kvm_run`.
+pub const KVM_RUN_IO_DATA_OFFSET: usize = KVM_RUN_EXIT_OFFSET + 8;
+
+/// The offset of `kvm_run.mmio.data`, which holds the data of the MMIO exit.
+pub const KVM_RUN_MMIO_DATA: usize = KVM_RUN_EXIT_OFFSET + 8;
+
+/// A mapping of the `kvm_run` structure of a virtual CPU, which is used to access the fields that
+/// the [`kvm_ioctls`] crate does not expose.
+pub struct KvmRun {
+    ptr: *mut u8,
+    size: usize,
+}
+
+// The mapping is owned by the virtual CPU and only accessed through it.
+unsafe impl Send for KvmRun {}
+
+impl KvmRun {
+    /// Maps the `kvm_run` structure of the virtual CPU with the given file descriptor.
+    pub fn new(fd: RawFd) -> Result<Self, std::io::Error> {
+        let size = std::mem::size_of::<kvm_bindings::kvm_run>();
+
+        let ptr = unsafe {
+            libc::mmap(
+                std::ptr::null_mut(),
+                size,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_SHARED,
+                fd,
+                0,
+            )
+        };
+
+        if ptr == libc::MAP_FAILED {
+            return Err(std::io::Error::last_os_error());
+        }
+
+        Ok(Self {
+            ptr: ptr as *mut u8,
+            size,
+        })
+    }
+
+    /// Returns a pointer to the `kvm_run` structure.
+    pub fn as_ptr(&self) -> *mut u8 {
+        self.ptr
+    }
+
+    /// Reads the value at the given offset in the `kvm_run` structure.
+    pub unsafe fn read<T: Copy>(&self, offset: usize) -> T {
+        std::ptr::read_volatile(self.ptr.add(offset) as *const T)
+    }
+
+    /// Writes the value at the given offset in the `kvm_run` structure.
+    pub unsafe fn write<T: Copy>(&mut self, offset: usize, value: T) {
+        std::ptr::write_volatile(self.ptr.add(offset) as *mut T, value)
+    }
+}
+
+impl Drop for KvmRun {
+    fn drop(&mut self) {
+        unsafe {
+            libc::munmap(self.ptr as *mut libc::c_void, self.size);
+        }
+    }
+}
+
+/// The VM types supported by KVM on x86, as a bitmask.
+pub const KVM_CAP_VM_TYPES: u32 = 235;
+/// The software-protected VM type on x86.
+pub const KVM_X86_SW_PROTECTED_VM: u64 = 1;
+
+/// Protected VM support through pKVM on AArch64.
+pub const KVM_CAP_ARM_PROTECTED_VM: u32 = 0xffba_dab1;
+/// The protected VM type on AArch64.
+pub const KVM_VM_TYPE_ARM_PROTECTED: u64 = 1 << 31;
+
+/// Xen HVM support.
+pub const KVM_CAP_XEN_HVM: u32 = 38;
+
+pub const KVM_XEN_HVM_CONFIG_HYPERCALL_MSR:   u32 = 1 << 0;
+pub const KVM_XEN_HVM_CONFIG_INTERCEPT_HCALL: u32 = 1 << 1;
+pub const KVM_XEN_HVM_CONFIG_SHARED_INFO:     u32 = 1 << 2;
+pub const KVM_XEN_HVM_CONFIG_EVTCHN_SEND:     u32 = 1 << 5;
+
+#[allow(non_camel_case_types)]
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+pub struct kvm_xen_hvm_config {
+    pub flags: u32,
+    pub msr: u32,
+    pub blob_addr_32: u64,
+    pub blob_addr_64: u64,
+    pub blob_size_32: u8,
+    pub blob_size_64: u8,
+    pub pad2: [u8; 30],
+}
+
+pub const KVM_XEN_ATTR_TYPE_LONG_MODE:      u16 = 0x0;
+pub const KVM_XEN_ATTR_TYPE_SHARED_INFO:    u16 = 0x1;
+pub const KVM_XEN_ATTR_TYPE_UPCALL_VECTOR:  u16 = 0x2;
+
+/// The attribute passed to `KVM_XEN_HVM_SET_ATTR` and `KVM_XEN_VCPU_SET_ATTR`. The union of the
+/// kernel is represented by its largest member, i.e. eight 64-bit words, of which the first word
+/// holds the value of the attributes used here.
+#[allow(non_camel_case_types)]
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+pub struct kvm_xen_attr {
+    pub ty: u16,
+    pub pad: [u16; 3],
+    pub u: [u64; 8],
+}
+
+pub const KVM_XEN_VCPU_ATTR_TYPE_VCPU_INFO: u16 = 0x1;
+
+#[allow(non_camel_case_types)]
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+pub struct kvm_irq_routing_xen_evtchn {
+    pub port: u32,
+    pub vcpu: u32,
+    pub priority: u32,
+}
+
+/// Delivers the event through the 2-level event channel ABI.
+pub const KVM_IRQ_ROUTING_XEN_EVTCHN_PRIO_2LEVEL: u32 = u32::MAX;
+
+pub const KVM_XEN_HVM_CONFIG: u32 =
+    iow(KVMIO, 0x7a, std::mem::size_of::<kvm_xen_hvm_config>());
+pub const KVM_XEN_HVM_SET_ATTR: u32 =
+    iow(KVMIO, 0xc9, std::mem::size_of::<kvm_xen_attr>());
+pub const KVM_XEN_VCPU_SET_ATTR: u32 =
+    iow(KVMIO, 0xcb, std::mem::size_of::<kvm_xen_attr>());
+pub const KVM_XEN_HVM_EVTCHN_SEND: u32 =
+    iow(KVMIO, 0xd0, std::mem::size_of::<kvm_irq_routing_xen_evtchn>());
+
+/// Disables the exits of the virtual CPUs, where the argument selects the exits. This has to be
+/// enabled before creating any virtual CPU.
+pub const KVM_CAP_X86_DISABLE_EXITS: u32 = 143;
+
+/// Lets the virtual CPU execute `hlt` without exiting.
+pub const KVM_X86_DISABLE_EXITS_HLT: u32 = 1 << 1;
+
+/// Exits to user space upon MSR accesses, where the argument selects the reasons.
+pub const KVM_CAP_X86_USER_SPACE_MSR: u32 = 188;
+
+/// Exits upon accesses to MSRs that KVM does not know about.
+pub const KVM_MSR_EXIT_REASON_UNKNOWN: u32 = 1 << 1;
+/// Exits upon accesses to MSRs that are denied by the MSR filter.
+pub const KVM_MSR_EXIT_REASON_FILTER:  u32 = 1 << 2;
+
+/// The MSRs that are not in any range of the filter are allowed or denied by default.
+pub const KVM_MSR_FILTER_DEFAULT_ALLOW: u32 = 0;
+pub const KVM_MSR_FILTER_DEFAULT_DENY:  u32 = 1 << 0;
+
+/// The range of the filter applies to reads, writes or both.
+pub const KVM_MSR_FILTER_READ:  u32 = 1 << 0;
+pub const KVM_MSR_FILTER_WRITE: u32 = 1 << 1;
+
+/// The maximum number of ranges of the filter.
+pub const KVM_MSR_FILTER_MAX_RANGES: usize = 16;
+
+/// The maximum size of the bitmap of a single range in bytes.
+pub const KVM_MSR_FILTER_MAX_BITMAP_SIZE: usize = 0x600;
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct kvm_msr_filter_range {
+    pub flags: u32,
+    pub nmsrs: u32,
+    pub base: u32,
+    /// The bitmap with a bit per MSR, where a set bit allows KVM to handle the MSR.
+    pub bitmap: *mut u8,
+}
+
+impl Default for kvm_msr_filter_range {
+    fn default() -> Self {
+        Self {
+            flags: 0,
+            nmsrs: 0,
+            base: 0,
+            bitmap: std::ptr::null_mut(),
+        }
+    }
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+pub struct kvm_msr_filter {
+    pub flags: u32,
+    pub ranges: [kvm_msr_filter_range; KVM_MSR_FILTER_MAX_RANGES],
+}
+
+/// Sets the MSR filter of the VM.
+pub const KVM_X86_SET_MSR_FILTER: u32 =
+    iow(KVMIO, 0xc6, std::mem::size_of::<kvm_msr_filter>());
+
+/// The exit reasons for MSR accesses that are handled by the VMM.
+pub const KVM_EXIT_X86_RDMSR: u32 = 29;
+pub const KVM_EXIT_X86_WRMSR: u32 = 30;
+
+/// The offsets of the fields of `kvm_run.msr`, relative to the start of `kvm_run`.
+pub const KVM_RUN_MSR_ERROR: usize = KVM_RUN_EXIT_OFFSET;
+pub const KVM_RUN_MSR_INDEX: usize = KVM_RUN_EXIT_OFFSET + 12;
+pub const KVM_RUN_MSR_DATA:  usize = KVM_RUN_EXIT_OFFSET + 16;
+
+/// The exit reason for Xen hypercalls that are intercepted by the VMM.
+pub const KVM_EXIT_XEN: u32 = 34;
+pub const KVM_EXIT_XEN_HCALL: u32 = 1;
+
+/// The offsets of the fields of `kvm_run.xen`, relative to the start of `kvm_run`.
+pub const KVM_RUN_XEN_TYPE:     usize = KVM_RUN_EXIT_OFFSET;
+pub const KVM_RUN_XEN_LONGMODE: usize = KVM_RUN_EXIT_OFFSET + 8;
+pub const KVM_RUN_XEN_CPL:      usize = KVM_RUN_EXIT_OFFSET + 12;
+pub const KVM_RUN_XEN_INPUT:    usize = KVM_RUN_EXIT_OFFSET + 16;
+pub const KVM_RUN_XEN_RESULT:   usize = KVM_RUN_EXIT_OFFSET + 24;
+pub const KVM_RUN_XEN_PARAMS:   usize = KVM_RUN_EXIT_OFFSET + 32;
+
+/// The device type of the in-kernel GICv3.
+pub const KVM_DEV_TYPE_ARM_VGIC_V3: u32 = 7;
+
+/// The attribute groups of the in-kernel GIC.
+pub const KVM_DEV_ARM_VGIC_GRP_ADDR:    u32 = 0;
+pub const KVM_DEV_ARM_VGIC_GRP_NR_IRQS: u32 = 3;
+pub const KVM_DEV_ARM_VGIC_GRP_CTRL:    u32 = 4;
+
+/// The attributes of the base addresses of the GICv3 distributor and redistributors.
+pub const KVM_VGIC_V3_ADDR_TYPE_DIST:   u64 = 2;
+pub const KVM_VGIC_V3_ADDR_TYPE_REDIST: u64 = 3;
+
+/// Initializes the in-kernel GIC once all the virtual CPUs have been created.
+pub const KVM_DEV_ARM_VGIC_CTRL_INIT: u64 = 0;
+
+/// The types of the system events that KVM reports when the guest calls the PSCI functions
+/// `SYSTEM_OFF` and `SYSTEM_RESET`.
+pub const KVM_SYSTEM_EVENT_SHUTDOWN: u32 = 1;
+pub const KVM_SYSTEM_EVENT_RESET:    u32 = 2;
+
+/// The IRQ type of the shared peripheral interrupts in the argument of `KVM_IRQ_LINE`.
+pub const KVM_ARM_IRQ_TYPE_SHIFT: u32 = 24;
+pub const KVM_ARM_IRQ_TYPE_SPI:   u32 = 1;
+
+/// The ioctl type of userfaultfd.
+pub const UFFDIO: u32 = 0xaa;
+
+/// The version of the userfaultfd API.
+pub const UFFD_API: u64 = 0xaa;
+
+/// Reports the faults on missing pages of the registered range.
+pub const UFFDIO_REGISTER_MODE_MISSING: u64 = 1 << 0;
+
+/// The event of a page fault on the registered range.
+pub const UFFD_EVENT_PAGEFAULT: u8 = 0x12;
+
+#[allow(non_camel_case_types)]
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+pub struct uffdio_api {
+    pub api: u64,
+    pub features: u64,
+    pub ioctls: u64,
+}
+
+#[allow(non_camel_case_types)]
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+pub struct uffdio_range {
+    pub start: u64,
+    pub len: u64,
+}
+
+#[allow(non_camel_case_types)]
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+pub struct uffdio_register {
+    pub range: uffdio_range,
+    pub mode: u64,
+    pub ioctls: u64,
+}
+
+#[allow(non_camel_case_types)]
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+pub struct uffdio_copy {
+    pub dst: u64,
+    pub src: u64,
+    pub len: u64,
+    pub mode: u64,
+    pub copy: i64,
+}
+
+/// The message read from userfaultfd. The union of the kernel is represented by the fields of a
+/// page fault, which is the only event reported for the ranges registered here.
+#[allow(non_camel_case_types)]
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+pub struct uffd_msg {
+    pub event: u8,
+    pub reserved1: u8,
+    pub reserved2: u16,
+    pub reserved3: u32,
+    pub flags: u64,
+    pub address: u64,
+    pub feat: u64,
+}
+
+pub const UFFDIO_API: u32 =
+    iowr(UFFDIO, 0x3f, std::mem::size_of::<uffdio_api>());
+pub const UFFDIO_REGISTER: u32 =
+    iowr(UFFDIO, 0x00, std::mem::size_of::<uffdio_register>());
+pub const UFFDIO_UNREGISTER: u32 =
+    ior(UFFDIO, 0x01, std::mem::size_of::<uffdio_range>());
+pub const UFFDIO_WAKE: u32 =
+    ior(UFFDIO, 0x02, std::mem::size_of::<uffdio_range>());
+pub const UFFDIO_COPY: u32 =
+    iowr(UFFDIO, 0x03, std::mem::size_of::<uffdio_copy>());
+
+/// The commands of the SEV API issued through `KVM_MEMORY_ENCRYPT_OP`.
+pub const KVM_SEV_INIT:               u32 = 0;
+pub const KVM_SEV_ES_INIT:            u32 = 1;
+pub const KVM_SEV_LAUNCH_START:       u32 = 2;
+pub const KVM_SEV_LAUNCH_UPDATE_DATA: u32 = 3;
+pub const KVM_SEV_LAUNCH_UPDATE_VMSA: u32 = 4;
+pub const KVM_SEV_LAUNCH_MEASURE:     u32 = 6;
+pub const KVM_SEV_LAUNCH_FINISH:      u32 = 7;
+
+/// The command passed to `KVM_MEMORY_ENCRYPT_OP`, where `data` points to the command-specific
+/// structure and `error` receives the error code of the SEV firmware.
+#[allow(non_camel_case_types)]
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+pub struct kvm_sev_cmd {
+    pub id: u32,
+    pub data: u64,
+    pub error: u32,
+    pub sev_fd: u32,
+}
+
+#[allow(non_camel_case_types)]
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+pub struct kvm_sev_launch_start {
+    pub handle: u32,
+    pub policy: u32,
+    pub dh_uaddr: u64,
+    pub dh_len: u32,
+    pub session_uaddr: u64,
+    pub session_len: u32,
+}
+
+#[allow(non_camel_case_types)]
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+pub struct kvm_sev_launch_update_data {
+    pub uaddr: u64,
+    pub len: u32,
+}
+
+#[allow(non_camel_case_types)]
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+pub struct kvm_sev_launch_measure {
+    pub uaddr: u64,
+    pub len: u32,
+}
+
+/// The range of host memory backing encrypted guest memory, which KVM pins.
+#[allow(non_camel_case_types)]
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+pub struct kvm_enc_region {
+    pub addr: u64,
+    pub size: u64,
+}
+
+pub const KVM_MEMORY_ENCRYPT_OP: u32 =
+    iowr(KVMIO, 0xba, std::mem::size_of::<libc::c_ulong>());
+pub const KVM_MEMORY_ENCRYPT_REG_REGION: u32 =
+    ior(KVMIO, 0xbb, std::mem::size_of::<kvm_enc_region>());
+pub const KVM_MEMORY_ENCRYPT_UNREG_REGION: u32 =
+    ior(KVMIO, 0xbc, std::mem::size_of::<kvm_enc_region>());
@@ -1,7 +1,33 @@
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+use crate::arch::x86_64::{CpuidEntry, CpuidResult};
 use crate::error::Error;
+use crate::hypervisor::Capability;
 use kvm_ioctls::Kvm;
+use std::os::unix::io::{AsRawFd, RawFd};
+use super::bindings::*;
 use super::vm::VmBuilder;
 
+/// The machine type used for protected guests.
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+pub(crate) const KVM_VM_TYPE_PROTECTED: u64 = KVM_X86_SW_PROTECTED_VM;
+#[cfg(target_arch = "aarch64")]
+pub(crate) const KVM_VM_TYPE_PROTECTED: u64 = KVM_VM_TYPE_ARM_PROTECTED;
+
+/// Helper function to check if KVM supports protected guests, using the given file descriptor to
+/// either `/dev/kvm` or a VM.
+pub(crate) fn protected_guest_supported(fd: RawFd) -> bool {
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    let result = unsafe {
+        ioctl_with_val(fd, KVM_CHECK_EXTENSION, KVM_CAP_VM_TYPES as _)
+    }.map(|types| types as u64 & (1 << KVM_VM_TYPE_PROTECTED) != 0);
+
+    #[cfg(target_arch = "aarch64")]
+    let result = unsafe {
+        ioctl_with_val(fd, KVM_CHECK_EXTENSION, KVM_CAP_ARM_PROTECTED_VM as _)
+    }.map(|supported| supported > 0);
+
+    result.unwrap_or(false)
+}
 pub struct Hypervisor {
     kvm: Kvm,
 }
@@ -13,11 +39,52 @@ impl Hypervisor {
         })
     }
 
+    pub fn has_capability(&self, capability: Capability) -> bool {
+        match capability {
+            Capability::ProtectedGuest =>
+                protected_guest_supported(self.kvm.as_raw_fd()),
+            // KVM handles the hypercalls of the guest itself.
+            Capability::Hypercalls => false,
+        }
+    }
+
     pub fn build_vm(&self) -> Result<VmBuilder, Error> {
         let vm = self.kvm.create_vm()?;
 
         Ok(VmBuilder {
             vm,
+            protected: false,
+            #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+            cpuid: None,
+            #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+            msr_exit_reasons: 0,
+            #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+            sev: None,
+            #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+            irqchip: false,
+            #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+            pit: None,
+            #[cfg(target_arch = "aarch64")]
+            gic: None,
+            #[cfg(feature = "xen")]
+            xen: None,
         })
     }
+
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    pub fn get_supported_cpuid(&self) -> Result<Vec<CpuidEntry>, Error> {
+        let cpuid = self.kvm.get_supported_cpuid(kvm_bindings::KVM_MAX_CPUID_ENTRIES)?;
+
+        Ok(cpuid.as_slice().iter().map(|entry| CpuidEntry {
+            leaf: entry.function,
+            subleaf: entry.index,
+            indexed: entry.flags & kvm_bindings::KVM_CPUID_FLAG_SIGNIFCANT_INDEX != 0,
+            result: CpuidResult {
+                eax: entry.eax,
+                ebx: entry.ebx,
+                ecx: entry.ecx,
+                edx: entry.edx,
+            },
+        }).collect())
+    }
 }
@@ -1,7 +1,12 @@
+pub mod bindings;
 pub mod hypervisor;
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+pub mod sev;
+pub mod thread;
+pub mod userfault;
 pub mod vcpu;
 pub mod vm;
 
 pub use hypervisor::Hypervisor;
 pub use vm::{Vm, VmBuilder};
-pub use vcpu::Vcpu;
+pub use vcpu::{Vcpu, VcpuHandle};
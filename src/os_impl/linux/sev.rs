@@ -0,0 +1,143 @@
+//! The launch flow of SEV and SEV-ES guests through `KVM_MEMORY_ENCRYPT_OP`.
+
+use crate::confidential::{
+    LaunchMeasurement, SevPolicy, LAUNCH_MEASUREMENT_SIZE, LAUNCH_NONCE_SIZE, SEV_DEVICE_PATH,
+};
+use crate::error::Error;
+use kvm_ioctls::VmFd;
+use std::fs::{File, OpenOptions};
+use std::os::unix::io::{AsRawFd, RawFd};
+use super::bindings::*;
+
+/// Helper function to check if KVM supports SEV for the VM with the given file descriptor, as
+/// `KVM_MEMORY_ENCRYPT_OP` without an argument only succeeds if memory encryption is enabled.
+pub(crate) fn sev_supported(fd: RawFd) -> bool {
+    unsafe {
+        ioctl_with_val(fd, KVM_MEMORY_ENCRYPT_OP, 0)
+    }.is_ok()
+}
+
+/// The SEV context of a VM.
+pub struct Sev {
+    /// The file descriptor to `/dev/sev`, which KVM uses to issue the firmware commands.
+    device: File,
+}
+
+impl Sev {
+    /// Initializes SEV or SEV-ES for the given VM, depending on the policy, and starts the launch
+    /// of the guest. This has to happen before creating any virtual CPU.
+    pub fn new(vm: &VmFd, policy: SevPolicy) -> Result<Self, Error> {
+        let device = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(SEV_DEVICE_PATH)?;
+
+        let sev = Self {
+            device,
+        };
+
+        let init = if policy.contains(SevPolicy::ES) {
+            KVM_SEV_ES_INIT
+        } else {
+            KVM_SEV_INIT
+        };
+
+        sev.command(vm, init, 0)?;
+
+        let mut start = kvm_sev_launch_start {
+            policy: policy.bits(),
+            ..Default::default()
+        };
+
+        sev.command(vm, KVM_SEV_LAUNCH_START, &mut start as *mut _ as u64)?;
+
+        Ok(sev)
+    }
+
+    /// Helper function to issue the given command with a pointer to the command-specific data.
+    fn command(&self, vm: &VmFd, id: u32, data: u64) -> Result<(), Error> {
+        let mut cmd = kvm_sev_cmd {
+            id,
+            data,
+            sev_fd: self.device.as_raw_fd() as u32,
+            ..Default::default()
+        };
+
+        unsafe {
+            ioctl_with_mut_ref(vm.as_raw_fd(), KVM_MEMORY_ENCRYPT_OP, &mut cmd)
+        }?;
+
+        Ok(())
+    }
+
+    /// Pins the given range of host memory and registers it as encrypted guest memory.
+    pub fn register_region(&self, vm: &VmFd, addr: u64, size: u64) -> Result<(), Error> {
+        let region = kvm_enc_region {
+            addr,
+            size,
+        };
+
+        unsafe {
+            ioctl_with_ref(vm.as_raw_fd(), KVM_MEMORY_ENCRYPT_REG_REGION, &region)
+        }?;
+
+        Ok(())
+    }
+
+    /// Unregisters and unpins the given range of host memory.
+    pub fn unregister_region(&self, vm: &VmFd, addr: u64, size: u64) -> Result<(), Error> {
+        let region = kvm_enc_region {
+            addr,
+            size,
+        };
+
+        unsafe {
+            ioctl_with_ref(vm.as_raw_fd(), KVM_MEMORY_ENCRYPT_UNREG_REGION, &region)
+        }?;
+
+        Ok(())
+    }
+
+    /// Encrypts the given range of host memory in place and adds it to the launch measurement.
+    pub fn launch_update_data(&self, vm: &VmFd, addr: u64, size: u64) -> Result<(), Error> {
+        let mut update = kvm_sev_launch_update_data {
+            uaddr: addr,
+            len: size as u32,
+        };
+
+        self.command(vm, KVM_SEV_LAUNCH_UPDATE_DATA, &mut update as *mut _ as u64)
+    }
+
+    /// Encrypts the register state of all the virtual CPUs and adds it to the launch
+    /// measurement.
+    pub fn launch_update_vmsa(&self, vm: &VmFd) -> Result<(), Error> {
+        self.command(vm, KVM_SEV_LAUNCH_UPDATE_VMSA, 0)
+    }
+
+    /// Retrieves the launch measurement.
+    pub fn launch_measure(&self, vm: &VmFd) -> Result<LaunchMeasurement, Error> {
+        let mut blob = [0u8; LAUNCH_MEASUREMENT_SIZE + LAUNCH_NONCE_SIZE];
+
+        let mut measure = kvm_sev_launch_measure {
+            uaddr: blob.as_mut_ptr() as u64,
+            len: blob.len() as u32,
+        };
+
+        self.command(vm, KVM_SEV_LAUNCH_MEASURE, &mut measure as *mut _ as u64)?;
+
+        let mut measurement = LaunchMeasurement {
+            measurement: [0u8; LAUNCH_MEASUREMENT_SIZE],
+            nonce: [0u8; LAUNCH_NONCE_SIZE],
+        };
+
+        measurement.measurement.copy_from_slice(&blob[..LAUNCH_MEASUREMENT_SIZE]);
+        measurement.nonce.copy_from_slice(&blob[LAUNCH_MEASUREMENT_SIZE..]);
+
+        Ok(measurement)
+    }
+
+    /// Completes the launch, after which the virtual CPUs can run.
+    pub fn launch_finish(&self, vm: &VmFd) -> Result<(), Error> {
+        self.command(vm, KVM_SEV_LAUNCH_FINISH, 0)
+    }
+}
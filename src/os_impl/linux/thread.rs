@@ -0,0 +1,139 @@
+use crate::error::Error;
+use crate::thread::{ResourceLimits, ThreadPriority, ThreadPriorityReport};
+use std::path::{Path, PathBuf};
+
+/// The nice value used for [`ThreadPriority::Elevated`].
+const ELEVATED_NICE: libc::c_int = -10;
+
+fn set_nice(value: libc::c_int) -> Result<(), std::io::Error> {
+    // On Linux, the nice value is a per-thread attribute when using the thread ID.
+    let tid = unsafe { libc::syscall(libc::SYS_gettid) } as libc::id_t;
+
+    let result = unsafe {
+        libc::setpriority(libc::PRIO_PROCESS, tid, value)
+    };
+
+    if result < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+
+    Ok(())
+}
+
+fn set_fifo(priority: u8) -> Result<libc::c_int, std::io::Error> {
+    let min = unsafe { libc::sched_get_priority_min(libc::SCHED_FIFO) };
+    let max = unsafe { libc::sched_get_priority_max(libc::SCHED_FIFO) };
+    let priority = (priority as libc::c_int).max(min).min(max);
+
+    let param = libc::sched_param {
+        sched_priority: priority,
+    };
+
+    let result = unsafe {
+        libc::pthread_setschedparam(libc::pthread_self(), libc::SCHED_FIFO, &param)
+    };
+
+    if result != 0 {
+        return Err(std::io::Error::from_raw_os_error(result));
+    }
+
+    Ok(priority)
+}
+
+fn is_permission_error(error: &std::io::Error) -> bool {
+    matches!(error.raw_os_error(), Some(libc::EPERM) | Some(libc::EACCES))
+}
+
+pub fn set_current_thread_priority(
+    priority: ThreadPriority,
+) -> Result<ThreadPriorityReport, Error> {
+    let mut diagnostics = vec![];
+
+    if let ThreadPriority::RealTime { priority: value } = priority {
+        match set_fifo(value) {
+            Ok(applied) => return Ok(ThreadPriorityReport {
+                requested: priority,
+                applied: ThreadPriority::RealTime { priority: applied as u8 },
+                diagnostics,
+            }),
+            Err(e) if is_permission_error(&e) => diagnostics.push(format!(
+                "SCHED_FIFO was denied ({}), CAP_SYS_NICE or a sufficient RLIMIT_RTPRIO is \
+                required; falling back to an elevated nice value",
+                e,
+            )),
+            Err(e) => return Err(e.into()),
+        }
+    }
+
+    match set_nice(ELEVATED_NICE) {
+        Ok(()) => Ok(ThreadPriorityReport {
+            requested: priority,
+            applied: ThreadPriority::Elevated,
+            diagnostics,
+        }),
+        Err(e) if is_permission_error(&e) => {
+            diagnostics.push(format!(
+                "setting the nice value to {} was denied ({}), CAP_SYS_NICE or a sufficient \
+                RLIMIT_NICE is required; keeping the normal priority",
+                ELEVATED_NICE,
+                e,
+            ));
+
+            Ok(ThreadPriorityReport {
+                requested: priority,
+                applied: ThreadPriority::Normal,
+                diagnostics,
+            })
+        }
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// The mount point of the cgroup v2 hierarchy.
+const CGROUP_ROOT: &str = "/sys/fs/cgroup";
+
+/// The period used for the CPU quota in microseconds.
+const CPU_PERIOD: u64 = 100_000;
+
+pub struct ResourceGroup {
+    /// The path of the cgroup.
+    path: PathBuf,
+}
+
+impl ResourceGroup {
+    pub fn new(name: &str, limits: &ResourceLimits) -> Result<Self, Error> {
+        let path = Path::new(CGROUP_ROOT).join(name.trim_start_matches('/'));
+
+        match std::fs::create_dir(&path) {
+            Err(e) if e.kind() != std::io::ErrorKind::AlreadyExists => return Err(e.into()),
+            _ => (),
+        }
+
+        // Individual threads can only be moved into threaded cgroups.
+        std::fs::write(path.join("cgroup.type"), "threaded")?;
+
+        if let Some(quota) = limits.cpu_quota {
+            let quota = quota as u64 * CPU_PERIOD / 100;
+
+            std::fs::write(path.join("cpu.max"), format!("{} {}", quota, CPU_PERIOD))?;
+        }
+
+        if let Some(cpus) = &limits.cpu_affinity {
+            let cpus: Vec<String> = cpus.iter().map(|cpu| cpu.to_string()).collect();
+
+            std::fs::write(path.join("cpuset.cpus"), cpus.join(","))?;
+        }
+
+        Ok(Self {
+            path,
+        })
+    }
+
+    pub fn add_current_thread(&self) -> Result<(), Error> {
+        let tid = unsafe { libc::syscall(libc::SYS_gettid) };
+
+        std::fs::write(self.path.join("cgroup.threads"), tid.to_string())?;
+
+        Ok(())
+    }
+}
@@ -0,0 +1,226 @@
+//! Demand paging of guest physical memory through userfaultfd, where the missing pages are
+//! supplied by a handler on a thread of its own.
+
+use crate::error::Error;
+use crate::vm::MissingPageHandler;
+use mmap_rs::MmapOptions;
+use std::fs::File;
+use std::io::Write;
+use std::os::unix::io::{AsRawFd, FromRawFd};
+use std::panic::{self, AssertUnwindSafe};
+use std::thread::JoinHandle;
+use super::bindings::*;
+
+/// A range of guest physical memory registered with userfaultfd.
+pub struct UserfaultRange {
+    /// The host address of the range.
+    pub host_address: u64,
+    /// The size of the range in bytes.
+    pub size: u64,
+    /// The guest physical address of the range.
+    pub guest_address: u64,
+}
+
+/// Serves the faults on the missing pages of the registered ranges until dropped.
+pub struct Userfault {
+    /// The eventfd that stops the thread.
+    stop: File,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Userfault {
+    /// Registers the given ranges with a new userfaultfd and spawns the thread that serves the
+    /// faults through the given handler.
+    pub fn new(ranges: Vec<UserfaultRange>, handler: MissingPageHandler) -> Result<Self, Error> {
+        let fd = unsafe {
+            libc::syscall(libc::SYS_userfaultfd, libc::O_CLOEXEC | libc::O_NONBLOCK)
+        } as i32;
+
+        if fd < 0 {
+            return Err(std::io::Error::last_os_error().into());
+        }
+
+        let uffd = unsafe { File::from_raw_fd(fd) };
+
+        let mut api = uffdio_api {
+            api: UFFD_API,
+            ..Default::default()
+        };
+
+        unsafe {
+            ioctl_with_mut_ref(uffd.as_raw_fd(), UFFDIO_API, &mut api)
+        }?;
+
+        for range in &ranges {
+            let mut register = uffdio_register {
+                range: uffdio_range {
+                    start: range.host_address,
+                    len: range.size,
+                },
+                mode: UFFDIO_REGISTER_MODE_MISSING,
+                ioctls: 0,
+            };
+
+            unsafe {
+                ioctl_with_mut_ref(uffd.as_raw_fd(), UFFDIO_REGISTER, &mut register)
+            }?;
+        }
+
+        let fd = unsafe { libc::eventfd(0, libc::EFD_CLOEXEC) };
+
+        if fd < 0 {
+            return Err(std::io::Error::last_os_error().into());
+        }
+
+        let stop = unsafe { File::from_raw_fd(fd) };
+        let thread_stop = stop.try_clone()?;
+
+        let thread = std::thread::Builder::new()
+            .name("hy-rs-userfault".to_string())
+            .spawn(move || {
+                // Unregister the ranges if serving the faults fails, e.g. because the handler
+                // panicked, such that the faulting threads are woken up and any missing pages are
+                // zero-filled rather than the threads waiting forever.
+                if serve(&uffd, thread_stop, &ranges, handler).is_err() {
+                    unregister(&uffd, &ranges);
+                }
+            })?;
+
+        Ok(Self {
+            stop,
+            thread: Some(thread),
+        })
+    }
+}
+
+impl Drop for Userfault {
+    fn drop(&mut self) {
+        let _ = self.stop.write_all(&1u64.to_ne_bytes());
+
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// Helper function to unregister the given ranges from the userfaultfd, which wakes up the threads
+/// waiting for a missing page of the ranges.
+fn unregister(uffd: &File, ranges: &[UserfaultRange]) {
+    for range in ranges {
+        let range = uffdio_range {
+            start: range.host_address,
+            len: range.size,
+        };
+
+        let _ = unsafe {
+            ioctl_with_ref(uffd.as_raw_fd(), UFFDIO_UNREGISTER, &range)
+        };
+    }
+}
+
+/// Helper function to serve the faults until the eventfd is signaled. Closing the userfaultfd
+/// afterwards unregisters the ranges, such that any missing pages are zero-filled from then on.
+fn serve(
+    uffd: &File,
+    stop: File,
+    ranges: &[UserfaultRange],
+    mut handler: MissingPageHandler,
+) -> Result<(), Error> {
+    let page_size = MmapOptions::page_size().1;
+
+    // The kernel copies the page from a page-aligned buffer.
+    let mut page = MmapOptions::new(page_size).map_mut()?;
+
+    loop {
+        let mut fds = [
+            libc::pollfd {
+                fd: uffd.as_raw_fd(),
+                events: libc::POLLIN,
+                revents: 0,
+            },
+            libc::pollfd {
+                fd: stop.as_raw_fd(),
+                events: libc::POLLIN,
+                revents: 0,
+            },
+        ];
+
+        if unsafe { libc::poll(fds.as_mut_ptr(), fds.len() as _, -1) } < 0 {
+            let error = std::io::Error::last_os_error();
+
+            if error.kind() == std::io::ErrorKind::Interrupted {
+                continue;
+            }
+
+            return Err(error.into());
+        }
+
+        if fds[1].revents != 0 {
+            return Ok(());
+        }
+
+        let mut msg = uffd_msg::default();
+
+        let size = unsafe {
+            libc::read(
+                uffd.as_raw_fd(),
+                &mut msg as *mut uffd_msg as *mut libc::c_void,
+                std::mem::size_of::<uffd_msg>(),
+            )
+        };
+
+        // Another fault may have been read already, as the file descriptor is non-blocking.
+        if size != std::mem::size_of::<uffd_msg>() as isize || msg.event != UFFD_EVENT_PAGEFAULT {
+            continue;
+        }
+
+        let address = msg.address & !(page_size as u64 - 1);
+
+        let range = match ranges
+            .iter()
+            .find(|range| (range.host_address..range.host_address + range.size).contains(&address))
+        {
+            Some(range) => range,
+            _ => continue,
+        };
+
+        page.fill(0);
+
+        let guest_address = range.guest_address + (address - range.host_address);
+        let result = panic::catch_unwind(AssertUnwindSafe(|| {
+            handler(guest_address, &mut page[..])
+        }));
+
+        if result.is_err() {
+            return Err(std::io::Error::other("the missing page handler panicked").into());
+        }
+
+        let mut copy = uffdio_copy {
+            dst: address,
+            src: page.as_ptr() as u64,
+            len: page_size as u64,
+            mode: 0,
+            copy: 0,
+        };
+
+        let result = unsafe {
+            ioctl_with_mut_ref(uffd.as_raw_fd(), UFFDIO_COPY, &mut copy)
+        };
+
+        // The page may have been populated in the meantime, in which case the faulting threads
+        // still have to be woken up.
+        match result {
+            Err(e) if e.raw_os_error() == Some(libc::EEXIST) => {
+                let range = uffdio_range {
+                    start: address,
+                    len: page_size as u64,
+                };
+
+                unsafe {
+                    ioctl_with_ref(uffd.as_raw_fd(), UFFDIO_WAKE, &range)
+                }?;
+            }
+            result => result?,
+        }
+    }
+}
@@ -1,44 +1,911 @@
+use crate::debug::GuestDebug;
 use crate::error::Error;
-use crate::vcpu::ExitReason;
+use crate::hypercall::Hypercalls;
+use crate::vcpu::{ExitPolicy, ExitReason};
 use kvm_bindings::{kvm_msr_entry, Msrs};
 use kvm_ioctls::{VcpuExit, VcpuFd};
+#[cfg(feature = "xen")]
+use crate::xen::XenHypercall;
+use std::cell::Cell;
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+use std::collections::VecDeque;
+use std::os::unix::io::AsRawFd;
+use std::sync::{Arc, Mutex, Once};
+use std::sync::atomic::{AtomicBool, Ordering};
+use super::bindings::{KvmRun, KVM_RUN_IMMEDIATE_EXIT, KVM_RUN_IO_DATA_OFFSET, KVM_RUN_MMIO_DATA};
+#[cfg(target_arch = "aarch64")]
+use super::vm::Gic;
+
+thread_local! {
+    /// The `kvm_run` structure of the virtual CPU that is running on this thread, if any, such
+    /// that the signal handler can request it to exit.
+    static KVM_RUN: Cell<*mut u8> = const { Cell::new(std::ptr::null_mut()) };
+}
+
+/// The signal handler for the signal that kicks a virtual CPU. Setting `immediate_exit` covers the
+/// case where the signal arrives right before entering the guest, as `KVM_RUN` only fails with
+/// `EINTR` by itself if the signal arrives while running the guest.
+extern "C" fn kick_handler(_signal: libc::c_int) {
+    let kvm_run = KVM_RUN.with(|kvm_run| kvm_run.get());
+
+    if !kvm_run.is_null() {
+        unsafe {
+            std::ptr::write_volatile(kvm_run.add(KVM_RUN_IMMEDIATE_EXIT), 1);
+        }
+    }
+}
+
+/// Helper function to install the signal handler for the signal that kicks a virtual CPU. The
+/// handler is installed without `SA_RESTART`, such that `KVM_RUN` fails with `EINTR`.
+fn install_kick_handler() -> Result<(), Error> {
+    static INSTALL: Once = Once::new();
+    let mut result = Ok(());
+
+    INSTALL.call_once(|| {
+        let mut action: libc::sigaction = unsafe { std::mem::zeroed() };
+        action.sa_sigaction = kick_handler as usize;
+
+        result = unsafe {
+            libc::sigemptyset(&mut action.sa_mask);
+
+            match libc::sigaction(libc::SIGRTMIN(), &action, std::ptr::null_mut()) {
+                0 => Ok(()),
+                _ => Err(std::io::Error::last_os_error().into()),
+            }
+        };
+    });
+
+    result
+}
+
+/// The state shared between a virtual CPU and its handles.
+#[derive(Default)]
+pub(crate) struct KickState {
+    /// Whether the virtual CPU has been kicked.
+    kicked: AtomicBool,
+    /// The thread that is running the virtual CPU, if any.
+    thread: Mutex<Option<libc::pthread_t>>,
+}
+
+/// Registers the current thread as the thread running the virtual CPU and unregisters it once
+/// dropped.
+struct RunGuard<'a> {
+    state: &'a KickState,
+}
+
+impl<'a> RunGuard<'a> {
+    fn new(state: &'a KickState, kvm_run: &KvmRun) -> Self {
+        KVM_RUN.with(|ptr| ptr.set(kvm_run.as_ptr()));
+        *state.thread.lock().unwrap() = Some(unsafe { libc::pthread_self() });
+
+        Self {
+            state,
+        }
+    }
+}
+
+impl<'a> Drop for RunGuard<'a> {
+    fn drop(&mut self) {
+        *self.state.thread.lock().unwrap() = None;
+        KVM_RUN.with(|ptr| ptr.set(std::ptr::null_mut()));
+    }
+}
+
+#[derive(Clone)]
+pub struct VcpuHandle {
+    state: Arc<KickState>,
+}
+
+impl VcpuHandle {
+    pub fn clear_kick(&self) {
+        self.state.kicked.store(false, Ordering::SeqCst);
+    }
+
+    pub fn kick(&self) -> Result<(), Error> {
+        self.state.kicked.store(true, Ordering::SeqCst);
+
+        // Hold the lock while sending the signal, such that the thread cannot stop running the
+        // virtual CPU in the meantime.
+        let thread = self.state.thread.lock().unwrap();
+
+        if let Some(thread) = *thread {
+            match unsafe { libc::pthread_kill(thread, libc::SIGRTMIN()) } {
+                0 => (),
+                error => return Err(std::io::Error::from_raw_os_error(error).into()),
+            }
+        }
+
+        Ok(())
+    }
+}
 
 pub struct Vcpu {
     pub(crate) vcpu: VcpuFd,
+    pub(crate) kvm_run: KvmRun,
+    /// The state shared with the handles to kick the virtual CPU.
+    pub(crate) kick: Arc<KickState>,
+    /// Whether [`Vcpu::run`] returns without entering the guest.
+    pub(crate) immediate_exit: bool,
+    /// The size of the data of the `in` instruction that KVM completes upon the next entry.
+    pub(crate) pending_io_in: Option<usize>,
+    /// The size of the MMIO read that KVM completes upon the next entry.
+    pub(crate) pending_mmio_read: Option<usize>,
+    /// The value of the `IA32_APIC_BASE` MSR observed upon the last exit.
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    pub(crate) apic_base: Option<u64>,
+    /// Whether the value of the `IA32_APIC_BASE` MSR changed and has yet to be reported.
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    pub(crate) apic_base_changed: bool,
+    /// Whether the last exit was an `rdmsr` instruction that is completed upon the next entry.
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    pub(crate) pending_msr_read: bool,
+    /// The vectors of the interrupts that are queued until the guest is able to accept them.
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    pub(crate) pending_interrupts: VecDeque<u8>,
+    /// Whether the caller requested an exit upon the interrupt window, as opposed to the requests
+    /// made to inject the queued interrupts.
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    pub(crate) interrupt_window_requested: bool,
+    /// Whether the interrupts are delivered through the in-kernel local APIC.
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    pub(crate) irqchip: bool,
+    /// The in-kernel GIC, which is initialized upon the first run of any virtual CPU.
+    #[cfg(target_arch = "aarch64")]
+    pub(crate) gic: Option<Arc<Gic>>,
 }
 
 impl Vcpu {
-    pub fn run(&self) -> Result<ExitReason, Error> {
-        let exit_reason = self.vcpu.run()?;
-
-        let exit_reason = match exit_reason {
-            VcpuExit::IoOut(port, data) =>
-                ExitReason::IoOut { port, data },
-            VcpuExit::IoIn(port, data) =>
-                ExitReason::IoIn { port, data },
-            VcpuExit::MmioRead(address, data) =>
-                ExitReason::MmioRead { address, data },
-            VcpuExit::MmioWrite(address, data) =>
-                ExitReason::MmioWrite { address, data },
-            VcpuExit::Hlt =>
-                ExitReason::Halted,
-            VcpuExit::Shutdown =>
-                ExitReason::UnhandledException,
-            _ =>
-                ExitReason::Unknown,
+    pub fn handle(&self) -> VcpuHandle {
+        VcpuHandle {
+            state: self.kick.clone(),
+        }
+    }
+
+    pub fn set_immediate_exit(&mut self, immediate_exit: bool) {
+        self.immediate_exit = immediate_exit;
+    }
+
+    pub fn complete_io(&mut self, data: &[u8]) -> Result<(), Error> {
+        match self.pending_io_in {
+            Some(size) if size == data.len() => (),
+            _ => return Err(Error::InvalidArgument),
+        }
+
+        // KVM copies the data at `kvm_run.io.data_offset` into the registers upon the next entry.
+        let offset = unsafe { self.kvm_run.read::<u64>(KVM_RUN_IO_DATA_OFFSET) } as usize;
+
+        for (index, byte) in data.iter().enumerate() {
+            unsafe { self.kvm_run.write(offset + index, *byte) };
+        }
+
+        Ok(())
+    }
+
+    pub fn complete_mmio_read(&mut self, data: &[u8]) -> Result<(), Error> {
+        match self.pending_mmio_read {
+            Some(size) if size == data.len() => (),
+            _ => return Err(Error::InvalidArgument),
+        }
+
+        // KVM copies the data at `kvm_run.mmio.data` into the register upon the next entry.
+        for (index, byte) in data.iter().enumerate() {
+            unsafe { self.kvm_run.write(KVM_RUN_MMIO_DATA + index, *byte) };
+        }
+
+        Ok(())
+    }
+
+    pub fn virtual_tsc(&self) -> Option<u64> {
+        None
+    }
+
+    pub fn set_virtual_tsc(&mut self, _value: u64) -> Result<(), Error> {
+        Err(Error::NotImplemented)
+    }
+
+    pub fn run(
+        &mut self,
+        // The policy only applies to the interrupt window on x86.
+        #[cfg_attr(target_arch = "aarch64", allow(unused_variables))]
+        policy: ExitPolicy,
+        _hypercalls: &Hypercalls,
+    ) -> Result<ExitReason, Error> {
+        // KVM handles writes to the `IA32_APIC_BASE` MSR itself, so report any change observed
+        // upon the last exit before re-entering the guest.
+        #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+        if std::mem::take(&mut self.apic_base_changed) {
+            let apic_base = ApicBase::from_msr(self.apic_base.unwrap_or(0));
+
+            return Ok(ExitReason::ApicBaseChanged(apic_base));
+        }
+
+        #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+        {
+            self.pending_msr_read = false;
+        }
+
+        self.pending_io_in = None;
+        self.pending_mmio_read = None;
+
+        #[cfg(target_arch = "aarch64")]
+        if let Some(gic) = self.gic.as_ref() {
+            gic.initialize()?;
+        }
+
+        install_kick_handler()?;
+
+        let kick = self.kick.clone();
+        let _guard = RunGuard::new(&kick, &self.kvm_run);
+
+        let exit_reason = loop {
+            if kick.kicked.swap(false, Ordering::SeqCst) {
+                break ExitReason::Canceled;
+            }
+
+            #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+            self.inject_pending_interrupt()?;
+
+            // KVM completes the pending I/O and MMIO operations before returning with `EINTR`.
+            if self.immediate_exit {
+                unsafe { self.kvm_run.write::<u8>(KVM_RUN_IMMEDIATE_EXIT, 1) };
+            }
+
+            let exit_reason = match self.vcpu.run() {
+                Ok(exit_reason) => exit_reason,
+                // The virtual CPU was interrupted by a signal, which may be a kick.
+                Err(e) if e.errno() == libc::EINTR => {
+                    unsafe { self.kvm_run.write::<u8>(KVM_RUN_IMMEDIATE_EXIT, 0) };
+
+                    if self.immediate_exit {
+                        break ExitReason::Canceled;
+                    }
+
+                    continue;
+                }
+                Err(e) => return Err(e.into()),
+            };
+
+            #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+            {
+                let offset = super::bindings::KVM_RUN_APIC_BASE;
+                let apic_base = unsafe { self.kvm_run.read::<u64>(offset) };
+
+                if let Some(value) = self.apic_base.replace(apic_base) {
+                    self.apic_base_changed |= value != apic_base;
+                }
+            }
+
+            break match exit_reason {
+                VcpuExit::IoOut(port, data) =>
+                    ExitReason::IoOut { port, data },
+                VcpuExit::IoIn(port, data) => {
+                    self.pending_io_in = Some(data.len());
+                    ExitReason::IoIn { port, data }
+                }
+                VcpuExit::MmioRead(address, data) => {
+                    self.pending_mmio_read = Some(data.len());
+                    ExitReason::MmioRead { address, data }
+                }
+                VcpuExit::MmioWrite(address, data) =>
+                    ExitReason::MmioWrite { address, data },
+                VcpuExit::Hlt =>
+                    ExitReason::Halted,
+                VcpuExit::Shutdown =>
+                    ExitReason::UnhandledException,
+                #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+                VcpuExit::IrqWindowOpen => {
+                    // KVM keeps exiting as long as the request is set, so clear it.
+                    let offset = super::bindings::KVM_RUN_REQUEST_INTERRUPT_WINDOW;
+                    unsafe { self.kvm_run.write::<u8>(offset, 0) };
+
+                    // Only report the exit if the caller requested it, as the window may also
+                    // have been requested to inject the queued interrupts.
+                    let requested = std::mem::take(&mut self.interrupt_window_requested);
+
+                    if !requested || policy.contains(ExitPolicy::RESUME_INTERRUPT_WINDOW) {
+                        continue;
+                    }
+
+                    ExitReason::InterruptWindow
+                }
+                #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+                VcpuExit::Debug { .. } =>
+                    Self::debug_exit_reason(&self.kvm_run),
+                #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+                VcpuExit::Unsupported(super::bindings::KVM_EXIT_X86_RDMSR) => {
+                    use super::bindings::*;
+
+                    // Clear the value and error, such that the guest reads zero unless the read
+                    // is completed.
+                    unsafe {
+                        self.kvm_run.write::<u8>(KVM_RUN_MSR_ERROR, 0);
+                        self.kvm_run.write::<u64>(KVM_RUN_MSR_DATA, 0);
+                    }
+
+                    self.pending_msr_read = true;
+
+                    ExitReason::MsrRead {
+                        msr: unsafe { self.kvm_run.read(KVM_RUN_MSR_INDEX) },
+                    }
+                }
+                #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+                VcpuExit::Unsupported(super::bindings::KVM_EXIT_X86_WRMSR) => {
+                    use super::bindings::*;
+
+                    unsafe {
+                        self.kvm_run.write::<u8>(KVM_RUN_MSR_ERROR, 0);
+                    }
+
+                    let (msr, value) = unsafe {(
+                        self.kvm_run.read(KVM_RUN_MSR_INDEX),
+                        self.kvm_run.read(KVM_RUN_MSR_DATA),
+                    )};
+
+                    ExitReason::MsrWrite { msr, value }
+                }
+                #[cfg(feature = "xen")]
+                VcpuExit::Unsupported(super::bindings::KVM_EXIT_XEN) =>
+                    Self::xen_exit_reason(&self.kvm_run),
+                // KVM implements PSCI itself, but leaves powering off and resetting the system to
+                // the VMM.
+                #[cfg(target_arch = "aarch64")]
+                VcpuExit::SystemEvent(super::bindings::KVM_SYSTEM_EVENT_SHUTDOWN, _) =>
+                    ExitReason::Psci {
+                        function: crate::arch::aarch64::PSCI_SYSTEM_OFF,
+                        args: [0; 3],
+                    },
+                #[cfg(target_arch = "aarch64")]
+                VcpuExit::SystemEvent(super::bindings::KVM_SYSTEM_EVENT_RESET, _) =>
+                    ExitReason::Psci {
+                        function: crate::arch::aarch64::PSCI_SYSTEM_RESET,
+                        args: [0; 3],
+                    },
+                _ =>
+                    ExitReason::Unknown,
+            };
         };
 
         Ok(exit_reason)
     }
+
+    /// Helper function to decode the debug exit from the `kvm_run` structure.
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    fn debug_exit_reason(kvm_run: &KvmRun) -> ExitReason<'static> {
+        use super::bindings::*;
+
+        let (exception, pc, dr6) = unsafe {(
+            kvm_run.read::<u32>(KVM_RUN_DEBUG_EXCEPTION),
+            kvm_run.read::<u64>(KVM_RUN_DEBUG_PC),
+            kvm_run.read::<u64>(KVM_RUN_DEBUG_DR6),
+        )};
+
+        let kind = match exception {
+            // #BP
+            3 => DebugExitKind::SoftwareBreakpoint,
+            // #DB
+            _ => decode_dr6(dr6),
+        };
+
+        ExitReason::Debug(DebugExit { pc, kind, breakpoint: None })
+    }
+
+    /// Helper function to decode the Xen exit from the `kvm_run` structure.
+    #[cfg(feature = "xen")]
+    fn xen_exit_reason(kvm_run: &KvmRun) -> ExitReason<'static> {
+        use super::bindings::*;
+
+        let ty: u32 = unsafe { kvm_run.read(KVM_RUN_XEN_TYPE) };
+
+        if ty != KVM_EXIT_XEN_HCALL {
+            return ExitReason::Unknown;
+        }
+
+        let hypercall = unsafe {
+            XenHypercall {
+                long_mode: kvm_run.read::<u32>(KVM_RUN_XEN_LONGMODE) != 0,
+                cpl: kvm_run.read(KVM_RUN_XEN_CPL),
+                input: kvm_run.read(KVM_RUN_XEN_INPUT),
+                params: kvm_run.read(KVM_RUN_XEN_PARAMS),
+            }
+        };
+
+        ExitReason::XenHypercall(hypercall)
+    }
+
+    #[cfg(feature = "xen")]
+    pub fn set_xen_vcpu_info(&mut self, gpa: u64) -> Result<(), Error> {
+        use super::bindings::*;
+
+        let mut attr = kvm_xen_attr {
+            ty: KVM_XEN_VCPU_ATTR_TYPE_VCPU_INFO,
+            ..Default::default()
+        };
+        attr.u[0] = gpa;
+
+        unsafe {
+            ioctl_with_ref(self.vcpu.as_raw_fd(), KVM_XEN_VCPU_SET_ATTR, &attr)
+        }?;
+
+        Ok(())
+    }
+
+    #[cfg(feature = "xen")]
+    pub fn complete_xen_hypercall(&mut self, result: u64) -> Result<(), Error> {
+        // KVM copies the result into the return register of the hypercall upon the next entry.
+        unsafe {
+            self.kvm_run.write(super::bindings::KVM_RUN_XEN_RESULT, result);
+        }
+
+        Ok(())
+    }
 }
 
-#[cfg(target_arch = "x86_64")]
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
 use crate::arch::x86_64::{
-    ControlRegister, CpuRegs, DescriptorTable, DescriptorTableRegister, Segment, SegmentRegister,
-    Register,
+    ApicBase, ControlRegister, CpuRegs, CpuidEntry, CpuidResult, DebugRegister, DescriptorTable,
+    DescriptorTableRegister, FpuState, PendingEvents, PendingException, Segment, SegmentRegister,
+    Register, SmmState,
 };
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+use crate::debug::{decode_dr6, encode_dr7, DebugExit, DebugExitKind};
+
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+impl Vcpu {
+    pub fn inject_smi(&mut self) -> Result<(), Error> {
+        unsafe {
+            super::bindings::ioctl(self.vcpu.as_raw_fd(), super::bindings::KVM_SMI)
+        }?;
+
+        Ok(())
+    }
+
+    pub fn inject_interrupt(&mut self, vector: u8) -> Result<(), Error> {
+        // KVM does not allow injecting interrupts once the local APIC is emulated in the kernel.
+        if self.irqchip {
+            return Err(Error::NotImplemented);
+        }
+
+        self.pending_interrupts.push_back(vector);
+
+        Ok(())
+    }
+
+    pub fn inject_exception(&mut self, vector: u8, error_code: Option<u32>) -> Result<(), Error> {
+        let mut events = self.vcpu.get_vcpu_events()?;
+
+        // KVM delivers any interrupt or NMI that was being injected after the exception.
+        events.exception.injected       = 1;
+        events.exception.pending        = 0;
+        events.exception.nr             = vector;
+        events.exception.has_error_code = error_code.is_some() as u8;
+        events.exception.error_code     = error_code.unwrap_or(0);
+
+        self.vcpu.set_vcpu_events(&events)?;
+
+        Ok(())
+    }
+
+    pub fn inject_nmi(&mut self) -> Result<(), Error> {
+        // KVM queues the NMI until the guest is able to accept it.
+        unsafe {
+            super::bindings::ioctl(self.vcpu.as_raw_fd(), super::bindings::KVM_NMI)
+        }?;
+
+        Ok(())
+    }
+
+    /// Helper function to inject the first of the queued interrupts if the guest is able to
+    /// accept it, and to request an exit once the guest is able to accept the next one.
+    fn inject_pending_interrupt(&mut self) -> Result<(), Error> {
+        use super::bindings::*;
+
+        if self.pending_interrupts.is_empty() {
+            return Ok(());
+        }
+
+        // KVM updates `ready_for_interrupt_injection` upon every exit.
+        if unsafe { self.kvm_run.read::<u8>(KVM_RUN_READY_FOR_INTERRUPT_INJECTION) } != 0 {
+            let irq = self.pending_interrupts[0] as u32;
+
+            unsafe {
+                ioctl_with_ref(self.vcpu.as_raw_fd(), KVM_INTERRUPT, &irq)
+            }?;
+
+            self.pending_interrupts.pop_front();
+
+            // KVM only accepts a single interrupt per entry.
+            unsafe { self.kvm_run.write::<u8>(KVM_RUN_READY_FOR_INTERRUPT_INJECTION, 0) };
+        }
+
+        if !self.pending_interrupts.is_empty() {
+            unsafe { self.kvm_run.write::<u8>(KVM_RUN_REQUEST_INTERRUPT_WINDOW, 1) };
+        }
+
+        Ok(())
+    }
+
+    pub fn translate_gva(&self, _gva: u64) -> Result<u64, Error> {
+        Err(Error::NotImplemented)
+    }
+
+    pub fn get_smm_state(&self) -> Result<SmmState, Error> {
+        let events = self.vcpu.get_vcpu_events()?;
+
+        Ok(SmmState {
+            active: events.smi.smm != 0,
+            pending: events.smi.pending != 0,
+            inside_nmi: events.smi.smm_inside_nmi != 0,
+            latched_init: events.smi.latched_init != 0,
+        })
+    }
+
+    pub fn set_smm_state(&mut self, state: &SmmState) -> Result<(), Error> {
+        let mut events = self.vcpu.get_vcpu_events()?;
+
+        events.smi.smm            = state.active as u8;
+        events.smi.pending        = state.pending as u8;
+        events.smi.smm_inside_nmi = state.inside_nmi as u8;
+        events.smi.latched_init   = state.latched_init as u8;
+        events.flags |= kvm_bindings::KVM_VCPUEVENT_VALID_SMM;
+
+        self.vcpu.set_vcpu_events(&events)?;
 
-#[cfg(target_arch = "x86_64")]
+        Ok(())
+    }
+
+    pub fn get_pending_events(&self) -> Result<PendingEvents, Error> {
+        let events = self.vcpu.get_vcpu_events()?;
+
+        let exception = match events.exception.injected | events.exception.pending {
+            0 => None,
+            _ => Some(PendingException {
+                vector: events.exception.nr,
+                error_code: match events.exception.has_error_code {
+                    0 => None,
+                    _ => Some(events.exception.error_code),
+                },
+            }),
+        };
+
+        let interrupt = match events.interrupt.injected {
+            0 => None,
+            _ => Some(events.interrupt.nr),
+        };
+
+        Ok(PendingEvents {
+            exception,
+            interrupt,
+            queued_interrupts: self.pending_interrupts.iter().copied().collect(),
+            nmi_pending: events.nmi.injected != 0 || events.nmi.pending != 0,
+            nmi_masked: events.nmi.masked != 0,
+            interrupt_shadow: events.interrupt.shadow != 0,
+        })
+    }
+
+    pub fn set_pending_events(&mut self, pending: &PendingEvents) -> Result<(), Error> {
+        let mut events = self.vcpu.get_vcpu_events()?;
+
+        let exception = pending.exception.unwrap_or(PendingException {
+            vector: 0,
+            error_code: None,
+        });
+
+        events.exception.injected       = pending.exception.is_some() as u8;
+        events.exception.pending        = 0;
+        events.exception.nr             = exception.vector;
+        events.exception.has_error_code = exception.error_code.is_some() as u8;
+        events.exception.error_code     = exception.error_code.unwrap_or(0);
+        events.interrupt.injected       = pending.interrupt.is_some() as u8;
+        events.interrupt.nr             = pending.interrupt.unwrap_or(0);
+        events.interrupt.soft           = 0;
+        events.interrupt.shadow         = pending.interrupt_shadow as u8;
+        events.nmi.injected             = 0;
+        events.nmi.pending              = pending.nmi_pending as u8;
+        events.nmi.masked               = pending.nmi_masked as u8;
+        events.flags |= kvm_bindings::KVM_VCPUEVENT_VALID_NMI_PENDING;
+        events.flags |= kvm_bindings::KVM_VCPUEVENT_VALID_SHADOW;
+
+        self.vcpu.set_vcpu_events(&events)?;
+        self.pending_interrupts = pending.queued_interrupts.iter().copied().collect();
+
+        Ok(())
+    }
+
+    pub fn get_xsave(&self) -> Result<Vec<u8>, Error> {
+        let xsave = self.vcpu.get_xsave()?;
+
+        Ok(xsave.region.iter().flat_map(|word| word.to_le_bytes()).collect())
+    }
+
+    pub fn set_xsave(&mut self, bytes: &[u8]) -> Result<(), Error> {
+        let mut xsave = kvm_bindings::kvm_xsave::default();
+
+        if bytes.len() > xsave.region.len() * 4 {
+            return Err(Error::InvalidArgument);
+        }
+
+        for (word, chunk) in xsave.region.iter_mut().zip(bytes.chunks(4)) {
+            let mut value = [0u8; 4];
+            value[..chunk.len()].copy_from_slice(chunk);
+            *word = u32::from_le_bytes(value);
+        }
+
+        self.vcpu.set_xsave(&xsave)?;
+
+        Ok(())
+    }
+
+    pub fn get_xcr0(&self) -> Result<u64, Error> {
+        let xcrs = self.vcpu.get_xcrs()?;
+
+        xcrs.xcrs[..xcrs.nr_xcrs as usize]
+            .iter()
+            .find(|xcr| xcr.xcr == 0)
+            .map(|xcr| xcr.value)
+            .ok_or(Error::NotImplemented)
+    }
+
+    pub fn set_xcr0(&mut self, value: u64) -> Result<(), Error> {
+        let mut xcrs = kvm_bindings::kvm_xcrs::default();
+
+        xcrs.nr_xcrs = 1;
+        xcrs.xcrs[0].xcr = 0;
+        xcrs.xcrs[0].value = value;
+
+        self.vcpu.set_xcrs(&xcrs)?;
+
+        Ok(())
+    }
+
+    pub fn get_tsc(&self) -> Result<u64, Error> {
+        Ok(self.get_msrs(&[crate::arch::x86_64::MSR_IA32_TSC])?[0])
+    }
+
+    pub fn set_tsc(&mut self, value: u64) -> Result<(), Error> {
+        self.set_msrs(&[crate::arch::x86_64::MSR_IA32_TSC], &[value])
+    }
+
+    pub fn get_tsc_offset(&self) -> Result<u64, Error> {
+        let mut offset = 0u64;
+
+        let attr = kvm_bindings::kvm_device_attr {
+            group: super::bindings::KVM_VCPU_TSC_CTRL,
+            attr: super::bindings::KVM_VCPU_TSC_OFFSET,
+            addr: &mut offset as *mut u64 as u64,
+            ..Default::default()
+        };
+
+        unsafe {
+            super::bindings::ioctl_with_ref(
+                self.vcpu.as_raw_fd(),
+                super::bindings::KVM_GET_DEVICE_ATTR,
+                &attr,
+            )
+        }?;
+
+        Ok(offset)
+    }
+
+    pub fn set_tsc_offset(&mut self, offset: u64) -> Result<(), Error> {
+        let attr = kvm_bindings::kvm_device_attr {
+            group: super::bindings::KVM_VCPU_TSC_CTRL,
+            attr: super::bindings::KVM_VCPU_TSC_OFFSET,
+            addr: &offset as *const u64 as u64,
+            ..Default::default()
+        };
+
+        unsafe {
+            super::bindings::ioctl_with_ref(
+                self.vcpu.as_raw_fd(),
+                super::bindings::KVM_SET_DEVICE_ATTR,
+                &attr,
+            )
+        }?;
+
+        Ok(())
+    }
+
+    pub fn get_tsc_khz(&self) -> Result<u32, Error> {
+        let khz = unsafe {
+            super::bindings::ioctl_with_val(
+                self.vcpu.as_raw_fd(),
+                super::bindings::KVM_GET_TSC_KHZ,
+                0,
+            )
+        }?;
+
+        Ok(khz as u32)
+    }
+
+    pub fn set_tsc_khz(&mut self, khz: u32) -> Result<(), Error> {
+        unsafe {
+            super::bindings::ioctl_with_val(
+                self.vcpu.as_raw_fd(),
+                super::bindings::KVM_SET_TSC_KHZ,
+                khz as libc::c_ulong,
+            )
+        }?;
+
+        Ok(())
+    }
+
+    pub fn set_tpr_threshold(&mut self, _threshold: u8) -> Result<(), Error> {
+        // KVM manages the TPR threshold internally.
+        Err(Error::NotImplemented)
+    }
+
+    pub fn set_virtual_apic_page(&mut self, gpa: u64) -> Result<(), Error> {
+        unsafe {
+            super::bindings::ioctl_with_ref(
+                self.vcpu.as_raw_fd(),
+                super::bindings::KVM_SET_VAPIC_ADDR,
+                &gpa,
+            )
+        }?;
+
+        Ok(())
+    }
+
+    pub fn request_interrupt_window(&mut self) -> Result<(), Error> {
+        self.interrupt_window_requested = true;
+
+        unsafe {
+            self.kvm_run.write::<u8>(super::bindings::KVM_RUN_REQUEST_INTERRUPT_WINDOW, 1)
+        };
+
+        Ok(())
+    }
+
+    pub fn complete_cpuid(&mut self, _result: &CpuidResult) -> Result<(), Error> {
+        Err(Error::NotImplemented)
+    }
+
+    pub fn set_cpuid(&mut self, entries: &[CpuidEntry]) -> Result<(), Error> {
+        let entries: Vec<kvm_bindings::kvm_cpuid_entry2> = entries.iter().map(|entry| {
+            kvm_bindings::kvm_cpuid_entry2 {
+                function: entry.leaf,
+                index: entry.subleaf,
+                flags: match entry.indexed {
+                    true => kvm_bindings::KVM_CPUID_FLAG_SIGNIFCANT_INDEX,
+                    _ => 0,
+                },
+                eax: entry.result.eax,
+                ebx: entry.result.ebx,
+                ecx: entry.result.ecx,
+                edx: entry.result.edx,
+                ..Default::default()
+            }
+        }).collect();
+
+        let cpuid = kvm_bindings::CpuId::from_entries(&entries)
+            .map_err(|_| Error::InvalidArgument)?;
+
+        self.vcpu.set_cpuid2(&cpuid)?;
+
+        Ok(())
+    }
+
+    pub fn set_exception_exits(&mut self, vectors: u32) -> Result<(), Error> {
+        // KVM only intercepts the exceptions used for guest debugging.
+        if vectors != 0 {
+            return Err(Error::NotImplemented);
+        }
+
+        Ok(())
+    }
+
+    pub fn complete_msr_read(&mut self, value: u64) -> Result<(), Error> {
+        if !self.pending_msr_read {
+            return Err(Error::InvalidArgument);
+        }
+
+        // KVM copies the value into `edx:eax` upon the next entry.
+        unsafe {
+            self.kvm_run.write(super::bindings::KVM_RUN_MSR_DATA, value);
+        }
+
+        Ok(())
+    }
+
+    pub fn set_guest_debug(&mut self, debug: &GuestDebug) -> Result<(), Error> {
+        use kvm_bindings::{
+            kvm_guest_debug, KVM_GUESTDBG_ENABLE, KVM_GUESTDBG_SINGLESTEP, KVM_GUESTDBG_USE_HW_BP,
+            KVM_GUESTDBG_USE_SW_BP,
+        };
+
+        let mut guest_debug = kvm_guest_debug::default();
+
+        if debug.is_enabled() {
+            guest_debug.control |= KVM_GUESTDBG_ENABLE;
+        }
+
+        if debug.single_step {
+            guest_debug.control |= KVM_GUESTDBG_SINGLESTEP;
+        }
+
+        if debug.sw_breakpoints {
+            guest_debug.control |= KVM_GUESTDBG_USE_SW_BP;
+        }
+
+        if !debug.hw_breakpoints.is_empty() {
+            guest_debug.control |= KVM_GUESTDBG_USE_HW_BP;
+        }
+
+        // Set up the debug registers for the hardware breakpoints.
+        guest_debug.arch.debugreg[7] = encode_dr7(&debug.hw_breakpoints)?;
+
+        for (index, breakpoint) in debug.hw_breakpoints.iter().enumerate() {
+            guest_debug.arch.debugreg[index] = breakpoint.address;
+        }
+
+        unsafe {
+            super::bindings::ioctl_with_ref(
+                self.vcpu.as_raw_fd(),
+                super::bindings::KVM_SET_GUEST_DEBUG,
+                &guest_debug,
+            )
+        }?;
+
+        Ok(())
+    }
+}
+
+#[cfg(target_arch = "aarch64")]
+use crate::arch::aarch64::{CpuRegs, Register};
+
+#[cfg(not(any(target_arch = "x86", target_arch = "x86_64")))]
+impl Vcpu {
+    pub fn set_guest_debug(&mut self, _debug: &GuestDebug) -> Result<(), Error> {
+        Err(Error::NotImplemented)
+    }
+}
+
+/// Helper function to map the [`Register`] to the ID used by `KVM_GET_ONE_REG` and
+/// `KVM_SET_ONE_REG`, i.e. the offset of the register in `struct kvm_regs` in 32-bit words.
+#[cfg(target_arch = "aarch64")]
+fn one_reg_id(register: Register) -> u64 {
+    const KVM_REG_ARM64_CORE_U64: u64 = 0x6030_0000_0010_0000;
+
+    let index = match register {
+        Register::Sp     => 31,
+        Register::Pc     => 32,
+        Register::Pstate => 33,
+        // X0 to X30 are stored consecutively.
+        register => register as u64,
+    };
+
+    KVM_REG_ARM64_CORE_U64 | (index * 2)
+}
+
+#[cfg(target_arch = "aarch64")]
+impl CpuRegs for Vcpu {
+    fn get_registers(
+        &self,
+        registers: &[Register],
+    ) -> Result<Vec<u64>, Error> {
+        let mut values = vec![];
+
+        for register in registers {
+            values.push(self.vcpu.get_one_reg(one_reg_id(*register))?);
+        }
+
+        Ok(values)
+    }
+
+    fn set_registers(
+        &mut self,
+        registers: &[Register],
+        values: &[u64],
+    ) -> Result<(), Error> {
+        for (register, value) in registers.iter().zip(values.iter()) {
+            self.vcpu.set_one_reg(one_reg_id(*register), *value)?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
 impl CpuRegs for Vcpu {
     fn get_registers(
         &self,
@@ -355,4 +1222,78 @@ impl CpuRegs for Vcpu {
 
         Ok(())
     }
+
+    fn get_debug_registers(
+        &self,
+        registers: &[DebugRegister],
+    ) -> Result<Vec<u64>, Error> {
+        let debugregs = self.vcpu.get_debug_regs()?;
+
+        Ok(registers.iter().map(|register| match register {
+            DebugRegister::Dr0 => debugregs.db[0],
+            DebugRegister::Dr1 => debugregs.db[1],
+            DebugRegister::Dr2 => debugregs.db[2],
+            DebugRegister::Dr3 => debugregs.db[3],
+            DebugRegister::Dr6 => debugregs.dr6,
+            DebugRegister::Dr7 => debugregs.dr7,
+        }).collect())
+    }
+
+    fn set_debug_registers(
+        &mut self,
+        registers: &[DebugRegister],
+        values: &[u64],
+    ) -> Result<(), Error> {
+        let mut debugregs = self.vcpu.get_debug_regs()?;
+
+        for (register, value) in registers.iter().zip(values.iter()) {
+            match register {
+                DebugRegister::Dr0 => debugregs.db[0] = *value,
+                DebugRegister::Dr1 => debugregs.db[1] = *value,
+                DebugRegister::Dr2 => debugregs.db[2] = *value,
+                DebugRegister::Dr3 => debugregs.db[3] = *value,
+                DebugRegister::Dr6 => debugregs.dr6 = *value,
+                DebugRegister::Dr7 => debugregs.dr7 = *value,
+            }
+        }
+
+        self.vcpu.set_debug_regs(&debugregs)?;
+
+        Ok(())
+    }
+
+    fn get_fpu(&self) -> Result<FpuState, Error> {
+        let fpu = self.vcpu.get_fpu()?;
+
+        Ok(FpuState {
+            fcw: fpu.fcw,
+            fsw: fpu.fsw,
+            ftw: fpu.ftwx,
+            last_opcode: fpu.last_opcode,
+            last_ip: fpu.last_ip,
+            last_dp: fpu.last_dp,
+            st: fpu.fpr,
+            mxcsr: fpu.mxcsr,
+            xmm: fpu.xmm,
+        })
+    }
+
+    fn set_fpu(&mut self, state: &FpuState) -> Result<(), Error> {
+        let fpu = kvm_bindings::kvm_fpu {
+            fpr: state.st,
+            fcw: state.fcw,
+            fsw: state.fsw,
+            ftwx: state.ftw,
+            last_opcode: state.last_opcode,
+            last_ip: state.last_ip,
+            last_dp: state.last_dp,
+            xmm: state.xmm,
+            mxcsr: state.mxcsr,
+            ..Default::default()
+        };
+
+        self.vcpu.set_fpu(&fpu)?;
+
+        Ok(())
+    }
 }
@@ -1,14 +1,65 @@
+#[cfg(target_arch = "aarch64")]
+use crate::arch::aarch64::{GicConfig, GIC_SPI_BASE};
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+use crate::arch::x86_64::{CrExits, MsrPolicy};
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+use crate::confidential::{LaunchMeasurement, SevPolicy};
 use crate::error::Error;
-use crate::vm::ProtectionFlags;
-use kvm_bindings::{KVM_MEM_READONLY, kvm_userspace_memory_region};
-use kvm_ioctls::VmFd;
-use mmap_rs::{MmapMut, MmapOptions};
+use crate::os_impl::unix::{discard, discard_shared, populate, resident_size};
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+use crate::pit::PitReinjection;
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+use crate::synic::SyntheticMessage;
+use crate::tsc::TscMode;
+use crate::vm::{
+    check_overlap, DirtyBitmap, MemoryBackingKind, MissingPageHandler, ProtectionFlags,
+};
+#[cfg(feature = "xen")]
+use crate::xen::XenConfig;
+use kvm_bindings::{KVM_MEM_LOG_DIRTY_PAGES, KVM_MEM_READONLY, kvm_userspace_memory_region};
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+use kvm_bindings::{CpuId, KVM_MAX_CPUID_ENTRIES};
+#[cfg(target_arch = "aarch64")]
+use kvm_ioctls::DeviceFd;
+use kvm_ioctls::{Kvm, VmFd};
+use mmap_rs::{MmapFlags, MmapMut, MmapOptions};
 use rangemap::RangeMap;
 use std::collections::HashMap;
+use std::fs::File;
+use std::ops::Range;
+use std::os::unix::io::{AsRawFd, FromRawFd};
+#[cfg(target_arch = "aarch64")]
+use std::sync::{Arc, Mutex};
+use super::bindings::{KvmRun, KVM_SMM_ADDRESS_SPACE};
+use super::hypervisor::{protected_guest_supported, KVM_VM_TYPE_PROTECTED};
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+use super::sev::{sev_supported, Sev};
+use super::userfault::{Userfault, UserfaultRange};
 use super::vcpu::Vcpu;
 
 pub struct VmBuilder {
     pub(crate) vm: VmFd,
+    pub(crate) protected: bool,
+    /// The CPUID exposed to the guest, or `None` to leave the CPUID untouched.
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    pub(crate) cpuid: Option<CpuId>,
+    /// The reasons for which the MSR accesses exit to user space.
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    pub(crate) msr_exit_reasons: u32,
+    /// The policy of the SEV guest, or `None` to not enable SEV.
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    pub(crate) sev: Option<SevPolicy>,
+    /// Whether to create the in-kernel PIC, I/O APIC and local APICs.
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    pub(crate) irqchip: bool,
+    /// The policy of the in-kernel PIT, or `None` to not create one.
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    pub(crate) pit: Option<PitReinjection>,
+    /// The configuration of the in-kernel GIC, or `None` to not create one.
+    #[cfg(target_arch = "aarch64")]
+    pub(crate) gic: Option<GicConfig>,
+    #[cfg(feature = "xen")]
+    pub(crate) xen: Option<XenConfig>,
 }
 
 impl VmBuilder {
@@ -16,37 +67,1142 @@ impl VmBuilder {
         Ok(self)
     }
 
+    pub fn with_tsc_mode(self, mode: TscMode) -> Result<Self, Error> {
+        // KVM does not support intercepting `rdtsc`.
+        match mode {
+            TscMode::Native => Ok(self),
+            _ => Err(Error::NotImplemented),
+        }
+    }
+
+    pub fn with_protected_guest(self, enabled: bool) -> Result<Self, Error> {
+        if enabled == self.protected {
+            return Ok(self);
+        }
+
+        if enabled && !protected_guest_supported(self.vm.as_raw_fd()) {
+            return Err(Error::NotImplemented);
+        }
+
+        // The machine type can only be specified upon creating the VM, so recreate the VM.
+        let vm_type = if enabled { KVM_VM_TYPE_PROTECTED } else { 0 };
+        let vm = Kvm::new()?.create_vm_with_type(vm_type)?;
+
+        Ok(Self {
+            vm,
+            protected: enabled,
+            ..self
+        })
+    }
+
+    pub fn with_synthetic_interrupts(self, enabled: bool) -> Result<Self, Error> {
+        // The Hyper-V enlightenments of KVM are not exposed yet.
+        if enabled {
+            return Err(Error::NotImplemented);
+        }
+
+        Ok(self)
+    }
+
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    pub fn with_la57(self, enabled: bool) -> Result<Self, Error> {
+        Ok(Self {
+            cpuid: Some(cpuid_with_la57(enabled)?),
+            ..self
+        })
+    }
+
+    #[cfg(target_arch = "aarch64")]
+    pub fn with_la57(self, _enabled: bool) -> Result<Self, Error> {
+        Err(Error::NotImplemented)
+    }
+
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    pub fn with_hlt_exiting(self, enabled: bool) -> Result<Self, Error> {
+        use kvm_bindings::kvm_enable_cap;
+        use super::bindings::*;
+
+        // KVM exits on `hlt` by default.
+        if enabled {
+            return Ok(self);
+        }
+
+        // The capability reports the exits that can be disabled.
+        let exits = unsafe {
+            ioctl_with_val(
+                self.vm.as_raw_fd(),
+                KVM_CHECK_EXTENSION,
+                KVM_CAP_X86_DISABLE_EXITS as _,
+            )
+        }? as u32;
+
+        if exits & KVM_X86_DISABLE_EXITS_HLT == 0 {
+            return Err(Error::NotImplemented);
+        }
+
+        let mut cap = kvm_enable_cap {
+            cap: KVM_CAP_X86_DISABLE_EXITS,
+            ..Default::default()
+        };
+        cap.args[0] = KVM_X86_DISABLE_EXITS_HLT as u64;
+
+        self.vm.enable_cap(&cap)?;
+
+        Ok(self)
+    }
+
+    #[cfg(target_arch = "aarch64")]
+    pub fn with_hlt_exiting(self, enabled: bool) -> Result<Self, Error> {
+        if !enabled {
+            return Err(Error::NotImplemented);
+        }
+
+        Ok(self)
+    }
+
+    pub fn with_cpuid_exits(self, enabled: bool) -> Result<Self, Error> {
+        // KVM does not exit on `cpuid`, but serves the CPUID table set through KVM_SET_CPUID2.
+        if enabled {
+            return Err(Error::NotImplemented);
+        }
+
+        Ok(self)
+    }
+
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    pub fn with_msr_exits(self, enabled: bool) -> Result<Self, Error> {
+        use kvm_bindings::kvm_enable_cap;
+        use super::bindings::*;
+
+        // The MSR exits are disabled by default.
+        if !enabled {
+            return Ok(self);
+        }
+
+        // The capability reports the supported exit reasons.
+        let reasons = unsafe {
+            ioctl_with_val(
+                self.vm.as_raw_fd(),
+                KVM_CHECK_EXTENSION,
+                KVM_CAP_X86_USER_SPACE_MSR as _,
+            )
+        }? as u32;
+
+        if reasons & KVM_MSR_EXIT_REASON_UNKNOWN == 0 {
+            return Err(Error::NotImplemented);
+        }
+
+        let mut cap = kvm_enable_cap {
+            cap: KVM_CAP_X86_USER_SPACE_MSR,
+            ..Default::default()
+        };
+        cap.args[0] = KVM_MSR_EXIT_REASON_UNKNOWN as u64;
+
+        self.vm.enable_cap(&cap)?;
+
+        Ok(Self {
+            msr_exit_reasons: KVM_MSR_EXIT_REASON_UNKNOWN,
+            ..self
+        })
+    }
+
+    #[cfg(target_arch = "aarch64")]
+    pub fn with_msr_exits(self, enabled: bool) -> Result<Self, Error> {
+        if enabled {
+            return Err(Error::NotImplemented);
+        }
+
+        Ok(self)
+    }
+
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    pub fn with_cr_exits(self, exits: CrExits) -> Result<Self, Error> {
+        // KVM handles the control register accesses in the kernel.
+        if !exits.is_empty() {
+            return Err(Error::NotImplemented);
+        }
+
+        Ok(self)
+    }
+
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    pub fn with_in_kernel_irqchip(self, enabled: bool) -> Result<Self, Error> {
+        Ok(Self {
+            irqchip: enabled,
+            ..self
+        })
+    }
+
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    pub fn with_in_kernel_pit(self, reinjection: PitReinjection) -> Result<Self, Error> {
+        Ok(Self {
+            pit: Some(reinjection),
+            ..self
+        })
+    }
+
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    pub fn with_sev(self, policy: SevPolicy) -> Result<Self, Error> {
+        if !sev_supported(self.vm.as_raw_fd()) {
+            return Err(Error::NotImplemented);
+        }
+
+        Ok(Self {
+            sev: Some(policy),
+            ..self
+        })
+    }
+
+    #[cfg(target_arch = "aarch64")]
+    pub fn with_gic(self, config: GicConfig) -> Result<Self, Error> {
+        // KVM supports up to 1024 interrupt IDs in multiples of 32, including the private ones.
+        let count = config.spi_count + GIC_SPI_BASE;
+
+        if config.spi_count == 0 || count % 32 != 0 || count > 1024 {
+            return Err(Error::InvalidArgument);
+        }
+
+        Ok(Self {
+            gic: Some(config),
+            ..self
+        })
+    }
+
+    #[cfg(feature = "xen")]
+    pub fn with_xen(self, config: XenConfig) -> Result<Self, Error> {
+        Ok(Self {
+            xen: Some(config),
+            ..self
+        })
+    }
+
+    /// Creates the in-kernel PIT, which also emulates port 0x61, and sets up whether KVM
+    /// reinjects the missed ticks of channel 0.
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    fn create_pit(&self, reinjection: PitReinjection) -> Result<(), Error> {
+        use kvm_bindings::{kvm_pit_config, kvm_reinject_control, KVM_PIT_SPEAKER_DUMMY};
+        use super::bindings::*;
+
+        self.vm.create_pit2(kvm_pit_config {
+            flags: KVM_PIT_SPEAKER_DUMMY,
+            ..Default::default()
+        })?;
+
+        let control = kvm_reinject_control {
+            pit_reinject: (reinjection == PitReinjection::Reinject) as u8,
+            ..Default::default()
+        };
+
+        unsafe {
+            ioctl_with_ref(self.vm.as_raw_fd(), KVM_REINJECT_CONTROL, &control)
+        }?;
+
+        Ok(())
+    }
+
+    #[cfg(feature = "xen")]
+    fn setup_xen(&self, config: &XenConfig) -> Result<(), Error> {
+        use super::bindings::*;
+
+        let fd = self.vm.as_raw_fd();
+
+        // Check if KVM can intercept the hypercalls and populate the hypercall page for us.
+        let flags = unsafe {
+            ioctl_with_val(fd, KVM_CHECK_EXTENSION, KVM_CAP_XEN_HVM as _)
+        }? as u32;
+
+        let required = KVM_XEN_HVM_CONFIG_HYPERCALL_MSR | KVM_XEN_HVM_CONFIG_INTERCEPT_HCALL;
+
+        if flags & required != required {
+            return Err(Error::NotImplemented);
+        }
+
+        let xen_config = kvm_xen_hvm_config {
+            flags: KVM_XEN_HVM_CONFIG_INTERCEPT_HCALL,
+            msr: config.hypercall_msr,
+            ..Default::default()
+        };
+
+        unsafe {
+            ioctl_with_ref(fd, KVM_XEN_HVM_CONFIG, &xen_config)
+        }?;
+
+        // Older kernels only support the hypercall page.
+        if flags & KVM_XEN_HVM_CONFIG_SHARED_INFO != 0 {
+            let mut attr = kvm_xen_attr {
+                ty: KVM_XEN_ATTR_TYPE_LONG_MODE,
+                ..Default::default()
+            };
+            attr.u[0] = config.long_mode as u64;
+
+            unsafe {
+                ioctl_with_ref(fd, KVM_XEN_HVM_SET_ATTR, &attr)
+            }?;
+        }
+
+        Ok(())
+    }
+
     pub fn build(self, _name: &str) -> Result<Vm, Error> {
         self.vm.set_tss_address(0xfffb_d000)?;
 
-        Ok(Vm {
-            vm: self.vm,
-            segments: HashMap::new(),
-            physical_ranges: RangeMap::new(),
-            available_slots: vec![],
-        })
+        #[cfg(feature = "xen")]
+        if let Some(config) = self.xen.as_ref() {
+            self.setup_xen(config)?;
+        }
+
+        // The interrupt controllers have to be created before creating any virtual CPU.
+        #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+        if self.irqchip {
+            self.vm.create_irq_chip()?;
+        }
+
+        // KVM delivers the interrupts of the PIT through the in-kernel interrupt controllers.
+        #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+        if let Some(reinjection) = self.pit {
+            if !self.irqchip {
+                return Err(Error::InvalidArgument);
+            }
+
+            self.create_pit(reinjection)?;
+        }
+
+        // SEV has to be initialized before creating any virtual CPU.
+        #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+        let sev = match self.sev {
+            Some(policy) => Some(Sev::new(&self.vm, policy)?),
+            _ => None,
+        };
+
+        #[cfg(target_arch = "aarch64")]
+        let gic = match self.gic.as_ref() {
+            Some(config) => Some(Arc::new(Gic::new(&self.vm, config)?)),
+            _ => None,
+        };
+
+        Ok(Vm {
+            vm: self.vm,
+            segments: HashMap::new(),
+            physical_ranges: RangeMap::new(),
+            available_slots: vec![],
+            next_slot: 0,
+            smram_segments: HashMap::new(),
+            smram_ranges: RangeMap::new(),
+            protected: self.protected,
+            dirty_tracking: false,
+            userfault: None,
+            #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+            cpuid: self.cpuid,
+            #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+            msr_exit_reasons: self.msr_exit_reasons,
+            #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+            sev,
+            #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+            irqchip: self.irqchip,
+            #[cfg(target_arch = "aarch64")]
+            gic,
+        })
+    }
+}
+
+/// The in-kernel GICv3, which is shared with the virtual CPUs, as KVM only allows it to be
+/// initialized once all the virtual CPUs have been created.
+#[cfg(target_arch = "aarch64")]
+pub struct Gic {
+    device: DeviceFd,
+    initialized: Mutex<bool>,
+}
+
+#[cfg(target_arch = "aarch64")]
+impl Gic {
+    /// Creates the GIC and sets up the base addresses and the number of interrupts.
+    fn new(vm: &VmFd, config: &GicConfig) -> Result<Self, Error> {
+        use kvm_bindings::kvm_create_device;
+        use super::bindings::*;
+
+        let mut device = kvm_create_device {
+            type_: KVM_DEV_TYPE_ARM_VGIC_V3,
+            ..Default::default()
+        };
+
+        let gic = Self {
+            device: vm.create_device(&mut device)?,
+            initialized: Mutex::new(false),
+        };
+
+        gic.set_attr(
+            KVM_DEV_ARM_VGIC_GRP_ADDR,
+            KVM_VGIC_V3_ADDR_TYPE_DIST,
+            config.distributor_base,
+        )?;
+        gic.set_attr(
+            KVM_DEV_ARM_VGIC_GRP_ADDR,
+            KVM_VGIC_V3_ADDR_TYPE_REDIST,
+            config.redistributor_base,
+        )?;
+        gic.set_attr(KVM_DEV_ARM_VGIC_GRP_NR_IRQS, 0, (config.spi_count + GIC_SPI_BASE) as u64)?;
+
+        Ok(gic)
+    }
+
+    /// Helper function to set the given attribute, where KVM reads the value through a pointer.
+    /// The number of interrupts is read as a 32-bit value, which is the lower half on
+    /// little-endian hosts.
+    fn set_attr(&self, group: u32, attr: u64, value: u64) -> Result<(), Error> {
+        let attr = kvm_bindings::kvm_device_attr {
+            group,
+            attr,
+            addr: &value as *const u64 as u64,
+            ..Default::default()
+        };
+
+        self.device.set_device_attr(&attr)?;
+
+        Ok(())
+    }
+
+    /// Initializes the GIC, unless it has been initialized already.
+    pub(crate) fn initialize(&self) -> Result<(), Error> {
+        use super::bindings::*;
+
+        let mut initialized = self.initialized.lock().unwrap();
+
+        if *initialized {
+            return Ok(());
+        }
+
+        self.set_attr(KVM_DEV_ARM_VGIC_GRP_CTRL, KVM_DEV_ARM_VGIC_CTRL_INIT, 0)?;
+        *initialized = true;
+
+        Ok(())
+    }
+}
+
+/// Helper function to get the CPUID supported by KVM with 5-level paging exposed or hidden.
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+fn cpuid_with_la57(enabled: bool) -> Result<CpuId, Error> {
+    use crate::arch::x86_64::CPUID_7_0_ECX_LA57;
+
+    let mut cpuid = Kvm::new()?.get_supported_cpuid(KVM_MAX_CPUID_ENTRIES)?;
+    let mut supported = false;
+
+    for entry in cpuid.as_mut_slice() {
+        if entry.function == 7 && entry.index == 0 {
+            supported = entry.ecx & CPUID_7_0_ECX_LA57 != 0;
+
+            if !enabled {
+                entry.ecx &= !CPUID_7_0_ECX_LA57;
+            }
+        }
+    }
+
+    if enabled && !supported {
+        return Err(Error::NotImplemented);
+    }
+
+    Ok(cpuid)
+}
+
+/// Creates an anonymous file of the given size through `memfd_create` to back shared memory.
+pub fn create_shared_memory(size: usize) -> Result<File, Error> {
+    let fd = unsafe {
+        libc::memfd_create(b"hy-rs\0".as_ptr() as *const libc::c_char, libc::MFD_CLOEXEC)
+    };
+
+    if fd < 0 {
+        return Err(std::io::Error::last_os_error().into());
+    }
+
+    let file = unsafe { File::from_raw_fd(fd) };
+    file.set_len(size as u64)?;
+
+    Ok(file)
+}
+
+/// Helper function to discard the host pages backing the given memory of a segment with the given
+/// backing. Anonymous memory is backed by a memfd, whose pages have to be punched out to release
+/// them, unless it is backed by huge pages.
+fn discard_backing(
+    address: *mut u8,
+    size: usize,
+    backing: MemoryBackingKind,
+) -> Result<(), Error> {
+    match backing {
+        MemoryBackingKind::Anonymous | MemoryBackingKind::Overcommit => {
+            discard_shared(address, size)
+        }
+        _ => discard(address, size),
+    }
+}
+
+/// Helper function to check if the given range overlaps with any of the ranges in the range map.
+fn overlaps(ranges: &RangeMap<u64, u64>, range: &Range<u64>) -> bool {
+    ranges.gaps(range).next() != Some(range.clone())
+}
+
+pub struct Segment {
+    mapping: MmapMut,
+    region: kvm_userspace_memory_region,
+    protection: ProtectionFlags,
+    backing: MemoryBackingKind,
+    /// The protection flags of the sub-ranges that differ from the protection flags of the
+    /// region.
+    protections: RangeMap<u64, ProtectionFlags>,
+    /// The memory slots that the region is split into, if the sub-ranges of the region are not
+    /// all writable or all read-only.
+    slots: Vec<kvm_userspace_memory_region>,
+}
+
+impl Segment {
+    /// Returns the memory slots that the region is registered as.
+    fn memory_slots(&self) -> Vec<kvm_userspace_memory_region> {
+        if self.slots.is_empty() {
+            vec![self.region]
+        } else {
+            self.slots.clone()
+        }
+    }
+}
+
+pub struct Vm {
+    pub(crate) vm: VmFd,
+    pub(crate) segments: HashMap<u64, Segment>,
+    pub(crate) physical_ranges: RangeMap<u64, u64>,
+    pub(crate) available_slots: Vec<u32>,
+    pub(crate) next_slot: u32,
+    pub(crate) smram_segments: HashMap<u64, Segment>,
+    pub(crate) smram_ranges: RangeMap<u64, u64>,
+    pub(crate) protected: bool,
+    /// Whether KVM logs the pages that the guest writes to.
+    pub(crate) dirty_tracking: bool,
+    /// Serves the missing pages of the guest physical memory, if any.
+    pub(crate) userfault: Option<Userfault>,
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    pub(crate) cpuid: Option<CpuId>,
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    pub(crate) msr_exit_reasons: u32,
+    /// The SEV context, if SEV is enabled.
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    pub(crate) sev: Option<Sev>,
+    /// Whether the in-kernel PIC, I/O APIC and local APICs have been created.
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    pub(crate) irqchip: bool,
+    #[cfg(target_arch = "aarch64")]
+    pub(crate) gic: Option<Arc<Gic>>,
+}
+
+impl Vm {
+    /// Helper function to allocate a memory slot.
+    fn alloc_slot(&mut self) -> u32 {
+        match self.available_slots.pop() {
+            Some(slot) => slot,
+            _ => {
+                let slot = self.next_slot;
+                self.next_slot += 1;
+                slot
+            }
+        }
+    }
+
+    /// Helper function to look up the memory slot starting at the given guest address.
+    fn memory_slot(&self, guest_address: u64) -> Option<kvm_userspace_memory_region> {
+        let start = self.physical_ranges.get(&guest_address)?;
+
+        self.segments
+            .get(start)?
+            .memory_slots()
+            .into_iter()
+            .find(|slot| slot.guest_phys_addr == guest_address)
+    }
+
+    /// Helper function to register the region at the given guest address as one memory slot for
+    /// every run of pages that are either all writable or all read-only, as KVM only supports
+    /// setting the protection of a memory slot as a whole.
+    fn update_memory_slots(&mut self, guest_address: u64) -> Result<(), Error> {
+        let segment = match self.segments.get(&guest_address) {
+            Some(segment) => segment,
+            _ => return Err(Error::InvalidGuestAddress),
+        };
+
+        let region = segment.region;
+        let old_slots = segment.memory_slots();
+        let readonly = |protection: ProtectionFlags| !protection.is_guest_writable();
+
+        // Split the region into runs of pages with the same protection as far as KVM is
+        // concerned.
+        let mut runs: Vec<(Range<u64>, bool)> = vec![];
+        let mut push_run = |range: Range<u64>, readonly: bool| {
+            match runs.last_mut() {
+                Some((last, last_readonly)) if *last_readonly == readonly => last.end = range.end,
+                _ => runs.push((range, readonly)),
+            }
+        };
+
+        let end = region.guest_phys_addr + region.memory_size;
+        let mut address = region.guest_phys_addr;
+
+        for (range, &protection) in segment.protections.iter() {
+            if address < range.start {
+                push_run(address..range.start, readonly(segment.protection));
+            }
+
+            push_run(range.clone(), readonly(protection));
+            address = range.end;
+        }
+
+        if address < end {
+            push_run(address..end, readonly(segment.protection));
+        }
+
+        // Remove the current memory slots.
+        for slot in &old_slots {
+            let mut removed = *slot;
+            removed.memory_size = 0;
+
+            unsafe {
+                self.set_user_memory_region(removed)
+            }?;
+        }
+
+        // Reuse the slots of the current memory slots.
+        let mut ids: Vec<u32> = old_slots.iter().map(|slot| slot.slot).collect();
+
+        while ids.len() < runs.len() {
+            let slot = self.alloc_slot();
+            ids.push(slot);
+        }
+
+        self.available_slots.extend(ids.drain(runs.len()..));
+
+        let slots: Vec<_> = runs
+            .into_iter()
+            .zip(ids)
+            .map(|((range, readonly), slot)| {
+                let mut flags = region.flags & !KVM_MEM_READONLY;
+
+                if readonly {
+                    flags |= KVM_MEM_READONLY;
+                }
+
+                kvm_userspace_memory_region {
+                    slot,
+                    guest_phys_addr: range.start,
+                    userspace_addr: region.userspace_addr + (range.start - region.guest_phys_addr),
+                    memory_size: range.end - range.start,
+                    flags,
+                }
+            })
+            .collect();
+
+        for slot in &slots {
+            unsafe {
+                self.set_user_memory_region(*slot)
+            }?;
+        }
+
+        if let Some(segment) = self.segments.get_mut(&guest_address) {
+            if slots.len() == 1 {
+                segment.region = slots[0];
+                segment.slots.clear();
+            } else {
+                segment.region.slot = slots[0].slot;
+                segment.slots = slots;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Helper function to reset the protection of the sub-ranges of the region at the given
+    /// guest address, such that the region is registered as a single memory slot again.
+    fn merge_memory_slots(&mut self, guest_address: u64) -> Result<(), Error> {
+        let segment = match self.segments.get_mut(&guest_address) {
+            Some(segment) => segment,
+            _ => return Err(Error::InvalidGuestAddress),
+        };
+
+        segment.protections = RangeMap::new();
+
+        if segment.slots.is_empty() {
+            return Ok(());
+        }
+
+        self.update_memory_slots(guest_address)
+    }
+
+    /// Helper function to set up a memory region. If SMRAM has been set up, then the region is
+    /// also mirrored into the SMM address space, unless it overlaps with SMRAM.
+    unsafe fn set_user_memory_region(
+        &self,
+        region: kvm_userspace_memory_region,
+    ) -> Result<(), Error> {
+        self.vm.set_user_memory_region(region)?;
+
+        if self.smram_segments.is_empty() {
+            return Ok(());
+        }
+
+        // Look up the original size when removing the memory region.
+        let size = match region.memory_size {
+            0 => match self.memory_slot(region.guest_phys_addr) {
+                Some(slot) => slot.memory_size,
+                _ => return Ok(()),
+            },
+            size => size,
+        };
+
+        let range = region.guest_phys_addr..region.guest_phys_addr + size;
+
+        if overlaps(&self.smram_ranges, &range) {
+            return Ok(());
+        }
+
+        let mut mirror = region;
+        mirror.slot |= KVM_SMM_ADDRESS_SPACE;
+
+        self.vm.set_user_memory_region(mirror)?;
+
+        Ok(())
+    }
+
+    /// Allocates SMRAM, i.e. guest physical memory that is only visible to the virtual CPUs
+    /// while they are in System Management Mode. Any regular guest physical memory that does not
+    /// overlap with SMRAM is mirrored into the SMM address space.
+    pub fn allocate_smram(
+        &mut self,
+        guest_address: u64,
+        size: usize,
+    ) -> Result<(), Error> {
+        let range = match guest_address.checked_add(size as u64) {
+            Some(end) => guest_address..end,
+            _ => return Err(Error::InvalidGuestAddress),
+        };
+
+        if overlaps(&self.smram_ranges, &range) {
+            return Err(Error::InvalidGuestAddress);
+        }
+
+        // Restore the mirrors in the SMM address space that have been changed so far, if setting
+        // up the SMRAM fails.
+        let mut changed = vec![];
+        let result = self.set_up_smram(range, &mut changed);
+
+        if result.is_err() {
+            for mirror in changed.into_iter().rev() {
+                let _ = unsafe { self.vm.set_user_memory_region(mirror) };
+            }
+        }
+
+        result
+    }
+
+    /// Implements [`Vm::allocate_smram`], where the previous state of every mirror in the SMM
+    /// address space that gets changed is pushed to `changed`.
+    fn set_up_smram(
+        &mut self,
+        range: Range<u64>,
+        changed: &mut Vec<kvm_userspace_memory_region>,
+    ) -> Result<(), Error> {
+        let guest_address = range.start;
+        let size = (range.end - range.start) as usize;
+
+        // Mirror the regular guest physical memory into the SMM address space the first time
+        // SMRAM gets set up.
+        if self.smram_segments.is_empty() {
+            for slot in self.segments.values().flat_map(|segment| segment.memory_slots()) {
+                let slot_range = slot.guest_phys_addr..slot.guest_phys_addr + slot.memory_size;
+
+                if slot_range.start < range.end && range.start < slot_range.end {
+                    continue;
+                }
+
+                let mut mirror = slot;
+                mirror.slot |= KVM_SMM_ADDRESS_SPACE;
+
+                unsafe {
+                    self.vm.set_user_memory_region(mirror)
+                }?;
+
+                mirror.memory_size = 0;
+                changed.push(mirror);
+            }
+        } else {
+            // Remove the mirrors of any regular guest physical memory overlapping with the new
+            // SMRAM.
+            for slot in self.segments.values().flat_map(|segment| segment.memory_slots()) {
+                let slot_range = slot.guest_phys_addr..slot.guest_phys_addr + slot.memory_size;
+
+                if slot_range.start >= range.end || range.start >= slot_range.end {
+                    continue;
+                }
+
+                if overlaps(&self.smram_ranges, &slot_range) {
+                    continue;
+                }
+
+                let mut mirror = slot;
+                mirror.slot |= KVM_SMM_ADDRESS_SPACE;
+                mirror.memory_size = 0;
+
+                unsafe {
+                    self.vm.set_user_memory_region(mirror)
+                }?;
+
+                mirror.memory_size = slot.memory_size;
+                changed.push(mirror);
+            }
+        }
+
+        let mapping = MmapOptions::new(size)
+            .map_mut()?;
+
+        let slot = self.alloc_slot();
+        let userspace_addr = mapping.as_ptr()
+            as *const std::ffi::c_void
+            as usize
+            as u64;
+        let segment = Segment {
+            mapping,
+            region: kvm_userspace_memory_region {
+                slot: slot | KVM_SMM_ADDRESS_SPACE,
+                guest_phys_addr: guest_address,
+                userspace_addr,
+                memory_size: size as u64,
+                flags: 0,
+            },
+            protection: ProtectionFlags::all(),
+            backing: MemoryBackingKind::Anonymous,
+            protections: RangeMap::new(),
+            slots: vec![],
+        };
+
+        let result = unsafe {
+            self.vm.set_user_memory_region(segment.region)
+        };
+
+        if let Err(e) = result {
+            self.available_slots.push(slot);
+
+            return Err(e.into());
+        }
+
+        self.smram_segments.insert(guest_address, segment);
+        self.smram_ranges.insert(range, guest_address);
+
+        Ok(())
+    }
+
+    /// Reads the bytes starting at the guest address in SMRAM into the given bytes buffer.
+    pub fn read_smram(
+        &self,
+        bytes: &mut [u8],
+        guest_address: u64,
+    ) -> Result<usize, Error> {
+        // The guest memory of protected guests is not accessible to the host.
+        if self.protected {
+            return Err(Error::ProtectedGuestMemory);
+        }
+
+        // Look up the base guest address.
+        let range = match self.smram_ranges.get_key_value(&guest_address) {
+            Some((range, _)) => range.clone(),
+            _ => return Err(Error::InvalidGuestAddress),
+        };
+
+        // Look up the segment.
+        let segment = match self.smram_segments.get(&range.start) {
+            Some(segment) => segment,
+            _ => return Err(Error::InvalidGuestAddress),
+        };
+
+        // Calculate the offset and size.
+        let offset = (guest_address - range.start) as usize;
+        let size = ((range.end - guest_address) as usize).min(bytes.len());
+
+        bytes[..size].copy_from_slice(&segment.mapping[offset..offset + size]);
+
+        Ok(size)
+    }
+
+    /// Writes the bytes from the given bytes buffer to the bytes starting at the guest address in
+    /// SMRAM.
+    pub fn write_smram(
+        &mut self,
+        guest_address: u64,
+        bytes: &[u8],
+    ) -> Result<usize, Error> {
+        // The guest memory of protected guests is not accessible to the host.
+        if self.protected {
+            return Err(Error::ProtectedGuestMemory);
+        }
+
+        // Look up the base guest address.
+        let range = match self.smram_ranges.get_key_value(&guest_address) {
+            Some((range, _)) => range.clone(),
+            _ => return Err(Error::InvalidGuestAddress),
+        };
+
+        // Look up the segment.
+        let segment = match self.smram_segments.get_mut(&range.start) {
+            Some(segment) => segment,
+            _ => return Err(Error::InvalidGuestAddress),
+        };
+
+        // Calculate the offset and size.
+        let offset = (guest_address - range.start) as usize;
+        let size = ((range.end - guest_address) as usize).min(bytes.len());
+
+        segment.mapping[offset..offset + size].copy_from_slice(&bytes[..size]);
+
+        Ok(size)
+    }
+
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    pub fn post_synthetic_message(
+        &self,
+        _vcpu_id: usize,
+        _sint: u8,
+        _message: &SyntheticMessage,
+    ) -> Result<(), Error> {
+        Err(Error::NotImplemented)
+    }
+
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    pub fn signal_synthetic_event(
+        &self,
+        _vcpu_id: usize,
+        _sint: u8,
+        _flag: u16,
+    ) -> Result<bool, Error> {
+        Err(Error::NotImplemented)
+    }
+
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    pub fn set_msr_filter(
+        &mut self,
+        ranges: &[Range<u32>],
+        policy: MsrPolicy,
+    ) -> Result<(), Error> {
+        use kvm_bindings::kvm_enable_cap;
+        use super::bindings::*;
+
+        // The capability reports the supported exit reasons.
+        let reasons = unsafe {
+            ioctl_with_val(
+                self.vm.as_raw_fd(),
+                KVM_CHECK_EXTENSION,
+                KVM_CAP_X86_USER_SPACE_MSR as _,
+            )
+        }? as u32;
+
+        if reasons & KVM_MSR_EXIT_REASON_FILTER == 0 {
+            return Err(Error::NotImplemented);
+        }
+
+        // Split the ranges such that the bitmap of every range fits.
+        let max_msrs = (KVM_MSR_FILTER_MAX_BITMAP_SIZE * 8) as u32;
+        let ranges: Vec<Range<u32>> = ranges
+            .iter()
+            .flat_map(|range| {
+                (range.start..range.end)
+                    .step_by(max_msrs as usize)
+                    .map(move |start| start..range.end.min(start.saturating_add(max_msrs)))
+            })
+            .collect();
+
+        if ranges.len() > KVM_MSR_FILTER_MAX_RANGES {
+            return Err(Error::InvalidArgument);
+        }
+
+        // A set bit allows KVM to handle the MSR, while a clear bit denies the access, which
+        // then exits to user space.
+        let (flags, fill) = match policy {
+            MsrPolicy::Exit => (KVM_MSR_FILTER_DEFAULT_ALLOW, 0x00),
+            MsrPolicy::Native => (KVM_MSR_FILTER_DEFAULT_DENY, 0xff),
+        };
+
+        let mut bitmaps: Vec<Vec<u8>> = ranges
+            .iter()
+            .map(|range| vec![fill; ((range.end - range.start) as usize + 7) / 8])
+            .collect();
+
+        let mut filter = kvm_msr_filter {
+            flags,
+            ..Default::default()
+        };
+
+        let entries = ranges.iter().zip(&mut bitmaps);
+
+        for (entry, (range, bitmap)) in filter.ranges.iter_mut().zip(entries) {
+            *entry = kvm_msr_filter_range {
+                flags: KVM_MSR_FILTER_READ | KVM_MSR_FILTER_WRITE,
+                nmsrs: range.end - range.start,
+                base: range.start,
+                bitmap: bitmap.as_mut_ptr(),
+            };
+        }
+
+        let mut cap = kvm_enable_cap {
+            cap: KVM_CAP_X86_USER_SPACE_MSR,
+            ..Default::default()
+        };
+        cap.args[0] = (self.msr_exit_reasons | KVM_MSR_EXIT_REASON_FILTER) as u64;
+
+        self.vm.enable_cap(&cap)?;
+        self.msr_exit_reasons |= KVM_MSR_EXIT_REASON_FILTER;
+
+        unsafe {
+            ioctl_with_ref(self.vm.as_raw_fd(), KVM_X86_SET_MSR_FILTER, &filter)
+        }?;
+
+        Ok(())
+    }
+
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    pub fn set_irq_line(&self, gsi: u32, level: bool) -> Result<(), Error> {
+        if !self.irqchip {
+            return Err(Error::NotImplemented);
+        }
+
+        self.vm.set_irq_line(gsi, level)?;
+
+        Ok(())
+    }
+
+    pub fn create_vcpu(&mut self, id: usize) -> Result<Vcpu, Error> {
+        let vcpu = self.vm.create_vcpu(id as u64)?;
+        let kvm_run = KvmRun::new(vcpu.as_raw_fd())?;
+
+        #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+        if let Some(cpuid) = self.cpuid.as_ref() {
+            vcpu.set_cpuid2(cpuid)?;
+        }
+
+        Ok(Vcpu {
+            vcpu,
+            kvm_run,
+            kick: Default::default(),
+            immediate_exit: false,
+            pending_io_in: None,
+            pending_mmio_read: None,
+            #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+            apic_base: None,
+            #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+            apic_base_changed: false,
+            #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+            pending_msr_read: false,
+            #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+            pending_interrupts: Default::default(),
+            #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+            interrupt_window_requested: false,
+            #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+            irqchip: self.irqchip,
+            #[cfg(target_arch = "aarch64")]
+            gic: self.gic.clone(),
+        })
+    }
+
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    pub fn sev_launch_update_data(
+        &self,
+        guest_address: u64,
+        size: usize,
+    ) -> Result<(), Error> {
+        let sev = self.sev.as_ref().ok_or(Error::NotImplemented)?;
+        let host_address = self.host_address(guest_address, size)?;
+
+        sev.launch_update_data(&self.vm, host_address as u64, size as u64)
+    }
+
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    pub fn sev_launch_update_vmsa(&self) -> Result<(), Error> {
+        let sev = self.sev.as_ref().ok_or(Error::NotImplemented)?;
+
+        sev.launch_update_vmsa(&self.vm)
+    }
+
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    pub fn sev_launch_measure(&self) -> Result<LaunchMeasurement, Error> {
+        let sev = self.sev.as_ref().ok_or(Error::NotImplemented)?;
+
+        sev.launch_measure(&self.vm)
+    }
+
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    pub fn sev_launch_finish(&self) -> Result<(), Error> {
+        let sev = self.sev.as_ref().ok_or(Error::NotImplemented)?;
+
+        sev.launch_finish(&self.vm)
+    }
+
+    #[cfg(target_arch = "aarch64")]
+    pub fn set_irq_level(&self, intid: u32, level: bool) -> Result<(), Error> {
+        use super::bindings::*;
+
+        if self.gic.is_none() {
+            return Err(Error::NotImplemented);
+        }
+
+        let irq = (KVM_ARM_IRQ_TYPE_SPI << KVM_ARM_IRQ_TYPE_SHIFT) | intid;
+
+        self.vm.set_irq_line(irq, level)?;
+
+        Ok(())
     }
-}
 
-pub struct Segment {
-    mapping: MmapMut,
-    region: kvm_userspace_memory_region,
-}
+    #[cfg(feature = "xen")]
+    pub fn set_xen_shared_info(&mut self, gfn: u64) -> Result<(), Error> {
+        use super::bindings::*;
 
-pub struct Vm {
-    pub(crate) vm: VmFd,
-    pub(crate) segments: HashMap<u64, Segment>,
-    pub(crate) physical_ranges: RangeMap<u64, u64>,
-    pub(crate) available_slots: Vec<u32>,
-}
+        let mut attr = kvm_xen_attr {
+            ty: KVM_XEN_ATTR_TYPE_SHARED_INFO,
+            ..Default::default()
+        };
+        attr.u[0] = gfn;
 
-impl Vm {
-    pub fn create_vcpu(&mut self, id: usize) -> Result<Vcpu, Error> {
-        let vcpu = self.vm.create_vcpu(id as u64)?;
+        unsafe {
+            ioctl_with_ref(self.vm.as_raw_fd(), KVM_XEN_HVM_SET_ATTR, &attr)
+        }?;
 
-        Ok(Vcpu {
-            vcpu,
-        })
+        Ok(())
+    }
+
+    #[cfg(feature = "xen")]
+    pub fn set_xen_upcall_vector(&mut self, vector: u8) -> Result<(), Error> {
+        use super::bindings::*;
+
+        let mut attr = kvm_xen_attr {
+            ty: KVM_XEN_ATTR_TYPE_UPCALL_VECTOR,
+            ..Default::default()
+        };
+        attr.u[0] = vector as u64;
+
+        unsafe {
+            ioctl_with_ref(self.vm.as_raw_fd(), KVM_XEN_HVM_SET_ATTR, &attr)
+        }?;
+
+        Ok(())
+    }
+
+    #[cfg(feature = "xen")]
+    pub fn send_xen_event(
+        &self,
+        port: u32,
+        vcpu_id: usize,
+    ) -> Result<(), Error> {
+        use super::bindings::*;
+
+        let event = kvm_irq_routing_xen_evtchn {
+            port,
+            vcpu: vcpu_id as u32,
+            priority: KVM_IRQ_ROUTING_XEN_EVTCHN_PRIO_2LEVEL,
+        };
+
+        unsafe {
+            ioctl_with_ref(self.vm.as_raw_fd(), KVM_XEN_HVM_EVTCHN_SEND, &event)
+        }?;
+
+        Ok(())
     }
 
     pub fn allocate_physical_memory(
@@ -62,6 +1218,7 @@ impl Vm {
             guest_address,
             mapping,
             protection,
+            MemoryBackingKind::Anonymous,
         )?;
 
         Ok(())
@@ -72,23 +1229,35 @@ impl Vm {
         guest_address: u64,
         mapping: MmapMut,
         protection: ProtectionFlags,
+        backing: MemoryBackingKind,
     ) -> Result<(), Error> {
+        check_overlap(&self.physical_ranges, guest_address..guest_address + mapping.len() as u64)?;
+
         let mut flags = 0;
 
-        if !protection.contains(ProtectionFlags::WRITE) {
+        // Guest writes to read-only memory slots exit as MMIO writes, which is how ROM is
+        // implemented.
+        if !protection.is_guest_writable() {
             flags |= KVM_MEM_READONLY;
         }
 
-        let slot = match self.available_slots.pop() {
-            Some(slot) => slot,
-            _ => self.segments.len() as u32,
-        };
+        if self.dirty_tracking {
+            flags |= KVM_MEM_LOG_DIRTY_PAGES;
+        }
 
         let userspace_addr = mapping.as_ptr()
             as *const std::ffi::c_void
             as usize
             as u64;
         let memory_size = mapping.len() as u64;
+
+        // The guest memory of SEV guests has to be pinned and registered with the SEV API.
+        #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+        if let Some(sev) = self.sev.as_ref() {
+            sev.register_region(&self.vm, userspace_addr, memory_size)?;
+        }
+
+        let slot = self.alloc_slot();
         let segment = Segment {
             mapping,
             region: kvm_userspace_memory_region {
@@ -98,10 +1267,14 @@ impl Vm {
                 memory_size,
                 flags,
             },
+            protection,
+            backing,
+            protections: RangeMap::new(),
+            slots: vec![],
         };
 
         unsafe {
-            self.vm.set_user_memory_region(segment.region)
+            self.set_user_memory_region(segment.region)
         }?;
 
         self.segments.insert(guest_address, segment);
@@ -110,36 +1283,215 @@ impl Vm {
         Ok(())
     }
 
+    pub fn regions(
+        &self,
+    ) -> Result<Vec<(Range<u64>, ProtectionFlags, MemoryBackingKind)>, Error> {
+        let regions = self.physical_ranges
+            .iter()
+            .filter_map(|(range, start)| {
+                let segment = self.segments.get(start)?;
+
+                Some((range.clone(), segment.protection, segment.backing))
+            })
+            .collect();
+
+        Ok(regions)
+    }
+
+    /// Returns the protection flags of the region containing the given guest address.
+    pub fn protection(&self, guest_address: u64) -> Option<ProtectionFlags> {
+        let start = self.physical_ranges.get(&guest_address)?;
+
+        self.segments
+            .get(start)
+            .map(|segment| segment.protection)
+    }
+
     pub fn unmap_physical_memory(
         &mut self,
         guest_address: u64,
     ) -> Result<(), Error> {
+        self.take_physical_memory(guest_address)?;
+
+        Ok(())
+    }
+
+    /// Unmaps the region at the given guest physical address like
+    /// [`Vm::unmap_physical_memory`], but hands back its mapping, protection and backing, such
+    /// that the region can be mapped again.
+    pub fn take_physical_memory(
+        &mut self,
+        guest_address: u64,
+    ) -> Result<(MmapMut, ProtectionFlags, MemoryBackingKind), Error> {
         // Look up the base guest address.
         let range = match self.physical_ranges.get_key_value(&guest_address) {
             Some((range, _)) => range.clone(),
             _ => return Err(Error::InvalidGuestAddress),
         };
 
-        // Look up the segment and clone the region.
-        let mut region = match self.segments.get(&range.start) {
-            Some(segment) => segment.region.clone(),
+        // Look up the segment and clone the memory slots.
+        let (region, slots) = match self.segments.get(&range.start) {
+            Some(segment) => (segment.region, segment.memory_slots()),
             _ => return Err(Error::InvalidGuestAddress),
         };
 
         // Unmap the guest physical memory from the VM.
-        region.memory_size = 0;
-        let slot = region.slot;
+        for slot in &slots {
+            let mut removed = *slot;
+            removed.memory_size = 0;
 
-        unsafe {
-            self.vm.set_user_memory_region(region)
-        }?;
+            unsafe {
+                self.set_user_memory_region(removed)
+            }?;
+        }
+
+        #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+        if let Some(sev) = self.sev.as_ref() {
+            sev.unregister_region(&self.vm, region.userspace_addr, range.end - range.start)?;
+        }
 
         // Remove the physical address range and segment.
-        self.segments.remove(&range.start);
+        let segment = self.segments
+            .remove(&range.start)
+            .ok_or(Error::InvalidGuestAddress)?;
         self.physical_ranges.remove(range);
 
-        // Mark the slot as available again.
-        self.available_slots.push(slot);
+        // Mark the slots as available again.
+        self.available_slots.extend(slots.iter().map(|slot| slot.slot));
+
+        Ok((segment.mapping, segment.protection, segment.backing))
+    }
+
+    pub fn remap_physical_memory(
+        &mut self,
+        guest_address: u64,
+        new_guest_address: u64,
+    ) -> Result<(), Error> {
+        // The missing page handler would still be passed the old guest addresses.
+        if self.userfault.is_some() {
+            return Err(Error::NotImplemented);
+        }
+
+        // Register the region as a single memory slot again.
+        self.merge_memory_slots(guest_address)?;
+
+        // Look up the segment and clone the region.
+        let mut region = match self.segments.get(&guest_address) {
+            Some(segment) => segment.region,
+            _ => return Err(Error::InvalidGuestAddress),
+        };
+
+        let size = region.memory_size;
+
+        // KVM does not support moving a memory slot, so remove the slot and add it again.
+        let mut removed = region;
+        removed.memory_size = 0;
+
+        unsafe {
+            self.set_user_memory_region(removed)
+        }?;
+
+        region.guest_phys_addr = new_guest_address;
+
+        unsafe {
+            self.set_user_memory_region(region)
+        }?;
+
+        if let Some(mut segment) = self.segments.remove(&guest_address) {
+            segment.region = region;
+            self.segments.insert(new_guest_address, segment);
+        }
+
+        self.physical_ranges.remove(guest_address..guest_address + size);
+        self.physical_ranges.insert(new_guest_address..new_guest_address + size, new_guest_address);
+
+        Ok(())
+    }
+
+    pub fn resize_physical_memory(
+        &mut self,
+        guest_address: u64,
+        size: usize,
+        backing_file: Option<(File, u64)>,
+    ) -> Result<(), Error> {
+        // The guest memory of SEV guests is pinned as registered.
+        #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+        if self.sev.is_some() {
+            return Err(Error::NotImplemented);
+        }
+
+        // Register the region as a single memory slot again.
+        self.merge_memory_slots(guest_address)?;
+
+        // Look up the segment.
+        let (mut region, backing, mapping_size) = match self.segments.get(&guest_address) {
+            Some(segment) => (segment.region, segment.backing, segment.mapping.len()),
+            _ => return Err(Error::InvalidGuestAddress),
+        };
+
+        let old_size = region.memory_size;
+
+        // Growing the region beyond its mapping grows the memfd backing the anonymous memory and
+        // maps it again, such that the region stays shared. The userfaultfd ranges would still
+        // refer to the old mapping.
+        let mapping = if size > mapping_size {
+            let anonymous = matches!(
+                backing,
+                MemoryBackingKind::Anonymous | MemoryBackingKind::Overcommit,
+            );
+
+            let (file, offset) = match backing_file {
+                Some(backing_file) if anonymous && self.userfault.is_none() => backing_file,
+                _ => return Err(Error::NotImplemented),
+            };
+
+            let len = offset + size as u64;
+
+            if file.metadata()?.len() < len {
+                file.set_len(len)?;
+            }
+
+            let options = unsafe { MmapOptions::new(size).with_file(file, offset) };
+
+            Some(options.with_flags(MmapFlags::SHARED).map_mut()?)
+        } else {
+            None
+        };
+
+        // KVM does not support resizing a memory slot, so remove the slot and add it again.
+        let mut removed = region;
+        removed.memory_size = 0;
+
+        unsafe {
+            self.set_user_memory_region(removed)
+        }?;
+
+        if let Some(mapping) = mapping.as_ref() {
+            region.userspace_addr = mapping.as_ptr() as u64;
+        } else if (size as u64) < old_size {
+            // Release the host pages beyond the end of the region.
+            let address = (region.userspace_addr + size as u64) as *mut u8;
+
+            discard_backing(address, (old_size - size as u64) as usize, backing)?;
+        }
+
+        region.memory_size = size as u64;
+
+        unsafe {
+            self.set_user_memory_region(region)
+        }?;
+
+        if let Some(segment) = self.segments.get_mut(&guest_address) {
+            segment.region = region;
+
+            // The pages are kept by the memfd, so the old mapping can simply be unmapped.
+            if let Some(mapping) = mapping {
+                segment.mapping = mapping;
+            }
+        }
+
+        self.physical_ranges.remove(guest_address..guest_address + old_size);
+        self.physical_ranges.insert(guest_address..guest_address + size as u64, guest_address);
 
         Ok(())
     }
@@ -161,24 +1513,146 @@ impl Vm {
             _ => return Err(Error::InvalidGuestAddress),
         };
 
-        if protection.contains(ProtectionFlags::WRITE) {
-            segment.region.flags &= !KVM_MEM_READONLY;
+        segment.protection = protection;
+        segment.protections = RangeMap::new();
+
+        // KVM does not support changing the protection of a memory slot, so remove the slots and
+        // add them again.
+        self.update_memory_slots(range.start)
+    }
+
+    pub fn protect_physical_memory_range(
+        &mut self,
+        range: Range<u64>,
+        protection: ProtectionFlags,
+    ) -> Result<(), Error> {
+        // Look up the base guest address.
+        let region = match self.physical_ranges.get_key_value(&range.start) {
+            Some((region, _)) if range.end <= region.end => region.clone(),
+            _ => return Err(Error::InvalidGuestAddress),
+        };
+
+        // Look up the segment.
+        let segment = match self.segments.get_mut(&region.start) {
+            Some(segment) => segment,
+            _ => return Err(Error::InvalidGuestAddress),
+        };
+
+        if protection == segment.protection {
+            segment.protections.remove(range);
         } else {
-            segment.region.flags |= KVM_MEM_READONLY;
+            segment.protections.insert(range, protection);
         }
 
-        unsafe {
-            self.vm.set_user_memory_region(segment.region)
-        }?;
+        // KVM only supports setting the protection of a memory slot as a whole, so split the
+        // region into multiple memory slots.
+        self.update_memory_slots(region.start)
+    }
+
+    pub fn set_dirty_tracking(&mut self, enabled: bool) -> Result<(), Error> {
+        if enabled == self.dirty_tracking {
+            return Ok(());
+        }
+
+        // Update the flags of the memory regions, as KVM only logs the dirty pages of the
+        // regions that have the flag set.
+        let regions: Vec<_> = self.segments
+            .values_mut()
+            .flat_map(|segment| {
+                let slots = std::iter::once(&mut segment.region).chain(segment.slots.iter_mut());
+
+                for slot in slots {
+                    if enabled {
+                        slot.flags |= KVM_MEM_LOG_DIRTY_PAGES;
+                    } else {
+                        slot.flags &= !KVM_MEM_LOG_DIRTY_PAGES;
+                    }
+                }
+
+                segment.memory_slots()
+            })
+            .collect();
+
+        for region in regions {
+            unsafe {
+                self.set_user_memory_region(region)
+            }?;
+        }
+
+        self.dirty_tracking = enabled;
 
         Ok(())
     }
 
+    pub fn dirty_bitmap(&self, guest_address: u64) -> Result<DirtyBitmap, Error> {
+        if !self.dirty_tracking {
+            return Err(Error::InvalidArgument);
+        }
+
+        // Look up the base guest address.
+        let range = match self.physical_ranges.get_key_value(&guest_address) {
+            Some((range, _)) => range.clone(),
+            _ => return Err(Error::InvalidGuestAddress),
+        };
+
+        // Look up the segment.
+        let segment = match self.segments.get(&range.start) {
+            Some(segment) => segment,
+            _ => return Err(Error::InvalidGuestAddress),
+        };
+
+        let page_size = MmapOptions::page_size().1 as u64;
+
+        // KVM clears the dirty log of the slot upon retrieving it.
+        if segment.slots.is_empty() {
+            let bitmap = self.vm.get_dirty_log(
+                segment.region.slot,
+                segment.region.memory_size as usize,
+            )?;
+
+            return Ok(DirtyBitmap {
+                range,
+                page_size,
+                bitmap,
+            });
+        }
+
+        // Combine the dirty logs of the memory slots that the region is split into.
+        let pages = (range.end - range.start + page_size - 1) / page_size;
+        let mut bitmap = vec![0u64; ((pages + 63) / 64) as usize];
+
+        for slot in &segment.slots {
+            let offset = (slot.guest_phys_addr - range.start) / page_size;
+            let dirty_log = self.vm.get_dirty_log(slot.slot, slot.memory_size as usize)?;
+
+            for (index, &word) in dirty_log.iter().enumerate() {
+                for bit in (0..64).filter(|bit| word & (1 << bit) != 0) {
+                    let page = offset + (index * 64 + bit) as u64;
+
+                    if page < pages {
+                        bitmap[(page / 64) as usize] |= 1 << (page % 64);
+                    }
+                }
+            }
+        }
+
+        Ok(DirtyBitmap {
+            range,
+            page_size,
+            bitmap,
+        })
+    }
+
     pub fn read_physical_memory(
         &self,
         bytes: &mut [u8],
         guest_address: u64,
     ) -> Result<usize, Error> {
+        // The guest memory of protected guests is not accessible to the host.
+        if self.protected {
+            return Err(Error::ProtectedGuestMemory);
+        }
+
         // Look up the base guest address.
         let range = match self.physical_ranges.get_key_value(&guest_address) {
             Some((range, _)) => range.clone(),
@@ -205,6 +1679,11 @@ impl Vm {
         guest_address: u64,
         bytes: &[u8],
     ) -> Result<usize, Error> {
+        // The guest memory of protected guests is not accessible to the host.
+        if self.protected {
+            return Err(Error::ProtectedGuestMemory);
+        }
+
         // Look up the base guest address.
         let range = match self.physical_ranges.get_key_value(&guest_address) {
             Some((range, _)) => range.clone(),
@@ -225,4 +1704,119 @@ impl Vm {
 
         Ok(size)
     }
+
+    pub fn host_address(
+        &self,
+        guest_address: u64,
+        size: usize,
+    ) -> Result<*mut u8, Error> {
+        // The guest memory of protected guests is not accessible to the host.
+        if self.protected {
+            return Err(Error::ProtectedGuestMemory);
+        }
+
+        // Look up the base guest address.
+        let range = match self.physical_ranges.get_key_value(&guest_address) {
+            Some((range, _)) => range.clone(),
+            _ => return Err(Error::InvalidGuestAddress),
+        };
+
+        // Look up the segment.
+        let segment = match self.segments.get(&range.start) {
+            Some(segment) => segment,
+            _ => return Err(Error::InvalidGuestAddress),
+        };
+
+        // The access must not cross the end of the segment.
+        if guest_address + size as u64 > range.end {
+            return Err(Error::InvalidGuestAddress);
+        }
+
+        let offset = (guest_address - range.start) as usize;
+
+        Ok(unsafe { segment.mapping.as_ptr().add(offset) as *mut u8 })
+    }
+
+    pub fn missing_page_handler(&mut self, handler: MissingPageHandler) -> Result<(), Error> {
+        // The guest memory of protected guests is not accessible to the host.
+        if self.protected {
+            return Err(Error::ProtectedGuestMemory);
+        }
+
+        // Stop serving the faults through the previous handler first, as a range can only be
+        // registered once.
+        self.userfault = None;
+
+        let ranges = self.segments
+            .iter()
+            .map(|(&guest_address, segment)| UserfaultRange {
+                host_address: segment.region.userspace_addr,
+                size: segment.region.memory_size,
+                guest_address,
+            })
+            .collect();
+
+        self.userfault = Some(Userfault::new(ranges, handler)?);
+
+        Ok(())
+    }
+
+    pub fn reclaim(&self, range: Range<u64>) -> Result<(), Error> {
+        let size = (range.end - range.start) as usize;
+        let address = self.host_address(range.start, size)?;
+
+        let backing = self.physical_ranges
+            .get(&range.start)
+            .and_then(|start| self.segments.get(start))
+            .map(|segment| segment.backing)
+            .ok_or(Error::InvalidGuestAddress)?;
+
+        discard_backing(address, size, backing)
+    }
+
+    pub fn reinflate(&self, range: Range<u64>) -> Result<(), Error> {
+        let size = (range.end - range.start) as usize;
+
+        populate(self.host_address(range.start, size)?, size)
+    }
+
+    pub fn resident_size(&self) -> Result<usize, Error> {
+        let mut size = 0;
+
+        for segment in self.segments.values() {
+            size += resident_size(&segment.mapping)?;
+        }
+
+        Ok(size)
+    }
+
+    pub fn trim_resident_size(&self, limit: usize) -> Result<usize, Error> {
+        let mut size = self.resident_size()?;
+
+        // Page out whole segments until the resident size drops below the limit. The pages are
+        // faulted back in as the guest touches them.
+        for segment in self.segments.values() {
+            if size <= limit {
+                break;
+            }
+
+            let resident = resident_size(&segment.mapping)?;
+
+            let result = unsafe {
+                libc::madvise(
+                    segment.mapping.as_ptr() as *mut libc::c_void,
+                    segment.mapping.len(),
+                    libc::MADV_PAGEOUT,
+                )
+            };
+
+            if result < 0 {
+                return Err(std::io::Error::last_os_error().into());
+            }
+
+            size -= resident - resident_size(&segment.mapping)?;
+        }
+
+        Ok(size)
+    }
 }
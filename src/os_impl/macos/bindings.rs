@@ -42,10 +42,19 @@ pub const HV_MEMORY_READ:  hv_memory_flags_t = 1 << 0;
 pub const HV_MEMORY_WRITE: hv_memory_flags_t = 1 << 1;
 pub const HV_MEMORY_EXEC:  hv_memory_flags_t = 1 << 2;
 
+#[cfg(target_arch = "x86_64")]
 pub type hv_vcpuid_t = c_uint;
+#[cfg(target_arch = "aarch64")]
+pub type hv_vcpuid_t = u64;
 pub const HV_VCPU_DEFAULT: u64 = 0;
 
 pub type hv_exit_reason_t = u32;
+
+pub const HV_EXIT_REASON_CANCELED:         hv_exit_reason_t = 0;
+pub const HV_EXIT_REASON_EXCEPTION:        hv_exit_reason_t = 1;
+pub const HV_EXIT_REASON_VTIMER_ACTIVATED: hv_exit_reason_t = 2;
+pub const HV_EXIT_REASON_UNKNOWN:          hv_exit_reason_t = 3;
+
 pub type hv_exception_syndrome_t = u64;
 pub type hv_exception_address_t = u64;
 pub type hv_ipa_t = u64;
@@ -82,6 +91,7 @@ extern {
 #[cfg(target_arch = "x86_64")]
 extern {
     pub fn hv_vcpu_create(vcpu: *mut hv_vcpuid_t, flags: hv_vm_options_t) -> hv_return_t;
+    pub fn hv_vcpu_interrupt(vcpus: *const hv_vcpuid_t, vcpu_count: u32) -> hv_return_t;
 }
 
 #[cfg(target_arch = "aarch64")]
@@ -89,8 +99,68 @@ extern {
     pub fn hv_vcpu_create(
         vcpu: *mut hv_vcpuid_t,
         exit: *mut *const hv_vcpu_exit_t,
-        config: *const hv_vcpu_config_t,
+        config: hv_vcpu_config_t,
+    ) -> hv_return_t;
+    pub fn hv_vcpus_exit(vcpus: *const hv_vcpuid_t, vcpu_count: u32) -> hv_return_t;
+    pub fn hv_vcpu_get_reg(vcpu: hv_vcpuid_t, reg: hv_reg_t, value: *mut u64) -> hv_return_t;
+    pub fn hv_vcpu_set_reg(vcpu: hv_vcpuid_t, reg: hv_reg_t, value: u64) -> hv_return_t;
+    pub fn hv_vcpu_get_sys_reg(
+        vcpu: hv_vcpuid_t,
+        reg: hv_sys_reg_t,
+        value: *mut u64,
+    ) -> hv_return_t;
+    pub fn hv_vcpu_set_sys_reg(vcpu: hv_vcpuid_t, reg: hv_sys_reg_t, value: u64) -> hv_return_t;
+}
+
+/// The type that defines the general-purpose registers of AArch64, where `X0` to `X30` are
+/// numbered consecutively.
+#[cfg(target_arch = "aarch64")]
+pub type hv_reg_t = u32;
+
+#[cfg(target_arch = "aarch64")]
+pub const HV_REG_X0:   hv_reg_t = 0;
+#[cfg(target_arch = "aarch64")]
+pub const HV_REG_PC:   hv_reg_t = 31;
+#[cfg(target_arch = "aarch64")]
+pub const HV_REG_FPCR: hv_reg_t = 32;
+#[cfg(target_arch = "aarch64")]
+pub const HV_REG_FPSR: hv_reg_t = 33;
+#[cfg(target_arch = "aarch64")]
+pub const HV_REG_CPSR: hv_reg_t = 34;
+
+/// The type that defines the system registers of AArch64, which are encoded like the operands
+/// of the `mrs` and `msr` instructions.
+#[cfg(target_arch = "aarch64")]
+pub type hv_sys_reg_t = u16;
+
+#[cfg(target_arch = "aarch64")]
+pub const HV_SYS_REG_SP_EL0: hv_sys_reg_t = 0xc208;
+#[cfg(target_arch = "aarch64")]
+pub const HV_SYS_REG_SP_EL1: hv_sys_reg_t = 0xe208;
+
+/// The configuration of the GIC, which is an OS object that has to be released through
+/// `os_release`.
+#[cfg(target_arch = "aarch64")]
+pub type hv_gic_config_t = *mut core::ffi::c_void;
+
+#[cfg(target_arch = "aarch64")]
+extern {
+    pub fn hv_gic_config_create() -> hv_gic_config_t;
+    pub fn hv_gic_config_set_distributor_base(
+        config: hv_gic_config_t,
+        distributor_base_address: hv_ipa_t,
+    ) -> hv_return_t;
+    pub fn hv_gic_config_set_redistributor_base(
+        config: hv_gic_config_t,
+        redistributor_base_address: hv_ipa_t,
+    ) -> hv_return_t;
+    pub fn hv_gic_create(config: hv_gic_config_t) -> hv_return_t;
+    pub fn hv_gic_set_spi(intid: u32, level: bool) -> hv_return_t;
+    pub fn hv_gic_get_spi_interrupt_range(
+        spi_intid_base: *mut u32,
+        spi_intid_count: *mut u32,
     ) -> hv_return_t;
+    pub fn os_release(object: *mut core::ffi::c_void);
 }
 
 #[cfg(target_arch = "x86_64")]
@@ -164,6 +234,26 @@ pub enum hv_x86_reg_t {
     HV_X86_CR3,
     /// The value that identifies the x86 control register CR4.
     HV_X86_CR4,
+    /// The value that identifies the x86 debug register DR0.
+    HV_X86_DR0,
+    /// The value that identifies the x86 debug register DR1.
+    HV_X86_DR1,
+    /// The value that identifies the x86 debug register DR2.
+    HV_X86_DR2,
+    /// The value that identifies the x86 debug register DR3.
+    HV_X86_DR3,
+    /// The value that identifies the x86 debug register DR4.
+    HV_X86_DR4,
+    /// The value that identifies the x86 debug register DR5.
+    HV_X86_DR5,
+    /// The value that identifies the x86 debug register DR6.
+    HV_X86_DR6,
+    /// The value that identifies the x86 debug register DR7.
+    HV_X86_DR7,
+    /// The value that identifies the x86 task priority register.
+    HV_X86_TPR,
+    /// The value that identifies the x86 extended control register XCR0.
+    HV_X86_XCR0,
 }
 
 #[cfg(target_arch = "x86_64")]
@@ -173,6 +263,8 @@ extern {
     pub fn hv_vcpu_read_msr(vcpu: hv_vcpuid_t, msr: u32, value: *mut u64) -> hv_return_t;
     pub fn hv_vcpu_write_msr(vcpu: hv_vcpuid_t, msr: u32, value: u64) -> hv_return_t;
     pub fn hv_vcpu_enable_native_msr(vcpu: hv_vcpuid_t, msr: u32, value: bool) -> hv_return_t;
+    pub fn hv_vcpu_read_fpstate(vcpu: hv_vcpuid_t, buffer: *mut std::ffi::c_void, size: usize) -> hv_return_t;
+    pub fn hv_vcpu_write_fpstate(vcpu: hv_vcpuid_t, buffer: *mut std::ffi::c_void, size: usize) -> hv_return_t;
     pub fn hv_vmx_vcpu_read_vmcs(vcpu: hv_vcpuid_t, field: Vmcs, value: *mut u64) -> hv_return_t;
     pub fn hv_vmx_vcpu_write_vmcs(vcpu: hv_vcpuid_t, field: Vmcs, value: u64) -> hv_return_t;
 }
@@ -1,4 +1,8 @@
+#[cfg(target_arch = "x86_64")]
+use crate::arch::x86_64::{host_cpuid, CpuidEntry};
 use crate::error::Error;
+use crate::hypervisor::Capability;
+use crate::tsc::TscMode;
 use super::bindings::*;
 use super::vm::VmBuilder;
 
@@ -9,11 +13,33 @@ impl Hypervisor {
         Ok(Self)
     }
 
+    pub fn has_capability(&self, capability: Capability) -> bool {
+        match capability {
+            Capability::ProtectedGuest => false,
+            Capability::Hypercalls => true,
+        }
+    }
+
     pub fn build_vm(&self) -> Result<VmBuilder, Error> {
         unsafe {
             hv_vm_create(HV_VM_DEFAULT)
         }.into_result()?;
 
-        Ok(VmBuilder)
+        Ok(VmBuilder {
+            tsc_mode: TscMode::Native,
+            hlt_exiting: true,
+            cpuid_exits: false,
+            msr_exits: false,
+            #[cfg(target_arch = "x86_64")]
+            cr_exits: Default::default(),
+            #[cfg(target_arch = "aarch64")]
+            gic: None,
+        })
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    pub fn get_supported_cpuid(&self) -> Result<Vec<CpuidEntry>, Error> {
+        // The Hypervisor Framework leaves the CPUID of the guest to the VMM.
+        Ok(host_cpuid())
     }
 }
@@ -0,0 +1,153 @@
+#![allow(non_camel_case_types)]
+
+use crate::error::Error;
+use crate::thread::{ResourceLimits, ThreadPriority, ThreadPriorityReport};
+
+type kern_return_t = libc::c_int;
+type mach_port_t = libc::c_uint;
+type thread_policy_flavor_t = libc::c_uint;
+type thread_policy_t = *mut libc::c_int;
+type mach_msg_type_number_t = libc::c_uint;
+type qos_class_t = libc::c_uint;
+
+const KERN_SUCCESS: kern_return_t = 0;
+const THREAD_TIME_CONSTRAINT_POLICY: thread_policy_flavor_t = 2;
+const QOS_CLASS_USER_INTERACTIVE: qos_class_t = 0x21;
+
+#[repr(C)]
+#[derive(Default)]
+struct mach_timebase_info_data_t {
+    numer: u32,
+    denom: u32,
+}
+
+#[repr(C)]
+struct thread_time_constraint_policy_data_t {
+    period: u32,
+    computation: u32,
+    constraint: u32,
+    preemptible: u32,
+}
+
+extern {
+    fn mach_thread_self() -> mach_port_t;
+    fn mach_timebase_info(info: *mut mach_timebase_info_data_t) -> kern_return_t;
+    fn thread_policy_set(
+        thread: mach_port_t,
+        flavor: thread_policy_flavor_t,
+        policy_info: thread_policy_t,
+        count: mach_msg_type_number_t,
+    ) -> kern_return_t;
+    fn pthread_set_qos_class_self_np(
+        qos_class: qos_class_t,
+        relative_priority: libc::c_int,
+    ) -> libc::c_int;
+}
+
+/// The period of the time-constraint policy in nanoseconds.
+const PERIOD_NS: u64 = 1_000_000;
+/// The maximum amount of computation per period in nanoseconds, which is scaled by the requested
+/// real-time priority.
+const COMPUTATION_NS: u64 = 500_000;
+
+fn set_time_constraint(priority: u8) -> Result<(), kern_return_t> {
+    let mut timebase = mach_timebase_info_data_t::default();
+
+    let result = unsafe {
+        mach_timebase_info(&mut timebase)
+    };
+
+    if result != KERN_SUCCESS {
+        return Err(result);
+    }
+
+    // Convert nanoseconds to Mach absolute time units.
+    let to_abs = |ns: u64| (ns * timebase.denom as u64 / timebase.numer as u64) as u32;
+
+    // Scale the computation budget by the priority, such that higher priorities get a larger
+    // share of the period.
+    let priority = (priority as u64).max(1).min(99);
+    let computation = (COMPUTATION_NS * priority / 99).max(50_000);
+
+    let mut policy = thread_time_constraint_policy_data_t {
+        period: to_abs(PERIOD_NS),
+        computation: to_abs(computation),
+        constraint: to_abs(PERIOD_NS),
+        preemptible: 1,
+    };
+
+    let count = (std::mem::size_of::<thread_time_constraint_policy_data_t>() /
+        std::mem::size_of::<libc::c_int>()) as mach_msg_type_number_t;
+
+    let result = unsafe {
+        thread_policy_set(
+            mach_thread_self(),
+            THREAD_TIME_CONSTRAINT_POLICY,
+            &mut policy as *mut thread_time_constraint_policy_data_t as thread_policy_t,
+            count,
+        )
+    };
+
+    if result != KERN_SUCCESS {
+        return Err(result);
+    }
+
+    Ok(())
+}
+
+pub fn set_current_thread_priority(
+    priority: ThreadPriority,
+) -> Result<ThreadPriorityReport, Error> {
+    let mut diagnostics = vec![];
+
+    if let ThreadPriority::RealTime { priority: value } = priority {
+        match set_time_constraint(value) {
+            Ok(()) => return Ok(ThreadPriorityReport {
+                requested: priority,
+                applied: priority,
+                diagnostics,
+            }),
+            Err(e) => diagnostics.push(format!(
+                "thread_policy_set() with THREAD_TIME_CONSTRAINT_POLICY failed with kern_return_t \
+                {}; falling back to the user-interactive QoS class",
+                e,
+            )),
+        }
+    }
+
+    let result = unsafe {
+        pthread_set_qos_class_self_np(QOS_CLASS_USER_INTERACTIVE, 0)
+    };
+
+    if result != 0 {
+        diagnostics.push(format!(
+            "pthread_set_qos_class_self_np() with QOS_CLASS_USER_INTERACTIVE failed ({}); keeping \
+            the normal priority",
+            std::io::Error::from_raw_os_error(result),
+        ));
+
+        return Ok(ThreadPriorityReport {
+            requested: priority,
+            applied: ThreadPriority::Normal,
+            diagnostics,
+        });
+    }
+
+    Ok(ThreadPriorityReport {
+        requested: priority,
+        applied: ThreadPriority::Elevated,
+        diagnostics,
+    })
+}
+
+pub struct ResourceGroup;
+
+impl ResourceGroup {
+    pub fn new(_name: &str, _limits: &ResourceLimits) -> Result<Self, Error> {
+        Err(Error::NotImplemented)
+    }
+
+    pub fn add_current_thread(&self) -> Result<(), Error> {
+        Err(Error::NotImplemented)
+    }
+}
@@ -1,13 +1,213 @@
+use crate::debug::GuestDebug;
 use crate::error::Error;
-use crate::vcpu::ExitReason;
+use crate::hypercall::Hypercalls;
+use crate::tsc::VirtualTsc;
+use crate::vcpu::{ExitPolicy, ExitReason};
 use num_traits::FromPrimitive;
+#[cfg(target_arch = "x86_64")]
+use std::collections::VecDeque;
+use std::sync::Arc;
+#[cfg(target_arch = "x86_64")]
+use std::sync::RwLock;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread::ThreadId;
 use super::bindings::*;
 
 #[cfg(target_arch = "x86_64")]
 use crate::arch::x86_64::*;
+#[cfg(target_arch = "x86_64")]
+use crate::debug::{decode_dr6, encode_dr7, DebugExit, DebugExitKind};
+#[cfg(target_arch = "x86_64")]
+use crate::mmio::{self, CodeMode, MmioOperation, RegisterOperand};
+#[cfg(target_arch = "x86_64")]
+use crate::unwind::PagingState;
+
+/// The MSRs that the Hypervisor Framework can pass through to the guest, other than the ones that
+/// are required for long mode, see [`Vcpu::reset`].
+#[cfg(target_arch = "x86_64")]
+const FILTERED_NATIVE_MSRS: [u32; 4] = [
+    MSR_IA32_SYSENTER_CS,
+    MSR_IA32_SYSENTER_ESP,
+    MSR_IA32_SYSENTER_EIP,
+    MSR_IA32_TSC_AUX,
+];
+
+/// The size of the buffer for the `xsave` area of the virtual CPU, which covers the state
+/// components supported by the Hypervisor Framework.
+#[cfg(target_arch = "x86_64")]
+const FPSTATE_SIZE: usize = 4096;
+
+/// Helper function to map the [`DebugRegister`] to the register of the Hypervisor Framework.
+#[cfg(target_arch = "x86_64")]
+fn debug_register(register: DebugRegister) -> hv_x86_reg_t {
+    match register {
+        DebugRegister::Dr0 => hv_x86_reg_t::HV_X86_DR0,
+        DebugRegister::Dr1 => hv_x86_reg_t::HV_X86_DR1,
+        DebugRegister::Dr2 => hv_x86_reg_t::HV_X86_DR2,
+        DebugRegister::Dr3 => hv_x86_reg_t::HV_X86_DR3,
+        DebugRegister::Dr6 => hv_x86_reg_t::HV_X86_DR6,
+        DebugRegister::Dr7 => hv_x86_reg_t::HV_X86_DR7,
+    }
+}
 
 pub struct Vcpu {
     pub(crate) vcpu: hv_vcpuid_t,
+    pub(crate) tsc: Option<VirtualTsc>,
+    /// The Hypervisor Framework requires the virtual CPU to be used on the thread that created it.
+    pub(crate) thread: ThreadId,
+    /// Whether the virtual CPU has been kicked through a [`VcpuHandle`].
+    pub(crate) kicked: Arc<AtomicBool>,
+    /// Whether [`Vcpu::run`] returns without entering the guest.
+    pub(crate) immediate_exit: bool,
+    /// The value of the `IA32_APIC_BASE` MSR, which the Hypervisor Framework leaves to the VMM.
+    #[cfg(target_arch = "x86_64")]
+    pub(crate) apic_base: u64,
+    /// The data of the last port I/O exit.
+    #[cfg(target_arch = "x86_64")]
+    pub(crate) io_data: [u8; 4],
+    /// The size of the `in` instruction that still has to be completed with the data filled in
+    /// by the caller.
+    #[cfg(target_arch = "x86_64")]
+    pub(crate) pending_io_in: Option<usize>,
+    /// The data of the last MMIO exit.
+    #[cfg(target_arch = "x86_64")]
+    pub(crate) mmio_data: [u8; 8],
+    /// The size of the last MMIO access in bytes.
+    #[cfg(target_arch = "x86_64")]
+    pub(crate) mmio_size: usize,
+    /// The register of the MMIO read that still has to be completed with the data filled in by
+    /// the caller.
+    #[cfg(target_arch = "x86_64")]
+    pub(crate) pending_mmio_read: Option<RegisterOperand>,
+    /// Whether the `hlt` instruction exits, rather than halting the virtual CPU in the hypervisor.
+    #[cfg(target_arch = "x86_64")]
+    pub(crate) hlt_exiting: bool,
+    /// Whether to report the `cpuid` instructions as [`ExitReason::Cpuid`].
+    #[cfg(target_arch = "x86_64")]
+    pub(crate) cpuid_exits: bool,
+    /// Whether the last exit was a `cpuid` instruction that has not been completed yet.
+    #[cfg(target_arch = "x86_64")]
+    pub(crate) pending_cpuid: bool,
+    /// The CPUID table that serves the `cpuid` instructions that are not reported.
+    #[cfg(target_arch = "x86_64")]
+    pub(crate) cpuid: Option<Vec<CpuidEntry>>,
+    /// Whether to report the MSR accesses as [`ExitReason::MsrRead`] and
+    /// [`ExitReason::MsrWrite`].
+    #[cfg(target_arch = "x86_64")]
+    pub(crate) msr_exits: bool,
+    /// Whether the last exit was an `rdmsr` instruction that has not been completed yet.
+    #[cfg(target_arch = "x86_64")]
+    pub(crate) pending_msr_read: bool,
+    /// The accesses to the control registers that exit with [`ExitReason::CrAccess`].
+    #[cfg(target_arch = "x86_64")]
+    pub(crate) cr_exits: CrExits,
+    /// The MSR filter of the VM.
+    #[cfg(target_arch = "x86_64")]
+    pub(crate) msr_filter: Arc<RwLock<Option<MsrFilter>>>,
+    /// The MSR filter that was last applied to the virtual CPU.
+    #[cfg(target_arch = "x86_64")]
+    pub(crate) applied_msr_filter: Option<MsrFilter>,
+    /// The exceptions that exit for guest debugging.
+    #[cfg(target_arch = "x86_64")]
+    pub(crate) debug_exceptions: u32,
+    /// The exceptions that exit with [`ExitReason::Exception`].
+    #[cfg(target_arch = "x86_64")]
+    pub(crate) exception_exits: u32,
+    /// The vectors of the interrupts that are queued until the guest is able to accept them.
+    #[cfg(target_arch = "x86_64")]
+    pub(crate) pending_interrupts: VecDeque<u8>,
+    /// Whether an NMI is queued until the guest is able to accept it.
+    #[cfg(target_arch = "x86_64")]
+    pub(crate) pending_nmi: bool,
+    /// Whether the caller requested an exit upon the interrupt window, as opposed to the requests
+    /// made to inject the queued interrupts.
+    #[cfg(target_arch = "x86_64")]
+    pub(crate) interrupt_window_requested: bool,
+    /// The information about the last exit, which the Hypervisor Framework updates upon every
+    /// exit.
+    #[cfg(target_arch = "aarch64")]
+    pub(crate) exit: ExitInfo,
+    /// The data of the last MMIO exit.
+    #[cfg(target_arch = "aarch64")]
+    pub(crate) mmio_data: [u8; 8],
+    /// The access of the MMIO read that still has to be completed with the data filled in by the
+    /// caller.
+    #[cfg(target_arch = "aarch64")]
+    pub(crate) pending_mmio_read: Option<DataAbort>,
+}
+
+/// The exit information of a virtual CPU, which is owned by the Hypervisor Framework.
+#[cfg(target_arch = "aarch64")]
+pub(crate) struct ExitInfo(pub(crate) *const hv_vcpu_exit_t);
+
+// The exit information is only accessed on the thread that created the virtual CPU, see
+// `Vcpu::check_thread`.
+#[cfg(target_arch = "aarch64")]
+unsafe impl Send for ExitInfo {}
+
+#[derive(Clone)]
+pub struct VcpuHandle {
+    vcpu: hv_vcpuid_t,
+    kicked: Arc<AtomicBool>,
+}
+
+impl VcpuHandle {
+    pub fn clear_kick(&self) {
+        self.kicked.store(false, Ordering::SeqCst);
+    }
+
+    pub fn kick(&self) -> Result<(), Error> {
+        self.kicked.store(true, Ordering::SeqCst);
+
+        // Unlike the other functions, these may be called from any thread.
+        #[cfg(target_arch = "x86_64")]
+        unsafe {
+            hv_vcpu_interrupt(&self.vcpu, 1)
+        }.into_result()?;
+
+        #[cfg(target_arch = "aarch64")]
+        unsafe {
+            hv_vcpus_exit(&self.vcpu, 1)
+        }.into_result()?;
+
+        Ok(())
+    }
+}
+
+impl Vcpu {
+    /// Helper function to check if the virtual CPU is used on the thread that created it, as the
+    /// Hypervisor Framework fails with an obscure error otherwise.
+    fn check_thread(&self) -> Result<(), Error> {
+        if std::thread::current().id() != self.thread {
+            return Err(Error::WrongThread);
+        }
+
+        Ok(())
+    }
+
+    pub fn handle(&self) -> VcpuHandle {
+        VcpuHandle {
+            vcpu: self.vcpu,
+            kicked: self.kicked.clone(),
+        }
+    }
+
+    pub fn set_immediate_exit(&mut self, immediate_exit: bool) {
+        self.immediate_exit = immediate_exit;
+    }
+
+    pub fn virtual_tsc(&self) -> Option<u64> {
+        self.tsc.as_ref().map(|tsc| tsc.value)
+    }
+
+    pub fn set_virtual_tsc(&mut self, value: u64) -> Result<(), Error> {
+        match self.tsc.as_mut() {
+            Some(tsc) => tsc.value = value,
+            _ => return Err(Error::NotImplemented),
+        }
+
+        Ok(())
+    }
 }
 
 #[cfg(target_arch = "x86_64")]
@@ -52,6 +252,17 @@ impl Vcpu {
         Ok(())
     }
 
+    /// Helper function to read the `xsave` area of the virtual CPU.
+    fn read_fpstate(&self) -> Result<Vec<u8>, Error> {
+        let mut bytes = vec![0u8; FPSTATE_SIZE];
+
+        unsafe {
+            hv_vcpu_read_fpstate(self.vcpu, bytes.as_mut_ptr() as *mut _, bytes.len())
+        }.into_result()?;
+
+        Ok(bytes)
+    }
+
     /// Helper function read from a MSR.
     pub(crate) fn read_msr(&self, register: u32) -> Result<u64, Error> {
         let mut value = 0;
@@ -81,12 +292,77 @@ impl Vcpu {
         Ok(())
     }
 
+    /// Helper function to pass the MSRs through to the guest according to the MSR filter of the
+    /// VM, if the filter changed since it was last applied.
+    fn apply_msr_filter(&mut self) -> Result<(), Error> {
+        let filter = {
+            let filter = self.msr_filter.read().unwrap();
+
+            if *filter == self.applied_msr_filter {
+                return Ok(());
+            }
+
+            filter.clone()
+        };
+
+        for msr in FILTERED_NATIVE_MSRS {
+            let native = match filter.as_ref() {
+                Some(filter) => !filter.exits(msr),
+                _ => false,
+            };
+
+            self.enable_native_msr(msr, native)?;
+        }
+
+        self.applied_msr_filter = filter;
+
+        Ok(())
+    }
+
+    /// Helper function to check if the access to the given MSR should be reported.
+    fn reports_msr(&self, msr: u32) -> bool {
+        match self.applied_msr_filter.as_ref() {
+            Some(filter) => self.msr_exits || filter.exits(msr),
+            _ => self.msr_exits,
+        }
+    }
+
     /// Resets the CPU to default state.
     pub fn reset(&mut self) -> Result<(), Error> {
         let mut value = self.read_vmcs(Vmcs::CpuBased)?;
         let mut cpu_based = CpuBased::empty();
-        cpu_based |= CpuBased::HLT;
+        cpu_based |= CpuBased::UNCONDITIONAL_IO;
         cpu_based |= CpuBased::SECONDARY_CONTROLS;
+
+        // Without HLT exiting, the virtual CPU halts until it receives an interrupt.
+        if self.hlt_exiting {
+            cpu_based |= CpuBased::HLT;
+        } else {
+            value &= !(CpuBased::HLT.bits() as u64);
+        }
+
+        // Intercept `rdtsc` and `rdtscp` to serve the virtual TSC.
+        if self.tsc.is_some() {
+            cpu_based |= CpuBased::RDTSC;
+        }
+
+        // Intercept the accesses to CR3 and CR8 that are reported as `ExitReason::CrAccess`.
+        if self.cr_exits.contains(CrExits::CR3_WRITE) {
+            cpu_based |= CpuBased::CR3_LOAD;
+        }
+
+        if self.cr_exits.contains(CrExits::CR3_READ) {
+            cpu_based |= CpuBased::CR3_STORE;
+        }
+
+        if self.cr_exits.contains(CrExits::CR8_WRITE) {
+            cpu_based |= CpuBased::CR8_LOAD;
+        }
+
+        if self.cr_exits.contains(CrExits::CR8_READ) {
+            cpu_based |= CpuBased::CR8_STORE;
+        }
+
         value |= cpu_based.bits() as u64;
         self.write_vmcs(Vmcs::CpuBased, value)?;
 
@@ -102,58 +378,764 @@ impl Vcpu {
         self.write_vmcs(Vmcs::GuestCsLimit, 0xf_ffff)?;
         self.write_vmcs(Vmcs::GuestCsAccessRights, 0x9b)?;
 
-        self.write_vmcs(Vmcs::GuestDs, 0)?;
-        self.write_vmcs(Vmcs::GuestDsBase, 0)?;
-        self.write_vmcs(Vmcs::GuestDsLimit, 0xf_ffff)?;
-        self.write_vmcs(Vmcs::GuestDsAccessRights, 0x93)?;
+        self.write_vmcs(Vmcs::GuestDs, 0)?;
+        self.write_vmcs(Vmcs::GuestDsBase, 0)?;
+        self.write_vmcs(Vmcs::GuestDsLimit, 0xf_ffff)?;
+        self.write_vmcs(Vmcs::GuestDsAccessRights, 0x93)?;
+
+        self.write_vmcs(Vmcs::GuestEs, 0)?;
+        self.write_vmcs(Vmcs::GuestEsBase, 0)?;
+        self.write_vmcs(Vmcs::GuestEsLimit, 0xf_ffff)?;
+        self.write_vmcs(Vmcs::GuestEsAccessRights, 0x93)?;
+
+        self.write_vmcs(Vmcs::GuestFs, 0)?;
+        self.write_vmcs(Vmcs::GuestFsBase, 0)?;
+        self.write_vmcs(Vmcs::GuestFsLimit, 0xf_ffff)?;
+        self.write_vmcs(Vmcs::GuestFsAccessRights, 0x93)?;
+
+        self.write_vmcs(Vmcs::GuestGs, 0)?;
+        self.write_vmcs(Vmcs::GuestGsBase, 0)?;
+        self.write_vmcs(Vmcs::GuestGsLimit, 0xf_ffff)?;
+        self.write_vmcs(Vmcs::GuestGsAccessRights, 0x93)?;
+
+        self.write_vmcs(Vmcs::GuestSs, 0)?;
+        self.write_vmcs(Vmcs::GuestSsBase, 0)?;
+        self.write_vmcs(Vmcs::GuestSsLimit, 0xf_ffff)?;
+        self.write_vmcs(Vmcs::GuestSsAccessRights, 0x93)?;
+
+        self.write_vmcs(Vmcs::GuestLdtrAccessRights, 0x1_0000)?;
+        self.write_vmcs(Vmcs::GuestTrAccessRights, 0x8b)?;
+
+        // These MSRs must be enabled. Otherwise enabling the long mode bits in EFER would cause
+        // the VM entry to fail without any indicative exit reason.
+        self.enable_native_msr(MSR_IA32_LSTAR, true)?;
+        self.enable_native_msr(MSR_IA32_CSTAR, true)?;
+        self.enable_native_msr(MSR_IA32_STAR, true)?;
+        self.enable_native_msr(MSR_IA32_SYSCALL_MASK, true)?;
+        self.enable_native_msr(MSR_IA32_KERNEL_GS_BASE, true)?;
+
+        self.write_register(hv_x86_reg_t::HV_X86_RIP, 0xfff0)?;
+        self.write_register(hv_x86_reg_t::HV_X86_RFLAGS, 2)?;
+
+        self.write_register(hv_x86_reg_t::HV_X86_CR0, 0)?;
+        self.write_register(hv_x86_reg_t::HV_X86_CR4, CR4_VMXE)?;
+        self.write_vmcs(Vmcs::GuestEfer, 0)?;
+
+        // Own all the bits of CR0 and CR4 to intercept the writes to them, where the guest reads
+        // the values from the read shadows instead.
+        if self.cr_exits.contains(CrExits::CR0_WRITE) {
+            self.write_vmcs(Vmcs::Cr0Mask, !0)?;
+            self.write_vmcs(Vmcs::Cr0Shadow, 0)?;
+        }
+
+        if self.cr_exits.contains(CrExits::CR4_WRITE) {
+            self.write_vmcs(Vmcs::Cr4Mask, !0)?;
+            self.write_vmcs(Vmcs::Cr4Shadow, 0)?;
+        }
+
+        Ok(())
+    }
+
+    /// Helper function to skip the instruction that caused the VM exit.
+    pub(crate) fn skip_instruction(&mut self) -> Result<(), Error> {
+        let length = self.read_vmcs(Vmcs::ExitInstructionLength)?;
+        let rip = self.read_register(hv_x86_reg_t::HV_X86_RIP)?;
+        self.write_register(hv_x86_reg_t::HV_X86_RIP, rip + length)?;
+
+        Ok(())
+    }
+
+    /// Helper function to decode a control register access. Returns `None` if the access is not
+    /// selected through [`CrExits`]. Otherwise, the instruction is skipped right away, and reads
+    /// are completed with the current value of the control register.
+    fn decode_cr_access(
+        &mut self,
+    ) -> Result<Option<(ControlRegister, bool, u64, Option<Register>)>, Error> {
+        let qualification = self.read_vmcs(Vmcs::ExitQualification)?;
+
+        let cr = match qualification & 0xf {
+            0 => ControlRegister::Cr0,
+            3 => ControlRegister::Cr3,
+            4 => ControlRegister::Cr4,
+            8 => ControlRegister::Cr8,
+            _ => return Ok(None),
+        };
+
+        let register = mmio::REGISTERS[((qualification >> 8) & 0xf) as usize];
+
+        let (write, value, register) = match (qualification >> 4) & 0x3 {
+            // `mov` to the control register.
+            0 => (true, self.get_registers(&[register])?[0], Some(register)),
+            // `mov` from the control register.
+            1 => (false, self.get_control_registers(&[cr])?[0], Some(register)),
+            // `clts` clears CR0.TS.
+            2 => (true, self.get_control_registers(&[cr])?[0] & !CR0_TS, None),
+            // `lmsw` loads CR0.PE, CR0.MP, CR0.EM and CR0.TS, but cannot clear CR0.PE.
+            _ => {
+                let cr0 = self.get_control_registers(&[cr])?[0];
+                let source = (qualification >> 16) & 0xf;
+
+                (true, (cr0 & !0xe) | source, None)
+            }
+        };
+
+        let exits = match (cr, write) {
+            (ControlRegister::Cr0, true) => CrExits::CR0_WRITE,
+            (ControlRegister::Cr3, false) => CrExits::CR3_READ,
+            (ControlRegister::Cr3, true) => CrExits::CR3_WRITE,
+            (ControlRegister::Cr4, true) => CrExits::CR4_WRITE,
+            (ControlRegister::Cr8, false) => CrExits::CR8_READ,
+            (ControlRegister::Cr8, true) => CrExits::CR8_WRITE,
+            _ => return Ok(None),
+        };
+
+        if !self.cr_exits.contains(exits) {
+            return Ok(None);
+        }
+
+        if let (false, Some(register)) = (write, register) {
+            self.set_registers(&[register], &[value])?;
+        }
+
+        self.skip_instruction()?;
+
+        Ok(Some((cr, write, value, register)))
+    }
+
+    /// Helper function to decode a port I/O exit. The instruction is skipped right away like KVM
+    /// does, such that the `in` instruction only has to be completed by writing `rax`.
+    fn decode_io(&mut self) -> Result<Option<(u16, usize, bool)>, Error> {
+        let qualification = self.read_vmcs(Vmcs::ExitQualification)?;
+
+        // The string instructions (`ins` and `outs`) are not supported.
+        if qualification & (1 << 4) != 0 {
+            return Ok(None);
+        }
+
+        let size = (qualification & 0x7) as usize + 1;
+        let is_in = qualification & (1 << 3) != 0;
+        let port = (qualification >> 16) as u16;
+
+        if is_in {
+            self.pending_io_in = Some(size);
+        } else {
+            let rax = self.read_register(hv_x86_reg_t::HV_X86_RAX)?;
+            self.io_data = (rax as u32).to_le_bytes();
+        }
+
+        self.skip_instruction()?;
+
+        Ok(Some((port, size, is_in)))
+    }
+
+    /// Helper function to complete a pending `in` instruction by moving the data filled in by the
+    /// caller into `rax`.
+    fn finish_io_in(&mut self) -> Result<(), Error> {
+        let size = match self.pending_io_in.take() {
+            Some(size) => size,
+            _ => return Ok(()),
+        };
+
+        let data = u32::from_le_bytes(self.io_data) as u64;
+        let rax = self.read_register(hv_x86_reg_t::HV_X86_RAX)?;
+
+        // Like on bare metal, a 32-bit access zero-extends into `rax`, while the smaller accesses
+        // preserve the upper bits.
+        let value = match size {
+            1 => (rax & !0xff) | (data & 0xff),
+            2 => (rax & !0xffff) | (data & 0xffff),
+            _ => data,
+        };
+
+        self.write_register(hv_x86_reg_t::HV_X86_RAX, value)
+    }
+
+    /// Helper function to fetch and decode the instruction that caused an EPT violation. The
+    /// instruction is skipped right away like KVM does, such that a read only has to be completed
+    /// by writing the register. Returns `None` if the instruction is not supported.
+    fn decode_mmio(&mut self, hypercalls: &Hypercalls) -> Result<Option<(usize, bool)>, Error> {
+        // Bit 0 of the exit qualification is set for reads, bit 1 for writes and bit 2 for
+        // instruction fetches.
+        let qualification = self.read_vmcs(Vmcs::ExitQualification)?;
+
+        if qualification & (1 << 2) != 0 {
+            return Ok(None);
+        }
+
+        let rip = self.read_register(hv_x86_reg_t::HV_X86_RIP)?;
+        let address = self.read_vmcs(Vmcs::GuestCsBase)?.wrapping_add(rip);
+        let mode = CodeMode::from_cs_access_rights(self.read_vmcs(Vmcs::GuestCsAccessRights)?);
+
+        // Fetch the instruction, which may cross into a page that is not mapped, in which case
+        // the instruction is decoded from the bytes up to the end of the page.
+        let paging = PagingState::new(&*self)?;
+        let vm = hypercalls.vm.read().unwrap();
+        let mut bytes = [0u8; 15];
+        let mut length = (0x1000 - (address & 0xfff) as usize).min(bytes.len());
+
+        if paging.read_virtual_memory(&vm, &mut bytes[..length], address).is_err() {
+            return Ok(None);
+        }
+
+        if paging.read_virtual_memory(&vm, &mut bytes[length..], address + length as u64).is_ok() {
+            length = bytes.len();
+        }
+
+        drop(vm);
+
+        let is_write = qualification & (1 << 1) != 0;
+
+        let instruction = match mmio::decode(&bytes[..length], mode) {
+            Some(instruction) if instruction.is_write() == is_write => instruction,
+            _ => return Ok(None),
+        };
+
+        self.mmio_size = instruction.size;
+
+        match instruction.operation {
+            MmioOperation::Read(operand) => {
+                self.mmio_data = [0; 8];
+                self.pending_mmio_read = Some(operand);
+            }
+            _ => self.mmio_data = instruction.write_data(&*self)?.to_le_bytes(),
+        }
+
+        self.write_register(hv_x86_reg_t::HV_X86_RIP, rip + instruction.length)?;
+
+        Ok(Some((instruction.size, is_write)))
+    }
+
+    /// Helper function to complete a pending MMIO read by moving the data filled in by the caller
+    /// into the register.
+    fn finish_mmio_read(&mut self) -> Result<(), Error> {
+        let operand = match self.pending_mmio_read.take() {
+            Some(operand) => operand,
+            _ => return Ok(()),
+        };
+
+        operand.write(self, u64::from_le_bytes(self.mmio_data))
+    }
+
+    pub fn complete_io(&mut self, data: &[u8]) -> Result<(), Error> {
+        match self.pending_io_in {
+            Some(size) if size == data.len() => (),
+            _ => return Err(Error::InvalidArgument),
+        }
+
+        self.io_data[..data.len()].copy_from_slice(data);
+
+        Ok(())
+    }
+
+    pub fn complete_mmio_read(&mut self, data: &[u8]) -> Result<(), Error> {
+        if self.pending_mmio_read.is_none() || data.len() != self.mmio_size {
+            return Err(Error::InvalidArgument);
+        }
+
+        self.mmio_data[..data.len()].copy_from_slice(data);
+
+        Ok(())
+    }
+
+    /// Helper function to decode the exception that caused the VM exit from the given
+    /// interruption information.
+    fn exception_exit_reason(&self, info: u64) -> Result<ExitReason<'static>, Error> {
+        let vector = (info & 0xff) as u8;
+
+        // Bit 11 indicates that the exception pushed an error code.
+        let error_code = match info & (1 << 11) {
+            0 => None,
+            _ => Some(self.read_vmcs(Vmcs::ExitInterruptionError)? as u32),
+        };
+
+        // The exit qualification holds the faulting address of page faults, which is only written
+        // to CR2 when the exception is delivered.
+        let cr2 = match vector {
+            14 => Some(self.read_vmcs(Vmcs::ExitQualification)?),
+            _ => None,
+        };
+
+        Ok(ExitReason::Exception { vector, error_code, cr2 })
+    }
+
+    /// Helper function to serve the virtual TSC to an `rdtsc` or `rdtscp` instruction.
+    fn emulate_rdtsc(&mut self, rdtscp: bool) -> Result<(), Error> {
+        let value = match self.tsc.as_mut() {
+            Some(tsc) => tsc.read(),
+            _ => return Err(Error::NotImplemented),
+        };
+
+        self.write_register(hv_x86_reg_t::HV_X86_RAX, value & 0xffff_ffff)?;
+        self.write_register(hv_x86_reg_t::HV_X86_RDX, value >> 32)?;
+
+        if rdtscp {
+            let aux = self.read_msr(MSR_IA32_TSC_AUX).unwrap_or(0);
+            self.write_register(hv_x86_reg_t::HV_X86_RCX, aux & 0xffff_ffff)?;
+        }
+
+        self.skip_instruction()
+    }
+
+    /// Helper function to serve the `IA32_APIC_BASE` MSR to an `rdmsr` instruction.
+    fn emulate_apic_base_read(&mut self) -> Result<(), Error> {
+        self.write_register(hv_x86_reg_t::HV_X86_RAX, self.apic_base & 0xffff_ffff)?;
+        self.write_register(hv_x86_reg_t::HV_X86_RDX, self.apic_base >> 32)?;
+
+        self.skip_instruction()
+    }
+
+    /// Helper function to update the `IA32_APIC_BASE` MSR upon a `wrmsr` instruction.
+    fn emulate_apic_base_write(&mut self) -> Result<ApicBase, Error> {
+        let eax = self.read_register(hv_x86_reg_t::HV_X86_RAX)?;
+        let edx = self.read_register(hv_x86_reg_t::HV_X86_RDX)?;
+        let value = (edx << 32) | (eax & 0xffff_ffff);
+
+        // The bootstrap processor flag is read-only.
+        self.apic_base = (value & !APIC_BASE_BSP) | (self.apic_base & APIC_BASE_BSP);
+
+        self.skip_instruction()?;
+
+        Ok(ApicBase::from_msr(self.apic_base))
+    }
+
+    pub fn inject_smi(&mut self) -> Result<(), Error> {
+        // The Hypervisor Framework does not support System Management Mode.
+        Err(Error::NotImplemented)
+    }
+
+    pub fn translate_gva(&self, _gva: u64) -> Result<u64, Error> {
+        Err(Error::NotImplemented)
+    }
+
+    pub fn get_smm_state(&self) -> Result<SmmState, Error> {
+        Err(Error::NotImplemented)
+    }
+
+    pub fn set_smm_state(&mut self, _state: &SmmState) -> Result<(), Error> {
+        Err(Error::NotImplemented)
+    }
+
+    pub fn get_pending_events(&self) -> Result<PendingEvents, Error> {
+        let info = self.read_vmcs(Vmcs::EntryInterruptionInfo)?;
+        let interruptibility = self.read_vmcs(Vmcs::GuestInterruptibility)?;
+
+        let mut events = PendingEvents {
+            // Blocking by `sti` or `mov ss`.
+            interrupt_shadow: interruptibility & 0b11 != 0,
+            // Blocking by NMI.
+            nmi_masked: interruptibility & (1 << 3) != 0,
+            queued_interrupts: self.pending_interrupts.iter().copied().collect(),
+            ..Default::default()
+        };
+
+        // Check if the interruption information is valid.
+        if info & (1 << 31) == 0 {
+            return Ok(events);
+        }
+
+        let vector = (info & 0xff) as u8;
+
+        match (info >> 8) & 0x7 {
+            0 => events.interrupt = Some(vector),
+            2 => events.nmi_pending = true,
+            3 => {
+                events.exception = Some(PendingException {
+                    vector,
+                    error_code: match info & (1 << 11) {
+                        0 => None,
+                        _ => Some(self.read_vmcs(Vmcs::EntryErrorCode)? as u32),
+                    },
+                });
+            }
+            _ => (),
+        }
+
+        Ok(events)
+    }
+
+    pub fn set_pending_events(&mut self, events: &PendingEvents) -> Result<(), Error> {
+        // Only a single event can be injected upon VM entry.
+        let info = match (events.exception, events.interrupt, events.nmi_pending) {
+            (None, None, false) => 0,
+            (Some(exception), None, false) => {
+                let mut info = (1 << 31) | (3 << 8) | exception.vector as u64;
+
+                if let Some(error_code) = exception.error_code {
+                    self.write_vmcs(Vmcs::EntryErrorCode, error_code as u64)?;
+                    info |= 1 << 11;
+                }
+
+                info
+            }
+            (None, Some(vector), false) => (1 << 31) | vector as u64,
+            (None, None, true) => (1 << 31) | (2 << 8) | 2,
+            _ => return Err(Error::InvalidArgument),
+        };
+
+        self.write_vmcs(Vmcs::EntryInterruptionInfo, info)?;
+
+        let mut interruptibility = self.read_vmcs(Vmcs::GuestInterruptibility)?;
+
+        interruptibility &= !0b1011;
+
+        if events.interrupt_shadow {
+            interruptibility |= 1 << 0;
+        }
+
+        if events.nmi_masked {
+            interruptibility |= 1 << 3;
+        }
+
+        self.write_vmcs(Vmcs::GuestInterruptibility, interruptibility)?;
+        self.pending_interrupts = events.queued_interrupts.iter().copied().collect();
+
+        Ok(())
+    }
+
+    pub fn get_xsave(&self) -> Result<Vec<u8>, Error> {
+        self.read_fpstate()
+    }
+
+    pub fn set_xsave(&mut self, bytes: &[u8]) -> Result<(), Error> {
+        if bytes.len() > FPSTATE_SIZE {
+            return Err(Error::InvalidArgument);
+        }
+
+        let mut buffer = vec![0u8; FPSTATE_SIZE];
+        buffer[..bytes.len()].copy_from_slice(bytes);
+
+        unsafe {
+            hv_vcpu_write_fpstate(self.vcpu, buffer.as_mut_ptr() as *mut _, buffer.len())
+        }.into_result()?;
+
+        Ok(())
+    }
+
+    pub fn get_xcr0(&self) -> Result<u64, Error> {
+        let mut value = 0;
+
+        unsafe {
+            hv_vcpu_read_register(self.vcpu, hv_x86_reg_t::HV_X86_XCR0, &mut value)
+        }.into_result()?;
+
+        Ok(value)
+    }
+
+    pub fn set_xcr0(&mut self, value: u64) -> Result<(), Error> {
+        unsafe {
+            hv_vcpu_write_register(self.vcpu, hv_x86_reg_t::HV_X86_XCR0, value)
+        }.into_result()?;
+
+        Ok(())
+    }
+
+    pub fn get_tsc(&self) -> Result<u64, Error> {
+        let offset = self.get_tsc_offset()?;
+
+        Ok(unsafe { core::arch::x86_64::_rdtsc() }.wrapping_add(offset))
+    }
+
+    pub fn set_tsc(&mut self, value: u64) -> Result<(), Error> {
+        let offset = value.wrapping_sub(unsafe { core::arch::x86_64::_rdtsc() });
+
+        self.set_tsc_offset(offset)
+    }
+
+    pub fn get_tsc_offset(&self) -> Result<u64, Error> {
+        let value = self.read_vmcs(Vmcs::CpuBased)?;
+
+        if value & CpuBased::TSC_OFFSET.bits() as u64 == 0 {
+            return Ok(0);
+        }
+
+        self.read_vmcs(Vmcs::TscOffset)
+    }
+
+    pub fn set_tsc_offset(&mut self, offset: u64) -> Result<(), Error> {
+        self.write_vmcs(Vmcs::TscOffset, offset)?;
+
+        // Enable TSC offsetting, such that the guest reads the TSC of the host plus the offset.
+        let value = self.read_vmcs(Vmcs::CpuBased)?;
+        self.write_vmcs(Vmcs::CpuBased, value | CpuBased::TSC_OFFSET.bits() as u64)
+    }
+
+    pub fn get_tsc_khz(&self) -> Result<u32, Error> {
+        let mut frequency = 0u64;
+        let mut size = std::mem::size_of::<u64>();
+
+        let result = unsafe {
+            libc::sysctlbyname(
+                b"machdep.tsc.frequency\0".as_ptr() as *const _,
+                &mut frequency as *mut u64 as *mut _,
+                &mut size,
+                std::ptr::null_mut(),
+                0,
+            )
+        };
+
+        if result < 0 {
+            return Err(std::io::Error::last_os_error().into());
+        }
+
+        Ok((frequency / 1000) as u32)
+    }
+
+    pub fn set_tsc_khz(&mut self, _khz: u32) -> Result<(), Error> {
+        // The guest runs at the TSC frequency of the host.
+        Err(Error::NotImplemented)
+    }
+
+    pub fn set_tpr_threshold(&mut self, threshold: u8) -> Result<(), Error> {
+        self.write_vmcs(Vmcs::TprThreshold, threshold as u64)?;
+
+        // Enable TPR shadowing, such that the guest accesses the TPR in the virtual-APIC page.
+        let value = self.read_vmcs(Vmcs::CpuBased)?;
+        self.write_vmcs(Vmcs::CpuBased, value | CpuBased::TPR_SHADOW.bits() as u64)?;
+
+        Ok(())
+    }
+
+    pub fn set_virtual_apic_page(&mut self, gpa: u64) -> Result<(), Error> {
+        self.write_vmcs(Vmcs::VirtualApicAddress, gpa)
+    }
+
+    pub fn request_interrupt_window(&mut self) -> Result<(), Error> {
+        self.interrupt_window_requested = true;
+
+        // Enable interrupt-window exiting.
+        let value = self.read_vmcs(Vmcs::CpuBased)?;
+        self.write_vmcs(Vmcs::CpuBased, value | CpuBased::IRQ_WND.bits() as u64)?;
+
+        Ok(())
+    }
+
+    pub fn inject_interrupt(&mut self, vector: u8) -> Result<(), Error> {
+        self.pending_interrupts.push_back(vector);
+
+        Ok(())
+    }
+
+    pub fn inject_nmi(&mut self) -> Result<(), Error> {
+        self.pending_nmi = true;
+
+        Ok(())
+    }
+
+    pub fn inject_exception(&mut self, vector: u8, error_code: Option<u32>) -> Result<(), Error> {
+        // Only a single event can be injected upon VM entry, so queue any interrupt or NMI that
+        // was being injected to deliver it after the exception.
+        let info = self.read_vmcs(Vmcs::EntryInterruptionInfo)?;
+
+        if info & (1 << 31) != 0 {
+            match (info >> 8) & 0x7 {
+                0 => self.pending_interrupts.push_front((info & 0xff) as u8),
+                2 => self.pending_nmi = true,
+                _ => (),
+            }
+        }
+
+        let mut info = (1 << 31) | (3 << 8) | vector as u64;
+
+        if let Some(error_code) = error_code {
+            self.write_vmcs(Vmcs::EntryErrorCode, error_code as u64)?;
+            info |= 1 << 11;
+        }
+
+        self.write_vmcs(Vmcs::EntryInterruptionInfo, info)
+    }
+
+    /// Helper function to inject the queued NMI or the first of the queued interrupts upon the
+    /// next VM entry if the guest is able to accept it. Otherwise, NMI-window or interrupt-window
+    /// exiting is enabled to try again once the guest is able to accept it.
+    fn inject_pending_events(&mut self) -> Result<(), Error> {
+        if !self.pending_nmi && self.pending_interrupts.is_empty() {
+            return Ok(());
+        }
+
+        let mut controls = self.read_vmcs(Vmcs::CpuBased)?;
+        let interruptibility = self.read_vmcs(Vmcs::GuestInterruptibility)?;
+
+        // Only a single event can be injected upon VM entry, which may already be taken by an
+        // event that is being re-injected or that was set through `set_pending_events`.
+        let mut injecting = self.read_vmcs(Vmcs::EntryInterruptionInfo)? & (1 << 31) != 0;
+
+        if self.pending_nmi {
+            // Blocking by `sti`, by `mov ss` or by NMI.
+            if injecting || interruptibility & 0b1011 != 0 {
+                controls |= CpuBased::VIRTUAL_NMI_WND.bits() as u64;
+            } else {
+                self.write_vmcs(Vmcs::EntryInterruptionInfo, (1 << 31) | (2 << 8) | 2)?;
+                self.pending_nmi = false;
+                injecting = true;
+            }
+        }
+
+        if let Some(&vector) = self.pending_interrupts.front() {
+            let rflags = self.read_register(hv_x86_reg_t::HV_X86_RFLAGS)?;
+
+            // Blocking by `sti` or by `mov ss`.
+            if !injecting && rflags & RFLAGS_IF != 0 && interruptibility & 0b11 == 0 {
+                self.write_vmcs(Vmcs::EntryInterruptionInfo, (1 << 31) | vector as u64)?;
+                self.pending_interrupts.pop_front();
+            }
+
+            if !self.pending_interrupts.is_empty() {
+                controls |= CpuBased::IRQ_WND.bits() as u64;
+            }
+        }
+
+        self.write_vmcs(Vmcs::CpuBased, controls)
+    }
+
+    /// Helper function to re-inject the external interrupt, NMI or hardware exception whose
+    /// delivery was interrupted by the VM exit, as the Hypervisor Framework does not do so.
+    fn reinject_interrupted_event(&mut self) -> Result<(), Error> {
+        let info = self.read_vmcs(Vmcs::IdtVectoringInfo)?;
+
+        if info & (1 << 31) == 0 || !matches!((info >> 8) & 0x7, 0 | 2 | 3) {
+            return Ok(());
+        }
+
+        if info & (1 << 11) != 0 {
+            let error_code = self.read_vmcs(Vmcs::IdtVectoringError)?;
+            self.write_vmcs(Vmcs::EntryErrorCode, error_code)?;
+        }
+
+        self.write_vmcs(Vmcs::EntryInterruptionInfo, info & 0x8000_0fff)
+    }
+
+    pub fn complete_cpuid(&mut self, result: &CpuidResult) -> Result<(), Error> {
+        if !self.pending_cpuid {
+            return Err(Error::InvalidArgument);
+        }
+
+        self.write_register(hv_x86_reg_t::HV_X86_RAX, result.eax as u64)?;
+        self.write_register(hv_x86_reg_t::HV_X86_RBX, result.ebx as u64)?;
+        self.write_register(hv_x86_reg_t::HV_X86_RCX, result.ecx as u64)?;
+        self.write_register(hv_x86_reg_t::HV_X86_RDX, result.edx as u64)?;
+
+        // The exit information is preserved until the next VM exit.
+        self.skip_instruction()?;
+        self.pending_cpuid = false;
+
+        Ok(())
+    }
+
+    pub fn set_cpuid(&mut self, entries: &[CpuidEntry]) -> Result<(), Error> {
+        self.cpuid = Some(entries.to_vec());
+
+        Ok(())
+    }
+
+    pub fn complete_msr_read(&mut self, value: u64) -> Result<(), Error> {
+        if !self.pending_msr_read {
+            return Err(Error::InvalidArgument);
+        }
+
+        self.write_register(hv_x86_reg_t::HV_X86_RAX, value & 0xffff_ffff)?;
+        self.write_register(hv_x86_reg_t::HV_X86_RDX, value >> 32)?;
+        self.pending_msr_read = false;
+
+        Ok(())
+    }
+
+    pub fn set_guest_debug(&mut self, debug: &GuestDebug) -> Result<(), Error> {
+        // Set up the debug registers for the hardware breakpoints.
+        let registers = [
+            hv_x86_reg_t::HV_X86_DR0,
+            hv_x86_reg_t::HV_X86_DR1,
+            hv_x86_reg_t::HV_X86_DR2,
+            hv_x86_reg_t::HV_X86_DR3,
+        ];
+
+        let dr7 = encode_dr7(&debug.hw_breakpoints)?;
+
+        for (index, register) in registers.iter().enumerate() {
+            let address = debug.hw_breakpoints
+                .get(index)
+                .map(|breakpoint| breakpoint.address)
+                .unwrap_or(0);
+
+            self.write_register(*register, address)?;
+        }
 
-        self.write_vmcs(Vmcs::GuestEs, 0)?;
-        self.write_vmcs(Vmcs::GuestEsBase, 0)?;
-        self.write_vmcs(Vmcs::GuestEsLimit, 0xf_ffff)?;
-        self.write_vmcs(Vmcs::GuestEsAccessRights, 0x93)?;
+        self.write_register(hv_x86_reg_t::HV_X86_DR7, dr7)?;
 
-        self.write_vmcs(Vmcs::GuestFs, 0)?;
-        self.write_vmcs(Vmcs::GuestFsBase, 0)?;
-        self.write_vmcs(Vmcs::GuestFsLimit, 0xf_ffff)?;
-        self.write_vmcs(Vmcs::GuestFsAccessRights, 0x93)?;
+        // Single-stepping is implemented through the monitor trap flag.
+        let mut value = self.read_vmcs(Vmcs::CpuBased)?;
 
-        self.write_vmcs(Vmcs::GuestGs, 0)?;
-        self.write_vmcs(Vmcs::GuestGsBase, 0)?;
-        self.write_vmcs(Vmcs::GuestGsLimit, 0xf_ffff)?;
-        self.write_vmcs(Vmcs::GuestGsAccessRights, 0x93)?;
+        if debug.single_step {
+            value |= CpuBased::MTF.bits() as u64;
+        } else {
+            value &= !(CpuBased::MTF.bits() as u64);
+        }
 
-        self.write_vmcs(Vmcs::GuestSs, 0)?;
-        self.write_vmcs(Vmcs::GuestSsBase, 0)?;
-        self.write_vmcs(Vmcs::GuestSsLimit, 0xf_ffff)?;
-        self.write_vmcs(Vmcs::GuestSsAccessRights, 0x93)?;
+        self.write_vmcs(Vmcs::CpuBased, value)?;
 
-        self.write_vmcs(Vmcs::GuestLdtrAccessRights, 0x1_0000)?;
-        self.write_vmcs(Vmcs::GuestTrAccessRights, 0x8b)?;
+        // Select the exceptions that should exit.
+        self.debug_exceptions = 0;
 
-        // These MSRs must be enabled. Otherwise enabling the long mode bits in EFER would cause
-        // the VM entry to fail without any indicative exit reason.
-        self.enable_native_msr(MSR_IA32_LSTAR, true)?;
-        self.enable_native_msr(MSR_IA32_CSTAR, true)?;
-        self.enable_native_msr(MSR_IA32_STAR, true)?;
-        self.enable_native_msr(MSR_IA32_SYSCALL_MASK, true)?;
-        self.enable_native_msr(MSR_IA32_KERNEL_GS_BASE, true)?;
+        if !debug.hw_breakpoints.is_empty() {
+            self.debug_exceptions |= 1 << 1;
+        }
 
-        self.write_register(hv_x86_reg_t::HV_X86_RIP, 0xfff0)?;
-        self.write_register(hv_x86_reg_t::HV_X86_RFLAGS, 2)?;
+        if debug.sw_breakpoints {
+            self.debug_exceptions |= 1 << 3;
+        }
 
-        self.write_register(hv_x86_reg_t::HV_X86_CR0, 0)?;
-        self.write_register(hv_x86_reg_t::HV_X86_CR4, CR4_VMXE)?;
-        self.write_vmcs(Vmcs::GuestEfer, 0)?;
+        self.update_exception_bitmap()
+    }
 
-        Ok(())
+    pub fn set_exception_exits(&mut self, vectors: u32) -> Result<(), Error> {
+        self.exception_exits = vectors;
+
+        self.update_exception_bitmap()
+    }
+
+    /// Helper function to select the exceptions that exit for guest debugging or as
+    /// [`ExitReason::Exception`].
+    fn update_exception_bitmap(&mut self) -> Result<(), Error> {
+        self.write_vmcs(
+            Vmcs::ExceptionBitmap,
+            (self.debug_exceptions | self.exception_exits) as u64,
+        )
     }
 
-    pub fn run(&mut self) -> Result<ExitReason, Error> {
+    pub fn run(
+        &mut self,
+        policy: ExitPolicy,
+        hypercalls: &Hypercalls,
+    ) -> Result<ExitReason, Error> {
+        self.check_thread()?;
+        self.finish_io_in()?;
+        self.finish_mmio_read()?;
+        self.pending_cpuid = false;
+        self.pending_msr_read = false;
+        self.apply_msr_filter()?;
+
         let exit_reason = loop {
+            // A kick that interrupts the virtual CPU results in an IRQ exit, which lands here.
+            if self.kicked.swap(false, Ordering::SeqCst) {
+                break ExitReason::Canceled;
+            }
+
+            // The pending I/O and MMIO operations have been completed above.
+            if self.immediate_exit {
+                break ExitReason::Canceled;
+            }
+
+            self.inject_pending_events()?;
+
             unsafe {
                 hv_vcpu_run(self.vcpu)
             }.into_result()?;
 
+            if let Some(tsc) = self.tsc.as_mut() {
+                tsc.on_exit();
+            }
+
+            self.reinject_interrupted_event()?;
+
             let value = self.read_vmcs(Vmcs::ExitReason)?;
 
             let exit_reason = match VmxReason::from_u32((value as u32) & 0x7fff_ffff) {
@@ -164,8 +1146,162 @@ impl Vcpu {
             break match exit_reason {
                 VmxReason::Irq =>
                     continue,
+                VmxReason::VmxTimerExpired if policy.contains(ExitPolicy::RESUME_HOUSEKEEPING) =>
+                    continue,
+                VmxReason::Rdtsc => {
+                    self.emulate_rdtsc(false)?;
+                    continue;
+                }
+                VmxReason::Rdtscp => {
+                    self.emulate_rdtsc(true)?;
+                    continue;
+                }
                 VmxReason::TripleFault =>
                     ExitReason::UnhandledException,
+                VmxReason::Cpuid if self.cpuid_exits => {
+                    let leaf = self.read_register(hv_x86_reg_t::HV_X86_RAX)? as u32;
+                    let subleaf = self.read_register(hv_x86_reg_t::HV_X86_RCX)? as u32;
+
+                    self.pending_cpuid = true;
+
+                    ExitReason::Cpuid { leaf, subleaf }
+                }
+                VmxReason::Cpuid if self.cpuid.is_some() => {
+                    let leaf = self.read_register(hv_x86_reg_t::HV_X86_RAX)? as u32;
+                    let subleaf = self.read_register(hv_x86_reg_t::HV_X86_RCX)? as u32;
+
+                    // Serve the `cpuid` instruction from the CPUID table, where the leaves that
+                    // are not in the table read as zero.
+                    let result = self.cpuid.as_deref()
+                        .and_then(|entries| lookup_cpuid(entries, leaf, subleaf))
+                        .unwrap_or_default();
+
+                    self.write_register(hv_x86_reg_t::HV_X86_RAX, result.eax as u64)?;
+                    self.write_register(hv_x86_reg_t::HV_X86_RBX, result.ebx as u64)?;
+                    self.write_register(hv_x86_reg_t::HV_X86_RCX, result.ecx as u64)?;
+                    self.write_register(hv_x86_reg_t::HV_X86_RDX, result.edx as u64)?;
+                    self.skip_instruction()?;
+
+                    continue;
+                }
+                VmxReason::IrqWnd => {
+                    // Stop exiting on the interrupt window until requested again.
+                    let value = self.read_vmcs(Vmcs::CpuBased)?;
+                    self.write_vmcs(Vmcs::CpuBased, value & !(CpuBased::IRQ_WND.bits() as u64))?;
+
+                    // Only report the exit if the caller requested it, as the window may also
+                    // have been requested to inject the queued interrupts.
+                    let requested = std::mem::take(&mut self.interrupt_window_requested);
+
+                    if !requested || policy.contains(ExitPolicy::RESUME_INTERRUPT_WINDOW) {
+                        continue;
+                    }
+
+                    ExitReason::InterruptWindow
+                }
+                VmxReason::VirtualNmiWnd => {
+                    // Stop exiting on the NMI window, as the queued NMI is injected upon resuming.
+                    let value = self.read_vmcs(Vmcs::CpuBased)?;
+                    let value = value & !(CpuBased::VIRTUAL_NMI_WND.bits() as u64);
+                    self.write_vmcs(Vmcs::CpuBased, value)?;
+
+                    continue;
+                }
+                VmxReason::Rdmsr | VmxReason::Wrmsr => {
+                    let msr = self.read_register(hv_x86_reg_t::HV_X86_RCX)? as u32;
+
+                    match (exit_reason, msr) {
+                        (VmxReason::Rdmsr, MSR_IA32_APIC_BASE) => {
+                            self.emulate_apic_base_read()?;
+                            continue;
+                        }
+                        (VmxReason::Wrmsr, MSR_IA32_APIC_BASE) =>
+                            ExitReason::ApicBaseChanged(self.emulate_apic_base_write()?),
+                        (VmxReason::Rdmsr, msr) if self.reports_msr(msr) => {
+                            // The guest reads zero unless the read is completed.
+                            self.write_register(hv_x86_reg_t::HV_X86_RAX, 0)?;
+                            self.write_register(hv_x86_reg_t::HV_X86_RDX, 0)?;
+                            self.skip_instruction()?;
+                            self.pending_msr_read = true;
+
+                            ExitReason::MsrRead { msr }
+                        }
+                        (VmxReason::Wrmsr, msr) if self.reports_msr(msr) => {
+                            let eax = self.read_register(hv_x86_reg_t::HV_X86_RAX)?;
+                            let edx = self.read_register(hv_x86_reg_t::HV_X86_RDX)?;
+                            self.skip_instruction()?;
+
+                            ExitReason::MsrWrite {
+                                msr,
+                                value: (edx << 32) | (eax & 0xffff_ffff),
+                            }
+                        }
+                        _ => ExitReason::Unknown,
+                    }
+                }
+                VmxReason::MovCr => {
+                    match self.decode_cr_access()? {
+                        Some((cr, write, value, register)) =>
+                            ExitReason::CrAccess { cr, write, value, register },
+                        _ => ExitReason::Unknown,
+                    }
+                }
+                VmxReason::TprThreshold => {
+                    if policy.contains(ExitPolicy::RESUME_TPR_BELOW_THRESHOLD) {
+                        continue;
+                    }
+
+                    ExitReason::TprBelowThreshold
+                }
+                VmxReason::Mtf => {
+                    let pc = self.read_register(hv_x86_reg_t::HV_X86_RIP)?;
+
+                    ExitReason::Debug(DebugExit {
+                        pc,
+                        kind: DebugExitKind::SingleStep,
+                        breakpoint: None,
+                    })
+                }
+                VmxReason::ExcNmi => {
+                    let info = self.read_vmcs(Vmcs::ExitInterruptionInfo)?;
+                    let pc = self.read_register(hv_x86_reg_t::HV_X86_RIP)?;
+                    let vector = (info & 0x1f) as u8;
+
+                    let kind = match vector {
+                        // #BP
+                        3 if self.debug_exceptions & (1 << 3) != 0 =>
+                            DebugExitKind::SoftwareBreakpoint,
+                        // #DB, where the exit qualification uses the same layout as DR6.
+                        1 if self.debug_exceptions & (1 << 1) != 0 =>
+                            decode_dr6(self.read_vmcs(Vmcs::ExitQualification)?),
+                        _ if self.exception_exits & (1 << vector) != 0 =>
+                            break self.exception_exit_reason(info)?,
+                        _ => break ExitReason::Unknown,
+                    };
+
+                    ExitReason::Debug(DebugExit { pc, kind, breakpoint: None })
+                }
+                VmxReason::VmCall => {
+                    // Skip the `vmcall` instruction.
+                    self.skip_instruction()?;
+
+                    match hypercalls.dispatch(self)? {
+                        Some(hypercall) => ExitReason::Hypercall(hypercall),
+                        _ => continue,
+                    }
+                }
+                VmxReason::Io => {
+                    match self.decode_io()? {
+                        Some((port, size, true)) => {
+                            self.io_data = [0; 4];
+
+                            ExitReason::IoIn { port, data: &mut self.io_data[..size] }
+                        }
+                        Some((port, size, false)) =>
+                            ExitReason::IoOut { port, data: &self.io_data[..size] },
+                        _ => ExitReason::Unknown,
+                    }
+                }
                 VmxReason::Hlt => {
                     // Skip the `hlt` instruction.
                     let rip = self.read_register(hv_x86_reg_t::HV_X86_RIP)?;
@@ -183,10 +1319,20 @@ impl Vcpu {
                         continue;
                     }*/
 
-                    // The virtual CPU just tried accessing some area we did not map.
-                    ExitReason::InvalidMemoryAccess {
-                        gpa: phys_addr,
-                        gva: virt_addr as usize,
+                    match self.decode_mmio(hypercalls)? {
+                        Some((size, true)) => ExitReason::MmioWrite {
+                            address: phys_addr,
+                            data: &self.mmio_data[..size],
+                        },
+                        Some((size, false)) => ExitReason::MmioRead {
+                            address: phys_addr,
+                            data: &mut self.mmio_data[..size],
+                        },
+                        // The virtual CPU just tried accessing some area we did not map.
+                        _ => ExitReason::InvalidMemoryAccess {
+                            gpa: phys_addr,
+                            gva: virt_addr as usize,
+                        },
                     }
                 }
                 _ => ExitReason::Unknown
@@ -197,6 +1343,17 @@ impl Vcpu {
     }
 }
 
+#[cfg(feature = "xen")]
+impl Vcpu {
+    pub fn set_xen_vcpu_info(&mut self, _gpa: u64) -> Result<(), Error> {
+        Err(Error::NotImplemented)
+    }
+
+    pub fn complete_xen_hypercall(&mut self, _result: u64) -> Result<(), Error> {
+        Err(Error::NotImplemented)
+    }
+}
+
 impl Drop for Vcpu {
     fn drop(&mut self) {
         unsafe {
@@ -330,6 +1487,15 @@ impl CpuRegs for Vcpu {
             unsafe {
                 hv_vcpu_write_register(self.vcpu, register, value)
             }.into_result()?;
+
+            // Keep the read shadows in sync while the writes to CR0 and CR4 are intercepted.
+            match register {
+                hv_x86_reg_t::HV_X86_CR0 if self.cr_exits.contains(CrExits::CR0_WRITE) =>
+                    self.write_vmcs(Vmcs::Cr0Shadow, value)?,
+                hv_x86_reg_t::HV_X86_CR4 if self.cr_exits.contains(CrExits::CR4_WRITE) =>
+                    self.write_vmcs(Vmcs::Cr4Shadow, value & !CR4_VMXE)?,
+                _ => (),
+            }
         }
 
         Ok(())
@@ -585,16 +1751,334 @@ impl CpuRegs for Vcpu {
 
         Ok(())
     }
+
+    fn get_debug_registers(
+        &self,
+        registers: &[DebugRegister],
+    ) -> Result<Vec<u64>, Error> {
+        let mut values = vec![];
+
+        for register in registers {
+            values.push(self.read_register(debug_register(*register))?);
+        }
+
+        Ok(values)
+    }
+
+    fn set_debug_registers(
+        &mut self,
+        registers: &[DebugRegister],
+        values: &[u64],
+    ) -> Result<(), Error> {
+        for (register, value) in registers.iter().zip(values.iter()) {
+            self.write_register(debug_register(*register), *value)?;
+        }
+
+        Ok(())
+    }
+
+    fn get_fpu(&self) -> Result<FpuState, Error> {
+        let bytes = self.read_fpstate()?;
+
+        Ok(FpuState::from_fxsave(&bytes))
+    }
+
+    fn set_fpu(&mut self, state: &FpuState) -> Result<(), Error> {
+        // Preserve the extended state that follows the legacy region.
+        let mut bytes = self.read_fpstate()?;
+
+        state.to_fxsave(&mut bytes);
+
+        unsafe {
+            hv_vcpu_write_fpstate(self.vcpu, bytes.as_mut_ptr() as *mut _, bytes.len())
+        }.into_result()?;
+
+        Ok(())
+    }
 }
 
+#[cfg(target_arch = "aarch64")]
+use crate::arch::aarch64::{is_psci_function, is_wfi, CpuRegs, DataAbort, ExceptionClass, Register};
+
 #[cfg(target_arch = "aarch64")]
 impl Vcpu {
+    /// Helper function to read a general-purpose register.
+    fn read_register(&self, register: hv_reg_t) -> Result<u64, Error> {
+        let mut value = 0;
+
+        unsafe {
+            hv_vcpu_get_reg(self.vcpu, register, &mut value)
+        }.into_result()?;
+
+        Ok(value)
+    }
+
+    /// Helper function to write a general-purpose register.
+    fn write_register(&self, register: hv_reg_t, value: u64) -> Result<(), Error> {
+        unsafe {
+            hv_vcpu_set_reg(self.vcpu, register, value)
+        }.into_result()
+    }
+
+    /// Helper function to read a system register.
+    fn read_sys_register(&self, register: hv_sys_reg_t) -> Result<u64, Error> {
+        let mut value = 0;
+
+        unsafe {
+            hv_vcpu_get_sys_reg(self.vcpu, register, &mut value)
+        }.into_result()?;
+
+        Ok(value)
+    }
+
+    /// Helper function to write a system register.
+    fn write_sys_register(&self, register: hv_sys_reg_t, value: u64) -> Result<(), Error> {
+        unsafe {
+            hv_vcpu_set_sys_reg(self.vcpu, register, value)
+        }.into_result()
+    }
+
+    /// Helper function to get the stack pointer that is in use, which is `SP_EL1` if the virtual
+    /// CPU runs at EL1 with `SPSel` set, i.e. EL1h, and `SP_EL0` otherwise.
+    fn stack_pointer(&self) -> Result<hv_sys_reg_t, Error> {
+        let cpsr = self.read_register(HV_REG_CPSR)?;
+
+        Ok(match cpsr & 0xf {
+            0b0101 => HV_SYS_REG_SP_EL1,
+            _ => HV_SYS_REG_SP_EL0,
+        })
+    }
+
+    /// Helper function to skip the instruction that caused the exit, as the instructions of
+    /// AArch64 are 4 bytes long.
+    fn skip_instruction(&mut self) -> Result<(), Error> {
+        let pc = self.read_register(HV_REG_PC)?;
+
+        self.write_register(HV_REG_PC, pc + 4)
+    }
+
+    /// Helper function to decode a PSCI call made through `hvc` or `smc`. Returns `None` if the
+    /// function ID in `x0` is not a PSCI function.
+    fn decode_psci(&self) -> Result<Option<(u32, [u64; 3])>, Error> {
+        let function = self.read_register(HV_REG_X0)?;
+
+        if !is_psci_function(function) {
+            return Ok(None);
+        }
+
+        let mut args = [0; 3];
+
+        for (index, arg) in args.iter_mut().enumerate() {
+            *arg = self.read_register(HV_REG_X0 + 1 + index as hv_reg_t)?;
+        }
+
+        Ok(Some((function as u32, args)))
+    }
+
+    /// Helper function to decode a data abort. The instruction is skipped right away like KVM
+    /// does, such that a read only has to be completed by writing the register. Returns `None` if
+    /// the syndrome does not describe the access.
+    fn decode_mmio(&mut self, syndrome: u64) -> Result<Option<DataAbort>, Error> {
+        let abort = match DataAbort::from_syndrome(syndrome) {
+            Some(abort) => abort,
+            _ => return Ok(None),
+        };
+
+        if abort.is_write {
+            // The zero register reads as zero.
+            let value = match abort.register {
+                Some(register) => self.get_registers(&[register])?[0],
+                _ => 0,
+            };
+
+            self.mmio_data = value.to_le_bytes();
+        } else {
+            self.mmio_data = [0; 8];
+            self.pending_mmio_read = Some(abort);
+        }
+
+        self.skip_instruction()?;
+
+        Ok(Some(abort))
+    }
+
+    /// Helper function to complete a pending MMIO read by moving the data filled in by the caller
+    /// into the register, where the value is sign-extended to the register size if requested.
+    fn finish_mmio_read(&mut self) -> Result<(), Error> {
+        let abort = match self.pending_mmio_read.take() {
+            Some(abort) => abort,
+            _ => return Ok(()),
+        };
+
+        // The zero register discards the value.
+        let register = match abort.register {
+            Some(register) => register,
+            _ => return Ok(()),
+        };
+
+        let bits = abort.size as u32 * 8;
+        let mut value = u64::from_le_bytes(self.mmio_data);
+
+        if bits < 64 {
+            value &= (1 << bits) - 1;
+
+            if abort.sign_extend {
+                value = (((value << (64 - bits)) as i64) >> (64 - bits)) as u64;
+            }
+        }
+
+        if !abort.is_64bit {
+            value &= 0xffff_ffff;
+        }
+
+        self.set_registers(&[register], &[value])
+    }
+
     /// Resets the CPU to default state.
     pub fn reset(&mut self) -> Result<(), Error> {
         Ok(())
     }
 
-    pub fn run(&mut self) -> Result<ExitReason, Error> {
-        Ok(ExitReason::Unknown)
+    pub fn set_guest_debug(&mut self, _debug: &GuestDebug) -> Result<(), Error> {
+        Err(Error::NotImplemented)
+    }
+
+    pub fn complete_io(&mut self, _data: &[u8]) -> Result<(), Error> {
+        Err(Error::NotImplemented)
+    }
+
+    pub fn complete_mmio_read(&mut self, data: &[u8]) -> Result<(), Error> {
+        match self.pending_mmio_read {
+            Some(abort) if abort.size == data.len() => (),
+            _ => return Err(Error::InvalidArgument),
+        }
+
+        self.mmio_data[..data.len()].copy_from_slice(data);
+
+        Ok(())
+    }
+
+    pub fn run(
+        &mut self,
+        _policy: ExitPolicy,
+        hypercalls: &Hypercalls,
+    ) -> Result<ExitReason, Error> {
+        self.check_thread()?;
+        self.finish_mmio_read()?;
+
+        let exit_reason = loop {
+            // A kick results in a canceled exit, which lands here.
+            if self.kicked.swap(false, Ordering::SeqCst) || self.immediate_exit {
+                break ExitReason::Canceled;
+            }
+
+            unsafe {
+                hv_vcpu_run(self.vcpu)
+            }.into_result()?;
+
+            let exit = unsafe { *self.exit.0 };
+
+            match exit.reason {
+                HV_EXIT_REASON_EXCEPTION => (),
+                HV_EXIT_REASON_CANCELED => continue,
+                _ => break ExitReason::Unknown,
+            }
+
+            let syndrome = exit.exception.syndrome;
+
+            break match ExceptionClass::from_syndrome(syndrome) {
+                Some(ExceptionClass::DataAbortLowerEl) => {
+                    let address = exit.exception.physical_address;
+
+                    match self.decode_mmio(syndrome)? {
+                        Some(abort) if abort.is_write => ExitReason::MmioWrite {
+                            address,
+                            data: &self.mmio_data[..abort.size],
+                        },
+                        Some(abort) => ExitReason::MmioRead {
+                            address,
+                            data: &mut self.mmio_data[..abort.size],
+                        },
+                        // The virtual CPU just tried accessing some area we did not map.
+                        _ => ExitReason::InvalidMemoryAccess {
+                            gpa: address,
+                            gva: exit.exception.virtual_address as usize,
+                        },
+                    }
+                }
+                Some(ExceptionClass::Wfx) => {
+                    // Skip the `wfi` or `wfe` instruction.
+                    self.skip_instruction()?;
+
+                    // The `wfe` instruction is a hint that may complete right away.
+                    if !is_wfi(syndrome) {
+                        continue;
+                    }
+
+                    ExitReason::Halted
+                }
+                // The program counter already points past the `hvc` instruction.
+                Some(ExceptionClass::Hvc64) => match self.decode_psci()? {
+                    Some((function, args)) => ExitReason::Psci { function, args },
+                    _ => match hypercalls.dispatch(self)? {
+                        Some(hypercall) => ExitReason::Hypercall(hypercall),
+                        _ => continue,
+                    },
+                },
+                // The program counter still points to the `smc` instruction.
+                Some(ExceptionClass::Smc64) => match self.decode_psci()? {
+                    Some((function, args)) => {
+                        self.skip_instruction()?;
+                        ExitReason::Psci { function, args }
+                    }
+                    _ => ExitReason::Unknown,
+                },
+                _ => ExitReason::Unknown,
+            };
+        };
+
+        Ok(exit_reason)
+    }
+}
+
+#[cfg(target_arch = "aarch64")]
+impl CpuRegs for Vcpu {
+    fn get_registers(
+        &self,
+        registers: &[Register],
+    ) -> Result<Vec<u64>, Error> {
+        let mut values = vec![];
+
+        for register in registers {
+            let value = match register {
+                Register::Sp     => self.read_sys_register(self.stack_pointer()?)?,
+                Register::Pc     => self.read_register(HV_REG_PC)?,
+                Register::Pstate => self.read_register(HV_REG_CPSR)?,
+                // X0 to X30 are numbered consecutively.
+                register => self.read_register(HV_REG_X0 + *register as hv_reg_t)?,
+            };
+
+            values.push(value);
+        }
+
+        Ok(values)
+    }
+
+    fn set_registers(
+        &mut self,
+        registers: &[Register],
+        values: &[u64],
+    ) -> Result<(), Error> {
+        for (register, value) in registers.iter().zip(values.iter()) {
+            match register {
+                Register::Sp     => self.write_sys_register(self.stack_pointer()?, *value)?,
+                Register::Pc     => self.write_register(HV_REG_PC, *value)?,
+                Register::Pstate => self.write_register(HV_REG_CPSR, *value)?,
+                // X0 to X30 are numbered consecutively.
+                register => self.write_register(HV_REG_X0 + *register as hv_reg_t, *value)?,
+            }
+        }
+
+        Ok(())
     }
 }
@@ -1,46 +1,360 @@
+#[cfg(target_arch = "aarch64")]
+use crate::arch::aarch64::{GicConfig, GIC_SPI_BASE};
+#[cfg(target_arch = "x86_64")]
+use crate::arch::x86_64::{CrExits, MsrFilter, MsrPolicy};
+#[cfg(target_arch = "x86_64")]
+use crate::confidential::{LaunchMeasurement, SevPolicy};
 use crate::error::Error;
-use crate::vm::ProtectionFlags;
+use crate::os_impl::unix::{discard, populate, resident_size};
+#[cfg(target_arch = "x86_64")]
+use crate::pit::PitReinjection;
+#[cfg(target_arch = "x86_64")]
+use crate::synic::SyntheticMessage;
+use crate::tsc::{TscMode, VirtualTsc};
+use crate::vm::{
+    check_overlap, DirtyBitmap, MemoryBackingKind, MissingPageHandler, ProtectionFlags,
+};
+#[cfg(feature = "xen")]
+use crate::xen::XenConfig;
 use mmap_rs::{MmapMut, MmapOptions};
 use rangemap::RangeMap;
 use std::collections::HashMap;
+use std::ffi::CString;
+use std::fs::File;
+use std::ops::Range;
+use std::os::unix::io::FromRawFd;
+use std::sync::atomic::{AtomicUsize, Ordering};
+#[cfg(target_arch = "x86_64")]
+use std::sync::{Arc, RwLock};
 use super::bindings::*;
+#[cfg(target_arch = "aarch64")]
+use super::vcpu::ExitInfo;
 use super::vcpu::Vcpu;
 
-pub struct VmBuilder;
+/// Creates a POSIX shared memory object of the given size to back shared memory. The object is
+/// unlinked right away, such that it is only reachable through the file descriptor.
+pub fn create_shared_memory(size: usize) -> Result<File, Error> {
+    static COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+    let name = format!("/hy-rs.{}.{}", std::process::id(), COUNTER.fetch_add(1, Ordering::Relaxed));
+    let name = CString::new(name).unwrap();
+
+    let fd = unsafe {
+        libc::shm_open(
+            name.as_ptr(),
+            libc::O_RDWR | libc::O_CREAT | libc::O_EXCL,
+            0o600 as libc::c_uint,
+        )
+    };
+
+    if fd < 0 {
+        return Err(std::io::Error::last_os_error().into());
+    }
+
+    unsafe {
+        libc::shm_unlink(name.as_ptr());
+    }
+
+    let file = unsafe { File::from_raw_fd(fd) };
+    file.set_len(size as u64)?;
+
+    Ok(file)
+}
+
+pub struct VmBuilder {
+    tsc_mode: TscMode,
+    hlt_exiting: bool,
+    cpuid_exits: bool,
+    msr_exits: bool,
+    /// The accesses to the control registers that exit.
+    #[cfg(target_arch = "x86_64")]
+    cr_exits: CrExits,
+    /// The configuration of the GIC, or `None` to not create one.
+    #[cfg(target_arch = "aarch64")]
+    gic: Option<GicConfig>,
+}
 
 impl VmBuilder {
     pub fn with_vcpu_count(self, _count: usize) -> Result<Self, Error> {
         Ok(self)
     }
 
+    #[cfg(target_arch = "x86_64")]
+    pub fn with_tsc_mode(self, mode: TscMode) -> Result<Self, Error> {
+        Ok(Self {
+            tsc_mode: mode,
+            ..self
+        })
+    }
+
+    #[cfg(target_arch = "aarch64")]
+    pub fn with_tsc_mode(self, mode: TscMode) -> Result<Self, Error> {
+        match mode {
+            TscMode::Native => Ok(self),
+            _ => Err(Error::NotImplemented),
+        }
+    }
+
+    pub fn with_protected_guest(self, enabled: bool) -> Result<Self, Error> {
+        if enabled {
+            return Err(Error::NotImplemented);
+        }
+
+        Ok(self)
+    }
+
+    pub fn with_synthetic_interrupts(self, enabled: bool) -> Result<Self, Error> {
+        // The Hypervisor Framework does not provide a SynIC.
+        if enabled {
+            return Err(Error::NotImplemented);
+        }
+
+        Ok(self)
+    }
+
+    pub fn with_la57(self, _enabled: bool) -> Result<Self, Error> {
+        Err(Error::NotImplemented)
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    pub fn with_hlt_exiting(self, enabled: bool) -> Result<Self, Error> {
+        Ok(Self {
+            hlt_exiting: enabled,
+            ..self
+        })
+    }
+
+    #[cfg(target_arch = "aarch64")]
+    pub fn with_hlt_exiting(self, enabled: bool) -> Result<Self, Error> {
+        if !enabled {
+            return Err(Error::NotImplemented);
+        }
+
+        Ok(self)
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    pub fn with_cpuid_exits(self, enabled: bool) -> Result<Self, Error> {
+        // VMX always exits on `cpuid`, so this only selects whether the exits are reported.
+        Ok(Self {
+            cpuid_exits: enabled,
+            ..self
+        })
+    }
+
+    #[cfg(target_arch = "aarch64")]
+    pub fn with_cpuid_exits(self, enabled: bool) -> Result<Self, Error> {
+        if enabled {
+            return Err(Error::NotImplemented);
+        }
+
+        Ok(self)
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    pub fn with_msr_exits(self, enabled: bool) -> Result<Self, Error> {
+        Ok(Self {
+            msr_exits: enabled,
+            ..self
+        })
+    }
+
+    #[cfg(target_arch = "aarch64")]
+    pub fn with_msr_exits(self, enabled: bool) -> Result<Self, Error> {
+        if enabled {
+            return Err(Error::NotImplemented);
+        }
+
+        Ok(self)
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    pub fn with_in_kernel_irqchip(self, enabled: bool) -> Result<Self, Error> {
+        // The Hypervisor Framework leaves the interrupt controllers to the VMM.
+        if enabled {
+            return Err(Error::NotImplemented);
+        }
+
+        Ok(self)
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    pub fn with_in_kernel_pit(self, _reinjection: PitReinjection) -> Result<Self, Error> {
+        Err(Error::NotImplemented)
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    pub fn with_sev(self, _policy: SevPolicy) -> Result<Self, Error> {
+        Err(Error::NotImplemented)
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    pub fn with_cr_exits(self, exits: CrExits) -> Result<Self, Error> {
+        Ok(Self {
+            cr_exits: exits,
+            ..self
+        })
+    }
+
+    #[cfg(target_arch = "aarch64")]
+    pub fn with_gic(self, config: GicConfig) -> Result<Self, Error> {
+        let mut base = 0;
+        let mut count = 0;
+
+        // The GIC is only available as of macOS 15.
+        unsafe {
+            hv_gic_get_spi_interrupt_range(&mut base, &mut count)
+        }.into_result().map_err(|_| Error::NotImplemented)?;
+
+        if base != GIC_SPI_BASE || config.spi_count == 0 || config.spi_count > count {
+            return Err(Error::InvalidArgument);
+        }
+
+        Ok(Self {
+            gic: Some(config),
+            ..self
+        })
+    }
+
+    #[cfg(feature = "xen")]
+    pub fn with_xen(self, _config: XenConfig) -> Result<Self, Error> {
+        Err(Error::NotImplemented)
+    }
+
+    /// Helper function to create the GIC, which has to happen before creating any virtual CPU.
+    #[cfg(target_arch = "aarch64")]
+    fn create_gic(config: &GicConfig) -> Result<(), Error> {
+        let gic_config = unsafe {
+            hv_gic_config_create()
+        };
+
+        let result = unsafe {
+            hv_gic_config_set_distributor_base(gic_config, config.distributor_base)
+                .into_result()
+                .and_then(|_| {
+                    hv_gic_config_set_redistributor_base(gic_config, config.redistributor_base)
+                        .into_result()
+                })
+                .and_then(|_| hv_gic_create(gic_config).into_result())
+        };
+
+        unsafe {
+            os_release(gic_config);
+        }
+
+        result
+    }
+
     pub fn build(self, _name: &str) -> Result<Vm, Error> {
+        #[cfg(target_arch = "aarch64")]
+        if let Some(config) = self.gic.as_ref() {
+            Self::create_gic(config)?;
+        }
+
         Ok(Vm {
             physical_ranges: RangeMap::new(),
             segments: HashMap::new(),
+            tsc_mode: self.tsc_mode,
+            hlt_exiting: self.hlt_exiting,
+            cpuid_exits: self.cpuid_exits,
+            msr_exits: self.msr_exits,
+            #[cfg(target_arch = "x86_64")]
+            cr_exits: self.cr_exits,
+            #[cfg(target_arch = "x86_64")]
+            msr_filter: Default::default(),
+            #[cfg(target_arch = "aarch64")]
+            gic: self.gic.is_some(),
         })
     }
 }
 
+/// Helper function to convert the protection flags into the flags of the Hypervisor Framework.
+fn memory_flags(protection: ProtectionFlags) -> hv_memory_flags_t {
+    let mut flags = 0;
+
+    if protection.contains(ProtectionFlags::READ) {
+        flags |= HV_MEMORY_READ;
+    }
+
+    if protection.is_guest_writable() {
+        flags |= HV_MEMORY_WRITE;
+    }
+
+    if protection.contains(ProtectionFlags::EXECUTE) {
+        flags |= HV_MEMORY_EXEC;
+    }
+
+    flags
+}
+
 pub struct Segment {
     mapping: MmapMut,
+    protection: ProtectionFlags,
+    backing: MemoryBackingKind,
 }
 
 pub struct Vm {
     physical_ranges: RangeMap<u64, u64>,
     segments: HashMap<u64, Segment>,
+    tsc_mode: TscMode,
+    hlt_exiting: bool,
+    cpuid_exits: bool,
+    msr_exits: bool,
+    /// The accesses to the control registers that exit.
+    #[cfg(target_arch = "x86_64")]
+    cr_exits: CrExits,
+    /// The MSR filter, which the virtual CPUs apply upon their next run.
+    #[cfg(target_arch = "x86_64")]
+    msr_filter: Arc<RwLock<Option<MsrFilter>>>,
+    /// Whether the GIC has been created.
+    #[cfg(target_arch = "aarch64")]
+    gic: bool,
 }
 
 impl Vm {
     #[cfg(target_arch = "x86_64")]
-    pub fn create_vcpu(&mut self, _id: usize) -> Result<Vcpu, Error> {
+    pub fn create_vcpu(&mut self, id: usize) -> Result<Vcpu, Error> {
+        use crate::arch::x86_64::{APIC_BASE_BSP, APIC_BASE_ENABLE, APIC_DEFAULT_BASE};
+
         let mut vcpu = 0;
 
         unsafe {
             hv_vcpu_create(&mut vcpu, HV_VCPU_DEFAULT)
         }.into_result()?;
 
+        // The first virtual CPU is the bootstrap processor.
+        let mut apic_base = APIC_DEFAULT_BASE | APIC_BASE_ENABLE;
+
+        if id == 0 {
+            apic_base |= APIC_BASE_BSP;
+        }
+
         let mut vcpu = Vcpu {
             vcpu,
+            tsc: VirtualTsc::new(self.tsc_mode),
+            thread: std::thread::current().id(),
+            kicked: Default::default(),
+            immediate_exit: false,
+            apic_base,
+            io_data: [0; 4],
+            pending_io_in: None,
+            mmio_data: [0; 8],
+            mmio_size: 0,
+            pending_mmio_read: None,
+            hlt_exiting: self.hlt_exiting,
+            cpuid_exits: self.cpuid_exits,
+            pending_cpuid: false,
+            cpuid: None,
+            msr_exits: self.msr_exits,
+            pending_msr_read: false,
+            cr_exits: self.cr_exits,
+            msr_filter: self.msr_filter.clone(),
+            applied_msr_filter: None,
+            debug_exceptions: 0,
+            exception_exits: 0,
+            pending_interrupts: Default::default(),
+            pending_nmi: false,
+            interrupt_window_requested: false,
         };
 
         vcpu.reset()?;
@@ -55,11 +369,18 @@ impl Vm {
         let vcpu_config: hv_vcpu_config_t = core::ptr::null_mut();
 
         unsafe {
-            hv_vcpu_create(&mut vcpu, &mut vcpu_exit, &vcpu_config)
+            hv_vcpu_create(&mut vcpu, &mut vcpu_exit, vcpu_config)
         }.into_result()?;
 
         let mut vcpu = Vcpu {
             vcpu,
+            tsc: VirtualTsc::new(self.tsc_mode),
+            thread: std::thread::current().id(),
+            kicked: Default::default(),
+            immediate_exit: false,
+            exit: ExitInfo(vcpu_exit),
+            mmio_data: [0; 8],
+            pending_mmio_read: None,
         };
 
         vcpu.reset()?;
@@ -67,6 +388,19 @@ impl Vm {
         Ok(vcpu)
     }
 
+    #[cfg(target_arch = "aarch64")]
+    pub fn set_irq_level(&self, intid: u32, level: bool) -> Result<(), Error> {
+        if !self.gic {
+            return Err(Error::NotImplemented);
+        }
+
+        unsafe {
+            hv_gic_set_spi(intid, level)
+        }.into_result()?;
+
+        Ok(())
+    }
+
     pub fn allocate_physical_memory(
         &mut self,
         guest_address: u64,
@@ -76,47 +410,42 @@ impl Vm {
         let mapping = MmapOptions::new(size)
             .map_mut()?;
 
-        unsafe {
-            self.map_physical_memory(
-                guest_address,
-                mapping,
-                protection,
-            )
-        }?;
+        self.map_physical_memory(
+            guest_address,
+            mapping,
+            protection,
+            MemoryBackingKind::Anonymous,
+        )?;
 
         Ok(())
     }
 
-    pub unsafe fn map_physical_memory(
+    pub fn map_physical_memory(
         &mut self,
         guest_address: u64,
         mapping: MmapMut,
         protection: ProtectionFlags,
+        backing: MemoryBackingKind,
     ) -> Result<(), Error> {
-        let mut flags = 0;
+        check_overlap(&self.physical_ranges, guest_address..guest_address + mapping.len() as u64)?;
 
-        if protection.contains(ProtectionFlags::READ) {
-            flags |= HV_MEMORY_READ;
-        }
+        let flags = memory_flags(protection);
 
-        if protection.contains(ProtectionFlags::WRITE) {
-            flags |= HV_MEMORY_WRITE;
-        }
-
-        if protection.contains(ProtectionFlags::EXECUTE) {
-            flags |= HV_MEMORY_EXEC;
-        }
-
-        hv_vm_map(
-            mapping.as_ptr() as *const std::ffi::c_void,
-            guest_address,
-            mapping.len(),
-            flags,
-        ).into_result()?;
+        // The mapping is kept alive in the segment until the guest physical memory is unmapped.
+        unsafe {
+            hv_vm_map(
+                mapping.as_ptr() as *const std::ffi::c_void,
+                guest_address,
+                mapping.len(),
+                flags,
+            )
+        }.into_result()?;
 
         let range = guest_address..guest_address + mapping.len() as u64;
         let segment = Segment {
             mapping,
+            protection,
+            backing,
         };
 
         self.physical_ranges.insert(range.clone(), range.start);
@@ -125,10 +454,46 @@ impl Vm {
         Ok(())
     }
 
+    pub fn regions(
+        &self,
+    ) -> Result<Vec<(Range<u64>, ProtectionFlags, MemoryBackingKind)>, Error> {
+        let regions = self.physical_ranges
+            .iter()
+            .filter_map(|(range, start)| {
+                let segment = self.segments.get(start)?;
+
+                Some((range.clone(), segment.protection, segment.backing))
+            })
+            .collect();
+
+        Ok(regions)
+    }
+
+    /// Returns the protection flags of the region containing the given guest address.
+    pub fn protection(&self, guest_address: u64) -> Option<ProtectionFlags> {
+        let start = self.physical_ranges.get(&guest_address)?;
+
+        self.segments
+            .get(start)
+            .map(|segment| segment.protection)
+    }
+
     pub fn unmap_physical_memory(
         &mut self,
         guest_address: u64,
     ) -> Result<(), Error> {
+        self.take_physical_memory(guest_address)?;
+
+        Ok(())
+    }
+
+    /// Unmaps the region at the given guest physical address like
+    /// [`Vm::unmap_physical_memory`], but hands back its mapping, protection and backing, such
+    /// that the region can be mapped again.
+    pub fn take_physical_memory(
+        &mut self,
+        guest_address: u64,
+    ) -> Result<(MmapMut, ProtectionFlags, MemoryBackingKind), Error> {
         // Look up the base guest address.
         let range = match self.physical_ranges.get_key_value(&guest_address) {
             Some((range, _)) => range.clone(),
@@ -140,9 +505,106 @@ impl Vm {
         }.into_result()?;
 
         // Remove the physical address range and segment.
-        self.segments.remove(&range.start);
+        let segment = self.segments
+            .remove(&range.start)
+            .ok_or(Error::InvalidGuestAddress)?;
         self.physical_ranges.remove(range);
 
+        Ok((segment.mapping, segment.protection, segment.backing))
+    }
+
+    pub fn remap_physical_memory(
+        &mut self,
+        guest_address: u64,
+        new_guest_address: u64,
+    ) -> Result<(), Error> {
+        // Look up the base guest address.
+        let range = match self.physical_ranges.get_key_value(&guest_address) {
+            Some((range, _)) => range.clone(),
+            _ => return Err(Error::InvalidGuestAddress),
+        };
+
+        // Look up the segment.
+        let segment = match self.segments.remove(&range.start) {
+            Some(segment) => segment,
+            _ => return Err(Error::InvalidGuestAddress),
+        };
+
+        let size = (range.end - range.start) as usize;
+
+        unsafe {
+            hv_vm_unmap(range.start, size)
+        }.into_result()?;
+
+        unsafe {
+            hv_vm_map(
+                segment.mapping.as_ptr() as *const std::ffi::c_void,
+                new_guest_address,
+                size,
+                memory_flags(segment.protection),
+            )
+        }.into_result()?;
+
+        self.physical_ranges.remove(range);
+        self.physical_ranges.insert(
+            new_guest_address..new_guest_address + size as u64,
+            new_guest_address,
+        );
+        self.segments.insert(new_guest_address, segment);
+
+        Ok(())
+    }
+
+    pub fn resize_physical_memory(
+        &mut self,
+        guest_address: u64,
+        size: usize,
+        _backing_file: Option<(File, u64)>,
+    ) -> Result<(), Error> {
+        // Look up the base guest address.
+        let range = match self.physical_ranges.get_key_value(&guest_address) {
+            Some((range, _)) => range.clone(),
+            _ => return Err(Error::InvalidGuestAddress),
+        };
+
+        // Look up the segment.
+        let segment = match self.segments.get(&range.start) {
+            Some(segment) => segment,
+            _ => return Err(Error::InvalidGuestAddress),
+        };
+
+        // The Hypervisor Framework maps the host memory as is, so the region can only grow up to
+        // the size of its mapping.
+        if size > segment.mapping.len() {
+            return Err(Error::NotImplemented);
+        }
+
+        let end = range.start + size as u64;
+        let offset = (range.end.min(end) - range.start) as usize;
+        let address = unsafe { segment.mapping.as_ptr().add(offset) };
+
+        if end < range.end {
+            let size = (range.end - end) as usize;
+
+            unsafe {
+                hv_vm_unmap(end, size)
+            }.into_result()?;
+
+            // Release the host pages beyond the end of the region.
+            discard(address as *mut u8, size)?;
+        } else if end > range.end {
+            unsafe {
+                hv_vm_map(
+                    address as *const std::ffi::c_void,
+                    range.end,
+                    (end - range.end) as usize,
+                    memory_flags(segment.protection),
+                )
+            }.into_result()?;
+        }
+
+        self.physical_ranges.remove(range.clone());
+        self.physical_ranges.insert(range.start..end, range.start);
 
         Ok(())
     }
@@ -158,20 +620,32 @@ impl Vm {
             _ => return Err(Error::InvalidGuestAddress),
         };
 
-        let mut flags = 0;
+        let flags = memory_flags(protection);
 
-        if protection.contains(ProtectionFlags::READ) {
-            flags |= HV_MEMORY_READ;
-        }
+        unsafe {
+            hv_vm_protect(range.start, (range.end - range.start) as usize, flags)
+        }.into_result()?;
 
-        if protection.contains(ProtectionFlags::WRITE) {
-            flags |= HV_MEMORY_WRITE;
+        if let Some(segment) = self.segments.get_mut(&range.start) {
+            segment.protection = protection;
         }
 
-        if protection.contains(ProtectionFlags::EXECUTE) {
-            flags |= HV_MEMORY_EXEC;
+        Ok(())
+    }
+
+    pub fn protect_physical_memory_range(
+        &mut self,
+        range: Range<u64>,
+        protection: ProtectionFlags,
+    ) -> Result<(), Error> {
+        // The range must not cross the end of the region.
+        match self.physical_ranges.get_key_value(&range.start) {
+            Some((region, _)) if range.end <= region.end => (),
+            _ => return Err(Error::InvalidGuestAddress),
         }
 
+        let flags = memory_flags(protection);
+
         unsafe {
             hv_vm_protect(range.start, (range.end - range.start) as usize, flags)
         }.into_result()?;
@@ -179,6 +653,14 @@ impl Vm {
         Ok(())
     }
 
+    pub fn set_dirty_tracking(&mut self, _enabled: bool) -> Result<(), Error> {
+        Err(Error::NotImplemented)
+    }
+
+    pub fn dirty_bitmap(&self, _guest_address: u64) -> Result<DirtyBitmap, Error> {
+        Err(Error::NotImplemented)
+    }
+
     pub fn read_physical_memory(
         &self,
         bytes: &mut [u8],
@@ -231,6 +713,171 @@ impl Vm {
         Ok(size)
     }
 
+    pub fn host_address(
+        &self,
+        guest_address: u64,
+        size: usize,
+    ) -> Result<*mut u8, Error> {
+        // Look up the base guest address.
+        let range = match self.physical_ranges.get_key_value(&guest_address) {
+            Some((range, _)) => range.clone(),
+            _ => return Err(Error::InvalidGuestAddress),
+        };
+
+        // Look up the segment.
+        let segment = match self.segments.get(&range.start) {
+            Some(segment) => segment,
+            _ => return Err(Error::InvalidGuestAddress),
+        };
+
+        // The access must not cross the end of the segment.
+        if guest_address + size as u64 > range.end {
+            return Err(Error::InvalidGuestAddress);
+        }
+
+        let offset = (guest_address - range.start) as usize;
+
+        Ok(unsafe { segment.mapping.as_ptr().add(offset) as *mut u8 })
+    }
+
+    pub fn missing_page_handler(&mut self, _handler: MissingPageHandler) -> Result<(), Error> {
+        Err(Error::NotImplemented)
+    }
+
+    pub fn reclaim(&self, range: Range<u64>) -> Result<(), Error> {
+        let size = (range.end - range.start) as usize;
+
+        discard(self.host_address(range.start, size)?, size)
+    }
+
+    pub fn reinflate(&self, range: Range<u64>) -> Result<(), Error> {
+        let size = (range.end - range.start) as usize;
+
+        populate(self.host_address(range.start, size)?, size)
+    }
+
+    pub fn resident_size(&self) -> Result<usize, Error> {
+        let mut size = 0;
+
+        for segment in self.segments.values() {
+            size += resident_size(&segment.mapping)?;
+        }
+
+        Ok(size)
+    }
+
+    pub fn trim_resident_size(&self, _limit: usize) -> Result<usize, Error> {
+        // Mac OS X does not support paging out memory on request.
+        Err(Error::NotImplemented)
+    }
+
+    pub fn allocate_smram(
+        &mut self,
+        _guest_address: u64,
+        _size: usize,
+    ) -> Result<(), Error> {
+        Err(Error::NotImplemented)
+    }
+
+    pub fn read_smram(
+        &self,
+        _bytes: &mut [u8],
+        _guest_address: u64,
+    ) -> Result<usize, Error> {
+        Err(Error::NotImplemented)
+    }
+
+    pub fn write_smram(
+        &mut self,
+        _guest_address: u64,
+        _bytes: &[u8],
+    ) -> Result<usize, Error> {
+        Err(Error::NotImplemented)
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    pub fn post_synthetic_message(
+        &self,
+        _vcpu_id: usize,
+        _sint: u8,
+        _message: &SyntheticMessage,
+    ) -> Result<(), Error> {
+        Err(Error::NotImplemented)
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    pub fn signal_synthetic_event(
+        &self,
+        _vcpu_id: usize,
+        _sint: u8,
+        _flag: u16,
+    ) -> Result<bool, Error> {
+        Err(Error::NotImplemented)
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    pub fn sev_launch_update_data(
+        &self,
+        _guest_address: u64,
+        _size: usize,
+    ) -> Result<(), Error> {
+        Err(Error::NotImplemented)
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    pub fn sev_launch_update_vmsa(&self) -> Result<(), Error> {
+        Err(Error::NotImplemented)
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    pub fn sev_launch_measure(&self) -> Result<LaunchMeasurement, Error> {
+        Err(Error::NotImplemented)
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    pub fn sev_launch_finish(&self) -> Result<(), Error> {
+        Err(Error::NotImplemented)
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    pub fn set_msr_filter(
+        &mut self,
+        ranges: &[Range<u32>],
+        policy: MsrPolicy,
+    ) -> Result<(), Error> {
+        // The MSRs can only be passed through on the thread of the virtual CPU, so the virtual
+        // CPUs apply the filter upon their next run.
+        *self.msr_filter.write().unwrap() = Some(MsrFilter {
+            ranges: ranges.to_vec(),
+            policy,
+        });
+
+        Ok(())
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    pub fn set_irq_line(&self, _gsi: u32, _level: bool) -> Result<(), Error> {
+        Err(Error::NotImplemented)
+    }
+
+    #[cfg(feature = "xen")]
+    pub fn set_xen_shared_info(&mut self, _gfn: u64) -> Result<(), Error> {
+        Err(Error::NotImplemented)
+    }
+
+    #[cfg(feature = "xen")]
+    pub fn set_xen_upcall_vector(&mut self, _vector: u8) -> Result<(), Error> {
+        Err(Error::NotImplemented)
+    }
+
+    #[cfg(feature = "xen")]
+    pub fn send_xen_event(
+        &self,
+        _port: u32,
+        _vcpu_id: usize,
+    ) -> Result<(), Error> {
+        Err(Error::NotImplemented)
+    }
 }
 
 impl Drop for Vm {
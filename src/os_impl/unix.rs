@@ -0,0 +1,80 @@
+//! This module provides helpers shared by the Unix-like platforms.
+
+use crate::error::Error;
+use mmap_rs::MmapMut;
+
+/// Returns the number of bytes of the mapping that are resident in host memory.
+pub fn resident_size(mapping: &MmapMut) -> Result<usize, Error> {
+    let page_size = mmap_rs::MmapOptions::page_size().1;
+    let mut pages = vec![0u8; (mapping.len() + page_size - 1) / page_size];
+
+    let result = unsafe {
+        libc::mincore(
+            mapping.as_ptr() as *mut libc::c_void,
+            mapping.len(),
+            pages.as_mut_ptr() as _,
+        )
+    };
+
+    if result < 0 {
+        return Err(std::io::Error::last_os_error().into());
+    }
+
+    // The least significant bit indicates whether the page is resident.
+    Ok(pages.iter().filter(|&&page| page & 1 != 0).count() * page_size)
+}
+
+/// `MADV_POPULATE_WRITE`, which is only available as of Linux 5.14.
+#[cfg(target_os = "linux")]
+const MADV_POPULATE_WRITE: libc::c_int = 23;
+
+/// Helper function to call `madvise` with the given advice.
+fn madvise(address: *mut u8, size: usize, advice: libc::c_int) -> Result<(), std::io::Error> {
+    let result = unsafe { libc::madvise(address as *mut libc::c_void, size, advice) };
+
+    if result < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+
+    Ok(())
+}
+
+/// Discards the host pages backing the given memory, such that they are no longer resident. The
+/// memory reads as zeroes once touched again, unless it is backed by a file.
+pub fn discard(address: *mut u8, size: usize) -> Result<(), Error> {
+    #[cfg(target_os = "linux")]
+    madvise(address, size, libc::MADV_DONTNEED)?;
+
+    #[cfg(target_os = "macos")]
+    madvise(address, size, libc::MADV_FREE_REUSABLE)?;
+
+    Ok(())
+}
+
+/// Discards the host pages backing the given shared memory like [`discard`], but punches a hole
+/// into the shared memory object, e.g. the memfd backing anonymous guest memory, as unmapping
+/// the pages does not release them. Falls back to [`discard`] for private memory.
+#[cfg(target_os = "linux")]
+pub fn discard_shared(address: *mut u8, size: usize) -> Result<(), Error> {
+    match madvise(address, size, libc::MADV_REMOVE) {
+        Err(e) if e.raw_os_error() == Some(libc::EINVAL) => discard(address, size),
+        result => Ok(result?),
+    }
+}
+
+/// Repopulates the host pages backing the given memory after [`discard`].
+pub fn populate(address: *mut u8, size: usize) -> Result<(), Error> {
+    // Fall back to prefaulting the pages lazily on kernels that do not support populating them.
+    #[cfg(target_os = "linux")]
+    match madvise(address, size, MADV_POPULATE_WRITE) {
+        Err(e) if e.raw_os_error() == Some(libc::EINVAL) => {
+            madvise(address, size, libc::MADV_WILLNEED)?;
+        }
+        result => result?,
+    }
+
+    #[cfg(target_os = "macos")]
+    madvise(address, size, libc::MADV_FREE_REUSE)?;
+
+    Ok(())
+}
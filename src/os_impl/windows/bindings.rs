@@ -1,3 +1,162 @@
 windows::include_bindings!();
 
+pub use Windows::Win32::Foundation::{CloseHandle, BOOL, HANDLE};
 pub use Windows::Win32::System::Hypervisor::*;
+pub use Windows::Win32::System::JobObjects::*;
+pub use Windows::Win32::System::Memory::{VirtualAlloc, MEM_RESET, MEM_RESET_UNDO, PAGE_READWRITE};
+pub use Windows::Win32::System::Threading::*;
+
+// The bindings generated by the windows crate only cover the x86-64 definitions of the Windows
+// Hypervisor Platform. The AArch64 definitions below follow the ARM64 section of
+// WinHvPlatformDefs.h.
+
+#[cfg(target_arch = "aarch64")]
+pub const WHvArm64RegisterX0:   WHV_REGISTER_NAME = WHV_REGISTER_NAME(0x0002_0000);
+#[cfg(target_arch = "aarch64")]
+pub const WHvArm64RegisterSp:   WHV_REGISTER_NAME = WHV_REGISTER_NAME(0x0002_001f);
+#[cfg(target_arch = "aarch64")]
+pub const WHvArm64RegisterPc:   WHV_REGISTER_NAME = WHV_REGISTER_NAME(0x0002_0022);
+#[cfg(target_arch = "aarch64")]
+pub const WHvArm64RegisterCpsr: WHV_REGISTER_NAME = WHV_REGISTER_NAME(0x0002_0023);
+
+#[cfg(target_arch = "aarch64")]
+pub const WHvRunVpExitReasonArm64None:                   u32 = 0x0000_0000;
+#[cfg(target_arch = "aarch64")]
+pub const WHvRunVpExitReasonArm64UnmappedGpa:            u32 = 0x8000_0000;
+#[cfg(target_arch = "aarch64")]
+pub const WHvRunVpExitReasonArm64GpaIntercept:           u32 = 0x8000_0001;
+#[cfg(target_arch = "aarch64")]
+pub const WHvRunVpExitReasonArm64InvalidVpRegisterValue: u32 = 0x8000_0020;
+#[cfg(target_arch = "aarch64")]
+pub const WHvRunVpExitReasonArm64UnrecoverableException: u32 = 0x8000_0021;
+#[cfg(target_arch = "aarch64")]
+pub const WHvRunVpExitReasonArm64Reset:                  u32 = 0x8001_000c;
+#[cfg(target_arch = "aarch64")]
+pub const WHvRunVpExitReasonArm64Canceled:               u32 = 0xffff_ffff;
+
+#[cfg(target_arch = "aarch64")]
+#[allow(non_camel_case_types, non_snake_case)]
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct WHV_INTERCEPT_MESSAGE_HEADER_ARM64 {
+    pub VpIndex: u32,
+    pub InstructionLength: u8,
+    pub InterceptAccessType: u8,
+    pub ExecutionState: u16,
+    pub Pc: u64,
+    pub Cpsr: u64,
+}
+
+#[cfg(target_arch = "aarch64")]
+#[allow(non_camel_case_types, non_snake_case)]
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct WHV_MEMORY_ACCESS_CONTEXT_ARM64 {
+    pub Header: WHV_INTERCEPT_MESSAGE_HEADER_ARM64,
+    pub Reserved0: u32,
+    pub InstructionByteCount: u8,
+    pub AccessInfo: u8,
+    pub Reserved1: u16,
+    pub InstructionBytes: [u8; 4],
+    pub Reserved2: u32,
+    pub Gva: u64,
+    pub Gpa: u64,
+    pub Syndrome: u64,
+}
+
+#[cfg(target_arch = "aarch64")]
+#[allow(non_camel_case_types, non_snake_case)]
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub union WHV_RUN_VP_EXIT_CONTEXT_ARM64_0 {
+    pub MemoryAccess: WHV_MEMORY_ACCESS_CONTEXT_ARM64,
+    pub AsUINT64: [u64; 32],
+}
+
+#[cfg(target_arch = "aarch64")]
+#[allow(non_camel_case_types, non_snake_case)]
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct WHV_RUN_VP_EXIT_CONTEXT_ARM64 {
+    pub ExitReason: u32,
+    pub Reserved: u32,
+    pub Reserved1: u64,
+    pub Anonymous: WHV_RUN_VP_EXIT_CONTEXT_ARM64_0,
+}
+
+#[cfg(target_arch = "aarch64")]
+impl Default for WHV_RUN_VP_EXIT_CONTEXT_ARM64 {
+    fn default() -> Self {
+        Self {
+            ExitReason: WHvRunVpExitReasonArm64None,
+            Reserved: 0,
+            Reserved1: 0,
+            Anonymous: WHV_RUN_VP_EXIT_CONTEXT_ARM64_0 {
+                AsUINT64: [0; 32],
+            },
+        }
+    }
+}
+
+/// The `NmiNotification` bit of `WHV_X64_DELIVERABILITY_NOTIFICATIONS_REGISTER`, which requests
+/// an exit once the guest can accept NMIs.
+#[cfg(target_arch = "x86_64")]
+pub const WHV_DELIVERABILITY_NMI_NOTIFICATION: u64 = 1 << 0;
+
+/// The `InterruptNotification` bit of `WHV_X64_DELIVERABILITY_NOTIFICATIONS_REGISTER`, which
+/// requests an exit once the guest can accept interrupts.
+#[cfg(target_arch = "x86_64")]
+pub const WHV_DELIVERABILITY_INTERRUPT_NOTIFICATION: u64 = 1 << 1;
+
+// The bindings generated by the windows crate do not cover Hyper-V sockets. The definitions below
+// follow hvsocket.h and winsock2.h.
+
+/// The address family of Hyper-V sockets.
+pub const AF_HYPERV: i32 = 34;
+/// The socket type of stream sockets.
+pub const SOCK_STREAM: i32 = 1;
+/// The protocol of Hyper-V sockets.
+pub const HV_PROTOCOL_RAW: i32 = 1;
+/// The maximum length of the queue of pending connections.
+pub const SOMAXCONN: i32 = 0x7fff_ffff;
+/// The socket returned by `socket` and `accept` on failure.
+pub const INVALID_SOCKET: SOCKET = !0;
+/// The value returned by the other socket functions on failure.
+pub const SOCKET_ERROR: i32 = -1;
+/// The size of `WSADATA`, rounded up.
+pub const WSADATA_SIZE: usize = 512;
+
+#[allow(non_camel_case_types)]
+pub type SOCKET = usize;
+
+/// The layout of `GUID`.
+#[allow(non_camel_case_types, non_snake_case)]
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct HV_GUID {
+    pub Data1: u32,
+    pub Data2: u16,
+    pub Data3: u16,
+    pub Data4: [u8; 8],
+}
+
+#[allow(non_camel_case_types, non_snake_case)]
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SOCKADDR_HV {
+    pub Family: u16,
+    pub Reserved: u16,
+    pub VmId: HV_GUID,
+    pub ServiceId: HV_GUID,
+}
+
+#[link(name = "ws2_32")]
+extern "system" {
+    pub fn WSAStartup(version: u16, data: *mut u8) -> i32;
+    pub fn WSAGetLastError() -> i32;
+    pub fn socket(af: i32, kind: i32, protocol: i32) -> SOCKET;
+    pub fn bind(s: SOCKET, name: *const SOCKADDR_HV, namelen: i32) -> i32;
+    pub fn listen(s: SOCKET, backlog: i32) -> i32;
+    pub fn accept(s: SOCKET, addr: *mut SOCKADDR_HV, addrlen: *mut i32) -> SOCKET;
+    pub fn connect(s: SOCKET, name: *const SOCKADDR_HV, namelen: i32) -> i32;
+}
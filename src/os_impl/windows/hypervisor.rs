@@ -1,6 +1,12 @@
+#[cfg(target_arch = "x86_64")]
+use crate::arch::x86_64::{host_cpuid, CpuidEntry};
 use crate::error::Error;
+use crate::hypervisor::Capability;
+use crate::tsc::TscMode;
 use super::bindings::*;
 use super::vm::{PartitionHandle, VmBuilder};
+#[cfg(target_arch = "x86_64")]
+use super::vm::{EXTENDED_VM_EXIT_EXCEPTION, EXTENDED_VM_EXIT_HYPERCALL};
 
 pub struct Hypervisor;
 
@@ -9,6 +15,13 @@ impl Hypervisor {
         Ok(Self)
     }
 
+    pub fn has_capability(&self, capability: Capability) -> bool {
+        match capability {
+            Capability::ProtectedGuest => false,
+            Capability::Hypercalls => cfg!(target_arch = "x86_64"),
+        }
+    }
+
     pub fn build_vm(&self) -> Result<VmBuilder, Error> {
         let handle = unsafe {
             WHvCreatePartition()
@@ -16,6 +29,22 @@ impl Hypervisor {
 
         Ok(VmBuilder {
             handle: PartitionHandle(handle),
+            // The exception exits are needed for guest debugging, but the exceptions only exit
+            // once they have been selected in the exception exit bitmap. The hypercall exits are
+            // needed to dispatch the hypercalls to the registered handlers.
+            #[cfg(target_arch = "x86_64")]
+            extended_vm_exits: EXTENDED_VM_EXIT_EXCEPTION | EXTENDED_VM_EXIT_HYPERCALL,
+            #[cfg(target_arch = "aarch64")]
+            extended_vm_exits: 0,
+            tsc_mode: TscMode::Native,
+            synthetic_interrupts: false,
+            in_kernel_irqchip: false,
         })
     }
+
+    #[cfg(target_arch = "x86_64")]
+    pub fn get_supported_cpuid(&self) -> Result<Vec<CpuidEntry>, Error> {
+        // The Windows Hypervisor Platform passes through the CPUID of the host by default.
+        Ok(host_cpuid())
+    }
 }
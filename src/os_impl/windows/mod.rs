@@ -1,8 +1,10 @@
 pub mod bindings;
 pub mod hypervisor;
+pub mod thread;
 pub mod vcpu;
 pub mod vm;
+pub mod vsock;
 
 pub use hypervisor::Hypervisor;
-pub use vcpu::Vcpu;
+pub use vcpu::{Vcpu, VcpuHandle};
 pub use vm::{Vm, VmBuilder};
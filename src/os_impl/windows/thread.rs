@@ -0,0 +1,165 @@
+use crate::error::Error;
+use crate::thread::{ResourceLimits, ThreadPriority, ThreadPriorityReport};
+use std::sync::Mutex;
+use super::bindings::*;
+
+pub fn set_current_thread_priority(
+    priority: ThreadPriority,
+) -> Result<ThreadPriorityReport, Error> {
+    let mut diagnostics = vec![];
+    let mut applied = ThreadPriority::Elevated;
+
+    if let ThreadPriority::RealTime { .. } = priority {
+        // The priority class applies to the whole process, so leave it to the embedder and only
+        // raise the priority of the calling thread within the priority class of the process.
+        let class = unsafe {
+            GetPriorityClass(GetCurrentProcess())
+        };
+
+        if class == REALTIME_PRIORITY_CLASS.0 {
+            unsafe {
+                SetThreadPriority(GetCurrentThread(), THREAD_PRIORITY_TIME_CRITICAL)
+            }.ok()?;
+
+            return Ok(ThreadPriorityReport {
+                requested: priority,
+                applied: priority,
+                diagnostics,
+            });
+        }
+
+        diagnostics.push(format!(
+            "the process runs in priority class {:#x} rather than REALTIME_PRIORITY_CLASS, which \
+            applies to the whole process; falling back to THREAD_PRIORITY_HIGHEST",
+            class,
+        ));
+    }
+
+    let result = unsafe {
+        SetThreadPriority(GetCurrentThread(), THREAD_PRIORITY_HIGHEST)
+    }.ok();
+
+    if let Err(e) = result {
+        diagnostics.push(format!(
+            "SetThreadPriority() with THREAD_PRIORITY_HIGHEST failed ({}); keeping the normal \
+            priority",
+            e,
+        ));
+
+        applied = ThreadPriority::Normal;
+    }
+
+    Ok(ThreadPriorityReport {
+        requested: priority,
+        applied,
+        diagnostics,
+    })
+}
+
+pub struct ResourceGroup {
+    /// The handle of the job object.
+    job: HANDLE,
+    /// The affinity mask applied to the added threads.
+    affinity: Option<usize>,
+    /// Whether the process has been assigned to the job object.
+    assigned: Mutex<bool>,
+}
+
+// The handle of the job object can be used from any thread.
+unsafe impl Send for ResourceGroup {}
+unsafe impl Sync for ResourceGroup {}
+
+impl ResourceGroup {
+    pub fn new(name: &str, limits: &ResourceLimits) -> Result<Self, Error> {
+        let affinity = match &limits.cpu_affinity {
+            Some(cpus) => {
+                let mut mask = 0usize;
+
+                for &cpu in cpus {
+                    if cpu >= usize::BITS as usize {
+                        return Err(Error::InvalidArgument);
+                    }
+
+                    mask |= 1 << cpu;
+                }
+
+                Some(mask)
+            }
+            _ => None,
+        };
+
+        let job = unsafe {
+            CreateJobObjectW(std::ptr::null_mut(), name)
+        };
+
+        if job.is_null() {
+            return Err(std::io::Error::last_os_error().into());
+        }
+
+        let group = Self {
+            job,
+            affinity,
+            assigned: Mutex::new(false),
+        };
+
+        if let Some(quota) = limits.cpu_quota {
+            // The CPU rate is expressed in hundredths of a percent of all the processors.
+            let count = std::thread::available_parallelism()
+                .map(|count| count.get())
+                .unwrap_or(1) as u32;
+            let rate = (quota.saturating_mul(100) / count).max(1).min(10_000);
+
+            let info = JOBOBJECT_CPU_RATE_CONTROL_INFORMATION {
+                ControlFlags: JOB_OBJECT_CPU_RATE_CONTROL_ENABLE |
+                    JOB_OBJECT_CPU_RATE_CONTROL_HARD_CAP,
+                Anonymous: JOBOBJECT_CPU_RATE_CONTROL_INFORMATION_0 {
+                    CpuRate: rate,
+                },
+            };
+
+            unsafe {
+                SetInformationJobObject(
+                    group.job,
+                    JobObjectCpuRateControlInformation,
+                    &info as *const _ as *const std::ffi::c_void,
+                    std::mem::size_of::<JOBOBJECT_CPU_RATE_CONTROL_INFORMATION>() as u32,
+                )
+            }.ok()?;
+        }
+
+        Ok(group)
+    }
+
+    pub fn add_current_thread(&self) -> Result<(), Error> {
+        // Job objects contain processes rather than threads, so assign the process once.
+        let mut assigned = self.assigned.lock().unwrap();
+
+        if !*assigned {
+            unsafe {
+                AssignProcessToJobObject(self.job, GetCurrentProcess())
+            }.ok()?;
+
+            *assigned = true;
+        }
+
+        if let Some(mask) = self.affinity {
+            let result = unsafe {
+                SetThreadAffinityMask(GetCurrentThread(), mask)
+            };
+
+            if result == 0 {
+                return Err(std::io::Error::last_os_error().into());
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl Drop for ResourceGroup {
+    fn drop(&mut self) {
+        let _ = unsafe {
+            CloseHandle(self.job)
+        };
+    }
+}
@@ -1,48 +1,170 @@
 use crate::error::Error;
-use crate::vcpu::ExitReason;
+use crate::hypercall::Hypercalls;
+use crate::tsc::VirtualTsc;
+use crate::vcpu::{ExitPolicy, ExitReason};
+#[cfg(target_arch = "x86_64")]
+use std::collections::VecDeque;
 use std::ops::Deref;
 use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
 use super::bindings::*;
 use super::vm::PartitionHandle;
 
 pub struct Vcpu {
     pub(crate) handle: Arc<PartitionHandle>,
     pub(crate) id: u32,
+    pub(crate) tsc: Option<VirtualTsc>,
+    /// Whether the virtual CPU has been kicked through a [`VcpuHandle`].
+    pub(crate) kicked: Arc<AtomicBool>,
+    /// Whether [`Vcpu::run`] returns without entering the guest.
+    pub(crate) immediate_exit: bool,
+    /// The data of the last port I/O exit.
+    #[cfg(target_arch = "x86_64")]
+    pub(crate) io_data: [u8; 4],
+    /// The size of the `in` instruction that still has to be completed with the data filled in
+    /// by the caller.
+    #[cfg(target_arch = "x86_64")]
+    pub(crate) pending_io_in: Option<usize>,
+    /// The data of the last MMIO exit.
+    #[cfg(target_arch = "x86_64")]
+    pub(crate) mmio_data: [u8; 8],
+    /// The size of the last MMIO access in bytes.
+    #[cfg(target_arch = "x86_64")]
+    pub(crate) mmio_size: usize,
+    /// The register of the MMIO read that still has to be completed with the data filled in by
+    /// the caller.
+    #[cfg(target_arch = "x86_64")]
+    pub(crate) pending_mmio_read: Option<RegisterOperand>,
+    /// The address of the instruction following the `cpuid` instruction that has not been
+    /// completed yet.
+    #[cfg(target_arch = "x86_64")]
+    pub(crate) pending_cpuid: Option<u64>,
+    /// Whether the last exit was an `rdmsr` instruction that has not been completed yet.
+    #[cfg(target_arch = "x86_64")]
+    pub(crate) pending_msr_read: bool,
+    /// The exceptions that exit for guest debugging.
+    #[cfg(target_arch = "x86_64")]
+    pub(crate) debug_exceptions: u32,
+    /// The exceptions that exit with [`ExitReason::Exception`].
+    #[cfg(target_arch = "x86_64")]
+    pub(crate) exception_exits: u32,
+    /// Whether the local APIC is emulated by the hypervisor, in which case interrupts are
+    /// requested through the local APIC rather than injected directly.
+    #[cfg(target_arch = "x86_64")]
+    pub(crate) apic_emulation: bool,
+    /// The vectors of the interrupts that are queued until the guest is able to accept them.
+    #[cfg(target_arch = "x86_64")]
+    pub(crate) pending_interrupts: VecDeque<u8>,
+    /// Whether an NMI is queued until the guest is able to accept it.
+    #[cfg(target_arch = "x86_64")]
+    pub(crate) pending_nmi: bool,
+    /// Whether the caller requested an exit upon the interrupt window, as opposed to the requests
+    /// made to inject the queued interrupts.
+    #[cfg(target_arch = "x86_64")]
+    pub(crate) interrupt_window_requested: bool,
+}
+
+#[derive(Clone)]
+pub struct VcpuHandle {
+    handle: Arc<PartitionHandle>,
+    id: u32,
+    kicked: Arc<AtomicBool>,
+}
+
+impl VcpuHandle {
+    pub fn clear_kick(&self) {
+        self.kicked.store(false, Ordering::SeqCst);
+    }
+
+    pub fn kick(&self) -> Result<(), Error> {
+        self.kicked.store(true, Ordering::SeqCst);
+
+        unsafe {
+            WHvCancelRunVirtualProcessor(
+                self.handle.deref().0,
+                self.id,
+                0,
+            )
+        }?;
+
+        Ok(())
+    }
 }
 
 impl Vcpu {
-    pub fn run(&mut self) -> Result<ExitReason, Error> {
-        let mut context = WHV_RUN_VP_EXIT_CONTEXT::default();
+    /// Helper function to get the values of the given registers.
+    pub(crate) fn get_raw_registers(
+        &self,
+        registers: &[WHV_REGISTER_NAME],
+    ) -> Result<Vec<WHV_REGISTER_VALUE>, Error> {
+        let mut values = vec![WHV_REGISTER_VALUE::default(); registers.len()];
 
         unsafe {
-            WHvRunVirtualProcessor(
+            WHvGetVirtualProcessorRegisters(
                 self.handle.deref().0,
                 self.id,
-                &mut context as *mut WHV_RUN_VP_EXIT_CONTEXT as *mut std::ffi::c_void,
-                std::mem::size_of::<WHV_RUN_VP_EXIT_CONTEXT>() as u32,
+                registers.as_ptr(),
+                registers.len() as u32,
+                values.as_mut_ptr(),
             )
         }?;
 
-        let exit_reason = match context.ExitReason {
-            super::bindings::WHvRunVpExitReasonMemoryAccess => {
-                let info = unsafe { context.Anonymous.MemoryAccess };
+        Ok(values)
+    }
 
-                ExitReason::InvalidMemoryAccess {
-                    gpa: info.Gpa,
-                    gva: info.Gva as usize,
-                }
-            }
-            super::bindings::WHvRunVpExitReasonUnrecoverableException =>
-                ExitReason::UnhandledException,
-            super::bindings::WHvRunVpExitReasonX64Halt =>
-                ExitReason::Halted,
-            exit_reason => {
-                println!("{:?}", exit_reason);
-                ExitReason::Unknown
-            }
-        };
+    /// Helper function to set the given registers to the given values.
+    pub(crate) fn set_raw_registers(
+        &mut self,
+        registers: &[WHV_REGISTER_NAME],
+        values: &[WHV_REGISTER_VALUE],
+    ) -> Result<(), Error> {
+        unsafe {
+            WHvSetVirtualProcessorRegisters(
+                self.handle.deref().0,
+                self.id,
+                registers.as_ptr(),
+                registers.len() as u32,
+                values.as_ptr(),
+            )
+        }?;
 
-        Ok(exit_reason)
+        Ok(())
+    }
+
+    pub fn handle(&self) -> VcpuHandle {
+        VcpuHandle {
+            handle: self.handle.clone(),
+            id: self.id,
+            kicked: self.kicked.clone(),
+        }
+    }
+
+    pub fn set_immediate_exit(&mut self, immediate_exit: bool) {
+        self.immediate_exit = immediate_exit;
+    }
+
+    pub fn virtual_tsc(&self) -> Option<u64> {
+        self.tsc.as_ref().map(|tsc| tsc.value)
+    }
+
+    pub fn set_virtual_tsc(&mut self, value: u64) -> Result<(), Error> {
+        match self.tsc.as_mut() {
+            Some(tsc) => tsc.value = value,
+            _ => return Err(Error::NotImplemented),
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(feature = "xen")]
+impl Vcpu {
+    pub fn set_xen_vcpu_info(&mut self, _gpa: u64) -> Result<(), Error> {
+        Err(Error::NotImplemented)
+    }
+
+    pub fn complete_xen_hypercall(&mut self, _result: u64) -> Result<(), Error> {
+        Err(Error::NotImplemented)
     }
 }
 
@@ -59,10 +181,899 @@ impl Drop for Vcpu {
 
 #[cfg(target_arch = "x86_64")]
 use crate::arch::x86_64::{
-    ControlRegister, CpuRegs, DescriptorTable, DescriptorTableRegister, Segment, SegmentRegister,
-    Register,
+    ControlRegister, CpuRegs, CpuidEntry, CpuidResult, DebugRegister, DescriptorTable,
+    DescriptorTableRegister, FpuState, PendingEvents, PendingException, Segment, SegmentRegister,
+    Register, SmmState, HV_X64_MSR_SINT0, HV_X64_MSR_SINT15, RFLAGS_IF, RFLAGS_TF,
+};
+#[cfg(target_arch = "x86_64")]
+use crate::mmio::{self, CodeMode, MmioOperation, RegisterOperand};
+#[cfg(target_arch = "x86_64")]
+use crate::debug::{
+    decode_dr6, encode_dr7, DebugExit, DebugExitKind, GuestDebug, MAX_HW_BREAKPOINTS,
 };
 
+#[cfg(target_arch = "x86_64")]
+impl Vcpu {
+    /// Helper function to serve the virtual TSC to an `rdtsc` or `rdtscp` instruction.
+    fn emulate_rdtsc(&mut self, context: &WHV_RUN_VP_EXIT_CONTEXT) -> Result<(), Error> {
+        let value = match self.tsc.as_mut() {
+            Some(tsc) => tsc.read(),
+            _ => return Err(Error::NotImplemented),
+        };
+
+        let info = unsafe { context.Anonymous.ReadTsc };
+        let length = (context.VpContext._bitfield & 0xf) as u64;
+
+        let mut registers = vec![WHvX64RegisterRax, WHvX64RegisterRdx, WHvX64RegisterRip];
+        let mut values = vec![
+            WHV_REGISTER_VALUE { Reg64: value & 0xffff_ffff },
+            WHV_REGISTER_VALUE { Reg64: value >> 32 },
+            WHV_REGISTER_VALUE { Reg64: context.VpContext.Rip + length },
+        ];
+
+        // The RdtscInfo field indicates whether the instruction was `rdtscp`.
+        if info.RdtscInfo.AsUINT64 & 0x1 == 0x1 {
+            registers.push(WHvX64RegisterRcx);
+            values.push(WHV_REGISTER_VALUE { Reg64: info.TscAux & 0xffff_ffff });
+        }
+
+        self.set_raw_registers(&registers, &values)
+    }
+
+    /// Helper function to decode a port I/O exit. The instruction is skipped right away like KVM
+    /// does, such that the `in` instruction only has to be completed by writing `rax`.
+    fn decode_io(
+        &mut self,
+        context: &WHV_RUN_VP_EXIT_CONTEXT,
+    ) -> Result<Option<(u16, usize, bool)>, Error> {
+        let info = unsafe { context.Anonymous.IoPortAccess };
+        let access_info = unsafe { info.AccessInfo.AsUINT32 };
+
+        // The string instructions (`ins` and `outs`) are not supported.
+        if access_info & (1 << 4) != 0 {
+            return Ok(None);
+        }
+
+        let size = ((access_info >> 1) & 0x7) as usize;
+        let is_in = access_info & 0x1 == 0;
+
+        if is_in {
+            self.pending_io_in = Some(size);
+        } else {
+            self.io_data = (info.Rax as u32).to_le_bytes();
+        }
+
+        let length = (context.VpContext._bitfield & 0xf) as u64;
+
+        self.set_raw_registers(
+            &[WHvX64RegisterRip],
+            &[WHV_REGISTER_VALUE { Reg64: context.VpContext.Rip + length }],
+        )?;
+
+        Ok(Some((info.PortNumber, size, is_in)))
+    }
+
+    /// Helper function to complete a pending `in` instruction by moving the data filled in by the
+    /// caller into `rax`.
+    fn finish_io_in(&mut self) -> Result<(), Error> {
+        let size = match self.pending_io_in.take() {
+            Some(size) => size,
+            _ => return Ok(()),
+        };
+
+        let data = u32::from_le_bytes(self.io_data) as u64;
+        let values = self.get_raw_registers(&[WHvX64RegisterRax])?;
+        let rax = unsafe { values[0].Reg64 };
+
+        // Like on bare metal, a 32-bit access zero-extends into `rax`, while the smaller accesses
+        // preserve the upper bits.
+        let value = match size {
+            1 => (rax & !0xff) | (data & 0xff),
+            2 => (rax & !0xffff) | (data & 0xffff),
+            _ => data,
+        };
+
+        self.set_raw_registers(&[WHvX64RegisterRax], &[WHV_REGISTER_VALUE { Reg64: value }])
+    }
+
+    /// Helper function to decode the instruction that caused a memory access exit. The instruction
+    /// is skipped right away like KVM does, such that a read only has to be completed by writing
+    /// the register. Returns `None` if the instruction is not supported.
+    fn decode_mmio(
+        &mut self,
+        context: &WHV_RUN_VP_EXIT_CONTEXT,
+    ) -> Result<Option<(usize, bool)>, Error> {
+        let info = unsafe { context.Anonymous.MemoryAccess };
+
+        // The AccessType field is 0 for reads, 1 for writes and 2 for instruction fetches.
+        let access_type = unsafe { info.AccessInfo.AsUINT32 } & 0x3;
+
+        if access_type > 1 {
+            return Ok(None);
+        }
+
+        let bytes = &info.InstructionBytes[..info.InstructionByteCount as usize];
+        let access_rights = unsafe { context.VpContext.Cs.Anonymous.Attributes } as u64;
+        let mode = CodeMode::from_cs_access_rights(access_rights);
+
+        let instruction = match mmio::decode(bytes, mode) {
+            Some(instruction) if instruction.is_write() == (access_type == 1) => instruction,
+            _ => return Ok(None),
+        };
+
+        self.mmio_size = instruction.size;
+
+        match instruction.operation {
+            MmioOperation::Read(operand) => {
+                self.mmio_data = [0; 8];
+                self.pending_mmio_read = Some(operand);
+            }
+            _ => self.mmio_data = instruction.write_data(self)?.to_le_bytes(),
+        }
+
+        self.set_raw_registers(
+            &[WHvX64RegisterRip],
+            &[WHV_REGISTER_VALUE { Reg64: context.VpContext.Rip + instruction.length }],
+        )?;
+
+        Ok(Some((instruction.size, instruction.is_write())))
+    }
+
+    /// Helper function to complete a pending MMIO read by moving the data filled in by the caller
+    /// into the register.
+    fn finish_mmio_read(&mut self) -> Result<(), Error> {
+        let operand = match self.pending_mmio_read.take() {
+            Some(operand) => operand,
+            _ => return Ok(()),
+        };
+
+        operand.write(self, u64::from_le_bytes(self.mmio_data))
+    }
+
+    pub fn complete_io(&mut self, data: &[u8]) -> Result<(), Error> {
+        match self.pending_io_in {
+            Some(size) if size == data.len() => (),
+            _ => return Err(Error::InvalidArgument),
+        }
+
+        self.io_data[..data.len()].copy_from_slice(data);
+
+        Ok(())
+    }
+
+    pub fn complete_mmio_read(&mut self, data: &[u8]) -> Result<(), Error> {
+        if self.pending_mmio_read.is_none() || data.len() != self.mmio_size {
+            return Err(Error::InvalidArgument);
+        }
+
+        self.mmio_data[..data.len()].copy_from_slice(data);
+
+        Ok(())
+    }
+
+    pub fn run(
+        &mut self,
+        policy: ExitPolicy,
+        hypercalls: &Hypercalls,
+    ) -> Result<ExitReason, Error> {
+        let mut context = WHV_RUN_VP_EXIT_CONTEXT::default();
+
+        self.finish_io_in()?;
+        self.finish_mmio_read()?;
+        self.pending_cpuid = None;
+        self.pending_msr_read = false;
+
+        let exit_reason = loop {
+            // A kick while the virtual CPU is running results in a canceled exit, which lands here.
+            if self.kicked.swap(false, Ordering::SeqCst) {
+                break ExitReason::Canceled;
+            }
+
+            // The pending I/O and MMIO operations have been completed above.
+            if self.immediate_exit {
+                break ExitReason::Canceled;
+            }
+
+            self.inject_pending_events()?;
+
+            unsafe {
+                WHvRunVirtualProcessor(
+                    self.handle.deref().0,
+                    self.id,
+                    &mut context as *mut WHV_RUN_VP_EXIT_CONTEXT as *mut std::ffi::c_void,
+                    std::mem::size_of::<WHV_RUN_VP_EXIT_CONTEXT>() as u32,
+                )
+            }?;
+
+            if let Some(tsc) = self.tsc.as_mut() {
+                tsc.on_exit();
+            }
+
+            break match context.ExitReason {
+                super::bindings::WHvRunVpExitReasonCanceled =>
+                    continue,
+                super::bindings::WHvRunVpExitReasonX64Rdtsc => {
+                    self.emulate_rdtsc(&context)?;
+                    continue;
+                }
+                super::bindings::WHvRunVpExitReasonMemoryAccess => {
+                    let info = unsafe { context.Anonymous.MemoryAccess };
+
+                    match self.decode_mmio(&context)? {
+                        Some((size, true)) => ExitReason::MmioWrite {
+                            address: info.Gpa,
+                            data: &self.mmio_data[..size],
+                        },
+                        Some((size, false)) => ExitReason::MmioRead {
+                            address: info.Gpa,
+                            data: &mut self.mmio_data[..size],
+                        },
+                        // The virtual CPU just tried accessing some area we did not map.
+                        _ => ExitReason::InvalidMemoryAccess {
+                            gpa: info.Gpa,
+                            gva: info.Gva as usize,
+                        },
+                    }
+                }
+                super::bindings::WHvRunVpExitReasonX64IoPortAccess => {
+                    match self.decode_io(&context)? {
+                        Some((port, size, true)) => {
+                            self.io_data = [0; 4];
+
+                            ExitReason::IoIn { port, data: &mut self.io_data[..size] }
+                        }
+                        Some((port, size, false)) =>
+                            ExitReason::IoOut { port, data: &self.io_data[..size] },
+                        _ => ExitReason::Unknown,
+                    }
+                }
+                super::bindings::WHvRunVpExitReasonX64Cpuid => {
+                    let info = unsafe { context.Anonymous.CpuidAccess };
+                    let length = (context.VpContext._bitfield & 0xf) as u64;
+
+                    self.pending_cpuid = Some(context.VpContext.Rip + length);
+
+                    ExitReason::Cpuid {
+                        leaf: info.Rax as u32,
+                        subleaf: info.Rcx as u32,
+                    }
+                }
+                super::bindings::WHvRunVpExitReasonX64MsrAccess => {
+                    let info = unsafe { context.Anonymous.MsrAccess };
+                    let is_write = unsafe { info.AccessInfo.AsUINT32 } & 0x1 != 0;
+                    let length = (context.VpContext._bitfield & 0xf) as u64;
+
+                    let mut registers = vec![WHvX64RegisterRip];
+                    let mut values = vec![
+                        WHV_REGISTER_VALUE { Reg64: context.VpContext.Rip + length },
+                    ];
+
+                    // The guest reads zero unless the read is completed.
+                    if !is_write {
+                        registers.extend_from_slice(&[WHvX64RegisterRax, WHvX64RegisterRdx]);
+                        values.extend_from_slice(&[
+                            WHV_REGISTER_VALUE { Reg64: 0 },
+                            WHV_REGISTER_VALUE { Reg64: 0 },
+                        ]);
+                    }
+
+                    self.set_raw_registers(&registers, &values)?;
+
+                    if is_write {
+                        ExitReason::MsrWrite {
+                            msr: info.MsrNumber,
+                            value: (info.Rdx << 32) | (info.Rax & 0xffff_ffff),
+                        }
+                    } else {
+                        self.pending_msr_read = true;
+
+                        ExitReason::MsrRead { msr: info.MsrNumber }
+                    }
+                }
+                super::bindings::WHvRunVpExitReasonUnrecoverableException =>
+                    ExitReason::UnhandledException,
+                super::bindings::WHvRunVpExitReasonX64Halt =>
+                    ExitReason::Halted,
+                super::bindings::WHvRunVpExitReasonHypercall => {
+                    // Skip the `vmcall` or `vmmcall` instruction.
+                    let length = (context.VpContext._bitfield & 0xf) as u64;
+
+                    self.set_raw_registers(
+                        &[WHvX64RegisterRip],
+                        &[WHV_REGISTER_VALUE { Reg64: context.VpContext.Rip + length }],
+                    )?;
+
+                    match hypercalls.dispatch(self)? {
+                        Some(hypercall) => ExitReason::Hypercall(hypercall),
+                        _ => continue,
+                    }
+                }
+                // The hypervisor clears the notification upon delivering this exit.
+                super::bindings::WHvRunVpExitReasonX64InterruptWindow => {
+                    let info = unsafe { context.Anonymous.InterruptWindow };
+
+                    // Only report the exit if the caller requested it, as the notification may
+                    // also have been requested to inject the queued NMI or interrupts.
+                    let requested = if info.DeliverableType == WHvX64PendingInterrupt {
+                        std::mem::take(&mut self.interrupt_window_requested)
+                    } else {
+                        false
+                    };
+
+                    if !requested || policy.contains(ExitPolicy::RESUME_INTERRUPT_WINDOW) {
+                        continue;
+                    }
+
+                    ExitReason::InterruptWindow
+                }
+                super::bindings::WHvRunVpExitReasonException => {
+                    let info = unsafe { context.Anonymous.VpException };
+                    let vector = info.ExceptionType & 0x1f;
+
+                    let kind = match vector {
+                        // #BP
+                        3 if self.debug_exceptions & (1 << 3) != 0 =>
+                            DebugExitKind::SoftwareBreakpoint,
+                        // #DB
+                        1 if self.debug_exceptions & (1 << 1) != 0 => {
+                            let values = self.get_raw_registers(&[WHvX64RegisterDr6])?;
+
+                            decode_dr6(unsafe { values[0].Reg64 })
+                        }
+                        _ => {
+                            // The ErrorCodeValid bit indicates that the exception pushed an error
+                            // code, while the exception parameter holds the faulting address of
+                            // page faults.
+                            let error_code = match unsafe { info.ExceptionInfo.AsUINT32 } & 0x1 {
+                                0 => None,
+                                _ => Some(info.ErrorCode),
+                            };
+
+                            let cr2 = match vector {
+                                14 => Some(info.ExceptionParameter),
+                                _ => None,
+                            };
+
+                            break ExitReason::Exception { vector, error_code, cr2 };
+                        }
+                    };
+
+                    ExitReason::Debug(DebugExit {
+                        pc: context.VpContext.Rip,
+                        kind,
+                        breakpoint: None,
+                    })
+                }
+                exit_reason => {
+                    println!("{:?}", exit_reason);
+                    ExitReason::Unknown
+                }
+            };
+        };
+
+        Ok(exit_reason)
+    }
+
+    pub fn inject_smi(&mut self) -> Result<(), Error> {
+        Err(Error::NotImplemented)
+    }
+
+    pub fn translate_gva(&self, gva: u64) -> Result<u64, Error> {
+        let mut result = WHV_TRANSLATE_GVA_RESULT::default();
+        let mut gpa = 0;
+
+        unsafe {
+            WHvTranslateGva(
+                self.handle.deref().0,
+                self.id,
+                gva,
+                WHvTranslateGvaFlagValidateRead,
+                &mut result,
+                &mut gpa,
+            )
+        }?;
+
+        match result.ResultCode {
+            WHvTranslateGvaResultSuccess => Ok(gpa),
+            WHvTranslateGvaResultPageNotPresent => Err(Error::PageNotPresent),
+            _ => Err(Error::InvalidGuestAddress),
+        }
+    }
+
+    pub fn get_smm_state(&self) -> Result<SmmState, Error> {
+        Err(Error::NotImplemented)
+    }
+
+    pub fn set_smm_state(&mut self, _state: &SmmState) -> Result<(), Error> {
+        Err(Error::NotImplemented)
+    }
+
+    pub fn get_pending_events(&self) -> Result<PendingEvents, Error> {
+        let values = self.get_raw_registers(&[
+            WHvRegisterPendingInterruption,
+            WHvRegisterInterruptState,
+        ])?;
+
+        let pending = unsafe { values[0].Reg64 };
+        let state = unsafe { values[1].Reg64 };
+
+        let mut events = PendingEvents {
+            interrupt_shadow: state & (1 << 0) != 0,
+            nmi_masked: state & (1 << 1) != 0,
+            queued_interrupts: self.pending_interrupts.iter().copied().collect(),
+            ..Default::default()
+        };
+
+        // Check if an interruption is pending.
+        if pending & 1 == 0 {
+            return Ok(events);
+        }
+
+        let vector = ((pending >> 16) & 0xff) as u8;
+
+        match (pending >> 1) & 0x7 {
+            0 => events.interrupt = Some(vector),
+            2 => events.nmi_pending = true,
+            3 => {
+                events.exception = Some(PendingException {
+                    vector,
+                    error_code: match pending & (1 << 4) {
+                        0 => None,
+                        _ => Some((pending >> 32) as u32),
+                    },
+                });
+            }
+            _ => (),
+        }
+
+        Ok(events)
+    }
+
+    pub fn set_pending_events(&mut self, events: &PendingEvents) -> Result<(), Error> {
+        // Only a single interruption can be pending.
+        let pending = match (events.exception, events.interrupt, events.nmi_pending) {
+            (None, None, false) => 0,
+            (Some(exception), None, false) => {
+                let mut pending = 1 | (3 << 1) | ((exception.vector as u64) << 16);
+
+                if let Some(error_code) = exception.error_code {
+                    pending |= (1 << 4) | ((error_code as u64) << 32);
+                }
+
+                pending
+            }
+            (None, Some(vector), false) => 1 | ((vector as u64) << 16),
+            (None, None, true) => 1 | (2 << 1) | (2 << 16),
+            _ => return Err(Error::InvalidArgument),
+        };
+
+        let state = (events.interrupt_shadow as u64) | ((events.nmi_masked as u64) << 1);
+
+        self.set_raw_registers(
+            &[WHvRegisterPendingInterruption, WHvRegisterInterruptState],
+            &[WHV_REGISTER_VALUE { Reg64: pending }, WHV_REGISTER_VALUE { Reg64: state }],
+        )?;
+
+        self.pending_interrupts = events.queued_interrupts.iter().copied().collect();
+
+        Ok(())
+    }
+
+    pub fn get_xsave(&self) -> Result<Vec<u8>, Error> {
+        let mut size = 0;
+
+        // Query the size of the XSAVE area first, which fails as the buffer is too small.
+        let _ = unsafe {
+            WHvGetVirtualProcessorXsaveState(
+                self.handle.deref().0,
+                self.id,
+                std::ptr::null_mut(),
+                0,
+                &mut size,
+            )
+        };
+
+        let mut bytes = vec![0u8; size as usize];
+
+        unsafe {
+            WHvGetVirtualProcessorXsaveState(
+                self.handle.deref().0,
+                self.id,
+                bytes.as_mut_ptr() as *mut _,
+                bytes.len() as u32,
+                &mut size,
+            )
+        }?;
+
+        bytes.truncate(size as usize);
+
+        Ok(bytes)
+    }
+
+    pub fn set_xsave(&mut self, bytes: &[u8]) -> Result<(), Error> {
+        unsafe {
+            WHvSetVirtualProcessorXsaveState(
+                self.handle.deref().0,
+                self.id,
+                bytes.as_ptr() as *const _,
+                bytes.len() as u32,
+            )
+        }?;
+
+        Ok(())
+    }
+
+    pub fn get_xcr0(&self) -> Result<u64, Error> {
+        let values = self.get_raw_registers(&[WHvX64RegisterXCr0])?;
+
+        Ok(unsafe { values[0].Reg64 })
+    }
+
+    pub fn set_xcr0(&mut self, value: u64) -> Result<(), Error> {
+        self.set_raw_registers(&[WHvX64RegisterXCr0], &[WHV_REGISTER_VALUE { Reg64: value }])
+    }
+
+    pub fn get_tsc(&self) -> Result<u64, Error> {
+        let values = self.get_raw_registers(&[WHvX64RegisterTsc])?;
+
+        Ok(unsafe { values[0].Reg64 })
+    }
+
+    pub fn set_tsc(&mut self, value: u64) -> Result<(), Error> {
+        self.set_raw_registers(&[WHvX64RegisterTsc], &[WHV_REGISTER_VALUE { Reg64: value }])
+    }
+
+    pub fn get_tsc_offset(&self) -> Result<u64, Error> {
+        // The Windows Hypervisor Platform only exposes the TSC of the guest.
+        Err(Error::NotImplemented)
+    }
+
+    pub fn set_tsc_offset(&mut self, _offset: u64) -> Result<(), Error> {
+        Err(Error::NotImplemented)
+    }
+
+    pub fn get_tsc_khz(&self) -> Result<u32, Error> {
+        let mut capability = WHV_CAPABILITY::default();
+        let mut size = 0;
+
+        unsafe {
+            WHvGetCapability(
+                WHvCapabilityCodeProcessorClockFrequency,
+                &mut capability as *mut WHV_CAPABILITY as *mut std::ffi::c_void,
+                std::mem::size_of::<WHV_CAPABILITY>() as u32,
+                &mut size,
+            )
+        }?;
+
+        Ok((unsafe { capability.ProcessorClockFrequency } / 1000) as u32)
+    }
+
+    pub fn set_tsc_khz(&mut self, _khz: u32) -> Result<(), Error> {
+        // The guest runs at the TSC frequency of the host.
+        Err(Error::NotImplemented)
+    }
+
+    pub fn set_tpr_threshold(&mut self, _threshold: u8) -> Result<(), Error> {
+        Err(Error::NotImplemented)
+    }
+
+    pub fn set_virtual_apic_page(&mut self, _gpa: u64) -> Result<(), Error> {
+        Err(Error::NotImplemented)
+    }
+
+    pub fn request_interrupt_window(&mut self) -> Result<(), Error> {
+        self.interrupt_window_requested = true;
+
+        // Request a notification once the guest can accept interrupts of any priority.
+        let values = self.get_raw_registers(&[WHvX64RegisterDeliverabilityNotifications])?;
+        let value = unsafe { values[0].Reg64 } | WHV_DELIVERABILITY_INTERRUPT_NOTIFICATION;
+
+        self.set_raw_registers(
+            &[WHvX64RegisterDeliverabilityNotifications],
+            &[WHV_REGISTER_VALUE { Reg64: value }],
+        )
+    }
+
+    pub fn inject_interrupt(&mut self, vector: u8) -> Result<(), Error> {
+        if !self.apic_emulation {
+            self.pending_interrupts.push_back(vector);
+            return Ok(());
+        }
+
+        // The local APIC emulated by the hypervisor delivers the interrupt once the guest is able
+        // to accept it.
+        self.request_apic_interrupt(WHvX64InterruptTypeFixed, vector)
+    }
+
+    pub fn inject_nmi(&mut self) -> Result<(), Error> {
+        if !self.apic_emulation {
+            self.pending_nmi = true;
+            return Ok(());
+        }
+
+        self.request_apic_interrupt(WHvX64InterruptTypeNmi, 2)
+    }
+
+    pub fn inject_exception(&mut self, vector: u8, error_code: Option<u32>) -> Result<(), Error> {
+        // Only a single interruption can be pending, so queue any interrupt or NMI that was
+        // pending to deliver it after the exception.
+        let values = self.get_raw_registers(&[WHvRegisterPendingInterruption])?;
+        let pending = unsafe { values[0].Reg64 };
+
+        if pending & 1 != 0 {
+            match (pending >> 1) & 0x7 {
+                0 => self.pending_interrupts.push_front(((pending >> 16) & 0xff) as u8),
+                2 => self.pending_nmi = true,
+                _ => (),
+            }
+        }
+
+        let mut pending = 1 | (3 << 1) | ((vector as u64) << 16);
+
+        if let Some(error_code) = error_code {
+            pending |= (1 << 4) | ((error_code as u64) << 32);
+        }
+
+        self.set_raw_registers(
+            &[WHvRegisterPendingInterruption],
+            &[WHV_REGISTER_VALUE { Reg64: pending }],
+        )
+    }
+
+    /// Helper function to request an interrupt of the given type through the local APIC of the
+    /// virtual CPU.
+    fn request_apic_interrupt(
+        &mut self,
+        interrupt_type: WHV_INTERRUPT_TYPE,
+        vector: u8,
+    ) -> Result<(), Error> {
+        // Deliver an edge-triggered interrupt to the APIC ID of the virtual CPU in physical mode.
+        let control = WHV_INTERRUPT_CONTROL {
+            _bitfield: interrupt_type.0 as u64,
+            Destination: self.id,
+            Vector: vector as u32,
+        };
+
+        unsafe {
+            WHvRequestInterrupt(
+                self.handle.deref().0,
+                &control,
+                std::mem::size_of::<WHV_INTERRUPT_CONTROL>() as u32,
+            )
+        }?;
+
+        Ok(())
+    }
+
+    /// Helper function to inject the queued NMI or the first of the queued interrupts upon the
+    /// next entry if the guest is able to accept it. Otherwise, a notification is requested to
+    /// try again once the guest is able to accept it.
+    fn inject_pending_events(&mut self) -> Result<(), Error> {
+        if !self.pending_nmi && self.pending_interrupts.is_empty() {
+            return Ok(());
+        }
+
+        let values = self.get_raw_registers(&[
+            WHvRegisterPendingInterruption,
+            WHvRegisterInterruptState,
+            WHvX64RegisterRflags,
+            WHvX64RegisterDeliverabilityNotifications,
+        ])?;
+
+        let mut pending = unsafe { values[0].Reg64 };
+        let state = unsafe { values[1].Reg64 };
+        let rflags = unsafe { values[2].Reg64 };
+        let mut notifications = unsafe { values[3].Reg64 };
+
+        // Only a single interruption can be pending, which may already be taken by one that was
+        // set through `set_pending_events`.
+        let mut injecting = pending & 1 != 0;
+
+        if self.pending_nmi {
+            // Blocking by the interrupt shadow or by NMI.
+            if injecting || state & 0b11 != 0 {
+                notifications |= WHV_DELIVERABILITY_NMI_NOTIFICATION;
+            } else {
+                pending = 1 | (2 << 1) | (2 << 16);
+                self.pending_nmi = false;
+                injecting = true;
+            }
+        }
+
+        if let Some(&vector) = self.pending_interrupts.front() {
+            if !injecting && rflags & RFLAGS_IF != 0 && state & 1 == 0 {
+                pending = 1 | ((vector as u64) << 16);
+                self.pending_interrupts.pop_front();
+            }
+
+            if !self.pending_interrupts.is_empty() {
+                notifications |= WHV_DELIVERABILITY_INTERRUPT_NOTIFICATION;
+            }
+        }
+
+        self.set_raw_registers(
+            &[WHvRegisterPendingInterruption, WHvX64RegisterDeliverabilityNotifications],
+            &[WHV_REGISTER_VALUE { Reg64: pending }, WHV_REGISTER_VALUE { Reg64: notifications }],
+        )
+    }
+
+    pub fn complete_cpuid(&mut self, result: &CpuidResult) -> Result<(), Error> {
+        let rip = match self.pending_cpuid.take() {
+            Some(rip) => rip,
+            _ => return Err(Error::InvalidArgument),
+        };
+
+        self.set_raw_registers(
+            &[
+                WHvX64RegisterRax,
+                WHvX64RegisterRbx,
+                WHvX64RegisterRcx,
+                WHvX64RegisterRdx,
+                WHvX64RegisterRip,
+            ],
+            &[
+                WHV_REGISTER_VALUE { Reg64: result.eax as u64 },
+                WHV_REGISTER_VALUE { Reg64: result.ebx as u64 },
+                WHV_REGISTER_VALUE { Reg64: result.ecx as u64 },
+                WHV_REGISTER_VALUE { Reg64: result.edx as u64 },
+                WHV_REGISTER_VALUE { Reg64: rip },
+            ],
+        )
+    }
+
+    pub fn set_cpuid(&mut self, entries: &[CpuidEntry]) -> Result<(), Error> {
+        // The CPUID result list does not distinguish subleaves, so only the first subleaf can be
+        // overridden.
+        let results = entries.iter().map(|entry| {
+            if entry.indexed && entry.subleaf != 0 {
+                return Err(Error::InvalidArgument);
+            }
+
+            Ok(WHV_X64_CPUID_RESULT {
+                Function: entry.leaf,
+                Reserved: [0; 3],
+                Eax: entry.result.eax,
+                Ebx: entry.result.ebx,
+                Ecx: entry.result.ecx,
+                Edx: entry.result.edx,
+            })
+        }).collect::<Result<Vec<_>, Error>>()?;
+
+        // The CPUID result list is a property of the partition, so this affects all the virtual
+        // CPUs.
+        unsafe {
+            WHvSetPartitionProperty(
+                self.handle.deref().0,
+                WHvPartitionPropertyCodeCpuidResultList,
+                results.as_ptr() as *const std::ffi::c_void,
+                (results.len() * std::mem::size_of::<WHV_X64_CPUID_RESULT>()) as u32,
+            )
+        }?;
+
+        Ok(())
+    }
+
+    pub fn complete_msr_read(&mut self, value: u64) -> Result<(), Error> {
+        if !std::mem::take(&mut self.pending_msr_read) {
+            return Err(Error::InvalidArgument);
+        }
+
+        self.set_raw_registers(
+            &[WHvX64RegisterRax, WHvX64RegisterRdx],
+            &[
+                WHV_REGISTER_VALUE { Reg64: value & 0xffff_ffff },
+                WHV_REGISTER_VALUE { Reg64: value >> 32 },
+            ],
+        )
+    }
+
+    pub fn set_guest_debug(&mut self, debug: &GuestDebug) -> Result<(), Error> {
+        // Set up the debug registers for the hardware breakpoints.
+        let mut addresses = [0u64; MAX_HW_BREAKPOINTS];
+
+        for (index, breakpoint) in debug.hw_breakpoints.iter().enumerate() {
+            addresses[index] = breakpoint.address;
+        }
+
+        let dr7 = encode_dr7(&debug.hw_breakpoints)?;
+
+        // Single-stepping is implemented through the trap flag.
+        let values = self.get_raw_registers(&[WHvX64RegisterRflags])?;
+        let mut rflags = unsafe { values[0].Reg64 };
+
+        if debug.single_step {
+            rflags |= RFLAGS_TF;
+        } else {
+            rflags &= !RFLAGS_TF;
+        }
+
+        let registers = [
+            WHvX64RegisterDr0,
+            WHvX64RegisterDr1,
+            WHvX64RegisterDr2,
+            WHvX64RegisterDr3,
+            WHvX64RegisterDr7,
+            WHvX64RegisterRflags,
+        ];
+
+        let values = [
+            WHV_REGISTER_VALUE { Reg64: addresses[0] },
+            WHV_REGISTER_VALUE { Reg64: addresses[1] },
+            WHV_REGISTER_VALUE { Reg64: addresses[2] },
+            WHV_REGISTER_VALUE { Reg64: addresses[3] },
+            WHV_REGISTER_VALUE { Reg64: dr7 },
+            WHV_REGISTER_VALUE { Reg64: rflags },
+        ];
+
+        self.set_raw_registers(&registers, &values)?;
+
+        // Select the exceptions that should exit.
+        self.debug_exceptions = 0;
+
+        if debug.single_step || !debug.hw_breakpoints.is_empty() {
+            self.debug_exceptions |= 1 << 1;
+        }
+
+        if debug.sw_breakpoints {
+            self.debug_exceptions |= 1 << 3;
+        }
+
+        self.update_exception_bitmap()
+    }
+
+    pub fn set_exception_exits(&mut self, vectors: u32) -> Result<(), Error> {
+        self.exception_exits = vectors;
+
+        self.update_exception_bitmap()
+    }
+
+    /// Helper function to select the exceptions that exit for guest debugging or as
+    /// [`ExitReason::Exception`]. Note that the exception exit bitmap applies to the whole
+    /// partition rather than just this virtual CPU.
+    fn update_exception_bitmap(&self) -> Result<(), Error> {
+        let property = WHV_PARTITION_PROPERTY {
+            ExceptionExitBitmap: (self.debug_exceptions | self.exception_exits) as u64,
+        };
+
+        unsafe {
+            WHvSetPartitionProperty(
+                self.handle.deref().0,
+                WHvPartitionPropertyCodeExceptionExitBitmap,
+                &property as *const WHV_PARTITION_PROPERTY as *const std::ffi::c_void,
+                std::mem::size_of::<WHV_PARTITION_PROPERTY>() as u32,
+            )
+        }?;
+
+        Ok(())
+    }
+}
+
+/// Helper function to get the registers that hold the state of the x87 FPU and the SSE registers,
+/// in the order of the `fxsave` area.
+#[cfg(target_arch = "x86_64")]
+fn fpu_registers() -> Vec<WHV_REGISTER_NAME> {
+    let mut registers = vec![WHvX64RegisterFpControlStatus, WHvX64RegisterXmmControlStatus];
+
+    registers.extend((0..8).map(|index| WHV_REGISTER_NAME(WHvX64RegisterFpMmx0.0 + index)));
+    registers.extend((0..16).map(|index| WHV_REGISTER_NAME(WHvX64RegisterXmm0.0 + index)));
+
+    registers
+}
+
+/// Helper function to map the [`DebugRegister`] to the register of the Windows Hypervisor
+/// Platform.
+#[cfg(target_arch = "x86_64")]
+fn debug_register(register: DebugRegister) -> WHV_REGISTER_NAME {
+    match register {
+        DebugRegister::Dr0 => WHvX64RegisterDr0,
+        DebugRegister::Dr1 => WHvX64RegisterDr1,
+        DebugRegister::Dr2 => WHvX64RegisterDr2,
+        DebugRegister::Dr3 => WHvX64RegisterDr3,
+        DebugRegister::Dr6 => WHvX64RegisterDr6,
+        DebugRegister::Dr7 => WHvX64RegisterDr7,
+    }
+}
+
 #[cfg(target_arch = "x86_64")]
 impl CpuRegs for Vcpu {
     fn get_registers(
@@ -271,6 +1282,18 @@ impl CpuRegs for Vcpu {
                     WHvX64RegisterCstar,
                 crate::arch::x86_64::MSR_IA32_SYSCALL_MASK =>
                     WHvX64RegisterSfmask,
+                crate::arch::x86_64::HV_X64_MSR_SCONTROL =>
+                    WHvRegisterScontrol,
+                crate::arch::x86_64::HV_X64_MSR_SVERSION =>
+                    WHvRegisterSversion,
+                crate::arch::x86_64::HV_X64_MSR_SIEFP =>
+                    WHvRegisterSiefp,
+                crate::arch::x86_64::HV_X64_MSR_SIMP =>
+                    WHvRegisterSimp,
+                crate::arch::x86_64::HV_X64_MSR_EOM =>
+                    WHvRegisterEom,
+                msr @ HV_X64_MSR_SINT0..=HV_X64_MSR_SINT15 =>
+                    WHV_REGISTER_NAME(WHvRegisterSint0.0 + (msr - HV_X64_MSR_SINT0) as i32),
                 _ => {
                     indices.push(index);
                     continue;
@@ -332,6 +1355,18 @@ impl CpuRegs for Vcpu {
                     WHvX64RegisterCstar,
                 crate::arch::x86_64::MSR_IA32_SYSCALL_MASK =>
                     WHvX64RegisterSfmask,
+                crate::arch::x86_64::HV_X64_MSR_SCONTROL =>
+                    WHvRegisterScontrol,
+                crate::arch::x86_64::HV_X64_MSR_SVERSION =>
+                    WHvRegisterSversion,
+                crate::arch::x86_64::HV_X64_MSR_SIEFP =>
+                    WHvRegisterSiefp,
+                crate::arch::x86_64::HV_X64_MSR_SIMP =>
+                    WHvRegisterSimp,
+                crate::arch::x86_64::HV_X64_MSR_EOM =>
+                    WHvRegisterEom,
+                msr @ HV_X64_MSR_SINT0..=HV_X64_MSR_SINT15 =>
+                    WHV_REGISTER_NAME(WHvRegisterSint0.0 + (msr - HV_X64_MSR_SINT0) as i32),
                 _ => continue,
             };
 
@@ -540,4 +1575,196 @@ impl CpuRegs for Vcpu {
 
         Ok(())
     }
+
+    fn get_debug_registers(
+        &self,
+        registers: &[DebugRegister],
+    ) -> Result<Vec<u64>, Error> {
+        let registers: Vec<WHV_REGISTER_NAME> = registers
+            .iter()
+            .map(|register| debug_register(*register))
+            .collect();
+
+        let values = self.get_raw_registers(&registers)?;
+
+        Ok(values.into_iter().map(|value| unsafe { value.Reg64 }).collect())
+    }
+
+    fn set_debug_registers(
+        &mut self,
+        registers: &[DebugRegister],
+        values: &[u64],
+    ) -> Result<(), Error> {
+        if registers.len() != values.len() {
+            return Err(Error::InvalidArgument);
+        }
+
+        let registers: Vec<WHV_REGISTER_NAME> = registers
+            .iter()
+            .map(|register| debug_register(*register))
+            .collect();
+
+        let values: Vec<WHV_REGISTER_VALUE> = values
+            .iter()
+            .map(|value| WHV_REGISTER_VALUE { Reg64: *value })
+            .collect();
+
+        self.set_raw_registers(&registers, &values)
+    }
+
+    fn get_fpu(&self) -> Result<FpuState, Error> {
+        let values = self.get_raw_registers(&fpu_registers())?;
+        let values: Vec<[u8; 16]> = values
+            .into_iter()
+            .map(|value| unsafe { std::mem::transmute(value) })
+            .collect();
+
+        // The layout of the control and status registers matches the one of the `fxsave` area.
+        let mut bytes = [0u8; FpuState::FXSAVE_SIZE];
+
+        bytes[0..16].copy_from_slice(&values[0]);
+        bytes[16..32].copy_from_slice(&values[1]);
+
+        for (index, value) in values[2..].iter().enumerate() {
+            bytes[32 + index * 16..48 + index * 16].copy_from_slice(value);
+        }
+
+        Ok(FpuState::from_fxsave(&bytes))
+    }
+
+    fn set_fpu(&mut self, state: &FpuState) -> Result<(), Error> {
+        let mut bytes = [0u8; FpuState::FXSAVE_SIZE];
+
+        state.to_fxsave(&mut bytes);
+
+        // Preserve the MXCSR mask, which is not part of the state.
+        let value = self.get_raw_registers(&[WHvX64RegisterXmmControlStatus])?[0];
+        let value: [u8; 16] = unsafe { std::mem::transmute(value) };
+
+        bytes[28..32].copy_from_slice(&value[12..16]);
+
+        let values: Vec<WHV_REGISTER_VALUE> = bytes
+            .chunks_exact(16)
+            .map(|chunk| {
+                let mut value = [0u8; 16];
+                value.copy_from_slice(chunk);
+
+                unsafe { std::mem::transmute(value) }
+            })
+            .collect();
+
+        self.set_raw_registers(&fpu_registers(), &values)
+    }
+}
+
+#[cfg(target_arch = "aarch64")]
+use crate::arch::aarch64::{CpuRegs, Register};
+#[cfg(target_arch = "aarch64")]
+use crate::debug::GuestDebug;
+
+#[cfg(target_arch = "aarch64")]
+impl Vcpu {
+    pub fn run(
+        &mut self,
+        _policy: ExitPolicy,
+        _hypercalls: &Hypercalls,
+    ) -> Result<ExitReason, Error> {
+        let mut context = WHV_RUN_VP_EXIT_CONTEXT_ARM64::default();
+
+        let exit_reason = loop {
+            // A kick while the virtual CPU is running results in a canceled exit, which lands here.
+            if self.kicked.swap(false, Ordering::SeqCst) || self.immediate_exit {
+                break ExitReason::Canceled;
+            }
+
+            unsafe {
+                WHvRunVirtualProcessor(
+                    self.handle.deref().0,
+                    self.id,
+                    &mut context as *mut WHV_RUN_VP_EXIT_CONTEXT_ARM64 as *mut std::ffi::c_void,
+                    std::mem::size_of::<WHV_RUN_VP_EXIT_CONTEXT_ARM64>() as u32,
+                )
+            }?;
+
+            break match context.ExitReason {
+                WHvRunVpExitReasonArm64Canceled =>
+                    continue,
+                WHvRunVpExitReasonArm64UnmappedGpa | WHvRunVpExitReasonArm64GpaIntercept => {
+                    let info = unsafe { context.Anonymous.MemoryAccess };
+
+                    ExitReason::InvalidMemoryAccess {
+                        gpa: info.Gpa,
+                        gva: info.Gva as usize,
+                    }
+                }
+                WHvRunVpExitReasonArm64UnrecoverableException =>
+                    ExitReason::UnhandledException,
+                _ => ExitReason::Unknown,
+            };
+        };
+
+        Ok(exit_reason)
+    }
+
+    pub fn set_guest_debug(&mut self, _debug: &GuestDebug) -> Result<(), Error> {
+        Err(Error::NotImplemented)
+    }
+
+    pub fn complete_io(&mut self, _data: &[u8]) -> Result<(), Error> {
+        Err(Error::NotImplemented)
+    }
+
+    pub fn complete_mmio_read(&mut self, _data: &[u8]) -> Result<(), Error> {
+        Err(Error::NotImplemented)
+    }
+}
+
+/// Helper function to map the [`Register`] to the corresponding [`WHV_REGISTER_NAME`].
+#[cfg(target_arch = "aarch64")]
+fn register_name(register: Register) -> WHV_REGISTER_NAME {
+    match register {
+        Register::Sp     => WHvArm64RegisterSp,
+        Register::Pc     => WHvArm64RegisterPc,
+        Register::Pstate => WHvArm64RegisterCpsr,
+        // X0 to X30 are numbered consecutively.
+        register => WHV_REGISTER_NAME(WHvArm64RegisterX0.0 + register as i32),
+    }
+}
+
+#[cfg(target_arch = "aarch64")]
+impl CpuRegs for Vcpu {
+    fn get_registers(
+        &self,
+        registers: &[Register],
+    ) -> Result<Vec<u64>, Error> {
+        let names: Vec<WHV_REGISTER_NAME> = registers
+            .iter()
+            .map(|register| register_name(*register))
+            .collect();
+
+        let values = self.get_raw_registers(&names)?;
+
+        Ok(values
+            .into_iter()
+            .map(|value| unsafe { value.Reg64 })
+            .collect())
+    }
+
+    fn set_registers(
+        &mut self,
+        registers: &[Register],
+        values: &[u64],
+    ) -> Result<(), Error> {
+        let names: Vec<WHV_REGISTER_NAME> = registers
+            .iter()
+            .map(|register| register_name(*register))
+            .collect();
+
+        let values: Vec<WHV_REGISTER_VALUE> = values
+            .iter()
+            .map(|value| WHV_REGISTER_VALUE { Reg64: *value })
+            .collect();
+
+        self.set_raw_registers(&names, &values)
+    }
 }
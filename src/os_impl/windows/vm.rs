@@ -1,13 +1,60 @@
+#[cfg(target_arch = "aarch64")]
+use crate::arch::aarch64::GicConfig;
+#[cfg(target_arch = "x86_64")]
+use crate::arch::x86_64::{CrExits, MsrPolicy};
+#[cfg(target_arch = "x86_64")]
+use crate::confidential::{LaunchMeasurement, SevPolicy};
 use crate::error::Error;
-use crate::vm::ProtectionFlags;
+#[cfg(target_arch = "x86_64")]
+use crate::pit::PitReinjection;
+#[cfg(target_arch = "x86_64")]
+use crate::synic::{SyntheticMessage, EVENT_FLAG_COUNT, SINT_COUNT};
+use crate::tsc::{TscMode, VirtualTsc};
+use crate::vm::{
+    check_overlap, DirtyBitmap, MemoryBackingKind, MissingPageHandler, ProtectionFlags,
+};
+#[cfg(feature = "xen")]
+use crate::xen::XenConfig;
 use mmap_rs::{MmapMut, MmapOptions};
 use rangemap::RangeMap;
 use std::collections::HashMap;
-use std::ops::Deref;
+use std::fs::{File, OpenOptions};
+use std::ops::{Deref, Range};
+use std::os::windows::fs::OpenOptionsExt;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::Arc;
 use super::bindings::*;
 use super::vcpu::Vcpu;
 
+/// Keeps the file in memory where possible rather than writing it to disk.
+const FILE_ATTRIBUTE_TEMPORARY: u32 = 0x100;
+/// Deletes the file once the last handle to it has been closed.
+const FILE_FLAG_DELETE_ON_CLOSE: u32 = 0x0400_0000;
+
+/// Creates a temporary file of the given size to back shared memory, which is deleted once the
+/// last handle to it has been closed.
+pub fn create_shared_memory(size: usize) -> Result<File, Error> {
+    static COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+    let path = std::env::temp_dir().join(format!(
+        "hy-rs-{}-{}.shm",
+        std::process::id(),
+        COUNTER.fetch_add(1, Ordering::Relaxed),
+    ));
+
+    let file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create_new(true)
+        .attributes(FILE_ATTRIBUTE_TEMPORARY)
+        .custom_flags(FILE_FLAG_DELETE_ON_CLOSE)
+        .open(path)?;
+
+    file.set_len(size as u64)?;
+
+    Ok(file)
+}
+
 pub struct PartitionHandle(pub WHV_PARTITION_HANDLE);
 
 impl Drop for PartitionHandle {
@@ -17,8 +64,30 @@ impl Drop for PartitionHandle {
         };
     }
 }
+/// Exits on `cpuid`.
+pub const EXTENDED_VM_EXIT_X64_CPUID: u64 = 1 << 0;
+/// Exits on `rdmsr` and `wrmsr`.
+pub const EXTENDED_VM_EXIT_X64_MSR:   u64 = 1 << 1;
+/// Exits on exceptions selected by the exception exit bitmap.
+pub const EXTENDED_VM_EXIT_EXCEPTION: u64 = 1 << 2;
+/// Exits on `rdtsc` and `rdtscp`.
+pub const EXTENDED_VM_EXIT_X64_RDTSC: u64 = 1 << 3;
+/// Exits on hypercalls.
+pub const EXTENDED_VM_EXIT_HYPERCALL: u64 = 1 << 5;
+
+/// The synthetic processor features exposed to the guest when the SynIC is enabled, i.e.
+/// HypervisorPresent, Hv1, AccessVpRunTimeReg, AccessPartitionReferenceCounter, AccessSynicRegs,
+/// AccessSyntheticTimerRegs, AccessIntrCtrlRegs, AccessHypercallRegs, AccessVpIndex and
+/// AccessPartitionReferenceTsc.
+const SYNTHETIC_PROCESSOR_FEATURES_SYNIC: u64 = 0x3ff;
+
 pub struct VmBuilder {
     pub(crate) handle: PartitionHandle,
+    pub(crate) extended_vm_exits: u64,
+    pub(crate) tsc_mode: TscMode,
+    pub(crate) synthetic_interrupts: bool,
+    /// Whether the local APIC is emulated by the hypervisor.
+    pub(crate) in_kernel_irqchip: bool,
 }
 
 impl VmBuilder {
@@ -39,7 +108,202 @@ impl VmBuilder {
         Ok(self)
     }
 
+    #[cfg(target_arch = "x86_64")]
+    pub fn with_tsc_mode(mut self, mode: TscMode) -> Result<Self, Error> {
+        match mode {
+            TscMode::Native =>
+                self.extended_vm_exits &= !EXTENDED_VM_EXIT_X64_RDTSC,
+            _ =>
+                self.extended_vm_exits |= EXTENDED_VM_EXIT_X64_RDTSC,
+        }
+
+        self.tsc_mode = mode;
+
+        Ok(self)
+    }
+
+    pub fn with_protected_guest(self, enabled: bool) -> Result<Self, Error> {
+        if enabled {
+            return Err(Error::NotImplemented);
+        }
+
+        Ok(self)
+    }
+
+    #[cfg(target_arch = "aarch64")]
+    pub fn with_tsc_mode(self, mode: TscMode) -> Result<Self, Error> {
+        match mode {
+            TscMode::Native => Ok(self),
+            _ => Err(Error::NotImplemented),
+        }
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    pub fn with_synthetic_interrupts(mut self, enabled: bool) -> Result<Self, Error> {
+        self.synthetic_interrupts = enabled;
+
+        Ok(self)
+    }
+
+    #[cfg(target_arch = "aarch64")]
+    pub fn with_synthetic_interrupts(self, enabled: bool) -> Result<Self, Error> {
+        if enabled {
+            return Err(Error::NotImplemented);
+        }
+
+        Ok(self)
+    }
+
+    pub fn with_la57(self, _enabled: bool) -> Result<Self, Error> {
+        Err(Error::NotImplemented)
+    }
+
+    pub fn with_hlt_exiting(self, enabled: bool) -> Result<Self, Error> {
+        // The Windows Hypervisor Platform always exits on `hlt`.
+        if !enabled {
+            return Err(Error::NotImplemented);
+        }
+
+        Ok(self)
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    pub fn with_cpuid_exits(mut self, enabled: bool) -> Result<Self, Error> {
+        if enabled {
+            self.extended_vm_exits |= EXTENDED_VM_EXIT_X64_CPUID;
+        } else {
+            self.extended_vm_exits &= !EXTENDED_VM_EXIT_X64_CPUID;
+        }
+
+        Ok(self)
+    }
+
+    #[cfg(target_arch = "aarch64")]
+    pub fn with_cpuid_exits(self, enabled: bool) -> Result<Self, Error> {
+        if enabled {
+            return Err(Error::NotImplemented);
+        }
+
+        Ok(self)
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    pub fn with_msr_exits(mut self, enabled: bool) -> Result<Self, Error> {
+        // The hypervisor only exits on the MSRs it does not handle itself.
+        if enabled {
+            self.extended_vm_exits |= EXTENDED_VM_EXIT_X64_MSR;
+        } else {
+            self.extended_vm_exits &= !EXTENDED_VM_EXIT_X64_MSR;
+        }
+
+        Ok(self)
+    }
+
+    #[cfg(target_arch = "aarch64")]
+    pub fn with_msr_exits(self, enabled: bool) -> Result<Self, Error> {
+        if enabled {
+            return Err(Error::NotImplemented);
+        }
+
+        Ok(self)
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    pub fn with_in_kernel_irqchip(mut self, enabled: bool) -> Result<Self, Error> {
+        self.in_kernel_irqchip = enabled;
+
+        Ok(self)
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    pub fn with_in_kernel_pit(self, _reinjection: PitReinjection) -> Result<Self, Error> {
+        // The hypervisor does not emulate the PIT.
+        Err(Error::NotImplemented)
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    pub fn with_sev(self, _policy: SevPolicy) -> Result<Self, Error> {
+        Err(Error::NotImplemented)
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    pub fn with_cr_exits(self, exits: CrExits) -> Result<Self, Error> {
+        // The Windows Hypervisor Platform does not provide exits for control register accesses.
+        if !exits.is_empty() {
+            return Err(Error::NotImplemented);
+        }
+
+        Ok(self)
+    }
+
+    #[cfg(target_arch = "aarch64")]
+    pub fn with_gic(self, _config: GicConfig) -> Result<Self, Error> {
+        // The in-kernel GIC of the WinHV API is not exposed yet.
+        Err(Error::NotImplemented)
+    }
+
+    #[cfg(feature = "xen")]
+    pub fn with_xen(self, _config: XenConfig) -> Result<Self, Error> {
+        Err(Error::NotImplemented)
+    }
+
     pub fn build(self, _name: &str) -> Result<Vm, Error> {
+        if self.extended_vm_exits != 0 {
+            let property = WHV_PARTITION_PROPERTY {
+                ExtendedVmExits: WHV_EXTENDED_VM_EXITS {
+                    AsUINT64: self.extended_vm_exits,
+                },
+            };
+
+            unsafe {
+                WHvSetPartitionProperty(
+                    self.handle.0,
+                    WHvPartitionPropertyCodeExtendedVmExits,
+                    &property as *const WHV_PARTITION_PROPERTY as *const std::ffi::c_void,
+                    std::mem::size_of::<WHV_PARTITION_PROPERTY>() as u32,
+                )
+            }?;
+        }
+
+        if self.synthetic_interrupts {
+            let property = WHV_PARTITION_PROPERTY {
+                SyntheticProcessorFeaturesBanks: WHV_SYNTHETIC_PROCESSOR_FEATURES_BANKS {
+                    BanksCount: 1,
+                    Reserved0: 0,
+                    Anonymous: WHV_SYNTHETIC_PROCESSOR_FEATURES_BANKS_0 {
+                        AsUINT64: [SYNTHETIC_PROCESSOR_FEATURES_SYNIC],
+                    },
+                },
+            };
+
+            unsafe {
+                WHvSetPartitionProperty(
+                    self.handle.0,
+                    WHvPartitionPropertyCodeSyntheticProcessorFeaturesBanks,
+                    &property as *const WHV_PARTITION_PROPERTY as *const std::ffi::c_void,
+                    std::mem::size_of::<WHV_PARTITION_PROPERTY>() as u32,
+                )
+            }?;
+        }
+
+        // The SynIC extends the local APIC, so the local APIC must be emulated by the hypervisor.
+        let apic_emulation = self.synthetic_interrupts || self.in_kernel_irqchip;
+
+        if apic_emulation {
+            let property = WHV_PARTITION_PROPERTY {
+                LocalApicEmulationMode: WHvX64LocalApicEmulationModeXApic,
+            };
+
+            unsafe {
+                WHvSetPartitionProperty(
+                    self.handle.0,
+                    WHvPartitionPropertyCodeLocalApicEmulationMode,
+                    &property as *const WHV_PARTITION_PROPERTY as *const std::ffi::c_void,
+                    std::mem::size_of::<WHV_PARTITION_PROPERTY>() as u32,
+                )
+            }?;
+        }
+
         unsafe {
             WHvSetupPartition(self.handle.0)
         }?;
@@ -48,14 +312,58 @@ impl VmBuilder {
             handle: Arc::new(self.handle),
             segments: HashMap::new(),
             physical_ranges: RangeMap::new(),
+            tsc_mode: self.tsc_mode,
+            apic_emulation,
+            dirty_tracking: false,
         })
     }
 }
 
+pub struct Segment {
+    mapping: MmapMut,
+    protection: ProtectionFlags,
+    backing: MemoryBackingKind,
+    /// The protection flags of the sub-ranges that differ from the protection flags of the
+    /// region.
+    protections: RangeMap<u64, ProtectionFlags>,
+}
+
 pub struct Vm {
     pub(crate) handle: Arc<PartitionHandle>,
-    pub(crate) segments: HashMap<u64, MmapMut>,
+    pub(crate) segments: HashMap<u64, Segment>,
     pub(crate) physical_ranges: RangeMap<u64, u64>,
+    pub(crate) tsc_mode: TscMode,
+    /// Whether the local APIC is emulated by the hypervisor, which is the case when the SynIC or
+    /// the in-kernel irqchip is enabled.
+    pub(crate) apic_emulation: bool,
+    /// Whether the hypervisor tracks the pages that the guest writes to.
+    pub(crate) dirty_tracking: bool,
+}
+
+/// The size of the pages tracked by `WHvQueryGpaRangeDirtyBitmap`.
+const DIRTY_PAGE_SIZE: u64 = 4096;
+
+/// Helper function to convert the protection flags into the flags to map a GPA range.
+fn map_flags(protection: ProtectionFlags, dirty_tracking: bool) -> WHV_MAP_GPA_RANGE_FLAGS {
+    let mut flags = WHvMapGpaRangeFlagNone;
+
+    if protection.contains(ProtectionFlags::READ) {
+        flags |= WHvMapGpaRangeFlagRead;
+    }
+
+    if protection.is_guest_writable() {
+        flags |= WHvMapGpaRangeFlagWrite;
+    }
+
+    if protection.contains(ProtectionFlags::EXECUTE) {
+        flags |= WHvMapGpaRangeFlagExecute;
+    }
+
+    if dirty_tracking {
+        flags |= WHvMapGpaRangeFlagTrackDirtyPages;
+    }
+
+    flags
 }
 
 impl Vm {
@@ -71,6 +379,35 @@ impl Vm {
         Ok(Vcpu {
             handle: self.handle.clone(),
             id: id as u32,
+            tsc: VirtualTsc::new(self.tsc_mode),
+            kicked: Default::default(),
+            immediate_exit: false,
+            #[cfg(target_arch = "x86_64")]
+            io_data: [0; 4],
+            #[cfg(target_arch = "x86_64")]
+            pending_io_in: None,
+            #[cfg(target_arch = "x86_64")]
+            mmio_data: [0; 8],
+            #[cfg(target_arch = "x86_64")]
+            mmio_size: 0,
+            #[cfg(target_arch = "x86_64")]
+            pending_mmio_read: None,
+            #[cfg(target_arch = "x86_64")]
+            pending_cpuid: None,
+            #[cfg(target_arch = "x86_64")]
+            pending_msr_read: false,
+            #[cfg(target_arch = "x86_64")]
+            debug_exceptions: 0,
+            #[cfg(target_arch = "x86_64")]
+            exception_exits: 0,
+            #[cfg(target_arch = "x86_64")]
+            apic_emulation: self.apic_emulation,
+            #[cfg(target_arch = "x86_64")]
+            pending_interrupts: Default::default(),
+            #[cfg(target_arch = "x86_64")]
+            pending_nmi: false,
+            #[cfg(target_arch = "x86_64")]
+            interrupt_window_requested: false,
         })
     }
 
@@ -87,6 +424,7 @@ impl Vm {
             guest_address,
             mapping,
             protection,
+            MemoryBackingKind::Anonymous,
         )?;
 
         Ok(())
@@ -97,23 +435,13 @@ impl Vm {
         guest_address: u64,
         mut mapping: MmapMut,
         protection: ProtectionFlags,
+        backing: MemoryBackingKind,
     ) -> Result<(), Error> {
-        let mut flags = WHvMapGpaRangeFlagNone;
-
-        if protection.contains(ProtectionFlags::READ) {
-            flags |= WHvMapGpaRangeFlagRead;
-        }
-
-        if protection.contains(ProtectionFlags::WRITE) {
-            flags |= WHvMapGpaRangeFlagWrite;
-        }
-
-        if protection.contains(ProtectionFlags::EXECUTE) {
-            flags |= WHvMapGpaRangeFlagExecute;
-        }
-
+        let flags = map_flags(protection, self.dirty_tracking);
         let size = mapping.len() as u64;
 
+        check_overlap(&self.physical_ranges, guest_address..guest_address + size)?;
+
         unsafe {
             WHvMapGpaRange(
                 self.handle.deref().0,
@@ -124,16 +452,59 @@ impl Vm {
             )
         }?;
 
-        self.segments.insert(guest_address, mapping);
+        let segment = Segment {
+            mapping,
+            protection,
+            backing,
+            protections: RangeMap::new(),
+        };
+
+        self.segments.insert(guest_address, segment);
         self.physical_ranges.insert(guest_address..guest_address + size, guest_address);
 
         Ok(())
     }
 
+    pub fn regions(
+        &self,
+    ) -> Result<Vec<(Range<u64>, ProtectionFlags, MemoryBackingKind)>, Error> {
+        let regions = self.physical_ranges
+            .iter()
+            .filter_map(|(range, start)| {
+                let segment = self.segments.get(start)?;
+
+                Some((range.clone(), segment.protection, segment.backing))
+            })
+            .collect();
+
+        Ok(regions)
+    }
+
+    /// Returns the protection flags of the region containing the given guest address.
+    pub fn protection(&self, guest_address: u64) -> Option<ProtectionFlags> {
+        let start = self.physical_ranges.get(&guest_address)?;
+
+        self.segments
+            .get(start)
+            .map(|segment| segment.protection)
+    }
+
     pub fn unmap_physical_memory(
         &mut self,
         guest_address: u64,
     ) -> Result<(), Error> {
+        self.take_physical_memory(guest_address)?;
+
+        Ok(())
+    }
+
+    /// Unmaps the region at the given guest physical address like
+    /// [`Vm::unmap_physical_memory`], but hands back its mapping, protection and backing, such
+    /// that the region can be mapped again.
+    pub fn take_physical_memory(
+        &mut self,
+        guest_address: u64,
+    ) -> Result<(MmapMut, ProtectionFlags, MemoryBackingKind), Error> {
         // Look up the base guest address.
         let range = match self.physical_ranges.get_key_value(&guest_address) {
             Some((range, _)) => range.clone(),
@@ -142,7 +513,7 @@ impl Vm {
 
         // Look up the segment size.
         let size = match self.segments.get(&range.start) {
-            Some(segment) => segment.len() as u64,
+            Some(segment) => segment.mapping.len() as u64,
             _ => return Err(Error::InvalidGuestAddress),
         };
 
@@ -155,12 +526,71 @@ impl Vm {
         }?;
 
         // Remove the physical address range and segment.
-        self.segments.remove(&range.start);
+        let segment = self.segments
+            .remove(&range.start)
+            .ok_or(Error::InvalidGuestAddress)?;
         self.physical_ranges.remove(range);
 
+        Ok((segment.mapping, segment.protection, segment.backing))
+    }
+
+    pub fn remap_physical_memory(
+        &mut self,
+        guest_address: u64,
+        new_guest_address: u64,
+    ) -> Result<(), Error> {
+        // Look up the base guest address.
+        let range = match self.physical_ranges.get_key_value(&guest_address) {
+            Some((range, _)) => range.clone(),
+            _ => return Err(Error::InvalidGuestAddress),
+        };
+
+        // Look up the segment.
+        let mut segment = match self.segments.remove(&range.start) {
+            Some(segment) => segment,
+            _ => return Err(Error::InvalidGuestAddress),
+        };
+
+        let size = segment.mapping.len() as u64;
+
+        unsafe {
+            WHvUnmapGpaRange(
+                self.handle.deref().0,
+                range.start,
+                size,
+            )
+        }?;
+
+        unsafe {
+            WHvMapGpaRange(
+                self.handle.deref().0,
+                segment.mapping.as_mut_ptr() as *mut std::ffi::c_void,
+                new_guest_address,
+                size,
+                map_flags(segment.protection, self.dirty_tracking),
+            )
+        }?;
+
+        // The sub-ranges have been mapped with the protection flags of the region.
+        segment.protections = RangeMap::new();
+
+        self.physical_ranges.remove(range);
+        self.physical_ranges.insert(new_guest_address..new_guest_address + size, new_guest_address);
+        self.segments.insert(new_guest_address, segment);
+
         Ok(())
     }
 
+    pub fn resize_physical_memory(
+        &mut self,
+        _guest_address: u64,
+        _size: usize,
+        _backing_file: Option<(File, u64)>,
+    ) -> Result<(), Error> {
+        // The GPA ranges of the segments always cover their mappings as a whole.
+        Err(Error::NotImplemented)
+    }
+
     pub fn protect_physical_memory(
         &mut self,
         guest_address: u64,
@@ -173,26 +603,58 @@ impl Vm {
         };
 
         // Look up the segment size.
-        let mapping = match self.segments.get_mut(&range.start) {
+        let segment = match self.segments.get_mut(&range.start) {
             Some(segment) => segment,
             _ => return Err(Error::InvalidGuestAddress),
         };
-        let size = mapping.len() as u64;
+        let size = segment.mapping.len() as u64;
+        let flags = map_flags(protection, self.dirty_tracking);
 
-        let mut flags = WHvMapGpaRangeFlagNone;
+        unsafe {
+            WHvUnmapGpaRange(
+                self.handle.deref().0,
+                range.start,
+                size,
+            )
+        }?;
 
-        if protection.contains(ProtectionFlags::READ) {
-            flags |= WHvMapGpaRangeFlagRead;
-        }
+        unsafe {
+            WHvMapGpaRange(
+                self.handle.deref().0,
+                segment.mapping.as_mut_ptr() as *mut std::ffi::c_void,
+                range.start,
+                size,
+                flags,
+            )
+        }?;
 
-        if protection.contains(ProtectionFlags::WRITE) {
-            flags |= WHvMapGpaRangeFlagWrite;
-        }
+        segment.protection = protection;
+        segment.protections = RangeMap::new();
 
-        if protection.contains(ProtectionFlags::EXECUTE) {
-            flags |= WHvMapGpaRangeFlagExecute;
-        }
+        Ok(())
+    }
+
+    pub fn protect_physical_memory_range(
+        &mut self,
+        range: Range<u64>,
+        protection: ProtectionFlags,
+    ) -> Result<(), Error> {
+        // Look up the base guest address.
+        let region = match self.physical_ranges.get_key_value(&range.start) {
+            Some((region, _)) if range.end <= region.end => region.clone(),
+            _ => return Err(Error::InvalidGuestAddress),
+        };
+
+        // Look up the segment.
+        let segment = match self.segments.get_mut(&region.start) {
+            Some(segment) => segment,
+            _ => return Err(Error::InvalidGuestAddress),
+        };
 
+        let offset = (range.start - region.start) as usize;
+        let size = range.end - range.start;
+
+        // The GPA ranges can be unmapped and mapped at page granularity.
         unsafe {
             WHvUnmapGpaRange(
                 self.handle.deref().0,
@@ -204,16 +666,112 @@ impl Vm {
         unsafe {
             WHvMapGpaRange(
                 self.handle.deref().0,
-                mapping.as_mut_ptr() as *mut std::ffi::c_void,
+                segment.mapping.as_mut_ptr().add(offset) as *mut std::ffi::c_void,
                 range.start,
                 size,
-                flags,
+                map_flags(protection, self.dirty_tracking),
             )
         }?;
 
+        if protection == segment.protection {
+            segment.protections.remove(range);
+        } else {
+            segment.protections.insert(range, protection);
+        }
+
+        Ok(())
+    }
+
+    pub fn set_dirty_tracking(&mut self, enabled: bool) -> Result<(), Error> {
+        if enabled == self.dirty_tracking {
+            return Ok(());
+        }
+
+        // Dirty tracking can only be specified upon mapping a GPA range, so remap the segments.
+        for (&guest_address, segment) in self.segments.iter_mut() {
+            let size = segment.mapping.len() as u64;
+
+            unsafe {
+                WHvUnmapGpaRange(
+                    self.handle.deref().0,
+                    guest_address,
+                    size,
+                )
+            }?;
+
+            unsafe {
+                WHvMapGpaRange(
+                    self.handle.deref().0,
+                    segment.mapping.as_mut_ptr() as *mut std::ffi::c_void,
+                    guest_address,
+                    size,
+                    map_flags(segment.protection, enabled),
+                )
+            }?;
+
+            // Map the sub-ranges with their own protection flags again.
+            for (range, &protection) in segment.protections.iter() {
+                let offset = (range.start - guest_address) as usize;
+                let size = range.end - range.start;
+
+                unsafe {
+                    WHvUnmapGpaRange(
+                        self.handle.deref().0,
+                        range.start,
+                        size,
+                    )
+                }?;
+
+                unsafe {
+                    WHvMapGpaRange(
+                        self.handle.deref().0,
+                        segment.mapping.as_mut_ptr().add(offset) as *mut std::ffi::c_void,
+                        range.start,
+                        size,
+                        map_flags(protection, enabled),
+                    )
+                }?;
+            }
+        }
+
+        self.dirty_tracking = enabled;
+
         Ok(())
     }
 
+    pub fn dirty_bitmap(&self, guest_address: u64) -> Result<DirtyBitmap, Error> {
+        if !self.dirty_tracking {
+            return Err(Error::InvalidArgument);
+        }
+
+        // Look up the base guest address.
+        let range = match self.physical_ranges.get_key_value(&guest_address) {
+            Some((range, _)) => range.clone(),
+            _ => return Err(Error::InvalidGuestAddress),
+        };
+
+        let size = range.end - range.start;
+        let pages = (size + DIRTY_PAGE_SIZE - 1) / DIRTY_PAGE_SIZE;
+        let mut bitmap = vec![0u64; ((pages + 63) / 64) as usize];
+
+        // The hypervisor clears the dirty bits upon querying them.
+        unsafe {
+            WHvQueryGpaRangeDirtyBitmap(
+                self.handle.deref().0,
+                range.start,
+                size,
+                bitmap.as_mut_ptr(),
+                (bitmap.len() * std::mem::size_of::<u64>()) as u32,
+            )
+        }?;
+
+        Ok(DirtyBitmap {
+            range,
+            page_size: DIRTY_PAGE_SIZE,
+            bitmap,
+        })
+    }
+
     pub fn read_physical_memory(
         &self,
         bytes: &mut [u8],
@@ -235,7 +793,7 @@ impl Vm {
         let offset = (guest_address - range.start) as usize;
         let size = ((range.end - guest_address) as usize).min(bytes.len());
 
-        bytes[..size].copy_from_slice(&segment[offset..offset + size]);
+        bytes[..size].copy_from_slice(&segment.mapping[offset..offset + size]);
 
         Ok(size)
     }
@@ -261,8 +819,234 @@ impl Vm {
         let offset = (guest_address - range.start) as usize;
         let size = ((range.end - guest_address) as usize).min(bytes.len());
 
-        segment[offset..offset + size].copy_from_slice(&bytes[..size]);
+        segment.mapping[offset..offset + size].copy_from_slice(&bytes[..size]);
 
         Ok(size)
     }
+
+    pub fn host_address(
+        &self,
+        guest_address: u64,
+        size: usize,
+    ) -> Result<*mut u8, Error> {
+        // Look up the base guest address.
+        let range = match self.physical_ranges.get_key_value(&guest_address) {
+            Some((range, _)) => range.clone(),
+            _ => return Err(Error::InvalidGuestAddress),
+        };
+
+        // Look up the segment.
+        let segment = match self.segments.get(&range.start) {
+            Some(segment) => segment,
+            _ => return Err(Error::InvalidGuestAddress),
+        };
+
+        // The access must not cross the end of the segment.
+        if guest_address + size as u64 > range.end {
+            return Err(Error::InvalidGuestAddress);
+        }
+
+        let offset = (guest_address - range.start) as usize;
+
+        Ok(unsafe { segment.mapping.as_ptr().add(offset) as *mut u8 })
+    }
+
+    pub fn missing_page_handler(&mut self, _handler: MissingPageHandler) -> Result<(), Error> {
+        Err(Error::NotImplemented)
+    }
+
+    pub fn reclaim(&self, range: Range<u64>) -> Result<(), Error> {
+        let size = (range.end - range.start) as usize;
+        let address = self.host_address(range.start, size)?;
+
+        // The pages are no longer written to the paging file and may be discarded by the host.
+        let result = unsafe {
+            VirtualAlloc(
+                address as *mut std::ffi::c_void,
+                size,
+                MEM_RESET,
+                PAGE_READWRITE,
+            )
+        };
+
+        if result.is_null() {
+            return Err(std::io::Error::last_os_error().into());
+        }
+
+        Ok(())
+    }
+
+    pub fn reinflate(&self, range: Range<u64>) -> Result<(), Error> {
+        let size = (range.end - range.start) as usize;
+        let address = self.host_address(range.start, size)?;
+
+        // Undoing the reset fails if the host discarded any of the pages in the meantime, in which
+        // case the host provides fresh pages as the guest touches them.
+        unsafe {
+            VirtualAlloc(
+                address as *mut std::ffi::c_void,
+                size,
+                MEM_RESET_UNDO,
+                PAGE_READWRITE,
+            )
+        };
+
+        Ok(())
+    }
+
+    pub fn resident_size(&self) -> Result<usize, Error> {
+        Err(Error::NotImplemented)
+    }
+
+    pub fn trim_resident_size(&self, _limit: usize) -> Result<usize, Error> {
+        Err(Error::NotImplemented)
+    }
+
+    pub fn allocate_smram(
+        &mut self,
+        _guest_address: u64,
+        _size: usize,
+    ) -> Result<(), Error> {
+        Err(Error::NotImplemented)
+    }
+
+    pub fn read_smram(
+        &self,
+        _bytes: &mut [u8],
+        _guest_address: u64,
+    ) -> Result<usize, Error> {
+        Err(Error::NotImplemented)
+    }
+
+    pub fn write_smram(
+        &mut self,
+        _guest_address: u64,
+        _bytes: &[u8],
+    ) -> Result<usize, Error> {
+        Err(Error::NotImplemented)
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    pub fn post_synthetic_message(
+        &self,
+        vcpu_id: usize,
+        sint: u8,
+        message: &SyntheticMessage,
+    ) -> Result<(), Error> {
+        if sint >= SINT_COUNT {
+            return Err(Error::InvalidArgument);
+        }
+
+        let bytes = match message.to_bytes() {
+            Some(bytes) => bytes,
+            _ => return Err(Error::InvalidArgument),
+        };
+
+        unsafe {
+            WHvPostVirtualProcessorSynicMessage(
+                self.handle.deref().0,
+                vcpu_id as u32,
+                sint as u32,
+                bytes.as_ptr() as *const std::ffi::c_void,
+                bytes.len() as u32,
+            )
+        }?;
+
+        Ok(())
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    pub fn signal_synthetic_event(
+        &self,
+        vcpu_id: usize,
+        sint: u8,
+        flag: u16,
+    ) -> Result<bool, Error> {
+        if sint >= SINT_COUNT || flag >= EVENT_FLAG_COUNT {
+            return Err(Error::InvalidArgument);
+        }
+
+        let parameters = WHV_SYNIC_EVENT_PARAMETERS {
+            VpIndex: vcpu_id as u32,
+            TargetSint: sint,
+            Reserved: 0,
+            FlagNumber: flag,
+        };
+
+        let mut newly_signaled = BOOL::default();
+
+        unsafe {
+            WHvSignalVirtualProcessorSynicEvent(
+                self.handle.deref().0,
+                parameters,
+                &mut newly_signaled,
+            )
+        }?;
+
+        Ok(newly_signaled.as_bool())
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    pub fn sev_launch_update_data(
+        &self,
+        _guest_address: u64,
+        _size: usize,
+    ) -> Result<(), Error> {
+        Err(Error::NotImplemented)
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    pub fn sev_launch_update_vmsa(&self) -> Result<(), Error> {
+        Err(Error::NotImplemented)
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    pub fn sev_launch_measure(&self) -> Result<LaunchMeasurement, Error> {
+        Err(Error::NotImplemented)
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    pub fn sev_launch_finish(&self) -> Result<(), Error> {
+        Err(Error::NotImplemented)
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    pub fn set_msr_filter(
+        &mut self,
+        _ranges: &[Range<u32>],
+        _policy: MsrPolicy,
+    ) -> Result<(), Error> {
+        // The hypervisor only exits on the MSRs it does not handle itself.
+        Err(Error::NotImplemented)
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    pub fn set_irq_line(&self, _gsi: u32, _level: bool) -> Result<(), Error> {
+        // The hypervisor does not emulate an I/O APIC or PIC.
+        Err(Error::NotImplemented)
+    }
+
+    #[cfg(target_arch = "aarch64")]
+    pub fn set_irq_level(&self, _intid: u32, _level: bool) -> Result<(), Error> {
+        Err(Error::NotImplemented)
+    }
+
+    #[cfg(feature = "xen")]
+    pub fn set_xen_shared_info(&mut self, _gfn: u64) -> Result<(), Error> {
+        Err(Error::NotImplemented)
+    }
+
+    #[cfg(feature = "xen")]
+    pub fn set_xen_upcall_vector(&mut self, _vector: u8) -> Result<(), Error> {
+        Err(Error::NotImplemented)
+    }
+
+    #[cfg(feature = "xen")]
+    pub fn send_xen_event(
+        &self,
+        _port: u32,
+        _vcpu_id: usize,
+    ) -> Result<(), Error> {
+        Err(Error::NotImplemented)
+    }
 }
@@ -0,0 +1,180 @@
+use crate::error::Error;
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, TcpListener, TcpStream};
+use std::os::windows::io::{AsRawSocket, FromRawSocket, IntoRawSocket, RawSocket};
+use std::sync::Once;
+use super::bindings::*;
+
+/// The size of `SOCKADDR_HV`.
+const SOCKADDR_HV_SIZE: i32 = std::mem::size_of::<SOCKADDR_HV>() as i32;
+
+/// Returns the error of the last socket function that failed.
+fn last_error() -> Error {
+    Error::Io(io::Error::from_raw_os_error(unsafe { WSAGetLastError() }))
+}
+
+/// Creates a Hyper-V socket, which is owned by the returned [`TcpStream`] such that it gets
+/// closed when dropped. The stream is only used to read, write, shut down and close the socket.
+fn create_socket() -> Result<TcpStream, Error> {
+    static STARTUP: Once = Once::new();
+
+    // Winsock has to be initialized before creating a socket, which std only does when it
+    // creates a socket itself. Any failure is reported by socket() below.
+    STARTUP.call_once(|| {
+        let mut data = [0u8; WSADATA_SIZE];
+
+        let _ = unsafe {
+            WSAStartup(0x0202, data.as_mut_ptr())
+        };
+    });
+
+    let socket = unsafe {
+        socket(AF_HYPERV, SOCK_STREAM, HV_PROTOCOL_RAW)
+    };
+
+    if socket == INVALID_SOCKET {
+        return Err(last_error());
+    }
+
+    Ok(unsafe {
+        TcpStream::from_raw_socket(socket as RawSocket)
+    })
+}
+
+/// Returns the address of the service with the given port on the VM with the given ID, where the
+/// service ID follows the template that maps the ports of AF_VSOCK to services, i.e.
+/// `xxxxxxxx-facb-11e6-bd58-64006a7986d3` with the port as the first field.
+fn address(vm_id: u128, port: u32) -> SOCKADDR_HV {
+    SOCKADDR_HV {
+        Family: AF_HYPERV as u16,
+        Reserved: 0,
+        VmId: HV_GUID {
+            Data1: (vm_id >> 96) as u32,
+            Data2: (vm_id >> 80) as u16,
+            Data3: (vm_id >> 64) as u16,
+            Data4: (vm_id as u64).to_be_bytes(),
+        },
+        ServiceId: HV_GUID {
+            Data1: port,
+            Data2: 0xfacb,
+            Data3: 0x11e6,
+            Data4: [0xbd, 0x58, 0x64, 0x00, 0x6a, 0x79, 0x86, 0xd3],
+        },
+    }
+}
+
+#[derive(Clone)]
+pub struct Endpoint {
+    vm_id: u128,
+}
+
+impl Endpoint {
+    pub fn new(vm_id: u128) -> Self {
+        Self {
+            vm_id,
+        }
+    }
+
+    pub fn listen(&self, port: u32) -> Result<Listener, Error> {
+        let socket = create_socket()?;
+        let handle = socket.as_raw_socket() as SOCKET;
+        let address = address(self.vm_id, port);
+
+        let result = unsafe {
+            bind(handle, &address, SOCKADDR_HV_SIZE)
+        };
+
+        if result == SOCKET_ERROR {
+            return Err(last_error());
+        }
+
+        let result = unsafe {
+            listen(handle, SOMAXCONN)
+        };
+
+        if result == SOCKET_ERROR {
+            return Err(last_error());
+        }
+
+        let listener = unsafe {
+            TcpListener::from_raw_socket(socket.into_raw_socket())
+        };
+
+        Ok(Listener {
+            listener,
+            port,
+        })
+    }
+
+    pub fn connect(&self, port: u32) -> Result<Stream, Error> {
+        let stream = create_socket()?;
+        let address = address(self.vm_id, port);
+
+        let result = unsafe {
+            connect(stream.as_raw_socket() as SOCKET, &address, SOCKADDR_HV_SIZE)
+        };
+
+        if result == SOCKET_ERROR {
+            return Err(last_error());
+        }
+
+        Ok(Stream {
+            stream,
+            port,
+        })
+    }
+}
+
+pub struct Listener {
+    /// The listening socket, which is only used to close the socket when dropped.
+    listener: TcpListener,
+    port: u32,
+}
+
+impl Listener {
+    pub fn port(&self) -> u32 {
+        self.port
+    }
+
+    pub fn accept(&self) -> Result<Stream, Error> {
+        // std cannot accept the connections itself, as it does not know the address family.
+        let mut address = SOCKADDR_HV::default();
+        let mut size = SOCKADDR_HV_SIZE;
+
+        let socket = unsafe {
+            accept(self.listener.as_raw_socket() as SOCKET, &mut address, &mut size)
+        };
+
+        if socket == INVALID_SOCKET {
+            return Err(last_error());
+        }
+
+        Ok(Stream {
+            stream: unsafe { TcpStream::from_raw_socket(socket as RawSocket) },
+            port: self.port,
+        })
+    }
+}
+
+pub struct Stream {
+    stream: TcpStream,
+    port: u32,
+}
+
+impl Stream {
+    pub fn port(&self) -> u32 {
+        self.port
+    }
+
+    pub fn read(&self, buf: &mut [u8]) -> Result<usize, Error> {
+        Ok((&self.stream).read(buf)?)
+    }
+
+    pub fn write(&self, buf: &[u8]) -> Result<usize, Error> {
+        Ok((&self.stream).write(buf)?)
+    }
+
+    pub fn shutdown(&self, how: Shutdown) -> Result<(), Error> {
+        Ok(self.stream.shutdown(how)?)
+    }
+}
@@ -0,0 +1,402 @@
+//! This module provides the configuration of the 8254 programmable interval timer (PIT) on x86,
+//! which guests rely on for early timekeeping, e.g. to calibrate the TSC or as the timer interrupt
+//! before the local APIC timer has been set up.
+//!
+//! On Linux, KVM can emulate the PIT in the kernel, see [`crate::VmBuilder::with_in_kernel_pit`],
+//! where [`PitReinjection`] selects how the ticks of channel 0 that the guest missed are handled.
+//! On FreeBSD, bhyve always emulates the PIT in the kernel. On the other platforms, [`Pit`]
+//! provides an emulation of the PIT in user space instead, which the VMM drives from the
+//! [`crate::ExitReason::IoIn`] and [`crate::ExitReason::IoOut`] exits of the PIT ports:
+//!
+//!  * Port I/O on the ports for which [`Pit::contains_port`] holds is passed to [`Pit::io_in`]
+//!    and [`Pit::io_out`].
+//!  * The VMM arms a timer for [`Pit::next_deadline`] and calls [`Pit::poll`] once it expires,
+//!    which returns whether to raise IRQ 0, e.g. through [`crate::Vm::set_irq_line`] or the
+//!    interrupt controller emulated by the VMM.
+//!
+//! Channels 0 and 1 are always enabled, while channel 2 is gated through bit 0 of port 0x61, of
+//! which bit 5 reflects the output of channel 2. Lowering the gate of channel 2 stops the counter
+//! until the gate is raised again. The BCD mode is not supported, and mode 3 counts down like mode
+//! 2 rather than by two.
+
+use std::time::{Duration, Instant};
+
+/// The frequency of the input clock of the PIT in Hz.
+pub const PIT_FREQUENCY: u64 = 1_193_182;
+/// The I/O port of the counter of channel 0, followed by those of channels 1 and 2.
+pub const PIT_PORT_CHANNEL0: u16 = 0x40;
+/// The I/O port of the mode/command register.
+pub const PIT_PORT_COMMAND: u16 = 0x43;
+/// The I/O port of the NMI status and control register, which gates channel 2.
+pub const PIT_PORT_SPEAKER: u16 = 0x61;
+
+/// The bit of port 0x61 that gates channel 2.
+const SPEAKER_GATE: u8 = 1 << 0;
+/// The bit of port 0x61 that enables the speaker.
+const SPEAKER_DATA: u8 = 1 << 1;
+/// The bit of port 0x61 that toggles on every read, i.e. the refresh clock.
+const SPEAKER_REFRESH: u8 = 1 << 4;
+/// The bit of port 0x61 that reflects the output of channel 2.
+const SPEAKER_OUTPUT: u8 = 1 << 5;
+
+/// Describes how the ticks of channel 0 of the PIT that the guest missed are handled, e.g. when
+/// the VMM has been descheduled for longer than the period of the timer.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Deserialize, serde::Serialize))]
+pub enum PitReinjection {
+    /// The missed ticks are delivered one after the other, such that guests that keep time by
+    /// counting the ticks do not fall behind.
+    Reinject,
+    /// The missed ticks are discarded, such that the guest receives at most one pending tick.
+    Discard,
+}
+
+impl Default for PitReinjection {
+    fn default() -> Self {
+        Self::Reinject
+    }
+}
+
+/// The state of a single channel of the PIT.
+#[derive(Clone, Debug, Default)]
+struct Channel {
+    /// The reload value, where zero means 65536.
+    reload: u16,
+    /// The operating mode, i.e. 0 to 5.
+    mode: u8,
+    /// The access mode, i.e. 1 for the low byte, 2 for the high byte, or 3 for the low byte
+    /// followed by the high byte.
+    access: u8,
+    /// Whether the channel uses BCD, which is only reflected in the status.
+    bcd: bool,
+    /// Whether the reload value has been written since the mode was set.
+    loaded: bool,
+    /// The time at which the counter started counting, or `None` if it is not counting.
+    start: Option<Instant>,
+    /// The latched count, if any.
+    count_latch: Option<u16>,
+    /// The latched status, if any.
+    status_latch: Option<u8>,
+    /// Whether the next read accesses the high byte in access mode 3.
+    read_high: bool,
+    /// The low byte that has been written in access mode 3, if any.
+    write_low: Option<u8>,
+}
+
+impl Channel {
+    /// Returns the period of the counter in ticks of the input clock.
+    fn period(&self) -> u64 {
+        match self.reload {
+            0 => 0x10000,
+            reload => reload as u64,
+        }
+    }
+
+    /// Returns the number of ticks of the input clock since the counter started counting.
+    fn ticks(&self, now: Instant) -> Option<u64> {
+        self.start.map(|start| {
+            let elapsed = now.saturating_duration_since(start).as_nanos();
+
+            (elapsed * PIT_FREQUENCY as u128 / 1_000_000_000) as u64
+        })
+    }
+
+    /// Returns the current value of the counter.
+    fn count(&self, now: Instant) -> u16 {
+        let period = self.period();
+
+        match (self.ticks(now), self.mode) {
+            (None, _) => period as u16,
+            (Some(ticks), 2) | (Some(ticks), 3) => (period - ticks % period) as u16,
+            // The counter keeps counting down after the terminal count in the one-shot modes.
+            (Some(ticks), _) => period.wrapping_sub(ticks) as u16,
+        }
+    }
+
+    /// Returns the state of the output of the channel.
+    fn output(&self, now: Instant) -> bool {
+        let period = self.period();
+
+        match (self.ticks(now), self.mode) {
+            // Setting mode 0 lowers the output, while the other modes raise it.
+            (None, mode) => mode != 0,
+            (Some(ticks), 0) | (Some(ticks), 1) => ticks >= period,
+            (Some(ticks), 2) => ticks % period != period - 1,
+            (Some(ticks), 3) => ticks % period < (period + 1) / 2,
+            (Some(ticks), _) => ticks != period,
+        }
+    }
+
+    /// Returns the number of times the output has signalled an interrupt since the counter
+    /// started counting.
+    fn interrupts(&self, now: Instant) -> u64 {
+        let period = self.period();
+
+        match (self.ticks(now), self.mode) {
+            (None, _) => 0,
+            (Some(ticks), 2) | (Some(ticks), 3) => ticks / period,
+            (Some(ticks), _) => (ticks >= period) as u64,
+        }
+    }
+
+    /// Returns the status byte as returned by the read-back command.
+    fn status(&self, now: Instant) -> u8 {
+        ((self.output(now) as u8) << 7) |
+            ((!self.loaded as u8) << 6) |
+            (self.access << 4) |
+            (self.mode << 1) |
+            self.bcd as u8
+    }
+
+    /// Latches the current value of the counter, unless a value has been latched already.
+    fn latch_count(&mut self, now: Instant) {
+        if self.count_latch.is_none() {
+            self.count_latch = Some(self.count(now));
+            self.read_high = false;
+        }
+    }
+
+    /// Latches the status, unless the status has been latched already.
+    fn latch_status(&mut self, now: Instant) {
+        if self.status_latch.is_none() {
+            self.status_latch = Some(self.status(now));
+        }
+    }
+
+    /// Reads a byte from the counter port of the channel.
+    fn read(&mut self, now: Instant) -> u8 {
+        if let Some(status) = self.status_latch.take() {
+            return status;
+        }
+
+        let (count, latched) = match self.count_latch {
+            Some(count) => (count, true),
+            _ => (self.count(now), false),
+        };
+
+        let (byte, done) = match self.access {
+            1 => (count as u8, true),
+            2 => ((count >> 8) as u8, true),
+            _ if self.read_high => ((count >> 8) as u8, true),
+            _ => (count as u8, false),
+        };
+
+        self.read_high = !done;
+
+        if latched && done {
+            self.count_latch = None;
+        }
+
+        byte
+    }
+
+    /// Writes a byte to the counter port of the channel, which starts the counter once the
+    /// reload value has been written in full and the gate is high.
+    fn write(&mut self, value: u8, gate: bool, now: Instant) {
+        let reload = match (self.access, self.write_low.take()) {
+            (1, _) => value as u16,
+            (2, _) => (value as u16) << 8,
+            (_, Some(low)) => ((value as u16) << 8) | low as u16,
+            (_, None) => {
+                self.write_low = Some(value);
+                return;
+            }
+        };
+
+        self.reload = reload;
+        self.loaded = true;
+        self.start = match gate {
+            true => Some(now),
+            _ => None,
+        };
+    }
+
+    /// Sets the access mode, the operating mode and the BCD mode of the channel, which stops the
+    /// counter until the reload value has been written.
+    fn set_mode(&mut self, command: u8) {
+        *self = Self {
+            access: (command >> 4) & 0x3,
+            // Modes 6 and 7 are aliases of modes 2 and 3.
+            mode: match (command >> 1) & 0x7 {
+                mode @ 6 | mode @ 7 => mode - 4,
+                mode => mode,
+            },
+            bcd: command & 1 != 0,
+            ..Default::default()
+        };
+    }
+}
+
+/// An emulation of the 8254 programmable interval timer in user space, for the platforms where
+/// the hypervisor does not emulate the PIT in the kernel. See the [module-level
+/// documentation](self) for how to drive it.
+#[derive(Clone, Debug)]
+pub struct Pit {
+    /// The three channels.
+    channels: [Channel; 3],
+    /// How the missed ticks of channel 0 are handled.
+    reinjection: PitReinjection,
+    /// The number of interrupts of channel 0 that have been delivered since the counter started
+    /// counting.
+    delivered: u64,
+    /// The value of port 0x61.
+    speaker: u8,
+}
+
+impl Pit {
+    /// Creates the PIT with the given policy for the missed ticks of channel 0. None of the
+    /// channels count until the guest programs them.
+    pub fn new(reinjection: PitReinjection) -> Self {
+        Self {
+            channels: Default::default(),
+            reinjection,
+            delivered: 0,
+            speaker: 0,
+        }
+    }
+
+    /// Returns whether the given I/O port belongs to the PIT, i.e. ports 0x40 to 0x43 and 0x61.
+    pub fn contains_port(port: u16) -> bool {
+        (PIT_PORT_CHANNEL0..=PIT_PORT_COMMAND).contains(&port) || port == PIT_PORT_SPEAKER
+    }
+
+    /// Handles an `in` instruction on the given port by filling the given data, where accesses
+    /// wider than a byte read the same byte repeatedly. Ports that do not belong to the PIT and
+    /// the write-only command port read as all ones.
+    pub fn io_in(&mut self, port: u16, data: &mut [u8]) {
+        let now = Instant::now();
+
+        let value = match port {
+            PIT_PORT_COMMAND => 0xff,
+            PIT_PORT_SPEAKER => {
+                self.speaker ^= SPEAKER_REFRESH;
+
+                let output = match self.channels[2].output(now) {
+                    true => SPEAKER_OUTPUT,
+                    _ => 0,
+                };
+
+                self.speaker | output
+            }
+            port if Self::contains_port(port) => {
+                self.channels[(port - PIT_PORT_CHANNEL0) as usize].read(now)
+            }
+            _ => 0xff,
+        };
+
+        for byte in data {
+            *byte = value;
+        }
+    }
+
+    /// Handles an `out` instruction on the given port with the given data, where only the first
+    /// byte is used. Writes to ports that do not belong to the PIT are ignored.
+    pub fn io_out(&mut self, port: u16, data: &[u8]) {
+        let now = Instant::now();
+        let value = match data.first() {
+            Some(&value) => value,
+            _ => return,
+        };
+
+        match port {
+            PIT_PORT_COMMAND => self.command(value, now),
+            PIT_PORT_SPEAKER => {
+                let channel = &mut self.channels[2];
+                let gate = value & SPEAKER_GATE != 0;
+
+                // Raising the gate restarts the counter, while lowering the gate stops it.
+                match (self.speaker & SPEAKER_GATE != 0, gate) {
+                    (false, true) if channel.loaded => channel.start = Some(now),
+                    (true, false) => channel.start = None,
+                    _ => (),
+                }
+
+                self.speaker = (self.speaker & SPEAKER_REFRESH) |
+                    (value & (SPEAKER_GATE | SPEAKER_DATA));
+            }
+            port if Self::contains_port(port) => {
+                let index = (port - PIT_PORT_CHANNEL0) as usize;
+                let gate = index != 2 || self.speaker & SPEAKER_GATE != 0;
+
+                self.channels[index].write(value, gate, now);
+
+                if index == 0 {
+                    self.delivered = 0;
+                }
+            }
+            _ => (),
+        }
+    }
+
+    /// Handles a write to the mode/command register.
+    fn command(&mut self, value: u8, now: Instant) {
+        let index = (value >> 6) as usize;
+
+        // The read-back command selects the channels through bits 1 to 3, where a clear bit 5
+        // latches the count and a clear bit 4 latches the status.
+        if index == 3 {
+            for (i, channel) in self.channels.iter_mut().enumerate() {
+                if value & (1 << (i + 1)) == 0 {
+                    continue;
+                }
+
+                if value & (1 << 5) == 0 {
+                    channel.latch_count(now);
+                }
+
+                if value & (1 << 4) == 0 {
+                    channel.latch_status(now);
+                }
+            }
+
+            return;
+        }
+
+        match (value >> 4) & 0x3 {
+            0 => self.channels[index].latch_count(now),
+            _ => {
+                self.channels[index].set_mode(value);
+
+                if index == 0 {
+                    self.delivered = 0;
+                }
+            }
+        }
+    }
+
+    /// Returns whether channel 0 has signalled an interrupt that has yet to be delivered, in which
+    /// case the VMM should raise IRQ 0. With [`PitReinjection::Reinject`], every missed tick is
+    /// reported by a call of its own, while [`PitReinjection::Discard`] reports the missed ticks
+    /// at most once.
+    pub fn poll(&mut self) -> bool {
+        let interrupts = self.channels[0].interrupts(Instant::now());
+
+        if interrupts <= self.delivered {
+            return false;
+        }
+
+        self.delivered = match self.reinjection {
+            PitReinjection::Reinject => self.delivered + 1,
+            PitReinjection::Discard => interrupts,
+        };
+
+        true
+    }
+
+    /// Returns the time at which channel 0 signals its next interrupt, or `None` if channel 0 is
+    /// not counting or has already signalled the interrupt of a one-shot mode. The time is in the
+    /// past if there are missed ticks that have yet to be delivered.
+    pub fn next_deadline(&self) -> Option<Instant> {
+        let channel = &self.channels[0];
+        let start = channel.start?;
+
+        if channel.mode != 2 && channel.mode != 3 && self.delivered > 0 {
+            return None;
+        }
+
+        let ticks = (self.delivered + 1) as u128 * channel.period() as u128;
+        let nanos = (ticks * 1_000_000_000 + PIT_FREQUENCY as u128 - 1) / PIT_FREQUENCY as u128;
+
+        Some(start + Duration::from_nanos(nanos as u64))
+    }
+}
@@ -0,0 +1,275 @@
+//! This module provides [`VcpuRunner`], which runs a set of virtual CPUs on threads of their own
+//! and delivers their exits to an [`ExitHandler`]. The virtual CPUs are created on the threads
+//! that run them, as required by the Hypervisor Framework on Mac OS X, and can be paused, resumed
+//! and stopped as a whole.
+//!
+//! Pausing and stopping the virtual CPUs relies on [`VcpuHandle::kick`], which is not supported on
+//! FreeBSD.
+
+use crate::error::Error;
+use crate::vcpu::{ExitReason, Vcpu, VcpuFactory, VcpuHandle, VcpuSpec};
+use std::sync::mpsc;
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread::JoinHandle;
+
+/// The action to take after handling an exit.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ExitAction {
+    /// Resume the virtual CPU.
+    Continue,
+    /// Stop the virtual CPU, while the other virtual CPUs keep running.
+    Stop,
+    /// Stop all the virtual CPUs, e.g. when the guest shuts down.
+    StopAll,
+}
+
+/// Handles the exits of a virtual CPU run by a [`VcpuRunner`]. Every virtual CPU has a handler of
+/// its own, which is called on the thread running the virtual CPU.
+pub trait ExitHandler: Send {
+    /// Called once the virtual CPU has been created and before it runs for the first time, e.g.
+    /// to set up the initial registers.
+    fn start(&mut self, _vcpu: &mut Vcpu) -> Result<(), Error> {
+        Ok(())
+    }
+
+    /// Handles the given exit and returns whether to resume the virtual CPU. The
+    /// [`ExitReason::Canceled`] exits caused by pausing or stopping the [`VcpuRunner`] are not
+    /// delivered.
+    fn handle_exit(&mut self, exit: ExitReason) -> Result<ExitAction, Error>;
+}
+
+/// The command for the threads running the virtual CPUs.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum Command {
+    Run,
+    Pause,
+    Stop,
+}
+
+/// The state shared between the [`VcpuRunner`] and its threads.
+struct State {
+    /// The current command.
+    command: Command,
+    /// The number of threads that are still running their virtual CPU.
+    active: usize,
+    /// The number of threads that are parked because of [`Command::Pause`].
+    parked: usize,
+    /// The handles to kick the virtual CPUs.
+    handles: Vec<VcpuHandle>,
+}
+
+struct Shared {
+    state: Mutex<State>,
+    condvar: Condvar,
+}
+
+impl Shared {
+    /// Issues the given command to the threads, where the virtual CPUs are kicked to pause or
+    /// stop them promptly. Stopping is final, so any command after that is ignored.
+    fn signal(&self, command: Command) -> Result<(), Error> {
+        let mut state = self.state.lock().unwrap();
+
+        if state.command == Command::Stop {
+            return Ok(());
+        }
+
+        state.command = command;
+        self.condvar.notify_all();
+
+        if command == Command::Run {
+            return Ok(());
+        }
+
+        for handle in &state.handles {
+            handle.kick()?;
+        }
+
+        Ok(())
+    }
+
+    /// Runs the virtual CPU until it is stopped, parking the thread while paused.
+    fn run<H: ExitHandler>(&self, vcpu: &mut Vcpu, handler: &mut H) -> Result<(), Error> {
+        loop {
+            let mut state = self.state.lock().unwrap();
+
+            if state.command == Command::Pause {
+                state.parked += 1;
+                self.condvar.notify_all();
+
+                while state.command == Command::Pause {
+                    state = self.condvar.wait(state).unwrap();
+                }
+
+                state.parked -= 1;
+
+                // Discard the kick that paused the virtual CPU, as the thread may have parked
+                // before the virtual CPU observed it.
+                vcpu.handle().inner.clear_kick();
+            }
+
+            if state.command == Command::Stop {
+                return Ok(());
+            }
+
+            drop(state);
+
+            let exit_reason = vcpu.run()?;
+
+            // Pausing or stopping kicks the virtual CPU, which is handled at the top of the loop.
+            if let ExitReason::Canceled = exit_reason {
+                if self.state.lock().unwrap().command != Command::Run {
+                    continue;
+                }
+            }
+
+            match handler.handle_exit(exit_reason)? {
+                ExitAction::Continue => (),
+                ExitAction::Stop => return Ok(()),
+                ExitAction::StopAll => {
+                    self.signal(Command::Stop)?;
+                    return Ok(());
+                }
+            }
+        }
+    }
+}
+
+/// The `VcpuRunner` runs a set of virtual CPUs on threads of their own, see the
+/// [module-level documentation](self). Dropping the `VcpuRunner` stops the virtual CPUs.
+pub struct VcpuRunner {
+    shared: Arc<Shared>,
+    threads: Vec<JoinHandle<Result<(), Error>>>,
+}
+
+impl VcpuRunner {
+    /// Spawns a thread for every [`VcpuFactory`], which creates the virtual CPU and runs it. The
+    /// handler of every virtual CPU is obtained by calling `make_handler` with the description of
+    /// the virtual CPU. Returns the error of the first virtual CPU that could not be created, in
+    /// which case the other virtual CPUs are stopped.
+    pub fn spawn<F, H>(factories: Vec<VcpuFactory>, mut make_handler: F) -> Result<Self, Error>
+    where
+        F: FnMut(&VcpuSpec) -> H,
+        H: ExitHandler + 'static,
+    {
+        // Keep the virtual CPUs parked until all of them have been created.
+        let shared = Arc::new(Shared {
+            state: Mutex::new(State {
+                command: Command::Pause,
+                active: factories.len(),
+                parked: 0,
+                handles: vec![],
+            }),
+            condvar: Condvar::new(),
+        });
+
+        let mut runner = Self {
+            shared: shared.clone(),
+            threads: vec![],
+        };
+
+        let mut created = vec![];
+
+        for factory in factories {
+            let (sender, receiver) = mpsc::channel();
+            let mut handler = make_handler(factory.spec());
+            let shared = shared.clone();
+
+            let thread = std::thread::Builder::new()
+                .name(format!("hy-rs-vcpu-{}", factory.spec().id))
+                .spawn(move || {
+                    let result = match factory.create() {
+                        Ok(mut vcpu) => {
+                            let _ = sender.send(Ok(vcpu.handle()));
+
+                            match handler.start(&mut vcpu) {
+                                Ok(()) => shared.run(&mut vcpu, &mut handler),
+                                Err(e) => Err(e),
+                            }
+                        }
+                        Err(e) => {
+                            let _ = sender.send(Err(e));
+                            Ok(())
+                        }
+                    };
+
+                    // The thread no longer has to park for the virtual CPUs to be paused.
+                    shared.state.lock().unwrap().active -= 1;
+                    shared.condvar.notify_all();
+
+                    result
+                })?;
+
+            runner.threads.push(thread);
+            created.push(receiver);
+        }
+
+        let mut handles = vec![];
+
+        for receiver in created {
+            handles.push(receiver.recv().map_err(|_| Error::VcpuThreadTerminated)??);
+        }
+
+        shared.state.lock().unwrap().handles = handles;
+        shared.signal(Command::Run)?;
+
+        Ok(runner)
+    }
+
+    /// Returns the handles of the virtual CPUs, in the order of the [`VcpuFactory`]s.
+    pub fn handles(&self) -> Vec<VcpuHandle> {
+        self.shared.state.lock().unwrap().handles.clone()
+    }
+
+    /// Pauses the virtual CPUs. This waits until every virtual CPU has exited and its thread is
+    /// parked, such that the guest does not execute until [`VcpuRunner::resume`] is called.
+    pub fn pause(&self) -> Result<(), Error> {
+        self.shared.signal(Command::Pause)?;
+
+        let mut state = self.shared.state.lock().unwrap();
+
+        while state.command == Command::Pause && state.parked < state.active {
+            state = self.shared.condvar.wait(state).unwrap();
+        }
+
+        Ok(())
+    }
+
+    /// Resumes the virtual CPUs after [`VcpuRunner::pause`].
+    pub fn resume(&self) -> Result<(), Error> {
+        self.shared.signal(Command::Run)
+    }
+
+    /// Stops the virtual CPUs and waits for their threads to terminate, see
+    /// [`VcpuRunner::wait`].
+    pub fn stop(&mut self) -> Result<(), Error> {
+        let result = self.shared.signal(Command::Stop);
+
+        self.wait()?;
+
+        result
+    }
+
+    /// Waits for the threads of the virtual CPUs to terminate, i.e. until every handler returned
+    /// [`ExitAction::Stop`], one of them returned [`ExitAction::StopAll`] or the
+    /// [`VcpuRunner`] is stopped. Returns the first error that any of the virtual CPUs failed
+    /// with.
+    pub fn wait(&mut self) -> Result<(), Error> {
+        let mut result = Ok(());
+
+        for thread in self.threads.drain(..) {
+            let outcome = thread.join().unwrap_or(Err(Error::VcpuThreadTerminated));
+
+            if result.is_ok() {
+                result = outcome;
+            }
+        }
+
+        result
+    }
+}
+
+impl Drop for VcpuRunner {
+    fn drop(&mut self) {
+        let _ = self.stop();
+    }
+}
@@ -0,0 +1,214 @@
+//! This module provides the [`Pattern`] struct, which describes a sequence of bytes with
+//! wildcards to search the guest physical memory for through [`crate::Vm::scan`], e.g. to locate
+//! kernel structures or code signatures for memory forensics and introspection.
+//!
+//! Patterns can be built from:
+//!  * A sequence of bytes that must all match, see [`Pattern::from_bytes`].
+//!  * A sequence of bytes and wildcards, see [`Pattern::new`].
+//!  * A string of hexadecimal bytes separated by whitespace, where `?` or `??` matches any byte,
+//!    e.g. `"48 8b ?? ?? 00 00"`, see [`Pattern::from_str`].
+
+use crate::error::Error;
+use crate::volatile::VolatileSlice;
+use std::str::FromStr;
+
+/// The size of the chunks that the guest physical memory is copied out in while searching it.
+const SCAN_CHUNK_SIZE: usize = 64 << 10;
+
+/// A sequence of bytes, where `None` matches any byte.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Pattern {
+    /// The bytes of the pattern, where `None` is a wildcard.
+    bytes: Vec<Option<u8>>,
+}
+
+impl Pattern {
+    /// Creates a pattern from the given bytes, where `None` matches any byte.
+    pub fn new(bytes: &[Option<u8>]) -> Self {
+        Self {
+            bytes: bytes.to_vec(),
+        }
+    }
+
+    /// Creates a pattern that matches the given bytes exactly.
+    pub fn from_bytes(bytes: &[u8]) -> Self {
+        Self {
+            bytes: bytes.iter().copied().map(Some).collect(),
+        }
+    }
+
+    /// Returns the length of the pattern in bytes.
+    pub fn len(&self) -> usize {
+        self.bytes.len()
+    }
+
+    /// Returns `true` if the pattern is empty.
+    pub fn is_empty(&self) -> bool {
+        self.bytes.is_empty()
+    }
+
+    /// Returns `true` if the pattern matches the start of the given bytes.
+    pub fn matches(&self, bytes: &[u8]) -> bool {
+        bytes.len() >= self.bytes.len() &&
+            self.bytes
+                .iter()
+                .zip(bytes)
+                .all(|(pattern, byte)| pattern.map_or(true, |pattern| pattern == *byte))
+    }
+
+    /// Returns an iterator over the offsets of the matches of the pattern in the given bytes in
+    /// ascending order, where matches may overlap.
+    pub(crate) fn find_iter<'a>(&'a self, bytes: &'a [u8]) -> impl Iterator<Item = usize> + 'a {
+        // Look for the first byte that is not a wildcard to skip ahead quickly.
+        let anchor = self.bytes.iter().position(|byte| byte.is_some());
+        let end = (bytes.len() + 1).saturating_sub(self.bytes.len());
+        let mut offset = 0;
+
+        std::iter::from_fn(move || {
+            while offset < end {
+                let start = match anchor {
+                    Some(anchor) => {
+                        let needle = self.bytes[anchor];
+
+                        bytes[offset + anchor..end + anchor]
+                            .iter()
+                            .position(|&byte| Some(byte) == needle)? + offset
+                    }
+                    _ => offset,
+                };
+
+                offset = start + 1;
+
+                if self.matches(&bytes[start..]) {
+                    return Some(start);
+                }
+            }
+
+            None
+        })
+    }
+
+    /// Returns the offsets of the matches of the pattern in the given slice in ascending order,
+    /// where matches may overlap. As the guest may modify its memory concurrently, the slice is
+    /// never exposed as a Rust slice, but copied out in bounded chunks through volatile reads
+    /// instead.
+    pub(crate) fn find_in_slice(&self, slice: &VolatileSlice) -> Vec<usize> {
+        self.find_in_chunks(slice, SCAN_CHUNK_SIZE)
+    }
+
+    /// Helper function to search the given slice in chunks of the given size, where every chunk
+    /// is extended by the length of the pattern minus one byte to find the matches that start
+    /// within the chunk, but end in the next chunk.
+    fn find_in_chunks(&self, slice: &VolatileSlice, chunk_size: usize) -> Vec<usize> {
+        let overlap = self.bytes.len().saturating_sub(1);
+        let mut buffer = vec![0u8; chunk_size + overlap];
+        let mut matches = vec![];
+        let mut offset = 0;
+
+        while offset < slice.len() {
+            let size = (slice.len() - offset).min(buffer.len());
+
+            // The offset lies within the slice, so this cannot fail.
+            let size = slice.copy_to(offset, &mut buffer[..size]).unwrap_or(0);
+
+            matches.extend(
+                self.find_iter(&buffer[..size])
+                    .take_while(|&start| start < chunk_size)
+                    .map(|start| offset + start),
+            );
+
+            offset += chunk_size;
+        }
+
+        matches
+    }
+}
+
+impl FromStr for Pattern {
+    type Err = Error;
+
+    /// Parses a string of hexadecimal bytes separated by whitespace, where `?` or `??` matches
+    /// any byte. Returns [`Error::InvalidArgument`] if the string contains anything else.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let bytes = s
+            .split_whitespace()
+            .map(|byte| match byte {
+                "?" | "??" => Ok(None),
+                _ if byte.len() == 2 && byte.chars().all(|c| c.is_ascii_hexdigit()) => {
+                    u8::from_str_radix(byte, 16)
+                        .map(Some)
+                        .map_err(|_| Error::InvalidArgument)
+                }
+                _ => Err(Error::InvalidArgument),
+            })
+            .collect::<Result<_, _>>()?;
+
+        Ok(Self {
+            bytes,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Helper function to search the given bytes through a volatile slice in chunks of the given
+    /// size.
+    fn find_in_chunks(pattern: &Pattern, bytes: &mut [u8], chunk_size: usize) -> Vec<usize> {
+        let slice = unsafe { VolatileSlice::new(bytes.as_mut_ptr(), bytes.len(), None) };
+
+        pattern.find_in_chunks(&slice, chunk_size)
+    }
+
+    #[test]
+    fn parse() {
+        let pattern: Pattern = "48 8b ?? ? 00".parse().unwrap();
+
+        assert_eq!(pattern, Pattern::new(&[Some(0x48), Some(0x8b), None, None, Some(0x00)]));
+    }
+
+    #[test]
+    fn parse_invalid() {
+        for s in ["4", "488b", "zz", "?x", "???", "+1", "48 -1"] {
+            assert!(matches!(s.parse::<Pattern>(), Err(Error::InvalidArgument)), "{}", s);
+        }
+
+        assert!("".parse::<Pattern>().unwrap().is_empty());
+    }
+
+    #[test]
+    fn overlapping_matches() {
+        let pattern = Pattern::from_bytes(b"aa");
+
+        assert_eq!(pattern.find_iter(b"aaaa").collect::<Vec<_>>(), [0, 1, 2]);
+        assert_eq!(pattern.find_iter(b"a").count(), 0);
+        assert_eq!(pattern.find_iter(b"").count(), 0);
+    }
+
+    #[test]
+    fn wildcards() {
+        let pattern: Pattern = "?? 02 ?? 04".parse().unwrap();
+
+        assert_eq!(pattern.find_iter(&[1, 2, 3, 4, 2, 9, 4]).collect::<Vec<_>>(), [0, 3]);
+
+        let pattern: Pattern = "?? ??".parse().unwrap();
+
+        assert_eq!(pattern.find_iter(&[1, 2, 3]).collect::<Vec<_>>(), [0, 1]);
+    }
+
+    #[test]
+    fn across_chunks() {
+        let mut bytes: Vec<u8> = (0..100).collect();
+        bytes[30..34].copy_from_slice(b"find");
+        bytes[62..66].copy_from_slice(b"find");
+        bytes[96..].copy_from_slice(b"find");
+
+        let pattern = Pattern::from_bytes(b"find");
+
+        // The matches straddle the ends of the chunks, but are only reported once.
+        for chunk_size in [1, 2, 3, 32, 64, 100, 200] {
+            assert_eq!(find_in_chunks(&pattern, &mut bytes, chunk_size), [30, 62, 96]);
+        }
+    }
+}
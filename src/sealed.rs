@@ -0,0 +1,338 @@
+//! This module provides the transports that seal and open encrypted snapshots, i.e. any state of a
+//! VM that the embedder persists or moves between machines, such as its guest physical memory,
+//! which may hold secrets of the guest. [`SealingWriter`] encrypts and authenticates the bytes
+//! written to it under a key supplied by the embedder, while [`OpeningReader`] only returns the
+//! bytes once they have been authenticated, such that any tampering is detected on load. Both wrap
+//! any [`Write`] or [`Read`] transport, e.g. a file. The snapshots of whole VMs are sealed and
+//! opened through [`crate::snapshot::save_encrypted`] and [`crate::snapshot::restore_encrypted`].
+//!
+//! The stream is split into chunks of [`SEALED_CHUNK_SIZE`] bytes that are encrypted and
+//! authenticated with XChaCha20-Poly1305 using the STREAM construction, i.e. the nonce of every
+//! chunk consists of the random nonce prefix from the header, a 32-bit counter and a flag that
+//! marks the last chunk. As a result, reordering, dropping or truncating chunks is detected. The
+//! header is authenticated as the associated data of every chunk.
+
+use chacha20poly1305::aead::rand_core::RngCore;
+use chacha20poly1305::aead::stream::{DecryptorBE32, EncryptorBE32};
+use chacha20poly1305::aead::{OsRng, Payload};
+use chacha20poly1305::{Key, XChaCha20Poly1305};
+use crate::error::Error;
+use std::io::{self, Read, Write};
+
+/// The magic that identifies an encrypted snapshot.
+pub const SEALED_MAGIC: [u8; 8] = *b"HYRSSEAL";
+/// The version of the encrypted snapshot format.
+pub const SEALED_VERSION: u32 = 1;
+/// The size of the chunks of plaintext that are sealed at once.
+pub const SEALED_CHUNK_SIZE: usize = 64 << 10;
+/// The size of the authentication tag that is appended to every sealed chunk.
+const TAG_SIZE: usize = 16;
+/// The size of the nonce prefix, i.e. the 24-byte nonce minus the 32-bit counter and the flag.
+const NONCE_PREFIX_SIZE: usize = 19;
+/// The size of the header, i.e. the magic, the version and the nonce prefix.
+const HEADER_SIZE: usize = 8 + 4 + NONCE_PREFIX_SIZE;
+
+/// Seals the bytes written to it and writes the sealed chunks to the underlying transport. The
+/// last chunk is only sealed by [`SealingWriter::finish`].
+pub struct SealingWriter<'a, W: Write> {
+    file: &'a mut W,
+    encryptor: Option<EncryptorBE32<XChaCha20Poly1305>>,
+    header: Vec<u8>,
+    buffer: Vec<u8>,
+}
+
+impl<'a, W: Write> SealingWriter<'a, W> {
+    /// Writes the header with a random nonce prefix to the given transport and returns the
+    /// writer to seal the stream with the given key.
+    pub fn new(file: &'a mut W, key: &[u8; 32]) -> Result<Self, Error> {
+        let mut prefix = [0u8; NONCE_PREFIX_SIZE];
+        OsRng.fill_bytes(&mut prefix);
+
+        let mut header = Vec::with_capacity(HEADER_SIZE);
+        header.extend_from_slice(&SEALED_MAGIC);
+        header.extend_from_slice(&SEALED_VERSION.to_le_bytes());
+        header.extend_from_slice(&prefix);
+        file.write_all(&header)?;
+
+        let encryptor = EncryptorBE32::new(Key::from_slice(key), prefix.as_ref().into());
+
+        Ok(Self {
+            file,
+            encryptor: Some(encryptor),
+            header,
+            buffer: Vec::with_capacity(SEALED_CHUNK_SIZE),
+        })
+    }
+
+    /// Seals the remaining bytes as the last chunk and flushes the underlying transport.
+    pub fn finish(mut self) -> Result<(), Error> {
+        self.seal(true)?;
+        self.file.flush()?;
+
+        Ok(())
+    }
+
+    /// Seals the buffered bytes as the next chunk, or as the last chunk if `last` is set, and
+    /// writes the chunk as an 8-bit flag, the 32-bit length of the ciphertext and the ciphertext.
+    fn seal(&mut self, last: bool) -> io::Result<()> {
+        let payload = Payload {
+            msg: &self.buffer,
+            aad: &self.header,
+        };
+
+        let sealed = if last {
+            self.encryptor.take().map(|encryptor| encryptor.encrypt_last(payload))
+        } else {
+            self.encryptor.as_mut().map(|encryptor| encryptor.encrypt_next(payload))
+        };
+
+        let sealed = match sealed {
+            Some(Ok(sealed)) => sealed,
+            _ => return Err(io::Error::other("failed to seal the snapshot")),
+        };
+
+        self.file.write_all(&[last as u8])?;
+        self.file.write_all(&(sealed.len() as u32).to_le_bytes())?;
+        self.file.write_all(&sealed)?;
+        self.buffer.clear();
+
+        Ok(())
+    }
+}
+
+impl<'a, W: Write> Write for SealingWriter<'a, W> {
+    fn write(&mut self, bytes: &[u8]) -> io::Result<usize> {
+        // Only seal a full chunk once more bytes follow, as the last chunk has to be sealed
+        // differently.
+        if self.buffer.len() == SEALED_CHUNK_SIZE {
+            self.seal(false)?;
+        }
+
+        let length = bytes.len().min(SEALED_CHUNK_SIZE - self.buffer.len());
+        self.buffer.extend_from_slice(&bytes[..length]);
+
+        Ok(length)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+/// Reads the sealed chunks from the underlying transport and only returns their bytes once they
+/// have been authenticated.
+pub struct OpeningReader<'a, R: Read> {
+    file: &'a mut R,
+    decryptor: Option<DecryptorBE32<XChaCha20Poly1305>>,
+    header: Vec<u8>,
+    buffer: Vec<u8>,
+    position: usize,
+    tampered: bool,
+}
+
+impl<'a, R: Read> OpeningReader<'a, R> {
+    /// Reads the header from the given transport and returns the reader to open the stream with
+    /// the given key. Returns [`Error::InvalidSnapshot`] if the transport does not hold an
+    /// encrypted snapshot.
+    pub fn new(file: &'a mut R, key: &[u8; 32]) -> Result<Self, Error> {
+        let mut header = vec![0u8; HEADER_SIZE];
+        file.read_exact(&mut header)?;
+
+        if header[..8] != SEALED_MAGIC || header[8..12] != SEALED_VERSION.to_le_bytes() {
+            return Err(Error::InvalidSnapshot);
+        }
+
+        let decryptor = DecryptorBE32::new(Key::from_slice(key), header[12..].into());
+
+        Ok(Self {
+            file,
+            decryptor: Some(decryptor),
+            header,
+            buffer: vec![],
+            position: 0,
+            tampered: false,
+        })
+    }
+
+    /// Returns `true` if a chunk failed to authenticate, i.e. if the snapshot has been tampered
+    /// with or if the key is wrong.
+    pub fn is_tampered(&self) -> bool {
+        self.tampered
+    }
+
+    /// Returns `true` if the last chunk has been opened and all of its bytes have been read.
+    pub fn is_finished(&self) -> bool {
+        self.decryptor.is_none() && self.position == self.buffer.len()
+    }
+
+    /// Reads and opens the next chunk.
+    fn open(&mut self) -> io::Result<()> {
+        let mut frame = [0u8; 5];
+        self.read_exact_sealed(&mut frame)?;
+
+        let last = frame[0];
+        let length = u32::from_le_bytes([frame[1], frame[2], frame[3], frame[4]]) as usize;
+
+        if last > 1 || !(TAG_SIZE..=SEALED_CHUNK_SIZE + TAG_SIZE).contains(&length) {
+            return Err(self.tamper());
+        }
+
+        let mut sealed = vec![0u8; length];
+        self.read_exact_sealed(&mut sealed)?;
+
+        let payload = Payload {
+            msg: &sealed,
+            aad: &self.header,
+        };
+
+        let opened = if last == 1 {
+            self.decryptor.take().map(|decryptor| decryptor.decrypt_last(payload))
+        } else {
+            self.decryptor.as_mut().map(|decryptor| decryptor.decrypt_next(payload))
+        };
+
+        match opened {
+            Some(Ok(buffer)) => {
+                self.buffer = buffer;
+                self.position = 0;
+
+                Ok(())
+            }
+            _ => Err(self.tamper()),
+        }
+    }
+
+    /// Helper function to read the bytes of a chunk, where running out of bytes before the last
+    /// chunk means that the snapshot has been truncated.
+    fn read_exact_sealed(&mut self, bytes: &mut [u8]) -> io::Result<()> {
+        match self.file.read_exact(bytes) {
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => Err(self.tamper()),
+            result => result,
+        }
+    }
+
+    /// Helper function to mark the stream as tampered with.
+    fn tamper(&mut self) -> io::Error {
+        self.tampered = true;
+        self.decryptor = None;
+        self.buffer.clear();
+        self.position = 0;
+
+        io::Error::new(io::ErrorKind::InvalidData, "failed to authenticate the snapshot")
+    }
+}
+
+impl<'a, R: Read> Read for OpeningReader<'a, R> {
+    fn read(&mut self, bytes: &mut [u8]) -> io::Result<usize> {
+        while self.position == self.buffer.len() {
+            if self.tampered {
+                return Err(self.tamper());
+            }
+
+            if self.decryptor.is_none() {
+                return Ok(0);
+            }
+
+            self.open()?;
+        }
+
+        let length = bytes.len().min(self.buffer.len() - self.position);
+        bytes[..length].copy_from_slice(&self.buffer[self.position..][..length]);
+        self.position += length;
+
+        Ok(length)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEY: [u8; 32] = [7; 32];
+
+    fn seal(bytes: &[u8]) -> Vec<u8> {
+        let mut sealed = vec![];
+        let mut writer = SealingWriter::new(&mut sealed, &KEY).unwrap();
+        writer.write_all(bytes).unwrap();
+        writer.finish().unwrap();
+
+        sealed
+    }
+
+    fn open(sealed: &[u8], key: &[u8; 32]) -> (io::Result<Vec<u8>>, bool) {
+        let mut file = sealed;
+        let mut reader = OpeningReader::new(&mut file, key).unwrap();
+        let mut bytes = vec![];
+        let result = reader.read_to_end(&mut bytes).map(|_| bytes);
+
+        (result, reader.is_tampered())
+    }
+
+    fn plaintext() -> Vec<u8> {
+        (0..SEALED_CHUNK_SIZE * 2 + 1).map(|i| (i * 31) as u8).collect()
+    }
+
+    #[test]
+    fn round_trip() {
+        for size in [0, 1, SEALED_CHUNK_SIZE, SEALED_CHUNK_SIZE * 2 + 1] {
+            let bytes = &plaintext()[..size];
+            let sealed = seal(bytes);
+            let mut file = &sealed[..];
+            let mut reader = OpeningReader::new(&mut file, &KEY).unwrap();
+            let mut opened = vec![];
+
+            reader.read_to_end(&mut opened).unwrap();
+            assert_eq!(opened, bytes);
+            assert!(reader.is_finished());
+        }
+    }
+
+    #[test]
+    fn wrong_key() {
+        let (result, tampered) = open(&seal(b"secret"), &[8; 32]);
+        assert!(result.is_err());
+        assert!(tampered);
+    }
+
+    #[test]
+    fn modified_header_or_chunk() {
+        let sealed = seal(&plaintext());
+
+        for offset in [HEADER_SIZE - 1, HEADER_SIZE + 5, sealed.len() - 1] {
+            let mut modified = sealed.clone();
+            modified[offset] ^= 1;
+
+            let (result, tampered) = open(&modified, &KEY);
+            assert!(result.is_err());
+            assert!(tampered);
+        }
+    }
+
+    #[test]
+    fn truncated() {
+        let sealed = seal(&plaintext());
+        let first = HEADER_SIZE + 5 + SEALED_CHUNK_SIZE + TAG_SIZE;
+
+        // Drop the chunks after the first chunk.
+        let (result, tampered) = open(&sealed[..first], &KEY);
+        assert!(result.is_err());
+        assert!(tampered);
+
+        // Drop the chunks after the first chunk and mark it as the last chunk.
+        let mut modified = sealed[..first].to_vec();
+        modified[HEADER_SIZE] = 1;
+
+        let (result, tampered) = open(&modified, &KEY);
+        assert!(result.is_err());
+        assert!(tampered);
+    }
+
+    #[test]
+    fn invalid_magic() {
+        let mut sealed = seal(b"secret");
+        sealed[0] ^= 1;
+
+        let mut file = &sealed[..];
+        assert!(matches!(OpeningReader::new(&mut file, &KEY), Err(Error::InvalidSnapshot)));
+    }
+}
@@ -0,0 +1,863 @@
+//! This module provides a versioned on-disk format to snapshot a whole VM, i.e. its guest physical
+//! memory, the architectural state of its virtual CPUs and any extra state of the VMM, e.g. the
+//! state of the emulated devices. A snapshot is saved to a file through [`crate::Vm::save`] and
+//! restored from a file through [`crate::Hypervisor::restore`]. Alternatively, [`save`] and
+//! [`restore`] stream the snapshot over any [`Write`] and [`Read`] transport, e.g. a TCP stream
+//! or an upload to object storage, as the snapshot is written and read sequentially.
+//!
+//! # Format
+//!
+//! The snapshot starts with the following header, where all fields are little-endian integers:
+//!
+//! | Offset | Field     | Description                                                           |
+//! |--------|-----------|-----------------------------------------------------------------------|
+//! | `0x00` | `magic`   | [`SNAPSHOT_MAGIC`].                                                   |
+//! | `0x08` | `version` | [`SNAPSHOT_VERSION`] as a 32-bit integer.                             |
+//! | `0x0c` | `arch`    | The architecture as a 32-bit integer, see [`SNAPSHOT_ARCH`].          |
+//!
+//! The header is followed by a sequence of sections, each of which starts with a 32-bit `tag`
+//! and the 64-bit `length` of the payload that follows. The sections that are not recognized are
+//! skipped. The sequence ends with a section tagged [`SECTION_END`]. The payloads of the sections
+//! are:
+//!  * [`SECTION_VCPU`]: the [`VcpuState`] of a virtual CPU. The sections are stored in the order
+//!    of the vCPU IDs and precede the [`SECTION_MEMORY`] sections.
+//!  * [`SECTION_EXTRA`]: the extra state of the VMM, which is opaque to this crate.
+//!  * [`SECTION_MEMORY`]: the 64-bit guest physical address and size of a region of guest
+//!    physical memory, the 32-bit [`crate::ProtectionFlags`] and the contents of the region.
+//!
+//! The registers of a [`VcpuState`] are stored by their index into the set of registers that
+//! [`crate::Vcpu::get_state`] captures, such that a snapshot can only hold the states returned by
+//! [`crate::Vcpu::get_state`].
+//!
+//! As the snapshot only holds the state of the guest, the VM has to be built with the same
+//! configuration as the original VM, see [`restore`]. The backing of the memory regions is not
+//! preserved, i.e. the regions are restored as anonymous memory.
+//!
+//! # Encryption
+//!
+//! As a snapshot holds the whole guest physical memory, including any secrets of the guest, the
+//! `encryption` feature provides [`save_encrypted`] and [`restore_encrypted`] to encrypt and
+//! authenticate the snapshot with XChaCha20-Poly1305 under a 256-bit key that is supplied by the
+//! embedder. The encrypted snapshot starts with the following header:
+//!
+//! | Offset | Field     | Description                                                           |
+//! |--------|-----------|-----------------------------------------------------------------------|
+//! | `0x00` | `magic`   | `HYRSSEAL`.                                                           |
+//! | `0x08` | `version` | The version of the encrypted format as a 32-bit integer, i.e. 1.      |
+//! | `0x0c` | `nonce`   | The random 19-byte nonce prefix.                                      |
+//!
+//! The header is followed by the snapshot split into chunks of 64 KiB, each of which is stored as
+//! an 8-bit flag that is set for the last chunk, the 32-bit length of the ciphertext and the
+//! ciphertext including its 16-byte tag. The chunks are sealed with the STREAM construction and
+//! the header is authenticated along with every chunk, such that any modification, reordering or
+//! truncation of the snapshot is detected on restore.
+
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+use crate::arch::x86_64::{
+    DescriptorTable, FpuState, PendingEvents, PendingException, Segment, SmmState,
+};
+use crate::error::Error;
+#[cfg(feature = "encryption")]
+use crate::sealed::{OpeningReader, SealingWriter};
+use crate::state::{VcpuState, STATE_REGISTERS};
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+use crate::state::{STATE_CONTROL_REGISTERS, STATE_DESCRIPTOR_TABLES, STATE_SEGMENT_REGISTERS};
+use crate::vm::{ProtectionFlags, Vm, VmBuilder};
+use std::convert::TryFrom;
+use std::io::{Read, Write};
+
+/// The magic that identifies a snapshot.
+pub const SNAPSHOT_MAGIC: [u8; 8] = *b"HYRSSNAP";
+/// The version of the snapshot format.
+pub const SNAPSHOT_VERSION: u32 = 1;
+/// The architecture of the snapshots taken on this host, which is 1 on the x86 architecture and 2
+/// on AArch64.
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+pub const SNAPSHOT_ARCH: u32 = 1;
+/// The architecture of the snapshots taken on this host, which is 1 on the x86 architecture and 2
+/// on AArch64.
+#[cfg(target_arch = "aarch64")]
+pub const SNAPSHOT_ARCH: u32 = 2;
+
+/// The section that ends the snapshot.
+pub const SECTION_END: u32 = 0;
+/// The section that holds a region of guest physical memory.
+pub const SECTION_MEMORY: u32 = 1;
+/// The section that holds the state of a virtual CPU.
+pub const SECTION_VCPU: u32 = 2;
+/// The section that holds the extra state of the VMM.
+pub const SECTION_EXTRA: u32 = 3;
+
+/// The size of the chunks in which the guest physical memory is copied.
+const CHUNK_SIZE: usize = 1 << 20;
+/// The maximum size of a payload that is read into memory as a whole, i.e. the state of a
+/// virtual CPU or the extra state of the VMM, such that a malformed length cannot exhaust the
+/// memory of the host.
+pub const MAX_PAYLOAD_SIZE: u64 = 64 << 20;
+
+/// A VM restored from a snapshot, received through [`crate::migration::receive`] or forked through
+/// [`crate::Vm::fork`].
+pub struct RestoredVm<'a> {
+    /// The VM with the regions of guest physical memory restored.
+    pub vm: Vm<'a>,
+    /// The states of the virtual CPUs in the order of the vCPU IDs, which have to be restored
+    /// through [`crate::Vcpu::set_state`] once the virtual CPUs have been created.
+    pub vcpus: Vec<VcpuState>,
+    /// The extra state of the VMM as written by the callback passed to [`crate::Vm::save`], or as
+    /// captured in [`crate::migration::MigrationState`]. This is empty for a fork.
+    pub extra: Vec<u8>,
+}
+
+/// Saves a snapshot of the given VM to the given transport, see [`crate::Vm::save`]. The
+/// transport should be buffered, as the snapshot is written in small pieces.
+pub fn save<W, F>(vm: &Vm, file: &mut W, vcpus: &[VcpuState], extra: F) -> Result<(), Error>
+where
+    W: Write,
+    F: FnOnce(&mut Vec<u8>) -> Result<(), Error>,
+{
+    file.write_all(&SNAPSHOT_MAGIC)?;
+    file.write_all(&SNAPSHOT_VERSION.to_le_bytes())?;
+    file.write_all(&SNAPSHOT_ARCH.to_le_bytes())?;
+
+    // Store the states of the virtual CPUs first, such that the VM can be built before its
+    // guest physical memory is restored.
+    for state in vcpus {
+        write_section(file, SECTION_VCPU, &encode_vcpu_state(state)?)?;
+    }
+
+    let mut bytes = vec![];
+    extra(&mut bytes)?;
+    write_section(file, SECTION_EXTRA, &bytes)?;
+
+    // Copy the guest physical memory in chunks, rather than buffering whole regions.
+    let mut chunk = vec![0u8; CHUNK_SIZE];
+
+    for (range, protection, _) in vm.regions()? {
+        let size = range.end - range.start;
+
+        write_section_header(file, SECTION_MEMORY, 20 + size)?;
+        file.write_all(&range.start.to_le_bytes())?;
+        file.write_all(&size.to_le_bytes())?;
+        file.write_all(&protection.bits().to_le_bytes())?;
+
+        let mut address = range.start;
+
+        while address < range.end {
+            let length = CHUNK_SIZE.min((range.end - address) as usize);
+
+            if vm.read_physical_memory(&mut chunk[..length], address)? != length {
+                return Err(Error::InvalidGuestAddress);
+            }
+
+            file.write_all(&chunk[..length])?;
+            address += length as u64;
+        }
+    }
+
+    write_section_header(file, SECTION_END, 0)?;
+    file.flush()?;
+
+    Ok(())
+}
+
+/// Restores the snapshot from the given transport by building the VM from the given
+/// [`VmBuilder`] with the number of virtual CPUs in the snapshot and restoring its guest physical
+/// memory. The builder should be configured the same way as the builder of the original VM. The
+/// transport should be buffered, as the snapshot is read in small pieces. Returns
+/// [`Error::InvalidSnapshot`] if the transport does not hold a snapshot, if the snapshot is
+/// malformed, e.g. if a region wraps around the address space or if a payload is larger than
+/// [`MAX_PAYLOAD_SIZE`], or if the snapshot was taken with another version of the format or on
+/// another architecture.
+pub fn restore<'a, R: Read>(
+    builder: VmBuilder,
+    file: &mut R,
+    name: &'a str,
+) -> Result<RestoredVm<'a>, Error> {
+    let mut magic = [0u8; 8];
+    file.read_exact(&mut magic)?;
+
+    if magic != SNAPSHOT_MAGIC || read_u32(file)? != SNAPSHOT_VERSION {
+        return Err(Error::InvalidSnapshot);
+    }
+
+    if read_u32(file)? != SNAPSHOT_ARCH {
+        return Err(Error::InvalidSnapshot);
+    }
+
+    let mut builder = Some(builder);
+    let mut vm = None;
+    let mut vcpus = vec![];
+    let mut extra = vec![];
+    let mut chunk = vec![0u8; CHUNK_SIZE];
+
+    loop {
+        let tag = read_u32(file)?;
+        let length = read_u64(file)?;
+
+        match tag {
+            SECTION_END => break,
+            SECTION_MEMORY => {
+                let guest_address = read_u64(file)?;
+                let size = read_u64(file)?;
+                let protection = ProtectionFlags::from_bits(read_u32(file)?)
+                    .ok_or(Error::InvalidSnapshot)?;
+
+                let end = guest_address.checked_add(size).ok_or(Error::InvalidSnapshot)?;
+
+                if size.checked_add(20) != Some(length) {
+                    return Err(Error::InvalidSnapshot);
+                }
+
+                // The states of the virtual CPUs precede the memory, so the number of virtual
+                // CPUs is known by now.
+                if let Some(builder) = builder.take() {
+                    vm = Some(build(builder, vcpus.len(), name)?);
+                }
+
+                let vm = vm.as_mut().ok_or(Error::InvalidSnapshot)?;
+                let size = usize::try_from(size).map_err(|_| Error::InvalidSnapshot)?;
+                vm.allocate_physical_memory(guest_address, size, protection)?;
+
+                let mut address = guest_address;
+
+                while address < end {
+                    let length = CHUNK_SIZE.min((end - address) as usize);
+
+                    file.read_exact(&mut chunk[..length])?;
+                    vm.write_all_physical_memory(address, &chunk[..length])?;
+                    address += length as u64;
+                }
+            }
+            SECTION_VCPU if vm.is_none() => {
+                let bytes = read_payload(file, length)?.ok_or(Error::InvalidSnapshot)?;
+                vcpus.push(decode_vcpu_state(&bytes)?);
+            }
+            SECTION_VCPU => return Err(Error::InvalidSnapshot),
+            SECTION_EXTRA => {
+                extra = read_payload(file, length)?.ok_or(Error::InvalidSnapshot)?;
+            }
+            _ => {
+                std::io::copy(&mut (&mut *file).take(length), &mut std::io::sink())?;
+            }
+        }
+    }
+
+    let vm = match (vm, builder) {
+        (Some(vm), _) => vm,
+        (_, Some(builder)) => build(builder, vcpus.len(), name)?,
+        _ => return Err(Error::InvalidSnapshot),
+    };
+
+    Ok(RestoredVm {
+        vm,
+        vcpus,
+        extra,
+    })
+}
+
+/// Saves a snapshot of the given VM to the given transport like [`save`], but encrypts and
+/// authenticates the snapshot with the given key, see [`crate::snapshot#encryption`].
+#[cfg(feature = "encryption")]
+pub fn save_encrypted<W, F>(
+    vm: &Vm,
+    file: &mut W,
+    key: &[u8; 32],
+    vcpus: &[VcpuState],
+    extra: F,
+) -> Result<(), Error>
+where
+    W: Write,
+    F: FnOnce(&mut Vec<u8>) -> Result<(), Error>,
+{
+    let mut writer = SealingWriter::new(file, key)?;
+    save(vm, &mut writer, vcpus, extra)?;
+    writer.finish()
+}
+
+/// Restores the encrypted snapshot from the given transport like [`restore`], but decrypts the
+/// snapshot with the given key and verifies its integrity, see [`crate::snapshot#encryption`].
+/// Returns [`Error::InvalidSnapshot`] if the key is wrong or if the snapshot has been tampered
+/// with, in which case the partially restored VM is destroyed.
+#[cfg(feature = "encryption")]
+pub fn restore_encrypted<'a, R: Read>(
+    builder: VmBuilder,
+    file: &mut R,
+    key: &[u8; 32],
+    name: &'a str,
+) -> Result<RestoredVm<'a>, Error> {
+    let mut reader = OpeningReader::new(file, key)?;
+
+    let restored = match restore(builder, &mut reader, name) {
+        Err(_) if reader.is_tampered() => return Err(Error::InvalidSnapshot),
+        result => result?,
+    };
+
+    // The snapshot must end with the last chunk, as otherwise chunks could have been appended.
+    if !reader.is_finished() {
+        return Err(Error::InvalidSnapshot);
+    }
+
+    Ok(restored)
+}
+
+/// Helper function to build the VM with the given number of virtual CPUs.
+fn build<'a>(builder: VmBuilder, vcpu_count: usize, name: &'a str) -> Result<Vm<'a>, Error> {
+    builder
+        .with_vcpu_count(vcpu_count.max(1))?
+        .build(name)
+}
+
+/// Helper function to write the header of a section with a payload of the given length.
+pub(crate) fn write_section_header<W: Write>(
+    file: &mut W,
+    tag: u32,
+    length: u64,
+) -> Result<(), Error> {
+    file.write_all(&tag.to_le_bytes())?;
+    file.write_all(&length.to_le_bytes())?;
+
+    Ok(())
+}
+
+/// Helper function to write a section with the given payload.
+pub(crate) fn write_section<W: Write>(file: &mut W, tag: u32, payload: &[u8]) -> Result<(), Error> {
+    write_section_header(file, tag, payload.len() as u64)?;
+    file.write_all(payload)?;
+
+    Ok(())
+}
+
+/// Helper function to read a little-endian 32-bit integer.
+pub(crate) fn read_u32<R: Read>(file: &mut R) -> Result<u32, Error> {
+    let mut bytes = [0u8; 4];
+    file.read_exact(&mut bytes)?;
+
+    Ok(u32::from_le_bytes(bytes))
+}
+
+/// Helper function to read a little-endian 64-bit integer.
+pub(crate) fn read_u64<R: Read>(file: &mut R) -> Result<u64, Error> {
+    let mut bytes = [0u8; 8];
+    file.read_exact(&mut bytes)?;
+
+    Ok(u64::from_le_bytes(bytes))
+}
+
+/// Helper function to read the payload of a section with the given length. Returns `None` if the
+/// length exceeds [`MAX_PAYLOAD_SIZE`], without reading the payload.
+pub(crate) fn read_payload<R: Read>(file: &mut R, length: u64) -> Result<Option<Vec<u8>>, Error> {
+    if length > MAX_PAYLOAD_SIZE {
+        return Ok(None);
+    }
+
+    let mut bytes = vec![0u8; length as usize];
+    file.read_exact(&mut bytes)?;
+
+    Ok(Some(bytes))
+}
+
+/// Helper function to encode the given state of a virtual CPU as the payload of a section.
+pub(crate) fn encode_vcpu_state(state: &VcpuState) -> Result<Vec<u8>, Error> {
+    let mut encoder = Encoder::default();
+    encoder.vcpu_state(state)?;
+
+    Ok(encoder.bytes)
+}
+
+/// Helper function to decode the state of a virtual CPU from the payload of a section.
+pub(crate) fn decode_vcpu_state(bytes: &[u8]) -> Result<VcpuState, Error> {
+    Decoder::new(bytes).vcpu_state()
+}
+
+/// Encodes the payload of a section.
+#[derive(Default)]
+struct Encoder {
+    bytes: Vec<u8>,
+}
+
+// Not all the helpers are needed for the smaller state of AArch64.
+#[cfg_attr(target_arch = "aarch64", allow(dead_code))]
+impl Encoder {
+    fn u8(&mut self, value: u8) {
+        self.bytes.push(value);
+    }
+
+    fn bool(&mut self, value: bool) {
+        self.u8(value as u8);
+    }
+
+    fn u16(&mut self, value: u16) {
+        self.bytes.extend_from_slice(&value.to_le_bytes());
+    }
+
+    fn u32(&mut self, value: u32) {
+        self.bytes.extend_from_slice(&value.to_le_bytes());
+    }
+
+    fn u64(&mut self, value: u64) {
+        self.bytes.extend_from_slice(&value.to_le_bytes());
+    }
+
+    /// Encodes the given bytes prefixed by their length.
+    fn bytes(&mut self, bytes: &[u8]) {
+        self.u32(bytes.len() as u32);
+        self.bytes.extend_from_slice(bytes);
+    }
+
+    /// Encodes the given register by its index into the given set of registers. Returns
+    /// [`Error::InvalidArgument`] if the register is not part of the set.
+    fn index<T: PartialEq>(&mut self, set: &[T], register: &T) -> Result<(), Error> {
+        let index = set
+            .iter()
+            .position(|entry| entry == register)
+            .ok_or(Error::InvalidArgument)?;
+
+        self.u8(index as u8);
+
+        Ok(())
+    }
+
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    fn vcpu_state(&mut self, state: &VcpuState) -> Result<(), Error> {
+        self.u32(state.registers.len() as u32);
+
+        for (register, value) in &state.registers {
+            self.index(STATE_REGISTERS, register)?;
+            self.u64(*value);
+        }
+
+        self.u32(state.control_registers.len() as u32);
+
+        for (register, value) in &state.control_registers {
+            self.index(STATE_CONTROL_REGISTERS, register)?;
+            self.u64(*value);
+        }
+
+        self.u32(state.segment_registers.len() as u32);
+
+        for (register, segment) in &state.segment_registers {
+            self.index(STATE_SEGMENT_REGISTERS, register)?;
+            self.segment(segment);
+        }
+
+        self.u32(state.descriptor_tables.len() as u32);
+
+        for (register, table) in &state.descriptor_tables {
+            self.index(STATE_DESCRIPTOR_TABLES, register)?;
+            self.u64(table.base);
+            self.u16(table.limit);
+        }
+
+        self.u32(state.msrs.len() as u32);
+
+        for (msr, value) in &state.msrs {
+            self.u32(*msr);
+            self.u64(*value);
+        }
+
+        self.bool(state.fpu.is_some());
+
+        if let Some(fpu) = &state.fpu {
+            self.fpu(fpu);
+        }
+
+        self.bool(state.xcr0.is_some());
+
+        if let Some(xcr0) = state.xcr0 {
+            self.u64(xcr0);
+        }
+
+        self.bool(state.xsave.is_some());
+
+        if let Some(xsave) = &state.xsave {
+            self.bytes(xsave);
+        }
+
+        self.bool(state.tsc.is_some());
+
+        if let Some(tsc) = state.tsc {
+            self.u64(tsc);
+        }
+
+        self.bool(state.pending_events.is_some());
+
+        if let Some(events) = &state.pending_events {
+            self.pending_events(events);
+        }
+
+        self.bool(state.smm.is_some());
+
+        if let Some(smm) = &state.smm {
+            self.bool(smm.active);
+            self.bool(smm.pending);
+            self.bool(smm.inside_nmi);
+            self.bool(smm.latched_init);
+        }
+
+        Ok(())
+    }
+
+    #[cfg(target_arch = "aarch64")]
+    fn vcpu_state(&mut self, state: &VcpuState) -> Result<(), Error> {
+        self.u32(state.registers.len() as u32);
+
+        for (register, value) in &state.registers {
+            self.index(STATE_REGISTERS, register)?;
+            self.u64(*value);
+        }
+
+        Ok(())
+    }
+
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    fn segment(&mut self, segment: &Segment) {
+        self.u64(segment.base);
+        self.u32(segment.limit);
+        self.u16(segment.selector);
+        self.u8(segment.segment_type);
+        self.bool(segment.non_system_segment);
+        self.u8(segment.dpl);
+        self.bool(segment.present);
+        self.bool(segment.available);
+        self.bool(segment.long);
+        self.bool(segment.default);
+        self.bool(segment.granularity);
+    }
+
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    fn fpu(&mut self, fpu: &FpuState) {
+        self.u16(fpu.fcw);
+        self.u16(fpu.fsw);
+        self.u8(fpu.ftw);
+        self.u16(fpu.last_opcode);
+        self.u64(fpu.last_ip);
+        self.u64(fpu.last_dp);
+
+        for register in fpu.st.iter().chain(fpu.xmm.iter()) {
+            self.bytes.extend_from_slice(register);
+        }
+
+        self.u32(fpu.mxcsr);
+    }
+
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    fn pending_events(&mut self, events: &PendingEvents) {
+        self.bool(events.exception.is_some());
+
+        if let Some(exception) = &events.exception {
+            self.u8(exception.vector);
+            self.bool(exception.error_code.is_some());
+            self.u32(exception.error_code.unwrap_or(0));
+        }
+
+        self.bool(events.interrupt.is_some());
+        self.u8(events.interrupt.unwrap_or(0));
+        self.bytes(&events.queued_interrupts);
+        self.bool(events.nmi_pending);
+        self.bool(events.nmi_masked);
+        self.bool(events.interrupt_shadow);
+    }
+}
+
+/// Decodes the payload of a section, where running out of bytes results in
+/// [`Error::InvalidSnapshot`].
+struct Decoder<'a> {
+    bytes: &'a [u8],
+}
+
+#[cfg_attr(target_arch = "aarch64", allow(dead_code))]
+impl<'a> Decoder<'a> {
+    fn new(bytes: &'a [u8]) -> Self {
+        Self {
+            bytes,
+        }
+    }
+
+    fn take(&mut self, length: usize) -> Result<&'a [u8], Error> {
+        if self.bytes.len() < length {
+            return Err(Error::InvalidSnapshot);
+        }
+
+        let (bytes, rest) = self.bytes.split_at(length);
+        self.bytes = rest;
+
+        Ok(bytes)
+    }
+
+    fn u8(&mut self) -> Result<u8, Error> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn bool(&mut self) -> Result<bool, Error> {
+        match self.u8()? {
+            0 => Ok(false),
+            1 => Ok(true),
+            _ => Err(Error::InvalidSnapshot),
+        }
+    }
+
+    fn u16(&mut self) -> Result<u16, Error> {
+        Ok(u16::from_le_bytes([self.u8()?, self.u8()?]))
+    }
+
+    fn u32(&mut self) -> Result<u32, Error> {
+        let mut bytes = [0u8; 4];
+        bytes.copy_from_slice(self.take(4)?);
+
+        Ok(u32::from_le_bytes(bytes))
+    }
+
+    fn u64(&mut self) -> Result<u64, Error> {
+        let mut bytes = [0u8; 8];
+        bytes.copy_from_slice(self.take(8)?);
+
+        Ok(u64::from_le_bytes(bytes))
+    }
+
+    /// Decodes the bytes prefixed by their length.
+    fn bytes(&mut self) -> Result<Vec<u8>, Error> {
+        let length = self.u32()? as usize;
+
+        Ok(self.take(length)?.to_vec())
+    }
+
+    /// Decodes a register by its index into the given set of registers.
+    fn index<T: Copy>(&mut self, set: &[T]) -> Result<T, Error> {
+        let index = self.u8()? as usize;
+
+        set.get(index).copied().ok_or(Error::InvalidSnapshot)
+    }
+
+    /// Helper function to decode a list of registers and their values.
+    fn list<T>(
+        &mut self,
+        mut decode: impl FnMut(&mut Self) -> Result<T, Error>,
+    ) -> Result<Vec<T>, Error> {
+        let count = self.u32()?;
+        let mut list = vec![];
+
+        for _ in 0..count {
+            list.push(decode(self)?);
+        }
+
+        Ok(list)
+    }
+
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    fn vcpu_state(&mut self) -> Result<VcpuState, Error> {
+        let registers = self.list(|d| Ok((d.index(STATE_REGISTERS)?, d.u64()?)))?;
+        let control_registers =
+            self.list(|d| Ok((d.index(STATE_CONTROL_REGISTERS)?, d.u64()?)))?;
+        let segment_registers =
+            self.list(|d| Ok((d.index(STATE_SEGMENT_REGISTERS)?, d.segment()?)))?;
+        let descriptor_tables = self.list(|d| {
+            let register = d.index(STATE_DESCRIPTOR_TABLES)?;
+            let base = d.u64()?;
+            let limit = d.u16()?;
+
+            Ok((register, DescriptorTable { base, limit }))
+        })?;
+        let msrs = self.list(|d| Ok((d.u32()?, d.u64()?)))?;
+
+        let fpu = match self.bool()? {
+            true => Some(self.fpu()?),
+            false => None,
+        };
+        let xcr0 = match self.bool()? {
+            true => Some(self.u64()?),
+            false => None,
+        };
+        let xsave = match self.bool()? {
+            true => Some(self.bytes()?),
+            false => None,
+        };
+        let tsc = match self.bool()? {
+            true => Some(self.u64()?),
+            false => None,
+        };
+        let pending_events = match self.bool()? {
+            true => Some(self.pending_events()?),
+            false => None,
+        };
+        let smm = match self.bool()? {
+            true => Some(SmmState {
+                active: self.bool()?,
+                pending: self.bool()?,
+                inside_nmi: self.bool()?,
+                latched_init: self.bool()?,
+            }),
+            false => None,
+        };
+
+        Ok(VcpuState {
+            registers,
+            control_registers,
+            segment_registers,
+            descriptor_tables,
+            msrs,
+            fpu,
+            xcr0,
+            xsave,
+            tsc,
+            pending_events,
+            smm,
+        })
+    }
+
+    #[cfg(target_arch = "aarch64")]
+    fn vcpu_state(&mut self) -> Result<VcpuState, Error> {
+        let registers = self.list(|d| Ok((d.index(STATE_REGISTERS)?, d.u64()?)))?;
+
+        Ok(VcpuState {
+            registers,
+        })
+    }
+
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    fn segment(&mut self) -> Result<Segment, Error> {
+        Ok(Segment {
+            base: self.u64()?,
+            limit: self.u32()?,
+            selector: self.u16()?,
+            segment_type: self.u8()?,
+            non_system_segment: self.bool()?,
+            dpl: self.u8()?,
+            present: self.bool()?,
+            available: self.bool()?,
+            long: self.bool()?,
+            default: self.bool()?,
+            granularity: self.bool()?,
+        })
+    }
+
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    fn fpu(&mut self) -> Result<FpuState, Error> {
+        let fcw = self.u16()?;
+        let fsw = self.u16()?;
+        let ftw = self.u8()?;
+        let last_opcode = self.u16()?;
+        let last_ip = self.u64()?;
+        let last_dp = self.u64()?;
+
+        let mut st = [[0u8; 16]; 8];
+        let mut xmm = [[0u8; 16]; 16];
+
+        for register in st.iter_mut().chain(xmm.iter_mut()) {
+            register.copy_from_slice(self.take(16)?);
+        }
+
+        Ok(FpuState {
+            fcw,
+            fsw,
+            ftw,
+            last_opcode,
+            last_ip,
+            last_dp,
+            st,
+            mxcsr: self.u32()?,
+            xmm,
+        })
+    }
+
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    fn pending_events(&mut self) -> Result<PendingEvents, Error> {
+        let exception = match self.bool()? {
+            true => {
+                let vector = self.u8()?;
+                let has_error_code = self.bool()?;
+                let error_code = self.u32()?;
+
+                Some(PendingException {
+                    vector,
+                    error_code: if has_error_code { Some(error_code) } else { None },
+                })
+            }
+            false => None,
+        };
+
+        let has_interrupt = self.bool()?;
+        let interrupt = self.u8()?;
+
+        Ok(PendingEvents {
+            exception,
+            interrupt: if has_interrupt { Some(interrupt) } else { None },
+            queued_interrupts: self.bytes()?,
+            nmi_pending: self.bool()?,
+            nmi_masked: self.bool()?,
+            interrupt_shadow: self.bool()?,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    use crate::arch::x86_64::{ControlRegister, Register};
+    #[cfg(target_arch = "aarch64")]
+    use crate::arch::aarch64::Register;
+
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    fn state() -> VcpuState {
+        VcpuState {
+            registers: vec![(Register::Rax, 1), (Register::Rip, 0xfff0)],
+            control_registers: vec![(ControlRegister::Cr0, 0x11)],
+            msrs: vec![(0xc000_0080, 0x500)],
+            xcr0: Some(1),
+            xsave: Some(vec![1, 2, 3]),
+            tsc: Some(1234),
+            ..Default::default()
+        }
+    }
+
+    #[cfg(target_arch = "aarch64")]
+    fn state() -> VcpuState {
+        VcpuState {
+            registers: vec![(Register::X0, 1), (Register::Pc, 0x8000_0000)],
+        }
+    }
+
+    #[test]
+    fn vcpu_state_round_trip() {
+        let state = state();
+        let bytes = encode_vcpu_state(&state).unwrap();
+        let decoded = decode_vcpu_state(&bytes).unwrap();
+
+        assert_eq!(format!("{:?}", decoded), format!("{:?}", state));
+    }
+
+    #[test]
+    fn truncated_vcpu_state() {
+        let bytes = encode_vcpu_state(&state()).unwrap();
+
+        for length in 0..bytes.len() {
+            assert!(matches!(decode_vcpu_state(&bytes[..length]), Err(Error::InvalidSnapshot)));
+        }
+    }
+
+    #[test]
+    fn invalid_register_index() {
+        let mut bytes = encode_vcpu_state(&state()).unwrap();
+
+        // The index of the first register follows the 32-bit count.
+        bytes[4] = 0xff;
+        assert!(matches!(decode_vcpu_state(&bytes), Err(Error::InvalidSnapshot)));
+    }
+
+    #[test]
+    fn oversized_payload() {
+        let mut file: &[u8] = &[0u8; 16];
+
+        assert!(read_payload(&mut file, MAX_PAYLOAD_SIZE + 1).unwrap().is_none());
+        assert!(read_payload(&mut file, u64::MAX).unwrap().is_none());
+        assert_eq!(file.len(), 16);
+    }
+
+    #[test]
+    fn truncated_payload() {
+        let mut file: &[u8] = &[0u8; 16];
+
+        assert_eq!(read_payload(&mut file, 16).unwrap(), Some(vec![0u8; 16]));
+        assert!(matches!(read_payload(&mut file, 1), Err(Error::Io(_))));
+    }
+}
@@ -0,0 +1,177 @@
+//! This module provides the [`VcpuState`] struct, which captures the architectural state of a
+//! virtual CPU through [`crate::Vcpu::get_state`], such that the virtual CPU can be restored to
+//! exactly that state later through [`crate::Vcpu::set_state`], e.g. to snapshot a VM.
+//!
+//! The state is described as lists of registers and their values, such that the same container
+//! type is used on every architecture and backend, while the register types are the ones of the
+//! architecture. The state of the local APIC is not included, as the local APIC is emulated by
+//! the VMM rather than the hypervisor on most platforms.
+
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+use crate::arch::x86_64::{
+    ControlRegister, DescriptorTable, DescriptorTableRegister, FpuState, PendingEvents, Register,
+    Segment, SegmentRegister, SmmState, MSR_IA32_CSTAR, MSR_IA32_EFER, MSR_IA32_KERNEL_GS_BASE,
+    MSR_IA32_LSTAR, MSR_IA32_STAR, MSR_IA32_SYSCALL_MASK, MSR_IA32_SYSENTER_CS,
+    MSR_IA32_SYSENTER_EIP, MSR_IA32_SYSENTER_ESP,
+};
+#[cfg(target_arch = "aarch64")]
+use crate::arch::aarch64::Register;
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+use crate::error::Error;
+
+/// The general-purpose registers that are part of the state.
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+pub(crate) const STATE_REGISTERS: &[Register] = &[
+    Register::Rax,
+    Register::Rcx,
+    Register::Rdx,
+    Register::Rbx,
+    Register::Rsp,
+    Register::Rbp,
+    Register::Rsi,
+    Register::Rdi,
+    Register::R8,
+    Register::R9,
+    Register::R10,
+    Register::R11,
+    Register::R12,
+    Register::R13,
+    Register::R14,
+    Register::R15,
+    Register::Rip,
+    Register::Rflags,
+];
+
+/// The general-purpose registers that are part of the state.
+#[cfg(target_arch = "aarch64")]
+pub(crate) const STATE_REGISTERS: &[Register] = &[
+    Register::X0,
+    Register::X1,
+    Register::X2,
+    Register::X3,
+    Register::X4,
+    Register::X5,
+    Register::X6,
+    Register::X7,
+    Register::X8,
+    Register::X9,
+    Register::X10,
+    Register::X11,
+    Register::X12,
+    Register::X13,
+    Register::X14,
+    Register::X15,
+    Register::X16,
+    Register::X17,
+    Register::X18,
+    Register::X19,
+    Register::X20,
+    Register::X21,
+    Register::X22,
+    Register::X23,
+    Register::X24,
+    Register::X25,
+    Register::X26,
+    Register::X27,
+    Register::X28,
+    Register::X29,
+    Register::X30,
+    Register::Sp,
+    Register::Pc,
+    Register::Pstate,
+];
+
+/// The control registers that are part of the state.
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+pub(crate) const STATE_CONTROL_REGISTERS: &[ControlRegister] = &[
+    ControlRegister::Cr0,
+    ControlRegister::Cr2,
+    ControlRegister::Cr3,
+    ControlRegister::Cr4,
+    ControlRegister::Cr8,
+];
+
+/// The segment registers that are part of the state.
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+pub(crate) const STATE_SEGMENT_REGISTERS: &[SegmentRegister] = &[
+    SegmentRegister::Cs,
+    SegmentRegister::Ds,
+    SegmentRegister::Es,
+    SegmentRegister::Fs,
+    SegmentRegister::Gs,
+    SegmentRegister::Ss,
+    SegmentRegister::Tr,
+    SegmentRegister::Ldt,
+];
+
+/// The descriptor tables that are part of the state.
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+pub(crate) const STATE_DESCRIPTOR_TABLES: &[DescriptorTableRegister] = &[
+    DescriptorTableRegister::Gdt,
+    DescriptorTableRegister::Idt,
+];
+
+/// The model-specific registers that are part of the state, i.e. the ones that configure the
+/// system call instructions and the segment bases.
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+pub(crate) const STATE_MSRS: &[u32] = &[
+    MSR_IA32_EFER,
+    MSR_IA32_STAR,
+    MSR_IA32_LSTAR,
+    MSR_IA32_CSTAR,
+    MSR_IA32_SYSCALL_MASK,
+    MSR_IA32_KERNEL_GS_BASE,
+    MSR_IA32_SYSENTER_CS,
+    MSR_IA32_SYSENTER_ESP,
+    MSR_IA32_SYSENTER_EIP,
+];
+
+/// The architectural state of a virtual CPU.
+#[derive(Clone, Debug, Default)]
+pub struct VcpuState {
+    /// The general-purpose registers and their values.
+    pub registers: Vec<(Register, u64)>,
+    /// The control registers and their values.
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    pub control_registers: Vec<(ControlRegister, u64)>,
+    /// The segment registers and their values.
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    pub segment_registers: Vec<(SegmentRegister, Segment)>,
+    /// The descriptor tables and their values.
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    pub descriptor_tables: Vec<(DescriptorTableRegister, DescriptorTable)>,
+    /// The model-specific registers and their values.
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    pub msrs: Vec<(u32, u64)>,
+    /// The state of the x87 FPU and the SSE registers, or `None` if the platform does not support
+    /// accessing it.
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    pub fpu: Option<FpuState>,
+    /// The extended control register XCR0, or `None` if the platform does not support accessing
+    /// it.
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    pub xcr0: Option<u64>,
+    /// The XSAVE area, or `None` if the platform does not support accessing it.
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    pub xsave: Option<Vec<u8>>,
+    /// The value of the TSC, or `None` if the platform does not support accessing it.
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    pub tsc: Option<u64>,
+    /// The pending events, or `None` if the platform does not support accessing them.
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    pub pending_events: Option<PendingEvents>,
+    /// The System Management Mode state, or `None` if the platform does not support SMM.
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    pub smm: Option<SmmState>,
+}
+
+/// Helper function to turn [`Error::NotImplemented`] into `None`, for the parts of the state that
+/// not every platform supports.
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+pub(crate) fn optional<T>(result: Result<T, Error>) -> Result<Option<T>, Error> {
+    match result {
+        Ok(value) => Ok(Some(value)),
+        Err(Error::NotImplemented) => Ok(None),
+        Err(e) => Err(e),
+    }
+}
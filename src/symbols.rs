@@ -0,0 +1,190 @@
+//! This module provides the [`SymbolMap`] struct, which maps guest virtual addresses to symbol
+//! names. A symbol map can be loaded into the VM through [`crate::Vm::set_symbol_map`], after
+//! which guest addresses, e.g. the instruction pointer at the time of an exit or the return
+//! addresses of a stack walk, can be annotated with symbol names through
+//! [`crate::Vm::symbolize`] and [`crate::Vcpu::symbolize`].
+//!
+//! Symbol maps can be built from:
+//!  * The contents of `/proc/kallsyms` or `System.map` of a Linux guest, see
+//!    [`SymbolMap::from_kallsyms`].
+//!  * A plain list of addresses and names, see [`SymbolMap::from_list`].
+//!  * The symbol table of an ELF file, see [`SymbolMap::from_elf`].
+//!  * Individual symbols, see [`SymbolMap::insert`].
+
+use crate::elf::Elf;
+use crate::error::Error;
+use std::collections::BTreeMap;
+use std::io::BufRead;
+
+/// Represents a symbol in the guest.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Symbol {
+    /// The name of the symbol.
+    pub name: String,
+    /// The size of the symbol in bytes, if known.
+    pub size: Option<u64>,
+}
+
+/// Maps guest virtual addresses to symbols.
+#[derive(Clone, Debug, Default)]
+pub struct SymbolMap {
+    /// A mapping of the start address of each symbol to the symbol.
+    symbols: BTreeMap<u64, Symbol>,
+}
+
+/// The ELF section type of a symbol table.
+const SHT_SYMTAB: u32 = 2;
+/// The ELF symbol type of a data object.
+const STT_OBJECT: u8 = 1;
+/// The ELF symbol type of a function.
+const STT_FUNC: u8 = 2;
+
+impl SymbolMap {
+    /// Creates an empty symbol map.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns the number of symbols.
+    pub fn len(&self) -> usize {
+        self.symbols.len()
+    }
+
+    /// Returns `true` if the symbol map is empty.
+    pub fn is_empty(&self) -> bool {
+        self.symbols.is_empty()
+    }
+
+    /// Adds the symbol at the given address, replacing any symbol previously added at that
+    /// address.
+    pub fn insert(&mut self, address: u64, name: &str, size: Option<u64>) {
+        self.symbols.insert(address, Symbol {
+            name: name.to_string(),
+            size,
+        });
+    }
+
+    /// Parses a list with one symbol per line, where each line consists of the address in
+    /// hexadecimal followed by the name. Empty lines are skipped.
+    pub fn from_list<R: BufRead>(reader: R) -> Result<Self, Error> {
+        let mut map = Self::new();
+
+        for line in reader.lines() {
+            let line = line?;
+            let mut fields = line.split_whitespace();
+
+            let address = match fields.next() {
+                Some(address) => parse_address(address)?,
+                _ => continue,
+            };
+
+            let name = fields.next().ok_or(Error::InvalidArgument)?;
+
+            map.insert(address, name, None);
+        }
+
+        Ok(map)
+    }
+
+    /// Parses the contents of `/proc/kallsyms` or `System.map` of a Linux guest, where each line
+    /// consists of the address in hexadecimal, the symbol type and the name, optionally followed
+    /// by the module name. Only symbols in the text and data sections are added.
+    pub fn from_kallsyms<R: BufRead>(reader: R) -> Result<Self, Error> {
+        let mut map = Self::new();
+
+        for line in reader.lines() {
+            let line = line?;
+            let mut fields = line.split_whitespace();
+
+            let address = match fields.next() {
+                Some(address) => parse_address(address)?,
+                _ => continue,
+            };
+
+            let kind = fields.next().ok_or(Error::InvalidArgument)?;
+            let name = fields.next().ok_or(Error::InvalidArgument)?;
+
+            if !matches!(kind, "T" | "t" | "W" | "w" | "D" | "d" | "R" | "r" | "B" | "b") {
+                continue;
+            }
+
+            // Symbols of kernel modules are suffixed with the module name, e.g. `[kvm]`.
+            match fields.next() {
+                Some(module) => map.insert(address, &format!("{} {}", name, module), None),
+                _ => map.insert(address, name, None),
+            }
+        }
+
+        Ok(map)
+    }
+
+    /// Parses the function and data object symbols from the symbol table of the given
+    /// little-endian ELF file. The addresses are the virtual addresses as linked, so the symbols
+    /// of a relocated image must be offset by the caller.
+    pub fn from_elf(bytes: &[u8]) -> Result<Self, Error> {
+        let elf = Elf::parse(bytes)?;
+        let mut map = Self::new();
+
+        for index in 0..elf.section_count {
+            let section = elf.section(index)?;
+
+            if section.kind != SHT_SYMTAB || section.entry_size == 0 {
+                continue;
+            }
+
+            let strings = elf.section(section.link as usize)?;
+
+            for offset in (0..section.size).step_by(section.entry_size as usize) {
+                let base = section.offset.checked_add(offset).ok_or(Error::InvalidArgument)?;
+                let symbol = elf.symbol(base)?;
+
+                if symbol.value == 0 || !matches!(symbol.info & 0xf, STT_OBJECT | STT_FUNC) {
+                    continue;
+                }
+
+                let name = strings.offset
+                    .checked_add(symbol.name as u64)
+                    .ok_or(Error::InvalidArgument)?;
+                let name = elf.string(name)?;
+
+                if name.is_empty() {
+                    continue;
+                }
+
+                let size = if symbol.size == 0 { None } else { Some(symbol.size) };
+
+                map.insert(symbol.value, name, size);
+            }
+        }
+
+        Ok(map)
+    }
+
+    /// Looks up the symbol containing the given address. Returns the symbol and the offset of the
+    /// address into the symbol, or `None` if there is no such symbol. Symbols of unknown size are
+    /// assumed to extend up to the next symbol.
+    pub fn lookup(&self, address: u64) -> Option<(&Symbol, u64)> {
+        let (start, symbol) = self.symbols.range(..=address).next_back()?;
+        let offset = address - start;
+
+        match symbol.size {
+            Some(size) if offset >= size => None,
+            _ => Some((symbol, offset)),
+        }
+    }
+
+    /// Formats the given address as `name+0xoffset`, or returns `None` if there is no symbol
+    /// containing the address.
+    pub fn symbolize(&self, address: u64) -> Option<String> {
+        let (symbol, offset) = self.lookup(address)?;
+
+        Some(format!("{}+{:#x}", symbol.name, offset))
+    }
+}
+
+/// Parses an address in hexadecimal with an optional `0x` prefix.
+fn parse_address(s: &str) -> Result<u64, Error> {
+    let s = s.trim_start_matches("0x");
+
+    u64::from_str_radix(s, 16).map_err(|_| Error::InvalidArgument)
+}
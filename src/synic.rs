@@ -0,0 +1,55 @@
+//! This module provides the types for the synthetic interrupt controller (SynIC), which is part of
+//! the Hyper-V enlightenments. The SynIC extends the local APIC with sixteen synthetic interrupt
+//! sources (SINTs), each of which can receive messages through the message page (SIMP) and events
+//! through the event flags page (SIEFP). Enlightened guests, e.g. Windows guests with the VMBus
+//! drivers, use the SynIC to communicate with the host far more efficiently than through emulated
+//! devices.
+//!
+//! The SynIC is enabled through [`crate::VmBuilder::with_synthetic_interrupts`], after which the
+//! guest can program the synthetic MSRs (see [`crate::arch::x86_64::HV_X64_MSR_SCONTROL`] and
+//! friends), and the host can deliver messages and events through
+//! [`crate::Vm::post_synthetic_message`] and [`crate::Vm::signal_synthetic_event`].
+//!
+//! This is currently only supported on Microsoft Windows.
+
+/// The number of synthetic interrupt sources per virtual CPU.
+pub const SINT_COUNT: u8 = 16;
+
+/// The maximum size of the payload of a synthetic message in bytes.
+pub const MESSAGE_PAYLOAD_SIZE: usize = 240;
+
+/// The size of a synthetic message, including its header, in bytes.
+pub const MESSAGE_SIZE: usize = 256;
+
+/// The number of event flags per synthetic interrupt source.
+pub const EVENT_FLAG_COUNT: u16 = 2048;
+
+/// Represents a message to post to a synthetic interrupt source of a virtual CPU.
+#[derive(Clone, Debug)]
+pub struct SyntheticMessage {
+    /// The type of the message. The values below `0x8000_0000` are reserved for the hypervisor.
+    pub message_type: u32,
+    /// The ID of the sender.
+    pub sender: u64,
+    /// The payload of the message, which must be at most [`MESSAGE_PAYLOAD_SIZE`] bytes.
+    pub payload: Vec<u8>,
+}
+
+impl SyntheticMessage {
+    /// Encodes the message into the `HV_MESSAGE` layout expected by the guest. Returns `None` if
+    /// the payload is too large.
+    pub fn to_bytes(&self) -> Option<[u8; MESSAGE_SIZE]> {
+        if self.payload.len() > MESSAGE_PAYLOAD_SIZE {
+            return None;
+        }
+
+        let mut bytes = [0u8; MESSAGE_SIZE];
+
+        bytes[0..4].copy_from_slice(&self.message_type.to_le_bytes());
+        bytes[4] = self.payload.len() as u8;
+        bytes[8..16].copy_from_slice(&self.sender.to_le_bytes());
+        bytes[16..16 + self.payload.len()].copy_from_slice(&self.payload);
+
+        Some(bytes)
+    }
+}
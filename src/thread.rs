@@ -0,0 +1,140 @@
+//! This module provides the [`ThreadPriority`] type which describes the scheduling priority of the
+//! threads running the virtual CPUs. Since [`crate::Vcpu::run`] consumes the calling thread, the
+//! priority is applied to the thread that runs the virtual CPU the first time it enters the guest.
+//!
+//! Elevating the priority of a thread usually requires additional privileges:
+//!  * On Linux and FreeBSD, real-time scheduling (`SCHED_FIFO`) requires the `CAP_SYS_NICE`
+//!    capability, a sufficient `RLIMIT_RTPRIO` or root privileges.
+//!  * On Microsoft Windows, the priority class applies to the whole process and is therefore left
+//!    untouched. Real-time priorities require the embedder to put the process into the
+//!    `REALTIME_PRIORITY_CLASS`, which requires the `SeIncreaseBasePriorityPrivilege`.
+//!  * On Mac OS X, time-constraint threads do not require any privileges, but the kernel may
+//!    demote threads that exceed their computation budget.
+//!
+//! When the requested priority cannot be applied, the implementation falls back to the next best
+//! priority rather than failing, and records why in the [`ThreadPriorityReport`].
+//!
+//! In addition, the threads running the virtual CPUs can be placed into a [`ResourceGroup`] that
+//! limits their CPU time and the CPUs they run on, such that the guest cannot starve the services
+//! of the host. The resource group is configured through
+//! [`crate::VmBuilder::with_vcpu_resource_group`], and other threads, e.g. the threads running the
+//! emulated devices, can be added through [`ResourceGroup::add_current_thread`]. This maps to:
+//!  * A threaded cgroup on Linux, which requires the cgroup v2 hierarchy mounted at
+//!    `/sys/fs/cgroup`, write access to the parent cgroup and the `cpu` and `cpuset` controllers
+//!    enabled in the parent cgroup. The threads can only be moved into a cgroup that is part of
+//!    the threaded subtree of the cgroup of the process.
+//!  * A job object on Microsoft Windows. As job objects contain processes rather than threads,
+//!    the CPU quota applies to the whole process, while the CPU affinity is applied to the added
+//!    threads only.
+//!
+//! Resource groups are not supported on Mac OS X and FreeBSD.
+
+use crate::error::Error;
+use crate::platform;
+
+/// The scheduling priority of the thread running a virtual CPU.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Deserialize, serde::Serialize))]
+pub enum ThreadPriority {
+    /// Leave the scheduling priority of the thread untouched.
+    Normal,
+    /// Run the thread at an elevated, but not real-time, priority. This maps to a negative nice
+    /// value on Linux, `THREAD_PRIORITY_HIGHEST` on Microsoft Windows and the user-interactive QoS
+    /// class on Mac OS X. This is not supported on FreeBSD, where the nice value applies to the
+    /// whole process.
+    Elevated,
+    /// Run the thread with a real-time scheduling policy. This maps to `SCHED_FIFO` on Linux and
+    /// FreeBSD, `THREAD_PRIORITY_TIME_CRITICAL` on Microsoft Windows if the process runs in the
+    /// `REALTIME_PRIORITY_CLASS` and the time-constraint policy on Mac OS X. The priority is a
+    /// value between 1 and 99 that gets clamped to the range supported by the platform.
+    RealTime {
+        /// The real-time priority of the thread.
+        priority: u8,
+    },
+}
+
+impl Default for ThreadPriority {
+    fn default() -> Self {
+        Self::Normal
+    }
+}
+
+/// Describes the outcome of applying a [`ThreadPriority`] to the current thread.
+#[derive(Clone, Debug)]
+pub struct ThreadPriorityReport {
+    /// The priority that was requested.
+    pub requested: ThreadPriority,
+    /// The priority that was actually applied. This may be lower than the requested priority if
+    /// the process lacks the privileges to apply the requested priority.
+    pub applied: ThreadPriority,
+    /// Human-readable diagnostics describing why the requested priority could not be applied.
+    pub diagnostics: Vec<String>,
+}
+
+impl ThreadPriorityReport {
+    /// Returns `true` if the requested priority was applied without falling back.
+    pub fn is_satisfied(&self) -> bool {
+        self.requested == self.applied
+    }
+}
+
+/// Applies the given [`ThreadPriority`] to the current thread. Failures caused by a lack of
+/// privileges result in a fallback that is described by the returned [`ThreadPriorityReport`],
+/// while any other failure results in an error.
+pub fn set_current_thread_priority(
+    priority: ThreadPriority,
+) -> Result<ThreadPriorityReport, Error> {
+    if priority == ThreadPriority::Normal {
+        return Ok(ThreadPriorityReport {
+            requested: priority,
+            applied: priority,
+            diagnostics: vec![],
+        });
+    }
+
+    platform::thread::set_current_thread_priority(priority)
+}
+
+/// The resource limits of a [`ResourceGroup`].
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Deserialize, serde::Serialize))]
+pub struct ResourceLimits {
+    /// The maximum CPU time of the threads as a percentage of a single CPU, e.g. 150 limits the
+    /// threads to one and a half CPUs. Unlimited if `None`.
+    pub cpu_quota: Option<u32>,
+    /// The indices of the CPUs the threads may run on. Any CPU if `None`.
+    pub cpu_affinity: Option<Vec<usize>>,
+}
+
+/// A group of threads that is subject to [`ResourceLimits`], i.e. a cgroup on Linux or a job
+/// object on Microsoft Windows. See [`crate::thread`] for the requirements on each platform.
+pub struct ResourceGroup {
+    /// The internal platform-specific implementation of the resource group.
+    inner: platform::thread::ResourceGroup,
+}
+
+impl ResourceGroup {
+    /// Creates the resource group with the given name and applies the given limits. On Linux, the
+    /// name is the path of the cgroup relative to `/sys/fs/cgroup`, and an existing cgroup is
+    /// reused. On Microsoft Windows, the name is the name of the job object.
+    pub fn new(name: &str, limits: &ResourceLimits) -> Result<Self, Error> {
+        if limits.cpu_quota == Some(0) {
+            return Err(Error::InvalidArgument);
+        }
+
+        if let Some(cpus) = &limits.cpu_affinity {
+            if cpus.is_empty() {
+                return Err(Error::InvalidArgument);
+            }
+        }
+
+        Ok(Self {
+            inner: platform::thread::ResourceGroup::new(name, limits)?,
+        })
+    }
+
+    /// Adds the current thread to the resource group.
+    pub fn add_current_thread(&self) -> Result<(), Error> {
+        self.inner.add_current_thread()
+    }
+}
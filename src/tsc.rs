@@ -0,0 +1,81 @@
+//! This module provides the [`TscMode`] type to configure how the virtual CPUs observe the time
+//! stamp counter (TSC). By default the guest reads the TSC of the host (offset by the hypervisor),
+//! which makes the guest observe wall-clock time. For record/replay and fuzzing, the
+//! [`TscMode::Deterministic`] mode instead serves a virtual TSC that only advances in response to
+//! events in the guest, such that two runs of the same guest observe the same TSC values.
+//!
+//! The hypervisor APIs do not expose the number of retired guest instructions. Instead, the
+//! virtual TSC advances by a fixed step on every read of the TSC and on every VM exit, which are
+//! both deterministic for a deterministic guest.
+//!
+//! Not all platforms support intercepting `rdtsc` and `rdtscp`:
+//!  * Mac OS X uses RDTSC exiting in the VMCS.
+//!  * Microsoft Windows uses the RDTSC extended VM exit.
+//!  * Linux and FreeBSD do not support the deterministic mode, as neither KVM nor bhyve support
+//!    intercepting `rdtsc`.
+
+/// Describes how the virtual CPUs observe the time stamp counter.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Deserialize, serde::Serialize))]
+pub enum TscMode {
+    /// The guest reads the TSC of the host.
+    Native,
+    /// The guest reads a virtual TSC that is controlled by the host.
+    Deterministic {
+        /// The initial value of the virtual TSC.
+        initial: u64,
+        /// The number of ticks to advance the virtual TSC by on every read.
+        step_per_read: u64,
+        /// The number of ticks to advance the virtual TSC by on every VM exit.
+        step_per_exit: u64,
+    },
+}
+
+impl Default for TscMode {
+    fn default() -> Self {
+        Self::Native
+    }
+}
+
+/// The state of the virtual TSC of a single virtual CPU.
+#[cfg(any(target_os = "macos", target_os = "windows"))]
+#[derive(Clone, Debug)]
+pub(crate) struct VirtualTsc {
+    /// The current value of the virtual TSC.
+    pub(crate) value: u64,
+    /// The number of ticks to advance the virtual TSC by on every read.
+    step_per_read: u64,
+    /// The number of ticks to advance the virtual TSC by on every VM exit.
+    step_per_exit: u64,
+}
+
+#[cfg(any(target_os = "macos", target_os = "windows"))]
+impl VirtualTsc {
+    /// Creates the virtual TSC state for the given mode, or `None` if the mode is
+    /// [`TscMode::Native`].
+    pub(crate) fn new(mode: TscMode) -> Option<Self> {
+        match mode {
+            TscMode::Native => None,
+            TscMode::Deterministic { initial, step_per_read, step_per_exit } => Some(Self {
+                value: initial,
+                step_per_read,
+                step_per_exit,
+            }),
+        }
+    }
+
+    /// Returns the value the guest should observe for an `rdtsc` or `rdtscp` and advances the
+    /// virtual TSC.
+    pub(crate) fn read(&mut self) -> u64 {
+        let value = self.value;
+
+        self.value = self.value.wrapping_add(self.step_per_read);
+
+        value
+    }
+
+    /// Advances the virtual TSC for a VM exit.
+    pub(crate) fn on_exit(&mut self) {
+        self.value = self.value.wrapping_add(self.step_per_exit);
+    }
+}
@@ -0,0 +1,216 @@
+//! This module provides the types used by [`crate::Vcpu::walk_stack`] to walk the stack of the
+//! guest, e.g. to get a quick backtrace when the guest crashes or hangs.
+//!
+//! The stack is walked by following the chain of frame pointers through guest virtual memory,
+//! which requires the guest code to be compiled with frame pointers. The guest virtual addresses
+//! are translated using the page tables of the guest as configured in the virtual CPU. When the
+//! guest does not use frame pointers, or when the current frame has not been set up yet, e.g.
+//! upon entry to a function, an [`UnwindHint`] can be passed to
+//! [`crate::Vcpu::walk_stack_with_hint`] to start the walk from a known frame instead.
+//!
+//! Stack walking is best effort: the walk stops at the first frame pointer that is null, that
+//! does not point further up the stack or that cannot be read.
+//!
+//! This is currently only supported on the x86 architecture.
+
+use crate::arch::x86_64::{ControlRegister, CpuRegs, MSR_IA32_EFER};
+use crate::error::Error;
+use crate::platform;
+use std::convert::TryInto;
+
+/// Describes the frame to start walking the stack from.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct UnwindHint {
+    /// The instruction pointer of the innermost frame, which is reported as the first frame.
+    pub pc: u64,
+    /// The frame pointer of the innermost frame, i.e. the guest virtual address of the saved
+    /// frame pointer of the caller, followed by the return address.
+    pub frame_pointer: u64,
+    /// The size of the saved frame pointer and return address in bytes, which must be 4 or 8.
+    pub word_size: usize,
+}
+
+/// The paging state of a virtual CPU of the x86 architecture, which is used to translate guest
+/// virtual addresses into guest physical addresses.
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct PagingState {
+    pub cr0: u64,
+    pub cr3: u64,
+    pub cr4: u64,
+    pub efer: u64,
+}
+
+/// The present bit of a page table entry.
+const PTE_PRESENT: u64 = 1 << 0;
+/// The page size bit of a page table entry, which indicates that the entry maps a large page.
+const PTE_PAGE_SIZE: u64 = 1 << 7;
+/// The mask of the physical address in a page table entry.
+const PTE_ADDRESS_MASK: u64 = 0x000f_ffff_ffff_f000;
+
+impl PagingState {
+    /// Reads the paging state from the given virtual CPU.
+    pub fn new(vcpu: &dyn CpuRegs) -> Result<Self, Error> {
+        let values = vcpu.get_control_registers(&[
+            ControlRegister::Cr0,
+            ControlRegister::Cr3,
+            ControlRegister::Cr4,
+        ])?;
+        let efer = vcpu.get_msrs(&[MSR_IA32_EFER])?[0];
+
+        Ok(Self {
+            cr0: values[0],
+            cr3: values[1],
+            cr4: values[2],
+            efer,
+        })
+    }
+
+    /// Translates the guest virtual address into a guest physical address by walking the page
+    /// tables of the guest.
+    pub fn translate(&self, vm: &platform::Vm, address: u64) -> Result<u64, Error> {
+        use crate::arch::x86_64::{CR0_PG, CR4_LA57, CR4_PAE, CR4_PSE, EFER_LMA};
+
+        // Without paging, guest virtual addresses are guest physical addresses.
+        if self.cr0 & CR0_PG == 0 {
+            return Ok(address);
+        }
+
+        // Determine the root of the page table hierarchy, the shift of each level and the size
+        // of the page table entries for the paging mode.
+        let long_mode = self.efer & EFER_LMA != 0;
+        let la57 = long_mode && self.cr4 & CR4_LA57 != 0;
+
+        let (mut table, shifts, entry_size): (u64, &[u32], usize) = if la57 {
+            (self.cr3 & PTE_ADDRESS_MASK, &[48, 39, 30, 21, 12], 8)
+        } else if long_mode {
+            (self.cr3 & PTE_ADDRESS_MASK, &[39, 30, 21, 12], 8)
+        } else if self.cr4 & CR4_PAE != 0 {
+            (self.cr3 & 0xffff_ffe0, &[30, 21, 12], 8)
+        } else {
+            (self.cr3 & 0xffff_f000, &[22, 12], 4)
+        };
+
+        for (level, &shift) in shifts.iter().enumerate() {
+            // The page directory pointer table of PAE paging only has four entries.
+            let bits = match (entry_size, level, shifts.len()) {
+                (4, _, _) => 10,
+                (_, 0, 3) => 2,
+                _ => 9,
+            };
+
+            let index = (address >> shift) & ((1 << bits) - 1);
+
+            let mut bytes = [0u8; 8];
+            vm.read_physical_memory(&mut bytes[..entry_size], table + index * entry_size as u64)?;
+            let entry = u64::from_le_bytes(bytes);
+
+            if entry & PTE_PRESENT == 0 {
+                return Err(Error::PageNotPresent);
+            }
+
+            // Check if the entry maps a large page. 32-bit paging only supports large pages if
+            // CR4.PSE is set. Otherwise, only the page directory entries and the PDPTEs of 4-level
+            // and 5-level paging map large pages.
+            let large = shift != 12 && entry & PTE_PAGE_SIZE != 0 && match entry_size {
+                4 => self.cr4 & CR4_PSE != 0,
+                _ => shift == 21 || (shift == 30 && shifts.len() > 3),
+            };
+
+            if large {
+                let mask = (1 << shift) - 1;
+
+                return Ok((entry & PTE_ADDRESS_MASK & !mask) | (address & mask));
+            }
+
+            table = entry & PTE_ADDRESS_MASK;
+        }
+
+        Ok(table | (address & 0xfff))
+    }
+
+    /// Reads the bytes at the given guest virtual address.
+    pub fn read_virtual_memory(
+        &self,
+        vm: &platform::Vm,
+        bytes: &mut [u8],
+        address: u64,
+    ) -> Result<(), Error> {
+        let mut offset = 0;
+
+        // Read the bytes page by page, as the pages may not be physically contiguous.
+        while offset < bytes.len() {
+            let address = address.wrapping_add(offset as u64);
+            let size = (0x1000 - (address & 0xfff) as usize).min(bytes.len() - offset);
+            let phys_addr = self.translate(vm, address)?;
+
+            let read = vm.read_physical_memory(&mut bytes[offset..offset + size], phys_addr)?;
+
+            if read == 0 {
+                return Err(Error::InvalidGuestAddress);
+            }
+
+            offset += read;
+        }
+
+        Ok(())
+    }
+
+    /// Walks the stack of the guest starting at the frame described by the hint. Returns the
+    /// instruction pointer of the innermost frame followed by the return addresses of at most
+    /// `max_frames - 1` of its callers.
+    pub fn walk_stack(
+        &self,
+        vm: &platform::Vm,
+        hint: &UnwindHint,
+        max_frames: usize,
+    ) -> Result<Vec<u64>, Error> {
+        if hint.word_size != 4 && hint.word_size != 8 {
+            return Err(Error::InvalidArgument);
+        }
+
+        let mut frames = vec![];
+
+        if max_frames == 0 {
+            return Ok(frames);
+        }
+
+        frames.push(hint.pc);
+
+        let mut frame_pointer = hint.frame_pointer;
+
+        while frames.len() < max_frames && frame_pointer != 0 {
+            // Read the saved frame pointer and the return address.
+            let mut bytes = [0u8; 16];
+
+            if self.read_virtual_memory(vm, &mut bytes[..hint.word_size * 2], frame_pointer).is_err() {
+                break;
+            }
+
+            let (next, return_address) = match hint.word_size {
+                4 => (
+                    u32::from_le_bytes(bytes[0..4].try_into().unwrap()) as u64,
+                    u32::from_le_bytes(bytes[4..8].try_into().unwrap()) as u64,
+                ),
+                _ => (
+                    u64::from_le_bytes(bytes[0..8].try_into().unwrap()),
+                    u64::from_le_bytes(bytes[8..16].try_into().unwrap()),
+                ),
+            };
+
+            if return_address == 0 {
+                break;
+            }
+
+            frames.push(return_address);
+
+            // The stack grows downwards, so the frame of the caller must be at a higher address.
+            if next <= frame_pointer {
+                break;
+            }
+
+            frame_pointer = next;
+        }
+
+        Ok(frames)
+    }
+}
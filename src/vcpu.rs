@@ -1,51 +1,788 @@
 //! This modules provides the [`Vcpu`] struct which represents a single virtual CPU that is part of
 //! the VM.
 
+use bitflags::bitflags;
+use crate::debug::{DebugExit, DebugExitKind, GuestDebug, HwBreakpoint};
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+use crate::devices::apic::ApicBus;
 use crate::error::Error;
+use crate::hypercall::{Hypercall, HypercallTable, Hypercalls};
 use crate::platform;
+use crate::state::VcpuState;
+use crate::symbols::SymbolMap;
+use crate::thread::{self, ResourceGroup, ThreadPriority, ThreadPriorityReport};
+use crate::vm::ProtectionFlags;
+#[cfg(feature = "xen")]
+use crate::xen::XenHypercall;
+use std::sync::{Arc, Condvar, Mutex, RwLock};
+use std::thread::{JoinHandle, ThreadId};
+use std::time::{Duration, Instant};
 
 /// The exit reason that describes why [`Vcpu::run`] quit.
 #[derive(Debug)]
 pub enum ExitReason<'a> {
     /// The virtual CPU executed an `out` instruction on the given port with the given data.
     IoOut { port: u16, data: &'a [u8] },
-    /// The virtual CPU exected an `in` instruction on the given port. The `data` slice should be
-    /// filled with data before calling [`Vcpu::run`] to resume execution of the virtual CPU.
-    IoIn { port: u16, data: &'a [u8] },
-    /// The virtual CPU tried to read from the given MMIO address. The `data` slice should be
-    /// filled with data before calling [`Vcpu::run`] to resume execution of the virtual CPU.
-    MmioRead { address: u64, data: &'a [u8] },
+    /// The virtual CPU exected an `in` instruction on the given port. The data should be supplied
+    /// by filling the `data` slice or through [`Vcpu::complete_io`] before calling [`Vcpu::run`]
+    /// to resume execution of the virtual CPU.
+    IoIn { port: u16, data: &'a mut [u8] },
+    /// The virtual CPU tried to read from the given MMIO address. The data should be supplied by
+    /// filling the `data` slice or through [`Vcpu::complete_mmio_read`] before calling
+    /// [`Vcpu::run`] to resume execution of the virtual CPU.
+    MmioRead { address: u64, data: &'a mut [u8] },
     /// The virtual CPU tried to write the given data to the given MMIO address.
     MmioWrite { address: u64, data: &'a [u8] },
-    /// The virtual CPU tried accessing an invalid guest physical address.
+    /// The virtual CPU tried to write the given data to the given address of a region mapped
+    /// with [`crate::ProtectionFlags::ROM`]. The write has been discarded, and the instruction
+    /// pointer has already been moved past the instruction, such that calling [`Vcpu::run`]
+    /// resumes the virtual CPU as if the write went to a real ROM. This is not reported on
+    /// FreeBSD, where guest physical memory is always writable.
+    RomWrite { address: u64, data: &'a [u8] },
+    /// The virtual CPU tried accessing an invalid guest physical address. This is reported for
+    /// both EPT violations on Intel VMX and nested page faults on AMD SVM. The guest virtual
+    /// address is zero if the hypervisor does not report it, e.g. on FreeBSD.
     InvalidMemoryAccess { gpa: u64, gva: usize },
-    /// The virtual CPU executed the `hlt` instruction.
+    /// The virtual CPU executed the `hlt` instruction, or the `wfi` instruction on AArch64.
     Halted,
+    /// The guest is able to accept interrupts, i.e. interrupts are enabled and there is no
+    /// interrupt shadow, as requested through [`Vcpu::request_interrupt_window`].
+    InterruptWindow,
+    /// The guest wrote to the `IA32_APIC_BASE` MSR, which may relocate, enable or disable the
+    /// local APIC. The VMM should move the MMIO range of its emulated local APIC to
+    /// [`ApicBase::mmio_range`]. On Linux, KVM handles the write itself, and the change is
+    /// reported by the call to [`Vcpu::run`] following the exit that observed it, before
+    /// re-entering the guest. This is not reported on Microsoft Windows and FreeBSD, where the
+    /// hypervisor emulates the local APIC and handles the relocation itself.
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    ApicBaseChanged(ApicBase),
+    /// The virtual CPU executed the `cpuid` instruction with the given leaf in `eax` and subleaf
+    /// in `ecx`, as enabled through [`crate::VmBuilder::with_cpuid_exits`]. The instruction must
+    /// be completed through [`Vcpu::complete_cpuid`] before calling [`Vcpu::run`]. Otherwise the
+    /// virtual CPU executes the instruction again upon resuming.
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    Cpuid { leaf: u32, subleaf: u32 },
+    /// The virtual CPU executed the `rdmsr` instruction for the given MSR, as enabled through
+    /// [`crate::VmBuilder::with_msr_exits`]. The instruction pointer has already been moved past
+    /// the instruction, and the value must be supplied through [`Vcpu::complete_msr_read`]
+    /// before calling [`Vcpu::run`]. Otherwise the guest reads zero.
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    MsrRead { msr: u32 },
+    /// The virtual CPU executed the `wrmsr` instruction to write the given value to the given MSR,
+    /// as enabled through [`crate::VmBuilder::with_msr_exits`]. The instruction pointer has
+    /// already been moved past the instruction.
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    MsrWrite { msr: u32, value: u64 },
+    /// The virtual CPU accessed the given control register, as enabled through
+    /// [`crate::VmBuilder::with_cr_exits`]. The instruction pointer has already been moved past
+    /// the instruction. For writes, `value` is the value the guest tried to write, which has not
+    /// been written to the control register yet, such that the VMM can apply it through
+    /// [`CpuRegs::set_control_registers`] or keep a shadow copy instead. The `clts` and `lmsw`
+    /// instructions are reported as writes to CR0 without a register. For reads, the current
+    /// value of the control register has already been written to the given register, and is
+    /// reported as `value`, such that the VMM can replace it by writing the register.
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    CrAccess { cr: ControlRegister, write: bool, value: u64, register: Option<Register> },
+    /// The guest lowered its task priority class below the threshold configured through
+    /// [`Vcpu::set_tpr_threshold`], such that pending interrupts may be deliverable.
+    TprBelowThreshold,
     /// The virtual CPU raised an exception that was not handled by the guest. This is also known
     /// as a triple fault on the x86(-64) architecture, as both the original exception handler and
     /// double fault handler were not able to handle the exception. Some implementations may leave
-    /// the virtual CPU in an undefined state or reset the virtual CPU state (e.g. KVM when using
-    /// AMD SVM). Therefore, you should not rely on the virtual CPU state in the event of an
-    /// unhandled exception.
+    /// the virtual CPU in an undefined state or reset the virtual CPU state, as the shutdown
+    /// intercept of AMD SVM does not preserve the state of the guest, e.g. KVM reinitializes it
+    /// and bhyve leaves it undefined. Therefore, you should not rely on the virtual CPU state in
+    /// the event of an unhandled exception.
     UnhandledException,
+    /// The virtual CPU single-stepped or hit a breakpoint configured through
+    /// [`Vcpu::set_guest_debug`].
+    Debug(DebugExit),
+    /// The virtual CPU raised an exception with the given vector, as selected through
+    /// [`Vcpu::set_exception_exits`]. The error code is only reported for the exceptions that push
+    /// one, and `cr2` is only reported for page faults. The exception has not been delivered to
+    /// the guest, and the instruction pointer still points to the faulting instruction.
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    Exception { vector: u8, error_code: Option<u32>, cr2: Option<u64> },
+    /// The virtual CPU made a Xen hypercall. The hypercall must be completed through
+    /// [`Vcpu::complete_xen_hypercall`] before calling [`Vcpu::run`] to resume execution of the
+    /// virtual CPU.
+    #[cfg(feature = "xen")]
+    XenHypercall(XenHypercall),
+    /// The virtual CPU made a hypercall for which no handler has been registered through
+    /// [`crate::Vm::register_hypercall`]. The instruction pointer has already been moved past the
+    /// hypercall instruction, such that the hypercall can be completed by writing the result
+    /// register before calling [`Vcpu::run`], see [`crate::hypercall`].
+    Hypercall(Hypercall),
+    /// The virtual CPU called the PSCI function with the given function ID and the arguments in
+    /// `x1` to `x3`, e.g. to power on another virtual CPU or to reset the system, see
+    /// [`crate::arch::aarch64::is_psci_function`]. The program counter has already been moved
+    /// past the `hvc` or `smc` instruction, such that the call can be completed by writing the
+    /// return code to `x0` before calling [`Vcpu::run`].
+    ///
+    /// On Linux, KVM implements PSCI itself and only reports `SYSTEM_OFF` and `SYSTEM_RESET`,
+    /// without any arguments.
+    #[cfg(target_arch = "aarch64")]
+    Psci { function: u32, args: [u64; 3] },
+    /// The virtual CPU was forced to exit through [`VcpuHandle::kick`].
+    Canceled,
+    /// The virtual CPU was forced to exit as the timeout passed to [`Vcpu::run_with_timeout`]
+    /// expired.
+    Timeout,
     /// The virtual CPU exited for some unknown reason.
     Unknown,
 }
 
+bitflags! {
+    /// The `ExitPolicy` selects the exits that [`Vcpu::run`] handles internally by resuming the
+    /// virtual CPU, rather than returning them to the caller. This avoids a round trip through
+    /// the caller for exits that the caller is not interested in, which cuts the exit overhead
+    /// for busy guests. The policy is configured through [`Vcpu::set_exit_policy`] and is empty
+    /// by default, i.e. all exits are returned.
+    pub struct ExitPolicy: u32 {
+        /// Resume the virtual CPU upon [`ExitReason::InterruptWindow`].
+        const RESUME_INTERRUPT_WINDOW    = 1 << 0;
+        /// Resume the virtual CPU upon the housekeeping exits that do not require any emulation,
+        /// which would otherwise be reported as [`ExitReason::Unknown`]. These are the expiry of
+        /// the VMX preemption timer on Mac OS X, and the bogus, rendezvous and idle request exits
+        /// of bhyve on FreeBSD. Other unknown exits, e.g. instructions that could not be
+        /// emulated or failed VM entries, are always reported, as resuming the virtual CPU would
+        /// run into the same exit again.
+        const RESUME_HOUSEKEEPING        = 1 << 1;
+        /// Resume the virtual CPU upon [`ExitReason::TprBelowThreshold`].
+        const RESUME_TPR_BELOW_THRESHOLD = 1 << 2;
+    }
+}
+
+impl Default for ExitPolicy {
+    fn default() -> Self {
+        Self::empty()
+    }
+}
+
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+impl<'a> ExitReason<'a> {
+    /// Detaches the exit reason from the virtual CPU by copying the data of an I/O or MMIO exit
+    /// into the given buffer. The data of the detached exit reason is left empty, see
+    /// [`ExitReason::attach`].
+    fn detach(self, buffer: &mut Vec<u8>) -> ExitReason<'static> {
+        buffer.clear();
+
+        match self {
+            Self::IoOut { port, data } => {
+                buffer.extend_from_slice(data);
+                ExitReason::IoOut { port, data: &[] }
+            }
+            Self::IoIn { port, data } => {
+                buffer.extend_from_slice(data);
+                ExitReason::IoIn { port, data: &mut [] }
+            }
+            Self::MmioRead { address, data } => {
+                buffer.extend_from_slice(data);
+                ExitReason::MmioRead { address, data: &mut [] }
+            }
+            Self::MmioWrite { address, data } => {
+                buffer.extend_from_slice(data);
+                ExitReason::MmioWrite { address, data: &[] }
+            }
+            Self::RomWrite { address, data } => {
+                buffer.extend_from_slice(data);
+                ExitReason::RomWrite { address, data: &[] }
+            }
+            Self::InvalidMemoryAccess { gpa, gva } => ExitReason::InvalidMemoryAccess { gpa, gva },
+            Self::Halted => ExitReason::Halted,
+            Self::InterruptWindow => ExitReason::InterruptWindow,
+            Self::ApicBaseChanged(apic_base) => ExitReason::ApicBaseChanged(apic_base),
+            Self::Cpuid { leaf, subleaf } => ExitReason::Cpuid { leaf, subleaf },
+            Self::MsrRead { msr } => ExitReason::MsrRead { msr },
+            Self::MsrWrite { msr, value } => ExitReason::MsrWrite { msr, value },
+            Self::CrAccess { cr, write, value, register } =>
+                ExitReason::CrAccess { cr, write, value, register },
+            Self::TprBelowThreshold => ExitReason::TprBelowThreshold,
+            Self::UnhandledException => ExitReason::UnhandledException,
+            Self::Debug(debug) => ExitReason::Debug(debug),
+            Self::Exception { vector, error_code, cr2 } =>
+                ExitReason::Exception { vector, error_code, cr2 },
+            #[cfg(feature = "xen")]
+            Self::XenHypercall(hypercall) => ExitReason::XenHypercall(hypercall),
+            Self::Hypercall(hypercall) => ExitReason::Hypercall(hypercall),
+            Self::Canceled => ExitReason::Canceled,
+            Self::Timeout => ExitReason::Timeout,
+            Self::Unknown => ExitReason::Unknown,
+        }
+    }
+}
+
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+impl ExitReason<'static> {
+    /// Attaches the data of an exit reason detached through [`ExitReason::detach`] to the given
+    /// buffer, such that the exit reason borrows the buffer rather than the virtual CPU.
+    fn attach(self, buffer: &mut [u8]) -> ExitReason<'_> {
+        match self {
+            Self::IoOut { port, .. } => ExitReason::IoOut { port, data: buffer },
+            Self::IoIn { port, .. } => ExitReason::IoIn { port, data: buffer },
+            Self::MmioRead { address, .. } => ExitReason::MmioRead { address, data: buffer },
+            Self::MmioWrite { address, .. } => ExitReason::MmioWrite { address, data: buffer },
+            Self::RomWrite { address, .. } => ExitReason::RomWrite { address, data: buffer },
+            exit_reason => exit_reason,
+        }
+    }
+}
+
+/// The `VcpuSpec` describes a virtual CPU to create later through a [`VcpuFactory`].
+#[derive(Clone, Debug)]
+pub struct VcpuSpec {
+    /// The vCPU ID.
+    pub id: usize,
+    /// The scheduling priority of the thread running the virtual CPU. Defaults to the priority
+    /// configured through [`crate::VmBuilder::with_vcpu_thread_priority`].
+    pub thread_priority: Option<ThreadPriority>,
+}
+
+impl VcpuSpec {
+    /// Describes the virtual CPU with the given vCPU ID.
+    pub fn new(id: usize) -> Self {
+        Self {
+            id,
+            thread_priority: None,
+        }
+    }
+
+    /// This is used to override the scheduling priority of the thread running the virtual CPU.
+    pub fn with_thread_priority(self, priority: ThreadPriority) -> Self {
+        Self {
+            thread_priority: Some(priority),
+            ..self
+        }
+    }
+}
+
+/// The `VcpuFactory` creates a virtual CPU from a [`VcpuSpec`] on the thread that will run it.
+/// Some platforms, e.g. the Hypervisor Framework on Mac OS X, require a virtual CPU to be created
+/// and run on the same thread. The `VcpuFactory` can be configured on any thread and sent to the
+/// thread that runs the virtual CPU, where [`VcpuFactory::create`] instantiates the virtual CPU.
+pub struct VcpuFactory {
+    /// The internal platform-specific implementation of the [`platform::Vm`] struct.
+    pub(crate) vm: Arc<RwLock<platform::Vm>>,
+    /// The description of the virtual CPU.
+    pub(crate) spec: VcpuSpec,
+    /// The scheduling priority of the thread running the virtual CPU.
+    pub(crate) thread_priority: ThreadPriority,
+    /// The resource group of the thread running the virtual CPU.
+    pub(crate) resource_group: Option<Arc<ResourceGroup>>,
+    /// The symbol map of the VM.
+    pub(crate) symbols: Arc<RwLock<SymbolMap>>,
+    /// The hypercall handlers of the VM.
+    pub(crate) hypercalls: Arc<RwLock<HypercallTable>>,
+    /// The local APICs emulated in user space, if enabled.
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    pub(crate) apic_bus: Option<Arc<ApicBus>>,
+}
+
+impl VcpuFactory {
+    /// Returns the description of the virtual CPU.
+    pub fn spec(&self) -> &VcpuSpec {
+        &self.spec
+    }
+
+    /// Creates the virtual CPU on the current thread. The virtual CPU should be run on the same
+    /// thread.
+    pub fn create(self) -> Result<Vcpu, Error> {
+        let mut vcpu = Vcpu {
+            inner: self.vm.write().unwrap().create_vcpu(self.spec.id)?,
+            vm: self.vm,
+            thread_priority: self.spec.thread_priority.unwrap_or(self.thread_priority),
+            thread_priority_report: None,
+            resource_group: self.resource_group,
+            symbols: self.symbols,
+            hypercalls: self.hypercalls,
+            exit_policy: ExitPolicy::default(),
+            crashed: false,
+            guest_debug: GuestDebug::default(),
+            timer: None,
+            #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+            software_apic: None,
+        };
+
+        vcpu.reset()?;
+
+        #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+        if let Some(bus) = self.apic_bus {
+            let handle = vcpu.handle();
+
+            bus.register(self.spec.id, handle.clone());
+
+            vcpu.software_apic = Some(SoftwareApic {
+                bus,
+                id: self.spec.id,
+                handle,
+                timer: None,
+                exit_data: vec![],
+                pending_read: None,
+            });
+        }
+
+        Ok(vcpu)
+    }
+}
+
+/// A handle to a [`Vcpu`] that can be shared with other threads to force the virtual CPU to exit,
+/// as [`Vcpu::run`] consumes the thread running the virtual CPU. A handle is obtained through
+/// [`Vcpu::handle`].
+#[derive(Clone)]
+pub struct VcpuHandle {
+    /// The internal platform-specific implementation of the [`platform::VcpuHandle`] struct.
+    pub(crate) inner: platform::VcpuHandle,
+}
+
+impl VcpuHandle {
+    /// Forces the virtual CPU to exit promptly with [`ExitReason::Canceled`]. If the virtual CPU
+    /// is not running, the next call to [`Vcpu::run`] returns [`ExitReason::Canceled`] without
+    /// entering the guest instead.
+    ///
+    /// On Linux, this interrupts the thread running the virtual CPU with a real-time signal,
+    /// i.e. `SIGRTMIN`, for which a handler is installed upon the first call to [`Vcpu::run`].
+    /// This is not supported on FreeBSD.
+    pub fn kick(&self) -> Result<(), Error> {
+        self.inner.kick()
+    }
+}
+
+/// The state shared between [`RunTimer`] and its thread.
+#[derive(Default)]
+struct RunTimerState {
+    /// The point in time at which to kick the virtual CPU, if armed.
+    deadline: Option<Instant>,
+    /// Whether the virtual CPU has been kicked since the timer was last armed.
+    fired: bool,
+    /// The error returned by the kick, if any.
+    error: Option<Error>,
+    /// Whether the thread should stop.
+    shutdown: bool,
+}
+
+/// A timer that kicks a virtual CPU once the deadline passes, which implements
+/// [`Vcpu::run_with_timeout`]. The timer runs on a thread of its own, such that it is only spawned
+/// once rather than for every call.
+struct RunTimer {
+    state: Arc<(Mutex<RunTimerState>, Condvar)>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl RunTimer {
+    /// Spawns the thread of the timer to kick the virtual CPU with the given handle.
+    fn new(handle: VcpuHandle) -> Result<Self, Error> {
+        let state = Arc::new((Mutex::new(RunTimerState::default()), Condvar::new()));
+        let shared = state.clone();
+
+        let thread = std::thread::Builder::new()
+            .name("hy-rs-vcpu-timer".to_string())
+            .spawn(move || {
+                let (lock, condvar) = &*shared;
+                let mut state = lock.lock().unwrap();
+
+                while !state.shutdown {
+                    let now = Instant::now();
+
+                    state = match state.deadline {
+                        Some(deadline) if deadline <= now => {
+                            state.deadline = None;
+
+                            match handle.kick() {
+                                Ok(()) => state.fired = true,
+                                Err(e) => state.error = Some(e),
+                            }
+
+                            state
+                        }
+                        Some(deadline) => condvar.wait_timeout(state, deadline - now).unwrap().0,
+                        None => condvar.wait(state).unwrap(),
+                    };
+                }
+            })?;
+
+        Ok(Self {
+            state,
+            thread: Some(thread),
+        })
+    }
+
+    /// Arms the timer to kick the virtual CPU after the given timeout.
+    fn arm(&self, timeout: Duration) {
+        let (lock, condvar) = &*self.state;
+        let mut state = lock.lock().unwrap();
+
+        state.deadline = Some(Instant::now() + timeout);
+        state.fired = false;
+        condvar.notify_one();
+    }
+
+    /// Disarms the timer. Returns whether the timer kicked the virtual CPU, or the error the kick
+    /// failed with.
+    fn disarm(&self) -> Result<bool, Error> {
+        let mut state = self.state.0.lock().unwrap();
+
+        state.deadline = None;
+
+        match state.error.take() {
+            Some(e) => Err(e),
+            _ => Ok(std::mem::take(&mut state.fired)),
+        }
+    }
+}
+
+/// The local APIC of a virtual CPU that is emulated in user space, see [`crate::devices::apic`].
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+struct SoftwareApic {
+    /// The local APICs of the VM.
+    bus: Arc<ApicBus>,
+    /// The vCPU ID, which is also the local APIC ID.
+    id: usize,
+    /// The handle of the virtual CPU, which is kept here such that the local APIC can handle an
+    /// exit without borrowing the virtual CPU.
+    handle: VcpuHandle,
+    /// The timer that kicks the virtual CPU once the timer of the local APIC expires, which is
+    /// spawned upon the first time the timer of the local APIC is armed.
+    timer: Option<RunTimer>,
+    /// The data of the last I/O or MMIO exit, which is moved out of the virtual CPU such that the
+    /// exit does not borrow the virtual CPU while it is handled, see [`ExitReason::detach`].
+    exit_data: Vec<u8>,
+    /// The kind of access that the data of the last exit completes upon the next run, if any.
+    pending_read: Option<DetachedRead>,
+}
+
+/// The kind of access that the data of a detached exit completes, see [`ExitReason::detach`].
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum DetachedRead {
+    /// An `in` instruction, see [`Vcpu::complete_io`].
+    Io,
+    /// An MMIO read, see [`Vcpu::complete_mmio_read`].
+    Mmio,
+}
+
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+impl SoftwareApic {
+    /// Handles the given exit on behalf of the local APIC. Returns whether the exit has been
+    /// handled, i.e. the virtual CPU should be resumed rather than reporting the exit.
+    fn handle_exit(&mut self, exit_reason: &ExitReason) -> Result<bool, Error> {
+        let fired = match self.timer.as_ref() {
+            Some(timer) => timer.disarm()?,
+            _ => false,
+        };
+
+        let kicked = self.bus.take_kicked(self.id);
+        let canceled = matches!(exit_reason, ExitReason::Canceled);
+
+        // The virtual CPU exited by itself before the kick arrived, so discard the kick rather
+        // than cancelling the next run.
+        if (fired || kicked) && !canceled {
+            self.handle.inner.clear_kick();
+        }
+
+        Ok(match exit_reason {
+            ExitReason::MmioRead { address, .. } =>
+                self.bus.mmio_read(self.id, *address, &mut self.exit_data),
+            ExitReason::MmioWrite { address, .. } =>
+                self.bus.mmio_write(self.id, *address, &self.exit_data)?,
+            ExitReason::Canceled => fired || kicked,
+            ExitReason::Halted => self.bus.has_interrupt(self.id),
+            ExitReason::ApicBaseChanged(apic_base) => {
+                self.bus.set_base(self.id, apic_base.address);
+
+                false
+            }
+            _ => false,
+        })
+    }
+}
+
+impl Drop for RunTimer {
+    fn drop(&mut self) {
+        let (lock, condvar) = &*self.state;
+
+        lock.lock().unwrap().shutdown = true;
+        condvar.notify_one();
+
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
 /// The `Vcpu` struct represents a virtual CPU that is part of the VM.
 pub struct Vcpu {
     /// The internal platform-specific implementation of the [`platform::Vcpu`] struct.
     pub(crate) inner: platform::Vcpu,
+    /// The VM the virtual CPU belongs to, which is used to access guest memory.
+    pub(crate) vm: Arc<RwLock<platform::Vm>>,
+    /// The scheduling priority of the thread running the virtual CPU.
+    pub(crate) thread_priority: ThreadPriority,
+    /// The thread the scheduling priority was last applied to and the outcome.
+    pub(crate) thread_priority_report: Option<(ThreadId, ThreadPriorityReport)>,
+    /// The resource group of the thread running the virtual CPU.
+    pub(crate) resource_group: Option<Arc<ResourceGroup>>,
+    /// The symbol map of the VM.
+    pub(crate) symbols: Arc<RwLock<SymbolMap>>,
+    /// The hypercall handlers of the VM.
+    pub(crate) hypercalls: Arc<RwLock<HypercallTable>>,
+    /// The exits that are handled internally by resuming the virtual CPU.
+    pub(crate) exit_policy: ExitPolicy,
+    /// Whether the last exit was [`ExitReason::UnhandledException`].
+    pub(crate) crashed: bool,
+    /// The debugging features configured through [`Vcpu::set_guest_debug`].
+    guest_debug: GuestDebug,
+    /// The timer of [`Vcpu::run_with_timeout`], which is spawned upon the first call.
+    timer: Option<Arc<RunTimer>>,
+    /// The local APIC emulated in user space, if enabled.
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    software_apic: Option<SoftwareApic>,
 }
 
 impl Vcpu {
     /// Consumes the current thread to run the virtual CPU until the next exit point. This
     /// function returns an [`ExitReason`] to describe why the virtual CPU exited.
+    ///
+    /// With [`crate::VmBuilder::with_software_apic`] enabled, the accesses to the MMIO page of the
+    /// local APIC, the exits caused by the timer of the local APIC or by IPIs, and `hlt` while an
+    /// interrupt is pending are handled without reporting them. The virtual CPUs other than the
+    /// bootstrap processor report [`ExitReason::Halted`] without entering the guest until they
+    /// receive a startup IPI, see [`Vcpu::wait_for_interrupt`].
     pub fn run(&mut self) -> Result<ExitReason, Error> {
-        self.inner.run()
+        self.apply_thread_priority()?;
+
+        #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+        if self.software_apic.is_some() {
+            return self.run_with_software_apic();
+        }
+
+        self.run_once()
     }
 
-    #[cfg(target_arch = "x86_64")]
+    /// Runs the virtual CPU until the next exit point.
+    fn run_once(&mut self) -> Result<ExitReason, Error> {
+        let hypercalls = Hypercalls {
+            table: &self.hypercalls,
+            vm: &self.vm,
+        };
+
+        let mut exit_reason = self.inner.run(self.exit_policy, &hypercalls)?;
+
+        // Guest writes to ROM exit like MMIO writes, as the memory is mapped read-only.
+        if let ExitReason::MmioWrite { address, data } = exit_reason {
+            let protection = self.vm
+                .read()
+                .unwrap()
+                .protection(address);
+
+            if matches!(protection, Some(protection) if protection.contains(ProtectionFlags::ROM)) {
+                exit_reason = ExitReason::RomWrite { address, data };
+            }
+        }
+
+        // Report the address and the kind of access of the hardware breakpoint that triggered.
+        if let ExitReason::Debug(exit) = &mut exit_reason {
+            if let DebugExitKind::HardwareBreakpoint(index) = exit.kind {
+                exit.breakpoint = self.guest_debug.hw_breakpoints.get(index).copied();
+            }
+        }
+
+        self.crashed = matches!(exit_reason, ExitReason::UnhandledException);
+
+        Ok(exit_reason)
+    }
+
+    /// Like [`Vcpu::run`], but forces the virtual CPU to exit with [`ExitReason::Timeout`] if it
+    /// did not exit by itself within the given timeout. This is useful to bound the time a guest
+    /// can run, e.g. when fuzzing.
+    ///
+    /// The virtual CPU is forced to exit from a timer thread in the same way as
+    /// [`VcpuHandle::kick`], such that this is not supported on FreeBSD either. There, the virtual
+    /// CPU runs until the next exit, after which [`Error::NotImplemented`] is returned.
+    pub fn run_with_timeout(&mut self, timeout: Duration) -> Result<ExitReason, Error> {
+        let handle = self.handle();
+
+        if self.timer.is_none() {
+            self.timer = Some(Arc::new(RunTimer::new(handle.clone())?));
+        }
+
+        // The exit reason borrows the virtual CPU, so keep a reference to the timer.
+        let timer = self.timer.clone().unwrap();
+
+        timer.arm(timeout);
+
+        let result = self.run();
+        let fired = timer.disarm()?;
+        let exit_reason = result?;
+
+        if !fired {
+            return Ok(exit_reason);
+        }
+
+        // The virtual CPU exited by itself before the kick arrived, so discard the kick rather
+        // than cancelling the next run.
+        if !matches!(exit_reason, ExitReason::Canceled) {
+            handle.inner.clear_kick();
+
+            return Ok(exit_reason);
+        }
+
+        Ok(ExitReason::Timeout)
+    }
+
+    /// Returns a handle that can be shared with other threads to force the virtual CPU to exit,
+    /// see [`VcpuHandle`].
+    pub fn handle(&self) -> VcpuHandle {
+        VcpuHandle {
+            inner: self.inner.handle(),
+        }
+    }
+
+    /// Sets whether [`Vcpu::run`] returns [`ExitReason::Canceled`] right away rather than entering
+    /// the guest, like the `immediate_exit` flag of KVM. Any `in` instruction or MMIO read of the
+    /// last exit is still completed, such that the virtual CPU is left in a consistent state, e.g.
+    /// to save it after a signal or an event is received. The flag remains set until cleared.
+    pub fn set_immediate_exit(&mut self, immediate_exit: bool) {
+        self.inner.set_immediate_exit(immediate_exit);
+    }
+
+    /// Completes the `in` instruction reported through [`ExitReason::IoIn`] with the given data,
+    /// which is moved into the registers upon the next call to [`Vcpu::run`]. This is equivalent
+    /// to filling the `data` slice of the exit, but does not require holding on to the exit.
+    /// Returns [`Error::InvalidArgument`] if there is no pending `in` instruction or if the size
+    /// of the data does not match the size of the access.
+    ///
+    /// This is not supported on FreeBSD.
+    pub fn complete_io(&mut self, data: &[u8]) -> Result<(), Error> {
+        self.inner.complete_io(data)?;
+
+        // The data of the exit detached by the local APIC emulated in user space is moved into
+        // the virtual CPU upon the next run, so keep it in sync.
+        #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+        if let Some(apic) = self.software_apic.as_mut() {
+            if apic.pending_read == Some(DetachedRead::Io) {
+                apic.exit_data = data.to_vec();
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Completes the MMIO read reported through [`ExitReason::MmioRead`] with the given data,
+    /// which is moved into the destination register upon the next call to [`Vcpu::run`]. This is
+    /// equivalent to filling the `data` slice of the exit, but does not require holding on to the
+    /// exit. Returns [`Error::InvalidArgument`] if there is no pending MMIO read or if the size of
+    /// the data does not match the size of the access.
+    ///
+    /// This is not supported on FreeBSD and on AArch64 hosts other than Linux.
+    pub fn complete_mmio_read(&mut self, data: &[u8]) -> Result<(), Error> {
+        self.inner.complete_mmio_read(data)?;
+
+        // The data of the exit detached by the local APIC emulated in user space is moved into
+        // the virtual CPU upon the next run, so keep it in sync.
+        #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+        if let Some(apic) = self.software_apic.as_mut() {
+            if apic.pending_read == Some(DetachedRead::Mmio) {
+                apic.exit_data = data.to_vec();
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Returns the exits that [`Vcpu::run`] handles internally.
+    pub fn exit_policy(&self) -> ExitPolicy {
+        self.exit_policy
+    }
+
+    /// Sets the exits that [`Vcpu::run`] handles internally by resuming the virtual CPU rather
+    /// than returning them, see [`ExitPolicy`].
+    pub fn set_exit_policy(&mut self, policy: ExitPolicy) {
+        self.exit_policy = policy;
+    }
+
+    /// Applies the scheduling priority configured through
+    /// [`crate::VmBuilder::with_vcpu_thread_priority`] to the current thread and adds it to the
+    /// resource group configured through [`crate::VmBuilder::with_vcpu_resource_group`], unless
+    /// this has already been done for this thread.
+    fn apply_thread_priority(&mut self) -> Result<(), Error> {
+        let current = std::thread::current().id();
+
+        match self.thread_priority_report {
+            Some((id, _)) if id == current => return Ok(()),
+            _ => (),
+        }
+
+        if let Some(group) = &self.resource_group {
+            group.add_current_thread()?;
+        }
+
+        let report = thread::set_current_thread_priority(self.thread_priority)?;
+
+        self.thread_priority_report = Some((current, report));
+
+        Ok(())
+    }
+
+    /// Returns the current value of the virtual TSC, or `None` if the VM was not built with
+    /// [`crate::TscMode::Deterministic`].
+    pub fn virtual_tsc(&self) -> Option<u64> {
+        self.inner.virtual_tsc()
+    }
+
+    /// Sets the current value of the virtual TSC, e.g. to restore the TSC when replaying a
+    /// recording. Returns [`Error::NotImplemented`] if the VM was not built with
+    /// [`crate::TscMode::Deterministic`].
+    pub fn set_virtual_tsc(&mut self, value: u64) -> Result<(), Error> {
+        self.inner.set_virtual_tsc(value)
+    }
+
+    /// Configures the debugging features of the virtual CPU, i.e. single-stepping, software
+    /// breakpoints and hardware breakpoints. This replaces any previous configuration, such that
+    /// passing the default [`GuestDebug`] disables debugging. See [`crate::debug`] for the
+    /// support on each platform.
+    pub fn set_guest_debug(&mut self, debug: &GuestDebug) -> Result<(), Error> {
+        self.inner.set_guest_debug(debug)?;
+        self.guest_debug = debug.clone();
+
+        Ok(())
+    }
+
+    /// Returns the debugging features configured through [`Vcpu::set_guest_debug`].
+    pub fn guest_debug(&self) -> &GuestDebug {
+        &self.guest_debug
+    }
+
+    /// Programs the hardware breakpoints, i.e. DR0-DR3 and DR7 on x86, while leaving
+    /// single-stepping and software breakpoints as configured through [`Vcpu::set_guest_debug`].
+    /// When one of the breakpoints triggers, the virtual CPU exits with [`ExitReason::Debug`],
+    /// where [`DebugExit::breakpoint`] describes the breakpoint. Passing an empty slice removes
+    /// the hardware breakpoints.
+    pub fn set_hw_breakpoints(&mut self, breakpoints: &[HwBreakpoint]) -> Result<(), Error> {
+        let debug = GuestDebug {
+            hw_breakpoints: breakpoints.to_vec(),
+            ..self.guest_debug.clone()
+        };
+
+        self.set_guest_debug(&debug)
+    }
+
+    /// Formats the given guest virtual address as `name+0xoffset` using the symbol map loaded
+    /// through [`crate::Vm::set_symbol_map`], e.g. to annotate the instruction pointer at the
+    /// time of an exit. Returns `None` if there is no symbol containing the address.
+    pub fn symbolize(&self, address: u64) -> Option<String> {
+        self.symbols.read().unwrap().symbolize(address)
+    }
+
+    /// Returns the outcome of applying the scheduling priority to the thread that last ran the
+    /// virtual CPU, including diagnostics if the process lacks the privileges to apply the
+    /// requested priority. Returns `None` if the virtual CPU has not run yet.
+    pub fn thread_priority_report(&self) -> Option<&ThreadPriorityReport> {
+        self.thread_priority_report
+            .as_ref()
+            .map(|(_, report)| report)
+    }
+
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
     pub fn reset(&mut self) -> Result<(), Error> {
         // Set up the CPU registers.
         let registers = vec![
@@ -58,25 +795,15 @@ impl Vcpu {
         self.set_registers(&registers, &values)?;
 
         // Set up the code, data and stack segments.
+        // The code segment uses the base of the reset vector rather than the selector multiplied
+        // by 16. KVM initializes the type to 0xa, but wHV expects 0xb. Check if this is correct
+        // and remove this comment once checked.
         let code_segment = Segment {
             base: 0xffff_0000,
-            limit: 0xffff,
-            selector: 0xf000,
-            // KVM initializes this to 0xa, but wHV expects 0xb. Check if this is correct and
-            // remove this comment once checked.
-            segment_type: 0xb,
-            non_system_segment: true,
-            present: true,
-            ..Default::default()
+            ..Segment::real_mode_code(0xf000)
         };
 
-        let data_segment = Segment {
-            limit: 0xffff,
-            segment_type: 0x3,
-            non_system_segment: true,
-            present: true,
-            ..Default::default()
-        };
+        let data_segment = Segment::real_mode_data(0);
 
         let registers = vec![
             (SegmentRegister::Cs, code_segment),
@@ -94,19 +821,758 @@ impl Vcpu {
         Ok(())
     }
 
-    #[cfg(not(target_arch = "x86_64"))]
+    #[cfg(not(any(target_arch = "x86", target_arch = "x86_64")))]
     pub fn reset(&mut self) -> Result<(), Error> {
         Ok(())
     }
 }
 
-#[cfg(target_arch = "x86_64")]
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
 use crate::arch::x86_64::{
-    ControlRegister, CpuRegs, DescriptorTable, DescriptorTableRegister, Segment, SegmentRegister,
-    Register,
+    ApicBase, ControlRegister, CpuRegs, CpuidEntry, CpuidResult, DebugRegister, DescriptorTable,
+    DescriptorTableRegister, FpuState, GdtBuilder, LongModeLayout, PendingEvents,
+    ProtectedModeLayout, Segment, SegmentRegister, Register, SmmState, CR0_ET, CR0_NE, CR0_PE,
+    CR0_PG, CR4_OSFXSR, CR4_OSXMMEXCPT, CR4_PAE, EFER_LMA, EFER_LME, GDT_CODE_SELECTOR,
+    GDT_DATA_SELECTOR, MSR_IA32_EFER,
 };
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+use crate::crash::{CrashReport, CRASH_BACKTRACE_FRAMES, CRASH_WINDOW_SIZE};
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+use crate::state::{
+    self, STATE_CONTROL_REGISTERS, STATE_DESCRIPTOR_TABLES, STATE_MSRS, STATE_REGISTERS,
+    STATE_SEGMENT_REGISTERS,
+};
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+use crate::unwind::{PagingState, UnwindHint};
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+use crate::vm::Vm;
+
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+impl Vcpu {
+    /// Injects a System Management Interrupt (SMI) into the virtual CPU. The virtual CPU enters
+    /// System Management Mode (SMM) upon the next call to [`Vcpu::run`], saving its state to the
+    /// SMRAM state save area at SMBASE and jumping to the SMI handler.
+    ///
+    /// This is only supported on Linux, as the other hypervisor APIs do not support SMM.
+    pub fn inject_smi(&mut self) -> Result<(), Error> {
+        self.inner.inject_smi()
+    }
+
+    /// Gets the System Management Mode state of the virtual CPU, which can be used to determine
+    /// whether the virtual CPU entered or left SMM.
+    pub fn get_smm_state(&self) -> Result<SmmState, Error> {
+        self.inner.get_smm_state()
+    }
+
+    /// Sets the System Management Mode state of the virtual CPU.
+    pub fn set_smm_state(&mut self, state: &SmmState) -> Result<(), Error> {
+        self.inner.set_smm_state(state)
+    }
+
+    /// Gets the events of the virtual CPU that are pending delivery, and whether the delivery of
+    /// interrupts and NMIs is blocked. This includes the interrupts that are queued through
+    /// [`Vcpu::inject_interrupt`]. On FreeBSD, NMIs are never reported as blocked.
+    pub fn get_pending_events(&self) -> Result<PendingEvents, Error> {
+        self.inner.get_pending_events()
+    }
+
+    /// Sets the events of the virtual CPU that are pending delivery. On Mac OS X, Microsoft Windows
+    /// and FreeBSD, only a single exception, interrupt or NMI can be pending, and
+    /// [`Error::InvalidArgument`] is returned otherwise. On FreeBSD, NMIs cannot be blocked, no
+    /// interrupts can be queued and the interrupt shadow can only be cleared.
+    pub fn set_pending_events(&mut self, events: &PendingEvents) -> Result<(), Error> {
+        self.inner.set_pending_events(events)
+    }
+
+    /// Gets the XSAVE area of the virtual CPU in the standard format of the `xsave` instruction,
+    /// which includes the AVX and AVX-512 state enabled in XCR0. The size of the area depends on
+    /// the platform. This is not supported on FreeBSD.
+    pub fn get_xsave(&self) -> Result<Vec<u8>, Error> {
+        self.inner.get_xsave()
+    }
+
+    /// Sets the XSAVE area of the virtual CPU, as returned by [`Vcpu::get_xsave`]. This is not
+    /// supported on FreeBSD.
+    pub fn set_xsave(&mut self, bytes: &[u8]) -> Result<(), Error> {
+        self.inner.set_xsave(bytes)
+    }
+
+    /// Gets the extended control register XCR0, which selects the state components that are
+    /// enabled for the XSAVE feature set. This is not supported on FreeBSD.
+    pub fn get_xcr0(&self) -> Result<u64, Error> {
+        self.inner.get_xcr0()
+    }
+
+    /// Sets the extended control register XCR0. This is not supported on FreeBSD.
+    pub fn set_xcr0(&mut self, value: u64) -> Result<(), Error> {
+        self.inner.set_xcr0(value)
+    }
+
+    /// Gets the value of the TSC as observed by the guest. This is not supported on FreeBSD.
+    pub fn get_tsc(&self) -> Result<u64, Error> {
+        self.inner.get_tsc()
+    }
+
+    /// Sets the value of the TSC as observed by the guest, e.g. to keep the timebase of a
+    /// restored guest consistent. On Mac OS X, this adjusts the TSC offset. If the VM was built
+    /// with [`crate::TscMode::Deterministic`], use [`Vcpu::set_virtual_tsc`] instead. This is not
+    /// supported on FreeBSD.
+    pub fn set_tsc(&mut self, value: u64) -> Result<(), Error> {
+        self.inner.set_tsc(value)
+    }
+
+    /// Gets the offset that is added to the TSC of the host when the guest reads the TSC. This
+    /// is only supported on Linux and Mac OS X.
+    pub fn get_tsc_offset(&self) -> Result<u64, Error> {
+        self.inner.get_tsc_offset()
+    }
+
+    /// Sets the offset that is added to the TSC of the host when the guest reads the TSC. This
+    /// is only supported on Linux and Mac OS X, where Linux requires `KVM_VCPU_TSC_CTRL`.
+    pub fn set_tsc_offset(&mut self, offset: u64) -> Result<(), Error> {
+        self.inner.set_tsc_offset(offset)
+    }
+
+    /// Gets the TSC frequency of the virtual CPU in kHz. On Mac OS X and Microsoft Windows,
+    /// this is the TSC frequency of the host. This is not supported on FreeBSD.
+    pub fn get_tsc_khz(&self) -> Result<u32, Error> {
+        self.inner.get_tsc_khz()
+    }
+
+    /// Sets the TSC frequency of the virtual CPU in kHz, such that a guest migrated from a host
+    /// with a different TSC frequency observes the same rate. This is only supported on Linux,
+    /// where KVM scales the TSC if the host supports TSC scaling.
+    pub fn set_tsc_khz(&mut self, khz: u32) -> Result<(), Error> {
+        self.inner.set_tsc_khz(khz)
+    }
+
+    /// Gets the architectural state of the virtual CPU, see [`VcpuState`]. The pending events
+    /// and the SMM state are left out on the platforms that do not support them.
+    pub fn get_state(&self) -> Result<VcpuState, Error> {
+        let registers = self.get_registers(STATE_REGISTERS)?;
+        let control_registers = self.get_control_registers(STATE_CONTROL_REGISTERS)?;
+        let segment_registers = self.get_segment_registers(STATE_SEGMENT_REGISTERS)?;
+        let descriptor_tables = self.get_descriptor_tables(STATE_DESCRIPTOR_TABLES)?;
+        let msrs = self.get_msrs(STATE_MSRS)?;
+
+        Ok(VcpuState {
+            registers: STATE_REGISTERS.iter().copied().zip(registers).collect(),
+            control_registers: STATE_CONTROL_REGISTERS.iter().copied()
+                .zip(control_registers)
+                .collect(),
+            segment_registers: STATE_SEGMENT_REGISTERS.iter().copied()
+                .zip(segment_registers)
+                .collect(),
+            descriptor_tables: STATE_DESCRIPTOR_TABLES.iter().copied()
+                .zip(descriptor_tables)
+                .collect(),
+            msrs: STATE_MSRS.iter().copied().zip(msrs).collect(),
+            fpu: state::optional(self.get_fpu())?,
+            xcr0: state::optional(self.get_xcr0())?,
+            xsave: state::optional(self.get_xsave())?,
+            tsc: state::optional(self.get_tsc())?,
+            pending_events: state::optional(self.get_pending_events())?,
+            smm: state::optional(self.get_smm_state())?,
+        })
+    }
+
+    /// Restores the architectural state of the virtual CPU from the given [`VcpuState`].
+    pub fn set_state(&mut self, state: &VcpuState) -> Result<(), Error> {
+        // Restore the system state before the general-purpose registers, as some platforms
+        // validate the registers against the mode of the virtual CPU.
+        let (registers, values): (Vec<ControlRegister>, Vec<u64>) =
+            state.control_registers.iter().cloned().unzip();
+        self.set_control_registers(&registers, &values)?;
+
+        let (registers, values): (Vec<u32>, Vec<u64>) = state.msrs.iter().cloned().unzip();
+        self.set_msrs(&registers, &values)?;
+
+        let (registers, values): (Vec<SegmentRegister>, Vec<Segment>) =
+            state.segment_registers.iter().cloned().unzip();
+        self.set_segment_registers(&registers, &values)?;
+
+        let (registers, values): (Vec<DescriptorTableRegister>, Vec<DescriptorTable>) =
+            state.descriptor_tables.iter().cloned().unzip();
+        self.set_descriptor_tables(&registers, &values)?;
+
+        let (registers, values): (Vec<Register>, Vec<u64>) =
+            state.registers.iter().cloned().unzip();
+        self.set_registers(&registers, &values)?;
+
+        if let Some(fpu) = &state.fpu {
+            self.set_fpu(fpu)?;
+        }
 
-#[cfg(target_arch = "x86_64")]
+        // Restore XCR0 first, as it determines which components of the XSAVE area are valid.
+        if let Some(xcr0) = state.xcr0 {
+            self.set_xcr0(xcr0)?;
+        }
+
+        if let Some(xsave) = &state.xsave {
+            self.set_xsave(xsave)?;
+        }
+
+        if let Some(smm) = &state.smm {
+            self.set_smm_state(smm)?;
+        }
+
+        if let Some(events) = &state.pending_events {
+            self.set_pending_events(events)?;
+        }
+
+        if let Some(tsc) = state.tsc {
+            self.set_tsc(tsc)?;
+        }
+
+        Ok(())
+    }
+
+    /// Sets the TPR threshold of the virtual CPU and enables TPR shadowing, such that the guest
+    /// accesses the task priority register through the virtual-APIC page without exiting,
+    /// unless it lowers the task priority class below the threshold, which causes an exit with
+    /// [`ExitReason::TprBelowThreshold`]. The threshold is a priority class between 0 and 15. The
+    /// virtual-APIC page must be set through [`Vcpu::set_virtual_apic_page`] first.
+    ///
+    /// This is only supported on Mac OS X, as KVM manages the TPR threshold internally.
+    pub fn set_tpr_threshold(&mut self, threshold: u8) -> Result<(), Error> {
+        if threshold > 15 {
+            return Err(Error::InvalidArgument);
+        }
+
+        self.inner.set_tpr_threshold(threshold)
+    }
+
+    /// Sets the guest physical address of the virtual-APIC page, which must be aligned to the
+    /// page size. On Mac OS X, this page backs the shadowed TPR, while on Linux it is used by KVM
+    /// to report and accelerate TPR accesses of guests that patch their TPR accesses.
+    ///
+    /// This is not supported on Microsoft Windows and FreeBSD, where the hypervisor manages the
+    /// virtual-APIC page internally.
+    pub fn set_virtual_apic_page(&mut self, gpa: u64) -> Result<(), Error> {
+        if gpa & 0xfff != 0 {
+            return Err(Error::MisalignedGuestAddress);
+        }
+
+        self.inner.set_virtual_apic_page(gpa)
+    }
+
+    /// Requests the virtual CPU to exit with [`ExitReason::InterruptWindow`] as soon as the guest
+    /// is able to accept interrupts, i.e. when `rflags.IF` is set and there is no interrupt
+    /// shadow. This allows the host to defer the injection of an interrupt until the guest can
+    /// accept it. The request is cleared once the exit has been reported.
+    ///
+    /// This is not supported on FreeBSD.
+    pub fn request_interrupt_window(&mut self) -> Result<(), Error> {
+        self.inner.request_interrupt_window()
+    }
+
+    /// Injects an external interrupt with the given vector into the virtual CPU. The interrupt is
+    /// queued until the guest is able to accept it, i.e. when `rflags.IF` is set and there is no
+    /// interrupt shadow, and is then delivered by [`Vcpu::run`] without reporting an exit. The
+    /// queued interrupts are delivered in the order in which they were injected. Returns
+    /// [`Error::InvalidArgument`] for the vectors below 32, which are reserved for exceptions.
+    ///
+    /// On Microsoft Windows with [`crate::VmBuilder::with_synthetic_interrupts`] enabled and on
+    /// FreeBSD, the interrupt is delivered through the local APIC emulated by the hypervisor, which
+    /// delivers the pending interrupts by priority instead. The same applies to the local APIC
+    /// emulated in user space, see [`crate::VmBuilder::with_software_apic`].
+    pub fn inject_interrupt(&mut self, vector: u8) -> Result<(), Error> {
+        if vector < 32 {
+            return Err(Error::InvalidArgument);
+        }
+
+        if let Some(apic) = self.software_apic.as_ref() {
+            apic.bus.accept(apic.id, vector);
+
+            return Ok(());
+        }
+
+        self.inner.inject_interrupt(vector)
+    }
+
+    /// Blocks the current thread until the local APIC emulated in user space has an interrupt,
+    /// an NMI or an INIT or startup IPI for the virtual CPU, or until the given timeout passes,
+    /// e.g. after [`Vcpu::run`] returned [`ExitReason::Halted`]. Returns whether the virtual CPU
+    /// has anything to handle. The timer of the local APIC is taken into account. Returns
+    /// [`Error::NotImplemented`] unless [`crate::VmBuilder::with_software_apic`] is enabled.
+    pub fn wait_for_interrupt(&self, timeout: Duration) -> Result<bool, Error> {
+        match self.software_apic.as_ref() {
+            Some(apic) => Ok(apic.bus.wait(apic.id, timeout)),
+            _ => Err(Error::NotImplemented),
+        }
+    }
+
+    /// Implements [`Vcpu::run`] for the local APIC emulated in user space. The exits are detached
+    /// from the virtual CPU before the local APIC handles them, as an exit that borrows the
+    /// virtual CPU cannot be held while the virtual CPU runs again. The exit that is reported is
+    /// attached to the data of the local APIC instead, which is moved into the virtual CPU upon
+    /// the next run.
+    fn run_with_software_apic(&mut self) -> Result<ExitReason, Error> {
+        let exit_reason = loop {
+            self.complete_software_apic_read()?;
+
+            if !self.prepare_software_apic()? {
+                return Ok(ExitReason::Halted);
+            }
+
+            let mut data = std::mem::take(&mut self.software_apic.as_mut().unwrap().exit_data);
+            let exit_reason = self.run_once()?.detach(&mut data);
+            let apic = self.software_apic.as_mut().unwrap();
+
+            apic.exit_data = data;
+            apic.pending_read = match exit_reason {
+                ExitReason::IoIn { .. } => Some(DetachedRead::Io),
+                ExitReason::MmioRead { .. } => Some(DetachedRead::Mmio),
+                _ => None,
+            };
+
+            if !apic.handle_exit(&exit_reason)? {
+                break exit_reason;
+            }
+        };
+
+        let apic = self.software_apic.as_mut().unwrap();
+
+        Ok(exit_reason.attach(&mut apic.exit_data))
+    }
+
+    /// Completes the `in` instruction or the MMIO read of the last exit with the data of the local
+    /// APIC emulated in user space, see [`Vcpu::run_with_software_apic`].
+    fn complete_software_apic_read(&mut self) -> Result<(), Error> {
+        let apic = self.software_apic.as_mut().unwrap();
+
+        match apic.pending_read.take() {
+            Some(DetachedRead::Io) => self.inner.complete_io(&apic.exit_data),
+            Some(DetachedRead::Mmio) => self.inner.complete_mmio_read(&apic.exit_data),
+            None => Ok(()),
+        }
+    }
+
+    /// Applies the INIT and startup IPIs, injects the pending NMI and the highest priority
+    /// pending interrupt, and arms the timer of the local APIC emulated in user space before
+    /// entering the guest. Returns whether the virtual CPU should enter the guest, i.e. whether
+    /// it is not waiting for a startup IPI.
+    fn prepare_software_apic(&mut self) -> Result<bool, Error> {
+        let apic = self.software_apic.as_ref().unwrap();
+        let entry = apic.bus.prepare(apic.id);
+
+        if entry.reset {
+            self.reset()?;
+        }
+
+        // The startup IPI starts the virtual CPU in real mode at the page given by the vector.
+        if let Some(vector) = entry.startup {
+            let code_segment = Segment::real_mode_code((vector as u16) << 8);
+
+            self.set_segment_registers(&[SegmentRegister::Cs], &[code_segment])?;
+            self.set_registers(&[Register::Rip], &[0])?;
+        }
+
+        if entry.waiting {
+            return Ok(false);
+        }
+
+        if entry.nmi {
+            self.inner.inject_nmi()?;
+        }
+
+        if let Some(vector) = entry.vector {
+            self.inner.inject_interrupt(vector)?;
+        }
+
+        if let Some(deadline) = entry.deadline {
+            let apic = self.software_apic.as_mut().unwrap();
+
+            if apic.timer.is_none() {
+                apic.timer = Some(RunTimer::new(apic.handle.clone())?);
+            }
+
+            let timer = apic.timer.as_ref().unwrap();
+
+            timer.arm(deadline.saturating_duration_since(Instant::now()));
+        }
+
+        Ok(true)
+    }
+
+    /// Injects a non-maskable interrupt (NMI) into the virtual CPU. The NMI is queued until the
+    /// guest is able to accept it, i.e. when the guest is not handling another NMI, and is then
+    /// delivered by [`Vcpu::run`]. Like on bare metal, NMIs that are injected while another NMI
+    /// is queued may be merged into one.
+    pub fn inject_nmi(&mut self) -> Result<(), Error> {
+        self.inner.inject_nmi()
+    }
+
+    /// Injects an exception with the given vector into the virtual CPU upon the next call to
+    /// [`Vcpu::run`], e.g. to reflect a page fault back into the guest after failing to map the
+    /// memory lazily. The error code must be specified for the exceptions that push one, such as
+    /// #GP and #PF, while `cr2` specifies the linear address that caused a #PF. Returns
+    /// [`Error::InvalidArgument`] for the vectors of 32 and above.
+    ///
+    /// Any exception that is pending is replaced, while an interrupt or NMI that is pending is
+    /// delivered after the exception.
+    pub fn inject_exception(
+        &mut self,
+        vector: u8,
+        error_code: Option<u32>,
+        cr2: Option<u64>,
+    ) -> Result<(), Error> {
+        if vector >= 32 {
+            return Err(Error::InvalidArgument);
+        }
+
+        if let Some(cr2) = cr2 {
+            self.set_control_registers(&[ControlRegister::Cr2], &[cr2])?;
+        }
+
+        self.inner.inject_exception(vector, error_code)
+    }
+
+    /// Completes the `cpuid` instruction reported through [`ExitReason::Cpuid`] by writing the
+    /// result to `eax`, `ebx`, `ecx` and `edx` and moving the instruction pointer past the
+    /// instruction. Returns [`Error::InvalidArgument`] if there is no pending `cpuid` instruction.
+    ///
+    /// This is only supported on Mac OS X and Microsoft Windows, see
+    /// [`crate::VmBuilder::with_cpuid_exits`].
+    pub fn complete_cpuid(&mut self, result: &CpuidResult) -> Result<(), Error> {
+        self.inner.complete_cpuid(result)
+    }
+
+    /// Sets the CPUID table of the virtual CPU, which determines the feature set the guest sees.
+    /// The table returned by [`crate::Hypervisor::get_supported_cpuid`] serves as a starting
+    /// point.
+    ///
+    /// On Linux, this must be called before the virtual CPU runs for the first time. On Mac OS X,
+    /// the table serves the `cpuid` instructions that are not reported as [`ExitReason::Cpuid`],
+    /// where the leaves that are not in the table read as zero. On Microsoft Windows, the table
+    /// applies to all the virtual CPUs of the VM and [`Error::InvalidArgument`] is returned for
+    /// entries of any subleaf other than the first. This is not supported on FreeBSD.
+    pub fn set_cpuid(&mut self, entries: &[CpuidEntry]) -> Result<(), Error> {
+        self.inner.set_cpuid(entries)
+    }
+
+    /// Completes the `rdmsr` instruction reported through [`ExitReason::MsrRead`] by returning
+    /// the given value to the guest in `edx:eax`. Returns [`Error::InvalidArgument`] if there is
+    /// no pending `rdmsr` instruction.
+    pub fn complete_msr_read(&mut self, value: u64) -> Result<(), Error> {
+        self.inner.complete_msr_read(value)
+    }
+
+    /// Selects the exceptions that cause the virtual CPU to exit with [`ExitReason::Exception`],
+    /// where bit `n` selects the exception with vector `n`, e.g. `1 << 13` for general protection
+    /// faults and `1 << 14` for page faults. This replaces any previous selection, such that
+    /// passing zero disables the exception exits. The exceptions used by
+    /// [`Vcpu::set_guest_debug`] are reported as [`ExitReason::Debug`] while guest debugging is
+    /// enabled.
+    ///
+    /// This is only supported on Mac OS X and Microsoft Windows, where the selection applies to
+    /// all the virtual CPUs of the VM on the latter.
+    pub fn set_exception_exits(&mut self, vectors: u32) -> Result<(), Error> {
+        self.inner.set_exception_exits(vectors)
+    }
+
+    /// Switches the virtual CPU straight into 64-bit long mode, such that the guest starts
+    /// executing at [`LongModeLayout::entry`] without going through real mode and protected mode
+    /// itself. This writes identity-mapped page tables and a GDT with a flat code and data segment
+    /// to the guest memory described by the [`LongModeLayout`], enables paging through CR0, CR3,
+    /// CR4 and EFER, loads the segment registers and sets up `rip`, `rsp` and `rflags`.
+    ///
+    /// The guest memory for the page tables and the GDT must have been allocated in the given
+    /// [`Vm`] beforehand. Returns [`Error::InvalidArgument`] if the layout identity maps more
+    /// than 512 GiB, or [`Error::UnmappedGuestAddress`] if the page tables or the GDT are not
+    /// backed by guest memory.
+    pub fn enter_long_mode(&mut self, vm: &mut Vm, layout: &LongModeLayout) -> Result<(), Error> {
+        /// The present and the writable bits of the page table entries.
+        const PRESENT_WRITABLE: u64 = 0x3;
+        /// The bit of the page directory entries that maps a 2 MiB page.
+        const PAGE_SIZE: u64 = 1 << 7;
+
+        if layout.page_tables % 0x1000 != 0 {
+            return Err(Error::MisalignedGuestAddress);
+        }
+
+        let count = layout.page_directory_count();
+
+        if count == 0 || count > 512 {
+            return Err(Error::InvalidArgument);
+        }
+
+        // Lay out the PML4, the PDPT and the page directories consecutively.
+        let pml4 = layout.page_tables;
+        let pdpt = pml4 + 0x1000;
+        let page_directories = pdpt + 0x1000;
+
+        let mut entries = vec![0u64; layout.page_tables_size() as usize / 8];
+
+        entries[0] = pdpt | PRESENT_WRITABLE;
+
+        for index in 0..count {
+            entries[512 + index as usize] = (page_directories + index * 0x1000) | PRESENT_WRITABLE;
+        }
+
+        for index in 0..count * 512 {
+            entries[1024 + index as usize] = (index << 21) | PRESENT_WRITABLE | PAGE_SIZE;
+        }
+
+        let bytes: Vec<u8> = entries.iter().flat_map(|entry| entry.to_le_bytes()).collect();
+        vm.write_all_physical_memory(pml4, &bytes)?;
+
+        let code = Segment::long_mode_code(GDT_CODE_SELECTOR);
+        let data = Segment::long_mode_data(GDT_DATA_SELECTOR);
+
+        self.install_gdt(vm, layout.gdt, &code, &data)?;
+
+        // Enable paging before long mode, as some platforms validate EFER.LMA against CR0.PG.
+        self.set_control_registers(
+            &[ControlRegister::Cr3, ControlRegister::Cr4, ControlRegister::Cr0],
+            &[
+                pml4,
+                CR4_PAE | CR4_OSFXSR | CR4_OSXMMEXCPT,
+                CR0_PE | CR0_ET | CR0_NE | CR0_PG,
+            ],
+        )?;
+
+        // The platforms that derive EFER.LMA and the VM-entry controls from EFER.LME do so when
+        // setting the MSR, see the Mac OS X implementation of `set_msrs`.
+        self.set_msrs(&[MSR_IA32_EFER], &[EFER_LME | EFER_LMA])?;
+
+        self.load_flat_segments(code, data)?;
+
+        self.set_registers(
+            &[Register::Rip, Register::Rsp, Register::Rflags],
+            &[layout.entry, layout.stack, 0x2],
+        )?;
+
+        Ok(())
+    }
+
+    /// Switches the virtual CPU into 32-bit protected mode without paging, such that the guest
+    /// starts executing at [`ProtectedModeLayout::entry`]. This writes a GDT with a flat 4 GiB
+    /// code and data segment to the guest memory described by the [`ProtectedModeLayout`], loads
+    /// the segment registers, sets `CR0.PE` and sets up `eip`, `esp` and `eflags`.
+    ///
+    /// The guest memory for the GDT must have been allocated in the given [`Vm`] beforehand.
+    /// Returns [`Error::UnmappedGuestAddress`] if the GDT is not backed by guest memory.
+    pub fn enter_protected_mode(
+        &mut self,
+        vm: &mut Vm,
+        layout: &ProtectedModeLayout,
+    ) -> Result<(), Error> {
+        let code = Segment::protected_flat_code(GDT_CODE_SELECTOR);
+        let data = Segment::protected_flat_data(GDT_DATA_SELECTOR);
+
+        self.install_gdt(vm, layout.gdt, &code, &data)?;
+
+        self.set_control_registers(&[ControlRegister::Cr0], &[CR0_PE | CR0_ET | CR0_NE])?;
+
+        self.load_flat_segments(code, data)?;
+
+        self.set_registers(
+            &[Register::Rip, Register::Rsp, Register::Rflags],
+            &[layout.entry, layout.stack, 0x2],
+        )?;
+
+        Ok(())
+    }
+
+    /// Helper function to write a GDT with the null descriptor, and the given code and data
+    /// segment descriptors to the given guest physical address, and to load it into the GDTR.
+    fn install_gdt(
+        &mut self,
+        vm: &mut Vm,
+        gdt: u64,
+        code: &Segment,
+        data: &Segment,
+    ) -> Result<(), Error> {
+        let mut builder = GdtBuilder::new();
+
+        builder.add_segment(code);
+        builder.add_segment(data);
+
+        let table = builder.write(vm, gdt)?;
+
+        self.set_descriptor_tables(&[DescriptorTableRegister::Gdt], &[table])
+    }
+
+    /// Helper function to load the given code segment into CS and the given data segment into
+    /// the other segment registers.
+    fn load_flat_segments(&mut self, code: Segment, data: Segment) -> Result<(), Error> {
+        self.set_segment_registers(
+            &[
+                SegmentRegister::Cs,
+                SegmentRegister::Ss,
+                SegmentRegister::Ds,
+                SegmentRegister::Es,
+                SegmentRegister::Fs,
+                SegmentRegister::Gs,
+            ],
+            &[code, data.clone(), data.clone(), data.clone(), data.clone(), data],
+        )
+    }
+
+    /// Walks the stack of the guest by following the chain of frame pointers starting at the
+    /// current `rip` and `rbp` registers of the virtual CPU. Returns the current instruction
+    /// pointer followed by the return addresses of the callers, up to `max_frames` entries in
+    /// total. See [`crate::unwind`] for the limitations.
+    pub fn walk_stack(&self, max_frames: usize) -> Result<Vec<u64>, Error> {
+        let values = self.get_registers(&[Register::Rip, Register::Rbp])?;
+        let paging = PagingState::new(&self.inner)?;
+        let cs = &self.get_segment_registers(&[SegmentRegister::Cs])?[0];
+
+        // The frames are 64-bit in long mode and 32-bit otherwise.
+        let word_size = if paging.efer & EFER_LMA != 0 && cs.long {
+            8
+        } else {
+            4
+        };
+
+        let hint = UnwindHint {
+            pc: values[0],
+            frame_pointer: values[1],
+            word_size,
+        };
+
+        paging.walk_stack(&self.vm.read().unwrap(), &hint, max_frames)
+    }
+
+    /// Walks the stack of the guest by following the chain of frame pointers starting at the
+    /// frame described by the [`UnwindHint`], e.g. when the guest does not use frame pointers for
+    /// the innermost frame.
+    pub fn walk_stack_with_hint(
+        &self,
+        hint: &UnwindHint,
+        max_frames: usize,
+    ) -> Result<Vec<u64>, Error> {
+        let paging = PagingState::new(&self.inner)?;
+
+        paging.walk_stack(&self.vm.read().unwrap(), hint, max_frames)
+    }
+
+    /// Translates the guest virtual address into a guest physical address using the page tables
+    /// of the guest as currently configured in the virtual CPU, e.g. to inspect the guest memory
+    /// from a debugger. Returns [`Error::PageNotPresent`] if the address is not mapped. On
+    /// Microsoft Windows, the translation is done by the hypervisor. On the other platforms, the
+    /// page tables are walked based on CR0, CR3, CR4 and EFER.
+    pub fn translate(&self, gva: u64) -> Result<u64, Error> {
+        match self.inner.translate_gva(gva) {
+            Err(Error::NotImplemented) => (),
+            result => return result,
+        }
+
+        PagingState::new(&self.inner)?.translate(&self.vm.read().unwrap(), gva)
+    }
+
+    /// Returns a [`CrashReport`] describing the state of the virtual CPU if the last call to
+    /// [`Vcpu::run`] returned [`ExitReason::UnhandledException`], or `None` otherwise. The report
+    /// is captured from the state of the virtual CPU, which is preserved until the next call to
+    /// [`Vcpu::run`].
+    pub fn last_crash_report(&self) -> Result<Option<CrashReport>, Error> {
+        if !self.crashed {
+            return Ok(None);
+        }
+
+        let registers = vec![
+            Register::Rax, Register::Rcx, Register::Rdx, Register::Rbx,
+            Register::Rsp, Register::Rbp, Register::Rsi, Register::Rdi,
+            Register::R8,  Register::R9,  Register::R10, Register::R11,
+            Register::R12, Register::R13, Register::R14, Register::R15,
+            Register::Rip, Register::Rflags,
+        ];
+        let values = self.get_registers(&registers)?;
+        let registers: Vec<(Register, u64)> = registers.into_iter().zip(values).collect();
+
+        let control_registers = vec![
+            ControlRegister::Cr0,
+            ControlRegister::Cr2,
+            ControlRegister::Cr3,
+            ControlRegister::Cr4,
+            ControlRegister::Cr8,
+        ];
+        let values = self.get_control_registers(&control_registers)?;
+        let control_registers: Vec<(ControlRegister, u64)> =
+            control_registers.into_iter().zip(values).collect();
+
+        let segments = vec![
+            SegmentRegister::Cs, SegmentRegister::Ds, SegmentRegister::Es, SegmentRegister::Fs,
+            SegmentRegister::Gs, SegmentRegister::Ss, SegmentRegister::Tr, SegmentRegister::Ldt,
+        ];
+        let values = self.get_segment_registers(&segments)?;
+        let segments: Vec<(SegmentRegister, Segment)> = segments.into_iter().zip(values).collect();
+
+        let descriptor_tables = vec![DescriptorTableRegister::Gdt, DescriptorTableRegister::Idt];
+        let values = self.get_descriptor_tables(&descriptor_tables)?;
+        let descriptor_tables: Vec<(DescriptorTableRegister, DescriptorTable)> =
+            descriptor_tables.into_iter().zip(values).collect();
+
+        let paging = PagingState::new(&self.inner)?;
+        let rip = registers[16].1;
+        let rsp = registers[4].1;
+
+        // Capture the guest memory around the instruction pointer and at the stack pointer on a
+        // best-effort basis, as the crash may have been caused by unmapped memory.
+        let vm = self.vm.read().unwrap();
+        let code_address = rip.saturating_sub(CRASH_WINDOW_SIZE as u64 / 2);
+        let mut code = vec![0u8; CRASH_WINDOW_SIZE];
+        let mut stack = vec![0u8; CRASH_WINDOW_SIZE];
+
+        if paging.read_virtual_memory(&vm, &mut code, code_address).is_err() {
+            code.clear();
+        }
+
+        if paging.read_virtual_memory(&vm, &mut stack, rsp).is_err() {
+            stack.clear();
+        }
+
+        drop(vm);
+
+        let backtrace = self.walk_stack(CRASH_BACKTRACE_FRAMES).unwrap_or_default();
+
+        Ok(Some(CrashReport {
+            fault_address: control_registers[1].1,
+            registers,
+            control_registers,
+            efer: paging.efer,
+            segments,
+            descriptor_tables,
+            code_address,
+            code,
+            stack,
+            backtrace,
+        }))
+    }
+
+    /// Walks the stack of the guest like [`Vcpu::walk_stack`], and annotates each frame with its
+    /// symbol through [`Vcpu::symbolize`].
+    pub fn walk_stack_symbolized(
+        &self,
+        max_frames: usize,
+    ) -> Result<Vec<(u64, Option<String>)>, Error> {
+        let frames = self.walk_stack(max_frames)?;
+        let symbols = self.symbols.read().unwrap();
+
+        Ok(frames
+            .into_iter()
+            .map(|address| (address, symbols.symbolize(address)))
+            .collect())
+    }
+}
+
+#[cfg(feature = "xen")]
+impl Vcpu {
+    /// Sets the guest physical address of the `vcpu_info` structure of the virtual CPU, as
+    /// registered by the guest through the `VCPUOP_register_vcpu_info` hypercall.
+    pub fn set_xen_vcpu_info(&mut self, gpa: u64) -> Result<(), Error> {
+        self.inner.set_xen_vcpu_info(gpa)
+    }
+
+    /// Completes the Xen hypercall reported by [`ExitReason::XenHypercall`] with the given return
+    /// value.
+    pub fn complete_xen_hypercall(&mut self, result: u64) -> Result<(), Error> {
+        self.inner.complete_xen_hypercall(result)
+    }
+}
+
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
 impl CpuRegs for Vcpu {
     fn get_registers(
         &self,
@@ -182,4 +1648,70 @@ impl CpuRegs for Vcpu {
     ) -> Result<(), Error> {
         self.inner.set_descriptor_tables(registers, values)
     }
+
+    fn get_debug_registers(
+        &self,
+        registers: &[DebugRegister],
+    ) -> Result<Vec<u64>, Error> {
+        self.inner.get_debug_registers(registers)
+    }
+
+    fn set_debug_registers(
+        &mut self,
+        registers: &[DebugRegister],
+        values: &[u64],
+    ) -> Result<(), Error> {
+        self.inner.set_debug_registers(registers, values)
+    }
+
+    fn get_fpu(&self) -> Result<FpuState, Error> {
+        self.inner.get_fpu()
+    }
+
+    fn set_fpu(&mut self, state: &FpuState) -> Result<(), Error> {
+        self.inner.set_fpu(state)
+    }
+}
+
+#[cfg(target_arch = "aarch64")]
+impl Vcpu {
+    /// Gets the architectural state of the virtual CPU, see [`VcpuState`].
+    pub fn get_state(&self) -> Result<VcpuState, Error> {
+        use crate::arch::aarch64::CpuRegs;
+        use crate::state::STATE_REGISTERS;
+
+        let registers = self.get_registers(STATE_REGISTERS)?;
+
+        Ok(VcpuState {
+            registers: STATE_REGISTERS.iter().copied().zip(registers).collect(),
+        })
+    }
+
+    /// Restores the architectural state of the virtual CPU from the given [`VcpuState`].
+    pub fn set_state(&mut self, state: &VcpuState) -> Result<(), Error> {
+        use crate::arch::aarch64::{CpuRegs, Register};
+
+        let (registers, values): (Vec<Register>, Vec<u64>) =
+            state.registers.iter().cloned().unzip();
+
+        self.set_registers(&registers, &values)
+    }
+}
+
+#[cfg(target_arch = "aarch64")]
+impl crate::arch::aarch64::CpuRegs for Vcpu {
+    fn get_registers(
+        &self,
+        registers: &[crate::arch::aarch64::Register],
+    ) -> Result<Vec<u64>, Error> {
+        self.inner.get_registers(registers)
+    }
+
+    fn set_registers(
+        &mut self,
+        registers: &[crate::arch::aarch64::Register],
+        values: &[u64],
+    ) -> Result<(), Error> {
+        self.inner.set_registers(registers, values)
+    }
 }
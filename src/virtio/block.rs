@@ -0,0 +1,404 @@
+//! This module provides [`Block`], a virtio-blk device that is backed by a file or a raw block
+//! device on the host, as described by section 5.2 of the virtio 1.1 specification.
+//!
+//! The device supports reading, writing and flushing, as well as retrieving the serial number of
+//! the disk. Writes are rejected when the disk is read-only, in which case the device also offers
+//! `VIRTIO_BLK_F_RO` such that the guest mounts the disk read-only. The requests are performed
+//! synchronously on the thread that notifies the virtqueue, i.e. the thread running the virtual
+//! CPU, and the data is copied through a bounded buffer, such that the size of the requests does
+//! not affect the memory usage of the VMM. Discarding and zeroing blocks are not supported.
+
+use crate::bytes::{self, AsBytes, FromBytes, Le};
+use crate::error::Error;
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use super::{
+    Descriptor, DescriptorChain, GuestMemory, Queue, VirtioDevice, VIRTIO_F_INDIRECT_DESC,
+    VIRTIO_ID_BLOCK,
+};
+
+/// The size of a sector in bytes, which is the unit of the capacity and of the offsets of the
+/// requests regardless of the block size of the backing file.
+pub const SECTOR_SIZE: u64 = 512;
+
+/// The size of the serial number of the disk in bytes.
+pub const VIRTIO_BLK_ID_BYTES: usize = 20;
+
+/// The feature bit of the maximum number of buffers in a request.
+const VIRTIO_BLK_F_SEG_MAX: u64 = 1 << 2;
+/// The feature bit that indicates that the disk is read-only.
+const VIRTIO_BLK_F_RO: u64 = 1 << 5;
+/// The feature bit of the flush command.
+const VIRTIO_BLK_F_FLUSH: u64 = 1 << 9;
+
+/// The request type to read sectors.
+const VIRTIO_BLK_T_IN: u32 = 0;
+/// The request type to write sectors.
+const VIRTIO_BLK_T_OUT: u32 = 1;
+/// The request type to flush the written sectors to the disk.
+const VIRTIO_BLK_T_FLUSH: u32 = 4;
+/// The request type to retrieve the serial number of the disk.
+const VIRTIO_BLK_T_GET_ID: u32 = 8;
+
+/// The request status that indicates that the request succeeded.
+const VIRTIO_BLK_S_OK: u8 = 0;
+/// The request status that indicates that the request failed.
+const VIRTIO_BLK_S_IOERR: u8 = 1;
+/// The request status that indicates that the request type is not supported.
+const VIRTIO_BLK_S_UNSUPP: u8 = 2;
+
+/// The size of the request virtqueue.
+const QUEUE_SIZE: u16 = 256;
+/// The size of the buffer through which the data is copied.
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// The guest physical address and the size of a buffer.
+type Buffer = (u64, u64);
+
+/// The header of a request.
+#[derive(Clone, Copy)]
+#[repr(C)]
+struct RequestHeader {
+    kind: Le<u32>,
+    reserved: Le<u32>,
+    sector: Le<u64>,
+}
+
+unsafe impl FromBytes for RequestHeader {}
+
+/// The start of the configuration space of the device, where the remaining fields read as zero.
+#[derive(Clone, Copy)]
+#[repr(C)]
+struct BlockConfig {
+    capacity: Le<u64>,
+    size_max: Le<u32>,
+    seg_max: Le<u32>,
+}
+
+unsafe impl AsBytes for BlockConfig {}
+
+/// A virtio-blk device backed by a file or a raw block device, see the
+/// [module-level documentation](self).
+pub struct Block {
+    /// The backing file.
+    file: File,
+    /// The number of sectors of the disk.
+    capacity: u64,
+    /// Whether the disk is read-only.
+    read_only: bool,
+    /// The serial number of the disk.
+    serial: [u8; VIRTIO_BLK_ID_BYTES],
+    /// The buffer through which the data is copied.
+    buffer: Vec<u8>,
+}
+
+impl Block {
+    /// Opens the file or the raw block device at the given path as a disk, which is opened
+    /// read-only if `read_only` is set.
+    pub fn open<P: AsRef<Path>>(path: P, read_only: bool) -> Result<Self, Error> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(!read_only)
+            .open(path)?;
+
+        Self::new(file, read_only)
+    }
+
+    /// Uses the given file as a disk, where the capacity is the size of the file rounded down to
+    /// whole sectors. The file must have been opened for writing unless `read_only` is set.
+    pub fn new(mut file: File, read_only: bool) -> Result<Self, Error> {
+        // The metadata reports a size of zero for raw block devices, so seek to the end instead.
+        let size = file.seek(SeekFrom::End(0))?;
+
+        Ok(Self {
+            file,
+            capacity: size / SECTOR_SIZE,
+            read_only,
+            serial: [0; VIRTIO_BLK_ID_BYTES],
+            buffer: vec![0; CHUNK_SIZE],
+        })
+    }
+
+    /// Sets the serial number of the disk, which is truncated to [`VIRTIO_BLK_ID_BYTES`] bytes.
+    pub fn with_serial(mut self, serial: &str) -> Self {
+        let size = serial.len().min(VIRTIO_BLK_ID_BYTES);
+
+        self.serial = [0; VIRTIO_BLK_ID_BYTES];
+        self.serial[..size].copy_from_slice(&serial.as_bytes()[..size]);
+        self
+    }
+
+    /// Returns the number of sectors of the disk.
+    pub fn capacity(&self) -> u64 {
+        self.capacity
+    }
+
+    /// Returns whether the disk is read-only.
+    pub fn read_only(&self) -> bool {
+        self.read_only
+    }
+
+    /// Checks that the given number of bytes starting at the given sector are a whole number of
+    /// sectors within the disk, and returns the offset of the sector in bytes.
+    fn check_range(&self, sector: u64, size: u64) -> Option<u64> {
+        if size % SECTOR_SIZE != 0 {
+            return None;
+        }
+
+        match sector.checked_add(size / SECTOR_SIZE) {
+            Some(end) if end <= self.capacity => Some(sector * SECTOR_SIZE),
+            _ => None,
+        }
+    }
+
+    /// Reads the disk starting at the given offset into the given buffers. Returns
+    /// [`Error::Io`] if the disk could not be read.
+    fn read_disk(
+        &mut self,
+        memory: &GuestMemory,
+        offset: u64,
+        buffers: &[Buffer],
+    ) -> Result<(), Error> {
+        self.file.seek(SeekFrom::Start(offset))?;
+
+        for &(address, size) in buffers {
+            let mut done = 0;
+
+            while done < size {
+                let chunk = (size - done).min(CHUNK_SIZE as u64) as usize;
+
+                self.file.read_exact(&mut self.buffer[..chunk])?;
+                memory.write(address + done, &self.buffer[..chunk])?;
+
+                done += chunk as u64;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Writes the given buffers to the disk starting at the given offset. Returns [`Error::Io`]
+    /// if the disk could not be written.
+    fn write_disk(
+        &mut self,
+        memory: &GuestMemory,
+        offset: u64,
+        buffers: &[Buffer],
+    ) -> Result<(), Error> {
+        self.file.seek(SeekFrom::Start(offset))?;
+
+        for &(address, size) in buffers {
+            let mut done = 0;
+
+            while done < size {
+                let chunk = (size - done).min(CHUNK_SIZE as u64) as usize;
+
+                memory.read(&mut self.buffer[..chunk], address + done)?;
+                self.file.write_all(&self.buffer[..chunk])?;
+
+                done += chunk as u64;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Performs the given request, and returns the status of the request along with the number
+    /// of bytes written to the data buffers. Only errors accessing the guest physical memory are
+    /// returned as errors, while the errors accessing the disk are reported to the driver.
+    fn handle_request(
+        &mut self,
+        memory: &GuestMemory,
+        header: &RequestHeader,
+        readable: &[Buffer],
+        writable: &[Buffer],
+    ) -> Result<(u8, u32), Error> {
+        let sector = header.sector.get();
+
+        let result = match header.kind.get() {
+            VIRTIO_BLK_T_IN => {
+                let size = total_size(writable);
+
+                match self.check_range(sector, size) {
+                    Some(offset) => self.read_disk(memory, offset, writable).map(|_| size),
+                    _ => return Ok((VIRTIO_BLK_S_IOERR, 0)),
+                }
+            }
+            VIRTIO_BLK_T_OUT => {
+                let size = total_size(readable);
+
+                match self.check_range(sector, size) {
+                    Some(offset) if !self.read_only =>
+                        self.write_disk(memory, offset, readable).map(|_| 0),
+                    _ => return Ok((VIRTIO_BLK_S_IOERR, 0)),
+                }
+            }
+            VIRTIO_BLK_T_FLUSH => match self.read_only {
+                true => Ok(0),
+                _ => self.file.sync_data().map(|_| 0).map_err(Error::from),
+            },
+            VIRTIO_BLK_T_GET_ID => {
+                let mut offset = 0;
+
+                for &(address, size) in writable {
+                    let size = (size as usize).min(VIRTIO_BLK_ID_BYTES - offset);
+
+                    memory.write(address, &self.serial[offset..offset + size])?;
+                    offset += size;
+                }
+
+                Ok(offset as u64)
+            }
+            _ => return Ok((VIRTIO_BLK_S_UNSUPP, 0)),
+        };
+
+        match result {
+            Ok(size) => Ok((VIRTIO_BLK_S_OK, size as u32)),
+            Err(Error::Io(_)) => Ok((VIRTIO_BLK_S_IOERR, 0)),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Parses and performs the request in the given chain of descriptors, and returns the number
+    /// of bytes written to the buffers of the chain. Returns [`Error::InvalidArgument`] if the
+    /// request is malformed.
+    fn process_request(
+        &mut self,
+        memory: &GuestMemory,
+        chain: &DescriptorChain,
+    ) -> Result<u32, Error> {
+        // The request starts with the header and ends with the status byte, where the data
+        // buffers are in between.
+        let readable = buffers(chain.readable());
+        let mut writable = buffers(chain.writable());
+
+        let (header, readable) = split_buffers(&readable, std::mem::size_of::<RequestHeader>())
+            .ok_or(Error::InvalidArgument)?;
+
+        let status = match writable.last_mut() {
+            Some((address, size)) => {
+                *size -= 1;
+                *address + *size
+            }
+            _ => return Err(Error::InvalidArgument),
+        };
+
+        if writable.last().map(|&(_, size)| size) == Some(0) {
+            writable.pop();
+        }
+
+        let mut bytes = vec![0; std::mem::size_of::<RequestHeader>()];
+        let mut offset = 0;
+
+        for (address, size) in header {
+            memory.read(&mut bytes[offset..offset + size as usize], address)?;
+            offset += size as usize;
+        }
+
+        let header: RequestHeader = bytes::from_bytes(&bytes);
+        let (status_byte, size) = self.handle_request(memory, &header, &readable, &writable)?;
+
+        memory.write(status, &[status_byte])?;
+
+        Ok(size + 1)
+    }
+}
+
+impl VirtioDevice for Block {
+    fn device_type(&self) -> u32 {
+        VIRTIO_ID_BLOCK
+    }
+
+    fn queue_max_sizes(&self) -> &[u16] {
+        &[QUEUE_SIZE]
+    }
+
+    fn features(&self) -> u64 {
+        let mut features = VIRTIO_F_INDIRECT_DESC | VIRTIO_BLK_F_SEG_MAX | VIRTIO_BLK_F_FLUSH;
+
+        if self.read_only {
+            features |= VIRTIO_BLK_F_RO;
+        }
+
+        features
+    }
+
+    fn read_config(&self, offset: u64, data: &mut [u8]) {
+        let config = BlockConfig {
+            capacity: Le::new(self.capacity),
+            size_max: Le::new(0),
+            // The request header and the status byte take up two of the descriptors.
+            seg_max: Le::new(QUEUE_SIZE as u32 - 2),
+        };
+        let config = bytes::as_bytes(&config);
+
+        for (i, byte) in data.iter_mut().enumerate() {
+            *byte = config.get(offset as usize + i).copied().unwrap_or(0);
+        }
+    }
+
+    fn process_queue(
+        &mut self,
+        index: usize,
+        queues: &mut [Queue],
+        memory: &GuestMemory,
+    ) -> Result<bool, Error> {
+        let queue = &mut queues[index];
+        let mut used = false;
+
+        while let Some(chain) = queue.pop(memory)? {
+            let size = self.process_request(memory, &chain)?;
+
+            queue.add_used(memory, chain.head, size)?;
+            used = true;
+        }
+
+        Ok(used)
+    }
+}
+
+/// Helper function to collect the guest physical addresses and the sizes of the given non-empty
+/// buffers.
+fn buffers<'a, I>(descriptors: I) -> Vec<Buffer>
+where
+    I: Iterator<Item = &'a Descriptor>,
+{
+    descriptors
+        .filter(|descriptor| descriptor.len > 0)
+        .map(|descriptor| (descriptor.address, descriptor.len as u64))
+        .collect()
+}
+
+/// Helper function to split the given buffers into the buffers covering the first `size` bytes
+/// and the buffers covering the remaining bytes. Returns `None` if the buffers are too small.
+fn split_buffers(
+    buffers: &[Buffer],
+    size: usize,
+) -> Option<(Vec<Buffer>, Vec<Buffer>)> {
+    let (mut head, mut tail) = (vec![], vec![]);
+    let mut remaining = size as u64;
+
+    for &(address, len) in buffers {
+        if remaining == 0 {
+            tail.push((address, len));
+        } else if len <= remaining {
+            head.push((address, len));
+            remaining -= len;
+        } else {
+            head.push((address, remaining));
+            tail.push((address + remaining, len - remaining));
+            remaining = 0;
+        }
+    }
+
+    match remaining {
+        0 => Some((head, tail)),
+        _ => None,
+    }
+}
+
+/// Helper function to return the total size of the given buffers.
+fn total_size(buffers: &[Buffer]) -> u64 {
+    buffers.iter().map(|&(_, size)| size).sum()
+}
@@ -0,0 +1,381 @@
+//! This module provides the virtio MMIO transport, i.e. the register interface of version 2 of
+//! the virtio-mmio device as described by section 4.2 of the virtio 1.1 specification.
+
+use crate::bus::Device;
+use crate::error::Error;
+use super::{
+    GuestMemory, Queue, VirtioDevice, VIRTIO_F_VERSION_1, VIRTIO_INTERRUPT_CONFIG,
+    VIRTIO_INTERRUPT_USED_RING, VIRTIO_STATUS_DEVICE_NEEDS_RESET, VIRTIO_STATUS_DRIVER_OK,
+    VIRTIO_STATUS_FEATURES_OK,
+};
+
+/// The magic value of the virtio-mmio device, i.e. "virt".
+const MMIO_MAGIC: u32 = 0x7472_6976;
+/// The version of the virtio-mmio device, where version 2 is the non-legacy interface.
+const MMIO_VERSION: u32 = 2;
+/// The vendor ID reported by the devices, where zero means that there is no vendor ID.
+const MMIO_VENDOR_ID: u32 = 0;
+
+/// The offset of the magic value register.
+const MMIO_MAGIC_VALUE: u64 = 0x000;
+/// The offset of the version register.
+const MMIO_VERSION_REG: u64 = 0x004;
+/// The offset of the device ID register.
+const MMIO_DEVICE_ID: u64 = 0x008;
+/// The offset of the vendor ID register.
+const MMIO_VENDOR_ID_REG: u64 = 0x00c;
+/// The offset of the register that reports the selected 32 bits of the device features.
+const MMIO_DEVICE_FEATURES: u64 = 0x010;
+/// The offset of the register that selects 32 bits of the device features.
+const MMIO_DEVICE_FEATURES_SEL: u64 = 0x014;
+/// The offset of the register that accepts the selected 32 bits of the driver features.
+const MMIO_DRIVER_FEATURES: u64 = 0x020;
+/// The offset of the register that selects 32 bits of the driver features.
+const MMIO_DRIVER_FEATURES_SEL: u64 = 0x024;
+/// The offset of the register that selects the virtqueue.
+const MMIO_QUEUE_SEL: u64 = 0x030;
+/// The offset of the register that reports the maximum size of the selected virtqueue.
+const MMIO_QUEUE_NUM_MAX: u64 = 0x034;
+/// The offset of the register that selects the size of the selected virtqueue.
+const MMIO_QUEUE_NUM: u64 = 0x038;
+/// The offset of the register that enables the selected virtqueue.
+const MMIO_QUEUE_READY: u64 = 0x044;
+/// The offset of the register through which the driver notifies a virtqueue.
+const MMIO_QUEUE_NOTIFY: u64 = 0x050;
+/// The offset of the interrupt status register.
+const MMIO_INTERRUPT_STATUS: u64 = 0x060;
+/// The offset of the register through which the driver acknowledges interrupts.
+const MMIO_INTERRUPT_ACK: u64 = 0x064;
+/// The offset of the device status register.
+const MMIO_STATUS: u64 = 0x070;
+/// The offset of the low half of the address of the descriptor table of the selected virtqueue.
+const MMIO_QUEUE_DESC_LOW: u64 = 0x080;
+/// The offset of the high half of the address of the descriptor table of the selected virtqueue.
+const MMIO_QUEUE_DESC_HIGH: u64 = 0x084;
+/// The offset of the low half of the address of the available ring of the selected virtqueue.
+const MMIO_QUEUE_DRIVER_LOW: u64 = 0x090;
+/// The offset of the high half of the address of the available ring of the selected virtqueue.
+const MMIO_QUEUE_DRIVER_HIGH: u64 = 0x094;
+/// The offset of the low half of the address of the used ring of the selected virtqueue.
+const MMIO_QUEUE_DEVICE_LOW: u64 = 0x0a0;
+/// The offset of the high half of the address of the used ring of the selected virtqueue.
+const MMIO_QUEUE_DEVICE_HIGH: u64 = 0x0a4;
+/// The offset of the configuration generation register.
+const MMIO_CONFIG_GENERATION: u64 = 0x0fc;
+/// The offset of the configuration space of the device.
+const MMIO_CONFIG: u64 = 0x100;
+
+/// The callback that raises or lowers the interrupt line of the transport.
+type IrqCallback = Box<dyn FnMut(bool) -> Result<(), Error> + Send>;
+
+/// The virtio MMIO transport of a single [`VirtioDevice`], which is registered on the MMIO bus of
+/// the VM as a [`Device`], see the [module-level documentation](super).
+pub struct MmioTransport<D: VirtioDevice> {
+    /// The device.
+    device: D,
+    /// The guest physical memory of the VM.
+    memory: GuestMemory,
+    /// The callback that drives the interrupt line.
+    irq: IrqCallback,
+    /// The virtqueues of the device.
+    queues: Vec<Queue>,
+    /// The index of the selected virtqueue.
+    queue_sel: u32,
+    /// The index of the selected 32 bits of the device features.
+    device_features_sel: u32,
+    /// The index of the selected 32 bits of the driver features.
+    driver_features_sel: u32,
+    /// The features accepted by the driver.
+    driver_features: u64,
+    /// The device status.
+    status: u32,
+    /// The pending interrupts.
+    interrupt_status: u32,
+    /// The generation of the configuration space, which changes whenever the device changes it.
+    config_generation: u32,
+}
+
+impl<D: VirtioDevice> MmioTransport<D> {
+    /// Creates the transport of the given device, which accesses the virtqueues through the given
+    /// guest physical memory and calls the given callback to raise or lower its interrupt line.
+    pub fn new<F>(device: D, memory: GuestMemory, irq: F) -> Self
+    where
+        F: FnMut(bool) -> Result<(), Error> + Send + 'static,
+    {
+        let queues = device
+            .queue_max_sizes()
+            .iter()
+            .map(|&max_size| Queue::new(max_size))
+            .collect();
+
+        Self {
+            device,
+            memory,
+            irq: Box::new(irq),
+            queues,
+            queue_sel: 0,
+            device_features_sel: 0,
+            driver_features_sel: 0,
+            driver_features: 0,
+            status: 0,
+            interrupt_status: 0,
+            config_generation: 0,
+        }
+    }
+
+    /// Returns the device.
+    pub fn device(&self) -> &D {
+        &self.device
+    }
+
+    /// Returns the device.
+    pub fn device_mut(&mut self) -> &mut D {
+        &mut self.device
+    }
+
+    /// Returns the features accepted by the driver.
+    pub fn driver_features(&self) -> u64 {
+        self.driver_features
+    }
+
+    /// Returns the device status, e.g. to check whether [`VIRTIO_STATUS_DRIVER_OK`] is set.
+    pub fn status(&self) -> u32 {
+        self.status
+    }
+
+    /// Returns the features offered by the device, including the features offered by the
+    /// transport itself.
+    fn device_features(&self) -> u64 {
+        self.device.features() | VIRTIO_F_VERSION_1
+    }
+
+    /// Returns the selected virtqueue, if it exists.
+    fn selected_queue(&mut self) -> Option<&mut Queue> {
+        self.queues.get_mut(self.queue_sel as usize)
+    }
+
+    /// Raises the given interrupts.
+    fn raise_interrupt(&mut self, interrupt: u32) -> Result<(), Error> {
+        let raised = self.interrupt_status == 0;
+
+        self.interrupt_status |= interrupt;
+
+        if raised {
+            (self.irq)(true)?;
+        }
+
+        Ok(())
+    }
+
+    /// Acknowledges the given interrupts, and lowers the interrupt line once no interrupts are
+    /// pending.
+    fn acknowledge_interrupt(&mut self, interrupt: u32) -> Result<(), Error> {
+        if self.interrupt_status == 0 {
+            return Ok(());
+        }
+
+        self.interrupt_status &= !interrupt;
+
+        if self.interrupt_status == 0 {
+            (self.irq)(false)?;
+        }
+
+        Ok(())
+    }
+
+    /// Notifies the driver that the configuration space of the device changed.
+    pub fn signal_config_change(&mut self) -> Result<(), Error> {
+        self.config_generation = self.config_generation.wrapping_add(1);
+
+        self.raise_interrupt(VIRTIO_INTERRUPT_CONFIG)
+    }
+
+    /// Lets the device process the virtqueue with the given index through
+    /// [`VirtioDevice::process_queue`], and interrupts the driver if the device returned any used
+    /// buffers. This does nothing unless the driver is ready and enabled the virtqueue. If the
+    /// driver set up the virtqueue incorrectly, i.e. the device returned
+    /// [`Error::InvalidArgument`] or [`Error::UnmappedGuestAddress`], the device is marked as
+    /// needing a reset rather than returning the error.
+    pub fn process_queue(&mut self, index: usize) -> Result<(), Error> {
+        if self.status & VIRTIO_STATUS_DRIVER_OK == 0 {
+            return Ok(());
+        }
+
+        if !matches!(self.queues.get(index), Some(queue) if queue.ready()) {
+            return Ok(());
+        }
+
+        match self.device.process_queue(index, &mut self.queues, &self.memory) {
+            Ok(true) => self.raise_interrupt(VIRTIO_INTERRUPT_USED_RING),
+            Ok(false) => Ok(()),
+            Err(Error::InvalidArgument) | Err(Error::UnmappedGuestAddress(_)) => {
+                self.status |= VIRTIO_STATUS_DEVICE_NEEDS_RESET;
+
+                self.signal_config_change()
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Resets the device and the transport, as requested by writing zero to the device status.
+    fn reset(&mut self) -> Result<(), Error> {
+        for queue in self.queues.iter_mut() {
+            queue.reset();
+        }
+
+        self.queue_sel = 0;
+        self.device_features_sel = 0;
+        self.driver_features_sel = 0;
+        self.driver_features = 0;
+        self.status = 0;
+        self.device.reset();
+
+        self.acknowledge_interrupt(!0)
+    }
+
+    /// Updates the device status as written by the driver.
+    fn set_status(&mut self, status: u32) -> Result<(), Error> {
+        if status == 0 {
+            return self.reset();
+        }
+
+        let mut status = status & 0xff;
+        let changed = status & !self.status;
+
+        // Only accept the features if the driver accepted a subset of the features offered by
+        // the device, including the non-legacy interface. Otherwise the driver observes that
+        // FEATURES_OK remains clear.
+        if changed & VIRTIO_STATUS_FEATURES_OK != 0 {
+            let features = self.driver_features;
+
+            if features & !self.device_features() != 0 || features & VIRTIO_F_VERSION_1 == 0 {
+                status &= !VIRTIO_STATUS_FEATURES_OK;
+            } else {
+                self.device.ack_features(features);
+            }
+        }
+
+        self.status = status | (self.status & VIRTIO_STATUS_DEVICE_NEEDS_RESET);
+
+        if changed & VIRTIO_STATUS_DRIVER_OK != 0 {
+            self.device.activate()?;
+        }
+
+        Ok(())
+    }
+
+    /// Reads the 32-bit register at the given offset.
+    fn read_register(&mut self, offset: u64) -> u32 {
+        let features = self.device_features();
+
+        match offset {
+            MMIO_MAGIC_VALUE => MMIO_MAGIC,
+            MMIO_VERSION_REG => MMIO_VERSION,
+            MMIO_DEVICE_ID => self.device.device_type(),
+            MMIO_VENDOR_ID_REG => MMIO_VENDOR_ID,
+            MMIO_DEVICE_FEATURES => match self.device_features_sel {
+                0 => features as u32,
+                1 => (features >> 32) as u32,
+                _ => 0,
+            },
+            MMIO_QUEUE_NUM_MAX => self.selected_queue().map_or(0, |queue| queue.max_size() as u32),
+            MMIO_QUEUE_READY => self.selected_queue().map_or(0, |queue| queue.ready() as u32),
+            MMIO_INTERRUPT_STATUS => self.interrupt_status,
+            MMIO_STATUS => self.status,
+            MMIO_QUEUE_DESC_LOW => self.selected_queue().map_or(0, |queue| queue.desc_table(false)),
+            MMIO_QUEUE_DESC_HIGH => self.selected_queue().map_or(0, |queue| queue.desc_table(true)),
+            MMIO_QUEUE_DRIVER_LOW =>
+                self.selected_queue().map_or(0, |queue| queue.avail_ring(false)),
+            MMIO_QUEUE_DRIVER_HIGH =>
+                self.selected_queue().map_or(0, |queue| queue.avail_ring(true)),
+            MMIO_QUEUE_DEVICE_LOW =>
+                self.selected_queue().map_or(0, |queue| queue.used_ring(false)),
+            MMIO_QUEUE_DEVICE_HIGH =>
+                self.selected_queue().map_or(0, |queue| queue.used_ring(true)),
+            MMIO_CONFIG_GENERATION => self.config_generation,
+            _ => 0,
+        }
+    }
+
+    /// Writes the given value to the 32-bit register at the given offset.
+    fn write_register(&mut self, offset: u64, value: u32) -> Result<(), Error> {
+        // The virtqueues can only be configured while they are disabled.
+        let configurable = matches!(self.selected_queue(), Some(queue) if !queue.ready());
+
+        match offset {
+            MMIO_DEVICE_FEATURES_SEL => self.device_features_sel = value,
+            // The features can only be changed until they have been accepted.
+            MMIO_DRIVER_FEATURES if self.status & VIRTIO_STATUS_FEATURES_OK == 0 => {
+                self.driver_features = match self.driver_features_sel {
+                    0 => (self.driver_features & !0xffff_ffff) | value as u64,
+                    1 => (self.driver_features & 0xffff_ffff) | ((value as u64) << 32),
+                    _ => self.driver_features,
+                };
+            }
+            MMIO_DRIVER_FEATURES_SEL => self.driver_features_sel = value,
+            MMIO_QUEUE_SEL => self.queue_sel = value,
+            MMIO_QUEUE_NOTIFY => return self.process_queue(value as usize),
+            MMIO_INTERRUPT_ACK => return self.acknowledge_interrupt(value),
+            MMIO_STATUS => return self.set_status(value),
+            _ => (),
+        }
+
+        let queue = match self.selected_queue() {
+            Some(queue) => queue,
+            _ => return Ok(()),
+        };
+
+        match offset {
+            MMIO_QUEUE_READY => queue.set_ready(value & 1 != 0),
+            MMIO_QUEUE_NUM if configurable => queue.set_size(value as u16),
+            MMIO_QUEUE_DESC_LOW if configurable => queue.set_desc_table(value, false),
+            MMIO_QUEUE_DESC_HIGH if configurable => queue.set_desc_table(value, true),
+            MMIO_QUEUE_DRIVER_LOW if configurable => queue.set_avail_ring(value, false),
+            MMIO_QUEUE_DRIVER_HIGH if configurable => queue.set_avail_ring(value, true),
+            MMIO_QUEUE_DEVICE_LOW if configurable => queue.set_used_ring(value, false),
+            MMIO_QUEUE_DEVICE_HIGH if configurable => queue.set_used_ring(value, true),
+            _ => (),
+        }
+
+        Ok(())
+    }
+}
+
+impl<D: VirtioDevice> Device for MmioTransport<D> {
+    fn read(&mut self, offset: u64, data: &mut [u8]) -> Result<(), Error> {
+        if offset >= MMIO_CONFIG {
+            self.device.read_config(offset - MMIO_CONFIG, data);
+
+            return Ok(());
+        }
+
+        // The registers only support aligned 32-bit accesses.
+        let value = match data.len() {
+            4 if offset % 4 == 0 => self.read_register(offset),
+            _ => 0,
+        };
+
+        for (byte, value) in data.iter_mut().zip(value.to_le_bytes().iter()) {
+            *byte = *value;
+        }
+
+        Ok(())
+    }
+
+    fn write(&mut self, offset: u64, data: &[u8]) -> Result<(), Error> {
+        if offset >= MMIO_CONFIG {
+            self.device.write_config(offset - MMIO_CONFIG, data);
+
+            return Ok(());
+        }
+
+        // The registers only support aligned 32-bit accesses.
+        if data.len() != 4 || offset % 4 != 0 {
+            return Ok(());
+        }
+
+        let value = u32::from_le_bytes([data[0], data[1], data[2], data[3]]);
+
+        self.write_register(offset, value)
+    }
+}
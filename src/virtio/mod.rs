@@ -0,0 +1,255 @@
+//! This module provides the virtio 1.1 MMIO transport, see [`MmioTransport`], such that
+//! paravirtualized devices can be implemented through the [`VirtioDevice`] trait and plugged into
+//! a VM on any platform, as the transport only relies on the MMIO exits and the guest physical
+//! memory of the VM.
+//!
+//! The VMM wraps the device in an [`MmioTransport`], registers the transport for
+//! [`VIRTIO_MMIO_SIZE`] bytes of guest physical memory through
+//! [`crate::Vm::register_mmio_device`], and describes the device to the guest, e.g. through the
+//! device tree or the `virtio_mmio.device=` option of the Linux kernel command line. The transport
+//! then handles:
+//!
+//!  * The negotiation of the features, where [`VIRTIO_F_VERSION_1`] is always offered and must be
+//!    accepted by the driver, as the legacy interface is not supported.
+//!  * The configuration of the split virtqueues, see [`Queue`], which the device processes from
+//!    [`VirtioDevice::process_queue`] once the driver notifies the queue. Devices that complete
+//!    requests asynchronously, e.g. upon receiving a packet, call
+//!    [`MmioTransport::process_queue`] through the shared handle to the transport instead.
+//!  * The interrupt status, where the interrupt line is raised while any interrupt is pending and
+//!    lowered once the driver acknowledged all of them, through the callback given upon creation,
+//!    e.g. by calling [`crate::Vm::set_irq_line`].
+//!
+//! The following devices are provided:
+//!
+//!  * [`Block`]: a disk backed by a file or a raw block device on the host, see [`block`].
+//!  * [`VsockDevice`]: the sockets between the host and the guest of [`crate::vsock`], see
+//!    [`vsock`]. This is not available on Microsoft Windows, where [`crate::vsock`] relies on
+//!    Hyper-V sockets instead.
+//!
+//! The packed virtqueues and [`VIRTIO_F_EVENT_IDX`] are not supported, and the device is
+//! interrupted whenever it returns any used buffers, regardless of whether the driver asked to
+//! suppress the interrupts. The shared memory regions of virtio 1.1 are not supported either.
+
+pub mod block;
+mod mmio;
+mod queue;
+#[cfg(not(target_os = "windows"))]
+pub mod vsock;
+
+pub use block::Block;
+pub use mmio::MmioTransport;
+pub use queue::{Descriptor, DescriptorChain, Queue};
+#[cfg(not(target_os = "windows"))]
+pub use vsock::VsockDevice;
+
+use crate::bytes::{self, AsBytes, FromBytes};
+use crate::error::Error;
+use crate::platform;
+use crate::vm::{read_across_regions, write_across_regions, Vm};
+use std::sync::{Arc, RwLock};
+
+/// The size of the register window of the MMIO transport, including the configuration space.
+pub const VIRTIO_MMIO_SIZE: u64 = 0x200;
+
+/// The device ID of a network card.
+pub const VIRTIO_ID_NET: u32 = 1;
+/// The device ID of a block device.
+pub const VIRTIO_ID_BLOCK: u32 = 2;
+/// The device ID of a console.
+pub const VIRTIO_ID_CONSOLE: u32 = 3;
+/// The device ID of an entropy source.
+pub const VIRTIO_ID_RNG: u32 = 4;
+/// The device ID of a memory balloon.
+pub const VIRTIO_ID_BALLOON: u32 = 5;
+/// The device ID of a socket device.
+pub const VIRTIO_ID_VSOCK: u32 = 19;
+
+/// The feature bit of the indirect descriptors.
+pub const VIRTIO_F_INDIRECT_DESC: u64 = 1 << 28;
+/// The feature bit of the `used_event` and `avail_event` fields, which is not supported.
+pub const VIRTIO_F_EVENT_IDX: u64 = 1 << 29;
+/// The feature bit of the virtio 1.x interface, i.e. the non-legacy interface.
+pub const VIRTIO_F_VERSION_1: u64 = 1 << 32;
+
+/// The driver status bit that indicates that the guest found the device.
+pub const VIRTIO_STATUS_ACKNOWLEDGE: u32 = 1;
+/// The driver status bit that indicates that the guest knows how to drive the device.
+pub const VIRTIO_STATUS_DRIVER: u32 = 2;
+/// The driver status bit that indicates that the driver is ready to drive the device.
+pub const VIRTIO_STATUS_DRIVER_OK: u32 = 4;
+/// The driver status bit that indicates that the feature negotiation is complete.
+pub const VIRTIO_STATUS_FEATURES_OK: u32 = 8;
+/// The device status bit that indicates that the device encountered an error and must be reset.
+pub const VIRTIO_STATUS_DEVICE_NEEDS_RESET: u32 = 0x40;
+/// The driver status bit that indicates that the driver gave up on the device.
+pub const VIRTIO_STATUS_FAILED: u32 = 0x80;
+
+/// The interrupt status bit that indicates that a virtqueue has used buffers.
+pub const VIRTIO_INTERRUPT_USED_RING: u32 = 1;
+/// The interrupt status bit that indicates that the configuration of the device changed.
+pub const VIRTIO_INTERRUPT_CONFIG: u32 = 2;
+
+/// A handle to the guest physical memory of a VM, through which the virtqueues are accessed.
+/// Unlike [`Vm`], the handle does not borrow anything, such that it can be moved into the devices
+/// and to other threads.
+#[derive(Clone)]
+pub struct GuestMemory {
+    /// The guest physical memory behind the handle.
+    backing: GuestMemoryBacking,
+}
+
+/// The guest physical memory behind a [`GuestMemory`] handle.
+#[derive(Clone)]
+enum GuestMemoryBacking {
+    /// The internal platform-specific implementation of the [`platform::Vm`] struct.
+    Vm(Arc<RwLock<platform::Vm>>),
+    /// A buffer that serves as the guest physical memory starting at guest address zero, such
+    /// that the virtqueues can be tested without a VM.
+    #[cfg(test)]
+    Buffer(Arc<RwLock<Vec<u8>>>),
+}
+
+impl GuestMemory {
+    /// Creates the handle to the guest physical memory of the given VM.
+    pub fn new(vm: &Vm) -> Self {
+        Self {
+            backing: GuestMemoryBacking::Vm(vm.inner.clone()),
+        }
+    }
+
+    /// Creates the handle to a buffer of the given size that serves as the guest physical memory.
+    #[cfg(test)]
+    pub(crate) fn from_buffer(size: usize) -> Self {
+        Self {
+            backing: GuestMemoryBacking::Buffer(Arc::new(RwLock::new(vec![0; size]))),
+        }
+    }
+
+    /// Reads the bytes at the given guest physical address. Fails with
+    /// [`Error::UnmappedGuestAddress`] if not all the bytes could be read.
+    pub fn read(&self, bytes: &mut [u8], guest_address: u64) -> Result<(), Error> {
+        let size = match &self.backing {
+            GuestMemoryBacking::Vm(vm) => {
+                read_across_regions(&vm.read().unwrap(), bytes, guest_address)?
+            }
+            #[cfg(test)]
+            GuestMemoryBacking::Buffer(buffer) => {
+                let buffer = buffer.read().unwrap();
+                let data = buffer_range(&buffer, guest_address, bytes.len())?;
+
+                bytes[..data.len()].copy_from_slice(&buffer[data.clone()]);
+                data.len()
+            }
+        };
+
+        if size != bytes.len() {
+            return Err(Error::UnmappedGuestAddress(guest_address + size as u64));
+        }
+
+        Ok(())
+    }
+
+    /// Writes the bytes to the given guest physical address. Fails with
+    /// [`Error::UnmappedGuestAddress`] if not all the bytes could be written.
+    pub fn write(&self, guest_address: u64, bytes: &[u8]) -> Result<(), Error> {
+        let size = match &self.backing {
+            GuestMemoryBacking::Vm(vm) => {
+                write_across_regions(&mut vm.write().unwrap(), guest_address, bytes)?
+            }
+            #[cfg(test)]
+            GuestMemoryBacking::Buffer(buffer) => {
+                let mut buffer = buffer.write().unwrap();
+                let data = buffer_range(&buffer, guest_address, bytes.len())?;
+
+                buffer[data.clone()].copy_from_slice(&bytes[..data.len()]);
+                data.len()
+            }
+        };
+
+        if size != bytes.len() {
+            return Err(Error::UnmappedGuestAddress(guest_address + size as u64));
+        }
+
+        Ok(())
+    }
+
+    /// Reads the object of type `T` at the given guest physical address.
+    pub fn read_obj<T: FromBytes>(&self, guest_address: u64) -> Result<T, Error> {
+        let mut bytes = vec![0u8; std::mem::size_of::<T>()];
+
+        self.read(&mut bytes, guest_address)?;
+
+        Ok(bytes::from_bytes(&bytes))
+    }
+
+    /// Writes the given object to the given guest physical address.
+    pub fn write_obj<T: AsBytes>(&self, guest_address: u64, value: &T) -> Result<(), Error> {
+        self.write(guest_address, bytes::as_bytes(value))
+    }
+}
+
+/// Helper function to return the range of the given buffer that backs the given number of bytes
+/// at the given guest physical address, which is cut short at the end of the buffer. Returns
+/// [`Error::UnmappedGuestAddress`] if the guest physical address lies beyond the buffer.
+#[cfg(test)]
+fn buffer_range(
+    buffer: &[u8],
+    guest_address: u64,
+    size: usize,
+) -> Result<std::ops::Range<usize>, Error> {
+    use std::convert::TryFrom;
+
+    match usize::try_from(guest_address) {
+        Ok(start) if start < buffer.len() => Ok(start..buffer.len().min(start.saturating_add(size))),
+        _ => Err(Error::UnmappedGuestAddress(guest_address)),
+    }
+}
+
+/// A virtio device that is plugged into a VM through an [`MmioTransport`], see the
+/// [module-level documentation](self).
+pub trait VirtioDevice: Send {
+    /// Returns the device ID, e.g. [`VIRTIO_ID_NET`].
+    fn device_type(&self) -> u32;
+
+    /// Returns the maximum size of each of the virtqueues of the device, which also determines the
+    /// number of virtqueues. The sizes must be powers of two of at most 32768.
+    fn queue_max_sizes(&self) -> &[u16];
+
+    /// Returns the features offered by the device. [`VIRTIO_F_VERSION_1`] is always offered by
+    /// the transport.
+    fn features(&self) -> u64;
+
+    /// Called with the features accepted by the driver once the driver completed the feature
+    /// negotiation. The features are a subset of the features offered by the device.
+    fn ack_features(&mut self, _features: u64) {}
+
+    /// Reads the configuration space of the device at the given offset by filling the given data.
+    /// The configuration space reads as zero by default.
+    fn read_config(&self, _offset: u64, _data: &mut [u8]) {}
+
+    /// Writes the given data to the configuration space of the device at the given offset. The
+    /// writes are ignored by default.
+    fn write_config(&mut self, _offset: u64, _data: &[u8]) {}
+
+    /// Called once the driver is ready to drive the device, i.e. when it sets
+    /// [`VIRTIO_STATUS_DRIVER_OK`].
+    fn activate(&mut self) -> Result<(), Error> {
+        Ok(())
+    }
+
+    /// Called when the driver resets the device, after which the virtqueues have been reset to
+    /// their initial state.
+    fn reset(&mut self) {}
+
+    /// Processes the available buffers of the virtqueue with the given index, e.g. after the
+    /// driver notified the virtqueue. All the virtqueues of the device are given, such that the
+    /// device can also return buffers on the other virtqueues, e.g. to respond to a request on the
+    /// virtqueue used to receive. Returns whether any buffers have been returned to the driver
+    /// through [`Queue::add_used`], such that the driver should be interrupted.
+    fn process_queue(
+        &mut self,
+        index: usize,
+        queues: &mut [Queue],
+        memory: &GuestMemory,
+    ) -> Result<bool, Error>;
+}
@@ -0,0 +1,589 @@
+//! This module provides the split virtqueues of virtio 1.1, which consist of the descriptor table,
+//! the available ring written by the driver and the used ring written by the device, all of which
+//! live in guest physical memory.
+
+use crate::bytes::{AsBytes, FromBytes, Le};
+use crate::error::Error;
+use std::sync::atomic::{fence, Ordering};
+use super::GuestMemory;
+
+/// The descriptor flag that indicates that the buffer continues in the next descriptor.
+const VIRTQ_DESC_F_NEXT: u16 = 1;
+/// The descriptor flag that indicates that the buffer is written by the device.
+const VIRTQ_DESC_F_WRITE: u16 = 2;
+/// The descriptor flag that indicates that the buffer refers to a table of descriptors.
+const VIRTQ_DESC_F_INDIRECT: u16 = 4;
+
+/// The size of a descriptor in the descriptor table.
+const DESCRIPTOR_SIZE: u64 = 16;
+/// The size of an element of the used ring.
+const USED_ELEMENT_SIZE: u64 = 8;
+
+/// A descriptor as laid out in the descriptor table.
+#[derive(Clone, Copy)]
+#[repr(C)]
+struct RawDescriptor {
+    address: Le<u64>,
+    len: Le<u32>,
+    flags: Le<u16>,
+    next: Le<u16>,
+}
+
+unsafe impl FromBytes for RawDescriptor {}
+unsafe impl AsBytes for RawDescriptor {}
+
+/// An element of the used ring.
+#[derive(Clone, Copy)]
+#[repr(C)]
+struct UsedElement {
+    id: Le<u32>,
+    len: Le<u32>,
+}
+
+unsafe impl FromBytes for UsedElement {}
+unsafe impl AsBytes for UsedElement {}
+
+/// A buffer in guest physical memory that is part of a [`DescriptorChain`].
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Descriptor {
+    /// The guest physical address of the buffer.
+    pub address: u64,
+    /// The size of the buffer in bytes.
+    pub len: u32,
+    /// Whether the buffer is written by the device, rather than read.
+    pub writable: bool,
+}
+
+/// The buffers that the driver made available in a single request, where the buffers read by the
+/// device precede the buffers written by the device. Indirect descriptors have been resolved.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct DescriptorChain {
+    /// The index of the head of the chain in the descriptor table, which identifies the request
+    /// when it is returned through [`Queue::add_used`].
+    pub head: u16,
+    /// The buffers of the chain.
+    pub descriptors: Vec<Descriptor>,
+}
+
+impl DescriptorChain {
+    /// Returns the buffers that are read by the device.
+    pub fn readable(&self) -> impl Iterator<Item = &Descriptor> {
+        self.descriptors.iter().filter(|descriptor| !descriptor.writable)
+    }
+
+    /// Returns the buffers that are written by the device.
+    pub fn writable(&self) -> impl Iterator<Item = &Descriptor> {
+        self.descriptors.iter().filter(|descriptor| descriptor.writable)
+    }
+
+    /// Reads the contents of all the buffers that are read by the device, which the driver
+    /// controls the sizes of. Returns [`Error::InvalidArgument`] if the buffers hold more than
+    /// `max_len` bytes in total.
+    pub fn read_all(&self, memory: &GuestMemory, max_len: usize) -> Result<Vec<u8>, Error> {
+        let size = self
+            .readable()
+            .try_fold(0usize, |size, descriptor| size.checked_add(descriptor.len as usize))
+            .filter(|&size| size <= max_len)
+            .ok_or(Error::InvalidArgument)?;
+
+        let mut bytes = Vec::with_capacity(size);
+
+        for descriptor in self.readable() {
+            let offset = bytes.len();
+
+            bytes.resize(offset + descriptor.len as usize, 0);
+            memory.read(&mut bytes[offset..], descriptor.address)?;
+        }
+
+        Ok(bytes)
+    }
+
+    /// Writes the given data to the buffers that are written by the device, in order. Returns the
+    /// number of bytes written, which is less than the size of the data if the buffers are too
+    /// small.
+    pub fn write_all(&self, memory: &GuestMemory, data: &[u8]) -> Result<usize, Error> {
+        let mut offset = 0;
+
+        for descriptor in self.writable() {
+            if offset == data.len() {
+                break;
+            }
+
+            let size = (descriptor.len as usize).min(data.len() - offset);
+
+            memory.write(descriptor.address, &data[offset..offset + size])?;
+            offset += size;
+        }
+
+        Ok(offset)
+    }
+}
+
+/// A split virtqueue as configured by the driver through the transport.
+#[derive(Clone, Debug)]
+pub struct Queue {
+    /// The maximum size of the virtqueue supported by the device.
+    max_size: u16,
+    /// The size of the virtqueue selected by the driver.
+    size: u16,
+    /// Whether the driver enabled the virtqueue.
+    ready: bool,
+    /// The guest physical address of the descriptor table.
+    desc_table: u64,
+    /// The guest physical address of the available ring.
+    avail_ring: u64,
+    /// The guest physical address of the used ring.
+    used_ring: u64,
+    /// The index of the next entry of the available ring to process.
+    next_avail: u16,
+    /// The index of the next entry of the used ring to fill.
+    next_used: u16,
+}
+
+impl Queue {
+    /// Creates the virtqueue with the given maximum size in its reset state.
+    pub fn new(max_size: u16) -> Self {
+        Self {
+            max_size,
+            size: max_size,
+            ready: false,
+            desc_table: 0,
+            avail_ring: 0,
+            used_ring: 0,
+            next_avail: 0,
+            next_used: 0,
+        }
+    }
+
+    /// Returns the maximum size of the virtqueue supported by the device.
+    pub fn max_size(&self) -> u16 {
+        self.max_size
+    }
+
+    /// Returns the size of the virtqueue selected by the driver.
+    pub fn size(&self) -> u16 {
+        self.size
+    }
+
+    /// Returns whether the driver enabled the virtqueue.
+    pub fn ready(&self) -> bool {
+        self.ready
+    }
+
+    /// Resets the virtqueue to its initial state.
+    pub(crate) fn reset(&mut self) {
+        *self = Self::new(self.max_size);
+    }
+
+    /// Selects the size of the virtqueue, which is ignored unless it is a power of two that does
+    /// not exceed the maximum size.
+    pub(crate) fn set_size(&mut self, size: u16) {
+        if size.is_power_of_two() && size <= self.max_size {
+            self.size = size;
+        }
+    }
+
+    /// Enables or disables the virtqueue.
+    pub(crate) fn set_ready(&mut self, ready: bool) {
+        self.ready = ready;
+    }
+
+    /// Sets the low or the high half of the guest physical address of the descriptor table.
+    pub(crate) fn set_desc_table(&mut self, value: u32, high: bool) {
+        self.desc_table = set_half(self.desc_table, value, high);
+    }
+
+    /// Sets the low or the high half of the guest physical address of the available ring.
+    pub(crate) fn set_avail_ring(&mut self, value: u32, high: bool) {
+        self.avail_ring = set_half(self.avail_ring, value, high);
+    }
+
+    /// Sets the low or the high half of the guest physical address of the used ring.
+    pub(crate) fn set_used_ring(&mut self, value: u32, high: bool) {
+        self.used_ring = set_half(self.used_ring, value, high);
+    }
+
+    /// Returns the low or the high half of the guest physical address of the descriptor table.
+    pub(crate) fn desc_table(&self, high: bool) -> u32 {
+        get_half(self.desc_table, high)
+    }
+
+    /// Returns the low or the high half of the guest physical address of the available ring.
+    pub(crate) fn avail_ring(&self, high: bool) -> u32 {
+        get_half(self.avail_ring, high)
+    }
+
+    /// Returns the low or the high half of the guest physical address of the used ring.
+    pub(crate) fn used_ring(&self, high: bool) -> u32 {
+        get_half(self.used_ring, high)
+    }
+
+    /// Reads the descriptor with the given index from the descriptor table at the given guest
+    /// physical address with the given number of descriptors.
+    fn read_descriptor(
+        memory: &GuestMemory,
+        table: u64,
+        count: u16,
+        index: u16,
+    ) -> Result<RawDescriptor, Error> {
+        if index >= count {
+            return Err(Error::InvalidArgument);
+        }
+
+        memory.read_obj(guest_address(table, index as u64 * DESCRIPTOR_SIZE)?)
+    }
+
+    /// Walks the chain of descriptors starting at the given index, where the chain may continue
+    /// in an indirect table of descriptors.
+    fn read_chain(&self, memory: &GuestMemory, head: u16) -> Result<DescriptorChain, Error> {
+        let mut descriptors = vec![];
+        let (mut table, mut count, mut index) = (self.desc_table, self.size, head);
+        let (mut indirect, mut visited) = (false, 0);
+
+        loop {
+            // A chain can visit every descriptor of a table at most once, so anything longer is a
+            // loop.
+            if visited == count {
+                return Err(Error::InvalidArgument);
+            }
+
+            visited += 1;
+
+            let raw = Self::read_descriptor(memory, table, count, index)?;
+            let flags = raw.flags.get();
+
+            if flags & VIRTQ_DESC_F_INDIRECT != 0 {
+                let len = raw.len.get() as u64;
+
+                // Indirect tables cannot be nested or chained.
+                if indirect || flags & VIRTQ_DESC_F_NEXT != 0 || len % DESCRIPTOR_SIZE != 0 {
+                    return Err(Error::InvalidArgument);
+                }
+
+                let entries = len / DESCRIPTOR_SIZE;
+
+                if entries == 0 || entries > u16::MAX as u64 {
+                    return Err(Error::InvalidArgument);
+                }
+
+                table = raw.address.get();
+                count = entries as u16;
+                index = 0;
+                indirect = true;
+                visited = 0;
+
+                continue;
+            }
+
+            descriptors.push(Descriptor {
+                address: raw.address.get(),
+                len: raw.len.get(),
+                writable: flags & VIRTQ_DESC_F_WRITE != 0,
+            });
+
+            if flags & VIRTQ_DESC_F_NEXT == 0 {
+                break;
+            }
+
+            index = raw.next.get();
+        }
+
+        Ok(DescriptorChain {
+            head,
+            descriptors,
+        })
+    }
+
+    /// Takes the next chain of descriptors that the driver made available, if any. Returns
+    /// [`Error::InvalidArgument`] if the driver set up the virtqueue or the chain incorrectly,
+    /// e.g. if the index of the available ring is more than the size of the virtqueue ahead.
+    pub fn pop(&mut self, memory: &GuestMemory) -> Result<Option<DescriptorChain>, Error> {
+        if !self.ready {
+            return Ok(None);
+        }
+
+        let avail_idx: Le<u16> = memory.read_obj(guest_address(self.avail_ring, 2)?)?;
+        let avail_idx = avail_idx.get();
+
+        if avail_idx == self.next_avail {
+            return Ok(None);
+        }
+
+        // The driver cannot make more chains available than the available ring holds.
+        if avail_idx.wrapping_sub(self.next_avail) > self.size {
+            return Err(Error::InvalidArgument);
+        }
+
+        // Read the ring entry only after observing the index that covers it.
+        fence(Ordering::Acquire);
+
+        let slot = (self.next_avail % self.size) as u64;
+        let head: Le<u16> = memory.read_obj(guest_address(self.avail_ring, 4 + slot * 2)?)?;
+        let chain = self.read_chain(memory, head.get())?;
+
+        self.next_avail = self.next_avail.wrapping_add(1);
+
+        Ok(Some(chain))
+    }
+
+    /// Returns the chain of descriptors with the given head to the driver, where the given number
+    /// of bytes have been written to the buffers that are written by the device.
+    pub fn add_used(&mut self, memory: &GuestMemory, head: u16, len: u32) -> Result<(), Error> {
+        if head >= self.size {
+            return Err(Error::InvalidArgument);
+        }
+
+        let slot = (self.next_used % self.size) as u64;
+        let element = UsedElement {
+            id: Le::new(head as u32),
+            len: Le::new(len),
+        };
+
+        let address = guest_address(self.used_ring, 4 + slot * USED_ELEMENT_SIZE)?;
+
+        memory.write_obj(address, &element)?;
+
+        self.next_used = self.next_used.wrapping_add(1);
+
+        // Publish the index only after the element it covers.
+        fence(Ordering::Release);
+
+        memory.write_obj(guest_address(self.used_ring, 2)?, &Le::new(self.next_used))
+    }
+}
+
+/// Helper function to compute the guest physical address at the given offset from the given
+/// guest physical address that the driver set up. Returns [`Error::InvalidArgument`] if the
+/// guest physical address overflows.
+fn guest_address(base: u64, offset: u64) -> Result<u64, Error> {
+    base.checked_add(offset).ok_or(Error::InvalidArgument)
+}
+
+/// Helper function to replace the low or the high half of the given 64-bit value.
+fn set_half(value: u64, half: u32, high: bool) -> u64 {
+    match high {
+        true => (value & 0x0000_0000_ffff_ffff) | ((half as u64) << 32),
+        _ => (value & 0xffff_ffff_0000_0000) | half as u64,
+    }
+}
+
+/// Helper function to return the low or the high half of the given 64-bit value.
+fn get_half(value: u64, high: bool) -> u32 {
+    match high {
+        true => (value >> 32) as u32,
+        _ => value as u32,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The guest physical address of the descriptor table.
+    const DESC_TABLE: u64 = 0x0000;
+    /// The guest physical address of the available ring.
+    const AVAIL_RING: u64 = 0x1000;
+    /// The guest physical address of the used ring.
+    const USED_RING: u64 = 0x2000;
+    /// The guest physical address of the indirect table of descriptors.
+    const INDIRECT_TABLE: u64 = 0x3000;
+    /// The guest physical address of the buffers.
+    const BUFFERS: u64 = 0x4000;
+
+    /// Helper function to set up a virtqueue with eight descriptors in a buffer that serves as
+    /// the guest physical memory.
+    fn setup() -> (Queue, GuestMemory) {
+        let mut queue = Queue::new(8);
+        queue.set_desc_table(DESC_TABLE as u32, false);
+        queue.set_avail_ring(AVAIL_RING as u32, false);
+        queue.set_used_ring(USED_RING as u32, false);
+        queue.set_ready(true);
+
+        (queue, GuestMemory::from_buffer(0x10000))
+    }
+
+    /// Helper function to write the descriptor with the given index to the given table.
+    fn write_descriptor(
+        memory: &GuestMemory,
+        table: u64,
+        index: u16,
+        address: u64,
+        len: u32,
+        flags: u16,
+        next: u16,
+    ) {
+        let raw = RawDescriptor {
+            address: Le::new(address),
+            len: Le::new(len),
+            flags: Le::new(flags),
+            next: Le::new(next),
+        };
+
+        memory.write_obj(table + index as u64 * DESCRIPTOR_SIZE, &raw).unwrap();
+    }
+
+    /// Helper function to make the chains with the given heads available.
+    fn make_available(memory: &GuestMemory, heads: &[u16]) {
+        for (slot, head) in heads.iter().enumerate() {
+            memory.write_obj(AVAIL_RING + 4 + slot as u64 * 2, &Le::new(*head)).unwrap();
+        }
+
+        memory.write_obj(AVAIL_RING + 2, &Le::new(heads.len() as u16)).unwrap();
+    }
+
+    #[test]
+    fn chain() {
+        let (mut queue, memory) = setup();
+        write_descriptor(&memory, DESC_TABLE, 0, BUFFERS, 4, VIRTQ_DESC_F_NEXT, 5);
+        write_descriptor(&memory, DESC_TABLE, 5, BUFFERS + 4, 8, VIRTQ_DESC_F_WRITE, 0);
+        memory.write(BUFFERS, b"ping").unwrap();
+        make_available(&memory, &[0]);
+
+        let chain = queue.pop(&memory).unwrap().unwrap();
+
+        assert_eq!(chain.head, 0);
+        assert_eq!(chain.descriptors.len(), 2);
+        assert_eq!(chain.read_all(&memory, 4).unwrap(), b"ping");
+        assert_eq!(chain.write_all(&memory, b"pong").unwrap(), 4);
+        assert!(queue.pop(&memory).unwrap().is_none());
+
+        queue.add_used(&memory, chain.head, 4).unwrap();
+
+        let used_idx: Le<u16> = memory.read_obj(USED_RING + 2).unwrap();
+        let element: UsedElement = memory.read_obj(USED_RING + 4).unwrap();
+
+        assert_eq!(used_idx.get(), 1);
+        assert_eq!((element.id.get(), element.len.get()), (0, 4));
+    }
+
+    #[test]
+    fn not_ready() {
+        let (mut queue, memory) = setup();
+        queue.set_ready(false);
+        make_available(&memory, &[0]);
+
+        assert!(queue.pop(&memory).unwrap().is_none());
+    }
+
+    #[test]
+    fn chain_loop() {
+        let (mut queue, memory) = setup();
+        write_descriptor(&memory, DESC_TABLE, 0, BUFFERS, 4, VIRTQ_DESC_F_NEXT, 1);
+        write_descriptor(&memory, DESC_TABLE, 1, BUFFERS, 4, VIRTQ_DESC_F_NEXT, 0);
+        make_available(&memory, &[0]);
+
+        assert!(matches!(queue.pop(&memory), Err(Error::InvalidArgument)));
+    }
+
+    #[test]
+    fn chain_out_of_bounds() {
+        let (mut queue, memory) = setup();
+        write_descriptor(&memory, DESC_TABLE, 0, BUFFERS, 4, VIRTQ_DESC_F_NEXT, 8);
+        make_available(&memory, &[0, 8]);
+
+        // The next descriptor and the head of the second chain are beyond the table.
+        assert!(matches!(queue.pop(&memory), Err(Error::InvalidArgument)));
+        assert!(matches!(queue.pop(&memory), Err(Error::InvalidArgument)));
+    }
+
+    #[test]
+    fn indirect() {
+        let (mut queue, memory) = setup();
+        write_descriptor(&memory, DESC_TABLE, 0, INDIRECT_TABLE, 32, VIRTQ_DESC_F_INDIRECT, 0);
+        write_descriptor(&memory, INDIRECT_TABLE, 0, BUFFERS, 4, VIRTQ_DESC_F_NEXT, 1);
+        write_descriptor(&memory, INDIRECT_TABLE, 1, BUFFERS + 4, 8, VIRTQ_DESC_F_WRITE, 0);
+        make_available(&memory, &[0]);
+
+        let chain = queue.pop(&memory).unwrap().unwrap();
+
+        assert_eq!(chain.readable().count(), 1);
+        assert_eq!(chain.writable().next().unwrap().address, BUFFERS + 4);
+    }
+
+    #[test]
+    fn invalid_indirect() {
+        let flags = VIRTQ_DESC_F_INDIRECT;
+
+        // The size of the table is not a multiple of the size of a descriptor.
+        let (mut queue, memory) = setup();
+        write_descriptor(&memory, DESC_TABLE, 0, INDIRECT_TABLE, 24, flags, 0);
+        make_available(&memory, &[0]);
+        assert!(matches!(queue.pop(&memory), Err(Error::InvalidArgument)));
+
+        // The table is empty.
+        let (mut queue, memory) = setup();
+        write_descriptor(&memory, DESC_TABLE, 0, INDIRECT_TABLE, 0, flags, 0);
+        make_available(&memory, &[0]);
+        assert!(matches!(queue.pop(&memory), Err(Error::InvalidArgument)));
+
+        // The table is nested.
+        let (mut queue, memory) = setup();
+        write_descriptor(&memory, DESC_TABLE, 0, INDIRECT_TABLE, 16, flags, 0);
+        write_descriptor(&memory, INDIRECT_TABLE, 0, INDIRECT_TABLE, 16, flags, 0);
+        make_available(&memory, &[0]);
+        assert!(matches!(queue.pop(&memory), Err(Error::InvalidArgument)));
+
+        // The table contains a loop.
+        let (mut queue, memory) = setup();
+        write_descriptor(&memory, DESC_TABLE, 0, INDIRECT_TABLE, 32, flags, 0);
+        write_descriptor(&memory, INDIRECT_TABLE, 0, BUFFERS, 4, VIRTQ_DESC_F_NEXT, 1);
+        write_descriptor(&memory, INDIRECT_TABLE, 1, BUFFERS, 4, VIRTQ_DESC_F_NEXT, 0);
+        make_available(&memory, &[0]);
+        assert!(matches!(queue.pop(&memory), Err(Error::InvalidArgument)));
+
+        // The table lies at the end of the guest physical address space.
+        let (mut queue, memory) = setup();
+        write_descriptor(&memory, DESC_TABLE, 0, u64::MAX - 15, 32, flags, 0);
+        make_available(&memory, &[0]);
+        assert!(matches!(queue.pop(&memory), Err(Error::UnmappedGuestAddress(_))));
+    }
+
+    #[test]
+    fn avail_idx_too_far_ahead() {
+        let (mut queue, memory) = setup();
+
+        for avail_idx in [9, 0x8000, 0xffff] {
+            memory.write_obj(AVAIL_RING + 2, &Le::new(avail_idx as u16)).unwrap();
+
+            assert!(matches!(queue.pop(&memory), Err(Error::InvalidArgument)));
+        }
+    }
+
+    #[test]
+    fn overflowing_rings() {
+        let (mut queue, memory) = setup();
+        queue.set_avail_ring(u32::MAX - 1, false);
+        queue.set_avail_ring(u32::MAX, true);
+        queue.set_used_ring(u32::MAX - 1, false);
+        queue.set_used_ring(u32::MAX, true);
+
+        assert!(matches!(queue.pop(&memory), Err(Error::InvalidArgument)));
+        assert!(matches!(queue.add_used(&memory, 0, 0), Err(Error::InvalidArgument)));
+    }
+
+    #[test]
+    fn read_all_limit() {
+        let memory = GuestMemory::from_buffer(0x100);
+        let descriptor = |len| Descriptor {
+            address: 0,
+            len,
+            writable: false,
+        };
+
+        let chain = DescriptorChain {
+            head: 0,
+            descriptors: vec![descriptor(0x10), descriptor(0x10)],
+        };
+
+        assert_eq!(chain.read_all(&memory, 0x20).unwrap().len(), 0x20);
+        assert!(matches!(chain.read_all(&memory, 0x1f), Err(Error::InvalidArgument)));
+
+        // The sizes are checked before anything is allocated or read.
+        let chain = DescriptorChain {
+            head: 0,
+            descriptors: vec![descriptor(u32::MAX); 4],
+        };
+
+        assert!(matches!(chain.read_all(&memory, 0x1000), Err(Error::InvalidArgument)));
+    }
+}
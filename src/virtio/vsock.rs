@@ -0,0 +1,932 @@
+//! This module provides [`VsockDevice`], a virtio-vsock device as described by section 5.10 of
+//! the virtio 1.1 specification, which is the backend of [`crate::vsock`] on Linux, Mac OS X and
+//! FreeBSD.
+//!
+//! The device does not forward the connections to the sockets of the host operating system.
+//! Instead, the host side of the connections is implemented in the VMM itself: the guest connects
+//! to the listeners created through [`crate::Vsock::listen`] by connecting to
+//! [`VMADDR_CID_HOST`], and the VMM connects to the listeners of the guest through
+//! [`crate::Vsock::connect`]. Only stream sockets are supported.
+//!
+//! The data received from the guest is buffered until the VMM reads it, where the guest is only
+//! allowed to send up to [`VSOCK_BUFFER_SIZE`] bytes ahead through the credit-based flow control
+//! of virtio-vsock. The data written by the VMM is buffered likewise, and is sent to the guest as
+//! the guest makes buffers available and has the credit to receive the data.
+
+use crate::bytes::{self, AsBytes, FromBytes, Le};
+use crate::error::Error;
+use crate::vm::Vm;
+use std::collections::{HashMap, VecDeque};
+use std::io;
+use std::net::Shutdown;
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+use super::{
+    GuestMemory, MmioTransport, Queue, VirtioDevice, VIRTIO_F_INDIRECT_DESC, VIRTIO_ID_VSOCK,
+    VIRTIO_MMIO_SIZE,
+};
+
+/// The CID of the host, which the guest connects to.
+pub const VMADDR_CID_HOST: u64 = 2;
+
+/// The number of bytes that are buffered for each direction of a connection.
+pub const VSOCK_BUFFER_SIZE: u32 = 256 * 1024;
+
+/// The index of the virtqueue through which the device sends packets to the guest.
+const RX_QUEUE: usize = 0;
+/// The index of the virtqueue through which the guest sends packets to the device.
+const TX_QUEUE: usize = 1;
+/// The size of the virtqueues, which are the receive, transmit and event virtqueues.
+const QUEUE_SIZE: u16 = 256;
+
+/// The socket type of stream sockets.
+const VIRTIO_VSOCK_TYPE_STREAM: u16 = 1;
+
+/// The operation that requests a connection.
+const VIRTIO_VSOCK_OP_REQUEST: u16 = 1;
+/// The operation that accepts a connection.
+const VIRTIO_VSOCK_OP_RESPONSE: u16 = 2;
+/// The operation that refuses or terminates a connection.
+const VIRTIO_VSOCK_OP_RST: u16 = 3;
+/// The operation that shuts down one or both directions of a connection.
+const VIRTIO_VSOCK_OP_SHUTDOWN: u16 = 4;
+/// The operation that carries data.
+const VIRTIO_VSOCK_OP_RW: u16 = 5;
+/// The operation that reports the credit of the sender.
+const VIRTIO_VSOCK_OP_CREDIT_UPDATE: u16 = 6;
+/// The operation that requests the credit of the receiver.
+const VIRTIO_VSOCK_OP_CREDIT_REQUEST: u16 = 7;
+
+/// The shutdown flag that indicates that the sender will not receive any more data.
+const VIRTIO_VSOCK_SHUTDOWN_RCV: u32 = 1;
+/// The shutdown flag that indicates that the sender will not send any more data.
+const VIRTIO_VSOCK_SHUTDOWN_SEND: u32 = 2;
+/// Both shutdown flags.
+const VIRTIO_VSOCK_SHUTDOWN_BOTH: u32 = VIRTIO_VSOCK_SHUTDOWN_RCV | VIRTIO_VSOCK_SHUTDOWN_SEND;
+
+/// The size of the header of a packet.
+const HEADER_SIZE: usize = 44;
+/// The maximum size of the payload of a packet, which matches the Linux driver.
+const MAX_PAYLOAD_SIZE: usize = 64 * 1024;
+/// The number of bytes that the VMM has to read before the credit is reported to the guest
+/// without the guest asking for it.
+const CREDIT_UPDATE_THRESHOLD: u32 = VSOCK_BUFFER_SIZE / 4;
+/// The first port used for the connections initiated by the VMM.
+const EPHEMERAL_PORT_START: u32 = 1024;
+/// The time to wait for the guest to accept or refuse a connection.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The header of a packet.
+#[derive(Clone, Copy)]
+#[repr(C, packed)]
+struct PacketHeader {
+    src_cid: Le<u64>,
+    dst_cid: Le<u64>,
+    src_port: Le<u32>,
+    dst_port: Le<u32>,
+    len: Le<u32>,
+    kind: Le<u16>,
+    op: Le<u16>,
+    flags: Le<u32>,
+    buf_alloc: Le<u32>,
+    fwd_cnt: Le<u32>,
+}
+
+unsafe impl FromBytes for PacketHeader {}
+unsafe impl AsBytes for PacketHeader {}
+
+/// The port of the host and the port of the guest of a connection.
+type ConnectionKey = (u32, u32);
+
+/// The callback that lets the device send the packets queued by the VMM.
+type Kick = Box<dyn Fn() -> Result<(), Error> + Send>;
+
+/// The state of a connection.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum ConnectionState {
+    /// The VMM requested the connection, but the guest did not respond yet.
+    Connecting,
+    /// The connection has been established.
+    Established,
+    /// The connection has been terminated, but the VMM may still read the data received before.
+    Closed,
+}
+
+/// A connection between the VMM and the guest.
+struct Connection {
+    /// The unique ID of the connection, as the ports may be reused once the connection is gone.
+    id: u64,
+    /// The state of the connection.
+    state: ConnectionState,
+    /// The data received from the guest that the VMM did not read yet.
+    rx: VecDeque<u8>,
+    /// The data written by the VMM that has not been sent to the guest yet.
+    tx: VecDeque<u8>,
+    /// The size of the receive buffer of the guest.
+    peer_buf_alloc: u32,
+    /// The number of bytes that the guest has read.
+    peer_fwd_cnt: u32,
+    /// The number of bytes sent to the guest.
+    tx_cnt: u32,
+    /// The number of bytes that the VMM has read.
+    fwd_cnt: u32,
+    /// The number of bytes that the VMM has read as last reported to the guest.
+    fwd_cnt_sent: u32,
+    /// The shutdown flags received from the guest.
+    peer_shutdown: u32,
+    /// The shutdown flags requested by the VMM.
+    shutdown: u32,
+    /// Whether the shutdown flags requested by the VMM have been sent to the guest.
+    shutdown_sent: bool,
+    /// Whether the VMM dropped its side of the connection.
+    dropped: bool,
+}
+
+impl Connection {
+    /// Creates the connection with the given ID in the given state.
+    fn new(id: u64, state: ConnectionState) -> Self {
+        Self {
+            id,
+            state,
+            rx: VecDeque::new(),
+            tx: VecDeque::new(),
+            peer_buf_alloc: 0,
+            peer_fwd_cnt: 0,
+            tx_cnt: 0,
+            fwd_cnt: 0,
+            fwd_cnt_sent: 0,
+            peer_shutdown: 0,
+            shutdown: 0,
+            shutdown_sent: false,
+            dropped: false,
+        }
+    }
+
+    /// Returns the number of bytes that can be sent to the guest right now.
+    fn sendable(&self) -> usize {
+        if self.state != ConnectionState::Established {
+            return 0;
+        }
+
+        let in_flight = self.tx_cnt.wrapping_sub(self.peer_fwd_cnt);
+        let credit = self.peer_buf_alloc.saturating_sub(in_flight);
+
+        self.tx.len().min(credit as usize)
+    }
+
+    /// Returns whether the shutdown requested by the VMM should be sent to the guest, which
+    /// happens once all the data written before has been sent.
+    fn needs_shutdown(&self) -> bool {
+        self.state == ConnectionState::Established &&
+            self.shutdown != 0 &&
+            !self.shutdown_sent &&
+            self.tx.is_empty()
+    }
+}
+
+/// The connections and the listeners of the VMM.
+struct State {
+    /// The CID of the guest.
+    guest_cid: u64,
+    /// The ports of the guest of the connections that have not been accepted yet by the port of
+    /// the listener.
+    listeners: HashMap<u32, VecDeque<u32>>,
+    /// The connections.
+    connections: HashMap<ConnectionKey, Connection>,
+    /// The packets without payload that are queued to be sent to the guest.
+    control: VecDeque<(ConnectionKey, u16, u32)>,
+    /// The ID of the next connection.
+    next_id: u64,
+    /// The next port to try for the connections initiated by the VMM.
+    next_port: u32,
+}
+
+impl State {
+    /// Creates the connection for the given ports, and returns its ID.
+    fn insert(&mut self, key: ConnectionKey, state: ConnectionState) -> u64 {
+        let id = self.next_id;
+
+        self.next_id += 1;
+        self.connections.insert(key, Connection::new(id, state));
+
+        id
+    }
+
+    /// Returns the connection for the given ports if it has the given ID.
+    fn get(&mut self, key: ConnectionKey, id: u64) -> Option<&mut Connection> {
+        self.connections
+            .get_mut(&key)
+            .filter(|connection| connection.id == id)
+    }
+
+    /// Terminates the connection for the given ports, and lets the guest know if `reset` is set.
+    /// The connection is kept until the VMM drops its side of the connection, such that the VMM
+    /// can still read the data received before.
+    fn close(&mut self, key: ConnectionKey, reset: bool) {
+        if reset {
+            self.control.push_back((key, VIRTIO_VSOCK_OP_RST, 0));
+        }
+
+        match self.connections.get_mut(&key) {
+            Some(connection) if !connection.dropped => connection.state = ConnectionState::Closed,
+            _ => {
+                self.connections.remove(&key);
+            }
+        }
+    }
+
+    /// Terminates all connections, e.g. when the driver resets the device.
+    fn reset(&mut self) {
+        for (port, backlog) in self.listeners.iter_mut() {
+            for guest_port in backlog.drain(..) {
+                self.connections.remove(&(*port, guest_port));
+            }
+        }
+
+        self.connections.retain(|_, connection| !connection.dropped);
+
+        for connection in self.connections.values_mut() {
+            connection.state = ConnectionState::Closed;
+        }
+
+        self.control.clear();
+    }
+
+    /// Handles the given packet sent by the guest.
+    fn handle_packet(&mut self, header: &PacketHeader, payload: &[u8]) {
+        let key = (header.dst_port.get(), header.src_port.get());
+        let op = header.op.get();
+
+        // Only stream sockets between the guest and the host are supported.
+        if header.src_cid.get() != self.guest_cid ||
+            header.dst_cid.get() != VMADDR_CID_HOST ||
+            header.kind.get() != VIRTIO_VSOCK_TYPE_STREAM
+        {
+            if op != VIRTIO_VSOCK_OP_RST {
+                self.control.push_back((key, VIRTIO_VSOCK_OP_RST, 0));
+            }
+
+            return;
+        }
+
+        if op == VIRTIO_VSOCK_OP_REQUEST {
+            let accepted = match self.listeners.get_mut(&key.0) {
+                Some(backlog) if !self.connections.contains_key(&key) => {
+                    backlog.push_back(key.1);
+                    true
+                }
+                _ => false,
+            };
+
+            if !accepted {
+                self.control.push_back((key, VIRTIO_VSOCK_OP_RST, 0));
+                return;
+            }
+
+            self.insert(key, ConnectionState::Established);
+            self.control.push_back((key, VIRTIO_VSOCK_OP_RESPONSE, 0));
+        }
+
+        let connection = match self.connections.get_mut(&key) {
+            Some(connection) if connection.state != ConnectionState::Closed => connection,
+            _ => {
+                if op != VIRTIO_VSOCK_OP_RST {
+                    self.control.push_back((key, VIRTIO_VSOCK_OP_RST, 0));
+                }
+
+                return;
+            }
+        };
+
+        // Every packet reports the credit of the guest.
+        connection.peer_buf_alloc = header.buf_alloc.get();
+        connection.peer_fwd_cnt = header.fwd_cnt.get();
+
+        match (op, connection.state) {
+            (VIRTIO_VSOCK_OP_REQUEST, _) => (),
+            (VIRTIO_VSOCK_OP_RESPONSE, ConnectionState::Connecting) =>
+                connection.state = ConnectionState::Established,
+            (VIRTIO_VSOCK_OP_RST, _) => self.close(key, false),
+            (VIRTIO_VSOCK_OP_SHUTDOWN, ConnectionState::Established) => {
+                connection.peer_shutdown |= header.flags.get() & VIRTIO_VSOCK_SHUTDOWN_BOTH;
+
+                // The guest expects the connection to be reset once it shut down both directions.
+                if connection.peer_shutdown == VIRTIO_VSOCK_SHUTDOWN_BOTH {
+                    self.close(key, true);
+                }
+            }
+            (VIRTIO_VSOCK_OP_RW, ConnectionState::Established) => {
+                // The guest must not send more data than it has credit for.
+                if connection.rx.len() + payload.len() > VSOCK_BUFFER_SIZE as usize {
+                    self.close(key, true);
+                } else {
+                    connection.rx.extend(payload);
+                }
+            }
+            (VIRTIO_VSOCK_OP_CREDIT_UPDATE, _) => (),
+            (VIRTIO_VSOCK_OP_CREDIT_REQUEST, _) =>
+                self.control.push_back((key, VIRTIO_VSOCK_OP_CREDIT_UPDATE, 0)),
+            _ => self.close(key, true),
+        }
+    }
+
+    /// Returns whether there is a packet to send to the guest.
+    fn has_packet(&self) -> bool {
+        !self.control.is_empty() ||
+            self.connections
+                .values()
+                .any(|connection| connection.sendable() > 0 || connection.needs_shutdown())
+    }
+
+    /// Takes the next packet to send to the guest, where the payload is limited to the given
+    /// size.
+    fn next_packet(&mut self, max_size: usize) -> Option<(PacketHeader, Vec<u8>)> {
+        if let Some((key, op, flags)) = self.control.pop_front() {
+            return Some((self.header(key, op, flags, 0), vec![]));
+        }
+
+        let key = self.connections
+            .iter()
+            .find(|(_, connection)| connection.sendable() > 0 || connection.needs_shutdown())
+            .map(|(key, _)| *key)?;
+        let connection = self.connections.get_mut(&key)?;
+
+        if connection.sendable() == 0 {
+            connection.shutdown_sent = true;
+            let flags = connection.shutdown;
+
+            return Some((self.header(key, VIRTIO_VSOCK_OP_SHUTDOWN, flags, 0), vec![]));
+        }
+
+        let size = connection.sendable().min(max_size).min(MAX_PAYLOAD_SIZE);
+        let payload: Vec<u8> = connection.tx.drain(..size).collect();
+
+        connection.tx_cnt = connection.tx_cnt.wrapping_add(size as u32);
+
+        Some((self.header(key, VIRTIO_VSOCK_OP_RW, 0, size as u32), payload))
+    }
+
+    /// Builds the header of a packet to the guest, which also reports the credit of the VMM.
+    fn header(&mut self, key: ConnectionKey, op: u16, flags: u32, len: u32) -> PacketHeader {
+        let fwd_cnt = match self.connections.get_mut(&key) {
+            Some(connection) => {
+                connection.fwd_cnt_sent = connection.fwd_cnt;
+                connection.fwd_cnt
+            }
+            _ => 0,
+        };
+
+        PacketHeader {
+            src_cid: Le::new(VMADDR_CID_HOST),
+            dst_cid: Le::new(self.guest_cid),
+            src_port: Le::new(key.0),
+            dst_port: Le::new(key.1),
+            len: Le::new(len),
+            kind: Le::new(VIRTIO_VSOCK_TYPE_STREAM),
+            op: Le::new(op),
+            flags: Le::new(flags),
+            buf_alloc: Le::new(VSOCK_BUFFER_SIZE),
+            fwd_cnt: Le::new(fwd_cnt),
+        }
+    }
+}
+
+/// The state shared between the device and the host side of the connections.
+struct Shared {
+    /// The connections and the listeners.
+    state: Mutex<State>,
+    /// Notifies the threads waiting for the state to change.
+    condvar: Condvar,
+    /// The callback that lets the device send the packets queued by the VMM, which is set once
+    /// the device has been attached to the VM.
+    kick: Mutex<Option<Kick>>,
+}
+
+impl Shared {
+    /// Locks the state.
+    fn lock(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap()
+    }
+
+    /// Waits for the state to change.
+    fn wait<'a>(&self, state: MutexGuard<'a, State>) -> MutexGuard<'a, State> {
+        self.condvar.wait(state).unwrap()
+    }
+
+    /// Lets the device send the packets queued by the VMM. The state must not be locked.
+    fn kick(&self) -> Result<(), Error> {
+        match self.kick.lock().unwrap().as_ref() {
+            Some(kick) => kick(),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Helper function to create the error of the given kind.
+fn io_error(kind: io::ErrorKind) -> Error {
+    Error::Io(io::Error::from(kind))
+}
+
+/// A virtio-vsock device, see the [module-level documentation](self). The device is usually
+/// created and attached through [`crate::Vsock::attach`].
+pub struct VsockDevice {
+    /// The state shared with the host side of the connections.
+    shared: Arc<Shared>,
+}
+
+impl VsockDevice {
+    /// Creates the device for the guest with the given CID. Returns [`Error::InvalidArgument`] if
+    /// the CID is one of the reserved CIDs, i.e. below 3, or does not fit in 32 bits.
+    pub fn new(guest_cid: u64) -> Result<Self, Error> {
+        if guest_cid <= VMADDR_CID_HOST || guest_cid >= u32::MAX as u64 {
+            return Err(Error::InvalidArgument);
+        }
+
+        let state = State {
+            guest_cid,
+            listeners: HashMap::new(),
+            connections: HashMap::new(),
+            control: VecDeque::new(),
+            next_id: 0,
+            next_port: EPHEMERAL_PORT_START,
+        };
+
+        Ok(Self {
+            shared: Arc::new(Shared {
+                state: Mutex::new(state),
+                condvar: Condvar::new(),
+                kick: Mutex::new(None),
+            }),
+        })
+    }
+
+    /// Returns the CID of the guest.
+    pub fn guest_cid(&self) -> u64 {
+        self.shared.lock().guest_cid
+    }
+
+    /// Returns the host side of the connections of the device.
+    pub(crate) fn endpoint(&self) -> Endpoint {
+        Endpoint {
+            shared: self.shared.clone(),
+        }
+    }
+
+    /// Handles the packets that the guest sent.
+    fn process_tx(&mut self, queue: &mut Queue, memory: &GuestMemory) -> Result<bool, Error> {
+        let mut state = self.shared.lock();
+        let mut used = false;
+
+        while let Some(chain) = queue.pop(memory)? {
+            let size: u64 = chain.readable().map(|descriptor| descriptor.len as u64).sum();
+
+            // Silently drop the malformed packets, as there is no connection to reset.
+            if size >= HEADER_SIZE as u64 && size <= (HEADER_SIZE + MAX_PAYLOAD_SIZE) as u64 {
+                let bytes = chain.read_all(memory, HEADER_SIZE + MAX_PAYLOAD_SIZE)?;
+                let header: PacketHeader = bytes::from_bytes(&bytes[..HEADER_SIZE]);
+                let len = (header.len.get() as usize).min(bytes.len() - HEADER_SIZE);
+
+                state.handle_packet(&header, &bytes[HEADER_SIZE..HEADER_SIZE + len]);
+            }
+
+            queue.add_used(memory, chain.head, 0)?;
+            used = true;
+        }
+
+        if used {
+            self.shared.condvar.notify_all();
+        }
+
+        Ok(used)
+    }
+
+    /// Sends the queued packets to the guest, as long as the guest made buffers available.
+    fn process_rx(&mut self, queue: &mut Queue, memory: &GuestMemory) -> Result<bool, Error> {
+        let mut state = self.shared.lock();
+        let mut used = false;
+
+        while state.has_packet() {
+            let chain = match queue.pop(memory)? {
+                Some(chain) => chain,
+                _ => break,
+            };
+
+            let size: u64 = chain.writable().map(|descriptor| descriptor.len as u64).sum();
+
+            if size < HEADER_SIZE as u64 {
+                return Err(Error::InvalidArgument);
+            }
+
+            let max_size = (size - HEADER_SIZE as u64).min(MAX_PAYLOAD_SIZE as u64) as usize;
+            let (header, payload) = match state.next_packet(max_size) {
+                Some(packet) => packet,
+                _ => break,
+            };
+
+            let mut bytes = bytes::as_bytes(&header).to_vec();
+            bytes.extend(payload);
+
+            let size = chain.write_all(memory, &bytes)?;
+
+            queue.add_used(memory, chain.head, size as u32)?;
+            used = true;
+        }
+
+        if used {
+            self.shared.condvar.notify_all();
+        }
+
+        Ok(used)
+    }
+}
+
+impl VirtioDevice for VsockDevice {
+    fn device_type(&self) -> u32 {
+        VIRTIO_ID_VSOCK
+    }
+
+    fn queue_max_sizes(&self) -> &[u16] {
+        &[QUEUE_SIZE; 3]
+    }
+
+    fn features(&self) -> u64 {
+        VIRTIO_F_INDIRECT_DESC
+    }
+
+    fn read_config(&self, offset: u64, data: &mut [u8]) {
+        let guest_cid = Le::new(self.guest_cid());
+        let config = bytes::as_bytes(&guest_cid);
+
+        for (i, byte) in data.iter_mut().enumerate() {
+            *byte = config.get(offset as usize + i).copied().unwrap_or(0);
+        }
+    }
+
+    fn reset(&mut self) {
+        self.shared.lock().reset();
+        self.shared.condvar.notify_all();
+    }
+
+    fn process_queue(
+        &mut self,
+        index: usize,
+        queues: &mut [Queue],
+        memory: &GuestMemory,
+    ) -> Result<bool, Error> {
+        let mut used = false;
+
+        if index == TX_QUEUE {
+            used |= self.process_tx(&mut queues[TX_QUEUE], memory)?;
+        }
+
+        // Handling the packets of the guest may have queued responses, and the guest may have
+        // made buffers available for the packets that are still queued.
+        used |= self.process_rx(&mut queues[RX_QUEUE], memory)?;
+
+        Ok(used)
+    }
+}
+
+/// Creates a [`VsockDevice`] for the guest with the given CID, and registers its
+/// [`MmioTransport`] for the given guest physical address, where the given callback drives the
+/// interrupt line. Returns the host side of the connections.
+pub(crate) fn attach<F>(vm: &Vm, guest_cid: u64, address: u64, irq: F) -> Result<Endpoint, Error>
+where
+    F: FnMut(bool) -> Result<(), Error> + Send + 'static,
+{
+    let device = VsockDevice::new(guest_cid)?;
+    let endpoint = device.endpoint();
+    let transport = Arc::new(Mutex::new(MmioTransport::new(device, GuestMemory::new(vm), irq)));
+
+    // The VMM queues packets from its own threads, after which the transport has to be locked to
+    // send them. The transport keeps the device alive, so only hold on to it weakly.
+    let weak = Arc::downgrade(&transport);
+
+    *endpoint.shared.kick.lock().unwrap() = Some(Box::new(move || match weak.upgrade() {
+        Some(transport) => transport.lock().unwrap().process_queue(RX_QUEUE),
+        _ => Ok(()),
+    }));
+
+    vm.register_mmio_device(address, VIRTIO_MMIO_SIZE, transport)?;
+
+    Ok(endpoint)
+}
+
+/// The host side of the connections of a [`VsockDevice`].
+#[derive(Clone)]
+pub(crate) struct Endpoint {
+    /// The state shared with the device.
+    shared: Arc<Shared>,
+}
+
+impl Endpoint {
+    /// Listens for connections from the guest on the given port. Returns [`Error::Io`] with
+    /// [`io::ErrorKind::AddrInUse`] if there is a listener on the port already.
+    pub(crate) fn listen(&self, port: u32) -> Result<Listener, Error> {
+        let mut state = self.shared.lock();
+
+        if state.listeners.contains_key(&port) {
+            return Err(io_error(io::ErrorKind::AddrInUse));
+        }
+
+        state.listeners.insert(port, VecDeque::new());
+
+        Ok(Listener {
+            shared: self.shared.clone(),
+            port,
+        })
+    }
+
+    /// Connects to the listener of the guest on the given port.
+    pub(crate) fn connect(&self, port: u32) -> Result<Stream, Error> {
+        let mut state = self.shared.lock();
+
+        // Pick a port that is not in use by a listener or a connection to the same port.
+        let key = loop {
+            let key = (state.next_port, port);
+
+            state.next_port = state.next_port.checked_add(1).unwrap_or(EPHEMERAL_PORT_START);
+
+            if !state.listeners.contains_key(&key.0) && !state.connections.contains_key(&key) {
+                break key;
+            }
+        };
+
+        let id = state.insert(key, ConnectionState::Connecting);
+        state.control.push_back((key, VIRTIO_VSOCK_OP_REQUEST, 0));
+        drop(state);
+
+        self.shared.kick()?;
+
+        let deadline = Instant::now() + CONNECT_TIMEOUT;
+        let mut state = self.shared.lock();
+
+        loop {
+            let connection_state = state.get(key, id).map(|connection| connection.state);
+
+            match connection_state {
+                Some(ConnectionState::Established) => break,
+                Some(ConnectionState::Connecting) => (),
+                _ => {
+                    state.connections.remove(&key);
+                    return Err(io_error(io::ErrorKind::ConnectionRefused));
+                }
+            }
+
+            let now = Instant::now();
+
+            if now >= deadline {
+                state.connections.remove(&key);
+                state.control.push_back((key, VIRTIO_VSOCK_OP_RST, 0));
+                drop(state);
+
+                self.shared.kick()?;
+
+                return Err(io_error(io::ErrorKind::TimedOut));
+            }
+
+            state = self.shared.condvar.wait_timeout(state, deadline - now).unwrap().0;
+        }
+
+        Ok(Stream {
+            shared: self.shared.clone(),
+            key,
+            id,
+            port,
+        })
+    }
+}
+
+/// A listener for the connections from the guest.
+pub(crate) struct Listener {
+    /// The state shared with the device.
+    shared: Arc<Shared>,
+    /// The port of the listener.
+    port: u32,
+}
+
+impl Listener {
+    /// Returns the port of the listener.
+    pub(crate) fn port(&self) -> u32 {
+        self.port
+    }
+
+    /// Waits for the next connection from the guest.
+    pub(crate) fn accept(&self) -> Result<Stream, Error> {
+        let mut state = self.shared.lock();
+
+        loop {
+            let guest_port = state.listeners
+                .get_mut(&self.port)
+                .and_then(|backlog| backlog.pop_front());
+
+            let guest_port = match guest_port {
+                Some(guest_port) => guest_port,
+                _ => {
+                    state = self.shared.wait(state);
+                    continue;
+                }
+            };
+
+            // The guest may have reset the connection before it got accepted.
+            let key = (self.port, guest_port);
+
+            if let Some(connection) = state.connections.get(&key) {
+                return Ok(Stream {
+                    shared: self.shared.clone(),
+                    key,
+                    id: connection.id,
+                    port: self.port,
+                });
+            }
+        }
+    }
+}
+
+impl Drop for Listener {
+    fn drop(&mut self) {
+        let mut state = self.shared.lock();
+
+        // Reset the connections that have not been accepted.
+        if let Some(backlog) = state.listeners.remove(&self.port) {
+            for guest_port in backlog {
+                let key = (self.port, guest_port);
+
+                state.connections.remove(&key);
+                state.control.push_back((key, VIRTIO_VSOCK_OP_RST, 0));
+            }
+        }
+
+        drop(state);
+
+        let _ = self.shared.kick();
+    }
+}
+
+/// A connection between the VMM and the guest.
+pub(crate) struct Stream {
+    /// The state shared with the device.
+    shared: Arc<Shared>,
+    /// The ports of the connection.
+    key: ConnectionKey,
+    /// The ID of the connection.
+    id: u64,
+    /// The port of the service, i.e. the port of the listener.
+    port: u32,
+}
+
+impl Stream {
+    /// Returns the port of the service, i.e. the port of the listener on the host for the
+    /// accepted connections, or the port of the listener on the guest otherwise.
+    pub(crate) fn port(&self) -> u32 {
+        self.port
+    }
+
+    /// Reads the data received from the guest, and waits for data if there is none. Returns zero
+    /// once the guest will not send any more data.
+    pub(crate) fn read(&self, buf: &mut [u8]) -> Result<usize, Error> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+
+        let mut state = self.shared.lock();
+
+        let (size, update) = loop {
+            let connection = match state.get(self.key, self.id) {
+                Some(connection) => connection,
+                _ => return Ok(0),
+            };
+
+            if !connection.rx.is_empty() {
+                let size = buf.len().min(connection.rx.len());
+
+                for (dst, src) in buf.iter_mut().zip(connection.rx.drain(..size)) {
+                    *dst = src;
+                }
+
+                connection.fwd_cnt = connection.fwd_cnt.wrapping_add(size as u32);
+
+                let unreported = connection.fwd_cnt.wrapping_sub(connection.fwd_cnt_sent);
+
+                break (size, unreported >= CREDIT_UPDATE_THRESHOLD);
+            }
+
+            if connection.state == ConnectionState::Closed ||
+                connection.peer_shutdown & VIRTIO_VSOCK_SHUTDOWN_SEND != 0 ||
+                connection.shutdown & VIRTIO_VSOCK_SHUTDOWN_RCV != 0
+            {
+                return Ok(0);
+            }
+
+            state = self.shared.wait(state);
+        };
+
+        if update {
+            state.control.push_back((self.key, VIRTIO_VSOCK_OP_CREDIT_UPDATE, 0));
+            drop(state);
+
+            self.shared.kick()?;
+        }
+
+        Ok(size)
+    }
+
+    /// Writes the data to the guest, and waits for room in the buffer if the buffer is full.
+    /// Returns [`Error::Io`] with [`io::ErrorKind::BrokenPipe`] if the data can no longer be
+    /// sent.
+    pub(crate) fn write(&self, buf: &[u8]) -> Result<usize, Error> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+
+        let mut state = self.shared.lock();
+
+        let size = loop {
+            let connection = match state.get(self.key, self.id) {
+                Some(connection) if connection.state == ConnectionState::Established =>
+                    connection,
+                _ => return Err(io_error(io::ErrorKind::ConnectionReset)),
+            };
+
+            if connection.peer_shutdown & VIRTIO_VSOCK_SHUTDOWN_RCV != 0 ||
+                connection.shutdown & VIRTIO_VSOCK_SHUTDOWN_SEND != 0
+            {
+                return Err(io_error(io::ErrorKind::BrokenPipe));
+            }
+
+            let room = VSOCK_BUFFER_SIZE as usize - connection.tx.len();
+
+            if room > 0 {
+                let size = buf.len().min(room);
+
+                connection.tx.extend(&buf[..size]);
+
+                break size;
+            }
+
+            state = self.shared.wait(state);
+        };
+
+        drop(state);
+
+        self.shared.kick()?;
+
+        Ok(size)
+    }
+
+    /// Shuts down the given directions of the connection.
+    pub(crate) fn shutdown(&self, how: Shutdown) -> Result<(), Error> {
+        let flags = match how {
+            Shutdown::Read => VIRTIO_VSOCK_SHUTDOWN_RCV,
+            Shutdown::Write => VIRTIO_VSOCK_SHUTDOWN_SEND,
+            Shutdown::Both => VIRTIO_VSOCK_SHUTDOWN_BOTH,
+        };
+
+        let mut state = self.shared.lock();
+
+        match state.get(self.key, self.id) {
+            Some(connection) if connection.shutdown | flags != connection.shutdown => {
+                connection.shutdown |= flags;
+                connection.shutdown_sent = false;
+            }
+            Some(_) => return Ok(()),
+            _ => return Err(io_error(io::ErrorKind::NotConnected)),
+        }
+
+        drop(state);
+
+        self.shared.condvar.notify_all();
+        self.shared.kick()
+    }
+}
+
+impl Drop for Stream {
+    fn drop(&mut self) {
+        let mut state = self.shared.lock();
+
+        let connection = match state.get(self.key, self.id) {
+            Some(connection) => connection,
+            _ => return,
+        };
+
+        // Shut down the connection gracefully once the data written before has been sent, after
+        // which the guest resets the connection.
+        match connection.state {
+            ConnectionState::Established => {
+                connection.dropped = true;
+
+                if connection.shutdown != VIRTIO_VSOCK_SHUTDOWN_BOTH {
+                    connection.shutdown = VIRTIO_VSOCK_SHUTDOWN_BOTH;
+                    connection.shutdown_sent = false;
+                }
+            }
+            _ => {
+                state.connections.remove(&self.key);
+            }
+        }
+
+        drop(state);
+
+        let _ = self.shared.kick();
+    }
+}
@@ -2,16 +2,45 @@
 //! virtual CPUs and a physical memory space.
 
 use bitflags::bitflags;
+use crate::bus::{MmioBus, PioBus, SharedDevice};
+use crate::bytes::{self, AsBytes, Atomic, FromBytes};
+#[cfg(target_arch = "aarch64")]
+use crate::arch::aarch64::{GicConfig, GIC_SPI_BASE};
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+use crate::arch::x86_64::{CrExits, MsrPolicy};
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+use crate::confidential::{LaunchMeasurement, SevPolicy};
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+use crate::devices::apic::ApicBus;
 use crate::error::Error;
+use crate::hypercall::{HypercallContext, HypercallTable};
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+use crate::pit::PitReinjection;
 use crate::platform;
-use crate::vcpu::Vcpu;
+use crate::scan::Pattern;
+use crate::snapshot::{self, RestoredVm};
+use crate::state::VcpuState;
+use crate::symbols::SymbolMap;
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+use crate::synic::SyntheticMessage;
+use crate::thread::{ResourceGroup, ThreadPriority};
+use crate::tsc::TscMode;
+use crate::vcpu::{ExitReason, Vcpu, VcpuFactory, VcpuSpec};
+use crate::volatile::VolatileSlice;
+#[cfg(feature = "xen")]
+use crate::xen::XenConfig;
 use intrusive_collections::intrusive_adapter;
 use intrusive_collections::{SinglyLinkedListLink, SinglyLinkedList};
-use mmap_rs::{MmapMut, MmapOptions};
+use mmap_rs::{MmapFlags, MmapMut, MmapOptions, PageSize};
 pub use page_walker::address_space::PageTableMapper;
 use rangemap::RangeMap;
 use std::collections::HashMap;
+use std::ffi::{CStr, CString};
+use std::fs::File;
+use std::io::BufWriter;
 use std::ops::Range;
+use std::path::Path;
+use std::sync::atomic::Ordering;
 use std::sync::{Arc, RwLock};
 
 /// Represents the metadata of a physical page of the guest VM.
@@ -61,6 +90,12 @@ impl<'a> PageAllocator<'a> {
             _ => return None,
         };
 
+        Some(self.page_address(page_info))
+    }
+
+    /// Helper function to look up the guest physical address of the page described by the given
+    /// page info.
+    fn page_address(&self, page_info: &PageInfo) -> u64 {
         let offset = page_info
             as *const PageInfo
             as *const std::ffi::c_void
@@ -71,20 +106,21 @@ impl<'a> PageAllocator<'a> {
             .expect("page info range must have been present");
 
         let index = (offset - range.start) / std::mem::size_of::<PageInfo>();
-        let guest_address = *guest_address + (index as u64) * self.page_size as u64;
 
-        Some(guest_address)
+        *guest_address + (index as u64) * self.page_size as u64
     }
 
-    /// Frees the given physical page.
+    /// Frees the given physical page. Pages that are no longer part of the allocator, e.g. the
+    /// pages of a region that shrunk while they were allocated, are ignored.
     pub fn free_page(&mut self, phys_addr: u64) {
-        let (range, _) = self.physical_ranges
-            .get_key_value(&phys_addr)
-            .expect("physical range must have been present");
-        let index = ((phys_addr - range.start) / self.page_size as u64) as usize;
+        let start = match self.physical_ranges.get(&phys_addr) {
+            Some(&start) => start,
+            _ => return,
+        };
+        let index = ((phys_addr - start) / self.page_size as u64) as usize;
 
         let segment = self.segments
-            .get(&range.start)
+            .get(&start)
             .expect("segment must have been present");
 
         let page_info = unsafe { &*segment.as_ptr().offset(index as isize) };
@@ -117,6 +153,85 @@ impl<'a> PageAllocator<'a> {
 
         Ok(())
     }
+
+    /// Returns `true` if the page at the given guest physical address is part of the allocator.
+    pub fn contains(&self, phys_addr: u64) -> bool {
+        self.physical_ranges.contains_key(&phys_addr)
+    }
+
+    /// Marks the pages within the given range of guest physical memory as allocated, e.g. when
+    /// the loader writes a guest image to them, such that they are not handed out. The pages
+    /// remain part of the allocator and can be freed through [`PageAllocator::free_page`].
+    pub fn reserve_range(&mut self, range: Range<u64>) {
+        // Rebuild the free list without the pages in the range.
+        let mut free_list = SinglyLinkedList::new(PageInfoAdapter::new());
+
+        while let Some(page_info) = self.free_list.pop_front() {
+            if !range.contains(&self.page_address(page_info)) {
+                free_list.push_front(page_info);
+            }
+        }
+
+        self.free_list = free_list;
+    }
+
+    /// Removes the given range of guest physical memory from the allocator, e.g. when a region
+    /// shrinks, such that its pages are no longer handed out.
+    pub fn remove_range(&mut self, range: Range<u64>) {
+        self.reserve_range(range.clone());
+        self.physical_ranges.remove(range.clone());
+
+        // Release the page infos of the segments that are no longer part of the allocator.
+        let page_size = self.page_size as u64;
+        let removed: Vec<u64> = self.segments
+            .iter()
+            .filter(|(&start, page_infos)| {
+                range.start <= start && start + page_infos.len() as u64 * page_size <= range.end
+            })
+            .map(|(&start, _)| start)
+            .collect();
+
+        for start in removed {
+            if let Some(page_infos) = self.segments.remove(&start) {
+                let base = page_infos.as_ptr() as usize;
+                let end = base + page_infos.len() * std::mem::size_of::<PageInfo>();
+
+                self.page_info_ranges.remove(base..end);
+            }
+        }
+    }
+
+    /// Moves the given range of guest physical memory to the given guest physical address, e.g.
+    /// when a region is remapped, where the pages that have been allocated remain allocated.
+    pub fn move_range(&mut self, range: Range<u64>, new_guest_address: u64) {
+        let moved: Vec<(Range<u64>, u64)> = self.physical_ranges
+            .iter()
+            .filter(|(piece, _)| range.start <= piece.start && piece.end <= range.end)
+            .map(|(piece, &start)| (piece.clone(), start))
+            .collect();
+
+        let relocate = |address: u64| address - range.start + new_guest_address;
+
+        // Take out all the segments first, as the new range may overlap with the old range.
+        let segments: Vec<(u64, Box<[PageInfo]>)> = moved
+            .iter()
+            .filter_map(|&(_, start)| Some((start, self.segments.remove(&start)?)))
+            .collect();
+
+        self.physical_ranges.remove(range.clone());
+
+        for (piece, start) in moved {
+            self.physical_ranges.insert(relocate(piece.start)..relocate(piece.end), relocate(start));
+        }
+
+        for (start, page_infos) in segments {
+            let base = page_infos.as_ptr() as usize;
+            let end = base + page_infos.len() * std::mem::size_of::<PageInfo>();
+
+            self.page_info_ranges.insert(base..end, relocate(start));
+            self.segments.insert(relocate(start), page_infos);
+        }
+    }
 }
 
 bitflags! {
@@ -127,6 +242,7 @@ bitflags! {
     ///    always executable.
     ///  * FreeBSD does not support any of the protection flags, which means that guest physical
     ///    memory is always readable, writable and executable.
+    #[cfg_attr(feature = "serde", derive(serde::Deserialize, serde::Serialize))]
     pub struct ProtectionFlags: u32 {
         /// The guest VM is allowed to read from the physical memory.
         const READ    = 1 << 0;
@@ -134,7 +250,336 @@ bitflags! {
         const WRITE   = 1 << 1;
         /// The guest VM is allowed to execute from the physical memory.
         const EXECUTE = 1 << 2;
+        /// The physical memory behaves like ROM, e.g. for option ROMs or the BIOS: rather than
+        /// faulting the VM, guest writes are reported as [`crate::ExitReason::RomWrite`] and
+        /// are discarded, such that the VMM can simply resume the virtual CPU. This overrides
+        /// [`ProtectionFlags::WRITE`], while the VMM can still write to the memory through
+        /// [`Vm::write_physical_memory`] to load the image. This applies to regions as a whole,
+        /// and is not supported by [`Vm::protect_physical_memory_range`].
+        const ROM     = 1 << 3;
+    }
+}
+
+impl ProtectionFlags {
+    /// Returns whether the guest VM is allowed to write to the physical memory, i.e. whether
+    /// [`ProtectionFlags::WRITE`] is set without [`ProtectionFlags::ROM`].
+    pub(crate) fn is_guest_writable(self) -> bool {
+        self.contains(Self::WRITE) && !self.contains(Self::ROM)
+    }
+}
+
+/// Describes the host memory backing guest physical memory allocated through
+/// [`Vm::allocate_backed_physical_memory`].
+#[derive(Debug)]
+pub enum MemoryBacking {
+    /// Anonymous memory, which is what [`Vm::allocate_physical_memory`] uses. On Linux and
+    /// Microsoft Windows, the memory is backed by the same kind of object as a [`SharedRegion`]
+    /// that is private to the VM, i.e. a memfd or a temporary file mapped through a section
+    /// object, such that [`Vm::fork`] can map it copy-on-write, unless it is backed by huge pages.
+    Anonymous,
+    /// Anonymous memory that does not reserve swap space up front, such that the memory of many
+    /// mostly-idle guests can be overcommitted. The host may fail to back a page when the guest
+    /// first touches it if the host runs out of memory and swap space.
+    Overcommit,
+    /// Memory backed by the given file starting at the given offset, such that the host can
+    /// write idle pages back to the file rather than to swap. The file must be at least as large
+    /// as the region.
+    File {
+        /// The file backing the memory.
+        file: File,
+        /// The offset into the file in bytes, which must be aligned to the page size.
+        offset: u64,
+    },
+}
+
+impl MemoryBacking {
+    /// Returns the [`MemoryBackingKind`] of the backing.
+    pub fn kind(&self) -> MemoryBackingKind {
+        match self {
+            MemoryBacking::Anonymous => MemoryBackingKind::Anonymous,
+            MemoryBacking::Overcommit => MemoryBackingKind::Overcommit,
+            MemoryBacking::File { .. } => MemoryBackingKind::File,
+        }
+    }
+}
+
+/// Describes how a region of guest physical memory returned by [`Vm::regions`] is backed.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum MemoryBackingKind {
+    /// Anonymous memory, see [`MemoryBacking::Anonymous`].
+    Anonymous,
+    /// Anonymous memory that does not reserve swap space, see [`MemoryBacking::Overcommit`].
+    Overcommit,
+    /// Memory backed by a file, see [`MemoryBacking::File`].
+    File,
+    /// Memory mapped by the caller through [`Vm::map_physical_memory`].
+    Mapped,
+    /// Memory shared with other VMs, see [`SharedRegion`].
+    Shared,
+    /// Memory mapped copy-on-write from the memory of another VM, see [`Vm::fork`].
+    CopyOnWrite,
+}
+
+/// The file behind a region of guest physical memory that is mapped shared, which allows
+/// [`Vm::fork`] to map the region copy-on-write.
+pub(crate) struct BackingFile {
+    file: File,
+    offset: u64,
+    size: u64,
+}
+
+/// A region of memory that can be mapped into several VMs at once, e.g. to implement inter-VM
+/// shared memory devices like ivshmem. The region is backed by an anonymous file, i.e. a memfd
+/// on Linux, a POSIX shared memory object on Mac OS X and a temporary file that is deleted once
+/// closed on Microsoft Windows, such that its handle can also be passed to another process.
+///
+/// This is not supported on FreeBSD, see [`Vm::map_physical_memory`].
+#[derive(Debug)]
+pub struct SharedRegion {
+    file: File,
+    size: usize,
+}
+
+impl SharedRegion {
+    /// Creates a shared region of the given size, which must be aligned to the page size.
+    pub fn new(size: usize) -> Result<Self, Error> {
+        Ok(Self {
+            file: platform::vm::create_shared_memory(size)?,
+            size,
+        })
+    }
+
+    /// Wraps the given file backing a shared region, e.g. a handle received from another
+    /// process. The size of the region is the size of the file.
+    pub fn from_file(file: File) -> Result<Self, Error> {
+        let size = file.metadata()?.len() as usize;
+
+        Ok(Self {
+            file,
+            size,
+        })
+    }
+
+    /// Returns the size of the region in bytes.
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    /// Returns the file backing the region.
+    pub fn file(&self) -> &File {
+        &self.file
+    }
+
+    /// Returns a new handle to the same region.
+    pub fn try_clone(&self) -> Result<Self, Error> {
+        Ok(Self {
+            file: self.file.try_clone()?,
+            size: self.size,
+        })
+    }
+
+    /// Helper function to map the region into the host address space.
+    fn map(&self) -> Result<MmapMut, Error> {
+        let options = unsafe { MmapOptions::new(self.size).with_file(self.file.try_clone()?, 0) };
+        let mapping = options
+            .with_flags(MmapFlags::SHARED)
+            .map_mut()?;
+
+        Ok(mapping)
+    }
+}
+
+/// Whether the changes to a file mapped through [`Vm::map_file`] are written back to the file.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum FileSharing {
+    /// The writes of the guest are written back to the file and are visible to other mappings of
+    /// the file. The file must be opened for writing.
+    Shared,
+    /// The writes of the guest are private to the VM, i.e. the pages are copied upon the first
+    /// write, such that the file is left untouched.
+    Private,
+}
+
+/// The size of the huge pages backing guest physical memory, see
+/// [`AllocationOptions::with_huge_pages`].
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum HugePageSize {
+    /// 2 MiB pages.
+    Size2M,
+    /// 1 GiB pages.
+    Size1G,
+}
+
+impl HugePageSize {
+    /// Returns the size of the pages in bytes.
+    pub fn bytes(&self) -> u64 {
+        match self {
+            HugePageSize::Size2M => 2 << 20,
+            HugePageSize::Size1G => 1 << 30,
+        }
+    }
+}
+
+/// The options to allocate guest physical memory with, see
+/// [`Vm::allocate_physical_memory_with`].
+#[derive(Debug)]
+pub struct AllocationOptions {
+    protection: ProtectionFlags,
+    backing: MemoryBacking,
+    huge_pages: Option<HugePageSize>,
+}
+
+impl AllocationOptions {
+    /// Creates the options to allocate anonymous memory backed by pages of the default size with
+    /// the given protection.
+    pub fn new(protection: ProtectionFlags) -> Self {
+        Self {
+            protection,
+            backing: MemoryBacking::Anonymous,
+            huge_pages: None,
+        }
+    }
+
+    /// Backs the memory by the given [`MemoryBacking`].
+    pub fn with_backing(self, backing: MemoryBacking) -> Self {
+        Self {
+            backing,
+            ..self
+        }
+    }
+
+    /// Backs the memory by huge pages of the given size rather than pages of the default size,
+    /// which relieves the TLB pressure of large guests. The huge pages are allocated through
+    /// `MAP_HUGETLB` on Linux, as large pages on Microsoft Windows and as superpages on Mac OS X,
+    /// where Microsoft Windows and Mac OS X only support 2 MiB pages. The host must have enough
+    /// huge pages available, and on Microsoft Windows the process must hold the
+    /// `SeLockMemoryPrivilege` privilege.
+    pub fn with_huge_pages(self, size: HugePageSize) -> Self {
+        Self {
+            huge_pages: Some(size),
+            ..self
+        }
+    }
+}
+
+/// The pages of a region of guest physical memory that were written to since dirty tracking was
+/// enabled or since the bitmap was last retrieved, as returned by [`Vm::dirty_bitmap`].
+#[derive(Clone, Debug)]
+pub struct DirtyBitmap {
+    /// The guest physical address range of the region.
+    pub range: Range<u64>,
+    /// The size of the pages tracked by the bitmap, which is the page size of the host.
+    pub page_size: u64,
+    /// The bitmap, where bit `n % 64` of word `n / 64` is set if page `n` of the region is dirty.
+    pub bitmap: Vec<u64>,
+}
+
+impl DirtyBitmap {
+    /// Returns the number of dirty pages.
+    pub fn count(&self) -> usize {
+        self.bitmap
+            .iter()
+            .map(|word| word.count_ones() as usize)
+            .sum()
+    }
+
+    /// Returns an iterator over the guest physical addresses of the dirty pages in ascending
+    /// order.
+    pub fn dirty_pages(&self) -> impl Iterator<Item = u64> + '_ {
+        self.bitmap
+            .iter()
+            .enumerate()
+            .flat_map(|(index, &word)| {
+                (0..64)
+                    .filter(move |bit| word & (1 << bit) != 0)
+                    .map(move |bit| (index * 64 + bit) as u64)
+            })
+            .map(move |page| self.range.start + page * self.page_size)
+            .filter(move |&address| address < self.range.end)
+    }
+}
+
+/// The handler that supplies the missing pages of the guest physical memory, see
+/// [`Vm::missing_page_handler`].
+pub(crate) type MissingPageHandler = Box<dyn FnMut(u64, &mut [u8]) + Send>;
+
+/// Helper function to read the bytes starting at the guest address into the given bytes buffer,
+/// where the read continues across adjacent regions, see [`Vm::read_physical_memory`].
+pub(crate) fn read_across_regions(
+    inner: &platform::Vm,
+    bytes: &mut [u8],
+    guest_address: u64,
+) -> Result<usize, Error> {
+    let mut offset = 0;
+
+    while offset < bytes.len() {
+        let address = guest_address + offset as u64;
+
+        match inner.read_physical_memory(&mut bytes[offset..], address) {
+            Ok(size) => offset += size,
+            Err(Error::InvalidGuestAddress) if offset > 0 => break,
+            Err(Error::InvalidGuestAddress) => return Err(Error::UnmappedGuestAddress(address)),
+            Err(e) => return Err(e),
+        }
+    }
+
+    Ok(offset)
+}
+
+/// Helper function to write the bytes from the given bytes buffer to the bytes starting at the
+/// guest address, where the write continues across adjacent regions, see
+/// [`Vm::write_physical_memory`].
+pub(crate) fn write_across_regions(
+    inner: &mut platform::Vm,
+    guest_address: u64,
+    bytes: &[u8],
+) -> Result<usize, Error> {
+    let mut offset = 0;
+
+    while offset < bytes.len() {
+        let address = guest_address + offset as u64;
+
+        match inner.write_physical_memory(address, &bytes[offset..]) {
+            Ok(size) => offset += size,
+            Err(Error::InvalidGuestAddress) if offset > 0 => break,
+            Err(Error::InvalidGuestAddress) => return Err(Error::UnmappedGuestAddress(address)),
+            Err(e) => return Err(e),
+        }
+    }
+
+    Ok(offset)
+}
+
+/// Helper function to check that the requested range of guest physical memory does not overlap
+/// with any of the existing regions in the given range map. Returns
+/// [`Error::OverlappingRegion`] with the first overlapping region otherwise.
+pub(crate) fn check_overlap(
+    ranges: &RangeMap<u64, u64>,
+    requested: Range<u64>,
+) -> Result<(), Error> {
+    let existing = ranges
+        .iter()
+        .map(|(range, _)| range)
+        .find(|range| range.start < requested.end && requested.start < range.end);
+
+    match existing {
+        Some(existing) => Err(Error::OverlappingRegion {
+            existing: existing.clone(),
+            requested,
+        }),
+        _ => Ok(()),
+    }
+}
+
+/// Helper function to check that the given range is aligned to the host page size.
+fn check_page_aligned(range: &Range<u64>) -> Result<(), Error> {
+    let page_size = MmapOptions::page_size().1 as u64;
+
+    if range.start > range.end || range.start % page_size != 0 || range.end % page_size != 0 {
+        return Err(Error::InvalidArgument);
     }
+
+    Ok(())
 }
 
 /// The `VmBuilder` allows for the configuration of certain properties for the new VM before
@@ -142,6 +587,13 @@ bitflags! {
 pub struct VmBuilder {
     /// The internal platform-specific implementation of the [`platform::VmBuilder`] struct.
     pub(crate) inner: platform::VmBuilder,
+    /// The scheduling priority of the threads running the virtual CPUs.
+    pub(crate) vcpu_thread_priority: ThreadPriority,
+    /// The resource group of the threads running the virtual CPUs.
+    pub(crate) vcpu_resource_group: Option<Arc<ResourceGroup>>,
+    /// Whether the local APICs are emulated in user space.
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    pub(crate) software_apic: bool,
 }
 
 impl VmBuilder {
@@ -149,6 +601,238 @@ impl VmBuilder {
     pub fn with_vcpu_count(self, count: usize) -> Result<Self, Error> {
         Ok(Self {
             inner: self.inner.with_vcpu_count(count)?,
+            ..self
+        })
+    }
+
+    /// This is used to specify the scheduling priority of the threads running the virtual CPUs.
+    /// The priority is applied to the calling thread the first time [`crate::Vcpu::run`] is
+    /// called on that thread. See [`crate::thread`] for the privileges required on each platform
+    /// and the fallbacks used when they are missing.
+    pub fn with_vcpu_thread_priority(self, priority: ThreadPriority) -> Result<Self, Error> {
+        Ok(Self {
+            vcpu_thread_priority: priority,
+            ..self
+        })
+    }
+
+    /// This is used to place the threads running the virtual CPUs into the given
+    /// [`ResourceGroup`] to limit their CPU time and the CPUs they run on. The calling thread is
+    /// added to the resource group the first time [`crate::Vcpu::run`] is called on that thread.
+    /// The resource group may be shared with other VMs and the threads running the emulated
+    /// devices. See [`crate::thread`] for the support on each platform.
+    pub fn with_vcpu_resource_group(self, group: Arc<ResourceGroup>) -> Result<Self, Error> {
+        Ok(Self {
+            vcpu_resource_group: Some(group),
+            ..self
+        })
+    }
+
+    /// This is used to specify how the virtual CPUs observe the time stamp counter. See
+    /// [`crate::tsc`] for the guarantees provided by each platform. Returns
+    /// [`Error::NotImplemented`] on platforms that do not support the given mode.
+    pub fn with_tsc_mode(self, mode: TscMode) -> Result<Self, Error> {
+        Ok(Self {
+            inner: self.inner.with_tsc_mode(mode)?,
+            ..self
+        })
+    }
+
+    /// This is used to create a protected guest, i.e. a guest whose memory is not accessible to
+    /// the host. Once built, [`Vm::read_physical_memory`], [`Vm::write_physical_memory`] and the
+    /// other accessors of guest memory return [`Error::ProtectedGuestMemory`]. Use
+    /// [`crate::Hypervisor::has_capability`] with [`crate::Capability::ProtectedGuest`] to check
+    /// whether protected guests are supported. Returns [`Error::NotImplemented`] otherwise.
+    ///
+    /// This is only supported on Linux, through pKVM on AArch64 and the software-protected VM
+    /// type on x86.
+    pub fn with_protected_guest(self, enabled: bool) -> Result<Self, Error> {
+        Ok(Self {
+            inner: self.inner.with_protected_guest(enabled)?,
+            ..self
+        })
+    }
+
+    /// This is used to enable the synthetic interrupt controller (SynIC). See [`crate::synic`] for
+    /// details. Returns [`Error::NotImplemented`] on platforms that do not support the SynIC.
+    pub fn with_synthetic_interrupts(self, enabled: bool) -> Result<Self, Error> {
+        Ok(Self {
+            inner: self.inner.with_synthetic_interrupts(enabled)?,
+            ..self
+        })
+    }
+
+    /// This is used to expose or hide 5-level paging (LA57), i.e. 57-bit linear addresses, to the
+    /// guest through CPUID. The guest can only set `CR4.LA57` if 5-level paging is exposed.
+    /// Returns [`Error::NotImplemented`] if 5-level paging is requested but not supported by the
+    /// host.
+    ///
+    /// This is only supported on Linux on the x86 architecture, as the other platforms do not
+    /// allow the CPUID of the guest to be configured yet.
+    pub fn with_la57(self, enabled: bool) -> Result<Self, Error> {
+        Ok(Self {
+            inner: self.inner.with_la57(enabled)?,
+            ..self
+        })
+    }
+
+    /// This is used to select whether the `hlt` instruction exits with
+    /// [`crate::ExitReason::Halted`], which is the default. Disabling this lets the hypervisor
+    /// halt the virtual CPU until it receives an interrupt, which lowers the latency of
+    /// long-running guests at the cost of the VMM no longer observing the idle virtual CPUs.
+    /// Returns [`Error::NotImplemented`] on platforms that do not support disabling HLT exits.
+    ///
+    /// On Linux, this relies on `KVM_CAP_X86_DISABLE_EXITS`. This is not supported on Microsoft
+    /// Windows and on AArch64 hosts.
+    pub fn with_hlt_exiting(self, enabled: bool) -> Result<Self, Error> {
+        Ok(Self {
+            inner: self.inner.with_hlt_exiting(enabled)?,
+            ..self
+        })
+    }
+
+    /// This is used to report the `cpuid` instructions executed by the guest as
+    /// [`crate::ExitReason::Cpuid`], such that the VMM can present custom feature bits to the
+    /// guest through [`crate::Vcpu::complete_cpuid`]. Returns [`Error::NotImplemented`] on
+    /// platforms that do not support CPUID exits.
+    ///
+    /// This is only supported on Mac OS X and Microsoft Windows on the x86 architecture, as KVM
+    /// serves the CPUID of the guest from the table configured in the kernel, see
+    /// [`VmBuilder::with_la57`].
+    pub fn with_cpuid_exits(self, enabled: bool) -> Result<Self, Error> {
+        Ok(Self {
+            inner: self.inner.with_cpuid_exits(enabled)?,
+            ..self
+        })
+    }
+
+    /// This is used to report the accesses of the guest to the MSRs that the hypervisor does not
+    /// handle itself as [`crate::ExitReason::MsrRead`] and [`crate::ExitReason::MsrWrite`], such
+    /// that the VMM can emulate them, e.g. the Hyper-V synthetic MSRs. Without this, the
+    /// hypervisor either injects a general protection fault or reports the access as
+    /// [`crate::ExitReason::Unknown`]. Returns [`Error::NotImplemented`] on platforms that do not
+    /// support MSR exits.
+    ///
+    /// On Linux, this relies on the user space MSR exits of KVM, i.e. `KVM_CAP_X86_USER_SPACE_MSR`.
+    /// On Mac OS X, all the MSRs that are not passed through to the guest are reported, except
+    /// for `IA32_APIC_BASE`, see [`crate::ExitReason::ApicBaseChanged`].
+    pub fn with_msr_exits(self, enabled: bool) -> Result<Self, Error> {
+        Ok(Self {
+            inner: self.inner.with_msr_exits(enabled)?,
+            ..self
+        })
+    }
+
+    /// This is used to report the accesses of the guest to the control registers selected by the
+    /// given [`CrExits`] as [`crate::ExitReason::CrAccess`], e.g. to track the mode switches of
+    /// the guest or to shadow its page tables. Returns [`Error::NotImplemented`] on platforms that
+    /// do not support control register exits.
+    ///
+    /// This is only supported on Mac OS X, as KVM, bhyve and the Windows Hypervisor Platform do
+    /// not report the control register accesses to the VMM.
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    pub fn with_cr_exits(self, exits: CrExits) -> Result<Self, Error> {
+        Ok(Self {
+            inner: self.inner.with_cr_exits(exits)?,
+            ..self
+        })
+    }
+
+    /// This is used to create the interrupt controllers emulated by the hypervisor, such that the
+    /// VMM raises the interrupt lines of its devices through [`Vm::set_irq_line`] rather than
+    /// emulating the interrupt controllers itself. Returns [`Error::NotImplemented`] on platforms
+    /// that do not emulate any interrupt controller.
+    ///
+    /// On Linux, this creates the PIC, the I/O APIC and the local APICs through
+    /// `KVM_CREATE_IRQCHIP`, after which KVM handles `hlt` in the kernel and
+    /// [`crate::Vcpu::inject_interrupt`] is no longer supported. On Microsoft Windows, this
+    /// selects the xAPIC emulation of the local APIC, but as the hypervisor does not provide an
+    /// I/O APIC, the interrupts are still delivered through [`crate::Vcpu::inject_interrupt`].
+    /// On FreeBSD, bhyve always emulates the interrupt controllers. This is not supported on Mac
+    /// OS X.
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    pub fn with_in_kernel_irqchip(self, enabled: bool) -> Result<Self, Error> {
+        Ok(Self {
+            inner: self.inner.with_in_kernel_irqchip(enabled)?,
+            ..self
+        })
+    }
+
+    /// This is used to emulate the 8254 PIT in the kernel with the given policy for the ticks of
+    /// channel 0 that the guest missed. Returns [`Error::NotImplemented`] on platforms that do not
+    /// emulate the PIT, in which case [`crate::Pit`] can be used to emulate the PIT in user space
+    /// instead, see [`crate::pit`].
+    ///
+    /// On Linux, this requires [`VmBuilder::with_in_kernel_irqchip`], as KVM raises the
+    /// interrupts of the PIT through the in-kernel interrupt controllers, and KVM also emulates
+    /// port 0x61 to gate channel 2. On FreeBSD, bhyve always emulates the PIT, but does not
+    /// reinject the missed ticks. This is not supported on Microsoft Windows and Mac OS X.
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    pub fn with_in_kernel_pit(self, reinjection: PitReinjection) -> Result<Self, Error> {
+        Ok(Self {
+            inner: self.inner.with_in_kernel_pit(reinjection)?,
+            ..self
+        })
+    }
+
+    /// This is used to emulate the local APICs of the virtual CPUs in user space, for the platforms
+    /// and configurations where the hypervisor does not virtualize the local APIC, i.e. Mac OS X,
+    /// Microsoft Windows without [`VmBuilder::with_synthetic_interrupts`] and Linux without
+    /// [`VmBuilder::with_in_kernel_irqchip`]. [`crate::Vcpu::run`] then handles the accesses to
+    /// the MMIO page of the local APIC and delivers the interrupts and IPIs between the virtual
+    /// CPUs without reporting them, and [`crate::Vcpu::inject_interrupt`] requests the interrupt
+    /// through the local APIC. See [`crate::devices::apic`] for the limitations.
+    ///
+    /// This should not be combined with the local APIC emulated by the hypervisor.
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    pub fn with_software_apic(self, enabled: bool) -> Result<Self, Error> {
+        Ok(Self {
+            software_apic: enabled,
+            ..self
+        })
+    }
+
+    /// This is used to run the guest as an AMD SEV guest with the given policy, such that its
+    /// memory is encrypted. SEV-ES is used if the policy contains [`SevPolicy::ES`]. Building the
+    /// VM initializes SEV and starts the launch of the guest, see [`crate::confidential`] for
+    /// the remainder of the launch flow. Returns [`Error::NotImplemented`] if SEV is not
+    /// supported.
+    ///
+    /// This is only supported on Linux.
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    pub fn with_sev(self, policy: SevPolicy) -> Result<Self, Error> {
+        Ok(Self {
+            inner: self.inner.with_sev(policy)?,
+            ..self
+        })
+    }
+
+    /// This is used to create an in-kernel GICv3 interrupt controller with the given
+    /// configuration, such that the hypervisor emulates the distributor and the redistributors,
+    /// and the VMM raises the shared peripheral interrupts through [`Vm::inject_irq`] and
+    /// [`Vm::set_irq_level`]. Returns [`Error::NotImplemented`] on platforms that do not provide
+    /// an in-kernel GIC.
+    ///
+    /// On Linux, the GIC is initialized the first time any virtual CPU runs, so all the virtual
+    /// CPUs have to be created before that. On Mac OS X, this requires macOS 15 or later and the
+    /// number of SPIs must not exceed the number supported by the Hypervisor Framework. This is
+    /// not supported on Microsoft Windows.
+    #[cfg(target_arch = "aarch64")]
+    pub fn with_gic(self, config: GicConfig) -> Result<Self, Error> {
+        Ok(Self {
+            inner: self.inner.with_gic(config)?,
+            ..self
+        })
+    }
+
+    /// This is used to enable support for Xen HVM guests with the given configuration. See
+    /// [`crate::xen`] for details. Returns [`Error::NotImplemented`] on platforms that do not
+    /// support Xen HVM guests.
+    #[cfg(feature = "xen")]
+    pub fn with_xen(self, config: XenConfig) -> Result<Self, Error> {
+        Ok(Self {
+            inner: self.inner.with_xen(config)?,
+            ..self
         })
     }
 
@@ -157,6 +841,18 @@ impl VmBuilder {
         Ok(Vm {
             inner: Arc::new(RwLock::new(self.inner.build(name)?)),
             page_allocator: Arc::new(RwLock::new(PageAllocator::new())),
+            vcpu_thread_priority: self.vcpu_thread_priority,
+            vcpu_resource_group: self.vcpu_resource_group,
+            symbols: Arc::new(RwLock::new(SymbolMap::new())),
+            hypercalls: Arc::new(RwLock::new(HypercallTable::default())),
+            backing_files: Arc::new(RwLock::new(HashMap::new())),
+            pio_bus: Arc::new(RwLock::new(PioBus::new())),
+            mmio_bus: Arc::new(RwLock::new(MmioBus::new())),
+            #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+            apic_bus: match self.software_apic {
+                true => Some(Arc::new(ApicBus::default())),
+                _ => None,
+            },
         })
     }
 }
@@ -169,30 +865,173 @@ pub struct Vm<'a> {
     pub(crate) inner: Arc<RwLock<platform::Vm>>,
     /// The page allocator.
     pub(crate) page_allocator: Arc<RwLock<PageAllocator<'a>>>,
+    /// The scheduling priority of the threads running the virtual CPUs.
+    pub(crate) vcpu_thread_priority: ThreadPriority,
+    /// The resource group of the threads running the virtual CPUs.
+    pub(crate) vcpu_resource_group: Option<Arc<ResourceGroup>>,
+    /// The symbol map used to annotate guest addresses.
+    pub(crate) symbols: Arc<RwLock<SymbolMap>>,
+    /// The hypercall handlers.
+    pub(crate) hypercalls: Arc<RwLock<HypercallTable>>,
+    /// The files behind the regions that are mapped shared by their guest physical address.
+    pub(crate) backing_files: Arc<RwLock<HashMap<u64, BackingFile>>>,
+    /// The devices registered for ranges of I/O ports.
+    pub(crate) pio_bus: Arc<RwLock<PioBus>>,
+    /// The devices registered for ranges of guest physical addresses.
+    pub(crate) mmio_bus: Arc<RwLock<MmioBus>>,
+    /// The local APICs emulated in user space, if enabled.
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    pub(crate) apic_bus: Option<Arc<ApicBus>>,
 }
 
 impl<'a> Vm<'a> {
-    /// Create a virtual CPU with the given vCPU ID.
+    /// Create a virtual CPU with the given vCPU ID on the current thread. On Mac OS X, the virtual
+    /// CPU must be run on the same thread. Use [`Vm::vcpu_factory`] to create the virtual CPU on
+    /// the thread that runs it instead.
     pub fn create_vcpu(&mut self, id: usize) -> Result<Vcpu, Error> {
-        let mut vcpu = Vcpu {
-            inner: self.inner.write().unwrap().create_vcpu(id)?,
+        self.vcpu_factory(VcpuSpec::new(id)).create()
+    }
+
+    /// Returns a [`VcpuFactory`] for the given [`VcpuSpec`], which can be sent to another thread
+    /// to create the virtual CPU there.
+    pub fn vcpu_factory(&self, spec: VcpuSpec) -> VcpuFactory {
+        VcpuFactory {
+            vm: self.inner.clone(),
+            spec,
+            thread_priority: self.vcpu_thread_priority,
+            resource_group: self.vcpu_resource_group.clone(),
+            symbols: self.symbols.clone(),
+            hypercalls: self.hypercalls.clone(),
+            #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+            apic_bus: self.apic_bus.clone(),
+        }
+    }
+
+    /// Loads the symbol map used to annotate guest addresses, replacing any previously loaded
+    /// symbol map. The symbol map is shared with the virtual CPUs of the VM.
+    pub fn set_symbol_map(&self, map: SymbolMap) {
+        *self.symbols.write().unwrap() = map;
+    }
+
+    /// Formats the given guest virtual address as `name+0xoffset` using the symbol map loaded
+    /// through [`Vm::set_symbol_map`]. Returns `None` if there is no symbol containing the
+    /// address.
+    pub fn symbolize(&self, address: u64) -> Option<String> {
+        self.symbols.read().unwrap().symbolize(address)
+    }
+
+    /// Registers the handler for the hypercall with the given number, replacing any handler
+    /// previously registered for that number. The handler is shared with the virtual CPUs of the
+    /// VM, which call it upon the hypercall and write the returned value back to the result
+    /// register before resuming. See [`crate::hypercall`] for the calling convention and the
+    /// support on each platform.
+    pub fn register_hypercall<F>(&self, nr: u64, handler: F)
+    where
+        F: Fn(&mut HypercallContext) -> Result<u64, Error> + Send + Sync + 'static,
+    {
+        self.hypercalls.write().unwrap().insert(nr, Arc::new(handler));
+    }
+
+    /// Removes the handler for the hypercall with the given number, such that the hypercall exits
+    /// with [`crate::ExitReason::Hypercall`] again.
+    pub fn unregister_hypercall(&self, nr: u64) {
+        self.hypercalls.write().unwrap().remove(nr);
+    }
+
+    /// Registers the device for the given number of I/O ports starting at the given port, such
+    /// that [`Vm::dispatch_exit`] routes the port I/O on those ports to the device. Returns
+    /// [`Error::InvalidArgument`] if the range is empty, exceeds the I/O port space, or overlaps
+    /// with the range of another device. See [`crate::bus`] for details.
+    pub fn register_pio_device(
+        &self,
+        port: u16,
+        count: u16,
+        device: SharedDevice,
+    ) -> Result<(), Error> {
+        self.pio_bus.write().unwrap().insert(port, count, device)
+    }
+
+    /// Removes the device registered for the range of I/O ports starting at the given port, and
+    /// returns it.
+    pub fn unregister_pio_device(&self, port: u16) -> Option<SharedDevice> {
+        self.pio_bus.write().unwrap().remove(port)
+    }
+
+    /// Registers the device for the given number of bytes starting at the given guest physical
+    /// address, such that [`Vm::dispatch_exit`] routes the MMIO accesses to that range to the
+    /// device. Returns [`Error::InvalidArgument`] if the range is empty, wraps around the address
+    /// space, or overlaps with the range of another device. The range should not be mapped as
+    /// guest physical memory, as the guest would access the memory rather than exiting.
+    pub fn register_mmio_device(
+        &self,
+        address: u64,
+        size: u64,
+        device: SharedDevice,
+    ) -> Result<(), Error> {
+        self.mmio_bus.write().unwrap().insert(address, size, device)
+    }
+
+    /// Removes the device registered for the range of guest physical addresses starting at the
+    /// given address, and returns it.
+    pub fn unregister_mmio_device(&self, address: u64) -> Option<SharedDevice> {
+        self.mmio_bus.write().unwrap().remove(address)
+    }
+
+    /// Routes the given [`ExitReason::IoIn`], [`ExitReason::IoOut`], [`ExitReason::MmioRead`] or
+    /// [`ExitReason::MmioWrite`] exit to the device registered for the port or the address, where
+    /// reads fill the data of the exit. Returns whether a device handled the exit, such that the
+    /// virtual CPU can be resumed, or `false` for the other exits and for the ports and addresses
+    /// without a device, which the VMM has to handle itself.
+    pub fn dispatch_exit(&self, exit: &mut ExitReason) -> Result<bool, Error> {
+        // Release the lock of the bus before accessing the device, such that the device can
+        // register devices itself.
+        let entry = match exit {
+            ExitReason::IoIn { port, .. } | ExitReason::IoOut { port, .. } =>
+                self.pio_bus.read().unwrap().get(*port),
+            ExitReason::MmioRead { address, .. } | ExitReason::MmioWrite { address, .. } =>
+                self.mmio_bus.read().unwrap().get(*address),
+            _ => return Ok(false),
         };
 
-        vcpu.reset()?;
+        let (device, offset) = match entry {
+            Some(entry) => entry,
+            _ => return Ok(false),
+        };
+
+        let mut device = device.lock().unwrap();
+
+        match exit {
+            ExitReason::IoIn { data, .. } | ExitReason::MmioRead { data, .. } =>
+                device.read(offset, data)?,
+            ExitReason::IoOut { data, .. } | ExitReason::MmioWrite { data, .. } =>
+                device.write(offset, data)?,
+            _ => (),
+        }
 
-        Ok(vcpu)
+        Ok(true)
     }
 
     /// Allocates guest physical memory into the VM's address space at the given guest address with
     /// the given size. The size must be aligned to the minimal page size. In addition, the
     /// protection of the memory mapping is set to the given protection. This protection affects
     /// how the guest VM can or cannot access the guest physical memory.
+    ///
+    /// On Linux and Microsoft Windows, the memory is backed by anonymous shared memory, such that
+    /// [`Vm::fork`] can map it copy-on-write, see [`MemoryBacking::Anonymous`].
     pub fn allocate_physical_memory(
         &mut self,
         guest_address: u64,
         size: usize,
         protection: ProtectionFlags,
     ) -> Result<(), Error> {
+        if cfg!(any(target_os = "linux", target_os = "windows")) {
+            return self.allocate_physical_memory_with(
+                guest_address,
+                size,
+                AllocationOptions::new(protection),
+            );
+        }
+
         self.inner
             .write()
             .unwrap()
@@ -206,40 +1045,691 @@ impl<'a> Vm<'a> {
         Ok(())
     }
 
-    /// Maps guest physical memory into the VM's address space. More specifically this function
-    /// takes a virtual address as `bytes`, resolves it to the host physical address and maps it to
-    /// the specified guest physical address `guest_address` with the specified protection
-    /// [`ProtectionFlags`] and the specified `size`, which must be page size aligned.
+    /// Allocates guest physical memory like [`Vm::allocate_physical_memory`], but backed by the
+    /// given [`MemoryBacking`], e.g. to overcommit the memory of the guest or to back it by a
+    /// file.
     ///
-    /// This function is not supported on FreeBSD due to underlying differences in the memory
-    /// management API provided by FreeBSD. While Microsoft Windows, Linux and Mac OS X allow us to
-    /// map in virtual memory, and then map that directly into our guest physical address space,
-    /// FreeBSD instead allocates guest physical memory for us and allows us to map that into our
-    /// virtual address space.
-    pub unsafe fn map_physical_memory(
+    /// This function is not supported on FreeBSD, see [`Vm::map_physical_memory`].
+    pub fn allocate_backed_physical_memory(
         &mut self,
         guest_address: u64,
-        mapping: MmapMut,
+        size: usize,
         protection: ProtectionFlags,
+        backing: MemoryBacking,
     ) -> Result<(), Error> {
-        self.inner
-            .write()
-            .unwrap()
-            .map_physical_memory(guest_address, mapping, protection)
+        self.allocate_physical_memory_with(
+            guest_address,
+            size,
+            AllocationOptions::new(protection).with_backing(backing),
+        )
     }
 
-    /// Unmaps the guest physical memory.
-    pub fn unmap_physical_memory(
+    /// Allocates guest physical memory like [`Vm::allocate_physical_memory`] with the given
+    /// [`AllocationOptions`], e.g. to back the memory by huge pages. When backed by huge pages,
+    /// the guest address and the size must be aligned to the size of the huge pages, and the
+    /// memory cannot be backed by a file. Returns [`Error::InvalidArgument`] otherwise.
+    ///
+    /// This function is not supported on FreeBSD, see [`Vm::map_physical_memory`].
+    pub fn allocate_physical_memory_with(
         &mut self,
         guest_address: u64,
+        size: usize,
+        options: AllocationOptions,
     ) -> Result<(), Error> {
+        let kind = options.backing.kind();
+        let mut mmap_options = MmapOptions::new(size);
+        let mut flags = MmapFlags::empty();
+        let mut backing_file = None;
+
+        if let Some(huge_pages) = options.huge_pages {
+            let page_size = huge_pages.bytes();
+
+            if guest_address % page_size != 0 || size as u64 % page_size != 0 {
+                return Err(Error::InvalidArgument);
+            }
+
+            if kind == MemoryBackingKind::File {
+                return Err(Error::InvalidArgument);
+            }
+
+            let page_size = match huge_pages {
+                HugePageSize::Size2M => PageSize::_2M,
+                HugePageSize::Size1G => PageSize::_1G,
+            };
+
+            mmap_options = mmap_options.with_page_size(page_size);
+            flags |= MmapFlags::HUGE_PAGES;
+        }
+
+        match options.backing {
+            MemoryBacking::Anonymous => (),
+            MemoryBacking::Overcommit => flags |= MmapFlags::NO_RESERVE,
+            MemoryBacking::File { file, offset } => {
+                backing_file = Some((file.try_clone()?, offset));
+                mmap_options = unsafe { mmap_options.with_file(file, offset) };
+                flags |= MmapFlags::SHARED;
+            }
+        }
+
+        // Back anonymous memory by anonymous shared memory, such that a fork can map it
+        // copy-on-write rather than copying it.
+        #[cfg(any(target_os = "linux", target_os = "windows"))]
+        if backing_file.is_none() && options.huge_pages.is_none() {
+            let file = platform::vm::create_shared_memory(size)?;
+
+            backing_file = Some((file.try_clone()?, 0));
+            mmap_options = unsafe { mmap_options.with_file(file, 0) };
+            flags |= MmapFlags::SHARED;
+        }
+
+        let mapping = mmap_options
+            .with_flags(flags)
+            .map_mut()?;
+
         self.inner
             .write()
             .unwrap()
-            .unmap_physical_memory(guest_address)
-    }
+            .map_physical_memory(guest_address, mapping, options.protection, kind)?;
 
-    /// Changes the protection flags of the guest physical memory.
+        if let Some((file, offset)) = backing_file {
+            self.track_backing_file(guest_address, file, offset, size);
+        }
+
+        self.page_allocator
+            .write()
+            .unwrap()
+            .add_range(guest_address..guest_address + size as u64)?;
+
+        Ok(())
+    }
+
+    /// Returns the regions of guest physical memory that are mapped into the VM in the order of
+    /// their guest physical addresses, where every region is described by its guest physical
+    /// address range, its protection and how it is backed. This allows tools to introspect the
+    /// guest physical layout rather than tracking it separately. The SMRAM is not included.
+    ///
+    /// This is not supported on FreeBSD.
+    pub fn regions(
+        &self,
+    ) -> Result<impl Iterator<Item = (Range<u64>, ProtectionFlags, MemoryBackingKind)>, Error> {
+        let regions = self.inner
+            .read()
+            .unwrap()
+            .regions()?;
+
+        Ok(regions.into_iter())
+    }
+
+    /// Saves a snapshot of the VM to the file at the given path, which holds the regions of guest
+    /// physical memory, the given states of the virtual CPUs in the order of their vCPU IDs and
+    /// the extra state that the given callback appends to the buffer, e.g. the state of the
+    /// emulated devices. The virtual CPUs should be paused while the snapshot is taken, and their
+    /// states should be captured through [`crate::Vcpu::get_state`]. See [`crate::snapshot`] for
+    /// the format, or [`crate::snapshot::save`] to write the snapshot to another transport.
+    ///
+    /// This is not supported on FreeBSD, see [`Vm::regions`].
+    pub fn save<P, F>(&self, path: P, vcpus: &[VcpuState], extra: F) -> Result<(), Error>
+    where
+        P: AsRef<Path>,
+        F: FnOnce(&mut Vec<u8>) -> Result<(), Error>,
+    {
+        let mut file = BufWriter::new(File::create(path)?);
+
+        snapshot::save(self, &mut file, vcpus, extra)
+    }
+
+    /// Saves a snapshot of the VM to the file at the given path like [`Vm::save`], but encrypts
+    /// and authenticates the snapshot with the given key, see [`crate::snapshot#encryption`].
+    #[cfg(feature = "encryption")]
+    pub fn save_encrypted<P, F>(
+        &self,
+        path: P,
+        key: &[u8; 32],
+        vcpus: &[VcpuState],
+        extra: F,
+    ) -> Result<(), Error>
+    where
+        P: AsRef<Path>,
+        F: FnOnce(&mut Vec<u8>) -> Result<(), Error>,
+    {
+        let mut file = BufWriter::new(File::create(path)?);
+
+        snapshot::save_encrypted(self, &mut file, key, vcpus, extra)
+    }
+
+    /// Enables or disables tracking which pages of guest physical memory the guest writes to,
+    /// which applies to all regions, including the ones allocated or mapped later on. The dirty
+    /// pages are retrieved through [`Vm::dirty_bitmap`]. This is the primitive behind
+    /// [`crate::migration`].
+    ///
+    /// This is only supported on Linux and Microsoft Windows.
+    pub fn set_dirty_tracking(&self, enabled: bool) -> Result<(), Error> {
+        self.inner
+            .write()
+            .unwrap()
+            .set_dirty_tracking(enabled)
+    }
+
+    /// Returns the pages of the region of guest physical memory at the given guest address that
+    /// were written to since dirty tracking was enabled or since the bitmap of the region was last
+    /// retrieved, and clears the bitmap. Returns [`Error::InvalidArgument`] if dirty tracking is
+    /// not enabled, see [`Vm::set_dirty_tracking`].
+    ///
+    /// This is only supported on Linux and Microsoft Windows.
+    pub fn dirty_bitmap(&self, guest_address: u64) -> Result<DirtyBitmap, Error> {
+        self.inner
+            .read()
+            .unwrap()
+            .dirty_bitmap(guest_address)
+    }
+
+    /// Supplies the pages of guest physical memory lazily, e.g. to stream the memory of the guest
+    /// during a post-copy restore. The regions that are mapped at the time of the call are
+    /// registered, such that any page of these regions that has not been populated yet is
+    /// supplied by the given handler once it is touched, whether by the guest or by the host. The
+    /// handler is called on a thread of its own with the guest physical address of the page and
+    /// a zero-filled buffer of the host page size to fill in, while the access waits for the
+    /// handler to return. The handler must therefore not access the guest physical memory of the
+    /// registered regions itself. Calling this function again replaces the handler.
+    ///
+    /// This is only supported on Linux through userfaultfd.
+    pub fn missing_page_handler<F>(&self, handler: F) -> Result<(), Error>
+    where
+        F: FnMut(u64, &mut [u8]) + Send + 'static,
+    {
+        self.inner
+            .write()
+            .unwrap()
+            .missing_page_handler(Box::new(handler))
+    }
+
+    /// Discards the host pages backing the given range of guest physical memory, such that the
+    /// host can reuse the memory, e.g. once the guest released the memory to a balloon device,
+    /// see [`crate::balloon`]. The range must be aligned to the host page size and must lie
+    /// within a single region. The contents of the memory are undefined afterwards, and the guest
+    /// should not touch the memory until it has been repopulated through [`Vm::reinflate`].
+    ///
+    /// This is not supported on FreeBSD.
+    pub fn reclaim(&self, range: Range<u64>) -> Result<(), Error> {
+        check_page_aligned(&range)?;
+
+        self.inner
+            .read()
+            .unwrap()
+            .reclaim(range)
+    }
+
+    /// Repopulates the host pages backing the given range of guest physical memory after
+    /// [`Vm::reclaim`], such that the guest does not fault on the host pages when it touches the
+    /// memory again. The range must be aligned to the host page size and must lie within a single
+    /// region.
+    ///
+    /// This is not supported on FreeBSD.
+    pub fn reinflate(&self, range: Range<u64>) -> Result<(), Error> {
+        check_page_aligned(&range)?;
+
+        self.inner
+            .read()
+            .unwrap()
+            .reinflate(range)
+    }
+
+    /// Returns the number of bytes of guest physical memory that are resident in host memory,
+    /// i.e. that are neither swapped out nor untouched by the guest.
+    ///
+    /// This is only supported on Linux and Mac OS X.
+    pub fn resident_size(&self) -> Result<usize, Error> {
+        self.inner
+            .read()
+            .unwrap()
+            .resident_size()
+    }
+
+    /// Caps the resident size of the guest physical memory by asking the host to page out guest
+    /// memory until the resident size drops to at most `limit` bytes. The pages are faulted back
+    /// in as the guest touches them. Returns the resident size after trimming. This is meant to
+    /// be called periodically for idle guests.
+    ///
+    /// This is only supported on Linux.
+    pub fn trim_resident_size(&self, limit: usize) -> Result<usize, Error> {
+        self.inner
+            .read()
+            .unwrap()
+            .trim_resident_size(limit)
+    }
+
+    /// Allocates guest physical memory like [`Vm::allocate_physical_memory`], but backed by a new
+    /// [`SharedRegion`], which is returned such that the region can be imported into other VMs
+    /// through [`Vm::import_region`].
+    ///
+    /// This function is not supported on FreeBSD, see [`Vm::map_physical_memory`].
+    pub fn export_region(
+        &mut self,
+        guest_address: u64,
+        size: usize,
+        protection: ProtectionFlags,
+    ) -> Result<SharedRegion, Error> {
+        let region = SharedRegion::new(size)?;
+
+        self.import_region(guest_address, &region, protection)?;
+
+        Ok(region)
+    }
+
+    /// Maps the given [`SharedRegion`] into the VM's address space at the given guest address
+    /// with the given protection. The writes of any of the VMs that the region is mapped into are
+    /// visible to the others.
+    ///
+    /// This function is not supported on FreeBSD, see [`Vm::map_physical_memory`].
+    pub fn import_region(
+        &mut self,
+        guest_address: u64,
+        region: &SharedRegion,
+        protection: ProtectionFlags,
+    ) -> Result<(), Error> {
+        let mapping = region.map()?;
+
+        self.inner
+            .write()
+            .unwrap()
+            .map_physical_memory(guest_address, mapping, protection, MemoryBackingKind::Shared)?;
+
+        self.track_backing_file(guest_address, region.file.try_clone()?, 0, region.size);
+
+        Ok(())
+    }
+
+    /// Maps `len` bytes of the given file starting at the given offset into the VM's address space
+    /// at the given guest address with the given protection, e.g. to map a disk image or a
+    /// prebuilt RAM image without copying it. The offset must be aligned to the page size, and the
+    /// file must be at least `offset + len` bytes large. Whether the writes of the guest end up in
+    /// the file depends on the given [`FileSharing`]. The region is reported as
+    /// [`MemoryBackingKind::File`] by [`Vm::regions`].
+    ///
+    /// This function is not supported on FreeBSD, see [`Vm::map_physical_memory`].
+    pub fn map_file(
+        &mut self,
+        guest_address: u64,
+        file: File,
+        offset: u64,
+        len: usize,
+        protection: ProtectionFlags,
+        sharing: FileSharing,
+    ) -> Result<(), Error> {
+        // Only the writes to a shared mapping end up in the file, so only a shared mapping can be
+        // mapped copy-on-write by a fork.
+        let (flags, backing_file) = match sharing {
+            FileSharing::Shared => (MmapFlags::SHARED, Some(file.try_clone()?)),
+            FileSharing::Private => (MmapFlags::empty(), None),
+        };
+
+        let options = unsafe { MmapOptions::new(len).with_file(file, offset) };
+        let mapping = options
+            .with_flags(flags)
+            .map_mut()?;
+
+        self.inner
+            .write()
+            .unwrap()
+            .map_physical_memory(guest_address, mapping, protection, MemoryBackingKind::File)?;
+
+        if let Some(file) = backing_file {
+            self.track_backing_file(guest_address, file, offset, len);
+        }
+
+        Ok(())
+    }
+
+    /// Helper function to keep track of the file behind a region that is mapped shared.
+    fn track_backing_file(&self, guest_address: u64, file: File, offset: u64, size: usize) {
+        let backing_file = BackingFile {
+            file,
+            offset,
+            size: size as u64,
+        };
+
+        self.backing_files
+            .write()
+            .unwrap()
+            .insert(guest_address, backing_file);
+    }
+
+    /// Forks the VM into a new VM that is built from the given [`VmBuilder`] with the given name,
+    /// e.g. to reset the guest to a snapshot for every iteration of a fuzzer. The builder should
+    /// be configured the same way as the builder of this VM, apart from the number of virtual
+    /// CPUs, which is taken from the given states. The states are returned along with the new VM,
+    /// as the virtual CPUs have to be created on the threads that run them on some platforms,
+    /// after which their states are restored through [`crate::Vcpu::set_state`].
+    ///
+    /// The regions that are backed by a file or by shared memory are mapped copy-on-write, such
+    /// that a page is only copied once the new VM writes to it. These are the regions allocated
+    /// through [`Vm::allocate_physical_memory`] or with [`MemoryBacking::Anonymous`] or
+    /// [`MemoryBacking::Overcommit`] on Linux and Microsoft Windows, the ones allocated with
+    /// [`MemoryBacking::File`], the ones mapped through [`Vm::map_file`] with
+    /// [`FileSharing::Shared`] and the ones mapped through [`Vm::export_region`] and
+    /// [`Vm::import_region`]. As the pages that the new VM did not write to yet still reflect the
+    /// memory of this VM, this VM must not write to these regions, e.g. the virtual CPUs of this
+    /// VM should remain paused, while the new VM is in use. The other regions, e.g. the ones
+    /// backed by huge pages or mapped through [`Vm::map_physical_memory`], are copied.
+    ///
+    /// This is not supported on FreeBSD, see [`Vm::regions`].
+    pub fn fork<'b>(
+        &self,
+        builder: VmBuilder,
+        name: &'b str,
+        vcpus: &[VcpuState],
+    ) -> Result<RestoredVm<'b>, Error> {
+        let mut vm = builder
+            .with_vcpu_count(vcpus.len().max(1))?
+            .build(name)?;
+
+        let backing_files = self.backing_files.read().unwrap();
+        let mut chunk = vec![];
+
+        for (range, protection, _) in self.regions()? {
+            let size = (range.end - range.start) as usize;
+
+            if let Some(backing_file) = backing_files.get(&range.start) {
+                // A private mapping of the file is copy-on-write.
+                let file = backing_file.file.try_clone()?;
+                let options = unsafe {
+                    MmapOptions::new(size).with_file(file, backing_file.offset)
+                };
+                let mapping = options.map_mut()?;
+
+                vm.inner
+                    .write()
+                    .unwrap()
+                    .map_physical_memory(
+                        range.start,
+                        mapping,
+                        protection,
+                        MemoryBackingKind::CopyOnWrite,
+                    )?;
+
+                continue;
+            }
+
+            vm.allocate_physical_memory(range.start, size, protection)?;
+
+            // Copy the guest physical memory in chunks, rather than buffering whole regions.
+            chunk.resize(size.min(1 << 20), 0);
+
+            let mut address = range.start;
+
+            while address < range.end {
+                let length = chunk.len().min((range.end - address) as usize);
+
+                if self.read_physical_memory(&mut chunk[..length], address)? != length {
+                    return Err(Error::InvalidGuestAddress);
+                }
+
+                vm.write_all_physical_memory(address, &chunk[..length])?;
+                address += length as u64;
+            }
+        }
+
+        Ok(RestoredVm {
+            vm,
+            vcpus: vcpus.to_vec(),
+            extra: vec![],
+        })
+    }
+
+    /// Maps guest physical memory into the VM's address space. More specifically this function
+    /// takes a virtual address as `bytes`, resolves it to the host physical address and maps it to
+    /// the specified guest physical address `guest_address` with the specified protection
+    /// [`ProtectionFlags`] and the specified `size`, which must be page size aligned.
+    ///
+    /// Returns [`Error::OverlappingRegion`] if the mapping overlaps with an existing region, see
+    /// [`Vm::replace_physical_memory`] to replace the existing regions instead.
+    ///
+    /// This function is not supported on FreeBSD due to underlying differences in the memory
+    /// management API provided by FreeBSD. While Microsoft Windows, Linux and Mac OS X allow us to
+    /// map in virtual memory, and then map that directly into our guest physical address space,
+    /// FreeBSD instead allocates guest physical memory for us and allows us to map that into our
+    /// virtual address space.
+    pub unsafe fn map_physical_memory(
+        &mut self,
+        guest_address: u64,
+        mapping: MmapMut,
+        protection: ProtectionFlags,
+    ) -> Result<(), Error> {
+        self.inner
+            .write()
+            .unwrap()
+            .map_physical_memory(guest_address, mapping, protection, MemoryBackingKind::Mapped)
+    }
+
+    /// Maps guest physical memory like [`Vm::map_physical_memory`], but rather than failing with
+    /// [`Error::OverlappingRegion`], this unmaps any regions that overlap with the new mapping as
+    /// a whole. The regions are swapped while holding on to the VM, such that no other accessor of
+    /// the VM observes the guest physical memory in between. The overlapping regions are kept
+    /// until the new mapping is in place, such that they are mapped again with their protection
+    /// if mapping the new memory fails. Any sub-ranges changed through
+    /// [`Vm::protect_physical_memory_range`] are reset in that case.
+    ///
+    /// This function is not supported on FreeBSD, see [`Vm::map_physical_memory`].
+    pub unsafe fn replace_physical_memory(
+        &mut self,
+        guest_address: u64,
+        mapping: MmapMut,
+        protection: ProtectionFlags,
+    ) -> Result<(), Error> {
+        let range = guest_address..guest_address + mapping.len() as u64;
+        let mut inner = self.inner.write().unwrap();
+
+        let overlapping: Vec<Range<u64>> = inner
+            .regions()?
+            .into_iter()
+            .map(|(region, _, _)| region)
+            .filter(|region| region.start < range.end && range.start < region.end)
+            .collect();
+
+        let mut taken = vec![];
+        let mut result = Ok(());
+
+        for region in &overlapping {
+            match inner.take_physical_memory(region.start) {
+                Ok(taken_region) => taken.push((region.start, taken_region)),
+                Err(e) => {
+                    result = Err(e);
+                    break;
+                }
+            }
+        }
+
+        if result.is_ok() {
+            result = inner.map_physical_memory(
+                guest_address,
+                mapping,
+                protection,
+                MemoryBackingKind::Mapped,
+            );
+        }
+
+        if let Err(e) = result {
+            // Roll back by mapping the regions that were taken out again.
+            for (start, (mapping, protection, backing)) in taken {
+                inner.map_physical_memory(start, mapping, protection, backing)?;
+            }
+
+            return Err(e);
+        }
+
+        drop(inner);
+
+        // The pages of the replaced regions must no longer be handed out by the page allocator.
+        let mut page_allocator = self.page_allocator.write().unwrap();
+
+        for region in &overlapping {
+            if page_allocator.contains(region.start) {
+                page_allocator.remove_range(region.clone());
+            }
+        }
+
+        drop(page_allocator);
+
+        self.backing_files
+            .write()
+            .unwrap()
+            .retain(|start, _| !overlapping.iter().any(|region| region.start == *start));
+
+        Ok(())
+    }
+
+    /// Unmaps the guest physical memory.
+    pub fn unmap_physical_memory(
+        &mut self,
+        guest_address: u64,
+    ) -> Result<(), Error> {
+        self.inner
+            .write()
+            .unwrap()
+            .unmap_physical_memory(guest_address)?;
+
+        self.backing_files
+            .write()
+            .unwrap()
+            .retain(|&start, backing_file| {
+                !(start..start + backing_file.size).contains(&guest_address)
+            });
+
+        Ok(())
+    }
+
+    /// Moves the region of guest physical memory at the given guest address to the new guest
+    /// address without copying its contents, i.e. the same host memory is mapped at the new guest
+    /// address, e.g. to implement the reprogramming of a PCI BAR. The guest address must be the
+    /// start of the region and the new guest address must be aligned to the page size. Returns
+    /// [`Error::OverlappingRegion`] if the moved region overlaps with any other region. The
+    /// virtual CPUs should not access the region while it is being moved.
+    ///
+    /// On Linux, this is not supported while a missing page handler is installed, see
+    /// [`Vm::missing_page_handler`]. This is not supported on FreeBSD.
+    pub fn remap_physical_memory(
+        &mut self,
+        guest_address: u64,
+        new_guest_address: u64,
+    ) -> Result<(), Error> {
+        let regions = self.regions()?;
+
+        let size = match regions.iter().find(|(region, _, _)| region.start == guest_address) {
+            Some((region, _, _)) => region.end - region.start,
+            _ => return Err(Error::InvalidGuestAddress),
+        };
+
+        let range = new_guest_address..new_guest_address + size;
+
+        check_page_aligned(&range)?;
+
+        // The moved region must not overlap with any other region.
+        let others: RangeMap<u64, u64> = regions
+            .iter()
+            .filter(|(region, _, _)| region.start != guest_address)
+            .map(|(region, _, _)| (region.clone(), region.start))
+            .collect();
+
+        check_overlap(&others, range)?;
+
+        self.inner
+            .write()
+            .unwrap()
+            .remap_physical_memory(guest_address, new_guest_address)?;
+
+        self.page_allocator
+            .write()
+            .unwrap()
+            .move_range(guest_address..guest_address + size, new_guest_address);
+
+        let mut backing_files = self.backing_files.write().unwrap();
+
+        if let Some(backing_file) = backing_files.remove(&guest_address) {
+            backing_files.insert(new_guest_address, backing_file);
+        }
+
+        Ok(())
+    }
+
+    /// Resizes the region of guest physical memory at the given guest address to the given size
+    /// in place, i.e. without unmapping it, copying its contents and mapping it again, such that
+    /// memory can be hot-plugged into or out of the guest. The contents of the region are
+    /// preserved up to the new size, while the memory that a region grows by is zero-filled.
+    /// The guest address must be the start of the region and the size must be a non-zero multiple
+    /// of the page size. Returns [`Error::OverlappingRegion`] if the grown region overlaps with
+    /// any other region. The virtual CPUs should not access the region while it is being resized.
+    ///
+    /// On Linux, the memory slot is registered again with the new size. The region can only grow
+    /// beyond the size it was allocated with if it is backed by anonymous memory that is not
+    /// backed by huge pages, in which case the memfd backing the memory is grown and mapped
+    /// again. On Mac OS X, the region can only grow back up to the size it was allocated with.
+    /// Returns [`Error::NotImplemented`] otherwise. This is not supported on Microsoft Windows and
+    /// FreeBSD.
+    pub fn resize_physical_memory(
+        &mut self,
+        guest_address: u64,
+        size: usize,
+    ) -> Result<(), Error> {
+        let range = guest_address..guest_address + size as u64;
+
+        if size == 0 {
+            return Err(Error::InvalidArgument);
+        }
+
+        check_page_aligned(&range)?;
+
+        let regions = self.regions()?;
+
+        let old_size = match regions.iter().find(|(region, _, _)| region.start == guest_address) {
+            Some((region, _, _)) => region.end - region.start,
+            _ => return Err(Error::InvalidGuestAddress),
+        };
+
+        // The grown region must not overlap with any other region.
+        let others: RangeMap<u64, u64> = regions
+            .iter()
+            .filter(|(region, _, _)| region.start != guest_address)
+            .map(|(region, _, _)| (region.clone(), region.start))
+            .collect();
+
+        check_overlap(&others, range.clone())?;
+
+        let backing_file = match self.backing_files.read().unwrap().get(&guest_address) {
+            Some(backing_file) => Some((backing_file.file.try_clone()?, backing_file.offset)),
+            _ => None,
+        };
+
+        self.inner
+            .write()
+            .unwrap()
+            .resize_physical_memory(guest_address, size, backing_file)?;
+
+        // Hand the pages that the region grew by to the page allocator, or take back the pages
+        // that it shrunk by, if the region is managed by the page allocator.
+        let mut page_allocator = self.page_allocator.write().unwrap();
+        let old_end = guest_address + old_size;
+
+        if page_allocator.contains(guest_address) {
+            if range.end > old_end {
+                page_allocator.add_range(old_end..range.end)?;
+            } else if range.end < old_end {
+                page_allocator.remove_range(range.end..old_end);
+            }
+        }
+
+        drop(page_allocator);
+
+        let mut backing_files = self.backing_files.write().unwrap();
+
+        if let Some(backing_file) = backing_files.get_mut(&guest_address) {
+            backing_file.size = size as u64;
+        }
+
+        Ok(())
+    }
+
+    /// Changes the protection flags of the guest physical memory. This also resets the protection
+    /// flags of any sub-ranges changed through [`Vm::protect_physical_memory_range`].
     pub fn protect_physical_memory(
         &mut self,
         guest_address: u64,
@@ -251,20 +1741,364 @@ impl<'a> Vm<'a> {
             .protect_physical_memory(guest_address, protection)
     }
 
-    /// Reads the bytes starting at the guest address into the given bytes buffer.
+    /// Changes the protection flags of the given page-aligned range of guest physical memory,
+    /// which must lie within a single region, e.g. to write-protect individual pages to track
+    /// or intercept writes to them. [`Vm::regions`] keeps reporting the protection flags of the
+    /// region as a whole. Moving the region, or resizing it on Linux, resets the protection flags
+    /// of its sub-ranges.
+    ///
+    /// On Linux, KVM only supports making a memory slot read-only as a whole, so the region is
+    /// split into multiple memory slots, and the protection flags other than
+    /// [`ProtectionFlags::WRITE`] are ignored. This is not supported on FreeBSD. Returns
+    /// [`Error::InvalidArgument`] for [`ProtectionFlags::ROM`], which only applies to regions as
+    /// a whole.
+    pub fn protect_physical_memory_range(
+        &mut self,
+        range: Range<u64>,
+        protection: ProtectionFlags,
+    ) -> Result<(), Error> {
+        if range.start == range.end || protection.contains(ProtectionFlags::ROM) {
+            return Err(Error::InvalidArgument);
+        }
+
+        check_page_aligned(&range)?;
+
+        self.inner
+            .write()
+            .unwrap()
+            .protect_physical_memory_range(range, protection)
+    }
+
+    /// Reads the bytes starting at the guest address into the given bytes buffer, where the read
+    /// continues across adjacent regions. Returns the number of bytes read, which is less than
+    /// the size of the buffer if the read reaches guest physical memory that is not mapped.
+    /// Returns [`Error::InvalidGuestAddress`] if the guest address itself is not mapped.
     pub fn read_physical_memory(
         &self,
         bytes: &mut [u8],
         guest_address: u64,
+    ) -> Result<usize, Error> {
+        match read_across_regions(&self.inner.read().unwrap(), bytes, guest_address) {
+            Err(Error::UnmappedGuestAddress(_)) => Err(Error::InvalidGuestAddress),
+            result => result,
+        }
+    }
+
+    /// Writes the bytes from the given bytes buffer to the bytes starting at guest address, where
+    /// the write continues across adjacent regions. Returns the number of bytes written, which is
+    /// less than the size of the buffer if the write reaches guest physical memory that is not
+    /// mapped. Returns [`Error::InvalidGuestAddress`] if the guest address itself is not mapped.
+    pub fn write_physical_memory(
+        &mut self,
+        guest_address: u64,
+        bytes: &[u8],
+    ) -> Result<usize, Error> {
+        match write_across_regions(&mut self.inner.write().unwrap(), guest_address, bytes) {
+            Err(Error::UnmappedGuestAddress(_)) => Err(Error::InvalidGuestAddress),
+            result => result,
+        }
+    }
+
+    /// Helper function to write the bytes from the given bytes buffer to guest physical memory,
+    /// where a partial write means that the bytes are not fully backed by guest memory.
+    pub(crate) fn write_all_physical_memory(
+        &mut self,
+        guest_address: u64,
+        bytes: &[u8],
+    ) -> Result<(), Error> {
+        let size = write_across_regions(&mut self.inner.write().unwrap(), guest_address, bytes)?;
+
+        if size != bytes.len() {
+            return Err(Error::UnmappedGuestAddress(guest_address + size as u64));
+        }
+
+        Ok(())
+    }
+
+    /// Helper function to fill the given bytes buffer from guest physical memory, where a partial
+    /// read means that the bytes are not fully backed by guest memory.
+    fn read_exact_physical_memory(
+        &self,
+        bytes: &mut [u8],
+        guest_address: u64,
+    ) -> Result<(), Error> {
+        let size = read_across_regions(&self.inner.read().unwrap(), bytes, guest_address)?;
+
+        if size != bytes.len() {
+            return Err(Error::UnmappedGuestAddress(guest_address + size as u64));
+        }
+
+        Ok(())
+    }
+
+    /// Reads the object of type `T` at the given guest address. The guest address does not have
+    /// to be aligned, and the object may span adjacent regions, but all of it must be mapped. See
+    /// [`crate::bytes`] for details.
+    pub fn read_obj<T: FromBytes>(&self, guest_address: u64) -> Result<T, Error> {
+        let mut bytes = vec![0u8; std::mem::size_of::<T>()];
+
+        self.read_exact_physical_memory(&mut bytes, guest_address)?;
+
+        Ok(bytes::from_bytes(&bytes))
+    }
+
+    /// Writes the given object to the given guest address. The guest address does not have to be
+    /// aligned, and the object may span adjacent regions, but all of it must be mapped. See
+    /// [`crate::bytes`] for details.
+    pub fn write_obj<T: AsBytes>(&mut self, guest_address: u64, value: &T) -> Result<(), Error> {
+        self.write_all_physical_memory(guest_address, bytes::as_bytes(value))
+    }
+
+    /// Reads the NUL-terminated string at the given guest address, where the string including its
+    /// NUL terminator is at most `max_len` bytes long. Returns [`Error::InvalidArgument`] if no
+    /// NUL terminator is found within `max_len` bytes.
+    pub fn read_cstr(&self, guest_address: u64, max_len: usize) -> Result<CString, Error> {
+        let mut string = vec![];
+        let mut chunk = [0u8; 64];
+
+        while string.len() < max_len {
+            let size = chunk.len().min(max_len - string.len());
+            let address = guest_address + string.len() as u64;
+            let size = self.read_physical_memory(&mut chunk[..size], address)?;
+
+            if size == 0 {
+                return Err(Error::InvalidGuestAddress);
+            }
+
+            if let Some(end) = chunk[..size].iter().position(|&byte| byte == 0) {
+                string.extend_from_slice(&chunk[..end]);
+
+                // The string does not contain any interior NUL bytes.
+                return Ok(CString::new(string).unwrap());
+            }
+
+            string.extend_from_slice(&chunk[..size]);
+        }
+
+        Err(Error::InvalidArgument)
+    }
+
+    /// Writes the given string including its NUL terminator to the given guest address.
+    pub fn write_cstr(&mut self, guest_address: u64, string: &CStr) -> Result<(), Error> {
+        self.write_all_physical_memory(guest_address, string.to_bytes_with_nul())
+    }
+
+    /// Returns a [`VolatileSlice`] for direct access to the host memory backing the given range of
+    /// guest physical memory. Unlike [`Vm::read_physical_memory`], the range must be backed by a
+    /// single region, as adjacent regions are not contiguous in host memory, and returns
+    /// [`Error::InvalidGuestAddress`] otherwise. The slice holds on to the memory map of the VM,
+    /// so mapping, unmapping or writing guest physical memory through the [`Vm`] blocks until the
+    /// slice is dropped. Use the slice itself to write to the guest physical memory instead.
+    ///
+    /// This is not supported on FreeBSD, nor for protected guests.
+    pub fn get_slice(&self, guest_address: u64, len: usize) -> Result<VolatileSlice<'_>, Error> {
+        // Hold on to the lock to prevent the memory from being unmapped while the slice is alive.
+        let inner = self.inner.read().unwrap();
+        let ptr = inner.host_address(guest_address, len)?;
+
+        Ok(unsafe { VolatileSlice::new(ptr, len, Some(inner)) })
+    }
+
+    /// Searches the guest physical memory within the given range for the given pattern and
+    /// returns the guest addresses of the matches in ascending order. The regions are copied out
+    /// of their host mappings in bounded chunks through volatile reads, as the guest may modify
+    /// its memory concurrently, and any guest physical memory within the range that is not mapped
+    /// is skipped. Matches may overlap, but do not cross the
+    /// end of a region. Returns [`Error::InvalidArgument`] if the pattern is empty.
+    ///
+    /// This is not supported on FreeBSD, nor for protected guests.
+    pub fn scan(&self, range: Range<u64>, pattern: &Pattern) -> Result<Vec<u64>, Error> {
+        if pattern.is_empty() {
+            return Err(Error::InvalidArgument);
+        }
+
+        // Hold on to the lock to prevent the memory from being unmapped during the search.
+        let inner = self.inner.read().unwrap();
+        let mut matches = vec![];
+
+        for (region, _, _) in inner.regions()? {
+            let start = region.start.max(range.start);
+            let end = region.end.min(range.end);
+
+            if start >= end {
+                continue;
+            }
+
+            let size = (end - start) as usize;
+            let ptr = inner.host_address(start, size)?;
+
+            // The lock is held for the lifetime of the slice.
+            let slice = unsafe { VolatileSlice::new(ptr, size, None) };
+
+            matches.extend(
+                pattern
+                    .find_in_slice(&slice)
+                    .into_iter()
+                    .map(|offset| start + offset as u64),
+            );
+        }
+
+        Ok(matches)
+    }
+
+    /// Helper function to call the given function with a reference to the atomic at the given
+    /// guest address. The guest address must be aligned to the size of the atomic.
+    fn with_atomic<T: Atomic, R>(
+        &self,
+        guest_address: u64,
+        f: impl FnOnce(&T::Atomic) -> R,
+    ) -> Result<R, Error> {
+        let size = std::mem::size_of::<T>();
+
+        // The host mappings are page aligned, so an aligned guest address is also aligned in the
+        // host mapping.
+        if guest_address % size as u64 != 0 {
+            return Err(Error::MisalignedGuestAddress);
+        }
+
+        // Hold on to the lock to prevent the memory from being unmapped during the access.
+        let inner = self.inner.read().unwrap();
+        let ptr = inner.host_address(guest_address, size)?;
+
+        let atomic = unsafe { &*(ptr as *const T::Atomic) };
+
+        Ok(f(atomic))
+    }
+
+    /// Atomically loads the integer at the given guest address with the given ordering. The guest
+    /// address must be aligned to the size of the integer, or this returns
+    /// [`Error::MisalignedGuestAddress`].
+    pub fn load_atomic<T: Atomic>(&self, guest_address: u64, order: Ordering) -> Result<T, Error> {
+        self.with_atomic::<T, _>(guest_address, |atomic| T::load(atomic, order))
+    }
+
+    /// Atomically stores the integer at the given guest address with the given ordering. The
+    /// guest address must be aligned to the size of the integer, or this returns
+    /// [`Error::MisalignedGuestAddress`].
+    pub fn store_atomic<T: Atomic>(
+        &self,
+        guest_address: u64,
+        value: T,
+        order: Ordering,
+    ) -> Result<(), Error> {
+        self.with_atomic::<T, _>(guest_address, |atomic| T::store(atomic, value, order))
+    }
+
+    /// Atomically replaces the integer at the given guest address with `new` if it is equal to
+    /// `current`. Returns `Ok(current)` on success, or `Err` with the integer in guest memory
+    /// otherwise. The orderings have the same meaning as for
+    /// [`AtomicU32::compare_exchange`](std::sync::atomic::AtomicU32::compare_exchange). The guest
+    /// address must be aligned to the size of the integer, or this returns
+    /// [`Error::MisalignedGuestAddress`].
+    pub fn compare_exchange_atomic<T: Atomic>(
+        &self,
+        guest_address: u64,
+        current: T,
+        new: T,
+        success: Ordering,
+        failure: Ordering,
+    ) -> Result<Result<T, T>, Error> {
+        self.with_atomic::<T, _>(guest_address, |atomic| {
+            T::compare_exchange(atomic, current, new, success, failure)
+        })
+    }
+
+    /// Atomically loads the 32-bit value at the given guest address with acquire ordering. The
+    /// guest address must be 4-byte aligned.
+    pub fn load_u32(&self, guest_address: u64) -> Result<u32, Error> {
+        self.load_atomic(guest_address, Ordering::Acquire)
+    }
+
+    /// Atomically stores the 32-bit value at the given guest address with release ordering. The
+    /// guest address must be 4-byte aligned.
+    pub fn store_u32(&self, guest_address: u64, value: u32) -> Result<(), Error> {
+        self.store_atomic(guest_address, value, Ordering::Release)
+    }
+
+    /// Atomically replaces the 32-bit value at the given guest address with `new` if it is equal
+    /// to `expected`. Returns `Ok(expected)` on success, or `Err(current)` with the current value
+    /// otherwise. This uses acquire-release ordering on success and acquire ordering on failure.
+    /// The guest address must be 4-byte aligned.
+    pub fn compare_exchange_u32(
+        &self,
+        guest_address: u64,
+        expected: u32,
+        new: u32,
+    ) -> Result<Result<u32, u32>, Error> {
+        self.compare_exchange_atomic(
+            guest_address,
+            expected,
+            new,
+            Ordering::AcqRel,
+            Ordering::Acquire,
+        )
+    }
+
+    /// Atomically loads the 64-bit value at the given guest address with acquire ordering. The
+    /// guest address must be 8-byte aligned.
+    pub fn load_u64(&self, guest_address: u64) -> Result<u64, Error> {
+        self.load_atomic(guest_address, Ordering::Acquire)
+    }
+
+    /// Atomically stores the 64-bit value at the given guest address with release ordering. The
+    /// guest address must be 8-byte aligned.
+    pub fn store_u64(&self, guest_address: u64, value: u64) -> Result<(), Error> {
+        self.store_atomic(guest_address, value, Ordering::Release)
+    }
+
+    /// Atomically replaces the 64-bit value at the given guest address with `new` if it is equal
+    /// to `expected`. Returns `Ok(expected)` on success, or `Err(current)` with the current value
+    /// otherwise. This uses acquire-release ordering on success and acquire ordering on failure.
+    /// The guest address must be 8-byte aligned.
+    pub fn compare_exchange_u64(
+        &self,
+        guest_address: u64,
+        expected: u64,
+        new: u64,
+    ) -> Result<Result<u64, u64>, Error> {
+        self.compare_exchange_atomic(
+            guest_address,
+            expected,
+            new,
+            Ordering::AcqRel,
+            Ordering::Acquire,
+        )
+    }
+}
+
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+impl<'a> Vm<'a> {
+    /// Allocates SMRAM at the given guest address with the given size, i.e. guest physical memory
+    /// that is only visible to the virtual CPUs while they are in System Management Mode (SMM).
+    /// The regular guest physical memory remains visible in SMM, unless it overlaps with SMRAM.
+    ///
+    /// This is only supported on Linux, as the other hypervisor APIs do not support SMM.
+    pub fn allocate_smram(
+        &mut self,
+        guest_address: u64,
+        size: usize,
+    ) -> Result<(), Error> {
+        self.inner
+            .write()
+            .unwrap()
+            .allocate_smram(guest_address, size)
+    }
+
+    /// Reads the bytes starting at the guest address in SMRAM into the given bytes buffer.
+    pub fn read_smram(
+        &self,
+        bytes: &mut [u8],
+        guest_address: u64,
     ) -> Result<usize, Error> {
         self.inner
             .read()
             .unwrap()
-            .read_physical_memory(bytes, guest_address)
+            .read_smram(bytes, guest_address)
     }
 
-    /// Writes the bytes from the given bytes buffer to the bytes starting at guest address.
-    pub fn write_physical_memory(
+    /// Writes the bytes from the given bytes buffer to the bytes starting at the guest address in
+    /// SMRAM, e.g. to install an SMI handler.
+    pub fn write_smram(
         &mut self,
         guest_address: u64,
         bytes: &[u8],
@@ -272,7 +2106,185 @@ impl<'a> Vm<'a> {
         self.inner
             .write()
             .unwrap()
-            .write_physical_memory(guest_address, bytes)
+            .write_smram(guest_address, bytes)
+    }
+
+    /// Posts the given message to the synthetic interrupt source `sint` of the virtual CPU with
+    /// the given vCPU ID. The synthetic interrupt controller must have been enabled through
+    /// [`VmBuilder::with_synthetic_interrupts`] and the guest must have set up its message page.
+    ///
+    /// This is only supported on Microsoft Windows.
+    pub fn post_synthetic_message(
+        &self,
+        vcpu_id: usize,
+        sint: u8,
+        message: &SyntheticMessage,
+    ) -> Result<(), Error> {
+        self.inner
+            .read()
+            .unwrap()
+            .post_synthetic_message(vcpu_id, sint, message)
+    }
+
+    /// Signals the event flag `flag` of the synthetic interrupt source `sint` of the virtual CPU
+    /// with the given vCPU ID. Returns `true` if the flag was newly set, or `false` if it was
+    /// already set.
+    ///
+    /// This is only supported on Microsoft Windows.
+    pub fn signal_synthetic_event(
+        &self,
+        vcpu_id: usize,
+        sint: u8,
+        flag: u16,
+    ) -> Result<bool, Error> {
+        self.inner
+            .read()
+            .unwrap()
+            .signal_synthetic_event(vcpu_id, sint, flag)
+    }
+
+    /// Selects the MSRs whose accesses exit to user space as [`crate::ExitReason::MsrRead`] and
+    /// [`crate::ExitReason::MsrWrite`], where the given policy applies to the MSRs in the given
+    /// ranges and the opposite policy applies to any other MSR. This replaces any previous
+    /// filter.
+    ///
+    /// On Linux, this relies on the MSR filtering of KVM, i.e. `KVM_CAP_X86_MSR_FILTER`. On Mac
+    /// OS X, the Hypervisor Framework can only handle a few MSRs natively, so any other MSR
+    /// always exits, and the MSRs required for long mode are always handled natively. This is
+    /// not supported on Microsoft Windows and FreeBSD, as they only exit on the MSRs that the
+    /// hypervisor does not handle itself, see [`VmBuilder::with_msr_exits`].
+    pub fn set_msr_filter(
+        &mut self,
+        ranges: &[Range<u32>],
+        policy: MsrPolicy,
+    ) -> Result<(), Error> {
+        self.inner
+            .write()
+            .unwrap()
+            .set_msr_filter(ranges, policy)
+    }
+
+    /// Raises or lowers the interrupt line with the given global system interrupt (GSI) number,
+    /// where level-triggered devices keep the line raised until the guest acknowledges the
+    /// interrupt, and edge-triggered devices raise and lower the line. The in-kernel interrupt
+    /// controllers must have been created through [`VmBuilder::with_in_kernel_irqchip`].
+    /// Returns [`Error::NotImplemented`] if the hypervisor does not emulate an I/O APIC.
+    ///
+    /// The GSIs below 16 are the ISA interrupts, which are routed to both the PIC and the I/O
+    /// APIC. This is not supported on Microsoft Windows and Mac OS X.
+    pub fn set_irq_line(&self, gsi: u32, level: bool) -> Result<(), Error> {
+        self.inner
+            .read()
+            .unwrap()
+            .set_irq_line(gsi, level)
+    }
+
+    /// Encrypts the guest physical memory at the given guest address with the given size in
+    /// place and adds it to the launch measurement of the SEV guest, e.g. after writing the
+    /// firmware. The range must not cross the end of the region and the guest address and size
+    /// must be aligned to 16 bytes. See [`crate::confidential`] for details.
+    ///
+    /// This is only supported on Linux.
+    pub fn sev_launch_update_data(
+        &self,
+        guest_address: u64,
+        size: usize,
+    ) -> Result<(), Error> {
+        self.inner
+            .read()
+            .unwrap()
+            .sev_launch_update_data(guest_address, size)
+    }
+
+    /// Encrypts the register state of all the virtual CPUs of the SEV-ES guest and adds it to
+    /// the launch measurement. The register state can no longer be accessed by the host
+    /// afterwards.
+    ///
+    /// This is only supported on Linux.
+    pub fn sev_launch_update_vmsa(&self) -> Result<(), Error> {
+        self.inner
+            .read()
+            .unwrap()
+            .sev_launch_update_vmsa()
+    }
+
+    /// Returns the launch measurement of the SEV guest, which the guest owner uses to verify the
+    /// initial state of the guest.
+    ///
+    /// This is only supported on Linux.
+    pub fn sev_launch_measure(&self) -> Result<LaunchMeasurement, Error> {
+        self.inner
+            .read()
+            .unwrap()
+            .sev_launch_measure()
+    }
+
+    /// Completes the launch of the SEV guest, after which the virtual CPUs can run.
+    ///
+    /// This is only supported on Linux.
+    pub fn sev_launch_finish(&self) -> Result<(), Error> {
+        self.inner
+            .read()
+            .unwrap()
+            .sev_launch_finish()
+    }
+}
+
+#[cfg(target_arch = "aarch64")]
+impl<'a> Vm<'a> {
+    /// Raises or lowers the line of the shared peripheral interrupt with the given interrupt ID,
+    /// e.g. for level-triggered devices. The in-kernel GIC must have been created through
+    /// [`VmBuilder::with_gic`]. Returns [`Error::InvalidArgument`] if the interrupt ID is not an
+    /// SPI.
+    pub fn set_irq_level(&self, intid: u32, level: bool) -> Result<(), Error> {
+        if intid < GIC_SPI_BASE {
+            return Err(Error::InvalidArgument);
+        }
+
+        self.inner
+            .read()
+            .unwrap()
+            .set_irq_level(intid, level)
+    }
+
+    /// Injects the shared peripheral interrupt with the given interrupt ID as an edge, i.e. by
+    /// raising and lowering its line, see [`Vm::set_irq_level`].
+    pub fn inject_irq(&self, intid: u32) -> Result<(), Error> {
+        self.set_irq_level(intid, true)?;
+        self.set_irq_level(intid, false)
+    }
+}
+
+#[cfg(feature = "xen")]
+impl<'a> Vm<'a> {
+    /// Sets the guest frame number of the shared info page, as registered by the guest through
+    /// the `XENMEM_add_to_physmap` hypercall.
+    pub fn set_xen_shared_info(&mut self, gfn: u64) -> Result<(), Error> {
+        self.inner
+            .write()
+            .unwrap()
+            .set_xen_shared_info(gfn)
+    }
+
+    /// Sets the vector used to deliver event channel upcalls to the guest, as registered by the
+    /// guest through the `HVMOP_set_param` hypercall with `HVM_PARAM_CALLBACK_IRQ`.
+    pub fn set_xen_upcall_vector(&mut self, vector: u8) -> Result<(), Error> {
+        self.inner
+            .write()
+            .unwrap()
+            .set_xen_upcall_vector(vector)
+    }
+
+    /// Raises the given event channel port on the virtual CPU with the given vCPU ID.
+    pub fn send_xen_event(
+        &self,
+        port: u32,
+        vcpu_id: usize,
+    ) -> Result<(), Error> {
+        self.inner
+            .read()
+            .unwrap()
+            .send_xen_event(port, vcpu_id)
     }
 }
 
@@ -320,3 +2332,80 @@ impl<'a> page_walker::PageTableMapper<u64, Error> for Vm<'a> {
             .free_page(phys_addr);
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn allocate_all(allocator: &mut PageAllocator) -> Vec<u64> {
+        let mut pages = vec![];
+
+        while let Some(page) = allocator.alloc_page() {
+            pages.push(page);
+        }
+
+        pages.sort_unstable();
+        pages
+    }
+
+    #[test]
+    fn page_allocator_remove_range() {
+        let page_size = MmapOptions::page_size().1 as u64;
+        let mut allocator = PageAllocator::new();
+        allocator.add_range(0..4 * page_size).unwrap();
+
+        let page = allocator.alloc_page().unwrap();
+        allocator.remove_range(2 * page_size..4 * page_size);
+        allocator.free_page(page);
+
+        assert_eq!(allocate_all(&mut allocator), vec![0, page_size]);
+        assert!(!allocator.contains(2 * page_size));
+    }
+
+    #[test]
+    fn page_allocator_reserve_range() {
+        let page_size = MmapOptions::page_size().1 as u64;
+        let mut allocator = PageAllocator::new();
+        allocator.add_range(0..3 * page_size).unwrap();
+        allocator.reserve_range(page_size..3 * page_size);
+
+        assert_eq!(allocate_all(&mut allocator), vec![0]);
+        assert!(allocator.contains(page_size));
+
+        allocator.free_page(2 * page_size);
+
+        assert_eq!(allocate_all(&mut allocator), vec![2 * page_size]);
+    }
+
+    #[test]
+    fn page_allocator_move_range() {
+        let page_size = MmapOptions::page_size().1 as u64;
+        let mut allocator = PageAllocator::new();
+        allocator.add_range(0..2 * page_size).unwrap();
+        allocator.add_range(2 * page_size..3 * page_size).unwrap();
+
+        let page = allocator.alloc_page().unwrap();
+        allocator.move_range(0..3 * page_size, 0x10_0000);
+
+        assert!(!allocator.contains(0));
+        assert!(allocator.contains(0x10_0000 + page));
+
+        allocator.free_page(0x10_0000 + page);
+
+        assert_eq!(
+            allocate_all(&mut allocator),
+            vec![0x10_0000, 0x10_0000 + page_size, 0x10_0000 + 2 * page_size],
+        );
+    }
+
+    #[test]
+    fn page_allocator_grow() {
+        let page_size = MmapOptions::page_size().1 as u64;
+        let mut allocator = PageAllocator::new();
+        allocator.add_range(0..page_size).unwrap();
+        allocator.remove_range(0..page_size);
+        allocator.add_range(0..2 * page_size).unwrap();
+
+        assert_eq!(allocate_all(&mut allocator), vec![0, page_size]);
+    }
+}
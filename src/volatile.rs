@@ -0,0 +1,173 @@
+//! This module provides the [`VolatileSlice`] struct, which gives direct access to the host
+//! memory backing a range of guest physical memory, see [`crate::Vm::get_slice`]. Unlike
+//! [`crate::Vm::read_physical_memory`] and [`crate::Vm::write_physical_memory`], this does not
+//! require copying the bytes through an intermediate buffer, e.g. when emulating DMA into
+//! multi-megabyte guest buffers.
+//!
+//! As the guest may access the memory concurrently through its virtual CPUs, the memory is never
+//! exposed as a Rust slice. Instead, all accesses are volatile, such that the compiler does not
+//! elide or merge them.
+
+use crate::bytes::{self, AsBytes, FromBytes};
+use crate::error::Error;
+use crate::platform;
+use std::marker::PhantomData;
+use std::sync::RwLockReadGuard;
+
+/// A range of guest physical memory that is accessed directly through the host memory backing
+/// it, see the [module-level documentation](self).
+pub struct VolatileSlice<'a> {
+    /// The host address of the start of the slice.
+    ptr: *mut u8,
+    /// The size of the slice in bytes.
+    len: usize,
+    /// The guard that prevents the guest physical memory from being unmapped while the slice is
+    /// in use. This is `None` for the slices obtained through [`VolatileSlice::subslice`], as
+    /// these borrow the slice holding the guard instead.
+    _guard: Option<RwLockReadGuard<'a, platform::Vm>>,
+    _marker: PhantomData<&'a mut [u8]>,
+}
+
+impl<'a> VolatileSlice<'a> {
+    /// Creates a volatile slice of the given size at the given host address.
+    ///
+    /// # Safety
+    ///
+    /// The host memory must remain valid for as long as the guard or the lifetime `'a` is alive.
+    pub(crate) unsafe fn new(
+        ptr: *mut u8,
+        len: usize,
+        guard: Option<RwLockReadGuard<'a, platform::Vm>>,
+    ) -> Self {
+        Self {
+            ptr,
+            len,
+            _guard: guard,
+            _marker: PhantomData,
+        }
+    }
+
+    /// Returns the size of the slice in bytes.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Returns `true` if the slice is empty.
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Returns the host address of the start of the slice, e.g. to pass the guest memory to an
+    /// I/O API directly. The host memory is only valid for as long as the slice is alive.
+    pub fn as_ptr(&self) -> *mut u8 {
+        self.ptr
+    }
+
+    /// Returns the slice of the given size at the given offset into this slice. Returns
+    /// [`Error::InvalidArgument`] if the subslice does not fit within this slice.
+    pub fn subslice(&self, offset: usize, len: usize) -> Result<VolatileSlice<'_>, Error> {
+        self.check_range(offset, len)?;
+
+        Ok(unsafe { VolatileSlice::new(self.ptr.add(offset), len, None) })
+    }
+
+    /// Copies the bytes starting at the given offset into the given bytes buffer. Returns the
+    /// number of bytes copied, which is less than the size of the buffer if the end of the slice
+    /// is reached.
+    pub fn copy_to(&self, offset: usize, bytes: &mut [u8]) -> Result<usize, Error> {
+        self.check_range(offset, 0)?;
+
+        let size = bytes.len().min(self.len - offset);
+
+        unsafe {
+            copy_volatile(bytes.as_mut_ptr(), self.ptr.add(offset), size);
+        }
+
+        Ok(size)
+    }
+
+    /// Copies the bytes from the given bytes buffer to the bytes starting at the given offset.
+    /// Returns the number of bytes copied, which is less than the size of the buffer if the end
+    /// of the slice is reached.
+    pub fn copy_from(&self, offset: usize, bytes: &[u8]) -> Result<usize, Error> {
+        self.check_range(offset, 0)?;
+
+        let size = bytes.len().min(self.len - offset);
+
+        unsafe {
+            copy_volatile(self.ptr.add(offset), bytes.as_ptr(), size);
+        }
+
+        Ok(size)
+    }
+
+    /// Sets the given number of bytes starting at the given offset to the given value. Returns
+    /// [`Error::InvalidArgument`] if the bytes do not fit within the slice.
+    pub fn fill(&self, offset: usize, len: usize, value: u8) -> Result<(), Error> {
+        self.check_range(offset, len)?;
+
+        for index in offset..offset + len {
+            unsafe {
+                std::ptr::write_volatile(self.ptr.add(index), value);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Reads the object of type `T` at the given offset, which does not have to be aligned.
+    /// Returns [`Error::InvalidArgument`] if the object does not fit within the slice.
+    pub fn read_obj<T: FromBytes>(&self, offset: usize) -> Result<T, Error> {
+        let mut bytes = vec![0u8; std::mem::size_of::<T>()];
+
+        self.check_range(offset, bytes.len())?;
+        self.copy_to(offset, &mut bytes)?;
+
+        Ok(bytes::from_bytes(&bytes))
+    }
+
+    /// Writes the given object to the given offset, which does not have to be aligned. Returns
+    /// [`Error::InvalidArgument`] if the object does not fit within the slice.
+    pub fn write_obj<T: AsBytes>(&self, offset: usize, value: &T) -> Result<(), Error> {
+        let bytes = bytes::as_bytes(value);
+
+        self.check_range(offset, bytes.len())?;
+        self.copy_from(offset, bytes)?;
+
+        Ok(())
+    }
+
+    /// Helper function to check that the given range fits within the slice.
+    fn check_range(&self, offset: usize, len: usize) -> Result<(), Error> {
+        match offset.checked_add(len) {
+            Some(end) if end <= self.len => Ok(()),
+            _ => Err(Error::InvalidArgument),
+        }
+    }
+}
+
+/// Helper function to copy the given number of bytes using volatile accesses, where the bytes
+/// are copied eight at a time if both the source and the destination are suitably aligned.
+unsafe fn copy_volatile(dst: *mut u8, src: *const u8, size: usize) {
+    let mut offset = 0;
+    let word_size = std::mem::size_of::<u64>();
+
+    if (dst as usize) % word_size == (src as usize) % word_size {
+        // Copy the bytes up to the first aligned address.
+        while offset < size && (dst as usize + offset) % word_size != 0 {
+            std::ptr::write_volatile(dst.add(offset), std::ptr::read_volatile(src.add(offset)));
+            offset += 1;
+        }
+
+        while offset + word_size <= size {
+            let word = std::ptr::read_volatile(src.add(offset) as *const u64);
+            std::ptr::write_volatile(dst.add(offset) as *mut u64, word);
+            offset += word_size;
+        }
+    }
+
+    while offset < size {
+        std::ptr::write_volatile(dst.add(offset), std::ptr::read_volatile(src.add(offset)));
+        offset += 1;
+    }
+}
@@ -0,0 +1,155 @@
+//! This module provides sockets between the host and the guest in the style of AF_VSOCK, such
+//! that agents running inside the guest can communicate with the VMM without any networking. The
+//! VMM obtains a [`Vsock`] for the VM, through which it listens for connections from the guest
+//! with [`Vsock::listen`] and connects to the listeners of the guest with [`Vsock::connect`].
+//! Either way, the connection is a [`VsockStream`] that implements [`Read`] and [`Write`], where
+//! the services on either side are identified by their port.
+//!
+//! The sockets are implemented as follows:
+//!  * On Linux, Mac OS X and FreeBSD, the VMM emulates a virtio-vsock device through
+//!    [`Vsock::attach`], see [`crate::virtio::vsock`]. The guest uses its virtio-vsock driver, e.g.
+//!    `vmw_vsock_virtio_transport` on Linux, and connects to [`VMADDR_CID_HOST`]. The host side of
+//!    the connections lives in the VMM rather than in the host operating system, so the listeners
+//!    are only visible to the VMM.
+//!  * On Microsoft Windows, the sockets are Hyper-V sockets to the VM with the given ID, see
+//!    [`Vsock::hyperv`]. The ports are mapped to the service IDs of the Hyper-V sockets through the
+//!    template that the `hv_sock` driver of Linux guests uses as well, i.e.
+//!    `xxxxxxxx-facb-11e6-bd58-64006a7986d3`. The services have to be registered under
+//!    `HKLM\SOFTWARE\Microsoft\Windows NT\CurrentVersion\Virtualization\GuestCommunicationServices`
+//!    on the host. This relies on the VMBus of Hyper-V, and is therefore not available for the
+//!    partitions of the Windows Hypervisor Platform.
+
+#[cfg(not(target_os = "windows"))]
+use crate::virtio::vsock as backend;
+#[cfg(target_os = "windows")]
+use crate::platform::vsock as backend;
+#[cfg(not(target_os = "windows"))]
+use crate::vm::Vm;
+use crate::error::Error;
+use std::io::{self, Read, Write};
+use std::net::Shutdown;
+
+#[cfg(not(target_os = "windows"))]
+pub use crate::virtio::vsock::VMADDR_CID_HOST;
+
+/// The host side of the sockets of a VM, see the [module-level documentation](self).
+#[derive(Clone)]
+pub struct Vsock {
+    /// The platform-specific implementation of the sockets.
+    inner: backend::Endpoint,
+}
+
+impl Vsock {
+    /// Creates a virtio-vsock device for the guest with the given context ID (CID), and registers
+    /// its [`crate::virtio::MmioTransport`] on the VM for [`crate::virtio::VIRTIO_MMIO_SIZE`]
+    /// bytes starting at the given guest physical address, where the given callback drives the
+    /// interrupt line of the device, e.g. by calling [`crate::Vm::set_irq_line`]. The device
+    /// still has to be described to the guest, see [`crate::virtio`]. Returns
+    /// [`Error::InvalidArgument`] if the CID is reserved, i.e. below 3, or if the range overlaps
+    /// with another device.
+    ///
+    /// This is only supported on Linux, Mac OS X and FreeBSD.
+    #[cfg(not(target_os = "windows"))]
+    pub fn attach<F>(vm: &Vm, guest_cid: u64, address: u64, irq: F) -> Result<Self, Error>
+    where
+        F: FnMut(bool) -> Result<(), Error> + Send + 'static,
+    {
+        Ok(Self {
+            inner: backend::attach(vm, guest_cid, address, irq)?,
+        })
+    }
+
+    /// Uses the Hyper-V sockets of the VM with the given ID, where the ID is the GUID of the VM
+    /// as a 128-bit integer, e.g. `0x12345678_9abc_def0_1234_56789abcdef0` for
+    /// `12345678-9abc-def0-1234-56789abcdef0`.
+    ///
+    /// This is only supported on Microsoft Windows.
+    #[cfg(target_os = "windows")]
+    pub fn hyperv(vm_id: u128) -> Result<Self, Error> {
+        Ok(Self {
+            inner: backend::Endpoint::new(vm_id),
+        })
+    }
+
+    /// Listens for connections from the guest on the given port. Returns [`Error::Io`] if there
+    /// is a listener on the port already.
+    pub fn listen(&self, port: u32) -> Result<VsockListener, Error> {
+        Ok(VsockListener {
+            inner: self.inner.listen(port)?,
+        })
+    }
+
+    /// Connects to the listener of the guest on the given port. Returns [`Error::Io`] if the
+    /// guest refused the connection or did not respond in time.
+    pub fn connect(&self, port: u32) -> Result<VsockStream, Error> {
+        Ok(VsockStream {
+            inner: self.inner.connect(port)?,
+        })
+    }
+}
+
+/// A listener for the connections from the guest, see [`Vsock::listen`]. The connections that
+/// have not been accepted yet are refused once the listener is dropped.
+pub struct VsockListener {
+    /// The platform-specific implementation of the listener.
+    inner: backend::Listener,
+}
+
+impl VsockListener {
+    /// Returns the port of the listener.
+    pub fn port(&self) -> u32 {
+        self.inner.port()
+    }
+
+    /// Waits for the next connection from the guest.
+    pub fn accept(&self) -> Result<VsockStream, Error> {
+        Ok(VsockStream {
+            inner: self.inner.accept()?,
+        })
+    }
+}
+
+/// A connection between the VMM and the guest, see [`Vsock::listen`] and [`Vsock::connect`]. The
+/// connection is shut down once the stream is dropped.
+pub struct VsockStream {
+    /// The platform-specific implementation of the stream.
+    inner: backend::Stream,
+}
+
+impl VsockStream {
+    /// Returns the port of the service, i.e. the port of the listener on the host for the
+    /// connections accepted through [`VsockListener::accept`], or the port of the listener on the
+    /// guest for the connections created through [`Vsock::connect`].
+    pub fn port(&self) -> u32 {
+        self.inner.port()
+    }
+
+    /// Shuts down the given directions of the connection.
+    pub fn shutdown(&self, how: Shutdown) -> Result<(), Error> {
+        self.inner.shutdown(how)
+    }
+}
+
+/// Helper function to convert the given error into an [`io::Error`] for [`Read`] and [`Write`].
+fn into_io_error(error: Error) -> io::Error {
+    match error {
+        Error::Io(error) => error,
+        error => io::Error::other(error.to_string()),
+    }
+}
+
+impl Read for VsockStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.inner.read(buf).map_err(into_io_error)
+    }
+}
+
+impl Write for VsockStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.inner.write(buf).map_err(into_io_error)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
@@ -0,0 +1,50 @@
+//! This module provides the types to run Xen HVM guests, i.e. guests that expect to run on top of
+//! the Xen hypervisor and use its hypercall interface, event channels and shared info page. This
+//! module is only available with the `xen` feature.
+//!
+//! Xen support is enabled through [`crate::VmBuilder::with_xen`]. Once enabled, a write of the
+//! guest physical address of a page to the hypercall MSR (see [`XenConfig::hypercall_msr`])
+//! causes the hypervisor to populate the hypercall page. Hypercalls made through that page exit
+//! with [`crate::ExitReason::XenHypercall`], which must be completed by calling
+//! [`crate::Vcpu::complete_xen_hypercall`] with the return value before resuming the virtual CPU.
+//!
+//! The host can deliver events to the guest through [`crate::Vm::send_xen_event`], once the guest
+//! has registered its shared info page and vCPU info through the corresponding hypercalls and the
+//! VMM has forwarded them through [`crate::Vm::set_xen_shared_info`] and
+//! [`crate::Vcpu::set_xen_vcpu_info`].
+//!
+//! This is currently only supported on Linux through KVM.
+
+/// The MSR used by Xen to set up the hypercall page.
+pub const XEN_HYPERCALL_MSR: u32 = 0x4000_0000;
+
+/// The configuration of the Xen HVM support.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct XenConfig {
+    /// The MSR the guest writes the guest physical address of the hypercall page to.
+    pub hypercall_msr: u32,
+    /// Whether the guest uses the 64-bit layout of the shared info page.
+    pub long_mode: bool,
+}
+
+impl Default for XenConfig {
+    fn default() -> Self {
+        Self {
+            hypercall_msr: XEN_HYPERCALL_MSR,
+            long_mode: true,
+        }
+    }
+}
+
+/// Describes a Xen hypercall made by the guest.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct XenHypercall {
+    /// Whether the hypercall was made from 64-bit mode.
+    pub long_mode: bool,
+    /// The current privilege level of the virtual CPU at the time of the hypercall.
+    pub cpl: u32,
+    /// The hypercall number.
+    pub input: u64,
+    /// The arguments to the hypercall.
+    pub params: [u64; 6],
+}